@@ -80,6 +80,10 @@ pub const FUSE_RELEASE_FLOCK_UNLOCK: u32 = 1 << 1;
 // Getattr flags.
 pub const FUSE_GETATTR_FH: u32 = 1;
 
+// fuse_attr flags (ABI 7.32 and 7.36).
+pub const FUSE_ATTR_SUBMOUNT: u32 = 1 << 0;
+pub const FUSE_ATTR_DAX: u32 = 1 << 1;
+
 // Lock flags.
 pub const FUSE_LK_FLOCK: u32 = 1 << 0;
 
@@ -133,7 +137,8 @@ pub struct fuse_attr {
     pub gid: u32,
     pub rdev: u32,
     pub blksize: u32,
-    pub padding: u32,
+    /// The attribute flags since ABI 7.32, which was a padding before.
+    pub flags: u32,
 }
 
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
@@ -285,9 +290,39 @@ pub struct fuse_in_header {
     pub uid: u32,
     pub gid: u32,
     pub pid: u32,
-    pub padding: u32,
+    /// The length of extension blocks following the argument, in 8-byte units.
+    ///
+    /// Since ABI 7.38.
+    pub total_extlen: u16,
+    pub padding: u16,
+}
+
+/// The header of an extension block appended to the request.
+///
+/// Since ABI 7.38.
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_ext_header {
+    /// The length of the block including this header, aligned to 8 bytes.
+    pub size: u32,
+    pub type_: u32,
 }
 
+/// The payload of the `FUSE_EXT_GROUPS` extension, followed by the `u32` group IDs.
+///
+/// Since ABI 7.39.
+#[derive(Clone, Copy, Default, FromBytes, AsBytes)]
+#[repr(C)]
+pub struct fuse_supp_groups {
+    pub nr_groups: u32,
+}
+
+/// The extension types less than this value are the number of security contexts.
+pub const FUSE_MAX_NR_SECCTX: u32 = 31;
+
+/// The extension type of supplementary groups.
+pub const FUSE_EXT_GROUPS: u32 = 32;
+
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
 #[repr(C)]
 pub struct fuse_init_in {
@@ -704,8 +739,15 @@ define_notify_code! {
     FUSE_NOTIFY_STORE = 4,
     FUSE_NOTIFY_RETRIEVE = 5,
     FUSE_NOTIFY_DELETE = 6,
+    /// Since ABI 7.40 (Linux 6.9).
+    FUSE_NOTIFY_RESEND = 7,
 }
 
+/// The bit set in the unique ID of requests resent by `FUSE_NOTIFY_RESEND`.
+///
+/// Since ABI 7.40 (Linux 6.9).
+pub const FUSE_UNIQUE_RESEND: u64 = 1 << 63;
+
 #[derive(Clone, Copy, Default, FromBytes, AsBytes)]
 #[repr(C)]
 pub struct fuse_notify_poll_wakeup_out {
@@ -789,3 +831,75 @@ pub struct cuse_init_out {
     pub dev_minor: u32,
     pub spare: [u32; 10],
 }
+
+// The sizes of ABI structs are fixed by the kernel regardless of the target.
+// These assertions catch the layouts that differ on 32-bit targets, where `u64`
+// may be aligned to 4 bytes and the missing padding fields shrink the structs.
+macro_rules! assert_abi_size {
+    ($($name:ident => $size:expr,)*) => {$(
+        const _: [(); $size] = [(); std::mem::size_of::<$name>()];
+    )*};
+}
+
+assert_abi_size! {
+    fuse_attr => 88,
+    fuse_dirent => 24,
+    fuse_direntplus => 152,
+    fuse_kstatfs => 80,
+    fuse_file_lock => 24,
+    fuse_in_header => 40,
+    fuse_ext_header => 8,
+    fuse_supp_groups => 4,
+    fuse_init_in => 16,
+    fuse_forget_in => 8,
+    fuse_getattr_in => 16,
+    fuse_setattr_in => 88,
+    fuse_mknod_in => 16,
+    fuse_mkdir_in => 8,
+    fuse_rename_in => 8,
+    fuse_link_in => 8,
+    fuse_open_in => 8,
+    fuse_read_in => 40,
+    fuse_write_in => 40,
+    fuse_flush_in => 24,
+    fuse_release_in => 24,
+    fuse_fsync_in => 16,
+    fuse_getxattr_in => 8,
+    fuse_setxattr_in => 8,
+    fuse_lk_in => 48,
+    fuse_access_in => 8,
+    fuse_create_in => 16,
+    fuse_bmap_in => 16,
+    fuse_out_header => 16,
+    fuse_attr_out => 104,
+    fuse_entry_out => 128,
+    fuse_init_out => 64,
+    fuse_getxattr_out => 8,
+    fuse_open_out => 16,
+    fuse_write_out => 8,
+    fuse_statfs_out => 80,
+    fuse_lk_out => 24,
+    fuse_bmap_out => 8,
+    fuse_ioctl_in => 32,
+    fuse_ioctl_out => 16,
+    fuse_ioctl_iovec => 16,
+    fuse_poll_in => 24,
+    fuse_poll_out => 8,
+    fuse_interrupt_in => 8,
+    fuse_fallocate_in => 32,
+    fuse_batch_forget_in => 8,
+    fuse_forget_one => 16,
+    fuse_rename2_in => 16,
+    fuse_lseek_in => 24,
+    fuse_lseek_out => 8,
+    fuse_copy_file_range_in => 56,
+    fuse_notify_poll_wakeup_out => 8,
+    fuse_notify_inval_inode_out => 24,
+    fuse_notify_inval_entry_out => 16,
+    fuse_notify_delete_out => 24,
+    fuse_notify_store_out => 24,
+    fuse_notify_retrieve_out => 32,
+    fuse_notify_retrieve_in => 40,
+    cuse_init_in => 16,
+    cuse_init_out => 72,
+}
@@ -0,0 +1,361 @@
+//! Admission control of the background requests on the session side.
+
+use crate::decoder::Decoder;
+use polyfuse_kernel::*;
+use std::{
+    collections::HashSet,
+    convert::TryFrom as _,
+    fmt, io,
+    os::unix::prelude::*,
+    sync::{Arc, Mutex},
+};
+
+/// The value of `max_background` used by the kernel when it is not specified.
+const DEFAULT_MAX_BACKGROUND: u16 = 12;
+
+/// Bounds the number of in-flight background requests by pausing the receive,
+/// in the same way as the kernel applies `max_background` and
+/// `congestion_threshold`.
+///
+/// Receiving is paused once the number of background requests reaches
+/// `max_background`, and resumed when the completions bring it below
+/// `congestion_threshold`.
+///
+/// The state is also signaled through an eventfd, which is readable unless
+/// receiving is paused, so that the waiting threads can watch the device
+/// for the forgets and the interrupts at the same time.
+pub(crate) struct BackgroundAdmission {
+    max_background: usize,
+    congestion_threshold: usize,
+    state: Mutex<State>,
+    resumed: EventFd,
+    on_resumed: Option<Arc<ResumeCallback>>,
+}
+
+pub(crate) type ResumeCallback = dyn Fn() + Send + Sync;
+
+impl fmt::Debug for BackgroundAdmission {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("BackgroundAdmission")
+            .field("max_background", &self.max_background)
+            .field("congestion_threshold", &self.congestion_threshold)
+            .field("state", &self.state)
+            .finish()
+    }
+}
+
+#[derive(Debug, Default)]
+struct State {
+    in_flight: HashSet<u64>,
+    paused: bool,
+    woken: bool,
+}
+
+impl BackgroundAdmission {
+    /// Create the controller from the negotiated parameters in `fuse_init_out`.
+    ///
+    /// `on_resumed` is called when receiving is resumed, outside of the lock.
+    pub(crate) fn new(
+        max_background: u16,
+        congestion_threshold: u16,
+        on_resumed: Option<Arc<ResumeCallback>>,
+    ) -> io::Result<Self> {
+        let max_background = match max_background {
+            0 => DEFAULT_MAX_BACKGROUND,
+            n => n,
+        };
+        let congestion_threshold = match congestion_threshold {
+            0 => max_background * 3 / 4,
+            n => n.min(max_background),
+        };
+        let resumed = EventFd::new()?;
+        resumed.signal()?;
+        Ok(Self {
+            max_background: max_background as usize,
+            congestion_threshold: congestion_threshold as usize,
+            state: Mutex::default(),
+            resumed,
+            on_resumed,
+        })
+    }
+
+    #[inline]
+    pub(crate) fn max_background(&self) -> usize {
+        self.max_background
+    }
+
+    #[inline]
+    pub(crate) fn congestion_threshold(&self) -> usize {
+        self.congestion_threshold
+    }
+
+    /// Return the number of background requests waiting for their replies.
+    pub(crate) fn count(&self) -> usize {
+        self.state.lock().unwrap().in_flight.len()
+    }
+
+    /// Return whether receiving requests is paused.
+    pub(crate) fn is_paused(&self) -> bool {
+        self.state.lock().unwrap().paused
+    }
+
+    /// Count the request as in flight if it is in the background class.
+    pub(crate) fn admit(&self, header: &fuse_in_header, arg: &[u8], pagesize: usize) {
+        if !is_background(header, arg, pagesize) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        state.in_flight.insert(header.unique);
+        if !state.paused && state.in_flight.len() >= self.max_background {
+            debug!(
+                "pause receiving requests ({} background requests in flight)",
+                state.in_flight.len()
+            );
+            state.paused = true;
+            self.resumed.reset();
+        }
+    }
+
+    /// Mark the request as completed, either replied or dropped.
+    pub(crate) fn complete(&self, unique: u64) {
+        {
+            let mut state = self.state.lock().unwrap();
+            if !state.in_flight.remove(&unique) {
+                return;
+            }
+            if !state.paused || state.in_flight.len() >= self.congestion_threshold {
+                return;
+            }
+            debug!("resume receiving requests");
+            state.paused = false;
+            if let Err(err) = self.resumed.signal() {
+                error!("failed to signal the resumption: {}", err);
+            }
+        }
+        if let Some(ref on_resumed) = self.on_resumed {
+            on_resumed();
+        }
+    }
+
+    /// Block the current thread while receiving is paused, or until `wake` is called.
+    ///
+    /// If `fd` is given, the waiting also ends when it becomes readable, and
+    /// the returned value is whether receiving is still paused.
+    pub(crate) fn wait(&self, fd: Option<RawFd>) -> io::Result<bool> {
+        loop {
+            {
+                let state = self.state.lock().unwrap();
+                if !state.paused || state.woken {
+                    return Ok(false);
+                }
+            }
+            // The eventfd stays readable after resuming, so the resumption
+            // between the check above and the poll is not missed.
+            let mut fds = [
+                libc::pollfd {
+                    fd: self.resumed.0,
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+                libc::pollfd {
+                    fd: fd.unwrap_or(-1),
+                    events: libc::POLLIN,
+                    revents: 0,
+                },
+            ];
+            let res = unsafe { libc::poll(fds.as_mut_ptr(), 2, -1) };
+            if res == -1 {
+                let err = io::Error::last_os_error();
+                if err.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                return Err(err);
+            }
+            if fds[1].revents != 0 {
+                return Ok(self.is_paused());
+            }
+        }
+    }
+
+    /// Release the waiting threads permanently, e.g. when the session is exiting.
+    pub(crate) fn wake(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.woken = true;
+        if let Err(err) = self.resumed.signal() {
+            error!("failed to wake the waiting threads: {}", err);
+        }
+    }
+}
+
+/// The eventfd signaling the resumption of receiving.
+struct EventFd(RawFd);
+
+impl EventFd {
+    fn new() -> io::Result<Self> {
+        let fd = unsafe { libc::eventfd(0, libc::EFD_CLOEXEC | libc::EFD_NONBLOCK) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(Self(fd))
+    }
+
+    fn signal(&self) -> io::Result<()> {
+        let value = 1u64;
+        let res = unsafe {
+            libc::write(
+                self.0,
+                &value as *const u64 as *const libc::c_void,
+                std::mem::size_of::<u64>(),
+            )
+        };
+        if res == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    /// Make the eventfd unreadable by consuming its counter.
+    fn reset(&self) {
+        let mut value = 0u64;
+        unsafe {
+            libc::read(
+                self.0,
+                &mut value as *mut u64 as *mut libc::c_void,
+                std::mem::size_of::<u64>(),
+            );
+        }
+    }
+}
+
+impl Drop for EventFd {
+    fn drop(&mut self) {
+        unsafe {
+            libc::close(self.0);
+        }
+    }
+}
+
+/// Return whether the request is counted as a background one.
+///
+/// The kernel sends the readahead and the writeback of the page cache, both
+/// of which consist of whole pages, as background requests, and the
+/// directory listing also tends to occupy the filesystem for a long time.
+fn is_background(header: &fuse_in_header, arg: &[u8], pagesize: usize) -> bool {
+    match fuse_opcode::try_from(header.opcode).ok() {
+        Some(fuse_opcode::FUSE_READDIR) | Some(fuse_opcode::FUSE_READDIRPLUS) => true,
+        Some(fuse_opcode::FUSE_READ) => matches!(
+            Decoder::new(arg).fetch::<fuse_read_in>(),
+            Ok(read_in) if read_in.size as usize >= pagesize
+        ),
+        Some(fuse_opcode::FUSE_WRITE) => matches!(
+            Decoder::new(arg).fetch::<fuse_write_in>(),
+            Ok(write_in) if write_in.size as usize >= pagesize
+        ),
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use zerocopy::AsBytes as _;
+
+    fn header(opcode: fuse_opcode, unique: u64) -> fuse_in_header {
+        fuse_in_header {
+            opcode: opcode as u32,
+            unique,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn pause_and_resume() {
+        let admission = BackgroundAdmission::new(4, 0, None).unwrap();
+        assert_eq!(admission.max_background(), 4);
+        assert_eq!(admission.congestion_threshold(), 3);
+
+        let large = fuse_read_in {
+            size: 4096,
+            ..Default::default()
+        };
+        let small = fuse_read_in {
+            size: 100,
+            ..Default::default()
+        };
+        admission.admit(&header(fuse_opcode::FUSE_READ, 1), small.as_bytes(), 4096);
+        admission.admit(&header(fuse_opcode::FUSE_GETATTR, 2), &[], 4096);
+        assert_eq!(admission.count(), 0);
+
+        for unique in 3..6 {
+            admission.admit(
+                &header(fuse_opcode::FUSE_READ, unique),
+                large.as_bytes(),
+                4096,
+            );
+        }
+        assert!(!admission.is_paused());
+        admission.admit(
+            &header(fuse_opcode::FUSE_READDIR, 6),
+            large.as_bytes(),
+            4096,
+        );
+        assert_eq!(admission.count(), 4);
+        assert!(admission.is_paused());
+
+        // The foreground requests do not affect the state.
+        admission.complete(1);
+        admission.complete(3);
+        assert!(admission.is_paused());
+        admission.complete(4);
+        assert!(!admission.is_paused());
+        assert_eq!(admission.count(), 2);
+        assert!(!admission.wait(None).unwrap());
+    }
+
+    #[test]
+    fn wake_releases_waiters() {
+        let admission = Arc::new(BackgroundAdmission::new(1, 1, None).unwrap());
+        admission.admit(&header(fuse_opcode::FUSE_READDIR, 1), &[], 4096);
+        assert!(admission.is_paused());
+
+        let waiter = std::thread::spawn({
+            let admission = admission.clone();
+            move || admission.wait(None).unwrap()
+        });
+        admission.wake();
+        assert!(!waiter.join().unwrap());
+    }
+
+    #[test]
+    fn resume_callback() {
+        let resumed = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let on_resumed = {
+            let resumed = resumed.clone();
+            move || {
+                resumed.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            }
+        };
+        let admission =
+            Arc::new(BackgroundAdmission::new(1, 1, Some(Arc::new(on_resumed))).unwrap());
+        admission.admit(&header(fuse_opcode::FUSE_READDIR, 1), &[], 4096);
+        assert!(admission.is_paused());
+
+        let waiter = std::thread::spawn({
+            let admission = admission.clone();
+            move || admission.wait(None).unwrap()
+        });
+        admission.complete(1);
+        assert!(!waiter.join().unwrap());
+        assert_eq!(resumed.load(std::sync::atomic::Ordering::SeqCst), 1);
+    }
+
+    #[test]
+    fn wait_for_readable_fd() {
+        let admission = BackgroundAdmission::new(1, 1, None).unwrap();
+        admission.admit(&header(fuse_opcode::FUSE_READDIR, 1), &[], 4096);
+
+        let (mut tx, rx) = std::os::unix::net::UnixStream::pair().unwrap();
+        std::io::Write::write_all(&mut tx, b"x").unwrap();
+        assert!(admission.wait(Some(rx.as_raw_fd())).unwrap());
+    }
+}
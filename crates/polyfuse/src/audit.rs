@@ -0,0 +1,404 @@
+//! Sanity checks of the replies sent by the filesystem.
+
+use polyfuse_kernel::*;
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    mem,
+    sync::Mutex,
+};
+
+/// The maximum number of forgotten inodes whose generations are remembered.
+const MAX_FORGOTTEN_GENERATIONS: usize = 4096;
+
+/// Tracks the generation numbers of the inodes announced to the kernel,
+/// enabled only in debug builds.
+///
+/// The kernel identifies an inode by the pair of its inode number and
+/// generation. If the filesystem reuses an inode number that has been
+/// forgotten by the kernel without changing the generation, NFS clients
+/// and the kernel may confuse the new inode with the old one.  Only the
+/// latest `MAX_FORGOTTEN_GENERATIONS` forgets are remembered.
+#[derive(Default)]
+pub(crate) struct GenerationAudit {
+    inner: Mutex<GenerationAuditInner>,
+}
+
+#[derive(Default)]
+struct GenerationAuditInner {
+    /// The lookup count and generation of inodes known by the kernel.
+    live: HashMap<u64, (u64, u64)>,
+    /// The last announced generation of inodes forgotten by the kernel,
+    /// with the sequence number of the forget.
+    forgotten: HashMap<u64, (u64, u64)>,
+    /// The forgets in the order of arrival, for evicting the oldest ones
+    /// beyond `MAX_FORGOTTEN_GENERATIONS`.
+    forgotten_order: VecDeque<(u64, u64)>,
+    next_seq: u64,
+}
+
+impl GenerationAudit {
+    /// Observe an entry announced to the kernel, and return whether the
+    /// inode is a forgotten one re-announced with the same generation.
+    pub(crate) fn entry(&self, out: &fuse_entry_out) -> bool {
+        if out.nodeid == 0 {
+            // negative entry.
+            return false;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let reused = inner
+            .forgotten
+            .remove(&out.nodeid)
+            .map(|(generation, _)| generation)
+            == Some(out.generation);
+        if reused {
+            warn!(
+                "the forgotten inode is re-announced with the same generation \
+                 (ino = {}, generation = {})",
+                out.nodeid, out.generation,
+            );
+        }
+
+        let live = inner.live.entry(out.nodeid).or_insert((0, out.generation));
+        live.0 += 1;
+        live.1 = out.generation;
+
+        reused
+    }
+
+    /// Observe the entries in a reply of `READDIRPLUS`.
+    pub(crate) fn entries_plus(&self, payload: &[u8]) {
+        for_each_entry_plus(payload, |out| {
+            self.entry(out);
+        });
+    }
+
+    /// Observe the kernel releasing the lookup count of an inode.
+    pub(crate) fn forget(&self, ino: u64, nlookup: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        let generation = match inner.live.get_mut(&ino) {
+            Some((count, generation)) => {
+                *count = count.saturating_sub(nlookup);
+                if *count > 0 {
+                    return;
+                }
+                *generation
+            }
+            None => return,
+        };
+        inner.live.remove(&ino);
+        let seq = inner.next_seq;
+        inner.next_seq += 1;
+        inner.forgotten.insert(ino, (generation, seq));
+        inner.forgotten_order.push_back((ino, seq));
+        if inner.forgotten_order.len() > MAX_FORGOTTEN_GENERATIONS {
+            // The older forgets of the inodes forgotten again or announced
+            // since then are also counted, so the map is bounded as well.
+            if let Some((oldest, seq)) = inner.forgotten_order.pop_front() {
+                if inner.forgotten.get(&oldest).map(|&(_, s)| s) == Some(seq) {
+                    inner.forgotten.remove(&oldest);
+                }
+            }
+        }
+    }
+}
+
+/// Call `f` with the entry of every `fuse_direntplus` in a reply of
+/// `READDIRPLUS`, except for `.` and `..`, whose lookup counts the kernel
+/// does not increment.
+fn for_each_entry_plus(mut payload: &[u8], mut f: impl FnMut(&fuse_entry_out)) {
+    const HEADER_SIZE: usize = mem::size_of::<fuse_direntplus>();
+    while payload.len() >= HEADER_SIZE {
+        let mut header = fuse_direntplus::default();
+        zerocopy::AsBytes::as_bytes_mut(&mut header).copy_from_slice(&payload[..HEADER_SIZE]);
+        let namelen = header.dirent.namelen as usize;
+        let name = match payload.get(HEADER_SIZE..HEADER_SIZE + namelen) {
+            Some(name) => name,
+            None => return,
+        };
+        if name != b"." && name != b".." {
+            f(&header.entry_out);
+        }
+        let entry_size = (HEADER_SIZE + namelen + 7) & !7;
+        payload = payload.get(entry_size..).unwrap_or(&[]);
+    }
+}
+
+/// Counts the lookups of inodes on the side of the session, enabled by
+/// `KernelConfig::lookup_audit` and `KernelConfig::reject_stale_inodes`.
+#[derive(Default)]
+pub(crate) struct LookupAudit {
+    counts: Mutex<HashMap<u64, u64>>,
+    // The inodes whose lookup counts have dropped to zero, tracked only
+    // for `InodeTracking::Forgotten`.
+    forgotten: Option<Mutex<HashSet<u64>>>,
+}
+
+impl LookupAudit {
+    /// Create the audit that also remembers the forgotten inodes.
+    pub(crate) fn tracking_forgotten() -> Self {
+        Self {
+            counts: Mutex::default(),
+            forgotten: Some(Mutex::default()),
+        }
+    }
+
+    /// Observe a reply that increments the lookup count of an inode.
+    pub(crate) fn entry(&self, ino: u64) {
+        if ino == 0 {
+            // negative entry.
+            return;
+        }
+        let mut counts = self.counts.lock().unwrap();
+        *counts.entry(ino).or_insert(0) += 1;
+        if let Some(ref forgotten) = self.forgotten {
+            forgotten.lock().unwrap().remove(&ino);
+        }
+    }
+
+    /// Return whether the kernel holds a lookup count of the inode.
+    pub(crate) fn is_live(&self, ino: u64) -> bool {
+        self.counts.lock().unwrap().contains_key(&ino)
+    }
+
+    /// Return whether the inode has been forgotten by the kernel and not
+    /// looked up again since then.
+    pub(crate) fn is_forgotten(&self, ino: u64) -> bool {
+        match self.forgotten {
+            Some(ref forgotten) => forgotten.lock().unwrap().contains(&ino),
+            None => false,
+        }
+    }
+
+    /// Observe the entries in a reply of `READDIRPLUS`.
+    pub(crate) fn entries_plus(&self, payload: &[u8]) {
+        for_each_entry_plus(payload, |out| self.entry(out.nodeid));
+    }
+
+    /// Observe the kernel releasing the lookup count of an inode.
+    pub(crate) fn forget(&self, ino: u64, nlookup: u64) {
+        let mut counts = self.counts.lock().unwrap();
+        if let Some(count) = counts.get_mut(&ino) {
+            *count = count.saturating_sub(nlookup);
+            if *count == 0 {
+                counts.remove(&ino);
+                if let Some(ref forgotten) = self.forgotten {
+                    forgotten.lock().unwrap().insert(ino);
+                }
+            }
+        }
+    }
+
+    pub(crate) fn snapshot(&self) -> LookupCounts {
+        LookupCounts {
+            counts: self.counts.lock().unwrap().clone(),
+        }
+    }
+
+    /// Replace the lookup counts with the ones observed by the previous session.
+    pub(crate) fn restore(&self, counts: LookupCounts) {
+        *self.counts.lock().unwrap() = counts.counts;
+    }
+}
+
+/// A snapshot of the lookup counts of inodes known by the kernel, obtained
+/// by `Session::lookup_counts`.
+///
+/// The counts are incremented by the entries replied to `LOOKUP`, `MKNOD`,
+/// `MKDIR`, `SYMLINK`, `LINK`, `CREATE` and `READDIRPLUS`, and decremented
+/// by `FORGET` and `BATCH_FORGET`.  The inodes whose counts drop to zero
+/// are removed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LookupCounts {
+    counts: HashMap<u64, u64>,
+}
+
+impl LookupCounts {
+    pub(crate) fn from_counts(counts: HashMap<u64, u64>) -> Self {
+        Self { counts }
+    }
+
+    /// Return the lookup count of the inode.
+    pub fn get(&self, ino: u64) -> u64 {
+        self.counts.get(&ino).copied().unwrap_or(0)
+    }
+
+    /// Return the number of inodes whose lookup counts are not zero.
+    pub fn len(&self) -> usize {
+        self.counts.len()
+    }
+
+    /// Return whether no inode is referred by the kernel.
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    /// Iterate over the pairs of the inode number and its lookup count.
+    pub fn iter(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.counts.iter().map(|(&ino, &count)| (ino, count))
+    }
+
+    /// Compare the counts with the table maintained by the filesystem, and
+    /// return the inodes whose counts differ, in the order of inode numbers.
+    ///
+    /// The inodes missing in either side are regarded as the count of zero.
+    pub fn diff<I>(&self, table: I) -> Vec<LookupDiscrepancy>
+    where
+        I: IntoIterator<Item = (u64, u64)>,
+    {
+        let mut table: HashMap<u64, u64> = table.into_iter().filter(|&(_, n)| n > 0).collect();
+        let mut diffs: Vec<_> = self
+            .counts
+            .iter()
+            .filter_map(|(&ino, &expected)| {
+                let actual = table.remove(&ino).unwrap_or(0);
+                if actual == expected {
+                    return None;
+                }
+                Some(LookupDiscrepancy {
+                    ino,
+                    expected,
+                    actual,
+                })
+            })
+            .collect();
+        diffs.extend(table.into_iter().map(|(ino, actual)| LookupDiscrepancy {
+            ino,
+            expected: 0,
+            actual,
+        }));
+        diffs.sort_by_key(|diff| diff.ino);
+        diffs
+    }
+}
+
+/// An inode whose lookup count in the filesystem differs from the session's one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct LookupDiscrepancy {
+    /// The inode number.
+    pub ino: u64,
+    /// The lookup count observed by the session.
+    pub expected: u64,
+    /// The lookup count in the table of the filesystem.
+    pub actual: u64,
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    fn entry_out(ino: u64, generation: u64) -> fuse_entry_out {
+        fuse_entry_out {
+            nodeid: ino,
+            generation,
+            ..Default::default()
+        }
+    }
+
+    pub(crate) fn direntplus(ino: u64, name: &[u8]) -> Vec<u8> {
+        let mut header = fuse_direntplus::default();
+        header.entry_out.nodeid = ino;
+        header.dirent.ino = ino;
+        header.dirent.namelen = name.len() as u32;
+        let mut entry = zerocopy::AsBytes::as_bytes(&header).to_vec();
+        entry.extend_from_slice(name);
+        entry.resize((entry.len() + 7) & !7, 0);
+        entry
+    }
+
+    #[test]
+    fn lookup_counts() {
+        let audit = LookupAudit::default();
+        audit.entry(2);
+        audit.entry(2);
+        audit.entry(0);
+        let mut payload = direntplus(1, b".");
+        payload.extend(direntplus(1, b".."));
+        payload.extend(direntplus(3, b"a.txt"));
+        payload.extend(direntplus(0, b"negative"));
+        payload.extend(direntplus(4, b"directory"));
+        audit.entries_plus(&payload);
+        audit.forget(4, 1);
+        audit.forget(5, 1);
+
+        let counts = audit.snapshot();
+        assert_eq!(counts.len(), 2);
+        assert_eq!((counts.get(1), counts.get(2), counts.get(3)), (0, 2, 1));
+
+        assert!(counts.diff(vec![(2, 2), (3, 1), (4, 0)]).is_empty());
+        assert_eq!(
+            counts.diff(vec![(2, 1), (5, 3)]),
+            [
+                LookupDiscrepancy {
+                    ino: 2,
+                    expected: 2,
+                    actual: 1
+                },
+                LookupDiscrepancy {
+                    ino: 3,
+                    expected: 1,
+                    actual: 0
+                },
+                LookupDiscrepancy {
+                    ino: 5,
+                    expected: 0,
+                    actual: 3
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn reuse_with_same_generation() {
+        let audit = GenerationAudit::default();
+        assert!(!audit.entry(&entry_out(2, 0)));
+        assert!(!audit.entry(&entry_out(2, 0)));
+        audit.forget(2, 1);
+        audit.forget(2, 1);
+        assert!(audit.entry(&entry_out(2, 0)));
+    }
+
+    #[test]
+    fn reuse_with_next_generation() {
+        let audit = GenerationAudit::default();
+        assert!(!audit.entry(&entry_out(2, 0)));
+        audit.forget(2, 1);
+        assert!(!audit.entry(&entry_out(2, 1)));
+    }
+
+    #[test]
+    fn reuse_in_readdirplus() {
+        let audit = GenerationAudit::default();
+        let mut payload = direntplus(1, b".");
+        payload.extend(direntplus(2, b"a.txt"));
+        audit.entries_plus(&payload);
+        audit.forget(2, 1);
+        audit.forget(1, 1);
+        assert!(audit.entry(&entry_out(2, 0)));
+    }
+
+    #[test]
+    fn forgotten_generations_are_bounded() {
+        let audit = GenerationAudit::default();
+        for ino in 2..2 + MAX_FORGOTTEN_GENERATIONS as u64 + 1 {
+            audit.entry(&entry_out(ino, 0));
+            audit.forget(ino, 1);
+        }
+        assert_eq!(
+            audit.inner.lock().unwrap().forgotten.len(),
+            MAX_FORGOTTEN_GENERATIONS
+        );
+        // The oldest one is no longer remembered.
+        assert!(!audit.entry(&entry_out(2, 0)));
+        assert!(audit.entry(&entry_out(3, 0)));
+    }
+
+    #[test]
+    fn alive_inode_is_not_reported() {
+        let audit = GenerationAudit::default();
+        assert!(!audit.entry(&entry_out(2, 0)));
+        assert!(!audit.entry(&entry_out(2, 1)));
+        audit.forget(2, 1);
+        assert!(!audit.entry(&entry_out(2, 1)));
+    }
+}
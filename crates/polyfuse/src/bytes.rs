@@ -34,6 +34,21 @@ pub trait FillBytes<'a> {
     fn put(&mut self, chunk: &'a [u8]);
 }
 
+/// Copy all of the chunks in `bytes` into a contiguous buffer.
+pub(crate) fn to_vec<T: ?Sized + Bytes>(bytes: &T) -> Vec<u8> {
+    struct Collector(Vec<u8>);
+
+    impl<'a> FillBytes<'a> for Collector {
+        fn put(&mut self, chunk: &'a [u8]) {
+            self.0.extend_from_slice(chunk);
+        }
+    }
+
+    let mut collector = Collector(Vec::with_capacity(bytes.size()));
+    bytes.fill_bytes(&mut collector);
+    collector.0
+}
+
 // ==== pointer types ====
 
 macro_rules! impl_reply_body_for_pointers {
@@ -315,6 +330,29 @@ mod impl_scattered_bytes_for_cont {
     }
 }
 
+impl Bytes for std::io::IoSlice<'_> {
+    #[inline]
+    fn size(&self) -> usize {
+        self.len()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+
+    #[inline]
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        if !self.is_empty() {
+            dst.put(self);
+        }
+    }
+}
+
 impl Bytes for std::ffi::OsStr {
     #[inline]
     fn size(&self) -> usize {
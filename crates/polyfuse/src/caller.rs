@@ -0,0 +1,185 @@
+//! Lookup of the information about the calling processes from procfs.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+/// The number of processes whose information is kept in the cache.
+const CACHE_CAPACITY: usize = 64;
+
+/// The identity of a process that survives the reuse of PID.
+type ProcessKey = (u32, u64);
+
+#[derive(Default)]
+struct CallerInfo {
+    exe: Option<PathBuf>,
+    cgroup: Option<String>,
+    groups: Option<Vec<u32>>,
+}
+
+/// An LRU cache of the information about the calling processes.
+#[derive(Default)]
+pub(crate) struct CallerCache {
+    inner: Mutex<Entries>,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<ProcessKey, Arc<Mutex<CallerInfo>>>,
+    order: VecDeque<ProcessKey>,
+}
+
+impl Entries {
+    fn get(&mut self, key: ProcessKey) -> Arc<Mutex<CallerInfo>> {
+        if let Some(pos) = self.order.iter().position(|k| *k == key) {
+            self.order.remove(pos);
+        } else if self.order.len() >= CACHE_CAPACITY {
+            if let Some(oldest) = self.order.pop_front() {
+                self.map.remove(&oldest);
+            }
+        }
+        self.order.push_back(key);
+        self.map.entry(key).or_default().clone()
+    }
+}
+
+impl CallerCache {
+    pub(crate) fn exe(&self, pid: u32) -> io::Result<PathBuf> {
+        self.lookup(
+            pid,
+            |info| info.exe.clone(),
+            |info, exe| info.exe = Some(exe),
+            || fs::read_link(format!("/proc/{}/exe", pid)),
+        )
+    }
+
+    pub(crate) fn cgroup(&self, pid: u32) -> io::Result<String> {
+        self.lookup(
+            pid,
+            |info| info.cgroup.clone(),
+            |info, cgroup| info.cgroup = Some(cgroup),
+            || {
+                let content = fs::read_to_string(format!("/proc/{}/cgroup", pid))?;
+                parse_cgroup(&content).ok_or_else(|| {
+                    io::Error::new(
+                        io::ErrorKind::NotFound,
+                        "the process does not belong to the unified cgroup hierarchy",
+                    )
+                })
+            },
+        )
+    }
+
+    pub(crate) fn supplementary_groups(&self, pid: u32) -> io::Result<Vec<u32>> {
+        self.lookup(
+            pid,
+            |info| info.groups.clone(),
+            |info, groups| info.groups = Some(groups),
+            || {
+                let content = fs::read_to_string(format!("/proc/{}/status", pid))?;
+                parse_groups(&content).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidData, "missing Groups in status")
+                })
+            },
+        )
+    }
+
+    fn lookup<T: Clone>(
+        &self,
+        pid: u32,
+        cached: impl FnOnce(&CallerInfo) -> Option<T>,
+        store: impl FnOnce(&mut CallerInfo, T),
+        read: impl FnOnce() -> io::Result<T>,
+    ) -> io::Result<T> {
+        if pid == 0 {
+            // The request was not issued by any processes (e.g. FORGET).
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        let key = (pid, start_time(pid)?);
+        let info = self.inner.lock().unwrap().get(key);
+        let mut info = info.lock().unwrap();
+        if let Some(value) = cached(&info) {
+            return Ok(value);
+        }
+
+        let value = read()?;
+
+        // The process may have exited and its PID may be reused while reading.
+        if start_time(pid)? != key.1 {
+            return Err(io::Error::from_raw_os_error(libc::ESRCH));
+        }
+
+        store(&mut info, value.clone());
+        Ok(value)
+    }
+}
+
+fn start_time(pid: u32) -> io::Result<u64> {
+    let stat = fs::read_to_string(format!("/proc/{}/stat", pid))?;
+    parse_start_time(&stat)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "malformed stat"))
+}
+
+fn parse_start_time(stat: &str) -> Option<u64> {
+    // The command name may contain spaces and parentheses, so the fields
+    // are counted from the last closing parenthesis.
+    let fields = &stat[stat.rfind(')')? + 1..];
+    // `starttime` is the 22nd field, and the fields after the command name start at the 3rd.
+    fields.split_whitespace().nth(22 - 3)?.parse().ok()
+}
+
+fn parse_cgroup(content: &str) -> Option<String> {
+    content
+        .lines()
+        .find_map(|line| line.strip_prefix("0::"))
+        .map(ToOwned::to_owned)
+}
+
+fn parse_groups(status: &str) -> Option<Vec<u32>> {
+    let line = status
+        .lines()
+        .find_map(|line| line.strip_prefix("Groups:"))?;
+    line.split_whitespace()
+        .map(|gid| gid.parse().ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_files() {
+        let stat = "42 (a) b (c)) S 1 42 42 0 -1 4194560 100 0 0 0 1 2 0 0 20 0 1 0 12345 0 0";
+        assert_eq!(parse_start_time(stat), Some(12345));
+        assert_eq!(parse_start_time("42 (a) S 1"), None);
+
+        let cgroup = "12:cpu:/foo\n0::/user.slice/app.scope\n";
+        assert_eq!(
+            parse_cgroup(cgroup).as_deref(),
+            Some("/user.slice/app.scope")
+        );
+        assert_eq!(parse_cgroup("12:cpu:/foo\n"), None);
+
+        let status = "Name:\tfoo\nGroups:\t4 24 1000 \nNgid:\t0\n";
+        assert_eq!(parse_groups(status), Some(vec![4, 24, 1000]));
+        assert_eq!(parse_groups("Groups:\t\n"), Some(vec![]));
+    }
+
+    #[test]
+    fn lookup_current_process() {
+        let cache = CallerCache::default();
+        let pid = std::process::id();
+        let exe = cache.exe(pid).unwrap();
+        assert_eq!(exe, std::env::current_exe().unwrap());
+        assert_eq!(cache.exe(pid).unwrap(), exe);
+        assert_eq!(cache.inner.lock().unwrap().order.len(), 1);
+
+        let err = cache.exe(0).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ESRCH));
+    }
+}
@@ -1,17 +1,51 @@
 use libc::{c_int, c_void, iovec};
 use std::{
-    cmp,
+    cmp, error,
     ffi::{OsStr, OsString},
-    io,
+    fmt,
+    fs::File,
+    io::{self, Read as _, Write as _},
     mem::{self, MaybeUninit},
     os::unix::{net::UnixStream, prelude::*},
     path::{Path, PathBuf},
-    process::{Command, ExitStatus},
+    process::{Command, ExitStatus, Stdio},
     ptr,
+    sync::atomic::{AtomicBool, Ordering},
 };
 
 const FUSERMOUNT_PROG: &str = "/usr/bin/fusermount";
 const FUSE_COMMFD_ENV: &str = "_FUSE_COMMFD";
+const FUSE_DEVICE: &str = "/dev/fuse";
+
+/// The way of unmounting the filesystem.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum UnmountMode {
+    /// Unmount the filesystem only if it is not busy.
+    ///
+    /// This fails with `EBUSY` while any file on the filesystem is open or
+    /// any process has its working directory in it.
+    Normal,
+
+    /// Detach the filesystem from the mount tree immediately (`MNT_DETACH`).
+    ///
+    /// The files already opened keep working, and the kernel closes the
+    /// connection after the last of them is closed.  This is the default.
+    Lazy,
+
+    /// Abort the connection through fusectl, and then detach the filesystem.
+    ///
+    /// All of the pending and following requests fail with `ENOTCONN` in
+    /// the kernel, and the session stops receiving requests immediately.
+    Force,
+}
+
+#[allow(clippy::derivable_impls)] // `#[default]` on enums requires Rust 1.62
+impl Default for UnmountMode {
+    fn default() -> Self {
+        Self::Lazy
+    }
+}
 
 macro_rules! syscall {
     ($fn:ident ( $($arg:expr),* $(,)* ) ) => {{
@@ -29,29 +63,167 @@ macro_rules! syscall {
 pub struct Connection {
     fd: RawFd,
     child: Option<Fusermount>,
-    mountpoint: PathBuf,
+    mountpoint: Option<PathBuf>,
+    device: Option<(u32, u32)>,
     mountopts: MountOptions,
+    handed_over: AtomicBool,
+    unmounted: AtomicBool,
 }
 
 impl Drop for Connection {
     fn drop(&mut self) {
-        self.unmount();
+        match self.unmount() {
+            Ok(()) => (),
+            Err(err) if err.raw_os_error() == Some(libc::EBUSY) => {
+                warn!(
+                    "the filesystem is busy and still mounted; unmount it lazily with `fusermount -u -z`"
+                );
+            }
+            Err(err) => error!("failed to unmount the filesystem: {}", err),
+        }
     }
 }
 
 impl Connection {
     /// Establish a connection with the FUSE kernel driver.
     pub(crate) fn open(mountpoint: PathBuf, mountopts: MountOptions) -> io::Result<Self> {
+        // The absolute path is required to find the mount in mountinfo later.
+        // Resolving it after mounting may issue requests to the filesystem itself.
+        let mountpoint = std::fs::canonicalize(&mountpoint).unwrap_or(mountpoint);
         let (fd, child) = mount(&mountpoint, &mountopts)?;
-        Ok(Self {
+        // The device number names the directory of the connection in fusectl.
+        let device = crate::mountinfo::device_number(&mountpoint)
+            .map_err(|err| debug!("failed to find the device number: {}", err))
+            .ok();
+        crate::mountinfo::check_visibility(&mountpoint);
+        let conn = Self {
             fd,
             child,
-            mountpoint,
+            mountpoint: Some(mountpoint),
+            device,
             mountopts,
-        })
+            handed_over: AtomicBool::new(false),
+            unmounted: AtomicBool::new(false),
+        };
+        set_nonblocking(conn.fd)?;
+        Ok(conn)
+    }
+
+    /// Return the mountpoint, if the filesystem is mounted by this connection.
+    pub(crate) fn mountpoint(&self) -> Option<&Path> {
+        self.mountpoint.as_deref()
+    }
+
+    /// Return the device number of the filesystem mounted by this connection.
+    pub(crate) fn device(&self) -> Option<(u32, u32)> {
+        self.device
+    }
+
+    /// Describe the mount registered by this connection, as listed in `/proc/mounts`.
+    pub(crate) fn mount_description(&self) -> Option<String> {
+        let mountpoint = self.mountpoint.as_deref()?;
+        let fsname = self
+            .mountopts
+            .fsname()
+            .unwrap_or_else(|| FUSE_DEVICE.into());
+        let fstype = match self.mountopts.subtype {
+            Some(ref subtype) => format!("fuse.{}", subtype),
+            None => "fuse".into(),
+        };
+        Some(format!(
+            "{} on {} type {}",
+            fsname,
+            mountpoint.display(),
+            fstype
+        ))
+    }
+
+    /// Create a connection from the file descriptor of FUSE device opened by another process.
+    ///
+    /// The returned connection does not unmount the filesystem on drop.
+    pub(crate) fn from_fd(fd: RawFd) -> io::Result<Self> {
+        let conn = Self {
+            fd,
+            child: None,
+            mountpoint: None,
+            device: None,
+            mountopts: MountOptions::default(),
+            handed_over: AtomicBool::new(false),
+            unmounted: AtomicBool::new(false),
+        };
+        set_nonblocking(conn.fd)?;
+        Ok(conn)
+    }
+
+    /// Create a pair of connected sockets that emulates the FUSE device.
+    ///
+    /// The socket type is `SOCK_SEQPACKET` so that the message boundaries
+    /// are preserved as in `/dev/fuse`.  The returned connection does not
+    /// correspond to any mountpoint.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn pair() -> io::Result<(Self, UnixStream)> {
+        let mut fds = [0 as c_int; 2];
+        syscall! {
+            socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        let peer = unsafe { UnixStream::from_raw_fd(fds[1]) };
+        let conn = Self::from_fd(fds[0])?;
+        Ok((conn, peer))
     }
 
     fn read(&self, dst: &mut [u8]) -> io::Result<usize> {
+        self.wait_for(libc::POLLIN, || self.try_read(dst))
+    }
+
+    fn read_vectored(&self, dst: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        self.wait_for(libc::POLLIN, || self.try_read_vectored(dst))
+    }
+
+    fn write(&self, src: &[u8]) -> io::Result<usize> {
+        self.wait_for(libc::POLLOUT, || self.try_write(src))
+    }
+
+    fn write_vectored(&self, src: &[io::IoSlice<'_>]) -> io::Result<usize> {
+        self.wait_for(libc::POLLOUT, || self.try_write_vectored(src))
+    }
+
+    /// Return the reader of the connection that fails with `EAGAIN`
+    /// instead of blocking when no request is available.
+    pub(crate) fn nonblocking(&self) -> NonBlocking<'_> {
+        NonBlocking(self)
+    }
+
+    /// Repeat the operation on the non-blocking descriptor, waiting for
+    /// the readiness of `events` each time it fails with `EAGAIN`.
+    ///
+    /// Since the other threads may consume the readiness first, the
+    /// operation is not assumed to succeed after `poll(2)` returns.
+    fn wait_for<T>(
+        &self,
+        events: libc::c_short,
+        mut f: impl FnMut() -> io::Result<T>,
+    ) -> io::Result<T> {
+        loop {
+            match f() {
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let mut pollfd = libc::pollfd {
+                        fd: self.fd,
+                        events,
+                        revents: 0,
+                    };
+                    syscall! { poll(&mut pollfd, 1, -1) };
+                }
+                res => return res,
+            }
+        }
+    }
+
+    fn try_read(&self, dst: &mut [u8]) -> io::Result<usize> {
         let len = syscall! {
             read(
                 self.fd, //
@@ -62,18 +234,18 @@ impl Connection {
         Ok(len as usize)
     }
 
-    fn read_vectored(&self, dst: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+    fn try_read_vectored(&self, dst: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
         let len = syscall! {
             readv(
                 self.fd, //
                 dst.as_mut_ptr() as *mut iovec,
-                cmp::min(dst.len(), c_int::max_value() as usize) as c_int,
+                cmp::min(dst.len(), c_int::MAX as usize) as c_int,
             )
         };
         Ok(len as usize)
     }
 
-    fn write(&self, src: &[u8]) -> io::Result<usize> {
+    fn try_write(&self, src: &[u8]) -> io::Result<usize> {
         let res = syscall! {
             write(
                 self.fd, //
@@ -84,27 +256,95 @@ impl Connection {
         Ok(res as usize)
     }
 
-    fn write_vectored(&self, src: &[io::IoSlice<'_>]) -> io::Result<usize> {
+    fn try_write_vectored(&self, src: &[io::IoSlice<'_>]) -> io::Result<usize> {
         let res = syscall! {
             writev(
                 self.fd, //
                 src.as_ptr() as *const iovec,
-                cmp::min(src.len(), c_int::max_value() as usize) as c_int,
+                cmp::min(src.len(), c_int::MAX as usize) as c_int,
             )
         };
         Ok(res as usize)
     }
 
-    fn unmount(&mut self) {
-        unsafe {
-            libc::close(self.fd);
+    /// Close the connection and unmount the filesystem.
+    ///
+    /// Unlike dropping the connection, the failure of unmounting is reported
+    /// to the caller so that it is not silently left mounted.
+    pub(crate) fn close(mut self) -> io::Result<()> {
+        self.unmount()
+    }
+
+    /// Return whether the mount is bound to the helper process of `auto_unmount`.
+    pub(crate) fn auto_unmount(&self) -> bool {
+        self.child.is_some()
+    }
+
+    /// Leave the mount to another process, so that it is not unmounted on drop.
+    pub(crate) fn hand_over(&self) {
+        self.handed_over.store(true, Ordering::SeqCst);
+    }
+
+    pub(crate) fn is_handed_over(&self) -> bool {
+        self.handed_over.load(Ordering::SeqCst)
+    }
+
+    /// Unmount the filesystem while keeping the connection open.
+    ///
+    /// On failure, the filesystem is unmounted again on drop.
+    pub(crate) fn unmount_with(&self, mode: UnmountMode) -> io::Result<()> {
+        if self.is_handed_over() {
+            #[allow(clippy::io_other_error)] // `io::Error::other` requires Rust 1.74
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "the mount has been handed over to another process",
+            ));
+        }
+        let mountpoint = self.mountpoint.as_deref().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the session is not mounted by this process",
+            )
+        })?;
+        if self.unmounted.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let result = unmount(mountpoint, mode, self.device);
+        if result.is_err() {
+            self.unmounted.store(false, Ordering::SeqCst);
+        }
+        result
+    }
+
+    fn unmount(&mut self) -> io::Result<()> {
+        if self.fd >= 0 {
+            unsafe {
+                libc::close(self.fd);
+            }
+            self.fd = -1;
+        }
+
+        if self.is_handed_over() {
+            return Ok(());
         }
 
+        // The helper process running with `auto_unmount` unmounts
+        // the filesystem when its input is closed.
         if let Some(child) = self.child.take() {
-            let _ = child.wait();
+            if let Err(err) = child.wait() {
+                warn!("failed to wait for the fusermount process: {}", err);
+            }
         }
 
-        unmount(&self.mountpoint);
+        if let Some(mountpoint) = self.mountpoint.take() {
+            if !self.unmounted.load(Ordering::SeqCst) {
+                // The connection has been aborted by closing the device,
+                // so there is nothing to abort for `Force`.
+                unmount(&mountpoint, self.mountopts.unmount_mode, None)?;
+            }
+        }
+
+        Ok(())
     }
 }
 
@@ -138,6 +378,22 @@ impl io::Read for &Connection {
     }
 }
 
+/// The reader of the connection which does not wait for the incoming requests.
+#[derive(Debug)]
+pub(crate) struct NonBlocking<'a>(&'a Connection);
+
+impl io::Read for NonBlocking<'_> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.try_read(buf)
+    }
+
+    #[inline]
+    fn read_vectored(&mut self, bufs: &mut [io::IoSliceMut<'_>]) -> io::Result<usize> {
+        self.0.try_read_vectored(bufs)
+    }
+}
+
 impl io::Write for Connection {
     #[inline]
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
@@ -174,22 +430,269 @@ impl io::Write for &Connection {
 
 // ==== mount ====
 
+/// The cause of failure on mounting the filesystem.
+///
+/// The error returned from `Session::mount` contains this value as its
+/// inner error, which can be obtained by `io::Error::get_ref` and downcasting.
 #[derive(Debug)]
+#[non_exhaustive]
+pub enum MountError {
+    /// The FUSE device `/dev/fuse` is not available.
+    ///
+    /// The kernel module may not be loaded (`modprobe fuse`), or the device
+    /// is not exposed to the container.
+    DeviceNotFound,
+
+    /// The `fusermount` binary is not found at the specified path.
+    FusermountNotFound(PathBuf),
+
+    /// The `allow_other` or `allow_root` option is specified, but it is not
+    /// permitted for non-root users.
+    AllowOtherNotPermitted,
+
+    /// The current user does not have the permission to mount the filesystem.
+    PermissionDenied(String),
+
+    /// The mountpoint is busy, e.g. another filesystem is mounted on it.
+    Busy(String),
+
+    /// The mountpoint does not exist or is not accessible.
+    InvalidMountpoint(String),
+
+    /// `fusermount` failed for the other reason.
+    Fusermount(String),
+
+    /// An I/O error occurred during the mount procedure.
+    Io(io::Error),
+}
+
+impl MountError {
+    /// Classify the failure of `fusermount`, by checking the conditions it
+    /// requires in the same way.
+    ///
+    /// The standard error of `fusermount` is kept only as the message,
+    /// since its wording depends on the version and the locale.
+    fn from_fusermount_failure(
+        mountpoint: &Path,
+        mountopts: &MountOptions,
+        status: Option<ExitStatus>,
+        message: &str,
+    ) -> Self {
+        let mut message = message.trim().to_owned();
+        if message.is_empty() {
+            if let Some(status) = status {
+                message = format!("fusermount {}", status);
+            }
+        }
+
+        let privileged = unsafe { libc::geteuid() } == 0;
+        if !privileged && mountopts.allows_other() && !user_allow_other() {
+            return Self::AllowOtherNotPermitted;
+        }
+        if let Err(err) = std::fs::metadata(mountpoint) {
+            return match err.raw_os_error() {
+                Some(libc::EACCES) | Some(libc::EPERM) => Self::PermissionDenied(message),
+                _ => Self::InvalidMountpoint(message),
+            };
+        }
+        if !privileged {
+            // fusermount requires the write permission to the mountpoint.
+            let c_mountpoint = std::ffi::CString::new(mountpoint.as_os_str().as_bytes());
+            if let Ok(c_mountpoint) = c_mountpoint {
+                if unsafe { libc::access(c_mountpoint.as_ptr(), libc::W_OK) } == -1 {
+                    return Self::PermissionDenied(message);
+                }
+            }
+        }
+        Self::Fusermount(message)
+    }
+
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::DeviceNotFound | Self::FusermountNotFound(..) | Self::InvalidMountpoint(..) => {
+                io::ErrorKind::NotFound
+            }
+            Self::AllowOtherNotPermitted | Self::PermissionDenied(..) => {
+                io::ErrorKind::PermissionDenied
+            }
+            Self::Io(err) => err.kind(),
+            _ => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for MountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::DeviceNotFound => write!(
+                f,
+                "{} is not available; check that the fuse kernel module is loaded",
+                FUSE_DEVICE
+            ),
+            Self::FusermountNotFound(path) => write!(
+                f,
+                "fusermount is not found at {}; install the fuse package or specify the path",
+                path.display()
+            ),
+            Self::AllowOtherNotPermitted => write!(
+                f,
+                "allow_other/allow_root requires 'user_allow_other' in /etc/fuse.conf"
+            ),
+            Self::PermissionDenied(msg) => write!(f, "permission denied on mounting: {}", msg),
+            Self::Busy(msg) => write!(f, "the mountpoint is busy: {}", msg),
+            Self::InvalidMountpoint(msg) => write!(f, "invalid mountpoint: {}", msg),
+            Self::Fusermount(msg) if msg.is_empty() => write!(f, "fusermount failed"),
+            Self::Fusermount(msg) => write!(f, "fusermount failed: {}", msg),
+            Self::Io(err) => write!(f, "I/O error during mount: {}", err),
+        }
+    }
+}
+
+impl error::Error for MountError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Io(err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<MountError> for io::Error {
+    fn from(err: MountError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// The cause of failure in unmounting the filesystem.
+///
+/// This is contained in the `io::Error` returned from `Session::unmount`
+/// when the mountpoint is no longer the filesystem of the session in the
+/// mount namespace of this process, but the connection is still alive.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum UnmountError {
+    /// The filesystem has been moved to another mountpoint, e.g. by
+    /// `mount --move`, which is given here.
+    Moved(PathBuf),
+
+    /// The filesystem is not found in the mount namespace of this process.
+    ///
+    /// It has been mounted in another namespace, e.g. by `fusermount`
+    /// running in a container, and can only be unmounted from there.  If
+    /// the namespace has disappeared, aborting the connection with
+    /// `UnmountMode::Force` is the only way to stop the session.
+    OtherNamespace(PathBuf),
+}
+
+impl fmt::Display for UnmountError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Moved(path) => write!(f, "the filesystem has been moved to {}", path.display()),
+            Self::OtherNamespace(path) => write!(
+                f,
+                "the filesystem on {} is not in the mount namespace of this process",
+                path.display()
+            ),
+        }
+    }
+}
+
+impl error::Error for UnmountError {}
+
+impl From<UnmountError> for io::Error {
+    fn from(err: UnmountError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
+}
+
+#[derive(Debug, Clone)]
 pub(crate) struct MountOptions {
     pub(crate) options: Vec<String>,
+    pub(crate) fsname: Option<String>,
+    pub(crate) subtype: Option<String>,
     pub(crate) auto_unmount: bool,
+    pub(crate) max_read: Option<u32>,
+    pub(crate) blksize: Option<u32>,
     pub(crate) fusermount_path: Option<PathBuf>,
     pub(crate) fuse_comm_fd: Option<OsString>,
+    pub(crate) unmount_mode: UnmountMode,
 }
 
 impl Default for MountOptions {
     fn default() -> Self {
         Self {
             options: vec![],
+            fsname: None,
+            subtype: None,
             auto_unmount: true,
+            max_read: None,
+            blksize: None,
             fusermount_path: None,
             fuse_comm_fd: None,
+            unmount_mode: UnmountMode::default(),
+        }
+    }
+}
+
+impl MountOptions {
+    /// Return the name of the mounted filesystem, which defaults to the name of the binary.
+    pub(crate) fn fsname(&self) -> Option<String> {
+        self.fsname.clone().or_else(|| {
+            let program = std::env::args_os().next()?;
+            let name = Path::new(&program).file_name()?.to_str()?;
+            Some(name.to_owned())
+        })
+    }
+
+    /// Return whether `allow_other` or `allow_root` is specified.
+    fn allows_other(&self) -> bool {
+        self.options
+            .iter()
+            .flat_map(|opts| opts.split(','))
+            .any(|opt| opt == "allow_other" || opt == "allow_root")
+    }
+
+    /// Render the options passed to `fusermount` with `-o`.
+    fn to_option_string(&self) -> String {
+        let mut opts = vec![];
+        if let Some(fsname) = self.fsname() {
+            opts.push(format!("fsname={}", escape_option(&fsname)));
+        }
+        if let Some(ref subtype) = self.subtype {
+            opts.push(format!("subtype={}", escape_option(subtype)));
+        }
+        opts.extend(self.options.iter().cloned());
+        if let Some(max_read) = self.max_read {
+            opts.push(format!("max_read={}", max_read));
+        }
+        if let Some(blksize) = self.blksize {
+            opts.push(format!("blksize={}", blksize));
+        }
+        if self.auto_unmount {
+            opts.push("auto_unmount".into());
         }
+        opts.join(",")
+    }
+}
+
+/// Escape the separators in the value of a mount option, as `fusermount` splits
+/// the options at the unescaped commas.
+fn escape_option(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if c == ',' || c == '\\' {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// Return whether `/etc/fuse.conf` permits `allow_other` to non-root users.
+fn user_allow_other() -> bool {
+    match std::fs::read_to_string("/etc/fuse.conf") {
+        Ok(conf) => conf.lines().any(|line| line.trim() == "user_allow_other"),
+        Err(..) => false,
     }
 }
 
@@ -197,43 +700,71 @@ impl Default for MountOptions {
 struct Fusermount {
     pid: c_int,
     input: UnixStream,
+    // Read until the process exits so that the writes to the standard
+    // error neither block nor cause `SIGPIPE`.  `None` after it is passed
+    // to the thread of `drain_stderr`.
+    stderr: Option<File>,
 }
 
 impl Fusermount {
-    fn wait(self) -> io::Result<ExitStatus> {
+    /// Wait for the process to exit, and return its exit status with the
+    /// rest of its standard error.
+    fn wait(self) -> io::Result<(ExitStatus, String)> {
         drop(self.input);
+        let mut message = String::new();
+        if let Some(mut stderr) = self.stderr {
+            let _ = stderr.read_to_string(&mut message);
+        }
         let mut status = 0;
         syscall! { waitpid(self.pid, &mut status, 0) };
-        Ok(ExitStatus::from_raw(status))
+        Ok((ExitStatus::from_raw(status), message))
+    }
+
+    /// Log the standard error of the helper process in a background thread,
+    /// while it lives with the session.
+    fn drain_stderr(&mut self) {
+        let stderr = match self.stderr.take() {
+            Some(stderr) => stderr,
+            None => return,
+        };
+        let res = std::thread::Builder::new()
+            .name("fusermount-stderr".into())
+            .spawn(move || {
+                use std::io::BufRead as _;
+                for line in io::BufReader::new(stderr).lines() {
+                    match line {
+                        Ok(line) => warn!("fusermount: {}", line),
+                        Err(..) => break,
+                    }
+                }
+            });
+        if let Err(err) = res {
+            warn!("failed to spawn the thread for fusermount: {}", err);
+        }
     }
 }
 
 fn mount(mountpoint: &Path, mountopts: &MountOptions) -> io::Result<(RawFd, Option<Fusermount>)> {
+    if !Path::new(FUSE_DEVICE).exists() {
+        return Err(MountError::DeviceNotFound.into());
+    }
+
+    let fusermount_path = mountopts
+        .fusermount_path
+        .as_deref()
+        .unwrap_or_else(|| Path::new(FUSERMOUNT_PROG));
+    if !fusermount_path.exists() {
+        return Err(MountError::FusermountNotFound(fusermount_path.to_owned()).into());
+    }
+
     let (input, output) = UnixStream::pair()?;
 
-    let mut fusermount = Command::new(
-        mountopts
-            .fusermount_path
-            .as_deref()
-            .unwrap_or_else(|| Path::new(FUSERMOUNT_PROG)),
-    );
+    // The standard error of `fusermount` is captured to diagnose the failure.
+    let (stderr_reader, stderr_writer) = pipe().map_err(MountError::Io)?;
 
-    let opts = mountopts
-        .options
-        .iter()
-        .map(|opt| opt.as_str())
-        .chain(if mountopts.auto_unmount {
-            Some("auto_unmount")
-        } else {
-            None
-        })
-        .fold(String::new(), |mut opts, opt| {
-            if !opts.is_empty() {
-                opts.push(',');
-            }
-            opts.push_str(&opt);
-            opts
-        });
+    let mut fusermount = Command::new(fusermount_path);
+
+    let opts = mountopts.to_option_string();
     if !opts.is_empty() {
         fusermount.arg("-o").arg(opts);
     }
@@ -256,6 +787,8 @@ fn mount(mountpoint: &Path, mountopts: &MountOptions) -> io::Result<(RawFd, Opti
             let output = output.into_raw_fd();
             unsafe { libc::fcntl(output, libc::F_SETFD, 0) };
 
+            unsafe { libc::dup2(stderr_writer, 2) };
+
             // Assumes that the UnixStream destructor only calls close(2).
             drop(input);
 
@@ -270,47 +803,174 @@ fn mount(mountpoint: &Path, mountopts: &MountOptions) -> io::Result<(RawFd, Opti
 
         ForkResult::Parent { child_pid, .. } => {
             drop(output);
+            unsafe { libc::close(stderr_writer) };
+            let stderr = unsafe { File::from_raw_fd(stderr_reader) };
 
-            let fd = receive_fd(&input)?;
-
-            let mut child = Some(Fusermount {
+            let received = recv_fd(&input, &mut [0u8; 1]).map(|(fd, _)| fd);
+            let mut child = Fusermount {
                 pid: child_pid,
                 input,
-            });
+                stderr: Some(stderr),
+            };
+            let fd = match received {
+                Ok(fd) => fd,
+                Err(err) => {
+                    // The process exits without sending the file descriptor.
+                    debug!("failed to receive the file descriptor: {}", err);
+                    let (status, message) = match child.wait() {
+                        Ok((status, message)) => (Some(status), message),
+                        Err(..) => (None, String::new()),
+                    };
+                    return Err(MountError::from_fusermount_failure(
+                        mountpoint, mountopts, status, &message,
+                    )
+                    .into());
+                }
+            };
 
             if !mountopts.auto_unmount {
                 // When auto_unmount is not specified, `fusermount` exits immediately
                 // after sending the file descriptor and thus we need to wait until
                 // the command is exited.
-                let child = child.take().unwrap();
-                let _st = child.wait()?;
+                let (_status, message) = child.wait()?;
+                if !message.trim().is_empty() {
+                    warn!("fusermount: {}", message.trim());
+                }
+                return Ok((fd, None));
             }
 
-            Ok((fd, child))
+            child.drain_stderr();
+            Ok((fd, Some(child)))
         }
     }
 }
 
-fn unmount(mountpoint: &Path) {
-    let _ = Command::new(FUSERMOUNT_PROG)
-        .args(&["-u", "-q", "-z", "--"])
-        .arg(&mountpoint)
-        .status();
+/// Return the arguments of `fusermount` to unmount the filesystem.
+fn unmount_args(mode: UnmountMode) -> &'static [&'static str] {
+    match mode {
+        UnmountMode::Normal => &["-u", "-q", "--"],
+        _ => &["-u", "-q", "-z", "--"],
+    }
 }
 
-fn receive_fd(reader: &UnixStream) -> io::Result<RawFd> {
-    let mut buf = [0u8; 1];
+fn unmount(mountpoint: &Path, mode: UnmountMode, device: Option<(u32, u32)>) -> io::Result<()> {
+    if mode == UnmountMode::Force {
+        // The entries of fusectl are owned by the user who mounted the filesystem.
+        let aborted = crate::fusectl::ConnectionDir::locate(device).and_then(|dir| dir.abort());
+        if let Err(err) = aborted {
+            warn!("failed to abort the connection: {}", err);
+        }
+    }
+
+    let output = Command::new(FUSERMOUNT_PROG)
+        .args(unmount_args(mode))
+        .arg(mountpoint)
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .output();
+    let fusermount_failed = match output {
+        Ok(output) if output.status.success() => return Ok(()),
+        Ok(output) => {
+            debug!(
+                "fusermount failed to unmount: {}",
+                String::from_utf8_lossy(&output.stderr).trim()
+            );
+            true
+        }
+        Err(..) => false,
+    };
+
+    // fusermount may be unavailable or have failed, e.g. when the mountpoint
+    // has already been unmounted by the helper process.  Fall back to the
+    // direct system call, which succeeds if the process is privileged.
+    let c_mountpoint = std::ffi::CString::new(mountpoint.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let flags = match mode {
+        UnmountMode::Normal => 0,
+        _ => libc::MNT_DETACH,
+    };
+    let res = unsafe { libc::umount2(c_mountpoint.as_ptr(), flags) };
+    if res == -1 {
+        let err = io::Error::last_os_error();
+        // Without privileges, the system call fails before checking whether
+        // the mount is busy.  The unmount of fusermount on the mount of the
+        // same user fails without `-z` only if it is busy.
+        if err.raw_os_error() == Some(libc::EPERM)
+            && fusermount_failed
+            && mode == UnmountMode::Normal
+            && crate::mountinfo::mount_flags(mountpoint).is_ok()
+        {
+            return Err(io::Error::from_raw_os_error(libc::EBUSY));
+        }
+        // `EINVAL` means that the path is no longer a mountpoint.
+        if err.raw_os_error() != Some(libc::EINVAL) {
+            return Err(err);
+        }
+        if let Some(device) = device {
+            if let Some(err) = crate::mountinfo::diagnose_unmount(mountpoint, device) {
+                return Err(err.into());
+            }
+        }
+    }
+    Ok(())
+}
+
+#[repr(C)]
+struct FdCmsg {
+    header: libc::cmsghdr,
+    fd: c_int,
+}
+
+/// Send a file descriptor over the Unix socket, along with the data.
+///
+/// The data must not be empty, since the file descriptor is attached to it.
+/// The receiver obtains a duplicate of the file descriptor by `recv_fd`,
+/// and the original one is still owned by the caller.
+pub fn send_fd(socket: &UnixStream, fd: RawFd, data: &[u8]) -> io::Result<()> {
+    if data.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the data sent with the file descriptor is empty",
+        ));
+    }
     let mut iov = libc::iovec {
-        iov_base: buf.as_mut_ptr() as *mut c_void,
-        iov_len: 1,
+        iov_base: data.as_ptr() as *mut c_void,
+        iov_len: data.len(),
     };
 
-    #[repr(C)]
-    struct Cmsg {
-        header: libc::cmsghdr,
-        fd: c_int,
+    let mut cmsg: FdCmsg = unsafe { mem::zeroed() };
+    cmsg.header.cmsg_len = unsafe { libc::CMSG_LEN(mem::size_of::<c_int>() as u32) } as _;
+    cmsg.header.cmsg_level = libc::SOL_SOCKET;
+    cmsg.header.cmsg_type = libc::SCM_RIGHTS;
+    cmsg.fd = fd;
+
+    let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+    msg.msg_iov = &mut iov;
+    msg.msg_iovlen = 1;
+    msg.msg_control = &mut cmsg as *mut FdCmsg as *mut c_void;
+    msg.msg_controllen = mem::size_of_val(&cmsg) as _;
+
+    let len = syscall! { sendmsg(socket.as_raw_fd(), &msg, libc::MSG_NOSIGNAL) } as usize;
+    if len < data.len() {
+        // The file descriptor has been sent with the first part.
+        (&*socket).write_all(&data[len..])?;
     }
-    let mut cmsg = MaybeUninit::<Cmsg>::uninit();
+    Ok(())
+}
+
+/// Receive a file descriptor sent by `send_fd` from the Unix socket.
+///
+/// The leading part of the data sent with it is read into `buf`, and the
+/// length of read data is returned along with the file descriptor, which
+/// is marked as close-on-exec.  Since the socket may be a stream, the rest
+/// of the data should be read in the usual way.
+pub fn recv_fd(socket: &UnixStream, buf: &mut [u8]) -> io::Result<(RawFd, usize)> {
+    let mut iov = libc::iovec {
+        iov_base: buf.as_mut_ptr() as *mut c_void,
+        iov_len: buf.len(),
+    };
+
+    let mut cmsg = MaybeUninit::<FdCmsg>::uninit();
 
     let mut msg = libc::msghdr {
         msg_name: ptr::null_mut(),
@@ -322,7 +982,7 @@ fn receive_fd(reader: &UnixStream) -> io::Result<RawFd> {
         msg_flags: 0,
     };
 
-    syscall! { recvmsg(reader.as_raw_fd(), &mut msg, 0) };
+    let len = syscall! { recvmsg(socket.as_raw_fd(), &mut msg, libc::MSG_CMSG_CLOEXEC) } as usize;
 
     if msg.msg_controllen < mem::size_of_val(&cmsg) {
         return Err(io::Error::new(
@@ -339,14 +999,28 @@ fn receive_fd(reader: &UnixStream) -> io::Result<RawFd> {
         ));
     }
 
-    let fd = cmsg.fd;
-    syscall! { fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) };
-
-    Ok(fd)
+    Ok((cmsg.fd, len))
 }
 
 // ==== util ====
 
+fn pipe() -> io::Result<(RawFd, RawFd)> {
+    let mut fds = [0 as c_int; 2];
+    syscall! { pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) };
+    Ok((fds[0], fds[1]))
+}
+
+/// Set `O_NONBLOCK` to the file descriptor, so that `try_next_request` can
+/// read from it without blocking even if another thread has dequeued the
+/// request first.  The blocking reads wait for the readiness by `poll(2)`.
+fn set_nonblocking(fd: RawFd) -> io::Result<()> {
+    let flags = syscall! { fcntl(fd, libc::F_GETFL) };
+    if flags & libc::O_NONBLOCK == 0 {
+        syscall! { fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) };
+    }
+    Ok(())
+}
+
 enum ForkResult {
     Parent { child_pid: c_int },
     Child,
@@ -360,3 +1034,130 @@ unsafe fn fork() -> io::Result<ForkResult> {
         pid => Ok(ForkResult::Parent { child_pid: pid }),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify_fusermount_failure() {
+        let mountopts = MountOptions::default();
+        let classify = |mountpoint: &str, message: &str| {
+            MountError::from_fusermount_failure(Path::new(mountpoint), &mountopts, None, message)
+        };
+
+        // The message is not parsed, whatever the locale of fusermount is.
+        assert!(matches!(
+            classify("/nonexistent/polyfuse", "fusermount: ...\n"),
+            MountError::InvalidMountpoint(ref msg) if msg == "fusermount: ..."
+        ));
+        assert!(matches!(
+            classify(
+                "/nonexistent/polyfuse",
+                "fusermount: mount failed: Device or resource busy"
+            ),
+            MountError::InvalidMountpoint(..)
+        ));
+
+        let dir = std::env::temp_dir();
+        let mountpoint = dir.to_str().unwrap();
+        assert!(matches!(
+            classify(mountpoint, "fusermount: mount failed"),
+            MountError::Fusermount(..) | MountError::PermissionDenied(..)
+        ));
+
+        let status = ExitStatus::from_raw(1 << 8);
+        let err = MountError::from_fusermount_failure(&dir, &mountopts, Some(status), "");
+        assert!(err.to_string().contains("exit"), "{}", err);
+
+        let mut mountopts = MountOptions::default();
+        mountopts.options.push("ro,allow_root".into());
+        assert!(mountopts.allows_other());
+    }
+
+    #[test]
+    fn unmount_modes() {
+        assert_eq!(UnmountMode::default(), UnmountMode::Lazy);
+        assert!(!unmount_args(UnmountMode::Normal).contains(&"-z"));
+        assert!(unmount_args(UnmountMode::Lazy).contains(&"-z"));
+        assert!(unmount_args(UnmountMode::Force).contains(&"-z"));
+
+        let (conn, _peer) = Connection::pair().unwrap();
+        let err = conn.unmount_with(UnmountMode::Lazy).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn write_waits_for_writable() {
+        let (conn, mut peer) = Connection::pair().unwrap();
+        let mut queued = 0;
+        loop {
+            match conn.try_write(&[0u8; 64]) {
+                Ok(..) => queued += 1,
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
+                Err(err) => panic!("unexpected error: {}", err),
+            }
+        }
+
+        let drainer = std::thread::spawn(move || {
+            let mut buf = [0u8; 64];
+            for _ in 0..queued {
+                io::Read::read(&mut peer, &mut buf[..]).unwrap();
+            }
+            let len = io::Read::read(&mut peer, &mut buf[..]).unwrap();
+            buf[..len].to_vec()
+        });
+
+        // The blocking write waits until the peer makes room for the message.
+        io::Write::write(&mut &conn, b"last").unwrap();
+        assert_eq!(drainer.join().unwrap(), b"last");
+    }
+
+    #[test]
+    fn render_mount_options() {
+        let mut mountopts = MountOptions::default();
+        let program = std::env::args_os().next().unwrap();
+        let program = Path::new(&program).file_name().unwrap().to_str().unwrap();
+        assert_eq!(
+            mountopts.to_option_string(),
+            format!("fsname={},auto_unmount", escape_option(program))
+        );
+
+        mountopts.fsname = Some("foo".into());
+        mountopts.subtype = Some("backup".into());
+        mountopts.options.push("allow_other".into());
+        mountopts.max_read = Some(65536);
+        mountopts.blksize = Some(4096);
+        mountopts.auto_unmount = false;
+        assert_eq!(
+            mountopts.to_option_string(),
+            "fsname=foo,subtype=backup,allow_other,max_read=65536,blksize=4096"
+        );
+
+        mountopts.fsname = Some(r"host:/a,b\c".into());
+        mountopts.subtype = None;
+        mountopts.options.clear();
+        mountopts.max_read = None;
+        mountopts.blksize = None;
+        assert_eq!(mountopts.to_option_string(), r"fsname=host:/a\,b\\c");
+    }
+
+    #[test]
+    fn missing_fusermount() {
+        let mountopts = MountOptions {
+            fusermount_path: Some("/nonexistent/fusermount".into()),
+            ..Default::default()
+        };
+        let err = Connection::open("/tmp".into(), mountopts).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+
+        let inner = err
+            .get_ref()
+            .and_then(|err| err.downcast_ref::<MountError>())
+            .expect("the inner error should be MountError");
+        assert!(matches!(
+            inner,
+            MountError::FusermountNotFound(..) | MountError::DeviceNotFound
+        ));
+    }
+}
@@ -1,4 +1,4 @@
-use std::{ffi::OsStr, mem, os::unix::prelude::*};
+use std::{ffi::OsStr, fmt, mem, os::unix::prelude::*};
 use zerocopy::{FromBytes, LayoutVerified};
 
 #[derive(Debug)]
@@ -8,6 +8,16 @@ pub(crate) enum DecodeError {
     Unaligned,
 }
 
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::UnexpectedEof => f.write_str("unexpected end of message"),
+            DecodeError::MissingNulCharacter => f.write_str("missing nul character"),
+            DecodeError::Unaligned => f.write_str("unaligned field"),
+        }
+    }
+}
+
 pub(crate) struct Decoder<'a> {
     bytes: &'a [u8],
 }
@@ -102,7 +112,7 @@ mod tests {
 
     #[test]
     fn unaligned() {
-        let input = vec![42u64, 0u64];
+        let input = [42u64, 0u64];
         let input = unsafe {
             std::slice::from_raw_parts(
                 input.as_ptr() as *const u8, //
@@ -115,7 +125,7 @@ mod tests {
 
         // Decoding will fail if the alignment of input bytes is wrong.
         let input = &input[2..];
-        assert!(input.as_ptr() as usize % mem::align_of::<u64>() != 0);
+        assert_ne!(input.as_ptr() as usize % mem::align_of::<u64>(), 0);
         assert!(matches!(
             Decoder::new(input).fetch::<[u64; 1]>().err(),
             Some(DecodeError::Unaligned)
@@ -124,7 +134,7 @@ mod tests {
 
     #[test]
     fn unaligned_array() {
-        let input = vec![42u64, 0u64, 0u64];
+        let input = [42u64, 0u64, 0u64];
         let input = unsafe {
             std::slice::from_raw_parts(
                 input.as_ptr() as *const u8, //
@@ -135,7 +145,7 @@ mod tests {
         assert!(Decoder::new(input).fetch_array::<u64>(2).is_ok());
 
         let input = &input[2..];
-        assert!(input.as_ptr() as usize % mem::align_of::<u64>() != 0);
+        assert_ne!(input.as_ptr() as usize % mem::align_of::<u64>(), 0);
         assert!(matches!(
             Decoder::new(input).fetch_array::<u64>(2).err(),
             Some(DecodeError::Unaligned)
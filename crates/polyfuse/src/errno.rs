@@ -0,0 +1,114 @@
+//! The error numbers replied to the kernel.
+
+use std::{fmt, io};
+
+/// An error number replied to the kernel, such as `ENOENT`.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct Errno(i32);
+
+impl fmt::Debug for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Errno({})", self.0)
+    }
+}
+
+impl fmt::Display for Errno {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&io::Error::from_raw_os_error(self.0), f)
+    }
+}
+
+impl Errno {
+    /// Create an error number from the raw value.
+    #[inline]
+    pub const fn from_raw(errno: i32) -> Self {
+        Self(errno)
+    }
+
+    /// Return the raw value of the error number, passed to `Request::reply_error`.
+    #[inline]
+    pub const fn raw(self) -> i32 {
+        self.0
+    }
+
+    /// Map an I/O error from the backend to the error number replied to the kernel.
+    ///
+    /// The error number of the OS error is preserved if present.  Otherwise
+    /// the kind of error is mapped as follows, and the others are replied
+    /// as `EIO`:
+    ///
+    /// | `io::ErrorKind`    | errno       |
+    /// |--------------------|-------------|
+    /// | `NotFound`         | `ENOENT`    |
+    /// | `PermissionDenied` | `EACCES`    |
+    /// | `AlreadyExists`    | `EEXIST`    |
+    /// | `WouldBlock`       | `EAGAIN`    |
+    /// | `InvalidInput`     | `EINVAL`    |
+    /// | `TimedOut`         | `ETIMEDOUT` |
+    ///
+    /// This is the mapping used by `Request::process` and the utilities of
+    /// this crate.  A filesystem that needs a different error number for a
+    /// particular operation can reply it explicitly instead.
+    pub fn from_io_error(err: &io::Error) -> Self {
+        if let Some(errno) = err.raw_os_error() {
+            if errno > 0 {
+                return Self(errno);
+            }
+        }
+        Self(match err.kind() {
+            io::ErrorKind::NotFound => libc::ENOENT,
+            io::ErrorKind::PermissionDenied => libc::EACCES,
+            io::ErrorKind::AlreadyExists => libc::EEXIST,
+            io::ErrorKind::WouldBlock => libc::EAGAIN,
+            io::ErrorKind::InvalidInput => libc::EINVAL,
+            io::ErrorKind::TimedOut => libc::ETIMEDOUT,
+            _ => libc::EIO,
+        })
+    }
+}
+
+impl From<&io::Error> for Errno {
+    #[inline]
+    fn from(err: &io::Error) -> Self {
+        Self::from_io_error(err)
+    }
+}
+
+impl From<Errno> for i32 {
+    #[inline]
+    fn from(errno: Errno) -> Self {
+        errno.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn from_io_error() {
+        let table = [
+            (io::ErrorKind::NotFound, libc::ENOENT),
+            (io::ErrorKind::PermissionDenied, libc::EACCES),
+            (io::ErrorKind::AlreadyExists, libc::EEXIST),
+            (io::ErrorKind::WouldBlock, libc::EAGAIN),
+            (io::ErrorKind::InvalidInput, libc::EINVAL),
+            (io::ErrorKind::TimedOut, libc::ETIMEDOUT),
+            (io::ErrorKind::UnexpectedEof, libc::EIO),
+            (io::ErrorKind::Other, libc::EIO),
+        ];
+        for &(kind, errno) in &table {
+            let err = io::Error::new(kind, "backend error");
+            assert_eq!(Errno::from_io_error(&err).raw(), errno, "{:?}", kind);
+        }
+
+        // The raw error numbers are preserved, even if the kind differs.
+        let err = io::Error::from_raw_os_error(libc::ENOTEMPTY);
+        assert_eq!(Errno::from_io_error(&err), Errno::from_raw(libc::ENOTEMPTY));
+        let err = io::Error::from_raw_os_error(libc::ENOENT);
+        assert_eq!(i32::from(Errno::from(&err)), libc::ENOENT);
+        // Non-positive values are not valid error numbers for the replies.
+        let err = io::Error::from_raw_os_error(0);
+        assert_eq!(Errno::from_io_error(&err).raw(), libc::EIO);
+    }
+}
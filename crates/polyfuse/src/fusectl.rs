@@ -0,0 +1,221 @@
+//! Access to the entries of the connection in the FUSE control filesystem (fusectl).
+//!
+//! The kernel creates the directory `/sys/fs/fuse/connections/<dev>` for
+//! each connection, where `<dev>` is the device number of the mount in the
+//! kernel's encoding.  It contains the following entries:
+//!
+//! * `waiting` - the number of requests waiting to be replied (read only)
+//! * `max_background` - the maximum number of background requests
+//! * `congestion_threshold` - the number of background requests at which
+//!   the connection is considered congested
+//! * `abort` - aborts the connection when written, used by `UnmountMode::Force`
+
+use std::{
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+};
+
+const FUSECTL_ROOT: &str = "/sys/fs/fuse/connections";
+
+/// The kernel-side statistics of the connection, read from fusectl.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct KernelStats {
+    waiting: u32,
+    max_background: u32,
+    congestion_threshold: u32,
+}
+
+impl KernelStats {
+    /// Return the number of requests that the kernel is waiting for,
+    /// including the ones not yet read by the filesystem.
+    pub fn waiting(&self) -> u32 {
+        self.waiting
+    }
+
+    /// Return the current maximum number of background requests.
+    pub fn max_background(&self) -> u32 {
+        self.max_background
+    }
+
+    /// Return the current congestion threshold.
+    pub fn congestion_threshold(&self) -> u32 {
+        self.congestion_threshold
+    }
+
+    /// Return whether the connection is likely to be congested.
+    ///
+    /// The kernel does not expose the number of background requests, so
+    /// this is estimated by comparing all of the waiting requests with the
+    /// congestion threshold.
+    pub fn congested(&self) -> bool {
+        self.waiting >= self.congestion_threshold
+    }
+}
+
+/// The errors on accessing the entries of the connection in fusectl.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum KernelStatsError {
+    /// The session is not mounted by `Session::mount` in this process, so
+    /// the device number of the connection is unknown.
+    NotMounted,
+
+    /// The entry of the connection is not accessible.
+    ///
+    /// The kernel may be too old, fusectl may not be mounted on
+    /// `/sys/fs/fuse/connections` (e.g. in a container), or the entries
+    /// may be owned by another user.
+    Unsupported(PathBuf, io::Error),
+
+    /// The content of the entry is not a number.
+    Malformed(PathBuf, String),
+}
+
+impl KernelStatsError {
+    fn kind(&self) -> io::ErrorKind {
+        match self {
+            Self::NotMounted => io::ErrorKind::NotFound,
+            Self::Unsupported(..) => io::ErrorKind::Other,
+            Self::Malformed(..) => io::ErrorKind::InvalidData,
+        }
+    }
+}
+
+impl fmt::Display for KernelStatsError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotMounted => write!(f, "the session is not mounted by this process"),
+            Self::Unsupported(path, err) => {
+                write!(f, "fusectl is not available at {}: {}", path.display(), err)
+            }
+            Self::Malformed(path, content) => {
+                write!(f, "malformed content of {}: {:?}", path.display(), content)
+            }
+        }
+    }
+}
+
+impl error::Error for KernelStatsError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            Self::Unsupported(_, err) => Some(err),
+            _ => None,
+        }
+    }
+}
+
+impl From<KernelStatsError> for io::Error {
+    fn from(err: KernelStatsError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+/// The directory of a connection in fusectl.
+#[derive(Debug)]
+pub(crate) struct ConnectionDir(PathBuf);
+
+impl ConnectionDir {
+    /// Locate the directory of the connection with the device number.
+    pub(crate) fn locate(device: Option<(u32, u32)>) -> io::Result<Self> {
+        Self::locate_in(Path::new(FUSECTL_ROOT), device)
+    }
+
+    fn locate_in(root: &Path, device: Option<(u32, u32)>) -> io::Result<Self> {
+        let (major, minor) = device.ok_or(KernelStatsError::NotMounted)?;
+        // MKDEV() of the kernel, which differs from the encoding of st_dev.
+        let dev = (u64::from(major) << 20) | u64::from(minor);
+        let path = root.join(dev.to_string());
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.is_dir() => Ok(Self(path)),
+            Ok(..) => Err(KernelStatsError::Unsupported(
+                path,
+                io::Error::from_raw_os_error(libc::ENOTDIR),
+            )
+            .into()),
+            Err(err) => Err(KernelStatsError::Unsupported(path, err).into()),
+        }
+    }
+
+    pub(crate) fn read_stats(&self) -> io::Result<KernelStats> {
+        Ok(KernelStats {
+            waiting: self.read("waiting")?,
+            max_background: self.read("max_background")?,
+            congestion_threshold: self.read("congestion_threshold")?,
+        })
+    }
+
+    pub(crate) fn write(&self, name: &str, value: u16) -> io::Result<()> {
+        let path = self.0.join(name);
+        fs::write(&path, value.to_string()).map_err(|err| unsupported(path, err))
+    }
+
+    /// Abort the connection, as `echo 1 > abort`.
+    pub(crate) fn abort(&self) -> io::Result<()> {
+        let path = self.0.join("abort");
+        fs::write(&path, "1").map_err(|err| unsupported(path, err))
+    }
+
+    fn read(&self, name: &str) -> io::Result<u32> {
+        let path = self.0.join(name);
+        let content = fs::read_to_string(&path).map_err(|err| unsupported(path.clone(), err))?;
+        match content.trim().parse() {
+            Ok(value) => Ok(value),
+            Err(..) => Err(KernelStatsError::Malformed(path, content).into()),
+        }
+    }
+}
+
+fn unsupported(path: PathBuf, err: io::Error) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::NotFound | io::ErrorKind::PermissionDenied => {
+            KernelStatsError::Unsupported(path, err).into()
+        }
+        _ => err,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fixtures() -> PathBuf {
+        Path::new(env!("CARGO_MANIFEST_DIR")).join("testdata/connections")
+    }
+
+    fn error_of(err: &io::Error) -> &KernelStatsError {
+        err.get_ref()
+            .and_then(|err| err.downcast_ref::<KernelStatsError>())
+            .expect("not a KernelStatsError")
+    }
+
+    #[test]
+    fn read_connection_entries() {
+        let dir = ConnectionDir::locate_in(&fixtures(), Some((0, 45))).unwrap();
+        let stats = dir.read_stats().unwrap();
+        assert_eq!(stats.waiting(), 3);
+        assert_eq!(stats.max_background(), 12);
+        assert_eq!(stats.congestion_threshold(), 9);
+        assert!(!stats.congested());
+
+        // The directory is named by MKDEV(major, minor) of the kernel.
+        let dir = ConnectionDir::locate_in(&fixtures(), Some((1, 2))).unwrap();
+        assert!(dir.0.ends_with("1048578"));
+        let err = dir.read_stats().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(matches!(error_of(&err), KernelStatsError::Malformed(..)));
+    }
+
+    #[test]
+    fn missing_connection_entries() {
+        let err = ConnectionDir::locate_in(&fixtures(), None).unwrap_err();
+        assert!(matches!(error_of(&err), KernelStatsError::NotMounted));
+
+        let err = ConnectionDir::locate_in(&fixtures(), Some((0, 99))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert!(matches!(error_of(&err), KernelStatsError::Unsupported(..)));
+
+        let root = fixtures().join("missing");
+        let err = ConnectionDir::locate_in(&root, Some((0, 45))).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+    }
+}
@@ -0,0 +1,190 @@
+//! Observation and modification of the replies before they are sent to the kernel.
+
+use crate::session::Caller;
+use polyfuse_kernel::{fuse_attr, fuse_direntplus};
+use std::{fmt, mem};
+use zerocopy::AsBytes as _;
+
+/// A hook called on every reply just before it is written to the kernel.
+///
+/// The interceptor is registered by `KernelConfig::reply_interceptor`, and
+/// receives the reply after the other processing of the session (such as
+/// the strict mode and the deadlines) has been applied, so it observes
+/// what the kernel actually receives.  This is intended to build the
+/// reply-aware middlewares, e.g. logging the error numbers, collecting
+/// metrics or mapping the owners of the replied attributes.
+///
+/// When no interceptor is registered, the replies are sent without
+/// decoding them.
+pub trait ReplyInterceptor: Send + Sync {
+    /// Inspect the reply to the request, and decide how it is sent.
+    ///
+    /// The modification through `ReplyBody` is sent to the kernel unless
+    /// `Action::Error` is returned.  Since this is called on every reply,
+    /// it should be cheap and should not block.
+    fn before_send(&self, req: &Caller<'_>, reply: ReplyBody<'_>) -> Action;
+}
+
+impl<F> ReplyInterceptor for F
+where
+    F: Fn(&Caller<'_>, ReplyBody<'_>) -> Action + Send + Sync,
+{
+    #[inline]
+    fn before_send(&self, req: &Caller<'_>, reply: ReplyBody<'_>) -> Action {
+        (*self)(req, reply)
+    }
+}
+
+/// A typed view of the reply passed to `ReplyInterceptor`.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ReplyBody<'a> {
+    /// An error reply, with the positive error number.
+    Error { errno: i32 },
+
+    /// An entry replied to `LOOKUP`, `MKNOD`, `MKDIR`, `SYMLINK`, `LINK`
+    /// and `CREATE`.  The inode number is `0` for a negative entry.
+    Entry { ino: u64, attr: ReplyAttr<'a> },
+
+    /// The attributes replied to `GETATTR` and `SETATTR`.
+    Attr(ReplyAttr<'a>),
+
+    /// The entries replied to `READDIRPLUS`, each of which carries the
+    /// attributes as `Entry` does.
+    Entries(ReplyEntries<'a>),
+
+    /// The data replied to `READ` and `READLINK`.
+    Data { len: usize },
+
+    /// The other replies, with the length of the payload.
+    Other { len: usize },
+}
+
+/// The replied attributes of a file, whose owner can be modified.
+pub struct ReplyAttr<'a> {
+    attr: &'a mut fuse_attr,
+}
+
+impl fmt::Debug for ReplyAttr<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplyAttr")
+            .field("ino", &self.ino())
+            .field("mode", &format_args!("{:#o}", self.mode()))
+            .field("uid", &self.uid())
+            .field("gid", &self.gid())
+            .finish()
+    }
+}
+
+impl<'a> ReplyAttr<'a> {
+    pub(crate) fn new(attr: &'a mut fuse_attr) -> Self {
+        Self { attr }
+    }
+
+    /// Return the inode number.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.attr.ino
+    }
+
+    /// Return the permission and the type of the inode.
+    #[inline]
+    pub fn mode(&self) -> u32 {
+        self.attr.mode
+    }
+
+    /// Return the size of content.
+    #[inline]
+    pub fn size(&self) -> u64 {
+        self.attr.size
+    }
+
+    /// Return the user ID of the owner.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.attr.uid
+    }
+
+    /// Return the group ID of the owner.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.attr.gid
+    }
+
+    /// Replace the user ID of the owner.
+    #[inline]
+    pub fn set_uid(&mut self, uid: u32) {
+        self.attr.uid = uid;
+    }
+
+    /// Replace the group ID of the owner.
+    #[inline]
+    pub fn set_gid(&mut self, gid: u32) {
+        self.attr.gid = gid;
+    }
+}
+
+/// The entries in a reply to `READDIRPLUS`, whose attributes can be modified.
+pub struct ReplyEntries<'a> {
+    payload: &'a mut [u8],
+    modified: &'a mut bool,
+}
+
+impl fmt::Debug for ReplyEntries<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReplyEntries")
+            .field("len", &self.payload.len())
+            .finish()
+    }
+}
+
+impl<'a> ReplyEntries<'a> {
+    pub(crate) fn new(payload: &'a mut [u8], modified: &'a mut bool) -> Self {
+        Self { payload, modified }
+    }
+
+    /// Call `f` with the inode number and the attributes of every entry,
+    /// including `.` and `..` if they are replied.
+    ///
+    /// The inode number is `0` for an entry without the attributes.
+    pub fn for_each<F>(&mut self, mut f: F)
+    where
+        F: FnMut(u64, ReplyAttr<'_>),
+    {
+        const HEADER_SIZE: usize = mem::size_of::<fuse_direntplus>();
+        let mut offset = 0;
+        while self.payload.len() - offset >= HEADER_SIZE {
+            // The entries may not be aligned in the buffer.
+            let chunk = &mut self.payload[offset..offset + HEADER_SIZE];
+            let mut header = fuse_direntplus::default();
+            header.as_bytes_mut().copy_from_slice(chunk);
+            let orig = header;
+
+            f(
+                header.entry_out.nodeid,
+                ReplyAttr::new(&mut header.entry_out.attr),
+            );
+            if header.as_bytes() != orig.as_bytes() {
+                chunk.copy_from_slice(header.as_bytes());
+                *self.modified = true;
+            }
+
+            let entry_size = (HEADER_SIZE + header.dirent.namelen as usize + 7) & !7;
+            offset = match offset.checked_add(entry_size) {
+                Some(next) if next <= self.payload.len() => next,
+                _ => return,
+            };
+        }
+    }
+}
+
+/// The decision of `ReplyInterceptor` on how the reply is sent.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Action {
+    /// Send the reply, including the modification made by the interceptor.
+    Send,
+
+    /// Send the error of the specified number instead of the reply.
+    Error(i32),
+}
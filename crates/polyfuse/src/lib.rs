@@ -1,17 +1,52 @@
 //! A FUSE (Filesystem in Userspace) library for Rust.
+//!
+//! The request, reply and session APIs, and the I/O-free protocol core in
+//! `proto`, are always available.  The other parts are enabled by the
+//! following features:
+//!
+//! * `notify` (default) - `Notifier` and `util::PollRegistry`
+//! * `tracing` (default) - the diagnostics emitted through `tracing`
+//! * `testing` - the `testing` module, for driving a session without mounting
+//! * `serde` - `Serialize` of the summary of operations
 
 #![doc(html_root_url = "https://docs.rs/polyfuse/0.4.0")]
 #![forbid(clippy::todo, clippy::unimplemented)]
 
+#[macro_use]
+mod log;
+
+mod admission;
+mod audit;
+mod caller;
 mod conn;
 mod decoder;
+mod errno;
+mod fusectl;
+mod intercept;
+mod mountinfo;
 mod session;
 
 pub mod bytes;
 pub mod op;
+pub mod proto;
 pub mod reply;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod util;
 
 pub use crate::{
+    audit::{LookupCounts, LookupDiscrepancy},
+    conn::{MountError, UnmountError, UnmountMode},
+    errno::Errno,
+    fusectl::{KernelStats, KernelStatsError},
+    intercept::{Action, ReplyAttr, ReplyBody, ReplyEntries, ReplyInterceptor},
+    mountinfo::{MountFlags, MountPropagation, Propagation},
     op::Operation,
-    session::{Data, KernelConfig, Notifier, Request, Session},
+    session::{
+        AlreadyReplied, Caller, CapabilityFlags, ConfigError, ConnectionClosed, Data,
+        InodeTracking, KernelConfig, OpcodeClass, ReaddirplusMode, Request, Session, SessionState,
+    },
 };
+
+#[cfg(feature = "notify")]
+pub use crate::session::{Closed, Notifier, Retrieved};
@@ -0,0 +1,83 @@
+//! The diagnostic macros used throughout the crate.
+//!
+//! They forward to `tracing` if the feature of the same name is enabled,
+//! and otherwise expand to nothing while still type-checking the arguments.
+
+#[cfg(feature = "tracing")]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        ::tracing::trace!($($arg)*)
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        ::tracing::debug!($($arg)*)
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        ::tracing::info!($($arg)*)
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        ::tracing::warn!($($arg)*)
+    };
+}
+
+#[cfg(feature = "tracing")]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        ::tracing::error!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! disabled {
+    ($($arg:tt)*) => {{
+        if false {
+            let _ = format_args!($($arg)*);
+        }
+    }};
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! trace {
+    ($($arg:tt)*) => {
+        disabled!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! debug {
+    ($($arg:tt)*) => {
+        disabled!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! info {
+    ($($arg:tt)*) => {
+        disabled!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! warn {
+    ($($arg:tt)*) => {
+        disabled!($($arg)*)
+    };
+}
+
+#[cfg(not(feature = "tracing"))]
+macro_rules! error {
+    ($($arg:tt)*) => {
+        disabled!($($arg)*)
+    };
+}
@@ -0,0 +1,473 @@
+//! Lookup of the state of mounts from `/proc/self/mountinfo`.
+
+use crate::conn::UnmountError;
+use std::{
+    convert::TryFrom,
+    ffi::{CString, OsString},
+    fmt, fs, io,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+};
+
+/// A set of flags of a mount, in the same bits as `statvfs(3)` (e.g. `libc::ST_RDONLY`).
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct MountFlags(u64);
+
+const MOUNT_FLAG_NAMES: &[(u64, &str)] = &[
+    (libc::ST_RDONLY, "ro"),
+    (libc::ST_NOSUID, "nosuid"),
+    (libc::ST_NODEV, "nodev"),
+    (libc::ST_NOEXEC, "noexec"),
+    (libc::ST_SYNCHRONOUS, "sync"),
+    (libc::ST_MANDLOCK, "mand"),
+    (libc::ST_NOATIME, "noatime"),
+    (libc::ST_NODIRATIME, "nodiratime"),
+    (libc::ST_RELATIME, "relatime"),
+];
+
+impl MountFlags {
+    /// Create a set of flags from the raw value.
+    #[inline]
+    pub const fn from_bits(bits: u64) -> Self {
+        Self(bits)
+    }
+
+    /// Return the raw value of flags.
+    #[inline]
+    pub const fn bits(self) -> u64 {
+        self.0
+    }
+
+    /// Return whether all of the specified flags are contained.
+    #[inline]
+    pub const fn contains(self, flags: u64) -> bool {
+        self.0 & flags == flags
+    }
+
+    /// Return whether the mount is read-only.
+    #[inline]
+    pub const fn read_only(self) -> bool {
+        self.contains(libc::ST_RDONLY)
+    }
+
+    fn from_options(options: &str) -> Self {
+        let bits = options.split(',').fold(0, |bits, option| {
+            MOUNT_FLAG_NAMES
+                .iter()
+                .find(|&&(_, name)| name == option)
+                .map_or(bits, |&(flag, _)| bits | flag)
+        });
+        Self(bits)
+    }
+}
+
+impl fmt::Debug for MountFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = MOUNT_FLAG_NAMES
+            .iter()
+            .filter(|&&(flag, _)| self.contains(flag))
+            .map(|&(_, name)| name)
+            .collect();
+        write!(f, "MountFlags({})", names.join(","))
+    }
+}
+
+/// The propagation of the mount and unmount events of a mount, as
+/// described in `mount_namespaces(7)`.
+///
+/// A mount created under a mountpoint is propagated to the other mount
+/// namespaces only through the peer group of its parent mount.  Inside the
+/// containers, the mounts are often private or slaves of the host, so that
+/// the filesystem mounted there is never visible from the host.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct MountPropagation {
+    peer_group: Option<u32>,
+    master: Option<u32>,
+    propagate_from: Option<u32>,
+    unbindable: bool,
+}
+
+impl MountPropagation {
+    /// Return the ID of the peer group, if the mount is shared (`shared:N`).
+    pub fn peer_group(&self) -> Option<u32> {
+        self.peer_group
+    }
+
+    /// Return the ID of the peer group from which the mount receives the
+    /// events, if the mount is a slave (`master:N`).
+    pub fn master(&self) -> Option<u32> {
+        self.master
+    }
+
+    /// Return the ID of the nearest dominant peer group in this namespace
+    /// (`propagate_from:N`), if the master is not visible.
+    pub fn propagate_from(&self) -> Option<u32> {
+        self.propagate_from
+    }
+
+    /// Return whether the mount sends the events to its peers.
+    pub fn is_shared(&self) -> bool {
+        self.peer_group.is_some()
+    }
+
+    /// Return whether the mount receives the events from its master.
+    pub fn is_slave(&self) -> bool {
+        self.master.is_some()
+    }
+
+    /// Return whether the mount cannot be bind-mounted.
+    pub fn is_unbindable(&self) -> bool {
+        self.unbindable
+    }
+
+    /// Return whether the mount neither sends nor receives any events.
+    pub fn is_private(&self) -> bool {
+        !self.is_shared() && !self.is_slave() && !self.unbindable
+    }
+
+    fn from_fields<'a>(fields: impl Iterator<Item = &'a str>) -> Self {
+        let mut propagation = Self::default();
+        for field in fields {
+            match split_once(field, ":") {
+                Some(("shared", id)) => propagation.peer_group = id.parse().ok(),
+                Some(("master", id)) => propagation.master = id.parse().ok(),
+                Some(("propagate_from", id)) => propagation.propagate_from = id.parse().ok(),
+                None if field == "unbindable" => propagation.unbindable = true,
+                _ => (),
+            }
+        }
+        propagation
+    }
+}
+
+/// The propagation type of a mount changed by `Session::set_propagation`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Propagation {
+    /// Neither send nor receive the events (`MS_PRIVATE`).
+    Private,
+    /// Send the events to and receive them from the peers (`MS_SHARED`).
+    Shared,
+    /// Receive the events from the current peer group (`MS_SLAVE`).
+    Slave,
+    /// Private, and cannot be bind-mounted (`MS_UNBINDABLE`).
+    Unbindable,
+}
+
+/// Change the propagation type of the mount at `mountpoint` by `mount(2)`.
+pub(crate) fn set_propagation(mountpoint: &Path, propagation: Propagation) -> io::Result<()> {
+    let flags = match propagation {
+        Propagation::Private => libc::MS_PRIVATE,
+        Propagation::Shared => libc::MS_SHARED,
+        Propagation::Slave => libc::MS_SLAVE,
+        Propagation::Unbindable => libc::MS_UNBINDABLE,
+    };
+    let target = CString::new(mountpoint.as_os_str().as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let res = unsafe {
+        libc::mount(
+            std::ptr::null(),
+            target.as_ptr(),
+            std::ptr::null(),
+            flags,
+            std::ptr::null(),
+        )
+    };
+    if res == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Read the propagation of the FUSE filesystem mounted at `mountpoint`.
+pub(crate) fn mount_propagation(mountpoint: &Path) -> io::Result<MountPropagation> {
+    let content = fs::read_to_string("/proc/self/mountinfo")?;
+    find_mount(&content, mountpoint)
+        .map(|entry| entry.propagation)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the filesystem is not found in mountinfo",
+            )
+        })
+}
+
+/// Log that the FUSE filesystem just mounted at `mountpoint` is not
+/// propagated to the other mount namespaces, if so.
+pub(crate) fn check_visibility(mountpoint: &Path) {
+    let content = match fs::read_to_string("/proc/self/mountinfo") {
+        Ok(content) => content,
+        Err(err) => return debug!("failed to read mountinfo: {}", err),
+    };
+    if let Some(parent) = unshared_parent(&content, mountpoint) {
+        info!(
+            "the filesystem on {} is only visible in this mount namespace, \
+             since the parent mount {} is not shared ({:?})",
+            mountpoint.display(),
+            parent.mountpoint.display(),
+            parent.propagation
+        );
+    }
+}
+
+/// Return the parent of the FUSE mount at `mountpoint`, if the parent does
+/// not propagate the mount to its peers.
+fn unshared_parent<'a>(content: &'a str, mountpoint: &Path) -> Option<MountEntry<'a>> {
+    let entry = find_mount(content, mountpoint)?;
+    let parent = content
+        .lines()
+        .filter_map(parse_line)
+        .find(|parent| parent.id == entry.parent)?;
+    match parent.propagation.is_shared() {
+        true => None,
+        false => Some(parent),
+    }
+}
+
+/// Explain why `umount2(2)` on `mountpoint` failed with `EINVAL` while the
+/// connection with the device number `device` may still be alive.
+///
+/// `None` is returned if the filesystem seems to be unmounted already.
+pub(crate) fn diagnose_unmount(mountpoint: &Path, device: (u32, u32)) -> Option<UnmountError> {
+    let content = fs::read_to_string("/proc/self/mountinfo").ok()?;
+    let connected = crate::fusectl::ConnectionDir::locate(Some(device)).is_ok();
+    diagnose_unmount_in(&content, mountpoint, device, connected)
+}
+
+fn diagnose_unmount_in(
+    content: &str,
+    mountpoint: &Path,
+    device: (u32, u32),
+    connected: bool,
+) -> Option<UnmountError> {
+    let entry = content
+        .lines()
+        .rev()
+        .filter_map(parse_line)
+        .find(|entry| entry.dev == device && entry.fstype.starts_with("fuse"));
+    match entry {
+        Some(entry) if entry.mountpoint != mountpoint => {
+            Some(UnmountError::Moved(entry.mountpoint))
+        }
+        Some(..) => None,
+        None if connected => Some(UnmountError::OtherNamespace(mountpoint.to_owned())),
+        None => None,
+    }
+}
+
+/// Read the flags of the FUSE filesystem mounted at `mountpoint`.
+pub(crate) fn mount_flags(mountpoint: &Path) -> io::Result<MountFlags> {
+    let content = fs::read_to_string("/proc/self/mountinfo")?;
+    find_mount_flags(&content, mountpoint).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            "the filesystem is not found in mountinfo",
+        )
+    })
+}
+
+/// Read the device number (major, minor) of the FUSE filesystem mounted at `mountpoint`.
+///
+/// This is the `st_dev` of the files in the filesystem, obtained without
+/// calling `stat(2)` on the mountpoint, which would be sent to the
+/// filesystem itself.
+pub(crate) fn device_number(mountpoint: &Path) -> io::Result<(u32, u32)> {
+    let content = fs::read_to_string("/proc/self/mountinfo")?;
+    find_mount(&content, mountpoint)
+        .map(|entry| entry.dev)
+        .ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the filesystem is not found in mountinfo",
+            )
+        })
+}
+
+fn find_mount_flags(content: &str, mountpoint: &Path) -> Option<MountFlags> {
+    find_mount(content, mountpoint).map(|entry| entry.flags)
+}
+
+fn find_mount<'a>(content: &'a str, mountpoint: &Path) -> Option<MountEntry<'a>> {
+    // The later entries shadow the earlier ones on the same mountpoint.
+    content
+        .lines()
+        .rev()
+        .filter_map(parse_line)
+        .find(|entry| entry.mountpoint == mountpoint && entry.fstype.starts_with("fuse"))
+}
+
+struct MountEntry<'a> {
+    id: u32,
+    parent: u32,
+    dev: (u32, u32),
+    mountpoint: PathBuf,
+    fstype: &'a str,
+    flags: MountFlags,
+    propagation: MountPropagation,
+}
+
+/// Split the string at the first occurrence of `delim`, as `str::split_once`
+/// which requires Rust 1.52.
+fn split_once<'a>(s: &'a str, delim: &str) -> Option<(&'a str, &'a str)> {
+    let pos = s.find(delim)?;
+    Some((&s[..pos], &s[pos + delim.len()..]))
+}
+
+fn parse_line(line: &str) -> Option<MountEntry<'_>> {
+    // The format is described in proc(5):
+    //
+    //   36 35 98:0 /mnt1 /mnt2 rw,noatime master:1 - ext3 /dev/root rw,errors=continue
+    //
+    // The optional fields are terminated by a single hyphen.
+    let (mount, sb) = split_once(line, " - ")?;
+    let mut fields = mount.split(' ');
+    let id = fields.next()?.parse().ok()?;
+    let parent = fields.next()?.parse().ok()?;
+    let (major, minor) = split_once(fields.next()?, ":")?;
+    let dev = (major.parse().ok()?, minor.parse().ok()?);
+    let mountpoint = unescape(fields.nth(1)?);
+    let mount_options = fields.next()?;
+    let propagation = MountPropagation::from_fields(fields);
+
+    let mut fields = sb.split(' ');
+    let fstype = fields.next()?;
+    let super_options = fields.nth(1)?;
+
+    // Both of the per-mount options and the superblock options may make
+    // the mount read-only (`mount -o remount,bind,ro` and `mount -o remount,ro`).
+    let flags = MountFlags::from_options(mount_options);
+    let flags = match super_options.split(',').any(|option| option == "ro") {
+        true => MountFlags(flags.0 | libc::ST_RDONLY),
+        false => flags,
+    };
+
+    Some(MountEntry {
+        id,
+        parent,
+        dev,
+        mountpoint,
+        fstype,
+        flags,
+        propagation,
+    })
+}
+
+/// Decode the octal escapes of space, tab, newline and backslash in mountinfo.
+fn unescape(field: &str) -> PathBuf {
+    let field = field.as_bytes();
+    let mut decoded = Vec::with_capacity(field.len());
+    let mut i = 0;
+    while i < field.len() {
+        if field[i] == b'\\' && i + 4 <= field.len() {
+            let digits = &field[i + 1..i + 4];
+            if digits.iter().all(|d| (b'0'..=b'7').contains(d)) {
+                let value = digits
+                    .iter()
+                    .fold(0u32, |value, d| value * 8 + u32::from(d - b'0'));
+                if let Ok(byte) = u8::try_from(value) {
+                    decoded.push(byte);
+                    i += 4;
+                    continue;
+                }
+            }
+        }
+        decoded.push(field[i]);
+        i += 1;
+    }
+    PathBuf::from(OsString::from_vec(decoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_mountinfo() {
+        let content = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw\n\
+40 22 0:45 / /mnt/my\\040data rw,nosuid,nodev,relatime shared:30 - fuse.memfs memfs rw,user_id=1000,group_id=1000\n\
+41 22 0:46 / /mnt/other ro,nosuid - fuse foo rw,user_id=0\n\
+42 22 0:47 / /mnt/remount rw,nosuid - fuse foo ro,user_id=0\n\
+43 22 0:48 / /mnt/my\\040data rw - tmpfs tmpfs rw\n";
+
+        let flags = find_mount_flags(content, Path::new("/mnt/my data")).unwrap();
+        assert!(!flags.read_only());
+        assert!(flags.contains(libc::ST_NOSUID | libc::ST_NODEV | libc::ST_RELATIME));
+        assert!(!flags.contains(libc::ST_NOEXEC));
+
+        assert!(find_mount_flags(content, Path::new("/mnt/other"))
+            .unwrap()
+            .read_only());
+        assert!(find_mount_flags(content, Path::new("/mnt/remount"))
+            .unwrap()
+            .read_only());
+        assert!(find_mount_flags(content, Path::new("/")).is_none());
+        assert_eq!(
+            find_mount(content, Path::new("/mnt/my data")).unwrap().dev,
+            (0, 45)
+        );
+        assert!(find_mount_flags(content, Path::new("/mnt/missing")).is_none());
+
+        assert_eq!(unescape("a\\011b\\134c\\012"), Path::new("a\tb\\c\n"));
+        assert_eq!(unescape("trailing\\04"), Path::new("trailing\\04"));
+    }
+
+    fn fixture(name: &str) -> String {
+        let path = Path::new(env!("CARGO_MANIFEST_DIR"))
+            .join("testdata/mountinfo")
+            .join(name);
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn parse_propagation() {
+        let content = fixture("container");
+        let propagation = |mountpoint: &str| {
+            find_mount(&content, Path::new(mountpoint))
+                .unwrap()
+                .propagation
+        };
+
+        assert!(propagation("/data/mnt").is_private());
+        let work = propagation("/work");
+        assert_eq!((work.peer_group(), work.master()), (Some(302), Some(250)));
+        assert!(work.is_shared() && work.is_slave() && !work.is_private());
+        let shared = propagation("/shared/mnt");
+        assert_eq!(shared.propagate_from(), Some(1));
+        assert!(propagation("/moved").is_unbindable());
+        assert!(!propagation("/moved").is_private());
+    }
+
+    #[test]
+    fn detect_unshared_parent() {
+        let host = fixture("host");
+        assert!(unshared_parent(&host, Path::new("/mnt/fuse")).is_none());
+        assert!(unshared_parent(&host, Path::new("/mnt/private")).is_none());
+
+        // Neither the private nor the slave parent sends the mount outside.
+        let container = fixture("container");
+        let parent = unshared_parent(&container, Path::new("/data/mnt")).unwrap();
+        assert_eq!(parent.mountpoint, Path::new("/data"));
+        let parent = unshared_parent(&container, Path::new("/work")).unwrap();
+        assert_eq!(parent.mountpoint, Path::new("/"));
+        assert!(parent.propagation.is_slave());
+        assert!(unshared_parent(&container, Path::new("/shared/mnt")).is_none());
+    }
+
+    #[test]
+    fn diagnose_failed_unmount() {
+        let content = fixture("container");
+        assert!(diagnose_unmount_in(&content, Path::new("/work"), (0, 62), true).is_none());
+        match diagnose_unmount_in(&content, Path::new("/old"), (0, 64), true) {
+            Some(UnmountError::Moved(path)) => assert_eq!(path, Path::new("/moved")),
+            err => panic!("unexpected diagnosis: {:?}", err),
+        }
+
+        // The mount of the device is gone from this namespace.
+        match diagnose_unmount_in(&content, Path::new("/mnt"), (0, 99), true) {
+            Some(UnmountError::OtherNamespace(path)) => assert_eq!(path, Path::new("/mnt")),
+            err => panic!("unexpected diagnosis: {:?}", err),
+        }
+        assert!(diagnose_unmount_in(&content, Path::new("/mnt"), (0, 99), false).is_none());
+    }
+}
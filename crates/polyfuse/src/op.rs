@@ -1,27 +1,184 @@
-use crate::decoder::Decoder;
+mod fields;
+
+use self::fields::{Fields, Value};
+use crate::{decoder::Decoder, util::num};
 use polyfuse_kernel::*;
-use std::{convert::TryFrom, ffi::OsStr, fmt, time::Duration, u32, u64};
+use std::{convert::TryFrom, ffi::OsStr, fmt, mem, time::Duration};
 
 #[derive(Debug)]
 pub struct DecodeError {
-    inner: crate::decoder::DecodeError,
+    inner: DecodeErrorKind,
+}
+
+#[derive(Debug)]
+enum DecodeErrorKind {
+    Malformed(crate::decoder::DecodeError),
+    InvalidOffset,
+    InvalidLockType,
 }
 
 impl DecodeError {
     #[inline]
-    const fn new(inner: crate::decoder::DecodeError) -> Self {
-        Self { inner }
+    pub(crate) const fn new(inner: crate::decoder::DecodeError) -> Self {
+        Self {
+            inner: DecodeErrorKind::Malformed(inner),
+        }
+    }
+
+    #[inline]
+    const fn invalid_offset() -> Self {
+        Self {
+            inner: DecodeErrorKind::InvalidOffset,
+        }
+    }
+
+    #[inline]
+    const fn invalid_lock_type() -> Self {
+        Self {
+            inner: DecodeErrorKind::InvalidLockType,
+        }
+    }
+
+    /// Return the error number to be replied to the kernel for this request.
+    ///
+    /// A file offset that cannot be represented as `loff_t` and an unknown
+    /// type of BSD lock are reported as `EINVAL`, and the other malformed
+    /// messages as `EIO`.
+    pub fn errno(&self) -> i32 {
+        match self.inner {
+            DecodeErrorKind::Malformed(..) => libc::EIO,
+            DecodeErrorKind::InvalidOffset | DecodeErrorKind::InvalidLockType => libc::EINVAL,
+        }
     }
 }
 
 impl fmt::Display for DecodeError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "failed to decode request message")
+        match self.inner {
+            DecodeErrorKind::Malformed(ref cause) => {
+                write!(f, "failed to decode request message: {}", cause)
+            }
+            DecodeErrorKind::InvalidOffset => write!(f, "the file offset is out of range"),
+            DecodeErrorKind::InvalidLockType => write!(f, "unknown type of the BSD lock"),
+        }
     }
 }
 
 impl std::error::Error for DecodeError {}
 
+macro_rules! define_opcode {
+    ($( $Variant:ident = $RAW:ident, )*) => {
+        /// The opcode of a request message sent from the kernel.
+        ///
+        /// The variants correspond to the constants in `polyfuse_kernel::fuse_opcode`,
+        /// and `Display` prints their canonical names such as `FUSE_LOOKUP`.
+        #[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+        #[non_exhaustive]
+        pub enum Opcode {
+            $( $Variant, )*
+        }
+
+        impl Opcode {
+            /// Convert the raw opcode, or return `None` if it is unknown to this library.
+            pub fn from_raw(opcode: u32) -> Option<Self> {
+                match opcode {
+                    $( polyfuse_kernel::$RAW => Some(Self::$Variant), )*
+                    _ => None,
+                }
+            }
+
+            /// Return the raw value of this opcode.
+            pub const fn as_raw(self) -> u32 {
+                match self {
+                    $( Self::$Variant => polyfuse_kernel::$RAW, )*
+                }
+            }
+
+            /// Return the canonical name of this opcode, as defined in `<linux/fuse.h>`.
+            pub const fn name(self) -> &'static str {
+                match self {
+                    $( Self::$Variant => stringify!($RAW), )*
+                }
+            }
+        }
+    };
+}
+
+define_opcode! {
+    Lookup = FUSE_LOOKUP,
+    Forget = FUSE_FORGET,
+    Getattr = FUSE_GETATTR,
+    Setattr = FUSE_SETATTR,
+    Readlink = FUSE_READLINK,
+    Symlink = FUSE_SYMLINK,
+    Mknod = FUSE_MKNOD,
+    Mkdir = FUSE_MKDIR,
+    Unlink = FUSE_UNLINK,
+    Rmdir = FUSE_RMDIR,
+    Rename = FUSE_RENAME,
+    Link = FUSE_LINK,
+    Open = FUSE_OPEN,
+    Read = FUSE_READ,
+    Write = FUSE_WRITE,
+    Statfs = FUSE_STATFS,
+    Release = FUSE_RELEASE,
+    Fsync = FUSE_FSYNC,
+    Setxattr = FUSE_SETXATTR,
+    Getxattr = FUSE_GETXATTR,
+    Listxattr = FUSE_LISTXATTR,
+    Removexattr = FUSE_REMOVEXATTR,
+    Flush = FUSE_FLUSH,
+    Init = FUSE_INIT,
+    Opendir = FUSE_OPENDIR,
+    Readdir = FUSE_READDIR,
+    Releasedir = FUSE_RELEASEDIR,
+    Fsyncdir = FUSE_FSYNCDIR,
+    Getlk = FUSE_GETLK,
+    Setlk = FUSE_SETLK,
+    Setlkw = FUSE_SETLKW,
+    Access = FUSE_ACCESS,
+    Create = FUSE_CREATE,
+    Interrupt = FUSE_INTERRUPT,
+    Bmap = FUSE_BMAP,
+    Destroy = FUSE_DESTROY,
+    Ioctl = FUSE_IOCTL,
+    Poll = FUSE_POLL,
+    NotifyReply = FUSE_NOTIFY_REPLY,
+    BatchForget = FUSE_BATCH_FORGET,
+    Fallocate = FUSE_FALLOCATE,
+    Readdirplus = FUSE_READDIRPLUS,
+    Rename2 = FUSE_RENAME2,
+    Lseek = FUSE_LSEEK,
+    CopyFileRange = FUSE_COPY_FILE_RANGE,
+    CuseInit = CUSE_INIT,
+}
+
+impl Opcode {
+    /// Return whether the kernel expects no reply to the requests with this opcode.
+    #[inline]
+    pub const fn is_no_reply(self) -> bool {
+        matches!(self, Self::Forget | Self::BatchForget)
+    }
+}
+
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Display the raw opcode in log messages, as `FUSE_IOCTL (39)`.
+pub(crate) struct DisplayOpcode(pub(crate) u32);
+
+impl fmt::Display for DisplayOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match Opcode::from_raw(self.0) {
+            Some(opcode) => write!(f, "{} ({})", opcode, self.0),
+            None => write!(f, "unknown ({})", self.0),
+        }
+    }
+}
+
 /// The kind of filesystem operation requested by the kernel.
 #[non_exhaustive]
 pub enum Operation<'op, T> {
@@ -38,6 +195,10 @@ pub enum Operation<'op, T> {
     Link(Link<'op>),
     Open(Open<'op>),
     Read(Read<'op>),
+    /// Write data to a file, with the payload to be written.
+    ///
+    /// The payload borrows the remaining part of the request message,
+    /// so the filesystem does not need to receive it separately.
     Write(Write<'op>, T),
     Release(Release<'op>),
     Statfs(Statfs<'op>),
@@ -60,71 +221,43 @@ pub enum Operation<'op, T> {
     Fallocate(Fallocate<'op>),
     CopyFileRange(CopyFileRange<'op>),
     Poll(Poll<'op>),
+    Ioctl(Ioctl<'op>),
 
+    /// The forgets of the lookup counts, which take no reply.
     Forget(Forgets<'op>),
     Interrupt(Interrupt<'op>),
+    /// The reply to a retrieve notification, with the retrieved data.
     NotifyReply(NotifyReply<'op>, T),
+    Destroy(Destroy<'op>),
 
-    #[doc(hidden)]
+    /// An operation unknown to this library, e.g. added in a newer kernel.
+    ///
+    /// The raw opcode and argument are available via `Request::raw_opcode`
+    /// and `Request::raw_arg`.
     Unknown,
 }
 
-impl<T> fmt::Debug for Operation<'_, T>
-where
-    T: fmt::Debug,
-{
+/// The one-line summary of the operation, e.g.
+/// `Write { ino: 5, fh: 3, offset: 4096, size: 131072, flags: 0x8002 }`.
+///
+/// The names are shown lossily, and the payloads (the written data, the
+/// values of extended attributes and the input of ioctls) are replaced by
+/// their lengths.
+impl<T> fmt::Debug for Operation<'_, T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        match self {
-            Operation::Lookup(op) => op.fmt(f),
-            Operation::Getattr(op) => op.fmt(f),
-            Operation::Setattr(op) => op.fmt(f),
-            Operation::Readlink(op) => op.fmt(f),
-            Operation::Symlink(op) => op.fmt(f),
-            Operation::Mknod(op) => op.fmt(f),
-            Operation::Mkdir(op) => op.fmt(f),
-            Operation::Unlink(op) => op.fmt(f),
-            Operation::Rmdir(op) => op.fmt(f),
-            Operation::Rename(op) => op.fmt(f),
-            Operation::Link(op) => op.fmt(f),
-            Operation::Open(op) => op.fmt(f),
-            Operation::Read(op) => op.fmt(f),
-            Operation::Release(op) => op.fmt(f),
-            Operation::Statfs(op) => op.fmt(f),
-            Operation::Fsync(op) => op.fmt(f),
-            Operation::Setxattr(op) => op.fmt(f),
-            Operation::Getxattr(op) => op.fmt(f),
-            Operation::Listxattr(op) => op.fmt(f),
-            Operation::Removexattr(op) => op.fmt(f),
-            Operation::Flush(op) => op.fmt(f),
-            Operation::Opendir(op) => op.fmt(f),
-            Operation::Readdir(op) => op.fmt(f),
-            Operation::Releasedir(op) => op.fmt(f),
-            Operation::Fsyncdir(op) => op.fmt(f),
-            Operation::Getlk(op) => op.fmt(f),
-            Operation::Setlk(op) => op.fmt(f),
-            Operation::Flock(op) => op.fmt(f),
-            Operation::Access(op) => op.fmt(f),
-            Operation::Create(op) => op.fmt(f),
-            Operation::Bmap(op) => op.fmt(f),
-            Operation::Fallocate(op) => op.fmt(f),
-            Operation::CopyFileRange(op) => op.fmt(f),
-            Operation::Poll(op) => op.fmt(f),
-            Operation::Forget(op) => op.fmt(f),
-            Operation::Interrupt(op) => op.fmt(f),
-
-            Operation::Write(op, data) => f
-                .debug_struct("Write")
-                .field("op", op)
-                .field("data", data)
-                .finish(),
-            Operation::NotifyReply(op, data) => f
-                .debug_struct("NotifyReply")
-                .field("op", op)
-                .field("data", data)
-                .finish(),
-
-            _ => f.debug_struct("Unknown").finish(),
-        }
+        fields::debug(self.summary(), f)
+    }
+}
+
+/// Serialize the summary as a map, with the name of the operation as the
+/// field `op`.  The fields are the same as `Debug`.
+#[cfg(feature = "serde")]
+impl<T> serde::Serialize for Operation<'_, T> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        fields::serialize(self.summary(), serializer)
     }
 }
 
@@ -134,9 +267,78 @@ impl<'op, T> Operation<'op, T> {
         Self::Unknown
     }
 
+    fn summary(&self) -> &dyn Fields {
+        match self {
+            Operation::Lookup(op) => op,
+            Operation::Getattr(op) => op,
+            Operation::Setattr(op) => op,
+            Operation::Readlink(op) => op,
+            Operation::Symlink(op) => op,
+            Operation::Mknod(op) => op,
+            Operation::Mkdir(op) => op,
+            Operation::Unlink(op) => op,
+            Operation::Rmdir(op) => op,
+            Operation::Rename(op) => op,
+            Operation::Link(op) => op,
+            Operation::Open(op) => op,
+            Operation::Read(op) => op,
+            Operation::Write(op, _) => op,
+            Operation::Release(op) => op,
+            Operation::Statfs(op) => op,
+            Operation::Fsync(op) => op,
+            Operation::Setxattr(op) => op,
+            Operation::Getxattr(op) => op,
+            Operation::Listxattr(op) => op,
+            Operation::Removexattr(op) => op,
+            Operation::Flush(op) => op,
+            Operation::Opendir(op) => op,
+            Operation::Readdir(op) => op,
+            Operation::Releasedir(op) => op,
+            Operation::Fsyncdir(op) => op,
+            Operation::Getlk(op) => op,
+            Operation::Setlk(op) => op,
+            Operation::Flock(op) => op,
+            Operation::Access(op) => op,
+            Operation::Create(op) => op,
+            Operation::Bmap(op) => op,
+            Operation::Fallocate(op) => op,
+            Operation::CopyFileRange(op) => op,
+            Operation::Poll(op) => op,
+            Operation::Ioctl(op) => op,
+            Operation::Forget(op) => op,
+            Operation::Interrupt(op) => op,
+            Operation::NotifyReply(op, _) => op,
+            Operation::Destroy(op) => op,
+            Operation::Unknown => &Unknown,
+        }
+    }
+
+    /// Mark the file handles of `Read` and `Write` as possibly omitted by the kernel.
+    pub(crate) fn into_stateless(mut self) -> Self {
+        match self {
+            Operation::Read(ref mut op) => op.stateless = true,
+            Operation::Write(ref mut op, ..) => op.stateless = true,
+            _ => (),
+        }
+        self
+    }
+
+    /// Mark the modes of the created nodes as not masked by the kernel,
+    /// i.e. `FUSE_DONT_MASK` has been negotiated.
+    pub(crate) fn into_dont_mask(mut self) -> Self {
+        match self {
+            Operation::Mknod(ref mut op) => op.dont_mask = true,
+            Operation::Mkdir(ref mut op) => op.dont_mask = true,
+            Operation::Create(ref mut op) => op.dont_mask = true,
+            _ => (),
+        }
+        self
+    }
+
     pub(crate) fn decode(
         header: &'op fuse_in_header,
         arg: &'op [u8],
+        ext: Extensions<'op>,
         data: T,
     ) -> Result<Self, DecodeError> {
         let mut decoder = Decoder::new(arg);
@@ -167,6 +369,8 @@ impl<'op, T> Operation<'op, T> {
                 Ok(Operation::Interrupt(Interrupt { header, arg }))
             }
 
+            Some(fuse_opcode::FUSE_DESTROY) => Ok(Operation::Destroy(Destroy { header })),
+
             Some(fuse_opcode::FUSE_NOTIFY_REPLY) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
                 Ok(Operation::NotifyReply(NotifyReply { header, arg }, data))
@@ -198,13 +402,25 @@ impl<'op, T> Operation<'op, T> {
             Some(fuse_opcode::FUSE_MKNOD) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
                 let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Mknod(Mknod { header, arg, name }))
+                Ok(Operation::Mknod(Mknod {
+                    header,
+                    arg,
+                    name,
+                    ext,
+                    dont_mask: false,
+                }))
             }
 
             Some(fuse_opcode::FUSE_MKDIR) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
                 let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Mkdir(Mkdir { header, arg, name }))
+                Ok(Operation::Mkdir(Mkdir {
+                    header,
+                    arg,
+                    name,
+                    ext,
+                    dont_mask: false,
+                }))
             }
 
             Some(fuse_opcode::FUSE_UNLINK) => {
@@ -256,13 +472,26 @@ impl<'op, T> Operation<'op, T> {
             }
 
             Some(fuse_opcode::FUSE_READ) => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
-                Ok(Operation::Read(Read { header, arg }))
+                let arg: &fuse_read_in = decoder.fetch().map_err(DecodeError::new)?;
+                num::file_offset(arg.offset).ok_or_else(DecodeError::invalid_offset)?;
+                Ok(Operation::Read(Read {
+                    header,
+                    arg,
+                    stateless: false,
+                }))
             }
 
             Some(fuse_opcode::FUSE_WRITE) => {
                 let arg: &fuse_write_in = decoder.fetch().map_err(DecodeError::new)?;
-                Ok(Operation::Write(Write { header, arg }, data))
+                num::file_offset(arg.offset).ok_or_else(DecodeError::invalid_offset)?;
+                Ok(Operation::Write(
+                    Write {
+                        header,
+                        arg,
+                        stateless: false,
+                    },
+                    data,
+                ))
             }
 
             Some(fuse_opcode::FUSE_RELEASE) => {
@@ -362,7 +591,8 @@ impl<'op, T> Operation<'op, T> {
                 if arg.lk_flags & FUSE_LK_FLOCK == 0 {
                     Ok(Operation::Setlk(Setlk { header, arg, sleep }))
                 } else {
-                    let op = convert_to_flock_op(arg.lk.typ, sleep).unwrap_or(0);
+                    let op = FlockOp::from_lock_type(arg.lk.typ, sleep)
+                        .ok_or_else(DecodeError::invalid_lock_type)?;
                     Ok(Operation::Flock(Flock { header, arg, op }))
                 }
             }
@@ -375,7 +605,13 @@ impl<'op, T> Operation<'op, T> {
             Some(fuse_opcode::FUSE_CREATE) => {
                 let arg = decoder.fetch().map_err(DecodeError::new)?;
                 let name = decoder.fetch_str().map_err(DecodeError::new)?;
-                Ok(Operation::Create(Create { header, arg, name }))
+                Ok(Operation::Create(Create {
+                    header,
+                    arg,
+                    name,
+                    ext,
+                    dont_mask: false,
+                }))
             }
 
             Some(fuse_opcode::FUSE_BMAP) => {
@@ -384,12 +620,15 @@ impl<'op, T> Operation<'op, T> {
             }
 
             Some(fuse_opcode::FUSE_FALLOCATE) => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg: &fuse_fallocate_in = decoder.fetch().map_err(DecodeError::new)?;
+                num::file_range(arg.offset, arg.length).ok_or_else(DecodeError::invalid_offset)?;
                 Ok(Operation::Fallocate(Fallocate { header, arg }))
             }
 
             Some(fuse_opcode::FUSE_COPY_FILE_RANGE) => {
-                let arg = decoder.fetch().map_err(DecodeError::new)?;
+                let arg: &fuse_copy_file_range_in = decoder.fetch().map_err(DecodeError::new)?;
+                num::file_offset(arg.off_in).ok_or_else(DecodeError::invalid_offset)?;
+                num::file_offset(arg.off_out).ok_or_else(DecodeError::invalid_offset)?;
                 Ok(Operation::CopyFileRange(CopyFileRange { header, arg }))
             }
 
@@ -398,34 +637,155 @@ impl<'op, T> Operation<'op, T> {
                 Ok(Operation::Poll(Poll { header, arg }))
             }
 
+            Some(fuse_opcode::FUSE_IOCTL) => {
+                let arg: &fuse_ioctl_in = decoder.fetch().map_err(DecodeError::new)?;
+                let input = decoder
+                    .fetch_bytes(arg.in_size as usize)
+                    .map_err(DecodeError::new)?;
+                Ok(Operation::Ioctl(Ioctl { header, arg, input }))
+            }
+
             _ => {
-                tracing::warn!("unsupported opcode: {}", header.opcode);
+                warn!("unsupported opcode: {}", DisplayOpcode(header.opcode));
                 Ok(Operation::Unknown)
             }
         }
     }
 }
 
+struct Unknown;
+
+impl Fields for Unknown {
+    fn name(&self) -> &'static str {
+        "Unknown"
+    }
+
+    fn fields(&self, _visit: &mut dyn FnMut(&'static str, Value<'_>)) {}
+}
+
+/// Implement `Debug`, and `Serialize` with the feature `serde`, from `Fields`.
+macro_rules! impl_summary {
+    ($($ty:ty),* $(,)?) => {$(
+        impl fmt::Debug for $ty {
+            fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                fields::debug(self, f)
+            }
+        }
+
+        #[cfg(feature = "serde")]
+        impl serde::Serialize for $ty {
+            fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+            where
+                S: serde::Serializer,
+            {
+                fields::serialize(self, serializer)
+            }
+        }
+    )*};
+}
+
+impl_summary! {
+    Forget, Forgets<'_>, NotifyReply<'_>, Interrupt<'_>, Destroy<'_>, Lookup<'_>, Getattr<'_>, Setattr<'_>, Readlink<'_>, Symlink<'_>, Mknod<'_>, Mkdir<'_>, Unlink<'_>, Rmdir<'_>, Rename<'_>, Link<'_>, Open<'_>, Read<'_>, Write<'_>, Release<'_>, Statfs<'_>, Fsync<'_>, Setxattr<'_>, Getxattr<'_>, Listxattr<'_>, Removexattr<'_>, Flush<'_>, Opendir<'_>, Readdir<'_>, Releasedir<'_>, Fsyncdir<'_>, Getlk<'_>, Setlk<'_>, Flock<'_>, Access<'_>, Create<'_>, Bmap<'_>, Fallocate<'_>, CopyFileRange<'_>, Poll<'_>, Ioctl<'_>
+}
+
+/// Apply the umask to the mode unless the kernel has already done so.
+fn masked_mode(mode: u32, umask: u32, dont_mask: bool) -> u32 {
+    if dont_mask {
+        mode & !(umask & 0o7777)
+    } else {
+        mode
+    }
+}
+
+/// The kernel sets the handle to zero for the files opened without `OPEN`.
+/// `stateless` is set by the session only if the inode has no file opened
+/// by `OPEN` with the handle zero, which the session counts until `RELEASE`.
 #[inline]
-fn convert_to_flock_op(lk_type: u32, sleep: bool) -> Option<u32> {
-    const F_RDLCK: u32 = libc::F_RDLCK as u32;
-    const F_WRLCK: u32 = libc::F_WRLCK as u32;
-    const F_UNLCK: u32 = libc::F_UNLCK as u32;
-
-    let mut op = match lk_type {
-        F_RDLCK => libc::LOCK_SH as u32,
-        F_WRLCK => libc::LOCK_EX as u32,
-        F_UNLCK => libc::LOCK_UN as u32,
-        _ => return None,
-    };
+fn stateless_fh(fh: u64, stateless: bool) -> Option<u64> {
+    if stateless && fh == 0 {
+        None
+    } else {
+        Some(fh)
+    }
+}
 
-    if !sleep {
-        op |= libc::LOCK_NB as u32;
+/// The operation of a BSD file lock, as the argument of [`flock(2)`][flock].
+///
+/// The kernel sends `flock(2)` as `SETLK` (or `SETLKW` if it may block)
+/// with the type of POSIX lock, which is translated back to this operation.
+///
+/// [flock]: http://man7.org/linux/man-pages/man2/flock.2.html
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+pub enum FlockOp {
+    /// Place a shared lock (`LOCK_SH`).
+    Shared { nonblocking: bool },
+
+    /// Place an exclusive lock (`LOCK_EX`).
+    Exclusive { nonblocking: bool },
+
+    /// Remove the lock held by this file (`LOCK_UN`).
+    Unlock { nonblocking: bool },
+}
+
+impl FlockOp {
+    fn from_lock_type(lk_type: u32, sleep: bool) -> Option<Self> {
+        const F_RDLCK: u32 = libc::F_RDLCK as u32;
+        const F_WRLCK: u32 = libc::F_WRLCK as u32;
+        const F_UNLCK: u32 = libc::F_UNLCK as u32;
+
+        let nonblocking = !sleep;
+        match lk_type {
+            F_RDLCK => Some(Self::Shared { nonblocking }),
+            F_WRLCK => Some(Self::Exclusive { nonblocking }),
+            F_UNLCK => Some(Self::Unlock { nonblocking }),
+            _ => None,
+        }
+    }
+
+    /// Convert the `operation` argument of `flock(2)`, composed of `LOCK_*`.
+    pub fn from_raw(op: i32) -> Option<Self> {
+        let nonblocking = op & libc::LOCK_NB != 0;
+        match op & !libc::LOCK_NB {
+            libc::LOCK_SH => Some(Self::Shared { nonblocking }),
+            libc::LOCK_EX => Some(Self::Exclusive { nonblocking }),
+            libc::LOCK_UN => Some(Self::Unlock { nonblocking }),
+            _ => None,
+        }
+    }
+
+    /// Return the `operation` argument of `flock(2)`, e.g. to lock the
+    /// backing file of a passthrough filesystem.
+    pub fn to_raw(self) -> i32 {
+        let (op, nonblocking) = match self {
+            Self::Shared { nonblocking } => (libc::LOCK_SH, nonblocking),
+            Self::Exclusive { nonblocking } => (libc::LOCK_EX, nonblocking),
+            Self::Unlock { nonblocking } => (libc::LOCK_UN, nonblocking),
+        };
+        if nonblocking {
+            op | libc::LOCK_NB
+        } else {
+            op
+        }
+    }
+
+    /// Return whether the operation fails with `EWOULDBLOCK` instead of
+    /// waiting for the conflicting lock to be released.
+    pub fn nonblocking(self) -> bool {
+        match self {
+            Self::Shared { nonblocking }
+            | Self::Exclusive { nonblocking }
+            | Self::Unlock { nonblocking } => nonblocking,
+        }
     }
-    Some(op)
 }
 
 /// The identifier for locking operations.
+///
+/// The kernel derives the owner from the file table of the process for
+/// `Read`, `Write`, `Flush` and `Release`, and from the owner of the lock
+/// for `Getlk`, `Setlk` and `Setlkw`, which is the same value for the POSIX
+/// locks.  The values can be used as the keys of the locks held by the
+/// backend, but have no meaning otherwise.
 #[repr(transparent)]
 #[derive(Copy, Clone, PartialEq, Eq, Hash)]
 pub struct LockOwner(u64);
@@ -450,14 +810,80 @@ impl LockOwner {
     }
 }
 
+/// The flags of `Fsync` and `Fsyncdir`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct FsyncFlags(u32);
+
+impl FsyncFlags {
+    /// Synchronize only the contents, as `fdatasync(2)`.
+    pub const DATASYNC: Self = Self(FUSE_FSYNC_FDATASYNC);
+
+    /// Create an empty set of flags.
+    #[inline]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Create a set of flags from the raw value.
+    #[inline]
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Return the raw value of flags.
+    #[inline]
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Return whether all of the specified flags are contained.
+    #[inline]
+    pub const fn contains(self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    /// Return whether `DATASYNC` is set.
+    #[inline]
+    pub const fn datasync(self) -> bool {
+        self.contains(Self::DATASYNC)
+    }
+
+    /// Return the bits not defined by the protocol, which are reserved for
+    /// the later kernels.
+    #[inline]
+    pub const fn unknown(self) -> u32 {
+        self.0 & !FUSE_FSYNC_FDATASYNC
+    }
+}
+
+impl std::ops::BitOr for FsyncFlags {
+    type Output = Self;
+
+    #[inline]
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for FsyncFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FsyncFlags({:#x})", self.0)
+    }
+}
+
 /// A set of forget information removed from the kernel's internal caches.
 pub struct Forgets<'op> {
     inner: ForgetsInner<'op>,
 }
 
-impl fmt::Debug for Forgets<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_set().entries(self.as_ref()).finish()
+// A batch may carry thousands of entries, so only the number is shown.
+impl Fields for Forgets<'_> {
+    fn name(&self) -> &'static str {
+        "Forgets"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("len", Value::Uint(self.len() as u64));
     }
 }
 
@@ -488,10 +914,14 @@ pub struct Forget {
     forget: fuse_forget_one,
 }
 
-impl fmt::Debug for Forget {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Forget").finish()
+impl Fields for Forget {
+    fn name(&self) -> &'static str {
+        "Forget"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("nlookup", Value::Uint(self.nlookup()));
     }
 }
 
@@ -515,10 +945,16 @@ pub struct NotifyReply<'op> {
     arg: &'op fuse_notify_retrieve_in,
 }
 
-impl fmt::Debug for NotifyReply<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("NotifyReply").finish()
+impl Fields for NotifyReply<'_> {
+    fn name(&self) -> &'static str {
+        "NotifyReply"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("unique", Value::Uint(self.unique()));
+        visit("ino", Value::Uint(self.ino()));
+        visit("offset", Value::Uint(self.offset()));
+        visit("size", Value::Uint(u64::from(self.size())));
     }
 }
 
@@ -555,10 +991,13 @@ pub struct Interrupt<'op> {
     arg: &'op fuse_interrupt_in,
 }
 
-impl fmt::Debug for Interrupt<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Interrupt").finish()
+impl Fields for Interrupt<'_> {
+    fn name(&self) -> &'static str {
+        "Interrupt"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("unique", Value::Uint(self.unique()));
     }
 }
 
@@ -570,6 +1009,144 @@ impl<'op> Interrupt<'op> {
     }
 }
 
+/// The extension blocks appended to a request message.
+///
+/// The iteration stops at the malformed block.  The extensions are sent
+/// only when ABI 7.38 or later is negotiated, which the handshake of polyfuse
+/// does not do yet, so they are always empty in a session initialized by it.
+#[derive(Clone, Copy, Default)]
+pub struct Extensions<'op> {
+    bytes: &'op [u8],
+}
+
+impl fmt::Debug for Extensions<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list().entries(*self).finish()
+    }
+}
+
+impl<'op> Extensions<'op> {
+    /// Split the extension blocks of `total_extlen` 8-byte units off the end of `arg`.
+    pub(crate) fn split(
+        arg: &'op [u8],
+        total_extlen: u16,
+    ) -> Result<(&'op [u8], Self), DecodeError> {
+        let len = usize::from(total_extlen) * 8;
+        if len > arg.len() {
+            return Err(DecodeError::new(crate::decoder::DecodeError::UnexpectedEof));
+        }
+        let (arg, bytes) = arg.split_at(arg.len() - len);
+        Ok((arg, Self { bytes }))
+    }
+
+    /// Return the supplementary groups of the calling process, if sent.
+    ///
+    /// The kernel does not send this extension until ABI 7.39 and
+    /// `FUSE_CREATE_SUPP_GROUP` are negotiated, which polyfuse does not yet.
+    pub fn supplementary_groups(self) -> Option<SupplementaryGroups<'op>> {
+        let ext = self.into_iter().find(|ext| ext.kind() == FUSE_EXT_GROUPS)?;
+        let mut decoder = Decoder::new(ext.payload);
+        let nr_groups = read_u32(decoder.fetch_bytes(4).ok()?);
+        let groups = decoder.fetch_bytes(nr_groups as usize * 4).ok()?;
+        Some(SupplementaryGroups { groups })
+    }
+}
+
+impl<'op> Iterator for Extensions<'op> {
+    type Item = Extension<'op>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.bytes.len() < mem::size_of::<fuse_ext_header>() {
+            self.bytes = &[];
+            return None;
+        }
+        // The blocks may not be aligned in the buffer.
+        let header = fuse_ext_header {
+            size: read_u32(&self.bytes[0..4]),
+            type_: read_u32(&self.bytes[4..8]),
+        };
+        let size = header.size as usize;
+        if size < mem::size_of::<fuse_ext_header>() || size > self.bytes.len() {
+            self.bytes = &[];
+            return None;
+        }
+        let (block, remaining) = self.bytes.split_at(size);
+        self.bytes = remaining;
+        Some(Extension {
+            kind: header.type_,
+            payload: &block[mem::size_of::<fuse_ext_header>()..],
+        })
+    }
+}
+
+/// An extension block appended to a request message.
+#[derive(Debug, Clone, Copy)]
+pub struct Extension<'op> {
+    kind: u32,
+    payload: &'op [u8],
+}
+
+impl<'op> Extension<'op> {
+    /// Return the type of this extension, such as `FUSE_EXT_GROUPS`.
+    #[inline]
+    pub fn kind(&self) -> u32 {
+        self.kind
+    }
+
+    /// Return the payload of this extension, including the padding.
+    #[inline]
+    pub fn payload(&self) -> &'op [u8] {
+        self.payload
+    }
+}
+
+/// An iterator over the supplementary group IDs carried by the request.
+#[derive(Debug, Clone)]
+pub struct SupplementaryGroups<'op> {
+    groups: &'op [u8],
+}
+
+impl Iterator for SupplementaryGroups<'_> {
+    type Item = u32;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.groups.len() < 4 {
+            return None;
+        }
+        let (gid, remaining) = self.groups.split_at(4);
+        self.groups = remaining;
+        Some(read_u32(gid))
+    }
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_ne_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+/// Clean up the filesystem, sent by the kernel at unmount.
+///
+/// The kernel does not guarantee that `Forget`s are sent for all of the
+/// inodes before unmounting, so the filesystem should treat this request
+/// as an implicit forget of all the inodes and release the resources
+/// associated with them.  The session exits after receiving this request,
+/// but the `Forget`s subsequently received are still delivered.
+///
+/// Note that this request is not sent if the connection is aborted or the
+/// filesystem is lazily unmounted while in use.  The filesystem should
+/// also release the resources when `Session::next_request` returns `None`.
+pub struct Destroy<'op> {
+    #[allow(dead_code)]
+    header: &'op fuse_in_header,
+}
+
+impl Fields for Destroy<'_> {
+    fn name(&self) -> &'static str {
+        "Destroy"
+    }
+
+    fn fields(&self, _visit: &mut dyn FnMut(&'static str, Value<'_>)) {}
+}
+
 /// Lookup a directory entry by name.
 ///
 /// If a matching entry is found, the filesystem replies to the kernel
@@ -582,10 +1159,14 @@ pub struct Lookup<'op> {
     name: &'op OsStr,
 }
 
-impl fmt::Debug for Lookup<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Lookup").finish()
+impl Fields for Lookup<'_> {
+    fn name(&self) -> &'static str {
+        "Lookup"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("parent", Value::Uint(self.parent()));
+        visit("name", Value::Name(self.name()));
     }
 }
 
@@ -612,10 +1193,16 @@ pub struct Getattr<'op> {
     arg: &'op fuse_getattr_in,
 }
 
-impl fmt::Debug for Getattr<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Getattr").finish()
+impl Fields for Getattr<'_> {
+    fn name(&self) -> &'static str {
+        "Getattr"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        if let Some(fh) = self.fh() {
+            visit("fh", Value::Uint(fh));
+        }
     }
 }
 
@@ -644,10 +1231,37 @@ pub struct Setattr<'op> {
     arg: &'op fuse_setattr_in,
 }
 
-impl fmt::Debug for Setattr<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Setattr").finish()
+impl Fields for Setattr<'_> {
+    fn name(&self) -> &'static str {
+        "Setattr"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        if let Some(fh) = self.fh() {
+            visit("fh", Value::Uint(fh));
+        }
+        if let Some(mode) = self.mode() {
+            visit("mode", Value::Octal(mode));
+        }
+        if let Some(uid) = self.uid() {
+            visit("uid", Value::Uint(u64::from(uid)));
+        }
+        if let Some(gid) = self.gid() {
+            visit("gid", Value::Uint(u64::from(gid)));
+        }
+        if let Some(size) = self.size() {
+            visit("size", Value::Uint(size));
+        }
+        if let Some(atime) = self.atime() {
+            visit("atime", Value::Debug(&atime));
+        }
+        if let Some(mtime) = self.mtime() {
+            visit("mtime", Value::Debug(&mtime));
+        }
+        if let Some(ctime) = self.ctime() {
+            visit("ctime", Value::Debug(&ctime));
+        }
     }
 }
 
@@ -655,7 +1269,7 @@ impl<'op> Setattr<'op> {
     #[inline(always)]
     fn get<R>(&self, flag: u32, f: impl FnOnce(&fuse_setattr_in) -> R) -> Option<R> {
         if self.arg.valid & flag != 0 {
-            Some(f(&self.arg))
+            Some(f(self.arg))
         } else {
             None
         }
@@ -745,14 +1359,21 @@ pub enum SetAttrTime {
 }
 
 /// Read a symbolic link.
+///
+/// The length of the link value must be less than `PATH_MAX`.  Otherwise,
+/// `ENAMETOOLONG` is sent to the kernel instead of the reply and the
+/// error is returned to the caller.
 pub struct Readlink<'op> {
     header: &'op fuse_in_header,
 }
 
-impl fmt::Debug for Readlink<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Readlink").finish()
+impl Fields for Readlink<'_> {
+    fn name(&self) -> &'static str {
+        "Readlink"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
     }
 }
 
@@ -774,10 +1395,15 @@ pub struct Symlink<'op> {
     link: &'op OsStr,
 }
 
-impl fmt::Debug for Symlink<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Symlink").finish()
+impl Fields for Symlink<'_> {
+    fn name(&self) -> &'static str {
+        "Symlink"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("parent", Value::Uint(self.parent()));
+        visit("name", Value::Name(self.name()));
+        visit("link", Value::Name(self.link()));
     }
 }
 
@@ -809,12 +1435,21 @@ pub struct Mknod<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_mknod_in,
     name: &'op OsStr,
+    ext: Extensions<'op>,
+    dont_mask: bool,
 }
 
-impl fmt::Debug for Mknod<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Mknod").finish()
+impl Fields for Mknod<'_> {
+    fn name(&self) -> &'static str {
+        "Mknod"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("parent", Value::Uint(self.parent()));
+        visit("name", Value::Name(self.name()));
+        visit("mode", Value::Octal(self.mode()));
+        visit("rdev", Value::Uint(u64::from(self.rdev())));
+        visit("umask", Value::Octal(self.umask()));
     }
 }
 
@@ -832,8 +1467,23 @@ impl<'op> Mknod<'op> {
     }
 
     /// Return the file type and permissions used when creating the new file.
+    ///
+    /// The umask of the calling process has already been applied, either
+    /// by the kernel or, if `KernelConfig::dont_mask` is negotiated, by
+    /// this method, so the filesystem must not apply it again.  Use
+    /// `raw_mode` and `umask` to apply it differently, e.g. when the
+    /// parent directory has the default ACL.
     #[inline]
     pub fn mode(&self) -> u32 {
+        masked_mode(self.arg.mode, self.arg.umask, self.dont_mask)
+    }
+
+    /// Return the mode sent by the kernel.
+    ///
+    /// This is the same as `mode` unless `KernelConfig::dont_mask` is
+    /// negotiated, in which case the umask is not applied yet.
+    #[inline]
+    pub fn raw_mode(&self) -> u32 {
         self.arg.mode
     }
 
@@ -846,10 +1496,97 @@ impl<'op> Mknod<'op> {
         self.arg.rdev
     }
 
-    #[doc(hidden)] // TODO: dox
+    /// Return the type of the node to create, derived from `mode` and `rdev`.
+    ///
+    /// `None` is returned for the file types that `mknod(2)` does not
+    /// create, which the filesystem should reply `EINVAL`.
+    ///
+    /// The device nodes on a mount by an unprivileged user are unusable,
+    /// since `fusermount` always mounts with `nodev`, and creating them
+    /// would not be allowed on the backend either.  Such filesystems
+    /// should reply `EPERM` to `NodeType::CharDevice` and
+    /// `NodeType::BlockDevice` unless the process itself is privileged.
+    pub fn node_type(&self) -> Option<NodeType> {
+        NodeType::from_raw(self.arg.mode, self.arg.rdev)
+    }
+
+    /// Return the umask of the calling process.
+    #[inline]
     pub fn umask(&self) -> u32 {
         self.arg.umask
     }
+
+    /// Return the supplementary groups of the calling process.
+    ///
+    /// The value is available only if the kernel sends the extension
+    /// of supplementary groups, which requires ABI 7.39 or later and the
+    /// `FUSE_CREATE_SUPP_GROUP` flag.  Since polyfuse negotiates up to ABI
+    /// 7.31 and does not request the flag yet, this method currently always
+    /// returns `None`; use `Request::caller_supplementary_groups` instead.
+    pub fn supplementary_groups(&self) -> Option<SupplementaryGroups<'op>> {
+        self.ext.supplementary_groups()
+    }
+}
+
+/// The type of the node created by `Mknod`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum NodeType {
+    /// A regular file (`S_IFREG`), sent when `CREATE` is not implemented.
+    Regular,
+
+    /// A named pipe (`S_IFIFO`).
+    Fifo,
+
+    /// A UNIX domain socket (`S_IFSOCK`).
+    Socket,
+
+    /// A character device (`S_IFCHR`).
+    CharDevice { major: u32, minor: u32 },
+
+    /// A block device (`S_IFBLK`).
+    BlockDevice { major: u32, minor: u32 },
+}
+
+impl NodeType {
+    fn from_raw(mode: u32, rdev: u32) -> Option<Self> {
+        let (major, minor) = decode_dev(rdev);
+        match mode & libc::S_IFMT {
+            libc::S_IFREG => Some(Self::Regular),
+            libc::S_IFIFO => Some(Self::Fifo),
+            libc::S_IFSOCK => Some(Self::Socket),
+            libc::S_IFCHR => Some(Self::CharDevice { major, minor }),
+            libc::S_IFBLK => Some(Self::BlockDevice { major, minor }),
+            _ => None,
+        }
+    }
+
+    /// Return whether the node is a character or block device.
+    pub fn is_device(&self) -> bool {
+        matches!(self, Self::CharDevice { .. } | Self::BlockDevice { .. })
+    }
+
+    /// Return the device number in the encoding of `st_rdev`, i.e. the
+    /// value of `makedev(3)`, or 0 if the node is not a device.
+    pub fn rdev(&self) -> u64 {
+        match *self {
+            Self::CharDevice { major, minor } | Self::BlockDevice { major, minor } => {
+                // `makedev` is an unsafe function in the older versions of libc.
+                #[allow(unused_unsafe)]
+                let rdev = unsafe { libc::makedev(major, minor) };
+                rdev as u64
+            }
+            _ => 0,
+        }
+    }
+}
+
+/// Decode a device number in the 32-bit format used by the kernel
+/// (`new_decode_dev`), which is the inverse of `reply::encode_dev`.
+fn decode_dev(dev: u32) -> (u32, u32) {
+    let major = (dev & 0xfff00) >> 8;
+    let minor = (dev & 0xff) | ((dev >> 12) & 0xfff00);
+    (major, minor)
 }
 
 /// Create a directory node.
@@ -860,12 +1597,20 @@ pub struct Mkdir<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_mkdir_in,
     name: &'op OsStr,
+    ext: Extensions<'op>,
+    dont_mask: bool,
 }
 
-impl fmt::Debug for Mkdir<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Mkdir").finish()
+impl Fields for Mkdir<'_> {
+    fn name(&self) -> &'static str {
+        "Mkdir"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("parent", Value::Uint(self.parent()));
+        visit("name", Value::Name(self.name()));
+        visit("mode", Value::Octal(self.mode()));
+        visit("umask", Value::Octal(self.umask()));
     }
 }
 
@@ -883,15 +1628,42 @@ impl<'op> Mkdir<'op> {
     }
 
     /// Return the file type and permissions used when creating the new directory.
+    ///
+    /// The umask of the calling process has already been applied, either
+    /// by the kernel or, if `KernelConfig::dont_mask` is negotiated, by
+    /// this method, so the filesystem must not apply it again.  Use
+    /// `raw_mode` and `umask` to apply it differently, e.g. when the
+    /// parent directory has the default ACL.
     #[inline]
     pub fn mode(&self) -> u32 {
+        masked_mode(self.arg.mode, self.arg.umask, self.dont_mask)
+    }
+
+    /// Return the mode sent by the kernel.
+    ///
+    /// This is the same as `mode` unless `KernelConfig::dont_mask` is
+    /// negotiated, in which case the umask is not applied yet.
+    #[inline]
+    pub fn raw_mode(&self) -> u32 {
         self.arg.mode
     }
 
-    #[doc(hidden)] // TODO: dox
+    /// Return the umask of the calling process.
+    #[inline]
     pub fn umask(&self) -> u32 {
         self.arg.umask
     }
+
+    /// Return the supplementary groups of the calling process.
+    ///
+    /// The value is available only if the kernel sends the extension
+    /// of supplementary groups, which requires ABI 7.39 or later and the
+    /// `FUSE_CREATE_SUPP_GROUP` flag.  Since polyfuse negotiates up to ABI
+    /// 7.31 and does not request the flag yet, this method currently always
+    /// returns `None`; use `Request::caller_supplementary_groups` instead.
+    pub fn supplementary_groups(&self) -> Option<SupplementaryGroups<'op>> {
+        self.ext.supplementary_groups()
+    }
 }
 
 // TODO: description about lookup count.
@@ -902,10 +1674,14 @@ pub struct Unlink<'op> {
     name: &'op OsStr,
 }
 
-impl fmt::Debug for Unlink<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Unlink").finish()
+impl Fields for Unlink<'_> {
+    fn name(&self) -> &'static str {
+        "Unlink"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("parent", Value::Uint(self.parent()));
+        visit("name", Value::Name(self.name()));
     }
 }
 
@@ -929,10 +1705,14 @@ pub struct Rmdir<'op> {
     name: &'op OsStr,
 }
 
-impl fmt::Debug for Rmdir<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Rmdir").finish()
+impl Fields for Rmdir<'_> {
+    fn name(&self) -> &'static str {
+        "Rmdir"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("parent", Value::Uint(self.parent()));
+        visit("name", Value::Name(self.name()));
     }
 }
 
@@ -965,10 +1745,17 @@ enum RenameArg<'op> {
     V2(&'op fuse_rename2_in),
 }
 
-impl fmt::Debug for Rename<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Rename").finish()
+impl Fields for Rename<'_> {
+    fn name(&self) -> &'static str {
+        "Rename"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("parent", Value::Uint(self.parent()));
+        visit("name", Value::Name(self.name()));
+        visit("newparent", Value::Uint(self.newparent()));
+        visit("newname", Value::Name(self.newname()));
+        visit("flags", Value::Hex(u64::from(self.flags())));
     }
 }
 
@@ -1020,10 +1807,15 @@ pub struct Link<'op> {
     newname: &'op OsStr,
 }
 
-impl fmt::Debug for Link<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Link").finish()
+impl Fields for Link<'_> {
+    fn name(&self) -> &'static str {
+        "Link"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("newparent", Value::Uint(self.newparent()));
+        visit("newname", Value::Name(self.newname()));
     }
 }
 
@@ -1061,10 +1853,14 @@ pub struct Open<'op> {
     arg: &'op fuse_open_in,
 }
 
-impl fmt::Debug for Open<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Open").finish()
+impl Fields for Open<'_> {
+    fn name(&self) -> &'static str {
+        "Open"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("flags", Value::Hex(u64::from(self.flags())));
     }
 }
 
@@ -1107,12 +1903,22 @@ impl<'op> Open<'op> {
 pub struct Read<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_read_in,
+    stateless: bool,
 }
 
-impl fmt::Debug for Read<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Read").finish()
+impl Fields for Read<'_> {
+    fn name(&self) -> &'static str {
+        "Read"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        if let Some(fh) = self.fh() {
+            visit("fh", Value::Uint(fh));
+        }
+        visit("offset", Value::Uint(self.offset()));
+        visit("size", Value::Uint(u64::from(self.size())));
+        visit("flags", Value::Hex(u64::from(self.flags())));
     }
 }
 
@@ -1124,12 +1930,20 @@ impl<'op> Read<'op> {
     }
 
     /// Return the handle of opened file.
+    ///
+    /// `None` is returned if the file was opened without sending `OPEN`,
+    /// i.e. the kernel supports `FUSE_NO_OPEN_SUPPORT` and the filesystem
+    /// has replied `ENOSYS` to an `OPEN` request.  In this mode, the
+    /// filesystem should read the file by its inode number.
+    /// See `Session::stateless_io` for details.
     #[inline]
-    pub fn fh(&self) -> u64 {
-        self.arg.fh
+    pub fn fh(&self) -> Option<u64> {
+        stateless_fh(self.arg.fh, self.stateless)
     }
 
     /// Return the starting position of the content to be read.
+    ///
+    /// The value is guaranteed to be within `0..=i64::MAX`.
     #[inline]
     pub fn offset(&self) -> u64 {
         self.arg.offset
@@ -1147,10 +1961,27 @@ impl<'op> Read<'op> {
         self.arg.flags
     }
 
-    /// Return the identifier of lock owner.
+    /// Return whether the kernel has set `FUSE_READ_LOCKOWNER`, i.e. the
+    /// request carries the lock owner of the caller.
+    ///
+    /// The kernel sets it only for the reads on behalf of a process, i.e.
+    /// the direct I/O reads.  The reads to fill the page cache (including
+    /// readahead) are shared by the processes, and never carry the owner.
+    #[inline]
+    pub fn has_lock_owner(&self) -> bool {
+        self.arg.read_flags & FUSE_READ_LOCKOWNER != 0
+    }
+
+    /// Return the identifier of lock owner, if `has_lock_owner` is true.
+    ///
+    /// The value is the same key as the owner of the POSIX locks taken by
+    /// the caller (`Getlk`, `Setlk` and `Setlkw`) and as `Flush::lock_owner`,
+    /// so a backend enforcing mandatory locks can check the access against
+    /// the locks held by the other owners.  `None` is returned without the
+    /// flag, even though the field in the request has some value.
     #[inline]
     pub fn lock_owner(&self) -> Option<LockOwner> {
-        if self.arg.read_flags & FUSE_READ_LOCKOWNER != 0 {
+        if self.has_lock_owner() {
             Some(LockOwner::from_raw(self.arg.lock_owner))
         } else {
             None
@@ -1171,12 +2002,22 @@ impl<'op> Read<'op> {
 pub struct Write<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_write_in,
+    stateless: bool,
 }
 
-impl fmt::Debug for Write<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Write").finish()
+impl Fields for Write<'_> {
+    fn name(&self) -> &'static str {
+        "Write"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        if let Some(fh) = self.fh() {
+            visit("fh", Value::Uint(fh));
+        }
+        visit("offset", Value::Uint(self.offset()));
+        visit("size", Value::Uint(u64::from(self.size())));
+        visit("flags", Value::Hex(u64::from(self.flags())));
     }
 }
 
@@ -1188,12 +2029,17 @@ impl<'op> Write<'op> {
     }
 
     /// Return the handle of opened file.
+    ///
+    /// As with `Read::fh`, `None` is returned if the file was opened
+    /// without sending `OPEN`.
     #[inline]
-    pub fn fh(&self) -> u64 {
-        self.arg.fh
+    pub fn fh(&self) -> Option<u64> {
+        stateless_fh(self.arg.fh, self.stateless)
     }
 
     /// Return the starting position of contents to be written.
+    ///
+    /// The value is guaranteed to be within `0..=i64::MAX`.
     #[inline]
     pub fn offset(&self) -> u64 {
         self.arg.offset
@@ -1211,10 +2057,23 @@ impl<'op> Write<'op> {
         self.arg.flags
     }
 
-    /// Return the identifier of lock owner.
+    /// Return whether the kernel has set `FUSE_WRITE_LOCKOWNER`, i.e. the
+    /// request carries the lock owner of the caller.
+    ///
+    /// As with `Read::has_lock_owner`, it is set only for the direct I/O
+    /// writes.  The writes through the page cache (e.g. the writeback with
+    /// `KernelConfig::writeback_cache`) never carry the owner.
+    #[inline]
+    pub fn has_lock_owner(&self) -> bool {
+        self.arg.write_flags & FUSE_WRITE_LOCKOWNER != 0
+    }
+
+    /// Return the identifier of lock owner, if `has_lock_owner` is true.
+    ///
+    /// See `Read::lock_owner` for the relation to the other operations.
     #[inline]
     pub fn lock_owner(&self) -> Option<LockOwner> {
-        if self.arg.write_flags & FUSE_WRITE_LOCKOWNER != 0 {
+        if self.has_lock_owner() {
             Some(LockOwner::from_raw(self.arg.lock_owner))
         } else {
             None
@@ -1228,10 +2087,17 @@ pub struct Release<'op> {
     arg: &'op fuse_release_in,
 }
 
-impl fmt::Debug for Release<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Release").finish()
+impl Fields for Release<'_> {
+    fn name(&self) -> &'static str {
+        "Release"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("flags", Value::Hex(u64::from(self.flags())));
+        visit("flush", Value::Bool(self.flush()));
+        visit("flock_release", Value::Bool(self.flock_release()));
     }
 }
 
@@ -1280,10 +2146,13 @@ pub struct Statfs<'op> {
     header: &'op fuse_in_header,
 }
 
-impl fmt::Debug for Statfs<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Statfs").finish()
+impl Fields for Statfs<'_> {
+    fn name(&self) -> &'static str {
+        "Statfs"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
     }
 }
 
@@ -1301,10 +2170,15 @@ pub struct Fsync<'op> {
     arg: &'op fuse_fsync_in,
 }
 
-impl fmt::Debug for Fsync<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Fsync").finish()
+impl Fields for Fsync<'_> {
+    fn name(&self) -> &'static str {
+        "Fsync"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("datasync", Value::Bool(self.datasync()));
     }
 }
 
@@ -1321,12 +2195,18 @@ impl<'op> Fsync<'op> {
         self.arg.fh
     }
 
+    /// Return the flags of this request.
+    #[inline]
+    pub fn flags(&self) -> FsyncFlags {
+        FsyncFlags::from_bits(self.arg.fsync_flags)
+    }
+
     /// Return whether to synchronize only the file contents.
     ///
     /// When this method returns `true`, the metadata does not have to be flushed.
     #[inline]
     pub fn datasync(&self) -> bool {
-        self.arg.fsync_flags & FUSE_FSYNC_FDATASYNC != 0
+        self.flags().datasync()
     }
 }
 
@@ -1338,10 +2218,16 @@ pub struct Setxattr<'op> {
     value: &'op [u8],
 }
 
-impl fmt::Debug for Setxattr<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Setxattr").finish()
+impl Fields for Setxattr<'_> {
+    fn name(&self) -> &'static str {
+        "Setxattr"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("name", Value::Name(self.name()));
+        visit("value_len", Value::Uint(self.value().len() as u64));
+        visit("flags", Value::Hex(u64::from(self.flags())));
     }
 }
 
@@ -1381,17 +2267,24 @@ impl<'op> Setxattr<'op> {
 ///
 /// * Otherwise, returns the attribute value with the specified name.
 ///   The filesystem should send an `ERANGE` error if the specified
-///   size is too small for the attribute value.
+///   size is too small for the attribute value.  If the reply exceeds
+///   `size`, `ERANGE` is sent to the kernel instead of the reply and the
+///   error is returned to the caller.
 pub struct Getxattr<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_getxattr_in,
     name: &'op OsStr,
 }
 
-impl fmt::Debug for Getxattr<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Getxattr").finish()
+impl Fields for Getxattr<'_> {
+    fn name(&self) -> &'static str {
+        "Getxattr"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("name", Value::Name(self.name()));
+        visit("size", Value::Uint(u64::from(self.size())));
     }
 }
 
@@ -1419,16 +2312,21 @@ impl<'op> Getxattr<'op> {
 ///
 /// Each element of the attribute names list must be null-terminated.
 /// As with `Getxattr`, the filesystem must send the data length of the attribute
-/// names using `ReplyXattr` if `size` is zero.
+/// names using `ReplyXattr` if `size` is zero.  The length limitation of
+/// replied value is the same as `Getxattr`.
 pub struct Listxattr<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_getxattr_in,
 }
 
-impl fmt::Debug for Listxattr<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Listxattr").finish()
+impl Fields for Listxattr<'_> {
+    fn name(&self) -> &'static str {
+        "Listxattr"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("size", Value::Uint(u64::from(self.size())));
     }
 }
 
@@ -1452,10 +2350,14 @@ pub struct Removexattr<'op> {
     name: &'op OsStr,
 }
 
-impl fmt::Debug for Removexattr<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Removexattr").finish()
+impl Fields for Removexattr<'_> {
+    fn name(&self) -> &'static str {
+        "Removexattr"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("name", Value::Name(self.name()));
     }
 }
 
@@ -1488,10 +2390,14 @@ pub struct Flush<'op> {
     arg: &'op fuse_flush_in,
 }
 
-impl fmt::Debug for Flush<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Flush").finish()
+impl Fields for Flush<'_> {
+    fn name(&self) -> &'static str {
+        "Flush"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
     }
 }
 
@@ -1524,10 +2430,14 @@ pub struct Opendir<'op> {
     arg: &'op fuse_open_in,
 }
 
-impl fmt::Debug for Opendir<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Opendir").finish()
+impl Fields for Opendir<'_> {
+    fn name(&self) -> &'static str {
+        "Opendir"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("flags", Value::Hex(u64::from(self.flags())));
     }
 }
 
@@ -1558,10 +2468,17 @@ pub enum ReaddirMode {
     Plus,
 }
 
-impl fmt::Debug for Readdir<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Readdir").finish()
+impl Fields for Readdir<'_> {
+    fn name(&self) -> &'static str {
+        "Readdir"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("offset", Value::Uint(self.offset()));
+        visit("size", Value::Uint(u64::from(self.size())));
+        visit("mode", Value::Debug(&self.mode()));
     }
 }
 
@@ -1579,6 +2496,11 @@ impl<'op> Readdir<'op> {
     }
 
     /// Return the *offset* value to continue reading the directory stream.
+    ///
+    /// The value is an opaque cookie previously replied by the filesystem
+    /// and is passed through without any range checks. Filesystems that use
+    /// signed positions (e.g. the result of `telldir(3)`) should convert it
+    /// in a way that round-trips all of the `u64` values.
     #[inline]
     pub fn offset(&self) -> u64 {
         self.arg.offset
@@ -1601,10 +2523,15 @@ pub struct Releasedir<'op> {
     arg: &'op fuse_release_in,
 }
 
-impl fmt::Debug for Releasedir<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Releasedir").finish()
+impl Fields for Releasedir<'_> {
+    fn name(&self) -> &'static str {
+        "Releasedir"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("flags", Value::Hex(u64::from(self.flags())));
     }
 }
 
@@ -1634,10 +2561,15 @@ pub struct Fsyncdir<'op> {
     arg: &'op fuse_fsync_in,
 }
 
-impl fmt::Debug for Fsyncdir<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Fsyncdir").finish()
+impl Fields for Fsyncdir<'_> {
+    fn name(&self) -> &'static str {
+        "Fsyncdir"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("datasync", Value::Bool(self.datasync()));
     }
 }
 
@@ -1654,12 +2586,18 @@ impl<'op> Fsyncdir<'op> {
         self.arg.fh
     }
 
+    /// Return the flags of this request.
+    #[inline]
+    pub fn flags(&self) -> FsyncFlags {
+        FsyncFlags::from_bits(self.arg.fsync_flags)
+    }
+
     /// Return whether to synchronize only the directory contents.
     ///
     /// When this method returns `true`, the metadata does not have to be flushed.
     #[inline]
     pub fn datasync(&self) -> bool {
-        self.arg.fsync_flags & FUSE_FSYNC_FDATASYNC != 0
+        self.flags().datasync()
     }
 }
 
@@ -1671,10 +2609,18 @@ pub struct Getlk<'op> {
     arg: &'op fuse_lk_in,
 }
 
-impl fmt::Debug for Getlk<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Getlk").finish()
+impl Fields for Getlk<'_> {
+    fn name(&self) -> &'static str {
+        "Getlk"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("typ", Value::Uint(u64::from(self.typ())));
+        visit("start", Value::Uint(self.start()));
+        visit("end", Value::Uint(self.end()));
+        visit("pid", Value::Uint(u64::from(self.pid())));
     }
 }
 
@@ -1725,10 +2671,19 @@ pub struct Setlk<'op> {
     sleep: bool,
 }
 
-impl fmt::Debug for Setlk<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Setlk").finish()
+impl Fields for Setlk<'_> {
+    fn name(&self) -> &'static str {
+        "Setlk"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("typ", Value::Uint(u64::from(self.typ())));
+        visit("start", Value::Uint(self.start()));
+        visit("end", Value::Uint(self.end()));
+        visit("pid", Value::Uint(u64::from(self.pid())));
+        visit("sleep", Value::Bool(self.sleep()));
     }
 }
 
@@ -1782,13 +2737,18 @@ impl<'op> Setlk<'op> {
 pub struct Flock<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_lk_in,
-    op: u32,
+    op: FlockOp,
 }
 
-impl fmt::Debug for Flock<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Flock").finish()
+impl Fields for Flock<'_> {
+    fn name(&self) -> &'static str {
+        "Flock"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("op", Value::Debug(&self.op));
     }
 }
 
@@ -1813,12 +2773,11 @@ impl<'op> Flock<'op> {
 
     /// Return the locking operation.
     ///
-    /// See [`flock(2)`][flock] for details.
-    ///
-    /// [flock]: http://man7.org/linux/man-pages/man2/flock.2.html
+    /// The requests with an unknown type of lock fail to decode, and are
+    /// replied `EINVAL` by `DecodeError::errno`.
     #[inline]
-    pub fn op(&self) -> Option<u32> {
-        Some(self.op)
+    pub fn op(&self) -> FlockOp {
+        self.op
     }
 }
 
@@ -1828,10 +2787,14 @@ pub struct Access<'op> {
     arg: &'op fuse_access_in,
 }
 
-impl fmt::Debug for Access<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Access").finish()
+impl Fields for Access<'_> {
+    fn name(&self) -> &'static str {
+        "Access"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("mask", Value::Octal(self.mask()));
     }
 }
 
@@ -1860,12 +2823,21 @@ pub struct Create<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_create_in,
     name: &'op OsStr,
+    ext: Extensions<'op>,
+    dont_mask: bool,
 }
 
-impl fmt::Debug for Create<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Create").finish()
+impl Fields for Create<'_> {
+    fn name(&self) -> &'static str {
+        "Create"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("parent", Value::Uint(self.parent()));
+        visit("name", Value::Name(self.name()));
+        visit("mode", Value::Octal(self.mode()));
+        visit("open_flags", Value::Hex(u64::from(self.open_flags())));
+        visit("umask", Value::Octal(self.umask()));
     }
 }
 
@@ -1891,6 +2863,14 @@ impl<'op> Create<'op> {
     /// This is the same as `Mknod::mode`.
     #[inline]
     pub fn mode(&self) -> u32 {
+        masked_mode(self.arg.mode, self.arg.umask, self.dont_mask)
+    }
+
+    /// Return the mode sent by the kernel.
+    ///
+    /// This is the same as `Mknod::raw_mode`.
+    #[inline]
+    pub fn raw_mode(&self) -> u32 {
         self.arg.mode
     }
 
@@ -1902,11 +2882,24 @@ impl<'op> Create<'op> {
         self.arg.flags
     }
 
-    #[doc(hidden)] // TODO: dox
+    /// Return the umask of the calling process.
+    ///
+    /// This is the same as `Mknod::umask`.
     #[inline]
     pub fn umask(&self) -> u32 {
         self.arg.umask
     }
+
+    /// Return the supplementary groups of the calling process.
+    ///
+    /// The value is available only if the kernel sends the extension
+    /// of supplementary groups, which requires ABI 7.39 or later and the
+    /// `FUSE_CREATE_SUPP_GROUP` flag.  Since polyfuse negotiates up to ABI
+    /// 7.31 and does not request the flag yet, this method currently always
+    /// returns `None`; use `Request::caller_supplementary_groups` instead.
+    pub fn supplementary_groups(&self) -> Option<SupplementaryGroups<'op>> {
+        self.ext.supplementary_groups()
+    }
 }
 
 /// Map block index within a file to block index within device.
@@ -1921,10 +2914,15 @@ pub struct Bmap<'op> {
     arg: &'op fuse_bmap_in,
 }
 
-impl fmt::Debug for Bmap<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Bmap").finish()
+impl Fields for Bmap<'_> {
+    fn name(&self) -> &'static str {
+        "Bmap"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("block", Value::Uint(self.block()));
+        visit("blocksize", Value::Uint(u64::from(self.blocksize())));
     }
 }
 
@@ -1958,10 +2956,17 @@ pub struct Fallocate<'op> {
     arg: &'op fuse_fallocate_in,
 }
 
-impl fmt::Debug for Fallocate<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("Fallocate").finish()
+impl Fields for Fallocate<'_> {
+    fn name(&self) -> &'static str {
+        "Fallocate"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("offset", Value::Uint(self.offset()));
+        visit("length", Value::Uint(self.length()));
+        visit("mode", Value::Hex(u64::from(self.mode())));
     }
 }
 
@@ -1979,6 +2984,8 @@ impl<'op> Fallocate<'op> {
     }
 
     /// Return the starting point of region to be allocated.
+    ///
+    /// The end of region, `offset + length`, is guaranteed not to exceed `i64::MAX`.
     #[inline]
     pub fn offset(&self) -> u64 {
         self.arg.offset
@@ -2004,15 +3011,31 @@ impl<'op> Fallocate<'op> {
 /// Copy a range of data from an opened file to another.
 ///
 /// The length of copied data must be replied using `ReplyWrite`.
+///
+/// Both of `ino_in` and `ino_out` are the inodes of this session, since
+/// the kernel fails the copies across the mounts with `EXDEV` by itself,
+/// even if the mounts are served by the same process.  When
+/// `KernelConfig::reject_stale_inodes` is enabled, the session also
+/// replies `EXDEV` if `ino_out` is not known to it.
 pub struct CopyFileRange<'op> {
     header: &'op fuse_in_header,
     arg: &'op fuse_copy_file_range_in,
 }
 
-impl fmt::Debug for CopyFileRange<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields
-        f.debug_struct("CopyFileRange").finish()
+impl Fields for CopyFileRange<'_> {
+    fn name(&self) -> &'static str {
+        "CopyFileRange"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino_in", Value::Uint(self.ino_in()));
+        visit("fh_in", Value::Uint(self.fh_in()));
+        visit("offset_in", Value::Uint(self.offset_in()));
+        visit("ino_out", Value::Uint(self.ino_out()));
+        visit("fh_out", Value::Uint(self.fh_out()));
+        visit("offset_out", Value::Uint(self.offset_out()));
+        visit("length", Value::Uint(self.length()));
+        visit("flags", Value::Hex(self.flags()));
     }
 }
 
@@ -2030,6 +3053,8 @@ impl<'op> CopyFileRange<'op> {
     }
 
     /// Return the starting point of source file where the data should be read.
+    ///
+    /// The value is guaranteed to be within `0..=i64::MAX`.
     #[inline]
     pub fn offset_in(&self) -> u64 {
         self.arg.off_in
@@ -2048,6 +3073,8 @@ impl<'op> CopyFileRange<'op> {
     }
 
     /// Return the starting point of target file where the data should be written.
+    ///
+    /// The value is guaranteed to be within `0..=i64::MAX`.
     #[inline]
     pub fn offset_out(&self) -> u64 {
         self.arg.off_out
@@ -2074,14 +3101,18 @@ pub struct Poll<'op> {
     arg: &'op fuse_poll_in,
 }
 
-impl fmt::Debug for Poll<'_> {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Poll")
-            .field("ino", &self.ino())
-            .field("fh", &self.fh())
-            .field("events", &self.events())
-            .field("kh", &self.kh())
-            .finish()
+impl Fields for Poll<'_> {
+    fn name(&self) -> &'static str {
+        "Poll"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("events", Value::Hex(u64::from(self.events())));
+        if let Some(kh) = self.kh() {
+            visit("kh", Value::Uint(kh));
+        }
     }
 }
 
@@ -2117,3 +3148,756 @@ impl<'op> Poll<'op> {
         }
     }
 }
+
+/// Perform an `ioctl(2)` on a file or a directory.
+///
+/// Only the restricted ioctls are sent to the filesystems except CUSE, so
+/// the size of the input and output data is fixed by the command, and the
+/// input data is sent with the request.  The filesystem replies with
+/// `IoctlOut` followed by at most `out_size` bytes of the output data.
+///
+/// The layout of the argument may differ between the 64-bit and the 32-bit
+/// callers, which are told by `is_compat` and `is_32bit`.
+pub struct Ioctl<'op> {
+    header: &'op fuse_in_header,
+    arg: &'op fuse_ioctl_in,
+    input: &'op [u8],
+}
+
+impl Fields for Ioctl<'_> {
+    fn name(&self) -> &'static str {
+        "Ioctl"
+    }
+
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>)) {
+        visit("ino", Value::Uint(self.ino()));
+        visit("fh", Value::Uint(self.fh()));
+        visit("flags", Value::Hex(u64::from(self.flags())));
+        visit("cmd", Value::Hex(u64::from(self.cmd())));
+        visit("arg", Value::Hex(self.arg()));
+        visit("in_size", Value::Uint(self.input().len() as u64));
+        visit("out_size", Value::Uint(u64::from(self.out_size())));
+    }
+}
+
+impl<'op> Ioctl<'op> {
+    /// Return the inode number of the target file.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the handle of the opened file or directory.
+    #[inline]
+    pub fn fh(&self) -> u64 {
+        self.arg.fh
+    }
+
+    /// Return the raw flags of this request, such as `FUSE_IOCTL_DIR`.
+    #[inline]
+    pub fn flags(&self) -> u32 {
+        self.arg.flags
+    }
+
+    /// Return whether the target is a directory.
+    ///
+    /// Whether the kernel sends the ioctls on directories is reported by
+    /// `Session::ioctl_dir`.
+    #[inline]
+    pub fn is_dir(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_DIR != 0
+    }
+
+    /// Return whether the ioctl is issued through the 32-bit compatible
+    /// syscall on a 64-bit kernel, such as those from the 32-bit processes.
+    #[inline]
+    pub fn is_compat(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_COMPAT != 0
+    }
+
+    /// Return whether the caller is a 32-bit process, and hence the
+    /// argument uses the 32-bit layout (e.g. `FS_IOC32_GETFLAGS`).
+    #[inline]
+    pub fn is_32bit(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_32BIT != 0
+    }
+
+    /// Return whether the ioctl is unrestricted, which only CUSE receives.
+    #[inline]
+    pub fn is_unrestricted(&self) -> bool {
+        self.arg.flags & FUSE_IOCTL_UNRESTRICTED != 0
+    }
+
+    /// Return the ioctl command.
+    #[inline]
+    pub fn cmd(&self) -> u32 {
+        self.arg.cmd
+    }
+
+    /// Return the raw argument of the ioctl, the address in the caller's memory.
+    #[inline]
+    pub fn arg(&self) -> u64 {
+        self.arg.arg
+    }
+
+    /// Return the input data copied from the caller.
+    #[inline]
+    pub fn input(&self) -> &'op [u8] {
+        self.input
+    }
+
+    /// Return the maximum length of the output data.
+    #[inline]
+    pub fn out_size(&self) -> u32 {
+        self.arg.out_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn decode_ioctl() {
+        let arg = fuse_ioctl_in {
+            fh: 3,
+            flags: FUSE_IOCTL_DIR | FUSE_IOCTL_COMPAT | FUSE_IOCTL_32BIT,
+            cmd: 0x4004_6602,
+            arg: 0,
+            in_size: 4,
+            out_size: 0,
+        };
+        let mut bytes = arg.as_bytes().to_vec();
+        bytes.extend_from_slice(&0x10u32.to_ne_bytes());
+        let header = in_header(fuse_opcode::FUSE_IOCTL, bytes.len());
+        match Operation::decode(&header, &bytes[..], Extensions::default(), ()) {
+            Ok(Operation::Ioctl(op)) => {
+                assert_eq!(op.fh(), 3);
+                assert!(op.is_dir() && op.is_compat() && op.is_32bit());
+                assert!(!op.is_unrestricted());
+                assert_eq!(op.cmd(), 0x4004_6602);
+                assert_eq!(op.input(), &0x10u32.to_ne_bytes()[..]);
+            }
+            _ => panic!("incorrect operation is returned"),
+        }
+
+        let header = in_header(fuse_opcode::FUSE_IOCTL, bytes.len() - 1);
+        assert!(Operation::decode(
+            &header,
+            &bytes[..bytes.len() - 1],
+            Extensions::default(),
+            ()
+        )
+        .is_err());
+    }
+
+    #[test]
+    #[allow(clippy::unnecessary_cast)] // `dev_t` is not `u64` on every target
+    fn decode_mknod() {
+        let decode = |mode: u32, rdev: u32| {
+            let arg = fuse_mknod_in {
+                mode,
+                rdev,
+                umask: 0o022,
+                padding: 0,
+            };
+            let mut bytes = arg.as_bytes().to_vec();
+            bytes.extend_from_slice(b"node\0");
+            let header = in_header(fuse_opcode::FUSE_MKNOD, bytes.len());
+            match Operation::decode(&header, &bytes[..], Extensions::default(), ()) {
+                Ok(Operation::Mknod(op)) => {
+                    assert_eq!(op.name(), "node");
+                    op.node_type()
+                }
+                _ => panic!("incorrect operation is returned"),
+            }
+        };
+
+        assert_eq!(decode(libc::S_IFREG | 0o644, 0), Some(NodeType::Regular));
+        assert_eq!(decode(libc::S_IFIFO | 0o600, 0), Some(NodeType::Fifo));
+        assert_eq!(decode(libc::S_IFSOCK | 0o755, 0), Some(NodeType::Socket));
+        assert_eq!(decode(libc::S_IFDIR | 0o755, 0), None);
+
+        // /dev/null, and a device number beyond the old 16-bit encoding.
+        let null = decode(libc::S_IFCHR | 0o666, (1 << 8) | 3).unwrap();
+        assert_eq!(null, NodeType::CharDevice { major: 1, minor: 3 });
+        assert!(null.is_device());
+        #[allow(unused_unsafe)]
+        let (null_rdev, rdev) = unsafe { (libc::makedev(1, 3), libc::makedev(259, 0x12345)) };
+        assert_eq!(null.rdev(), null_rdev as u64);
+        let rdev = crate::reply::encode_dev(rdev as u64);
+        assert_eq!(
+            decode(libc::S_IFBLK | 0o660, rdev),
+            Some(NodeType::BlockDevice {
+                major: 259,
+                minor: 0x12345
+            })
+        );
+        assert!(!NodeType::Fifo.is_device());
+        assert_eq!(NodeType::Fifo.rdev(), 0);
+    }
+
+    #[test]
+    fn opcode_round_trip() {
+        for raw in 0..=u32::from(u16::MAX) {
+            match (Opcode::from_raw(raw), fuse_opcode::try_from(raw).ok()) {
+                (Some(opcode), Some(sys)) => {
+                    assert_eq!(opcode.as_raw(), raw);
+                    assert_eq!(sys as u32, raw);
+                }
+                (None, None) => (),
+                (opcode, _) => panic!("mismatched opcode {}: {:?}", raw, opcode),
+            }
+        }
+
+        assert_eq!(Opcode::from_raw(FUSE_IOCTL), Some(Opcode::Ioctl));
+        assert_eq!(Opcode::Ioctl.to_string(), "FUSE_IOCTL");
+        assert_eq!(Opcode::CopyFileRange.to_string(), "FUSE_COPY_FILE_RANGE");
+        assert_eq!(Opcode::CuseInit.to_string(), "CUSE_INIT");
+        assert_eq!(DisplayOpcode(FUSE_IOCTL).to_string(), "FUSE_IOCTL (39)");
+        assert_eq!(DisplayOpcode(7).to_string(), "unknown (7)");
+
+        assert!(Opcode::Forget.is_no_reply());
+        assert!(Opcode::BatchForget.is_no_reply());
+        assert!(!Opcode::Interrupt.is_no_reply());
+        assert!(!Opcode::Lookup.is_no_reply());
+    }
+
+    fn summary(opcode: fuse_opcode, parts: &[&[u8]]) -> String {
+        let arg = parts.concat();
+        let header = in_header(opcode, arg.len());
+        let op = Operation::decode(&header, &arg[..], Extensions::default(), ()).unwrap();
+        format!("{:?}", op)
+    }
+
+    #[test]
+    fn debug_summary() {
+        use fuse_opcode::*;
+
+        let notify_reply = fuse_notify_retrieve_in {
+            offset: 4096,
+            size: 10,
+            ..Default::default()
+        };
+        let setattr = fuse_setattr_in {
+            valid: FATTR_MODE | FATTR_SIZE | FATTR_MTIME | FATTR_MTIME_NOW,
+            mode: 0o644,
+            size: 10,
+            ..Default::default()
+        };
+        let mknod = fuse_mknod_in {
+            mode: libc::S_IFREG | 0o644,
+            umask: 0o022,
+            ..Default::default()
+        };
+        let mkdir = fuse_mkdir_in {
+            mode: 0o755,
+            umask: 0o022,
+        };
+        let open = fuse_open_in {
+            flags: 0x8002,
+            ..Default::default()
+        };
+        let write = fuse_write_in {
+            fh: 3,
+            offset: 4096,
+            size: 131_072,
+            flags: 0x8002,
+            ..Default::default()
+        };
+        let release = fuse_release_in {
+            fh: 3,
+            release_flags: FUSE_RELEASE_FLUSH,
+            ..Default::default()
+        };
+        let fsync = fuse_fsync_in {
+            fh: 3,
+            fsync_flags: 1,
+            ..Default::default()
+        };
+        let getxattr = fuse_getxattr_in {
+            size: 64,
+            ..Default::default()
+        };
+        let flush = fuse_flush_in {
+            fh: 3,
+            ..Default::default()
+        };
+        let lk = fuse_lk_in {
+            fh: 3,
+            lk: fuse_file_lock {
+                start: 0,
+                end: 99,
+                typ: libc::F_WRLCK as u32,
+                pid: 12,
+            },
+            ..Default::default()
+        };
+        let flock = fuse_lk_in {
+            lk_flags: FUSE_LK_FLOCK,
+            ..lk
+        };
+        let access = fuse_access_in {
+            mask: 4,
+            ..Default::default()
+        };
+        let create = fuse_create_in {
+            flags: 0x8041,
+            mode: libc::S_IFREG | 0o644,
+            umask: 0o022,
+            ..Default::default()
+        };
+        let bmap = fuse_bmap_in {
+            block: 7,
+            blocksize: 4096,
+            ..Default::default()
+        };
+        let fallocate = fuse_fallocate_in {
+            fh: 3,
+            length: 4096,
+            mode: 1,
+            ..Default::default()
+        };
+        let copy_file_range = fuse_copy_file_range_in {
+            fh_in: 3,
+            nodeid_out: 2,
+            fh_out: 4,
+            off_out: 10,
+            len: 100,
+            ..Default::default()
+        };
+        let poll = fuse_poll_in {
+            fh: 3,
+            kh: 9,
+            flags: FUSE_POLL_SCHEDULE_NOTIFY,
+            events: 1,
+        };
+        let ioctl = fuse_ioctl_in {
+            fh: 3,
+            cmd: 0x8008_6601,
+            in_size: 4,
+            out_size: 8,
+            ..Default::default()
+        };
+
+        assert_eq!(
+            summary(FUSE_FORGET, &[fuse_forget_in { nlookup: 2 }.as_bytes()]),
+            "Forgets { len: 1 }"
+        );
+        assert_eq!(
+            summary(
+                FUSE_BATCH_FORGET,
+                &[
+                    fuse_batch_forget_in { count: 3, dummy: 0 }.as_bytes(),
+                    [fuse_forget_one::default(); 3].as_bytes()
+                ]
+            ),
+            "Forgets { len: 3 }"
+        );
+        assert_eq!(
+            summary(
+                FUSE_INTERRUPT,
+                &[fuse_interrupt_in { unique: 8 }.as_bytes()]
+            ),
+            "Interrupt { unique: 8 }"
+        );
+        assert_eq!(summary(FUSE_DESTROY, &[]), "Destroy");
+        assert_eq!(
+            summary(FUSE_NOTIFY_REPLY, &[notify_reply.as_bytes()]),
+            "NotifyReply { unique: 2, ino: 1, offset: 4096, size: 10 }"
+        );
+        assert_eq!(
+            summary(FUSE_LOOKUP, &[b"foo\xff\0"]),
+            "Lookup { parent: 1, name: \"foo\\xff\" }"
+        );
+        assert_eq!(
+            summary(FUSE_GETATTR, &[fuse_getattr_in::default().as_bytes()]),
+            "Getattr { ino: 1 }"
+        );
+        assert_eq!(
+            summary(FUSE_SETATTR, &[setattr.as_bytes()]),
+            "Setattr { ino: 1, mode: 0o644, size: 10, mtime: Now }"
+        );
+        assert_eq!(summary(FUSE_READLINK, &[]), "Readlink { ino: 1 }");
+        assert_eq!(
+            summary(FUSE_SYMLINK, &[b"foo\0", b"../bar\0"]),
+            "Symlink { parent: 1, name: \"foo\", link: \"../bar\" }"
+        );
+        assert_eq!(
+            summary(FUSE_MKNOD, &[mknod.as_bytes(), b"foo\0"]),
+            "Mknod { parent: 1, name: \"foo\", mode: 0o100644, rdev: 0, umask: 0o22 }"
+        );
+        assert_eq!(
+            summary(FUSE_MKDIR, &[mkdir.as_bytes(), b"foo\0"]),
+            "Mkdir { parent: 1, name: \"foo\", mode: 0o755, umask: 0o22 }"
+        );
+        assert_eq!(
+            summary(FUSE_UNLINK, &[b"foo\0"]),
+            "Unlink { parent: 1, name: \"foo\" }"
+        );
+        assert_eq!(
+            summary(FUSE_RMDIR, &[b"foo\0"]),
+            "Rmdir { parent: 1, name: \"foo\" }"
+        );
+        assert_eq!(
+            summary(
+                FUSE_RENAME,
+                &[fuse_rename_in { newdir: 2 }.as_bytes(), b"foo\0", b"bar\0"]
+            ),
+            "Rename { parent: 1, name: \"foo\", newparent: 2, newname: \"bar\", flags: 0x0 }"
+        );
+        assert_eq!(
+            summary(
+                FUSE_LINK,
+                &[fuse_link_in { oldnodeid: 2 }.as_bytes(), b"bar\0"]
+            ),
+            "Link { ino: 2, newparent: 1, newname: \"bar\" }"
+        );
+        assert_eq!(
+            summary(FUSE_OPEN, &[open.as_bytes()]),
+            "Open { ino: 1, flags: 0x8002 }"
+        );
+        assert_eq!(
+            summary(FUSE_READ, &[read_in(4096).as_bytes()]),
+            "Read { ino: 1, fh: 0, offset: 4096, size: 4096, flags: 0x0 }"
+        );
+        assert_eq!(
+            summary(FUSE_WRITE, &[write.as_bytes()]),
+            "Write { ino: 1, fh: 3, offset: 4096, size: 131072, flags: 0x8002 }"
+        );
+        assert_eq!(
+            summary(FUSE_RELEASE, &[release.as_bytes()]),
+            "Release { ino: 1, fh: 3, flags: 0x0, flush: true, flock_release: false }"
+        );
+        assert_eq!(summary(FUSE_STATFS, &[]), "Statfs { ino: 1 }");
+        assert_eq!(
+            summary(FUSE_FSYNC, &[fsync.as_bytes()]),
+            "Fsync { ino: 1, fh: 3, datasync: true }"
+        );
+        assert_eq!(
+            summary(
+                FUSE_SETXATTR,
+                &[
+                    fuse_setxattr_in { size: 5, flags: 1 }.as_bytes(),
+                    b"user.foo\0",
+                    b"value"
+                ]
+            ),
+            "Setxattr { ino: 1, name: \"user.foo\", value_len: 5, flags: 0x1 }"
+        );
+        assert_eq!(
+            summary(FUSE_GETXATTR, &[getxattr.as_bytes(), b"user.foo\0"]),
+            "Getxattr { ino: 1, name: \"user.foo\", size: 64 }"
+        );
+        assert_eq!(
+            summary(FUSE_LISTXATTR, &[getxattr.as_bytes()]),
+            "Listxattr { ino: 1, size: 64 }"
+        );
+        assert_eq!(
+            summary(FUSE_REMOVEXATTR, &[b"user.foo\0"]),
+            "Removexattr { ino: 1, name: \"user.foo\" }"
+        );
+        assert_eq!(
+            summary(FUSE_FLUSH, &[flush.as_bytes()]),
+            "Flush { ino: 1, fh: 3 }"
+        );
+        assert_eq!(
+            summary(FUSE_OPENDIR, &[fuse_open_in::default().as_bytes()]),
+            "Opendir { ino: 1, flags: 0x0 }"
+        );
+        assert_eq!(
+            summary(FUSE_READDIRPLUS, &[read_in(2).as_bytes()]),
+            "Readdir { ino: 1, fh: 0, offset: 2, size: 4096, mode: Plus }"
+        );
+        assert_eq!(
+            summary(FUSE_RELEASEDIR, &[release.as_bytes()]),
+            "Releasedir { ino: 1, fh: 3, flags: 0x0 }"
+        );
+        assert_eq!(
+            summary(FUSE_FSYNCDIR, &[fsync.as_bytes()]),
+            "Fsyncdir { ino: 1, fh: 3, datasync: true }"
+        );
+        assert_eq!(
+            summary(FUSE_GETLK, &[lk.as_bytes()]),
+            "Getlk { ino: 1, fh: 3, typ: 1, start: 0, end: 99, pid: 12 }"
+        );
+        assert_eq!(
+            summary(FUSE_SETLKW, &[lk.as_bytes()]),
+            "Setlk { ino: 1, fh: 3, typ: 1, start: 0, end: 99, pid: 12, sleep: true }"
+        );
+        assert_eq!(
+            summary(FUSE_SETLK, &[flock.as_bytes()]),
+            "Flock { ino: 1, fh: 3, op: Exclusive { nonblocking: true } }"
+        );
+        assert_eq!(
+            summary(FUSE_ACCESS, &[access.as_bytes()]),
+            "Access { ino: 1, mask: 0o4 }"
+        );
+        assert_eq!(
+            summary(FUSE_CREATE, &[create.as_bytes(), b"foo\0"]),
+            "Create { parent: 1, name: \"foo\", mode: 0o100644, open_flags: 0x8041, umask: 0o22 }"
+        );
+        assert_eq!(
+            summary(FUSE_BMAP, &[bmap.as_bytes()]),
+            "Bmap { ino: 1, block: 7, blocksize: 4096 }"
+        );
+        assert_eq!(
+            summary(FUSE_FALLOCATE, &[fallocate.as_bytes()]),
+            "Fallocate { ino: 1, fh: 3, offset: 0, length: 4096, mode: 0x1 }"
+        );
+        assert_eq!(
+            summary(FUSE_COPY_FILE_RANGE, &[copy_file_range.as_bytes()]),
+            "CopyFileRange { ino_in: 1, fh_in: 3, offset_in: 0, ino_out: 2, fh_out: 4, offset_out: 10, length: 100, flags: 0x0 }"
+        );
+        assert_eq!(
+            summary(FUSE_POLL, &[poll.as_bytes()]),
+            "Poll { ino: 1, fh: 3, events: 0x1, kh: 9 }"
+        );
+        assert_eq!(
+            summary(FUSE_IOCTL, &[ioctl.as_bytes(), &[0u8; 4]]),
+            "Ioctl { ino: 1, fh: 3, flags: 0x0, cmd: 0x80086601, arg: 0x0, in_size: 4, out_size: 8 }"
+        );
+        assert_eq!(summary(FUSE_LSEEK, &[]), "Unknown");
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn serialize_summary() {
+        let arg = fuse_write_in {
+            fh: 3,
+            offset: 4096,
+            size: 131_072,
+            ..Default::default()
+        };
+        let header = in_header(fuse_opcode::FUSE_WRITE, mem::size_of_val(&arg));
+        let op = Operation::decode(&header, arg.as_bytes(), Extensions::default(), ()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&op).unwrap(),
+            r#"{"op":"Write","ino":1,"fh":3,"offset":4096,"size":131072,"flags":0}"#
+        );
+
+        let header = in_header(fuse_opcode::FUSE_LOOKUP, 5);
+        let op = Operation::decode(&header, b"foo\xff\0", Extensions::default(), ()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&op).unwrap(),
+            "{\"op\":\"Lookup\",\"parent\":1,\"name\":\"foo\\\\xff\"}"
+        );
+    }
+
+    #[test]
+    fn decode_flock() {
+        let (session, kernel) = crate::testing::session(crate::KernelConfig::default()).unwrap();
+        let flock = |opcode: fuse_opcode, typ: i32| {
+            let arg = fuse_lk_in {
+                fh: 3,
+                lk_flags: FUSE_LK_FLOCK,
+                lk: fuse_file_lock {
+                    typ: typ as u32,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            kernel
+                .send_request(opcode as u32, 2, arg.as_bytes())
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            match req.operation() {
+                Ok(Operation::Flock(op)) => Ok(op.op()),
+                Ok(..) => panic!("incorrect operation is returned"),
+                Err(err) => Err(err.errno()),
+            }
+        };
+
+        use fuse_opcode::{FUSE_SETLK, FUSE_SETLKW};
+        let cases = [
+            (FUSE_SETLKW, libc::F_RDLCK, libc::LOCK_SH),
+            (FUSE_SETLK, libc::F_RDLCK, libc::LOCK_SH | libc::LOCK_NB),
+            (FUSE_SETLKW, libc::F_WRLCK, libc::LOCK_EX),
+            (FUSE_SETLK, libc::F_WRLCK, libc::LOCK_EX | libc::LOCK_NB),
+            (FUSE_SETLKW, libc::F_UNLCK, libc::LOCK_UN),
+            (FUSE_SETLK, libc::F_UNLCK, libc::LOCK_UN | libc::LOCK_NB),
+        ];
+        for &(opcode, typ, raw) in &cases {
+            let op = flock(opcode, typ).unwrap();
+            assert_eq!(op.to_raw(), raw);
+            assert_eq!(op.nonblocking(), opcode == FUSE_SETLK);
+            assert_eq!(FlockOp::from_raw(raw), Some(op));
+        }
+        assert_eq!(
+            flock(FUSE_SETLK, libc::F_WRLCK),
+            Ok(FlockOp::Exclusive { nonblocking: true })
+        );
+
+        // An unknown type of lock is rejected with EINVAL rather than EIO.
+        assert_eq!(flock(FUSE_SETLK, 42), Err(libc::EINVAL));
+        assert_eq!(FlockOp::from_raw(libc::LOCK_NB), None);
+    }
+
+    fn in_header(opcode: fuse_opcode, arg_len: usize) -> fuse_in_header {
+        fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg_len) as u32,
+            opcode: opcode as u32,
+            unique: 2,
+            nodeid: 1,
+            uid: 100,
+            gid: 100,
+            pid: 12,
+            total_extlen: 0,
+            padding: 0,
+        }
+    }
+
+    fn read_in(offset: u64) -> fuse_read_in {
+        fuse_read_in {
+            fh: 0,
+            offset,
+            size: 4096,
+            read_flags: 0,
+            lock_owner: 0,
+            flags: 0,
+            padding: 0,
+        }
+    }
+
+    #[test]
+    fn decode_read_max_offset() {
+        let arg = read_in(i64::MAX as u64);
+        let header = in_header(fuse_opcode::FUSE_READ, mem::size_of_val(&arg));
+        match Operation::decode(&header, arg.as_bytes(), Extensions::default(), ()) {
+            Ok(Operation::Read(op)) => assert_eq!(op.offset(), i64::MAX as u64),
+            _ => panic!("incorrect operation is returned"),
+        }
+    }
+
+    #[test]
+    fn decode_lock_owner_of_read_write() {
+        let owner = LockOwner::from_raw(0xdead_beef);
+        for &flag in [0, FUSE_READ_LOCKOWNER].iter() {
+            let mut arg = read_in(0);
+            arg.read_flags = flag;
+            arg.lock_owner = owner.into_raw();
+            let header = in_header(fuse_opcode::FUSE_READ, mem::size_of_val(&arg));
+            match Operation::decode(&header, arg.as_bytes(), Extensions::default(), ()) {
+                Ok(Operation::Read(op)) => {
+                    assert_eq!(op.has_lock_owner(), flag != 0);
+                    assert_eq!(op.lock_owner(), Some(owner).filter(|_| flag != 0));
+                }
+                _ => panic!("incorrect operation is returned"),
+            }
+        }
+
+        for &flag in [0, FUSE_WRITE_LOCKOWNER, FUSE_WRITE_CACHE].iter() {
+            let arg = fuse_write_in {
+                fh: 3,
+                size: 4,
+                write_flags: flag,
+                lock_owner: owner.into_raw(),
+                ..Default::default()
+            };
+            let mut bytes = arg.as_bytes().to_vec();
+            bytes.extend_from_slice(b"data");
+            let header = in_header(fuse_opcode::FUSE_WRITE, bytes.len());
+            match Operation::decode(&header, &bytes[..], Extensions::default(), ()) {
+                Ok(Operation::Write(op, _)) => {
+                    let expected = flag == FUSE_WRITE_LOCKOWNER;
+                    assert_eq!(op.has_lock_owner(), expected);
+                    assert_eq!(op.lock_owner(), Some(owner).filter(|_| expected));
+                }
+                _ => panic!("incorrect operation is returned"),
+            }
+        }
+    }
+
+    #[test]
+    fn decode_read_negative_offset() {
+        let arg = read_in(i64::MAX as u64 + 1);
+        let header = in_header(fuse_opcode::FUSE_READ, mem::size_of_val(&arg));
+        match Operation::decode(&header, arg.as_bytes(), Extensions::default(), ()) {
+            Err(err) => assert_eq!(err.errno(), libc::EINVAL),
+            Ok(..) => panic!("the offset should be rejected"),
+        }
+    }
+
+    #[test]
+    fn decode_fallocate_overflow() {
+        let arg = fuse_fallocate_in {
+            fh: 0,
+            offset: 1,
+            length: i64::MAX as u64,
+            mode: 0,
+            padding: 0,
+        };
+        let header = in_header(fuse_opcode::FUSE_FALLOCATE, mem::size_of_val(&arg));
+        match Operation::decode(&header, arg.as_bytes(), Extensions::default(), ()) {
+            Err(err) => assert_eq!(err.errno(), libc::EINVAL),
+            Ok(..) => panic!("the range should be rejected"),
+        }
+    }
+
+    fn ext_block(kind: u32, payload: &[u8]) -> Vec<u8> {
+        let size = mem::size_of::<fuse_ext_header>() + payload.len();
+        let size = size + (8 - size % 8) % 8;
+        let header = fuse_ext_header {
+            size: size as u32,
+            type_: kind,
+        };
+        let mut block = header.as_bytes().to_vec();
+        block.extend_from_slice(payload);
+        block.resize(size, 0);
+        block
+    }
+
+    #[test]
+    fn decode_mkdir_supplementary_groups() {
+        let mut groups = fuse_supp_groups { nr_groups: 2 }.as_bytes().to_vec();
+        groups.extend_from_slice(&10u32.to_ne_bytes());
+        groups.extend_from_slice(&20u32.to_ne_bytes());
+
+        let mut ext = ext_block(0, b"secctx");
+        ext.extend(ext_block(FUSE_EXT_GROUPS, &groups[..]));
+        assert_eq!(ext.len() % 8, 0);
+
+        let mut arg = fuse_mkdir_in::default().as_bytes().to_vec();
+        arg.extend_from_slice(b"foo\0");
+        arg.extend_from_slice(&ext[..]);
+        let mut header = in_header(fuse_opcode::FUSE_MKDIR, arg.len());
+        header.total_extlen = (ext.len() / 8) as u16;
+
+        let (arg, ext) = Extensions::split(&arg[..], header.total_extlen).unwrap();
+        let kinds: Vec<u32> = ext.map(|ext| ext.kind()).collect();
+        assert_eq!(kinds, vec![0, FUSE_EXT_GROUPS]);
+
+        match Operation::decode(&header, arg, ext, ()) {
+            Ok(Operation::Mkdir(op)) => {
+                assert_eq!(op.name(), "foo");
+                let groups: Vec<u32> = op.supplementary_groups().unwrap().collect();
+                assert_eq!(groups, vec![10, 20]);
+            }
+            _ => panic!("incorrect operation is returned"),
+        }
+    }
+
+    #[test]
+    fn decode_malformed_extensions() {
+        assert!(Extensions::split(&[0u8; 8], 2).is_err());
+
+        // The size of block exceeds the remaining bytes.
+        let mut ext = ext_block(FUSE_EXT_GROUPS, &[0u8; 8]);
+        ext[0..4].copy_from_slice(&32u32.to_ne_bytes());
+        let (_, mut ext) = Extensions::split(&ext[..], 2).unwrap();
+        assert!(ext.next().is_none());
+        assert!(ext.supplementary_groups().is_none());
+    }
+
+    #[test]
+    fn decode_readdir_offset_passthrough() {
+        let arg = read_in(u64::MAX);
+        let header = in_header(fuse_opcode::FUSE_READDIR, mem::size_of_val(&arg));
+        match Operation::decode(&header, arg.as_bytes(), Extensions::default(), ()) {
+            Ok(Operation::Readdir(op)) => assert_eq!(op.offset(), u64::MAX),
+            _ => panic!("incorrect operation is returned"),
+        }
+    }
+}
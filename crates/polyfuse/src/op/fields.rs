@@ -0,0 +1,87 @@
+//! The summary of the operations, shared by `Debug` and `Serialize`.
+//!
+//! Only the fixed-size fields and the names are shown, and the payloads
+//! such as the written data or the values of extended attributes are
+//! replaced by their lengths, so that the output of a request is always
+//! a short line.
+
+use crate::util::DisplayName;
+use std::{ffi::OsStr, fmt};
+
+/// A field of the summary.
+pub(super) enum Value<'a> {
+    Uint(u64),
+    Bool(bool),
+    /// Shown in octal, e.g. the file modes.
+    Octal(u32),
+    /// Shown in hexadecimal, e.g. the flags.
+    Hex(u64),
+    /// Shown by `DisplayName`, with the invalid UTF-8 bytes escaped.
+    Name(&'a OsStr),
+    Debug(&'a dyn fmt::Debug),
+}
+
+impl fmt::Debug for Value<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Value::Uint(n) => fmt::Display::fmt(&n, f),
+            Value::Bool(b) => fmt::Display::fmt(&b, f),
+            Value::Octal(n) => write!(f, "{:#o}", n),
+            Value::Hex(n) => write!(f, "{:#x}", n),
+            Value::Name(name) => fmt::Debug::fmt(&DisplayName::new(name), f),
+            Value::Debug(value) => value.fmt(f),
+        }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl serde::Serialize for Value<'_> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        match *self {
+            Value::Uint(n) | Value::Hex(n) => serializer.serialize_u64(n),
+            Value::Bool(b) => serializer.serialize_bool(b),
+            Value::Octal(n) => serializer.serialize_u32(n),
+            Value::Name(name) => serializer.collect_str(&DisplayName::new(name)),
+            Value::Debug(value) => serializer.collect_str(&format_args!("{:?}", value)),
+        }
+    }
+}
+
+/// The operations summarized by the fields.
+pub(super) trait Fields {
+    fn name(&self) -> &'static str;
+
+    /// Visit the fields in order.  The absent optional values are skipped.
+    fn fields(&self, visit: &mut dyn FnMut(&'static str, Value<'_>));
+}
+
+pub(super) fn debug(op: &dyn Fields, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    let mut s = f.debug_struct(op.name());
+    op.fields(&mut |name, value| {
+        s.field(name, &value);
+    });
+    s.finish()
+}
+
+/// Serialize the operation as a map, with its name as the field `op`.
+#[cfg(feature = "serde")]
+pub(super) fn serialize<S>(op: &dyn Fields, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    use serde::ser::SerializeMap as _;
+
+    let mut map = serializer.serialize_map(None)?;
+    map.serialize_entry("op", op.name())?;
+    let mut result = Ok(());
+    op.fields(&mut |name, value| {
+        if result.is_ok() {
+            result = map.serialize_entry(name, &value);
+        }
+    });
+    result?;
+    map.end()
+}
@@ -0,0 +1,715 @@
+//! The protocol core of FUSE, without any I/O.
+//!
+//! `Session` reads the request messages from the FUSE device and writes the
+//! replies to it, but encoding and decoding the messages does not depend on
+//! the device.  This module exposes that part over plain byte buffers, so
+//! that other transports (e.g. the queues of virtio-fs) or the tools
+//! replaying a captured stream of requests can reuse the protocol without
+//! `Session`:
+//!
+//! * `Handshake` negotiates the parameters of the connection on `INIT`, as
+//!   a state machine that is fed with the request messages and emits the
+//!   replies to be written.
+//! * `RawRequest` parses a request message and decodes its `Operation`.
+//! * `encode_reply` and `encode_error` serialize the replies.
+//!
+//! `Session` is built on the same code, so the bytes produced here are
+//! identical to the ones it writes for the same input.
+
+use crate::{
+    bytes::{self, Bytes, FillBytes},
+    conn::MountOptions,
+    decoder::{self, Decoder},
+    op::{DecodeError, DisplayOpcode, Extensions, Opcode, Operation},
+    session::{pagesize, KernelConfig, SessionState},
+};
+use polyfuse_kernel::*;
+use std::{
+    cmp,
+    collections::VecDeque,
+    convert::{TryFrom as _, TryInto as _},
+    fmt, io, mem,
+    time::Instant,
+};
+use zerocopy::AsBytes as _;
+
+// The minimum supported ABI minor version by polyfuse.
+pub(crate) const MINIMUM_SUPPORTED_MINOR_VERSION: u32 = 23;
+
+// The maximum number of messages received before the handshake is given up.
+const MAX_INIT_ATTEMPTS: usize = 10;
+
+pub(crate) const INIT_FLAGS_MASK: u32 = FUSE_ASYNC_READ
+    | FUSE_ATOMIC_O_TRUNC
+    | FUSE_AUTO_INVAL_DATA
+    | FUSE_ASYNC_DIO
+    | FUSE_PARALLEL_DIROPS
+    | FUSE_HANDLE_KILLPRIV
+    | FUSE_POSIX_LOCKS
+    | FUSE_FLOCK_LOCKS
+    | FUSE_EXPORT_SUPPORT
+    | FUSE_DONT_MASK
+    | FUSE_WRITEBACK_CACHE
+    | FUSE_POSIX_ACL
+    | FUSE_DO_READDIRPLUS
+    | FUSE_READDIRPLUS_AUTO
+    | FUSE_ABORT_ERROR;
+
+/// A request received before the initialization, or put off while the
+/// background admission is paused, with the time of arrival.
+pub(crate) type EarlyRequest = (fuse_in_header, Vec<u8>, Instant);
+
+// ==== requests ====
+
+/// A request message written by the kernel.
+pub struct RawRequest {
+    header: fuse_in_header,
+    arg: Vec<u8>,
+}
+
+impl fmt::Debug for RawRequest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawRequest")
+            .field("unique", &self.unique())
+            .field(
+                "opcode",
+                &format_args!("{}", DisplayOpcode(self.header.opcode)),
+            )
+            .field("ino", &self.ino())
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl RawRequest {
+    /// Parse the request message at the start of `bytes`.
+    ///
+    /// The message may be followed by the next ones, e.g. in a captured
+    /// stream of requests; they are left untouched, and `len` returns the
+    /// offset of the next message.
+    pub fn parse(bytes: &[u8]) -> Result<Self, DecodeError> {
+        let header_len = mem::size_of::<fuse_in_header>();
+        if bytes.len() < header_len {
+            return Err(DecodeError::new(decoder::DecodeError::UnexpectedEof));
+        }
+        let mut header = fuse_in_header::default();
+        header.as_bytes_mut().copy_from_slice(&bytes[..header_len]);
+        let len = header.len as usize;
+        if len < header_len || len > bytes.len() {
+            return Err(DecodeError::new(decoder::DecodeError::UnexpectedEof));
+        }
+        // The argument is copied so that it is aligned regardless of the
+        // position of the message in `bytes`.
+        Ok(Self::from_parts(header, bytes[header_len..len].to_vec()))
+    }
+
+    pub(crate) fn from_parts(header: fuse_in_header, arg: Vec<u8>) -> Self {
+        Self { header, arg }
+    }
+
+    /// Return the length of the message, including the header.
+    #[allow(clippy::len_without_is_empty)]
+    pub fn len(&self) -> usize {
+        self.header.len as usize
+    }
+
+    /// Return the unique ID of the request.
+    pub fn unique(&self) -> u64 {
+        self.header.unique
+    }
+
+    /// Return the user ID of the calling process.
+    pub fn uid(&self) -> u32 {
+        self.header.uid
+    }
+
+    /// Return the group ID of the calling process.
+    pub fn gid(&self) -> u32 {
+        self.header.gid
+    }
+
+    /// Return the process ID of the calling process.
+    pub fn pid(&self) -> u32 {
+        self.header.pid
+    }
+
+    /// Return the inode number targeted by the request.
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the opcode of the request, or `None` if it is unknown to this library.
+    pub fn opcode(&self) -> Option<Opcode> {
+        Opcode::from_raw(self.header.opcode)
+    }
+
+    /// Return the raw opcode of the request.
+    pub fn raw_opcode(&self) -> u32 {
+        self.header.opcode
+    }
+
+    /// Return the raw argument bytes of the request, i.e. everything after the header.
+    pub fn raw_arg(&self) -> &[u8] {
+        &self.arg[..]
+    }
+
+    /// Decode the argument of the request on the connection negotiated as `state`.
+    ///
+    /// The data of `WRITE` and `NOTIFY_REPLY` is returned as a byte slice.
+    pub fn operation<'op>(
+        &'op self,
+        state: &SessionState,
+    ) -> Result<Operation<'op, &'op [u8]>, DecodeError> {
+        let (arg, ext, data) = split_arg(&self.header, &self.arg[..], state.minor())?;
+        let op = Operation::decode(&self.header, arg, ext, data)?;
+        if state.granted().contains(FUSE_DONT_MASK) {
+            return Ok(op.into_dont_mask());
+        }
+        Ok(op)
+    }
+}
+
+/// Split the argument of a request into the fixed part, the extension
+/// blocks and the trailing data of `WRITE` and `NOTIFY_REPLY`.
+///
+/// The extensions are recognized only on ABI 7.38 or later.
+pub(crate) fn split_arg<'op>(
+    header: &fuse_in_header,
+    arg: &'op [u8],
+    minor: u32,
+) -> Result<(&'op [u8], Extensions<'op>, &'op [u8]), DecodeError> {
+    let (arg, ext) = split_extensions(header, arg, minor)?;
+    let (arg, data) = match fuse_opcode::try_from(header.opcode).ok() {
+        Some(fuse_opcode::FUSE_WRITE) | Some(fuse_opcode::FUSE_NOTIFY_REPLY) => {
+            arg.split_at(mem::size_of::<fuse_write_in>().min(arg.len()))
+        }
+        _ => (arg, &[] as &[_]),
+    };
+    Ok((arg, ext, data))
+}
+
+/// Split the extension blocks off the argument of a request, on ABI 7.38 or later.
+pub(crate) fn split_extensions<'op>(
+    header: &fuse_in_header,
+    arg: &'op [u8],
+    minor: u32,
+) -> Result<(&'op [u8], Extensions<'op>), DecodeError> {
+    if minor < 38 {
+        return Ok((arg, Extensions::default()));
+    }
+    Extensions::split(arg, header.total_extlen)
+}
+
+// ==== replies ====
+
+/// Serialize the reply message to the request with `unique`.
+pub fn encode_reply<T>(unique: u64, arg: T) -> Vec<u8>
+where
+    T: Bytes,
+{
+    bytes::to_vec(&Reply::new(unique, 0, arg))
+}
+
+/// Serialize the error reply to the request with `unique`.
+///
+/// `errno` is a positive error number, e.g. `libc::ENOENT`.
+pub fn encode_error(unique: u64, errno: i32) -> Vec<u8> {
+    bytes::to_vec(&Reply::new(unique, errno, ()))
+}
+
+pub(crate) struct Reply<T> {
+    pub(crate) header: fuse_out_header,
+    arg: T,
+}
+
+impl<T> Reply<T>
+where
+    T: Bytes,
+{
+    #[inline]
+    pub(crate) fn new(unique: u64, error: i32, arg: T) -> Self {
+        let len = (mem::size_of::<fuse_out_header>() + arg.size())
+            .try_into()
+            .expect("Argument size is too large");
+        Self {
+            header: fuse_out_header {
+                len,
+                error: -error,
+                unique,
+            },
+            arg,
+        }
+    }
+}
+
+impl<T> Bytes for Reply<T>
+where
+    T: Bytes,
+{
+    #[inline]
+    fn size(&self) -> usize {
+        self.header.len as usize
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        self.arg.count() + 1
+    }
+
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        dst.put(self.header.as_bytes());
+        self.arg.fill_bytes(dst);
+    }
+}
+
+// ==== handshake ====
+
+/// The state machine of the `INIT` handshake.
+///
+/// Each request message read from the kernel is passed to `feed`, and the
+/// replies queued by it are written back in the order returned by `emit`.
+/// The handshake is complete when the kernel accepts the reply to `INIT`,
+/// and then `state` returns the negotiated parameters.
+///
+/// The requests sent before `INIT` are queued up to
+/// `KernelConfig::max_early_requests`, and available by `early_requests`;
+/// the others are replied with `EIO`.  The handshake fails with
+/// `io::ErrorKind::ConnectionRefused` if it has not been completed within
+/// ten messages, in which case the replies queued until then should still
+/// be written.
+pub struct Handshake {
+    init_out: fuse_init_out,
+    init_in: Option<fuse_init_in>,
+    max_early_requests: usize,
+    early_requests: VecDeque<EarlyRequest>,
+    replies: VecDeque<Vec<u8>>,
+    received: usize,
+}
+
+impl fmt::Debug for Handshake {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Handshake")
+            .field("complete", &self.is_complete())
+            .field("received", &self.received)
+            .field("early_requests", &self.early_requests.len())
+            .field("replies", &self.replies.len())
+            .finish()
+    }
+}
+
+impl Handshake {
+    /// Start a handshake proposing the parameters in `config`.
+    pub fn new(config: &KernelConfig) -> Self {
+        let mut init_out = config.init_out;
+        clamp_init_out(&mut init_out, &config.mountopts);
+        Self::with_init_out(init_out, config.max_early_requests)
+    }
+
+    pub(crate) fn with_init_out(init_out: fuse_init_out, max_early_requests: usize) -> Self {
+        Self {
+            init_out,
+            init_in: None,
+            max_early_requests,
+            early_requests: VecDeque::new(),
+            replies: VecDeque::new(),
+            received: 0,
+        }
+    }
+
+    /// Return whether the handshake has been completed.
+    pub fn is_complete(&self) -> bool {
+        self.init_in.is_some()
+    }
+
+    /// Return the negotiated parameters, or `None` if the handshake is not completed.
+    pub fn state(&self) -> Option<SessionState> {
+        self.init_in
+            .map(|init_in| SessionState::new(init_in, self.init_out))
+    }
+
+    /// Return the requests queued before `INIT`, in the order received.
+    pub fn early_requests(&self) -> impl Iterator<Item = RawRequest> + '_ {
+        self.early_requests
+            .iter()
+            .map(|(header, arg, _)| RawRequest::from_parts(*header, arg.clone()))
+    }
+
+    /// Take the next reply message to be written to the kernel.
+    pub fn emit(&mut self) -> Option<Vec<u8>> {
+        self.replies.pop_front()
+    }
+
+    /// Process a request message read from the kernel.
+    pub fn feed(&mut self, msg: &[u8]) -> io::Result<()> {
+        if self.is_complete() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the handshake has already been completed",
+            ));
+        }
+        if self.received >= MAX_INIT_ATTEMPTS {
+            return Err(aborted());
+        }
+        self.received += 1;
+
+        let header_len = mem::size_of::<fuse_in_header>();
+        if msg.len() < header_len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "request message is too short",
+            ));
+        }
+        let mut header = fuse_in_header::default();
+        header.as_bytes_mut().copy_from_slice(&msg[..header_len]);
+        // Copy the argument out to align it with the FUSE argument types.
+        let arg = msg[header_len..].to_vec();
+
+        self.process(header, arg)?;
+        if !self.is_complete() && self.received >= MAX_INIT_ATTEMPTS {
+            return Err(aborted());
+        }
+        Ok(())
+    }
+
+    fn process(&mut self, header: fuse_in_header, arg: Vec<u8>) -> io::Result<()> {
+        let mut decoder = Decoder::new(&arg[..]);
+        match fuse_opcode::try_from(header.opcode) {
+            Ok(fuse_opcode::FUSE_INIT) => {
+                #[allow(clippy::io_other_error)] // `io::Error::other` requires Rust 1.74
+                let init_in = decoder
+                    .fetch::<fuse_init_in>() //
+                    .map_err(|_| {
+                        io::Error::new(io::ErrorKind::Other, "failed to decode fuse_init_in")
+                    })?;
+                self.negotiate(header.unique, init_in);
+            }
+
+            // These requests must not be replied.
+            Ok(fuse_opcode::FUSE_FORGET)
+            | Ok(fuse_opcode::FUSE_BATCH_FORGET)
+            | Ok(fuse_opcode::FUSE_INTERRUPT) => {
+                debug!(
+                    "discard an operation before init (opcode = {})",
+                    DisplayOpcode(header.opcode)
+                );
+            }
+
+            _ if self.early_requests.len() < self.max_early_requests => {
+                debug!(
+                    "queue an operation before init (opcode = {})",
+                    DisplayOpcode(header.opcode)
+                );
+                self.early_requests.push_back((header, arg, Instant::now()));
+            }
+
+            _ => {
+                warn!(
+                    "ignoring an operation before init (opcode = {})",
+                    DisplayOpcode(header.opcode)
+                );
+                self.replies
+                    .push_back(encode_error(header.unique, libc::EIO));
+            }
+        }
+        Ok(())
+    }
+
+    fn negotiate(&mut self, unique: u64, init_in: &fuse_init_in) {
+        let init_out = &mut self.init_out;
+        let capable = init_in.flags & INIT_FLAGS_MASK;
+        let readonly_flags = init_in.flags & !INIT_FLAGS_MASK;
+
+        debug!("INIT request:");
+        debug!("  proto = {}.{}:", init_in.major, init_in.minor);
+        debug!("  flags = 0x{:08x} ({:?})", init_in.flags, capable);
+        debug!("  max_readahead = 0x{:08X}", init_in.max_readahead);
+        debug!("  max_pages = {}", readonly_flags & FUSE_MAX_PAGES != 0);
+        debug!(
+            "  no_open_support = {}",
+            readonly_flags & FUSE_NO_OPEN_SUPPORT != 0
+        );
+        debug!(
+            "  no_opendir_support = {}",
+            readonly_flags & FUSE_NO_OPENDIR_SUPPORT != 0
+        );
+
+        if init_in.major > 7 {
+            debug!("wait for a second INIT request with an older version.");
+            let init_out = fuse_init_out {
+                major: FUSE_KERNEL_VERSION,
+                minor: FUSE_KERNEL_MINOR_VERSION,
+                ..Default::default()
+            };
+            self.replies
+                .push_back(encode_reply(unique, init_out.as_bytes()));
+            return;
+        }
+
+        if init_in.major < 7 || init_in.minor < MINIMUM_SUPPORTED_MINOR_VERSION {
+            warn!(
+                "polyfuse supports only ABI 7.{} or later. {}.{} is not supported",
+                MINIMUM_SUPPORTED_MINOR_VERSION, init_in.major, init_in.minor
+            );
+            self.replies.push_back(encode_error(unique, libc::EPROTO));
+            return;
+        }
+
+        init_out.minor = cmp::min(init_out.minor, init_in.minor);
+
+        init_out.max_readahead = cmp::min(init_out.max_readahead, init_in.max_readahead);
+
+        init_out.flags &= capable;
+        init_out.flags |= FUSE_BIG_WRITES; // the flag was superseded by `max_write`.
+        if init_out.flags & FUSE_DO_READDIRPLUS == 0 {
+            init_out.flags &= !FUSE_READDIRPLUS_AUTO;
+        }
+
+        if init_in.flags & FUSE_MAX_PAGES != 0 {
+            init_out.flags |= FUSE_MAX_PAGES;
+            init_out.max_pages = cmp::min(
+                (init_out.max_write - 1) / (pagesize() as u32) + 1,
+                u16::MAX as u32,
+            ) as u16;
+        }
+
+        debug_assert_eq!(init_out.major, FUSE_KERNEL_VERSION);
+        debug_assert!(init_out.minor >= MINIMUM_SUPPORTED_MINOR_VERSION);
+
+        debug!("Reply to INIT:");
+        debug!("  proto = {}.{}:", init_out.major, init_out.minor);
+        debug!("  flags = 0x{:08x}", init_out.flags);
+        debug!("  max_readahead = 0x{:08X}", init_out.max_readahead);
+        debug!("  max_write = 0x{:08X}", init_out.max_write);
+        debug!("  max_background = 0x{:04X}", init_out.max_background);
+        debug!(
+            "  congestion_threshold = 0x{:04X}",
+            init_out.congestion_threshold
+        );
+        debug!("  time_gran = {}", init_out.time_gran);
+        self.replies
+            .push_back(encode_reply(unique, init_out.as_bytes()));
+
+        init_out.flags |= readonly_flags;
+        self.init_in = Some(*init_in);
+    }
+
+    pub(crate) fn into_parts(self) -> (fuse_init_in, fuse_init_out, VecDeque<EarlyRequest>) {
+        let init_in = self.init_in.expect("the handshake is not completed");
+        (init_in, self.init_out, self.early_requests)
+    }
+}
+
+fn aborted() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::ConnectionRefused,
+        "session initialization is aborted",
+    )
+}
+
+/// Clamp the parameters of `INIT` reply to the limits specified by the mount options.
+pub(crate) fn clamp_init_out(init_out: &mut fuse_init_out, mountopts: &MountOptions) {
+    if let Some(max_read) = mountopts.max_read {
+        if init_out.max_readahead > max_read {
+            warn!(
+                "max_readahead (= {}) exceeds the mount option max_read (= {}); clamped",
+                init_out.max_readahead, max_read
+            );
+            init_out.max_readahead = max_read;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        conn::Connection,
+        reply::{AttrOut, EntryOut, WriteOut},
+        Session,
+    };
+    use std::{io::prelude::*, time::Duration};
+
+    fn message(opcode: fuse_opcode, unique: u64, nodeid: u64, arg: &[&[u8]]) -> Vec<u8> {
+        let arg_len: usize = arg.iter().map(|chunk| chunk.len()).sum();
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg_len) as u32,
+            opcode: opcode as u32,
+            unique,
+            nodeid,
+            uid: 1000,
+            gid: 1000,
+            pid: 42,
+            total_extlen: 0,
+            padding: 0,
+        };
+        let mut msg = header.as_bytes().to_vec();
+        for chunk in arg {
+            msg.extend_from_slice(chunk);
+        }
+        msg
+    }
+
+    fn init_message(unique: u64, major: u32, minor: u32) -> Vec<u8> {
+        let init_in = fuse_init_in {
+            major,
+            minor,
+            max_readahead: 4096,
+            flags: INIT_FLAGS_MASK | FUSE_MAX_PAGES,
+        };
+        message(fuse_opcode::FUSE_INIT, unique, 0, &[init_in.as_bytes()])
+    }
+
+    fn respond<T: Read>(op: Operation<'_, T>) -> Result<Vec<u8>, i32> {
+        match op {
+            Operation::Lookup(op) if op.name() == "foo" => {
+                let mut out = EntryOut::default();
+                out.ino(2);
+                out.attr().ino(2);
+                out.ttl_entry(Duration::from_secs(1));
+                Ok(bytes::to_vec(&out))
+            }
+            Operation::Lookup(..) => Err(libc::ENOENT),
+            Operation::Getattr(op) => {
+                let mut out = AttrOut::default();
+                out.attr().ino(op.ino());
+                out.attr().size(42);
+                Ok(bytes::to_vec(&out))
+            }
+            Operation::Write(_, mut data) => {
+                let mut buf = vec![];
+                data.read_to_end(&mut buf).unwrap();
+                let mut out = WriteOut::default();
+                WriteOut::size(&mut out, buf.len() as u32);
+                Ok(bytes::to_vec(&out))
+            }
+            _ => Err(libc::ENOSYS),
+        }
+    }
+
+    #[test]
+    fn replay_matches_session() {
+        let write_in = fuse_write_in {
+            fh: 1,
+            size: 5,
+            ..Default::default()
+        };
+        let messages = vec![
+            message(
+                fuse_opcode::FUSE_GETATTR,
+                1,
+                1,
+                &[fuse_getattr_in::default().as_bytes()],
+            ),
+            init_message(2, 7, 31),
+            message(fuse_opcode::FUSE_LOOKUP, 3, 1, &[b"foo\0"]),
+            message(fuse_opcode::FUSE_LOOKUP, 4, 1, &[b"bar\0"]),
+            message(
+                fuse_opcode::FUSE_GETATTR,
+                5,
+                2,
+                &[fuse_getattr_in::default().as_bytes()],
+            ),
+            message(
+                fuse_opcode::FUSE_WRITE,
+                6,
+                2,
+                &[write_in.as_bytes(), b"hello"],
+            ),
+            message(fuse_opcode::FUSE_READLINK, 7, 2, &[]),
+        ];
+
+        // The replies written by a session over the emulated device.
+        let (conn, mut peer) = Connection::pair().unwrap();
+        for msg in &messages {
+            peer.write_all(&msg[..]).unwrap();
+        }
+        let session = Session::init(conn, KernelConfig::default()).unwrap();
+        for _ in 0..5 {
+            let req = session.next_request().unwrap().unwrap();
+            match respond(req.operation().unwrap()) {
+                Ok(out) => req.reply(out).unwrap(),
+                Err(errno) => req.reply_error(errno).unwrap(),
+            }
+        }
+        let mut expected = vec![];
+        for _ in 0..messages.len() {
+            let mut buf = vec![0u8; 4096];
+            let len = peer.read(&mut buf[..]).unwrap();
+            expected.push(buf[..len].to_vec());
+        }
+        drop(session);
+
+        // The replies produced from the captured stream.
+        let stream = messages.concat();
+        let mut remaining = &stream[..];
+        let mut replies = vec![];
+        let mut handshake = Handshake::new(&KernelConfig::default());
+        while !handshake.is_complete() {
+            let len = RawRequest::parse(remaining).unwrap().len();
+            handshake.feed(&remaining[..len]).unwrap();
+            replies.extend(std::iter::from_fn(|| handshake.emit()));
+            remaining = &remaining[len..];
+        }
+        let state = handshake.state().unwrap();
+        assert_eq!(state.minor(), 31);
+        while !remaining.is_empty() {
+            let req = RawRequest::parse(remaining).unwrap();
+            remaining = &remaining[req.len()..];
+            replies.push(match respond(req.operation(&state).unwrap()) {
+                Ok(out) => encode_reply(req.unique(), out),
+                Err(errno) => encode_error(req.unique(), errno),
+            });
+        }
+
+        assert_eq!(replies, expected);
+    }
+
+    #[test]
+    fn handshake_negotiates_version() {
+        let mut handshake = Handshake::new(&KernelConfig::default());
+
+        // A newer major version is answered with the supported one.
+        handshake.feed(&init_message(1, 8, 0)).unwrap();
+        let reply = handshake.emit().unwrap();
+        let mut init_out = fuse_init_out::default();
+        init_out
+            .as_bytes_mut()
+            .copy_from_slice(&reply[mem::size_of::<fuse_out_header>()..]);
+        assert_eq!(init_out.major, FUSE_KERNEL_VERSION);
+        assert!(!handshake.is_complete());
+
+        handshake.feed(&init_message(2, 7, 22)).unwrap();
+        assert_eq!(handshake.emit().unwrap(), encode_error(2, libc::EPROTO));
+        assert!(handshake.state().is_none());
+
+        handshake.feed(&init_message(3, 7, 31)).unwrap();
+        assert!(handshake.emit().is_some());
+        assert!(handshake.emit().is_none());
+        assert_eq!(handshake.state().unwrap().minor(), 31);
+        assert!(handshake.feed(&init_message(4, 7, 31)).is_err());
+    }
+
+    #[test]
+    fn handshake_queues_early_requests() {
+        let mut config = KernelConfig::default();
+        config.max_early_requests(1);
+        let mut handshake = Handshake::new(&config);
+        for unique in 1..=3 {
+            let msg = message(fuse_opcode::FUSE_READLINK, unique, 1, &[]);
+            handshake.feed(&msg).unwrap();
+        }
+        assert_eq!(handshake.emit().unwrap(), encode_error(2, libc::EIO));
+        assert_eq!(handshake.emit().unwrap(), encode_error(3, libc::EIO));
+        let early: Vec<_> = handshake.early_requests().map(|req| req.unique()).collect();
+        assert_eq!(early, [1]);
+
+        // The handshake is given up after ten messages.
+        for unique in 4..10 {
+            let msg = message(fuse_opcode::FUSE_FORGET, unique, 1, &[&[0; 8]]);
+            handshake.feed(&msg).unwrap();
+        }
+        let err = handshake.feed(&init_message(10, 6, 0)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionRefused);
+        assert_eq!(handshake.emit().unwrap(), encode_error(10, libc::EPROTO));
+    }
+}
@@ -4,12 +4,50 @@ use std::{convert::TryInto as _, ffi::OsStr, fmt, mem, os::unix::prelude::*, tim
 use zerocopy::AsBytes as _;
 
 /// Attributes about a file.
+///
+/// The values compare equal when all of attributes are the same, so the code
+/// that maps the backend's metadata into attributes can be tested by
+/// comparing with an expected `FileAttr` built from `FileAttr::default()`.
 #[repr(transparent)]
+#[derive(Clone, Copy, Default)]
 pub struct FileAttr {
     attr: fuse_attr,
 }
 
+impl fmt::Debug for FileAttr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FileAttr")
+            .field("ino", &self.attr.ino)
+            .field("size", &self.attr.size)
+            .field("mode", &format_args!("{:#o}", self.attr.mode))
+            .field("nlink", &self.attr.nlink)
+            .field("uid", &self.attr.uid)
+            .field("gid", &self.attr.gid)
+            .field("rdev", &self.attr.rdev)
+            .field("blksize", &self.attr.blksize)
+            .field("blocks", &self.attr.blocks)
+            .field("atime", &(self.attr.atime, self.attr.atimensec))
+            .field("mtime", &(self.attr.mtime, self.attr.mtimensec))
+            .field("ctime", &(self.attr.ctime, self.attr.ctimensec))
+            .field("flags", &AttrFlags(self.attr.flags))
+            .finish()
+    }
+}
+
+impl PartialEq for FileAttr {
+    fn eq(&self, other: &Self) -> bool {
+        self.attr.as_bytes() == other.attr.as_bytes()
+    }
+}
+
+impl Eq for FileAttr {}
+
 impl FileAttr {
+    #[inline]
+    fn from_attr(attr: &fuse_attr) -> &FileAttr {
+        unsafe { &*(attr as *const fuse_attr as *const FileAttr) }
+    }
+
     #[inline]
     fn from_attr_mut(attr: &mut fuse_attr) -> &mut FileAttr {
         unsafe { &mut *(attr as *mut fuse_attr as *mut FileAttr) }
@@ -89,6 +127,185 @@ impl FileAttr {
         self.attr.ctime = ctime.as_secs();
         self.attr.ctimensec = ctime.subsec_nanos();
     }
+
+    /// Set the per-inode attribute flags.
+    ///
+    /// The flags are dropped when sending the reply if the negotiated
+    /// protocol does not define them, i.e. `DAX` before ABI 7.36.  Before
+    /// ABI 7.32, the field is a padding ignored by the kernel and is sent
+    /// as it is.
+    #[inline]
+    pub fn flags(&mut self, flags: AttrFlags) {
+        self.attr.flags = flags.bits();
+    }
+
+    /// Set the attributes from the result of `stat(2)`.
+    ///
+    /// The fields are converted one by one, since the layout of `libc::stat`
+    /// and the widths of its fields depend on the target.  The timestamps
+    /// before the epoch are also preserved, unlike `atime` and the others.
+    #[allow(clippy::unnecessary_cast)]
+    pub fn stat(&mut self, st: &libc::stat) {
+        self.attr.ino = st.st_ino as u64;
+        self.attr.size = st.st_size as u64;
+        self.attr.mode = st.st_mode as u32;
+        self.attr.nlink = st.st_nlink.try_into().unwrap_or(u32::MAX);
+        self.attr.uid = st.st_uid;
+        self.attr.gid = st.st_gid;
+        self.attr.rdev = encode_dev(st.st_rdev as u64);
+        self.attr.blksize = st.st_blksize as u32;
+        self.attr.blocks = st.st_blocks as u64;
+        // `time_t` may be 32-bit on 32-bit targets without the time64 ABI.
+        let (atime, atimensec) = timestamp(st.st_atime as i64, st.st_atime_nsec as i64);
+        let (mtime, mtimensec) = timestamp(st.st_mtime as i64, st.st_mtime_nsec as i64);
+        let (ctime, ctimensec) = timestamp(st.st_ctime as i64, st.st_ctime_nsec as i64);
+        self.attr.atime = atime;
+        self.attr.atimensec = atimensec;
+        self.attr.mtime = mtime;
+        self.attr.mtimensec = mtimensec;
+        self.attr.ctime = ctime;
+        self.attr.ctimensec = ctimensec;
+    }
+}
+
+/// Convert a timestamp into the representation in `fuse_attr`.
+///
+/// The kernel interprets the seconds as a signed integer.
+#[allow(clippy::manual_clamp)] // `i64::clamp` requires Rust 1.50
+fn timestamp(secs: i64, nsecs: i64) -> (u64, u32) {
+    (secs as u64, nsecs.max(0).min(999_999_999) as u32)
+}
+
+/// Encode a device number into the 32-bit format used by the kernel (`new_encode_dev`).
+pub(crate) fn encode_dev(dev: u64) -> u32 {
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    ((minor & 0xff) | (major << 8) | ((minor & !0xff) << 12)) as u32
+}
+
+/// A set of per-inode attribute flags, set by `FileAttr::flags`.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct AttrFlags(u32);
+
+impl AttrFlags {
+    /// The inode is the mountpoint of a submount (ABI 7.32).
+    pub const SUBMOUNT: Self = Self(FUSE_ATTR_SUBMOUNT);
+
+    /// The direct access (DAX) is enabled on the inode (ABI 7.36).
+    pub const DAX: Self = Self(FUSE_ATTR_DAX);
+
+    /// Create an empty set of flags.
+    #[inline]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Create a set of flags from the raw value.
+    #[inline]
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Return the raw value of flags.
+    #[inline]
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Return whether all of the specified flags are contained.
+    #[inline]
+    pub const fn contains(self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    /// Return the flags defined in the specified minor version of the protocol.
+    pub(crate) const fn supported(minor: u32) -> Self {
+        match minor {
+            0..=31 => Self::empty(),
+            32..=35 => Self::SUBMOUNT,
+            _ => Self(FUSE_ATTR_SUBMOUNT | FUSE_ATTR_DAX),
+        }
+    }
+}
+
+impl std::ops::BitOr for AttrFlags {
+    type Output = Self;
+
+    #[inline]
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for AttrFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "AttrFlags({:#x})", self.0)
+    }
+}
+
+/// The generation number of an inode.
+///
+/// The pair of inode number and generation must be unique for the lifetime
+/// of the filesystem.  When the filesystem reuses an inode number that has
+/// been forgotten by the kernel, it must announce the new inode with a
+/// different generation, typically obtained by `Generation::next`.
+/// `util::InodeNumbers` allocates the inode numbers in this way.
+#[repr(transparent)]
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Generation(u64);
+
+impl Generation {
+    /// Create a `Generation` from the raw value.
+    #[inline]
+    pub const fn from_raw(generation: u64) -> Self {
+        Self(generation)
+    }
+
+    /// Take the raw value of this generation.
+    #[inline]
+    pub const fn into_raw(self) -> u64 {
+        self.0
+    }
+
+    /// Return the generation to be used when the inode number is reused.
+    ///
+    /// # Panics
+    /// This method panics if the generation number is exhausted.
+    #[inline]
+    pub fn next(self) -> Self {
+        Self(
+            self.0
+                .checked_add(1)
+                .expect("the generation number is exhausted"),
+        )
+    }
+}
+
+impl From<Generation> for u64 {
+    #[inline]
+    fn from(generation: Generation) -> Self {
+        generation.into_raw()
+    }
+}
+
+/// Implement `PartialEq` for the reply types, by comparing their wire representations.
+macro_rules! impl_eq_by_bytes {
+    ($($name:ident),*$(,)?) => {$(
+        impl PartialEq for $name {
+            fn eq(&self, other: &Self) -> bool {
+                self.out.as_bytes() == other.out.as_bytes()
+            }
+        }
+
+        impl Eq for $name {}
+    )*};
+}
+
+impl_eq_by_bytes!(EntryOut, AttrOut, OpenOut, WriteOut, StatfsOut);
+
+#[inline]
+fn duration(secs: u64, nsecs: u32) -> Duration {
+    Duration::new(secs, nsecs)
 }
 
 #[derive(Default)]
@@ -98,8 +315,13 @@ pub struct EntryOut {
 
 impl fmt::Debug for EntryOut {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields.
-        f.debug_struct("EntryOut").finish()
+        f.debug_struct("EntryOut")
+            .field("ino", &self.out.nodeid)
+            .field("generation", &self.out.generation)
+            .field("entry_valid", &self.entry_valid())
+            .field("attr_valid", &self.attr_valid())
+            .field("attr", FileAttr::from_attr(&self.out.attr))
+            .finish()
     }
 }
 
@@ -144,10 +366,17 @@ impl EntryOut {
     /// when the filesystem reuse inode numbers.  That is, the operations
     /// must ensure that the pair of entry's inode number and generation
     /// are unique for the lifetime of the filesystem.
+    ///
+    /// See also `inode_generation` for the typed version of this method.
     pub fn generation(&mut self, generation: u64) {
         self.out.generation = generation;
     }
 
+    /// Set the generation of this entry with the typed value.
+    pub fn inode_generation(&mut self, generation: Generation) {
+        self.out.generation = generation.into_raw();
+    }
+
     /// Set the validity timeout for inode attributes.
     ///
     /// The operations should set this value to very large
@@ -167,6 +396,24 @@ impl EntryOut {
         self.out.entry_valid = ttl.as_secs();
         self.out.entry_valid_nsec = ttl.subsec_nanos();
     }
+
+    /// Return the validity timeout for the name, set by `ttl_entry`.
+    pub fn entry_valid(&self) -> Duration {
+        duration(self.out.entry_valid, self.out.entry_valid_nsec)
+    }
+
+    /// Return the validity timeout for inode attributes, set by `ttl_attr`.
+    pub fn attr_valid(&self) -> Duration {
+        duration(self.out.attr_valid, self.out.attr_valid_nsec)
+    }
+
+    pub(crate) fn nodeid(&self) -> u64 {
+        self.out.nodeid
+    }
+
+    pub(crate) fn attr_ref(&self) -> &FileAttr {
+        FileAttr::from_attr(&self.out.attr)
+    }
 }
 
 #[derive(Default)]
@@ -176,8 +423,10 @@ pub struct AttrOut {
 
 impl fmt::Debug for AttrOut {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields.
-        f.debug_struct("AttrOut").finish()
+        f.debug_struct("AttrOut")
+            .field("attr_valid", &self.attr_valid())
+            .field("attr", FileAttr::from_attr(&self.out.attr))
+            .finish()
     }
 }
 
@@ -193,6 +442,15 @@ impl AttrOut {
         self.out.attr_valid = ttl.as_secs();
         self.out.attr_valid_nsec = ttl.subsec_nanos();
     }
+
+    /// Return the validity timeout for this attribute, set by `ttl`.
+    pub fn attr_valid(&self) -> Duration {
+        duration(self.out.attr_valid, self.out.attr_valid_nsec)
+    }
+
+    pub(crate) fn attr_ref(&self) -> &FileAttr {
+        FileAttr::from_attr(&self.out.attr)
+    }
 }
 
 impl Bytes for AttrOut {
@@ -219,8 +477,10 @@ pub struct OpenOut {
 
 impl fmt::Debug for OpenOut {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields.
-        f.debug_struct("OpenOut").finish()
+        f.debug_struct("OpenOut")
+            .field("fh", &self.out.fh)
+            .field("open_flags", &format_args!("{:#x}", self.out.open_flags))
+            .finish()
     }
 }
 
@@ -247,6 +507,11 @@ impl OpenOut {
         self.out.fh = fh;
     }
 
+    /// Return the `FOPEN_*` flags set by `direct_io` and the others.
+    pub fn open_flags(&self) -> u32 {
+        self.out.open_flags
+    }
+
     #[inline]
     fn set_flag(&mut self, flag: u32, enabled: bool) {
         if enabled {
@@ -263,6 +528,11 @@ impl OpenOut {
 
     /// Indicates that the currently cached file data in the kernel
     /// need not be invalidated.
+    ///
+    /// For `opendir` operations, this flag keeps the cached directory entries
+    /// enabled by `cache_dir` across opens, instead of discarding them at
+    /// every `opendir`.  This behavior is supported since Linux 4.20 and
+    /// ignored by the older kernels.
     pub fn keep_cache(&mut self, enabled: bool) {
         self.set_flag(FOPEN_KEEP_CACHE, enabled);
     }
@@ -274,7 +544,16 @@ impl OpenOut {
 
     /// Enable caching of entries returned by `readdir`.
     ///
-    /// This flag is meaningful only for `opendir` operations.
+    /// This flag is meaningful only for `opendir` operations.  The cached
+    /// entries are dropped at the next `opendir` unless `keep_cache` is
+    /// also set, and can be invalidated explicitly by `Notifier::inval_dir`.
+    ///
+    /// The kernel fills the cache from the entries of both `readdir` and
+    /// `readdirplus` replies, as long as the directory is read sequentially
+    /// from the beginning.  Only the names are cached, so the attributes in
+    /// `readdirplus` replies are not served from the cache.  Enabling
+    /// `KernelConfig::parallel_dirops` is recommended as well, since the
+    /// lookups in the cached directory are otherwise serialized.
     pub fn cache_dir(&mut self, enabled: bool) {
         self.set_flag(FOPEN_CACHE_DIR, enabled);
     }
@@ -315,7 +594,7 @@ impl WriteOut {
     }
 }
 
-#[derive(Default)]
+#[derive(Clone, Default)]
 pub struct StatfsOut {
     out: fuse_statfs_out,
 }
@@ -575,14 +854,83 @@ impl PollOut {
     }
 }
 
+/// The reply of `Ioctl`, to be followed by the output data.
+#[derive(Default)]
+pub struct IoctlOut {
+    out: fuse_ioctl_out,
+}
+
+impl fmt::Debug for IoctlOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("IoctlOut")
+            .field("result", &self.out.result)
+            .finish()
+    }
+}
+
+impl Bytes for IoctlOut {
+    #[inline]
+    fn size(&self) -> usize {
+        self.out.as_bytes().len()
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        1
+    }
+
+    #[inline]
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        dst.put(self.out.as_bytes());
+    }
+}
+
+impl IoctlOut {
+    /// Set the return value of `ioctl(2)` seen by the caller.
+    pub fn result(&mut self, result: i32) {
+        self.out.result = result;
+    }
+}
+
+/// The reply of `Readdir`, a list of directory entries.
+///
+/// The offset stored with each entry is the cookie of the *next* entry,
+/// that is, the value the kernel passes as `Readdir::offset` to resume
+/// reading after that entry.  `with_offset` and `next_entry` assign such
+/// offsets sequentially from the offset of the request:
+///
+/// ```
+/// # use polyfuse::reply::ReaddirOut;
+/// # use std::ffi::OsStr;
+/// let entries = [("foo", 2), ("bar", 3), ("baz", 4)];
+///
+/// // The kernel reads the directory from the offset 1, i.e. after "foo".
+/// let offset = 1;
+/// let mut out = ReaddirOut::with_offset(4096, offset);
+/// for &(name, ino) in entries.iter().skip(offset as usize) {
+///     if out.next_entry(OsStr::new(name), ino, libc::DT_REG as u32) {
+///         break;
+///     }
+/// }
+///
+/// // "bar" and "baz" are stored with the offsets 2 and 3.
+/// assert_eq!(out.last_offset(), 3);
+/// ```
+///
+/// The filesystems whose cookies are not sequential (e.g. hashes of the
+/// names) specify the offsets explicitly with `entry`.
 pub struct ReaddirOut {
     buf: Vec<u8>,
+    offset: u64,
 }
 
 impl fmt::Debug for ReaddirOut {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        // TODO: add fields.
-        f.debug_struct("ReaddirOut").finish()
+        f.debug_struct("ReaddirOut")
+            .field("len", &self.buf.len())
+            .field("capacity", &self.buf.capacity())
+            .field("last_offset", &self.offset)
+            .finish()
     }
 }
 
@@ -603,12 +951,43 @@ impl Bytes for ReaddirOut {
 }
 
 impl ReaddirOut {
+    /// Create an empty list of entries, up to `capacity` bytes.
     pub fn new(capacity: usize) -> Self {
+        Self::with_offset(capacity, 0)
+    }
+
+    /// Create an empty list of entries, whose offsets are assigned by
+    /// `next_entry` after the specified one.
+    ///
+    /// `offset` is usually the value of `Readdir::offset`.
+    pub fn with_offset(capacity: usize, offset: u64) -> Self {
         Self {
             buf: Vec::with_capacity(capacity),
+            offset,
         }
     }
 
+    /// Return the offset of the last entry added, or the initial offset if empty.
+    ///
+    /// The filesystems that keep a cursor in the directory handle can
+    /// store this value to resume from the next `Readdir`.
+    pub fn last_offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Add an entry with the offset following the last one.
+    ///
+    /// The return value is `true` if the entry does not fit in the buffer,
+    /// in which case the entry is not added and the offset is not advanced.
+    pub fn next_entry(&mut self, name: &OsStr, ino: u64, typ: u32) -> bool {
+        let off = self.offset + 1;
+        self.entry(name, ino, typ, off)
+    }
+
+    /// Add an entry with the specified offset.
+    ///
+    /// `off` is the offset to resume reading after this entry.  The return
+    /// value is `true` if the entry does not fit in the buffer.
     pub fn entry(&mut self, name: &OsStr, ino: u64, typ: u32, off: u64) -> bool {
         let name = name.as_bytes();
         let remaining = self.buf.capacity() - self.buf.len();
@@ -631,6 +1010,7 @@ impl ReaddirOut {
         self.buf.extend_from_slice(dirent.as_bytes());
         self.buf.extend_from_slice(name);
         self.buf.resize(lenbefore + aligned_entry_size, 0);
+        self.offset = off;
 
         false
     }
@@ -640,3 +1020,83 @@ impl ReaddirOut {
 const fn aligned(len: usize) -> usize {
     (len + mem::size_of::<u64>() - 1) & !(mem::size_of::<u64>() - 1)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    #[allow(clippy::unnecessary_cast)] // `dev_t` is not `u64` on every target
+    fn file_attr_from_stat() {
+        let mut st = unsafe { mem::zeroed::<libc::stat>() };
+        st.st_ino = 42;
+        st.st_size = 4096;
+        st.st_mode = libc::S_IFCHR | 0o644;
+        st.st_nlink = 1;
+        // `makedev` is an unsafe function in the older versions of libc.
+        #[allow(unused_unsafe)]
+        let (null_rdev, rdev) = unsafe { (libc::makedev(1, 3), libc::makedev(259, 0x12345)) };
+        st.st_rdev = null_rdev as _;
+        st.st_atime = -1;
+        st.st_atime_nsec = 500;
+        st.st_mtime = 1_600_000_000;
+
+        let mut attr = FileAttr::default();
+        attr.stat(&st);
+        assert_eq!(attr.attr.ino, 42);
+        assert_eq!(attr.attr.size, 4096);
+        assert_eq!(attr.attr.mode, libc::S_IFCHR | 0o644);
+        assert_eq!(attr.attr.rdev, (1 << 8) | 3);
+        assert_eq!(attr.attr.atime as i64, -1);
+        assert_eq!(attr.attr.atimensec, 500);
+        assert_eq!(attr.attr.mtime, 1_600_000_000);
+
+        assert_eq!(encode_dev(rdev as u64), 0x1231_0345);
+    }
+
+    #[test]
+    fn readdir_offsets_in_batches() {
+        let entries = [("a", 2), ("b", 3), ("c", 4)];
+        let entry_size = aligned(mem::size_of::<fuse_dirent>() + 1);
+
+        // Parse the entries as the kernel does, into the pairs of name and offset.
+        let parse = |out: &ReaddirOut| {
+            let mut parsed = vec![];
+            let mut buf = &out.buf[..];
+            while !buf.is_empty() {
+                let mut dirent = fuse_dirent::default();
+                let header_len = mem::size_of::<fuse_dirent>();
+                dirent.as_bytes_mut().copy_from_slice(&buf[..header_len]);
+                let name = &buf[header_len..header_len + dirent.namelen as usize];
+                parsed.push((String::from_utf8(name.to_vec()).unwrap(), dirent.off));
+                buf = &buf[aligned(header_len + dirent.namelen as usize)..];
+            }
+            parsed
+        };
+
+        let mut offset = 0;
+        let mut batches = vec![];
+        loop {
+            // Only two entries fit in a batch.
+            let mut out = ReaddirOut::with_offset(entry_size * 2, offset);
+            for &(name, ino) in entries.iter().skip(offset as usize) {
+                if out.next_entry(OsStr::new(name), ino, libc::DT_REG as u32) {
+                    break;
+                }
+            }
+            if out.buf.is_empty() {
+                break;
+            }
+            offset = out.last_offset();
+            batches.push(parse(&out));
+        }
+
+        assert_eq!(
+            batches,
+            vec![
+                vec![("a".into(), 1), ("b".into(), 2)],
+                vec![("c".into(), 3)],
+            ]
+        );
+    }
+}
@@ -1,74 +1,129 @@
 use crate::{
+    admission::{BackgroundAdmission, ResumeCallback},
+    audit::{GenerationAudit, LookupAudit, LookupCounts},
     bytes::{Bytes, FillBytes},
-    conn::{Connection, MountOptions},
+    caller::CallerCache,
+    conn::{Connection, MountOptions, UnmountMode},
     decoder::Decoder,
-    op::{DecodeError, Operation},
+    errno::Errno,
+    fusectl::{ConnectionDir, KernelStats},
+    intercept::{Action, ReplyAttr, ReplyBody, ReplyEntries, ReplyInterceptor},
+    mountinfo::{MountFlags, MountPropagation, Propagation},
+    op::{DecodeError, DisplayOpcode, Extensions, FsyncFlags, Opcode, Operation},
+    proto::{self, EarlyRequest, Handshake, Reply},
+    reply::{AttrFlags, XattrOut},
+    util::{InodeGuard, InodeLocks, InodeTicket},
 };
 use polyfuse_kernel::*;
 use std::{
+    cell::Cell,
     cmp,
-    convert::{TryFrom, TryInto as _},
+    collections::{HashMap, HashSet, VecDeque},
+    convert::TryFrom,
     ffi::OsStr,
     fmt,
     io::{self, prelude::*, IoSlice, IoSliceMut},
     mem::{self, MaybeUninit},
-    os::unix::prelude::*,
+    os::unix::{net::UnixStream, prelude::*},
     path::{Path, PathBuf},
     sync::{
-        atomic::{AtomicBool, AtomicU64, Ordering},
-        Arc,
+        atomic::{AtomicBool, AtomicI32, AtomicU32, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
     },
+    time::{Duration, Instant},
 };
-use zerocopy::AsBytes as _;
+use zerocopy::{AsBytes, FromBytes};
 
-// The minimum supported ABI minor version by polyfuse.
-const MINIMUM_SUPPORTED_MINOR_VERSION: u32 = 23;
+#[cfg(feature = "notify")]
+mod notify;
+
+#[cfg(feature = "notify")]
+use self::notify::RetrieveReply;
+#[cfg(feature = "notify")]
+pub use self::notify::{Closed, Notifier, Retrieved};
+#[cfg(feature = "notify")]
+use std::{sync::mpsc, task::Waker};
 
 const DEFAULT_MAX_WRITE: u32 = 16 * 1024 * 1024;
 const MIN_MAX_WRITE: u32 = FUSE_MIN_READ_BUFFER - BUFFER_HEADER_SIZE as u32;
 
 // copied from fuse_i.h
 const MAX_MAX_PAGES: usize = 256;
-//const DEFAULT_MAX_PAGES_PER_REQ: usize = 32;
+const DEFAULT_MAX_PAGES_PER_REQ: usize = 32;
 const BUFFER_HEADER_SIZE: usize = 0x1000;
 
+// The upper bound of the receive buffer grown on `EINVAL`.
+const MAX_RECEIVE_BUFFER_SIZE: usize = BUFFER_HEADER_SIZE + 2 * DEFAULT_MAX_WRITE as usize;
+
+// The maximum length of the requests whose arguments are copied out of the receive buffer.
+const SMALL_REQUEST_SIZE: usize = 16 * 1024;
+
+// The number of the uniques kept by `Session::failed_replies`.
+const MAX_FAILED_REPLIES: usize = 1024;
+
+// The node ID of the root directory, which the kernel never forgets.
+const ROOT_INO: u64 = 1;
+
 // TODO: add FUSE_IOCTL_DIR
 const DEFAULT_INIT_FLAGS: u32 = FUSE_ASYNC_READ
     | FUSE_PARALLEL_DIROPS
     | FUSE_AUTO_INVAL_DATA
     | FUSE_HANDLE_KILLPRIV
     | FUSE_ASYNC_DIO
-    | FUSE_ATOMIC_O_TRUNC;
-
-const INIT_FLAGS_MASK: u32 = FUSE_ASYNC_READ
     | FUSE_ATOMIC_O_TRUNC
-    | FUSE_AUTO_INVAL_DATA
-    | FUSE_ASYNC_DIO
-    | FUSE_PARALLEL_DIROPS
-    | FUSE_HANDLE_KILLPRIV
-    | FUSE_POSIX_LOCKS
-    | FUSE_FLOCK_LOCKS
-    | FUSE_EXPORT_SUPPORT
-    | FUSE_DONT_MASK
-    | FUSE_WRITEBACK_CACHE
-    | FUSE_POSIX_ACL
-    | FUSE_DO_READDIRPLUS
-    | FUSE_READDIRPLUS_AUTO;
+    | FUSE_ABORT_ERROR;
 
 // ==== KernelConfig ====
 
 /// Parameters for setting up the connection with FUSE driver
 /// and the kernel side behavior.
 pub struct KernelConfig {
-    mountopts: MountOptions,
-    init_out: fuse_init_out,
+    pub(crate) mountopts: MountOptions,
+    pub(crate) init_out: fuse_init_out,
+    pub(crate) caller_filter: Option<Arc<CallerFilter>>,
+    opcode_filter: Option<Arc<OpcodeFilter>>,
+    denied_opcode_errno: Option<i32>,
+    unknown_opcode_eopnotsupp: Option<u32>,
+    deadlines: HashMap<OpcodeClass, Duration>,
+    deadline_errno: Option<i32>,
+    max_reply_failures: Option<u32>,
+    pub(crate) max_early_requests: usize,
+    strict: bool,
+    no_interrupt: bool,
+    reply_interceptor: Option<Arc<dyn ReplyInterceptor>>,
+    background_admission: bool,
+    background_resumed: Option<Arc<ResumeCallback>>,
+    lookup_audit: bool,
+    stale_inodes: Option<InodeTracking>,
+    live_inodes: HashSet<u64>,
+    serialize_per_inode: bool,
 }
 
+type CallerFilter = dyn Fn(&Caller<'_>) -> bool + Send + Sync;
+type OpcodeFilter = dyn Fn(Option<Opcode>) -> bool + Send + Sync;
+
 impl Default for KernelConfig {
     fn default() -> Self {
         Self {
             mountopts: MountOptions::default(),
             init_out: default_init_out(),
+            caller_filter: None,
+            opcode_filter: None,
+            denied_opcode_errno: None,
+            unknown_opcode_eopnotsupp: None,
+            deadlines: HashMap::new(),
+            deadline_errno: None,
+            max_reply_failures: None,
+            max_early_requests: 0,
+            strict: false,
+            no_interrupt: false,
+            reply_interceptor: None,
+            background_admission: false,
+            background_resumed: None,
+            lookup_audit: false,
+            stale_inodes: None,
+            live_inodes: HashSet::new(),
+            serialize_per_inode: false,
         }
     }
 }
@@ -80,6 +135,212 @@ impl KernelConfig {
         self
     }
 
+    /// Specify how the filesystem is unmounted when the session is dropped.
+    ///
+    /// The default is `UnmountMode::Lazy`.  With `UnmountMode::Normal`, the
+    /// filesystem is left mounted if it is still busy, and a warning is
+    /// logged.  Note that the helper process of `auto_unmount` always
+    /// detaches the filesystem lazily when this process exits.
+    pub fn unmount_mode(&mut self, mode: UnmountMode) -> &mut Self {
+        self.mountopts.unmount_mode = mode;
+        self
+    }
+
+    /// Restrict the callers whose requests are delivered to the filesystem.
+    ///
+    /// The predicate is called on every incoming request before it is
+    /// returned from `Session::next_request`, and the requests for which
+    /// it returns `false` are rejected with `EACCES` without being seen by
+    /// the filesystem.  This is useful together with the mount option
+    /// `allow_other`, which permits all users on the system to access the
+    /// filesystem.  The requests that do not accept any reply, such as
+    /// `FORGET` and `INTERRUPT`, are always delivered.
+    ///
+    /// Since the predicate runs on every request, it should be cheap and
+    /// should not block.
+    pub fn caller_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(&Caller<'_>) -> bool + Send + Sync + 'static,
+    {
+        self.caller_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Restrict the opcodes of requests delivered to the filesystem.
+    ///
+    /// The predicate receives the opcode before the request is decoded, or
+    /// `None` if the opcode is unknown to this library so that such
+    /// requests can also be rejected.  The
+    /// denied requests are replied with the error number specified by
+    /// `denied_opcode_errno`, and counted in `Session::denied_requests`.
+    ///
+    /// As with `caller_filter`, the requests that do not accept any reply
+    /// are always delivered.  The opcode filter is applied first.
+    pub fn opcode_filter<F>(&mut self, filter: F) -> &mut Self
+    where
+        F: Fn(Option<Opcode>) -> bool + Send + Sync + 'static,
+    {
+        self.opcode_filter = Some(Arc::new(filter));
+        self
+    }
+
+    /// Specify the error number replied to the requests denied by `opcode_filter`.
+    ///
+    /// By default, `EROFS` is replied to the requests that modify the
+    /// filesystem, and `ENOSYS` to the others.
+    pub fn denied_opcode_errno(&mut self, errno: i32) -> &mut Self {
+        self.denied_opcode_errno = Some(errno);
+        self
+    }
+
+    /// Reply `EOPNOTSUPP` instead of `ENOSYS` to the requests with the
+    /// opcodes unknown to this library, numbered `first_opcode` or above.
+    ///
+    /// The kernel remembers `ENOSYS` and never sends the operation again
+    /// until the filesystem is unmounted, while `EOPNOTSUPP` only fails the
+    /// request at hand.  This keeps the operations added in newer kernels
+    /// available to a later version of the filesystem taking over the
+    /// connection, or to a bridge that forwards them as `Operation::Unknown`
+    /// using `Request::raw_arg`.  The replacement applies to the replies
+    /// from the filesystem as well as to those sent by the session, such as
+    /// the denials of `opcode_filter`.
+    pub fn unknown_opcode_eopnotsupp(&mut self, first_opcode: u32) -> &mut Self {
+        self.unknown_opcode_eopnotsupp = Some(first_opcode);
+        self
+    }
+
+    /// Specify the time budget of requests in the class of opcodes.
+    ///
+    /// The deadline of each request is computed from the budget at the time
+    /// when the request is read from the connection, so the delay before the
+    /// request is processed also counts against it.  It can be obtained by
+    /// `Request::deadline` and propagated to the backend.
+    ///
+    /// A request whose deadline has already passed when it is about to be
+    /// delivered, e.g. after waiting in the queue of the early requests, is
+    /// replied with the error number specified by `deadline_errno` without
+    /// reaching the filesystem.  Once delivered, the replies are sent as they
+    /// are even after the deadline, since the operation may have already
+    /// taken effect; the handler should give up by itself if needed.
+    ///
+    /// `FORGET`, `BATCH_FORGET`, `INTERRUPT`, `NOTIFY_REPLY` and `DESTROY`
+    /// have no deadlines, since they must not be lost.
+    pub fn deadline(&mut self, class: OpcodeClass, budget: Duration) -> &mut Self {
+        self.deadlines.insert(class, budget);
+        self
+    }
+
+    /// Specify the error number replied to the requests exceeding their deadline.
+    ///
+    /// The default value is `ETIMEDOUT`.
+    pub fn deadline_errno(&mut self, errno: i32) -> &mut Self {
+        self.deadline_errno = Some(errno);
+        self
+    }
+
+    /// Close the session after `max` consecutive replies have failed to be
+    /// written, e.g. because the file descriptor of the connection has been
+    /// closed by mistake elsewhere in the process.
+    ///
+    /// Then the kernel waits forever for the replies, while the session
+    /// keeps receiving the requests that can never complete.  Once closed,
+    /// `closed_reason` returns `ConnectionClosed::ReplyFailed`, and
+    /// `next_request` and `try_next_request` fail with an error containing
+    /// it instead of receiving further requests.  The replies rejected by
+    /// the kernel as malformed (`EINVAL`) or after unmounting (`ENODEV`)
+    /// are not counted, and a successful reply resets the count.
+    ///
+    /// By default, the session keeps running whatever the replies fail.
+    pub fn max_reply_failures(&mut self, max: u32) -> &mut Self {
+        self.max_reply_failures = Some(max.max(1));
+        self
+    }
+
+    /// Validate the replies against the requests, for catching the protocol
+    /// mistakes of the filesystem during development.
+    ///
+    /// In the strict mode, the following replies are logged as errors and
+    /// replaced with `EIO`, and the `reply` method returns the error.  By
+    /// default, these replies are sent to the kernel as they are.
+    ///
+    /// * A negative entry (the inode number `0`) replied to the requests
+    ///   other than `LOOKUP`, which only the lookups can be answered with.
+    /// * `WRITE` replies reporting more bytes than the request carried.
+    /// * `READDIR` and `READDIRPLUS` replies exceeding the requested size.
+    /// * The attributes of entries, `GETATTR` and `SETATTR` with the inode
+    ///   number `0` or the file mode without the file type bits.
+    /// * An entry whose attributes have no links, which the kernel takes
+    ///   for a removed file.
+    ///
+    /// The block size of `0` is accepted, since the kernel substitutes the
+    /// one of the filesystem for it.  The inode number of the attributes
+    /// may differ from the node ID of the entry, as the pass-through
+    /// filesystems report the ones of the underlying files.
+    ///
+    /// The strict mode also checks the requests: `FSYNC` and `FSYNCDIR`
+    /// with the flags unknown to this library are replied with `EINVAL`
+    /// without being delivered, rather than synchronized in a way the
+    /// kernel may not have meant.  Otherwise they are logged and delivered.
+    pub fn strict(&mut self, enabled: bool) -> &mut Self {
+        self.strict = enabled;
+        self
+    }
+
+    /// Decline all of the `INTERRUPT` requests in the session.
+    ///
+    /// When enabled, the session replies `ENOSYS` to an `INTERRUPT` request
+    /// instead of delivering it, and the kernel stops sending them for the
+    /// rest of the connection, waiting for the completion of the original
+    /// requests instead.  This is for the filesystems that cannot abort the
+    /// operations anyway.  `EAGAIN`, which the kernel takes as "not yet",
+    /// is not used since the kernel repeats the interrupt after it.
+    ///
+    /// This setting is not kept in `SessionState`.
+    pub fn no_interrupt(&mut self, enabled: bool) -> &mut Self {
+        self.no_interrupt = enabled;
+        self
+    }
+
+    /// Register the hook called on every reply before it is sent to the kernel.
+    ///
+    /// See `ReplyInterceptor` for details.
+    pub fn reply_interceptor<I>(&mut self, interceptor: I) -> &mut Self
+    where
+        I: ReplyInterceptor + 'static,
+    {
+        self.reply_interceptor = Some(Arc::new(interceptor));
+        self
+    }
+
+    /// Allow only the requests from the specified users.
+    ///
+    /// This is a shorthand of `caller_filter` that checks the user ID.
+    /// Note that the superuser is not implicitly allowed.
+    pub fn allowed_uids<I>(&mut self, uids: I) -> &mut Self
+    where
+        I: IntoIterator<Item = u32>,
+    {
+        let mut uids: Vec<u32> = uids.into_iter().collect();
+        uids.sort_unstable();
+        uids.dedup();
+        self.caller_filter(move |caller| uids.binary_search(&caller.uid()).is_ok())
+    }
+
+    /// Specify the number of requests queued while waiting for `INIT`.
+    ///
+    /// The kernel may send requests before the `INIT` request around the
+    /// race of mounting, and they are rejected with `EIO` by default.  If
+    /// a non-zero value is given, up to that number of such requests are
+    /// kept and delivered by `Session::next_request` after the session is
+    /// initialized, and the rest are rejected as before.
+    ///
+    /// The requests that do not accept any reply, such as `FORGET`, are
+    /// always discarded silently regardless of this setting.
+    pub fn max_early_requests(&mut self, max: usize) -> &mut Self {
+        self.max_early_requests = max;
+        self
+    }
+
     #[doc(hidden)] // TODO: dox
     pub fn mount_option(&mut self, option: &str) -> &mut Self {
         for option in option.split(',').map(|s| s.trim()) {
@@ -87,7 +348,25 @@ impl KernelConfig {
                 "auto_unmount" => {
                     self.auto_unmount(true);
                 }
-                option => self.mountopts.options.push(option.to_owned()),
+                option => match option
+                    .find('=')
+                    .map(|pos| (&option[..pos], &option[pos + 1..]))
+                {
+                    Some(("max_read", value)) if value.parse::<u32>().is_ok() => {
+                        self.max_read(value.parse().unwrap());
+                    }
+                    // The value is checked by `validate`, rather than causing a panic in `blksize`.
+                    Some(("blksize", value)) if value.parse::<u32>().is_ok() => {
+                        self.mountopts.blksize = Some(value.parse().unwrap());
+                    }
+                    Some(("fsname", value)) => {
+                        self.fsname(value);
+                    }
+                    Some(("subtype", value)) => {
+                        self.subtype(value);
+                    }
+                    _ => self.mountopts.options.push(option.to_owned()),
+                },
             }
         }
         self
@@ -129,6 +408,12 @@ impl KernelConfig {
 
     /// Specify that the filesystem supports the `O_TRUNC` open flag.
     ///
+    /// When this flag is granted, the kernel passes `O_TRUNC` through to
+    /// `Open` and the filesystem must truncate the file while opening it.
+    /// Otherwise, the kernel strips `O_TRUNC` from the open flags and
+    /// truncates the file by a separate `Setattr` with the size of zero.
+    /// `Session::atomic_o_trunc` tells which flow is in effect.
+    ///
     /// Enabled by default.
     pub fn atomic_o_trunc(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_ATOMIC_O_TRUNC, enabled);
@@ -188,12 +473,19 @@ impl KernelConfig {
 
     /// Specify that the kernel should not apply the umask to the file mode
     /// on `create` operations.
+    ///
+    /// `mode` of `Mknod`, `Mkdir` and `Create` still returns the mode with
+    /// the umask applied, and the unmasked one is available by `raw_mode`.
     pub fn dont_mask(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_DONT_MASK, enabled);
         self
     }
 
     /// Specify that the kernel should enable writeback caching.
+    ///
+    /// In this mode, the kernel trusts the file sizes replied by the
+    /// filesystem even while the written data is still cached, so the
+    /// backends that apply the writes lazily should use `util::SizeEpoch`.
     pub fn writeback_cache(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_WRITEBACK_CACHE, enabled);
         self
@@ -206,6 +498,11 @@ impl KernelConfig {
     }
 
     /// Specify that the filesystem supports `readdirplus` operations.
+    ///
+    /// The kernel sends `READDIRPLUS` instead of `READDIR` only if this is
+    /// enabled, and the session replies `ENOSYS` to a `READDIRPLUS` request
+    /// received otherwise.  Use `Session::readdirplus` to find the mode
+    /// negotiated with the kernel.
     pub fn readdirplus(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_DO_READDIRPLUS, enabled);
         self
@@ -213,12 +510,73 @@ impl KernelConfig {
 
     /// Indicates that the kernel uses the adaptive readdirplus.
     ///
-    /// This option is meaningful only if `readdirplus` is enabled.
+    /// In this mode, the kernel sends `READDIRPLUS` only for the first
+    /// read of a directory and for the directories whose entries have
+    /// been looked up since the last read, and `READDIR` otherwise.  The
+    /// protocol has no control of the mode per directory, so a filesystem
+    /// whose attributes are expensive only in some directories should
+    /// enable this and keep the plain `READDIR` cheap.
+    ///
+    /// This option is meaningful only if `readdirplus` is enabled, and is
+    /// not sent to the kernel otherwise.
     pub fn readdirplus_auto(&mut self, enabled: bool) -> &mut Self {
         self.set_init_flag(FUSE_READDIRPLUS_AUTO, enabled);
         self
     }
 
+    /// Specify that the kernel reports the aborted connection with `ECONNABORTED`,
+    /// instead of `ENODEV`.
+    ///
+    /// When this flag is negotiated, `Session::closed_reason` can distinguish
+    /// whether the connection has been aborted or the filesystem is unmounted.
+    ///
+    /// Enabled by default.
+    pub fn abort_error(&mut self, enabled: bool) -> &mut Self {
+        self.set_init_flag(FUSE_ABORT_ERROR, enabled);
+        self
+    }
+
+    /// Set the maximum size of read requests, with the mount option `max_read`.
+    ///
+    /// Unlike `max_readahead`, this option also caps the size of the
+    /// `READ` requests issued by the direct I/O.  If the maximum readahead
+    /// exceeds this value, it is clamped at the initialization.
+    pub fn max_read(&mut self, value: u32) -> &mut Self {
+        self.mountopts.max_read = Some(value);
+        self
+    }
+
+    /// Set the block size of the filesystem, with the mount option `blksize`.
+    ///
+    /// This option is only meaningful for the block device based
+    /// filesystems (`fuseblk`).
+    ///
+    /// # Panic
+    /// It causes an assertion panic if the value is not a power of two
+    /// greater than or equal to 512.
+    pub fn blksize(&mut self, value: u32) -> &mut Self {
+        assert!(
+            value >= 512 && value.is_power_of_two(),
+            "blksize must be a power of two greater or equal to 512",
+        );
+        self.mountopts.blksize = Some(value);
+        self
+    }
+
+    /// Set the name of the filesystem shown as the source of the mount in `/proc/mounts`.
+    ///
+    /// The default value is the name of the running binary.
+    pub fn fsname(&mut self, fsname: &str) -> &mut Self {
+        self.mountopts.fsname = Some(fsname.to_owned());
+        self
+    }
+
+    /// Set the subtype of the filesystem, which is shown as the type `fuse.<subtype>`.
+    pub fn subtype(&mut self, subtype: &str) -> &mut Self {
+        self.mountopts.subtype = Some(subtype.to_owned());
+        self
+    }
+
     /// Set the maximum readahead.
     pub fn max_readahead(&mut self, value: u32) -> &mut Self {
         self.init_out.max_readahead = value;
@@ -259,12 +617,117 @@ impl KernelConfig {
         );
         if threshold == 0 {
             threshold = self.init_out.max_background * 3 / 4;
-            tracing::debug!(congestion_threshold = threshold);
+            debug!("congestion_threshold = {}", threshold);
         }
         self.init_out.congestion_threshold = threshold;
         self
     }
 
+    /// Enforce `max_background` and `congestion_threshold` on the session side.
+    ///
+    /// The old kernels may not respect the values of `max_background` and
+    /// `congestion_threshold` replied to `INIT`.  When this option is
+    /// enabled, the session counts the background requests in flight (the
+    /// `READ` and `WRITE` of whole pages, `READDIR` and `READDIRPLUS`),
+    /// and `Session::next_request` stops reading the requests once the
+    /// number reaches `max_background` until the replies bring it below
+    /// `congestion_threshold`.  The current number is obtained by
+    /// `Session::background_requests`.
+    ///
+    /// Since receiving is paused until the in-flight requests are replied
+    /// or dropped, they must be processed by other threads or tasks than
+    /// the one calling `next_request`.  While paused, the session still
+    /// reads the device and delivers `FORGET`, `BATCH_FORGET` and
+    /// `INTERRUPT` at once, so that the paused requests can be interrupted.
+    /// The other requests read in the meantime, up to `max_background`,
+    /// are delivered after receiving is resumed.
+    ///
+    /// Disabled by default.
+    pub fn background_admission(&mut self, enabled: bool) -> &mut Self {
+        self.background_admission = enabled;
+        self
+    }
+
+    /// Set the callback invoked when receiving is resumed after being
+    /// paused by `background_admission`.
+    ///
+    /// `Session::try_next_request` returns `None` while receiving is paused,
+    /// even if the file descriptor is readable, and no further readiness
+    /// may be reported to the event loops waiting for the edge-triggered
+    /// one.  Such loops should call `try_next_request` again when this
+    /// callback is invoked, e.g. by waking the task that receives the requests.
+    ///
+    /// The callback is invoked on the thread replying to or dropping the
+    /// request that resumes receiving, and hence it should not block.
+    pub fn on_background_resumed<F>(&mut self, callback: F) -> &mut Self
+    where
+        F: Fn() + Send + Sync + 'static,
+    {
+        self.background_resumed = Some(Arc::new(callback));
+        self
+    }
+
+    /// Count the lookups of inodes on the side of the session, for
+    /// debugging the bookkeeping of the filesystem.
+    ///
+    /// When enabled, the session increments the lookup count of the inodes
+    /// in the entries it replies and decrements it on the forgets, and the
+    /// counts are obtained by `Session::lookup_counts`.  The filesystem can
+    /// compare them with its own table with `LookupCounts::diff`.
+    ///
+    /// Disabled by default.
+    pub fn lookup_audit(&mut self, enabled: bool) -> &mut Self {
+        self.lookup_audit = enabled;
+        self
+    }
+
+    /// Reply `ESTALE` to the requests for the inodes unknown to the kernel,
+    /// without delivering them to the filesystem.
+    ///
+    /// The session tracks the lookup counts in the same way as
+    /// `lookup_audit`, and checks the node ID of each request against them
+    /// according to `tracking`.  This protects the filesystem from the late
+    /// requests racing with `FORGET`, which would otherwise refer to the
+    /// entries already removed from its inode table.
+    ///
+    /// The root inode and the inodes specified by `live_inode` are always
+    /// accepted.  The other node IDs in the arguments, such as the new
+    /// parent of `RENAME`, are not checked, except for the destination of
+    /// `COPY_FILE_RANGE`, which is replied `EXDEV` if it is unknown.  The
+    /// rejected requests are counted in `Session::stale_requests`.
+    pub fn reject_stale_inodes(&mut self, tracking: InodeTracking) -> &mut Self {
+        self.stale_inodes = Some(tracking);
+        self
+    }
+
+    /// Process the requests on the same inode in the order of arrival.
+    ///
+    /// The kernel does not guarantee the order of concurrent requests, so
+    /// a `WRITE` and the `SETATTR` truncating the file after it may be
+    /// processed in the reversed order when they are dispatched to different
+    /// threads.  When enabled, each request takes its turn on the inode when
+    /// it is received, and `Request::operation` blocks until the requests
+    /// on the same inode received before it are replied or dropped.  The
+    /// requests on the other inodes are still processed in parallel.
+    ///
+    /// Since a request waits for the preceding ones, they must be processed
+    /// by other threads than the one calling `operation`, and the asynchronous
+    /// tasks should decode the requests with `spawn_blocking` or similar.  For
+    /// serializing only a part of the operations, use `util::InodeLocks` instead.
+    ///
+    /// Disabled by default.
+    pub fn serialize_per_inode(&mut self, enabled: bool) -> &mut Self {
+        self.serialize_per_inode = enabled;
+        self
+    }
+
+    /// Specify an inode accepted by `reject_stale_inodes` regardless of its
+    /// lookup count, e.g. the one announced before the session restarts.
+    pub fn live_inode(&mut self, ino: u64) -> &mut Self {
+        self.live_inodes.insert(ino);
+        self
+    }
+
     /// Set the timestamp resolution supported by the filesystem.
     ///
     /// The setting value has the nanosecond unit and should be a power of 10.
@@ -274,950 +737,5482 @@ impl KernelConfig {
         self.init_out.time_gran = time_gran;
         self
     }
+
+    /// Check the combination of the options, as `Session::mount` does
+    /// before mounting the filesystem.
+    ///
+    /// The options are applied in any order, so some mistakes can only be
+    /// found after all of them are set, e.g. `max_background` lowered after
+    /// `congestion_threshold`.  The returned error contains `ConfigError`.
+    pub fn validate(&self) -> io::Result<()> {
+        for &(option, errno) in &[
+            ("denied_opcode_errno", self.denied_opcode_errno),
+            ("deadline_errno", self.deadline_errno),
+        ] {
+            match errno {
+                Some(errno) if !(1..=4095).contains(&errno) => {
+                    return Err(ConfigError::InvalidErrno(option, errno).into());
+                }
+                _ => (),
+            }
+        }
+
+        if let Some(blksize) = self.mountopts.blksize {
+            if blksize < 512 || !blksize.is_power_of_two() {
+                return Err(ConfigError::InvalidValue("blksize", blksize).into());
+            }
+        }
+
+        if self.init_out.congestion_threshold > self.init_out.max_background {
+            return Err(ConfigError::Conflict("congestion_threshold", "max_background").into());
+        }
+
+        if self.denied_opcode_errno.is_some() && self.opcode_filter.is_none() {
+            return Err(ConfigError::Requires("denied_opcode_errno", "opcode_filter").into());
+        }
+        if self.deadline_errno.is_some() && self.deadlines.is_empty() {
+            return Err(ConfigError::Requires("deadline_errno", "deadline").into());
+        }
+        if !self.live_inodes.is_empty() && self.stale_inodes.is_none() {
+            return Err(ConfigError::Requires("live_inode", "reject_stale_inodes").into());
+        }
+
+        Ok(())
+    }
 }
 
-// ==== Session ====
+/// The error of the inconsistent options in `KernelConfig`.
+///
+/// It is returned from `KernelConfig::validate` and `Session::mount` as the
+/// inner error of `io::Error` with the kind `InvalidInput`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConfigError {
+    /// The option is given an error number out of the valid range.
+    InvalidErrno(&'static str, i32),
 
-/// The object containing the contextrual information about a FUSE session.
-pub struct Session {
-    inner: Arc<SessionInner>,
+    /// The option is given a value out of the valid range.
+    InvalidValue(&'static str, u32),
+
+    /// The values of the two options contradict each other.
+    Conflict(&'static str, &'static str),
+
+    /// The first option has no effect without the second one.
+    Requires(&'static str, &'static str),
 }
 
-impl fmt::Debug for Session {
+impl fmt::Display for ConfigError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Session").finish()
+        match self {
+            Self::InvalidErrno(option, errno) => {
+                write!(f, "`{}` is not a valid error number: {}", option, errno)
+            }
+            Self::InvalidValue(option, value) => {
+                write!(f, "`{}` is not a valid value: {}", option, value)
+            }
+            Self::Conflict(option, other) => write!(f, "`{}` conflicts with `{}`", option, other),
+            Self::Requires(option, other) => write!(f, "`{}` requires `{}`", option, other),
+        }
     }
 }
 
-struct SessionInner {
-    conn: Connection,
-    init_out: fuse_init_out,
-    bufsize: usize,
-    exited: AtomicBool,
-    notify_unique: AtomicU64,
+impl std::error::Error for ConfigError {}
+
+impl From<ConfigError> for io::Error {
+    fn from(err: ConfigError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, err)
+    }
 }
 
-impl SessionInner {
+// ==== CapabilityFlags ====
+
+/// A set of capability flags exchanged in the `INIT` handshake.
+#[derive(Copy, Clone, PartialEq, Eq, Hash)]
+pub struct CapabilityFlags(u32);
+
+const CAPABILITY_FLAG_NAMES: &[(u32, &str)] = &[
+    (FUSE_ASYNC_READ, "ASYNC_READ"),
+    (FUSE_POSIX_LOCKS, "POSIX_LOCKS"),
+    (FUSE_FILE_OPS, "FILE_OPS"),
+    (FUSE_ATOMIC_O_TRUNC, "ATOMIC_O_TRUNC"),
+    (FUSE_EXPORT_SUPPORT, "EXPORT_SUPPORT"),
+    (FUSE_BIG_WRITES, "BIG_WRITES"),
+    (FUSE_DONT_MASK, "DONT_MASK"),
+    (FUSE_SPLICE_WRITE, "SPLICE_WRITE"),
+    (FUSE_SPLICE_MOVE, "SPLICE_MOVE"),
+    (FUSE_SPLICE_READ, "SPLICE_READ"),
+    (FUSE_FLOCK_LOCKS, "FLOCK_LOCKS"),
+    (FUSE_HAS_IOCTL_DIR, "HAS_IOCTL_DIR"),
+    (FUSE_AUTO_INVAL_DATA, "AUTO_INVAL_DATA"),
+    (FUSE_DO_READDIRPLUS, "DO_READDIRPLUS"),
+    (FUSE_READDIRPLUS_AUTO, "READDIRPLUS_AUTO"),
+    (FUSE_ASYNC_DIO, "ASYNC_DIO"),
+    (FUSE_WRITEBACK_CACHE, "WRITEBACK_CACHE"),
+    (FUSE_NO_OPEN_SUPPORT, "NO_OPEN_SUPPORT"),
+    (FUSE_PARALLEL_DIROPS, "PARALLEL_DIROPS"),
+    (FUSE_HANDLE_KILLPRIV, "HANDLE_KILLPRIV"),
+    (FUSE_POSIX_ACL, "POSIX_ACL"),
+    (FUSE_ABORT_ERROR, "ABORT_ERROR"),
+    (FUSE_MAX_PAGES, "MAX_PAGES"),
+    (FUSE_CACHE_SYMLINKS, "CACHE_SYMLINKS"),
+    (FUSE_NO_OPENDIR_SUPPORT, "NO_OPENDIR_SUPPORT"),
+    (FUSE_EXPLICIT_INVAL_DATA, "EXPLICIT_INVAL_DATA"),
+];
+
+impl CapabilityFlags {
+    /// Create a set of flags from the raw value.
     #[inline]
-    fn exited(&self) -> bool {
-        // FIXME: choose appropriate atomic ordering.
-        self.exited.load(Ordering::SeqCst)
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
     }
 
+    /// Return the raw value of flags.
     #[inline]
-    fn exit(&self) {
-        // FIXME: choose appropriate atomic ordering.
-        self.exited.store(true, Ordering::SeqCst)
+    pub const fn bits(self) -> u32 {
+        self.0
     }
-}
 
-impl Drop for Session {
-    fn drop(&mut self) {
-        self.inner.exit();
+    /// Return whether all of the specified flags are contained.
+    #[inline]
+    pub const fn contains(self, flags: u32) -> bool {
+        self.0 & flags == flags
     }
-}
 
-impl AsRawFd for Session {
-    fn as_raw_fd(&self) -> RawFd {
-        self.inner.conn.as_raw_fd()
+    /// Iterate over the names of flags contained in this set.
+    ///
+    /// The bits unknown to polyfuse are not included.
+    pub fn names(self) -> impl Iterator<Item = &'static str> {
+        CAPABILITY_FLAG_NAMES
+            .iter()
+            .filter(move |&&(flag, _)| self.0 & flag != 0)
+            .map(|&(_, name)| name)
     }
 }
 
-impl Session {
-    /// Start a FUSE daemon mount on the specified path.
-    pub fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
-        let KernelConfig {
-            mountopts,
-            mut init_out,
-        } = config;
+impl fmt::Display for CapabilityFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut first = true;
+        let mut put = |f: &mut fmt::Formatter<'_>, args: fmt::Arguments<'_>| {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            first = false;
+            f.write_fmt(args)
+        };
 
-        let conn = Connection::open(mountpoint, mountopts)?;
+        for name in self.names() {
+            put(f, format_args!("{}", name))?;
+        }
 
-        init_session(&mut init_out, &conn, &conn)?;
-        let bufsize = BUFFER_HEADER_SIZE + init_out.max_write as usize;
+        let unknown = CAPABILITY_FLAG_NAMES
+            .iter()
+            .fold(self.0, |bits, &(flag, _)| bits & !flag);
+        if unknown != 0 {
+            put(f, format_args!("0x{:08x}", unknown))?;
+        }
 
-        Ok(Self {
-            inner: Arc::new(SessionInner {
-                conn,
-                init_out,
-                bufsize,
-                exited: AtomicBool::new(false),
-                notify_unique: AtomicU64::new(0),
-            }),
-        })
-    }
+        if first {
+            f.write_str("(empty)")?;
+        }
 
-    /// Return whether the kernel supports for zero-message opens.
-    ///
-    /// When the returned value is `true`, the kernel treat an `ENOSYS`
-    /// error for a `FUSE_OPEN` request as successful and does not send
-    /// subsequent `open` requests.  Otherwise, the filesystem should
-    /// implement the handler for `open` requests appropriately.
-    pub fn no_open_support(&self) -> bool {
-        self.inner.init_out.flags & FUSE_NO_OPEN_SUPPORT != 0
+        Ok(())
     }
+}
 
-    /// Return whether the kernel supports for zero-message opendirs.
-    ///
-    /// See the documentation of `no_open_support` for details.
-    pub fn no_opendir_support(&self) -> bool {
-        self.inner.init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0
+impl fmt::Debug for CapabilityFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "CapabilityFlags({})", self)
     }
+}
 
-    /// Receive an incoming FUSE request from the kernel.
-    pub fn next_request(&self) -> io::Result<Option<Request>> {
-        let mut conn = &self.inner.conn;
+// ==== ReaddirplusMode ====
 
-        // FIXME: Align the allocated region in `arg` with the FUSE argument types.
-        let mut header = fuse_in_header::default();
-        let mut arg = vec![0u8; self.inner.bufsize - mem::size_of::<fuse_in_header>()];
+/// How the kernel reads the directories, as negotiated in the `INIT` handshake.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ReaddirplusMode {
+    /// Only `READDIR` is sent.
+    Disabled,
 
-        loop {
-            match conn.read_vectored(&mut [
-                io::IoSliceMut::new(header.as_bytes_mut()),
-                io::IoSliceMut::new(&mut arg[..]),
-            ]) {
-                Ok(len) => {
-                    if len < mem::size_of::<fuse_in_header>() {
-                        return Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "dequeued request message is too short",
-                        ));
-                    }
-                    unsafe {
-                        arg.set_len(len - mem::size_of::<fuse_in_header>());
-                    }
+    /// `READDIRPLUS` is always sent instead of `READDIR`.
+    Always,
 
-                    break;
-                }
+    /// The kernel chooses between `READDIR` and `READDIRPLUS` for each
+    /// read, as described in `KernelConfig::readdirplus_auto`.
+    Auto,
+}
 
-                Err(err) => match err.raw_os_error() {
-                    Some(libc::ENODEV) => {
-                        tracing::debug!("ENODEV");
-                        return Ok(None);
-                    }
-                    Some(libc::ENOENT) => {
-                        tracing::debug!("ENOENT");
-                        continue;
-                    }
-                    _ => return Err(err),
-                },
-            }
+impl ReaddirplusMode {
+    fn from_flags(flags: u32) -> Self {
+        if flags & FUSE_DO_READDIRPLUS == 0 {
+            Self::Disabled
+        } else if flags & FUSE_READDIRPLUS_AUTO == 0 {
+            Self::Always
+        } else {
+            Self::Auto
         }
-
-        Ok(Some(Request {
-            session: self.inner.clone(),
-            header,
-            arg,
-        }))
     }
+}
 
-    /// Create an instance of `Notifier` corresponding to this session.
-    pub fn notifier(&self) -> Notifier {
-        Notifier {
-            session: self.inner.clone(),
-        }
-    }
+// ==== SessionState ====
+
+const SESSION_STATE_MAGIC: &[u8; 4] = b"PFSS";
+const SESSION_STATE_VERSION: u32 = 2;
+const SESSION_STATE_LEN: usize = SESSION_STATE_MAGIC.len()
+    + mem::size_of::<u32>()
+    + mem::size_of::<fuse_init_in>()
+    + mem::size_of::<fuse_init_out>()
+    + mem::size_of::<u32>();
+
+/// The upper limit of the serialized state accepted by `Session::take_over`.
+const MAX_SESSION_STATE_LEN: usize = 256 * 1024 * 1024;
+
+const SESSION_STATE_STATELESS_IO: u32 = 1 << 0;
+const SESSION_STATE_LOOKUPS: u32 = 1 << 1;
+
+/// The state of a session, to resume it in another process.
+///
+/// In addition to the negotiated parameters, the state carries the lookup
+/// counts observed by `KernelConfig::lookup_audit` or
+/// `KernelConfig::reject_stale_inodes`, and whether the files are opened
+/// without `OPEN` as described in `Session::stateless_io` along with the
+/// files opened with the handle `0` before that.
+///
+/// The serialized form is intended to be passed between the processes
+/// on the same host, and hence it is not portable across architectures.
+#[derive(Clone)]
+pub struct SessionState {
+    init_in: fuse_init_in,
+    init_out: fuse_init_out,
+    stateless_io: bool,
+    zero_handles: HashMap<u64, u64>,
+    // `None` if the lookups were not tracked by the session.
+    lookups: Option<LookupCounts>,
 }
 
-fn init_session<R, W>(init_out: &mut fuse_init_out, mut reader: R, mut writer: W) -> io::Result<()>
-where
-    R: io::Read,
-    W: io::Write,
-{
-    // FIXME: align the allocated buffer in `buf` with FUSE argument types.
-    let mut header = fuse_in_header::default();
-    let mut arg = vec![0u8; pagesize() * MAX_MAX_PAGES];
+impl fmt::Debug for SessionState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SessionState")
+            .field("kernel_minor", &self.init_in.minor)
+            .field("minor", &self.init_out.minor)
+            .field("flags", &CapabilityFlags(self.init_out.flags))
+            .field("max_write", &self.init_out.max_write)
+            .field("stateless_io", &self.stateless_io)
+            .field("lookups", &self.lookups.as_ref().map(|counts| counts.len()))
+            .finish()
+    }
+}
 
-    for _ in 0..10 {
-        let len = reader.read_vectored(&mut [
-            io::IoSliceMut::new(header.as_bytes_mut()),
-            io::IoSliceMut::new(&mut arg[..]),
-        ])?;
-        if len < mem::size_of::<fuse_in_header>() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidData,
-                "request message is too short",
-            ));
+impl SessionState {
+    /// Create the state of a session just initialized, where no inode
+    /// other than the root has been looked up.
+    pub(crate) fn new(init_in: fuse_init_in, init_out: fuse_init_out) -> Self {
+        Self {
+            init_in,
+            init_out,
+            stateless_io: false,
+            zero_handles: HashMap::new(),
+            lookups: Some(LookupCounts::default()),
         }
+    }
 
-        let mut decoder = Decoder::new(&arg[..]);
-
-        match fuse_opcode::try_from(header.opcode) {
-            Ok(fuse_opcode::FUSE_INIT) => {
-                let init_in = decoder
-                    .fetch::<fuse_init_in>() //
-                    .map_err(|_| {
-                        io::Error::new(io::ErrorKind::Other, "failed to decode fuse_init_in")
-                    })?;
-
-                let capable = init_in.flags & INIT_FLAGS_MASK;
-                let readonly_flags = init_in.flags & !INIT_FLAGS_MASK;
+    /// Return the negotiated minor version of the protocol.
+    pub fn minor(&self) -> u32 {
+        self.init_out.minor
+    }
 
-                tracing::debug!("INIT request:");
-                tracing::debug!("  proto = {}.{}:", init_in.major, init_in.minor);
-                tracing::debug!("  flags = 0x{:08x} ({:?})", init_in.flags, capable);
-                tracing::debug!("  max_readahead = 0x{:08X}", init_in.max_readahead);
-                tracing::debug!("  max_pages = {}", readonly_flags & FUSE_MAX_PAGES != 0);
-                tracing::debug!(
-                    "  no_open_support = {}",
-                    readonly_flags & FUSE_NO_OPEN_SUPPORT != 0
-                );
-                tracing::debug!(
-                    "  no_opendir_support = {}",
-                    readonly_flags & FUSE_NO_OPENDIR_SUPPORT != 0
-                );
+    /// Return the capability flags granted in the `INIT` handshake.
+    pub fn granted(&self) -> CapabilityFlags {
+        CapabilityFlags(self.init_out.flags)
+    }
 
-                if init_in.major > 7 {
-                    tracing::debug!("wait for a second INIT request with an older version.");
-                    let init_out = fuse_init_out {
-                        major: FUSE_KERNEL_VERSION,
-                        minor: FUSE_KERNEL_MINOR_VERSION,
-                        ..Default::default()
-                    };
-                    write_bytes(
-                        &mut writer,
-                        Reply::new(header.unique, 0, init_out.as_bytes()),
-                    )?;
-                    continue;
-                }
+    /// Serialize the state into bytes.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.stateless_io {
+            flags |= SESSION_STATE_STATELESS_IO;
+        }
+        if self.lookups.is_some() {
+            flags |= SESSION_STATE_LOOKUPS;
+        }
 
-                if init_in.major < 7 || init_in.minor < MINIMUM_SUPPORTED_MINOR_VERSION {
-                    tracing::warn!(
-                        "polyfuse supports only ABI 7.{} or later. {}.{} is not supported",
-                        MINIMUM_SUPPORTED_MINOR_VERSION,
-                        init_in.major,
-                        init_in.minor
-                    );
-                    write_bytes(&mut writer, Reply::new(header.unique, libc::EPROTO, ()))?;
-                    continue;
-                }
+        let mut buf = Vec::with_capacity(SESSION_STATE_LEN);
+        buf.extend_from_slice(SESSION_STATE_MAGIC);
+        buf.extend_from_slice(&SESSION_STATE_VERSION.to_ne_bytes());
+        buf.extend_from_slice(self.init_in.as_bytes());
+        buf.extend_from_slice(self.init_out.as_bytes());
+        buf.extend_from_slice(&flags.to_ne_bytes());
+        put_counts(
+            &mut buf,
+            self.zero_handles.iter().map(|(&ino, &n)| (ino, n)),
+        );
+        if let Some(ref lookups) = self.lookups {
+            put_counts(&mut buf, lookups.iter());
+        }
+        buf
+    }
 
-                init_out.minor = cmp::min(init_out.minor, init_in.minor);
+    /// Deserialize the state from bytes created by `to_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> io::Result<Self> {
+        let invalid = || io::Error::new(io::ErrorKind::InvalidData, "invalid session state");
 
-                init_out.max_readahead = cmp::min(init_out.max_readahead, init_in.max_readahead);
+        let mut decoder = Decoder::new(bytes);
+        let magic = decoder
+            .fetch_bytes(SESSION_STATE_MAGIC.len())
+            .map_err(|_| invalid())?;
+        if magic != SESSION_STATE_MAGIC {
+            return Err(invalid());
+        }
+        let version = decoder
+            .fetch_bytes(mem::size_of::<u32>())
+            .map_err(|_| invalid())?;
+        if version != SESSION_STATE_VERSION.to_ne_bytes() {
+            return Err(invalid());
+        }
 
-                init_out.flags &= capable;
-                init_out.flags |= FUSE_BIG_WRITES; // the flag was superseded by `max_write`.
+        let mut init_in = fuse_init_in::default();
+        init_in.as_bytes_mut().copy_from_slice(
+            decoder
+                .fetch_bytes(mem::size_of::<fuse_init_in>())
+                .map_err(|_| invalid())?,
+        );
+        let mut init_out = fuse_init_out::default();
+        init_out.as_bytes_mut().copy_from_slice(
+            decoder
+                .fetch_bytes(mem::size_of::<fuse_init_out>())
+                .map_err(|_| invalid())?,
+        );
 
-                if init_in.flags & FUSE_MAX_PAGES != 0 {
-                    init_out.flags |= FUSE_MAX_PAGES;
-                    init_out.max_pages = cmp::min(
-                        (init_out.max_write - 1) / (pagesize() as u32) + 1,
-                        u16::max_value() as u32,
-                    ) as u16;
-                }
+        let flags = fetch_u32(&mut decoder).ok_or_else(invalid)?;
+        if flags & !(SESSION_STATE_STATELESS_IO | SESSION_STATE_LOOKUPS) != 0 {
+            return Err(invalid());
+        }
 
-                debug_assert_eq!(init_out.major, FUSE_KERNEL_VERSION);
-                debug_assert!(init_out.minor >= MINIMUM_SUPPORTED_MINOR_VERSION);
-
-                tracing::debug!("Reply to INIT:");
-                tracing::debug!("  proto = {}.{}:", init_out.major, init_out.minor);
-                tracing::debug!("  flags = 0x{:08x}", init_out.flags);
-                tracing::debug!("  max_readahead = 0x{:08X}", init_out.max_readahead);
-                tracing::debug!("  max_write = 0x{:08X}", init_out.max_write);
-                tracing::debug!("  max_background = 0x{:04X}", init_out.max_background);
-                tracing::debug!(
-                    "  congestion_threshold = 0x{:04X}",
-                    init_out.congestion_threshold
-                );
-                tracing::debug!("  time_gran = {}", init_out.time_gran);
-                write_bytes(writer, Reply::new(header.unique, 0, init_out.as_bytes()))?;
+        let zero_handles = fetch_counts(&mut decoder).ok_or_else(invalid)?;
+        let lookups = if flags & SESSION_STATE_LOOKUPS != 0 {
+            let counts = fetch_counts(&mut decoder).ok_or_else(invalid)?;
+            Some(LookupCounts::from_counts(counts))
+        } else {
+            None
+        };
 
-                init_out.flags |= readonly_flags;
+        Ok(Self {
+            init_in,
+            init_out,
+            stateless_io: flags & SESSION_STATE_STATELESS_IO != 0,
+            zero_handles,
+            lookups,
+        })
+    }
+}
 
-                return Ok(());
-            }
+/// Serialize the non-zero counts of inodes, in the order of inode numbers
+/// so that the same counts are always serialized identically.
+fn put_counts(buf: &mut Vec<u8>, counts: impl Iterator<Item = (u64, u64)>) {
+    let mut counts: Vec<_> = counts.collect();
+    counts.sort_unstable();
+    buf.extend_from_slice(&(counts.len() as u64).to_ne_bytes());
+    for (ino, count) in counts {
+        buf.extend_from_slice(&ino.to_ne_bytes());
+        buf.extend_from_slice(&count.to_ne_bytes());
+    }
+}
 
-            _ => {
-                tracing::warn!(
-                    "ignoring an operation before init (opcode={:?})",
-                    header.opcode
-                );
-                write_bytes(&mut writer, Reply::new(header.unique, libc::EIO, ()))?;
-                continue;
-            }
+fn fetch_counts(decoder: &mut Decoder<'_>) -> Option<HashMap<u64, u64>> {
+    let len = fetch_u64(decoder)?;
+    let mut counts = HashMap::new();
+    for _ in 0..len {
+        let ino = fetch_u64(decoder)?;
+        let count = fetch_u64(decoder)?;
+        if count == 0 || counts.insert(ino, count).is_some() {
+            return None;
         }
     }
-
-    Err(io::Error::new(
-        io::ErrorKind::ConnectionRefused,
-        "session initialization is aborted",
-    ))
+    Some(counts)
 }
 
-// ==== Request ====
+fn fetch_u32(decoder: &mut Decoder<'_>) -> Option<u32> {
+    let mut n = [0u8; 4];
+    n.copy_from_slice(decoder.fetch_bytes(4).ok()?);
+    Some(u32::from_ne_bytes(n))
+}
 
-/// Context about an incoming FUSE request.
-pub struct Request {
-    session: Arc<SessionInner>,
-    header: fuse_in_header,
-    arg: Vec<u8>,
+fn fetch_u64(decoder: &mut Decoder<'_>) -> Option<u64> {
+    let mut n = [0u8; 8];
+    n.copy_from_slice(decoder.fetch_bytes(8).ok()?);
+    Some(u64::from_ne_bytes(n))
 }
 
-impl Request {
-    /// Return the unique ID of the request.
-    #[inline]
-    pub fn unique(&self) -> u64 {
-        self.header.unique
-    }
+// ==== Session ====
 
-    /// Return the user ID of the calling process.
-    #[inline]
-    pub fn uid(&self) -> u32 {
-        self.header.uid
-    }
+/// The reason why the connection with the kernel has been closed.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ConnectionClosed {
+    /// The filesystem has been unmounted.
+    Unmounted,
 
-    /// Return the group ID of the calling process.
-    #[inline]
-    pub fn gid(&self) -> u32 {
-        self.header.gid
-    }
+    /// The connection has been aborted, e.g. via `/sys/fs/fuse/connections/*/abort`.
+    ///
+    /// This reason is reported only if `KernelConfig::abort_error` is negotiated.
+    Aborted,
 
-    /// Return the process ID of the calling process.
-    #[inline]
-    pub fn pid(&self) -> u32 {
-        self.header.pid
+    /// The session has exited, by receiving a `DESTROY` request or dropping `Session`.
+    Exited,
+
+    /// The replies have repeatedly failed to be written, as configured by
+    /// `KernelConfig::max_reply_failures`.
+    ReplyFailed,
+}
+
+impl ConnectionClosed {
+    /// Return the error number that the kernel reports for this reason.
+    pub fn errno(self) -> i32 {
+        match self {
+            Self::Unmounted => libc::ENODEV,
+            Self::Aborted => libc::ECONNABORTED,
+            Self::Exited => libc::ENOTCONN,
+            Self::ReplyFailed => libc::EIO,
+        }
     }
+}
 
-    /// Decode the argument of this request.
-    pub fn operation(&self) -> Result<Operation<'_, Data<'_>>, DecodeError> {
-        if self.session.exited() {
-            return Ok(Operation::unknown());
+impl fmt::Display for ConnectionClosed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unmounted => f.write_str("the filesystem has been unmounted"),
+            Self::Aborted => f.write_str("the connection has been aborted"),
+            Self::Exited => f.write_str("the session has exited"),
+            Self::ReplyFailed => f.write_str("the replies cannot be written to the connection"),
         }
+    }
+}
 
-        let (arg, data) = match fuse_opcode::try_from(self.header.opcode).ok() {
-            Some(fuse_opcode::FUSE_WRITE) | Some(fuse_opcode::FUSE_NOTIFY_REPLY) => {
-                self.arg.split_at(mem::size_of::<fuse_write_in>())
-            }
-            _ => (&self.arg[..], &[] as &[_]),
-        };
+impl std::error::Error for ConnectionClosed {}
+
+/// The error returned when a request has already been replied.
+///
+/// The kernel accepts exactly one reply for each request, so the session
+/// keeps track of the requests waiting for their replies and rejects the
+/// replies sent after the first one, even if they race from different
+/// threads.  The error is carried by `io::Error` with the kind `InvalidInput`.
+///
+/// The same error is returned for the requests that never take a reply,
+/// i.e. `FORGET`, `BATCH_FORGET` and `NOTIFY_REPLY`, whose replies are
+/// not sent at all since the kernel would reject them as unknown.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct AlreadyReplied {
+    unique: u64,
+}
 
-        Operation::decode(&self.header, arg, Data { data })
+impl AlreadyReplied {
+    /// Return the unique ID of the request.
+    pub fn unique(&self) -> u64 {
+        self.unique
     }
 
-    pub fn reply<T>(&self, arg: T) -> io::Result<()>
-    where
-        T: Bytes,
-    {
-        write_bytes(&self.session.conn, Reply::new(self.unique(), 0, arg))
+    /// Return whether the I/O error is caused by `AlreadyReplied`.
+    pub fn is(err: &io::Error) -> bool {
+        matches!(err.get_ref(), Some(inner) if inner.is::<Self>())
     }
+}
 
-    pub fn reply_error(&self, code: i32) -> io::Result<()> {
-        write_bytes(&self.session.conn, Reply::new(self.unique(), code, ()))
+impl fmt::Display for AlreadyReplied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the request has already been replied (unique = {})",
+            self.unique
+        )
     }
 }
 
-/// The remaining part of request message.
-pub struct Data<'op> {
-    data: &'op [u8],
+impl std::error::Error for AlreadyReplied {}
+
+/// The object containing the contextrual information about a FUSE session.
+pub struct Session {
+    inner: Arc<SessionInner>,
 }
 
-impl fmt::Debug for Data<'_> {
+impl fmt::Debug for Session {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        f.debug_struct("Data").finish()
+        f.debug_struct("Session").finish()
     }
 }
 
-impl<'op> io::Read for Data<'op> {
-    #[inline]
-    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        io::Read::read(&mut self.data, buf)
-    }
-
-    #[inline]
-    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
-        io::Read::read_vectored(&mut self.data, bufs)
-    }
+struct SessionInner {
+    conn: Connection,
+    init_in: fuse_init_in,
+    init_out: fuse_init_out,
+    receive_buffer: ReceiveBuffer,
+    exited: AtomicBool,
+    #[cfg(feature = "notify")]
+    notify_unique: AtomicU64,
+    // The uniques of the latest replies failed to be written, up to `MAX_FAILED_REPLIES`.
+    failed_replies: Mutex<VecDeque<u64>>,
+    closed: Mutex<Option<ConnectionClosed>>,
+    #[cfg(feature = "notify")]
+    exit_wakers: Mutex<Vec<Waker>>,
+    generations: GenerationAudit,
+    callers: CallerCache,
+    caller_filter: Option<Arc<CallerFilter>>,
+    opcode_filter: Option<Arc<OpcodeFilter>>,
+    denied_opcode_errno: Option<i32>,
+    unknown_opcode_eopnotsupp: Option<u32>,
+    denied_requests: AtomicU64,
+    aborted_replies: AtomicU64,
+    max_reply_failures: Option<u32>,
+    // The number of consecutive failures of writing replies.
+    reply_failures: AtomicU32,
+    deadlines: HashMap<OpcodeClass, Duration>,
+    deadline_errno: i32,
+    strict: bool,
+    no_interrupt: bool,
+    reply_interceptor: Option<Arc<dyn ReplyInterceptor>>,
+    max_read: Option<u32>,
+    blksize: Option<u32>,
+    early_requests: Mutex<VecDeque<EarlyRequest>>,
+    stateless_io: AtomicBool,
+    // The number of open files with the handle `0` for each inode, counted
+    // while `FUSE_NO_OPEN_SUPPORT` is granted.
+    zero_handles: Mutex<HashMap<u64, u64>>,
+    // The uniques of requests waiting for their replies.
+    in_flight: Mutex<HashSet<u64>>,
+    background: Option<BackgroundAdmission>,
+    lookups: Option<LookupAudit>,
+    stale_inodes: Option<InodeTracking>,
+    live_inodes: HashSet<u64>,
+    stale_requests: AtomicU64,
+    inode_locks: Option<InodeLocks>,
+    // The senders of the replies to retrieves issued by `Notifier::retrieve_range`.
+    #[cfg(feature = "notify")]
+    retrievals: Mutex<HashMap<u64, mpsc::Sender<RetrieveReply>>>,
 }
 
-impl<'op> BufRead for Data<'op> {
+impl SessionInner {
     #[inline]
-    fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        io::BufRead::fill_buf(&mut self.data)
+    fn exited(&self) -> bool {
+        // FIXME: choose appropriate atomic ordering.
+        self.exited.load(Ordering::SeqCst)
     }
 
-    #[inline]
-    fn consume(&mut self, amt: usize) {
-        io::BufRead::consume(&mut self.data, amt)
+    fn exit(&self) {
+        // FIXME: choose appropriate atomic ordering.
+        self.exited.store(true, Ordering::SeqCst);
+        #[cfg(feature = "notify")]
+        for waker in self.exit_wakers.lock().unwrap().drain(..) {
+            waker.wake();
+        }
+        if let Some(ref background) = self.background {
+            background.wake();
+        }
+        #[cfg(feature = "notify")]
+        self.cancel_retrievals();
     }
-}
 
-// ==== Notifier ====
-
-#[derive(Clone)]
-pub struct Notifier {
-    session: Arc<SessionInner>,
-}
+    /// Return whether receiving is paused by the background admission.
+    fn is_paused(&self) -> bool {
+        matches!(self.background, Some(ref background) if background.is_paused())
+    }
 
-impl Notifier {
-    /// Notify the cache invalidation about an inode to the kernel.
-    pub fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_inval_inode_out>(),
+    /// Return whether another request can be put off while receiving is paused.
+    fn can_put_off(&self) -> bool {
+        matches!(
+            self.background,
+            Some(ref background)
+                if self.early_requests.lock().unwrap().len() < background.max_background()
         )
-        .unwrap();
-
-        return write_bytes(
-            &self.session.conn,
-            InvalInode {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_inval_inode_out { ino, off, len },
-            },
-        );
+    }
 
-        struct InvalInode {
-            header: fuse_out_header,
-            arg: fuse_notify_inval_inode_out,
+    /// Deliver the request read from the device, or put it off while
+    /// receiving is paused unless it is a forget or an interrupt.
+    fn dispatch(
+        self: &Arc<Self>,
+        header: fuse_in_header,
+        arg: Vec<u8>,
+        received: Instant,
+    ) -> io::Result<Option<Request>> {
+        #[cfg(feature = "notify")]
+        if self.deliver_retrieved(&header, &arg[..]) {
+            return Ok(None);
         }
-        impl Bytes for InvalInode {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
-
-            fn count(&self) -> usize {
-                2
-            }
-
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-            }
+        if self.is_paused() && !is_urgent(header.opcode) {
+            self.early_requests
+                .lock()
+                .unwrap()
+                .push_back((header, arg, received));
+            return Ok(None);
+        }
+        if !self.accept(&header, &arg[..])? {
+            return Ok(None);
         }
+        self.new_request(header, arg, received)
     }
 
-    /// Notify the invalidation about a directory entry to the kernel.
-    pub fn inval_entry<T>(&self, parent: u64, name: T) -> io::Result<()>
-    where
-        T: AsRef<OsStr>,
-    {
-        let namelen = u32::try_from(name.as_ref().len()).expect("provided name is too long");
+    /// Apply the filter of callers, and reply `EACCES` if the request is rejected.
+    fn accept(&self, header: &fuse_in_header, arg: &[u8]) -> io::Result<bool> {
+        if header.opcode == fuse_opcode::FUSE_READDIRPLUS as u32
+            && self.init_out.flags & FUSE_DO_READDIRPLUS == 0
+        {
+            // The entries of the reply would be parsed in the wrong format.
+            warn!(
+                "READDIRPLUS is received without readdirplus (unique = {})",
+                header.unique
+            );
+            self.reply_error(header, libc::ENOSYS)?;
+            return Ok(false);
+        }
 
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_inval_entry_out>()
-                + name.as_ref().len()
-                + 1,
-        )
-        .unwrap();
-
-        return write_bytes(
-            &self.session.conn,
-            InvalEntry {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_inval_entry_out {
-                    parent,
-                    namelen,
-                    padding: 0,
-                },
-                name,
-            },
-        );
+        if header.opcode == fuse_opcode::FUSE_INTERRUPT as u32 && self.no_interrupt {
+            debug!("decline the interrupt (unique = {})", header.unique);
+            self.reply_error(header, libc::ENOSYS)?;
+            return Ok(false);
+        }
 
-        struct InvalEntry<T>
-        where
-            T: AsRef<OsStr>,
+        if header.opcode == fuse_opcode::FUSE_FSYNC as u32
+            || header.opcode == fuse_opcode::FUSE_FSYNCDIR as u32
         {
-            header: fuse_out_header,
-            arg: fuse_notify_inval_entry_out,
-            name: T,
+            let unknown = Decoder::new(arg)
+                .fetch::<fuse_fsync_in>()
+                .map_or(0, |arg| FsyncFlags::from_bits(arg.fsync_flags).unknown());
+            if unknown != 0 {
+                if self.strict {
+                    error!(
+                        "unknown fsync flags {:#x} (unique = {}); replied EINVAL",
+                        unknown, header.unique
+                    );
+                    self.reply_error(header, libc::EINVAL)?;
+                    return Ok(false);
+                }
+                warn!(
+                    "unknown fsync flags {:#x} are ignored (unique = {})",
+                    unknown, header.unique
+                );
+            }
         }
-        impl<T> Bytes for InvalEntry<T>
-        where
-            T: AsRef<OsStr>,
+
+        if self.caller_filter.is_none()
+            && self.opcode_filter.is_none()
+            && self.stale_inodes.is_none()
         {
-            fn size(&self) -> usize {
-                self.header.len as usize
+            return Ok(true);
+        }
+
+        match fuse_opcode::try_from(header.opcode).ok() {
+            Some(fuse_opcode::FUSE_FORGET)
+            | Some(fuse_opcode::FUSE_BATCH_FORGET)
+            | Some(fuse_opcode::FUSE_INTERRUPT)
+            | Some(fuse_opcode::FUSE_NOTIFY_REPLY)
+            | Some(fuse_opcode::FUSE_DESTROY) => return Ok(true),
+            _ => (),
+        }
+
+        if let Some(ref filter) = self.opcode_filter {
+            if !filter(Opcode::from_raw(header.opcode)) {
+                let errno = self.denied_opcode_errno.unwrap_or_else(|| {
+                    if is_write_opcode(header.opcode) {
+                        libc::EROFS
+                    } else {
+                        libc::ENOSYS
+                    }
+                });
+                debug!(
+                    "deny the request (unique = {}, opcode = {}, errno = {})",
+                    header.unique,
+                    DisplayOpcode(header.opcode),
+                    errno
+                );
+                self.denied_requests.fetch_add(1, Ordering::Relaxed);
+                self.reply_error(header, errno)?;
+                return Ok(false);
             }
+        }
 
-            fn count(&self) -> usize {
-                4
+        if let Some(ref filter) = self.caller_filter {
+            if !filter(&Caller { header, arg }) {
+                debug!(
+                    "reject the request from uid={} (unique = {}, opcode = {})",
+                    header.uid,
+                    header.unique,
+                    DisplayOpcode(header.opcode)
+                );
+                self.reply_error(header, libc::EACCES)?;
+                return Ok(false);
             }
+        }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                dst.put(self.name.as_ref().as_bytes());
-                dst.put(b"\0"); // null terminator
+        if self.is_stale(header.nodeid) {
+            debug!(
+                "reject the request for the stale inode {} (unique = {}, opcode = {})",
+                header.nodeid,
+                header.unique,
+                DisplayOpcode(header.opcode)
+            );
+            self.stale_requests.fetch_add(1, Ordering::Relaxed);
+            self.reply_error(header, libc::ESTALE)?;
+            return Ok(false);
+        }
+
+        if header.opcode == fuse_opcode::FUSE_COPY_FILE_RANGE as u32 && self.stale_inodes.is_some()
+        {
+            if let Ok(Operation::CopyFileRange(op)) =
+                Operation::decode(header, arg, Extensions::default(), ())
+            {
+                if self.is_stale(op.ino_out()) {
+                    debug!(
+                        "reject the copy to the inode {} unknown to the session (unique = {})",
+                        op.ino_out(),
+                        header.unique
+                    );
+                    self.stale_requests.fetch_add(1, Ordering::Relaxed);
+                    self.reply_error(header, libc::EXDEV)?;
+                    return Ok(false);
+                }
             }
         }
+
+        Ok(true)
     }
 
-    /// Notify the invalidation about a directory entry to the kernel.
+    /// Reply an error to the request not delivered to the filesystem.
     ///
-    /// The role of this notification is similar to `notify_inval_entry`.
-    /// Additionally, when the provided `child` inode matches the inode
-    /// in the dentry cache, the inotify will inform the deletion to
-    /// watchers if exists.
-    pub fn delete<T>(&self, parent: u64, child: u64, name: T) -> io::Result<()>
-    where
-        T: AsRef<OsStr>,
-    {
-        let namelen = u32::try_from(name.as_ref().len()).expect("provided name is too long");
-
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_delete_out>()
-                + name.as_ref().len()
-                + 1,
+    /// As with the replies from the filesystem, the failure due to the
+    /// request aborted by the kernel is counted in `Session::aborted_replies`
+    /// rather than returned.
+    fn reply_error(&self, header: &fuse_in_header, errno: i32) -> io::Result<()> {
+        self.observe_error(header.opcode, errno);
+        write_reply(
+            &self.conn,
+            Reply::new(header.unique, errno, ()),
+            &self.aborted_replies,
         )
-        .expect("payload is too long");
-
-        return write_bytes(
-            &self.session.conn,
-            Delete {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_DELETE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_delete_out {
-                    parent,
-                    child,
-                    namelen,
-                    padding: 0,
-                },
-                name,
-            },
-        );
+    }
 
-        struct Delete<T>
-        where
-            T: AsRef<OsStr>,
+    /// Observe an error replied to a request, whether or not it has been
+    /// delivered to the filesystem.
+    fn observe_error(&self, opcode: u32, errno: i32) {
+        if errno == libc::ENOSYS
+            && opcode == fuse_opcode::FUSE_OPEN as u32
+            && self.init_out.flags & FUSE_NO_OPEN_SUPPORT != 0
         {
-            header: fuse_out_header,
-            arg: fuse_notify_delete_out,
-            name: T,
+            // The kernel no longer sends OPEN after this reply.
+            self.stateless_io.store(true, Ordering::Release);
         }
-        impl<T> Bytes for Delete<T>
-        where
-            T: AsRef<OsStr>,
-        {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
+    }
 
-            fn count(&self) -> usize {
-                4
-            }
+    /// Count a file opened with the handle `0`, which `Read::fh` and
+    /// `Write::fh` must not take for the one opened without `OPEN`.
+    fn open_zero_handle(&self, ino: u64) {
+        *self.zero_handles.lock().unwrap().entry(ino).or_insert(0) += 1;
+    }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                dst.put(self.name.as_ref().as_bytes());
-                dst.put(b"\0"); // null terminator
+    fn release_zero_handle(&self, header: &fuse_in_header, arg: &[u8]) {
+        if header.opcode != fuse_opcode::FUSE_RELEASE as u32
+            || self.init_out.flags & FUSE_NO_OPEN_SUPPORT == 0
+        {
+            return;
+        }
+        match Decoder::new(arg).fetch::<fuse_release_in>() {
+            Ok(arg) if arg.fh == 0 => (),
+            _ => return,
+        }
+        let mut zero_handles = self.zero_handles.lock().unwrap();
+        if let Some(count) = zero_handles.get_mut(&header.nodeid) {
+            *count -= 1;
+            if *count == 0 {
+                zero_handles.remove(&header.nodeid);
             }
         }
     }
 
-    /// Push the data in an inode for updating the kernel cache.
-    pub fn store<T>(&self, ino: u64, offset: u64, data: T) -> io::Result<()>
-    where
-        T: Bytes,
-    {
-        let size = u32::try_from(data.size()).expect("provided data is too large");
-
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>()
-                + mem::size_of::<fuse_notify_store_out>()
-                + data.size(),
-        )
-        .expect("payload is too long");
-
-        return write_bytes(
-            &self.session.conn,
-            Store {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_STORE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_store_out {
-                    nodeid: ino,
-                    offset,
-                    size,
-                    padding: 0,
-                },
-                data,
-            },
-        );
+    /// Return whether the handle `0` of a `READ` or `WRITE` on the inode
+    /// refers to a file opened without `OPEN`.
+    fn is_stateless(&self, ino: u64) -> bool {
+        self.stateless_io.load(Ordering::Acquire)
+            && !self.zero_handles.lock().unwrap().contains_key(&ino)
+    }
 
-        struct Store<T>
-        where
-            T: Bytes,
-        {
-            header: fuse_out_header,
-            arg: fuse_notify_store_out,
-            data: T,
+    /// Return whether the inode is rejected by `KernelConfig::reject_stale_inodes`.
+    fn is_stale(&self, ino: u64) -> bool {
+        let lookups = match (self.stale_inodes, &self.lookups) {
+            (Some(..), Some(lookups)) => lookups,
+            _ => return false,
+        };
+        if ino == 0 || ino == ROOT_INO || self.live_inodes.contains(&ino) {
+            return false;
         }
-        impl<T> Bytes for Store<T>
-        where
-            T: Bytes,
-        {
-            fn size(&self) -> usize {
-                self.header.len as usize
-            }
+        match self.stale_inodes {
+            Some(InodeTracking::Forgotten) => lookups.is_forgotten(ino),
+            _ => !lookups.is_live(ino),
+        }
+    }
 
-            fn count(&self) -> usize {
-                2 + self.data.count()
+    /// Build the request to be delivered, or reply to it if its deadline has
+    /// already passed.
+    fn new_request(
+        self: &Arc<Self>,
+        header: fuse_in_header,
+        arg: Vec<u8>,
+        received: Instant,
+    ) -> io::Result<Option<Request>> {
+        if header.opcode == fuse_opcode::FUSE_DESTROY as u32 {
+            debug!("receive DESTROY request; the session is exiting");
+            self.exit();
+        }
+        if cfg!(debug_assertions) || self.lookups.is_some() {
+            self.audit_forgets(&header, &arg);
+        }
+        self.release_zero_handle(&header, &arg);
+        let deadline = match fuse_opcode::try_from(header.opcode).ok() {
+            Some(fuse_opcode::FUSE_FORGET)
+            | Some(fuse_opcode::FUSE_BATCH_FORGET)
+            | Some(fuse_opcode::FUSE_INTERRUPT)
+            | Some(fuse_opcode::FUSE_NOTIFY_REPLY)
+            | Some(fuse_opcode::FUSE_DESTROY) => None,
+            _ => self
+                .deadlines
+                .get(&OpcodeClass::of(header.opcode))
+                .map(|budget| received + *budget),
+        };
+        if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+            debug!(
+                "the request exceeds the deadline before delivery (unique = {}, errno = {})",
+                header.unique, self.deadline_errno
+            );
+            self.reply_error(&header, self.deadline_errno)?;
+            return Ok(None);
+        }
+        let mut req = Request {
+            session: self.clone(),
+            header,
+            arg,
+            deadline,
+            replied: AtomicBool::new(false),
+            reply_errno: AtomicI32::new(NOT_DELIVERED),
+            turn: Mutex::new(Turn::Free),
+            sink: ReplySink::Connection,
+        };
+        if req.expects_reply() {
+            if let Some(ref locks) = self.inode_locks {
+                if header.nodeid != 0 {
+                    req.turn = Mutex::new(Turn::Waiting(locks.enqueue(header.nodeid)));
+                }
             }
-
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
-                self.data.fill_bytes(dst);
+            self.in_flight.lock().unwrap().insert(header.unique);
+            if let Some(ref background) = self.background {
+                background.admit(&req.header, &req.arg[..], pagesize());
             }
         }
+        Ok(Some(req))
     }
 
-    /// Retrieve data in an inode from the kernel cache.
-    pub fn retrieve(&self, ino: u64, offset: u64, size: u32) -> io::Result<u64> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_retrieve_out>(),
-        )
-        .unwrap();
-
-        // FIXME: choose appropriate memory ordering.
-        let notify_unique = self.session.notify_unique.fetch_add(1, Ordering::SeqCst);
-
-        write_bytes(
-            &self.session.conn,
-            Retrieve {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_RETRIEVE as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_retrieve_out {
-                    nodeid: ino,
-                    offset,
-                    size,
-                    notify_unique,
-                    padding: 0,
-                },
-            },
-        )?;
-
-        return Ok(notify_unique);
+    /// Take the right to reply to the request, which only the first caller obtains.
+    fn claim_reply(&self, unique: u64) -> bool {
+        if let Some(ref background) = self.background {
+            background.complete(unique);
+        }
+        self.in_flight.lock().unwrap().remove(&unique)
+    }
 
-        struct Retrieve {
-            header: fuse_out_header,
-            arg: fuse_notify_retrieve_out,
+    fn audit_forgets(&self, header: &fuse_in_header, arg: &[u8]) {
+        match fuse_opcode::try_from(header.opcode).ok() {
+            Some(fuse_opcode::FUSE_FORGET) | Some(fuse_opcode::FUSE_BATCH_FORGET) => (),
+            _ => return,
         }
-        impl Bytes for Retrieve {
-            fn size(&self) -> usize {
-                self.header.len as usize
+        if let Ok(Operation::Forget(forgets)) =
+            Operation::decode(header, arg, Extensions::default(), ())
+        {
+            for forget in forgets.as_ref() {
+                self.generations.forget(forget.ino(), forget.nlookup());
+                if let Some(ref lookups) = self.lookups {
+                    lookups.forget(forget.ino(), forget.nlookup());
+                }
+            }
+        }
+    }
+
+    fn close(&self, reason: ConnectionClosed) {
+        debug!("the connection is closed: {:?}", reason);
+        self.closed.lock().unwrap().get_or_insert(reason);
+        self.exit();
+    }
+
+    /// Count the consecutive failures of writing replies, and close the
+    /// session once they reach `KernelConfig::max_reply_failures`.
+    fn record_reply(&self, res: &io::Result<()>) {
+        let max = match self.max_reply_failures {
+            Some(max) => max,
+            None => return,
+        };
+        match res {
+            Ok(()) => self.reply_failures.store(0, Ordering::Relaxed),
+            Err(err) if matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENODEV)) => (),
+            Err(..) => {
+                if self.reply_failures.fetch_add(1, Ordering::Relaxed) + 1 == max {
+                    error!(
+                        "{} consecutive replies have failed to be written; closing the session",
+                        max
+                    );
+                    self.close(ConnectionClosed::ReplyFailed);
+                }
+            }
+        }
+    }
+
+    /// Stop receiving the requests after closed by `record_reply`.
+    fn check_reply_failed(&self) -> io::Result<()> {
+        if self.exited() {
+            if let Some(reason @ ConnectionClosed::ReplyFailed) = *self.closed.lock().unwrap() {
+                return Err(io::Error::new(io::ErrorKind::BrokenPipe, reason));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        self.inner.exit();
+    }
+}
+
+impl AsRawFd for Session {
+    fn as_raw_fd(&self) -> RawFd {
+        self.inner.conn.as_raw_fd()
+    }
+}
+
+impl Session {
+    /// Start a FUSE daemon mount on the specified path.
+    ///
+    /// If mounting fails, the returned error contains `MountError` that
+    /// describes the cause of failure.  The configuration is checked by
+    /// `KernelConfig::validate` first, and nothing is mounted if it fails.
+    pub fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
+        config.validate()?;
+        let conn = Connection::open(mountpoint, config.mountopts.clone())?;
+        Self::init(conn, config)
+    }
+
+    /// Start a session over the connection, with the handshake of `INIT` request.
+    pub(crate) fn init(conn: Connection, config: KernelConfig) -> io::Result<Self> {
+        let mut handshake = Handshake::new(&config);
+        if let Err(err) = init_session(&mut handshake, &conn, &conn) {
+            // Unmount explicitly so that the mountpoint is not left
+            // in the state of "Transport endpoint is not connected".
+            error!("failed to initialize the session: {}", err);
+            if let Err(unmount_err) = conn.close() {
+                error!("failed to unmount the filesystem: {}", unmount_err);
+            }
+            return Err(err);
+        }
+        let (init_in, init_out, early_requests) = handshake.into_parts();
+        let state = SessionState::new(init_in, init_out);
+        let session = Self::from_parts(conn, state, config, early_requests)?;
+        info!("{}", session.summary());
+        Ok(session)
+    }
+
+    /// Resume a session on the FUSE device already initialized by another process.
+    ///
+    /// The `INIT` handshake is skipped and the negotiated parameters are
+    /// restored from `state`, which is obtained by `Session::state` in the
+    /// previous process.  The file descriptor must be a duplicate of the
+    /// one served by the previous session, e.g. passed over a Unix socket.
+    ///
+    /// The resumed session does not unmount the filesystem on drop.  Note
+    /// that the connection will be aborted by the kernel when the last
+    /// file descriptor referring to it is closed.
+    ///
+    /// Pending requests that the previous process had read but not replied
+    /// will never be completed unless the kernel supports `Notifier::resend`.
+    ///
+    /// As `Session::take_over`, the settings of `config` such as the filters,
+    /// the strict mode and the deadlines are applied to the resumed session,
+    /// and the settings negotiated by `INIT` in `config` are ignored.
+    ///
+    /// # Safety
+    /// The session takes the ownership of `fd` and closes it on drop, as
+    /// `FromRawFd::from_raw_fd`.  The caller must pass an open file descriptor
+    /// of the FUSE device which is not owned by anything else.
+    pub unsafe fn resume(fd: RawFd, state: SessionState, config: KernelConfig) -> io::Result<Self> {
+        let conn = Connection::from_fd(fd)?;
+        Self::from_parts(conn, state, config, VecDeque::new())
+    }
+
+    /// Pass the connection to another process over the Unix socket, for
+    /// upgrading the daemon without unmounting the filesystem.
+    ///
+    /// The file descriptor of the FUSE device is sent with the serialized
+    /// `SessionState`, and the receiver resumes the session by
+    /// `Session::take_over`.  Before calling this, the process must stop
+    /// receiving requests on all threads and reply to the requests it has
+    /// read; otherwise `io::ErrorKind::WouldBlock` is returned without
+    /// sending anything, and the caller can retry after draining them.
+    /// The requests that arrive after the handover are read by the new
+    /// process.
+    ///
+    /// On success, this session exits without unmounting the filesystem.
+    /// The mount bound to `auto_unmount` cannot be handed over, since the
+    /// helper process unmounts it when this process exits; `EOPNOTSUPP` is
+    /// returned in that case.
+    pub fn hand_over(&self, socket: &UnixStream) -> io::Result<()> {
+        if self.inner.exited() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotConnected,
+                self.inner
+                    .closed
+                    .lock()
+                    .unwrap()
+                    .unwrap_or(ConnectionClosed::Exited),
+            ));
+        }
+        if self.inner.conn.auto_unmount() {
+            debug!("the mount with auto_unmount cannot be handed over");
+            return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
+        }
+        let in_flight = self.inner.in_flight.lock().unwrap().len()
+            + self.inner.early_requests.lock().unwrap().len();
+        if in_flight > 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::WouldBlock,
+                format!("{} requests are waiting for their replies", in_flight),
+            ));
+        }
+
+        let state = self.state().to_bytes();
+        let mut msg = Vec::with_capacity(mem::size_of::<u64>() + state.len());
+        msg.extend_from_slice(&(state.len() as u64).to_ne_bytes());
+        msg.extend_from_slice(&state[..]);
+        crate::conn::send_fd(socket, self.as_raw_fd(), &msg[..])?;
+        info!("the connection has been handed over");
+        self.inner.conn.hand_over();
+        self.inner.exit();
+        Ok(())
+    }
+
+    /// Resume the session handed over by `Session::hand_over` in another process.
+    ///
+    /// The negotiated parameters are restored from the previous process, and
+    /// the other settings of `config`, such as the filters, the strict mode
+    /// and the deadlines, are applied to the resumed session.  The settings
+    /// negotiated by `INIT` in `config` are ignored.
+    ///
+    /// The lookup counts are carried over if both processes track them.
+    /// If the previous process did not track them, `InodeTracking::Live`
+    /// would reject every inode known by the kernel, and hence the resumed
+    /// session falls back to `InodeTracking::Forgotten`.  The inodes
+    /// forgotten before the handover are not remembered.
+    pub fn take_over(socket: &UnixStream, config: KernelConfig) -> io::Result<Self> {
+        let mut prefix = [0u8; 8];
+        let (fd, len) = crate::conn::recv_fd(socket, &mut prefix[..])?;
+        let conn = Connection::from_fd(fd)?;
+        if len == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "the socket is closed before receiving the session state",
+            ));
+        }
+        (&*socket).read_exact(&mut prefix[len..])?;
+        let state_len = u64::from_ne_bytes(prefix) as usize;
+        if state_len > MAX_SESSION_STATE_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the session state is too large",
+            ));
+        }
+        let mut buf = vec![0u8; state_len];
+        (&*socket).read_exact(&mut buf[..])?;
+        let state = SessionState::from_bytes(&buf[..])?;
+        info!("take over the connection");
+        Self::from_parts(conn, state, config, VecDeque::new())
+    }
+
+    /// Build a session from the negotiated parameters and the settings of
+    /// `config` other than them.
+    fn from_parts(
+        conn: Connection,
+        state: SessionState,
+        config: KernelConfig,
+        early_requests: VecDeque<EarlyRequest>,
+    ) -> io::Result<Self> {
+        let SessionState {
+            init_in,
+            init_out,
+            stateless_io,
+            zero_handles,
+            lookups: lookup_counts,
+        } = state;
+        let KernelConfig {
+            mountopts,
+            caller_filter,
+            opcode_filter,
+            denied_opcode_errno,
+            unknown_opcode_eopnotsupp,
+            deadlines,
+            deadline_errno,
+            max_reply_failures,
+            strict,
+            no_interrupt,
+            reply_interceptor,
+            background_admission,
+            background_resumed,
+            lookup_audit,
+            stale_inodes,
+            live_inodes,
+            serialize_per_inode,
+            ..
+        } = config;
+
+        let stale_inodes = match (stale_inodes, &lookup_counts) {
+            (Some(InodeTracking::Live), None) => {
+                warn!("the lookup counts are not restored; fall back to InodeTracking::Forgotten");
+                Some(InodeTracking::Forgotten)
+            }
+            (stale_inodes, _) => stale_inodes,
+        };
+        let lookups = match stale_inodes {
+            Some(InodeTracking::Forgotten) => Some(LookupAudit::tracking_forgotten()),
+            Some(InodeTracking::Live) => Some(LookupAudit::default()),
+            None if lookup_audit => Some(LookupAudit::default()),
+            None => None,
+        };
+        if let (Some(lookups), Some(counts)) = (&lookups, lookup_counts) {
+            lookups.restore(counts);
+        }
+        let background = if background_admission {
+            Some(BackgroundAdmission::new(
+                init_out.max_background,
+                init_out.congestion_threshold,
+                background_resumed,
+            )?)
+        } else {
+            None
+        };
+
+        let bufsize = BUFFER_HEADER_SIZE + init_out.max_write as usize;
+        Ok(Self {
+            inner: Arc::new(SessionInner {
+                conn,
+                init_in,
+                init_out,
+                receive_buffer: ReceiveBuffer::new(bufsize),
+                exited: AtomicBool::new(false),
+                #[cfg(feature = "notify")]
+                notify_unique: AtomicU64::new(0),
+                failed_replies: Mutex::new(VecDeque::new()),
+                closed: Mutex::new(None),
+                #[cfg(feature = "notify")]
+                exit_wakers: Mutex::new(vec![]),
+                callers: CallerCache::default(),
+                caller_filter,
+                opcode_filter,
+                denied_opcode_errno,
+                unknown_opcode_eopnotsupp,
+                denied_requests: AtomicU64::new(0),
+                aborted_replies: AtomicU64::new(0),
+                max_reply_failures,
+                reply_failures: AtomicU32::new(0),
+                deadlines,
+                deadline_errno: deadline_errno.unwrap_or(libc::ETIMEDOUT),
+                strict,
+                no_interrupt,
+                reply_interceptor,
+                max_read: mountopts.max_read,
+                blksize: mountopts.blksize,
+                early_requests: Mutex::new(early_requests),
+                stateless_io: AtomicBool::new(stateless_io),
+                zero_handles: Mutex::new(zero_handles),
+                in_flight: Mutex::new(HashSet::new()),
+                background,
+                lookups,
+                stale_inodes,
+                live_inodes,
+                stale_requests: AtomicU64::new(0),
+                inode_locks: if serialize_per_inode {
+                    Some(InodeLocks::new())
+                } else {
+                    None
+                },
+                #[cfg(feature = "notify")]
+                retrievals: Mutex::new(HashMap::new()),
+                generations: GenerationAudit::default(),
+            }),
+        })
+    }
+
+    /// Return the state of this session, for `Session::resume`.
+    pub fn state(&self) -> SessionState {
+        SessionState {
+            init_in: self.inner.init_in,
+            init_out: self.inner.init_out,
+            stateless_io: self.stateless_io(),
+            zero_handles: self.inner.zero_handles.lock().unwrap().clone(),
+            lookups: self.lookup_counts(),
+        }
+    }
+
+    /// Return whether the kernel supports for zero-message opens.
+    ///
+    /// When the returned value is `true`, the kernel treat an `ENOSYS`
+    /// error for a `FUSE_OPEN` request as successful and does not send
+    /// subsequent `open` requests.  Otherwise, the filesystem should
+    /// implement the handler for `open` requests appropriately.
+    pub fn no_open_support(&self) -> bool {
+        self.inner.init_out.flags & FUSE_NO_OPEN_SUPPORT != 0
+    }
+
+    /// Return whether the files are opened without sending `OPEN` requests.
+    ///
+    /// This becomes `true` once `ENOSYS` is replied to an `OPEN` request
+    /// while `no_open_support` is granted, either by the filesystem or by
+    /// `KernelConfig::opcode_filter`.  After that, `Read::fh` and
+    /// `Write::fh` return `None` for the files opened without `OPEN`,
+    /// instead of the handle `0` sent by the kernel, and `RELEASE` is not
+    /// sent for such files.  The files opened before by `OPEN` keep their
+    /// handles, including `0`, until they are released; while such a file
+    /// is open, the handle `0` on its inode is always taken for it.  This
+    /// state is carried over by `SessionState`.
+    pub fn stateless_io(&self) -> bool {
+        self.inner.stateless_io.load(Ordering::Acquire)
+    }
+
+    /// Return whether the kernel sends `IOCTL` requests on directories.
+    pub fn ioctl_dir(&self) -> bool {
+        self.inner.init_in.flags & FUSE_HAS_IOCTL_DIR != 0
+    }
+
+    /// Return whether the kernel supports for zero-message opendirs.
+    ///
+    /// See the documentation of `no_open_support` for details.
+    pub fn no_opendir_support(&self) -> bool {
+        self.inner.init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0
+    }
+
+    /// Return whether the kernel passes `O_TRUNC` through to `Open` requests.
+    ///
+    /// See the documentation of `KernelConfig::atomic_o_trunc` for details.
+    pub fn atomic_o_trunc(&self) -> bool {
+        self.inner.init_out.flags & FUSE_ATOMIC_O_TRUNC != 0
+    }
+
+    /// Return whether the kernel enables the writeback cache.
+    ///
+    /// See `util::SizeEpoch` for reporting the file sizes correctly in this mode.
+    pub fn writeback_cache(&self) -> bool {
+        self.inner.init_out.flags & FUSE_WRITEBACK_CACHE != 0
+    }
+
+    /// Return whether the `INTERRUPT` requests are declined by the session.
+    ///
+    /// See `KernelConfig::no_interrupt` for details.
+    pub fn no_interrupt(&self) -> bool {
+        self.inner.no_interrupt
+    }
+
+    /// Return how the kernel reads the directories.
+    ///
+    /// With `ReaddirplusMode::Always`, the filesystem should optimize
+    /// `READDIRPLUS`, since `READDIR` is never sent.
+    pub fn readdirplus(&self) -> ReaddirplusMode {
+        ReaddirplusMode::from_flags(self.inner.init_out.flags)
+    }
+
+    /// Return the capability flags granted in the `INIT` handshake.
+    ///
+    /// In addition to the flags enabled by `KernelConfig`, the result also
+    /// includes the flags that only the kernel reports, such as `FUSE_NO_OPEN_SUPPORT`.
+    pub fn granted(&self) -> CapabilityFlags {
+        CapabilityFlags(self.inner.init_out.flags)
+    }
+
+    /// Return the capability flags proposed by the kernel in the `INIT` handshake.
+    pub fn offered(&self) -> CapabilityFlags {
+        CapabilityFlags(self.inner.init_in.flags)
+    }
+
+    /// Return a human-readable summary of the negotiated connection parameters.
+    pub fn summary(&self) -> String {
+        let init_in = &self.inner.init_in;
+        let init_out = &self.inner.init_out;
+        format!(
+            "protocol = {}.{} (kernel {}.{}), max_write = {}, max_readahead = {}, \
+             max_background = {}, time_gran = {}, flags = {}",
+            init_out.major,
+            init_out.minor,
+            init_in.major,
+            init_in.minor,
+            init_out.max_write,
+            init_out.max_readahead,
+            init_out.max_background,
+            init_out.time_gran,
+            self.granted(),
+        )
+    }
+
+    /// Return the maximum size of read requests specified by the mount option `max_read`.
+    pub fn max_read(&self) -> Option<u32> {
+        self.inner.max_read
+    }
+
+    /// Return the block size specified by the mount option `blksize`.
+    pub fn blksize(&self) -> Option<u32> {
+        self.inner.blksize
+    }
+
+    /// Return the maximum number of pages in the data of a single request.
+    ///
+    /// The size of `READ` requests does not exceed this number of pages, so
+    /// a `util::AlignedBuf` of this size can be reused for all of the replies.
+    pub fn max_pages(&self) -> usize {
+        if self.inner.init_in.flags & FUSE_MAX_PAGES != 0 && self.inner.init_out.max_pages > 0 {
+            self.inner.init_out.max_pages as usize
+        } else {
+            DEFAULT_MAX_PAGES_PER_REQ
+        }
+    }
+
+    /// Return the size of the buffer required to receive a request message.
+    ///
+    /// The value is the negotiated `max_write` plus the room for the header
+    /// and the arguments of `WRITE` requests.  Reading from the FUSE device
+    /// into a smaller buffer fails with `EINVAL`, in which case the session
+    /// grows the buffer and the returned value is updated.
+    ///
+    /// The session always receives the requests into the buffers allocated
+    /// by itself, so the value is provided only for information.
+    pub fn buffer_size(&self) -> usize {
+        self.inner.receive_buffer.bufsize()
+    }
+
+    /// Read the current flags of the mount from `/proc/self/mountinfo`.
+    ///
+    /// The kernel does not notify the filesystem when the administrator
+    /// remounts it (e.g. `mount -o remount,ro`), so the flags are read on
+    /// every call.  The filesystem may start replying `EROFS` to the write
+    /// operations voluntarily when it observes the read-only state, rather
+    /// than relying on the VFS to reject them.  `statfs(2)` reports the
+    /// flags without the help of the filesystem, since the kernel computes
+    /// them from the mount.
+    ///
+    /// The mount is looked up by its mountpoint, so an error is returned
+    /// if the session is not mounted by `Session::mount` in this process.
+    pub fn mount_flags(&self) -> io::Result<MountFlags> {
+        crate::mountinfo::mount_flags(self.own_mountpoint()?)
+    }
+
+    /// Read the propagation of the mount from `/proc/self/mountinfo`.
+    ///
+    /// `Session::mount` logs a message if the parent mount of the
+    /// mountpoint is not shared, since the filesystem is then invisible
+    /// from the other mount namespaces, e.g. from the host of a container.
+    /// As with `mount_flags`, an error is returned if the session is not
+    /// mounted by `Session::mount` in this process.
+    pub fn mount_propagation(&self) -> io::Result<MountPropagation> {
+        crate::mountinfo::mount_propagation(self.own_mountpoint()?)
+    }
+
+    /// Change the propagation type of the mount with `mount(2)`.
+    ///
+    /// This requires `CAP_SYS_ADMIN` in the user namespace owning the
+    /// mount namespace, and fails with `EPERM` otherwise, since
+    /// `fusermount` offers no way to change it.
+    pub fn set_propagation(&self, propagation: Propagation) -> io::Result<()> {
+        crate::mountinfo::set_propagation(self.own_mountpoint()?, propagation)
+    }
+
+    fn own_mountpoint(&self) -> io::Result<&Path> {
+        self.inner.conn.mountpoint().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::NotFound,
+                "the session is not mounted by this process",
+            )
+        })
+    }
+
+    /// Read the statistics of the connection from the FUSE control filesystem.
+    ///
+    /// The directory of the connection under `/sys/fs/fuse/connections` is
+    /// located by the device number of the mount, which is recorded by
+    /// `Session::mount`.  `KernelStatsError::NotMounted` is returned for the
+    /// sessions resumed or taken over from another process, and
+    /// `KernelStatsError::Unsupported` if the directory is not accessible.
+    pub fn kernel_stats(&self) -> io::Result<KernelStats> {
+        ConnectionDir::locate(self.inner.conn.device())?.read_stats()
+    }
+
+    /// Change the maximum number of background requests without remounting.
+    ///
+    /// Writing to the entry requires the privilege of its owner, usually
+    /// root.  The limit of `KernelConfig::background_admission` is not
+    /// updated, since it follows the values negotiated by `INIT`.
+    pub fn set_max_background(&self, max_background: u16) -> io::Result<()> {
+        ConnectionDir::locate(self.inner.conn.device())?.write("max_background", max_background)
+    }
+
+    /// Change the congestion threshold without remounting.
+    ///
+    /// The same restrictions as `Session::set_max_background` apply.
+    pub fn set_congestion_threshold(&self, threshold: u16) -> io::Result<()> {
+        ConnectionDir::locate(self.inner.conn.device())?.write("congestion_threshold", threshold)
+    }
+
+    /// Unmount the filesystem without dropping the session.
+    ///
+    /// This is intended to be called on shutdown, e.g. from a signal
+    /// handling thread, while the main loop keeps receiving requests until
+    /// the kernel closes the connection.  `UnmountMode::Normal` fails with
+    /// `EBUSY` if the filesystem is in use.  After `UnmountMode::Lazy`, the
+    /// requests for the files still open continue to arrive, and
+    /// `next_request` returns `None` after the last of them is closed.
+    /// `UnmountMode::Force` closes the connection immediately.
+    ///
+    /// An error is returned if the session is not mounted by `Session::mount`
+    /// in this process.  If unmounting fails, it is tried again on drop.
+    /// The error contains `UnmountError` if the filesystem has been moved or
+    /// is not in the mount namespace of this process.
+    pub fn unmount(&self, mode: UnmountMode) -> io::Result<()> {
+        self.inner.conn.unmount_with(mode)
+    }
+
+    /// Describe the mount registered by `Session::mount`, in the same form
+    /// as `mount(8)` lists it (e.g. `myfsd on /mnt/x type fuse.backup`).
+    ///
+    /// `None` is returned if the session is not mounted by this process.
+    pub fn mount_description(&self) -> Option<String> {
+        self.inner.conn.mount_description()
+    }
+
+    /// Receive an incoming FUSE request from the kernel.
+    ///
+    /// The returned value is `None` if the connection has been closed.
+    /// Since the kernel may close the connection without sending `Forget`s
+    /// or `Destroy`, the filesystem should release all the resources of inodes
+    /// at this point, in the same way as receiving `Operation::Destroy`.
+    ///
+    /// After the session is closed by `KernelConfig::max_reply_failures`,
+    /// an error containing `ConnectionClosed::ReplyFailed` is returned
+    /// instead, which the filesystem should handle in the same way.
+    pub fn next_request(&self) -> io::Result<Option<Request>> {
+        self.inner.check_reply_failed()?;
+        loop {
+            if let Some(req) = self.next_early_request()? {
+                return Ok(Some(req));
+            }
+            if self.inner.conn.is_handed_over() {
+                return Ok(None);
+            }
+
+            let paused = match self.inner.background {
+                Some(ref background) if background.is_paused() => {
+                    // The device is watched while paused only if the
+                    // requests read in the meantime can be put off.
+                    let fd = if self.inner.can_put_off() {
+                        Some(self.inner.conn.as_raw_fd())
+                    } else {
+                        None
+                    };
+                    if background.wait(fd)? {
+                        true
+                    } else if background.is_paused() {
+                        // Woken up by the exit of the session.
+                        false
+                    } else {
+                        // Deliver the requests put off first.
+                        continue;
+                    }
+                }
+                _ => false,
+            };
+            let res = if paused {
+                read_request(self.inner.conn.nonblocking(), &self.inner.receive_buffer)
+            } else {
+                read_request(&self.inner.conn, &self.inner.receive_buffer)
+            };
+            match res {
+                Ok(Received::Request(header, arg)) => {
+                    self.inner.check_reply_failed()?;
+                    if let Some(req) = self.inner.dispatch(header, arg, Instant::now())? {
+                        return Ok(Some(req));
+                    }
+                }
+                Ok(Received::Closed(reason)) => {
+                    self.inner.close(reason);
+                    return Ok(None);
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => {
+                    debug!("ENOENT");
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    /// Attempt to receive an incoming FUSE request without blocking.
+    ///
+    /// This method is intended to integrate the session into an external
+    /// event loop, such as the one based on `poll(2)`, by waiting for the
+    /// readiness of the file descriptor obtained from `AsRawFd`.
+    ///
+    /// Unlike `next_request`, the returned value is `None` if no request
+    /// is available at the moment.  When the connection has been closed
+    /// (e.g. the filesystem is unmounted), an error with the error number
+    /// corresponding to `closed_reason` is returned.  `None` is also
+    /// returned while receiving is paused by `KernelConfig::background_admission`,
+    /// except for the forgets and the interrupts; use
+    /// `KernelConfig::on_background_resumed` to be notified of the resumption.
+    ///
+    /// The file descriptor of the connection is in the non-blocking mode,
+    /// so this method never blocks even if another thread has received
+    /// the request that made the descriptor ready.
+    pub fn try_next_request(&self) -> io::Result<Option<Request>> {
+        self.inner.check_reply_failed()?;
+        loop {
+            if let Some(req) = self.next_early_request()? {
+                return Ok(Some(req));
+            }
+            if self.inner.conn.is_handed_over()
+                || (self.inner.is_paused() && !self.inner.can_put_off())
+            {
+                return Ok(None);
+            }
+
+            match read_request(self.inner.conn.nonblocking(), &self.inner.receive_buffer) {
+                Ok(Received::Request(header, arg)) => {
+                    if let Some(req) = self.inner.dispatch(header, arg, Instant::now())? {
+                        return Ok(Some(req));
+                    }
+                }
+                Ok(Received::Closed(reason)) => {
+                    self.inner.close(reason);
+                    return Err(io::Error::from_raw_os_error(reason.errno()));
+                }
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(None),
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => {
+                    debug!("ENOENT");
+                    continue;
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+
+    fn next_early_request(&self) -> io::Result<Option<Request>> {
+        loop {
+            if self.inner.is_paused() {
+                return Ok(None);
+            }
+            let (header, arg, received) =
+                match self.inner.early_requests.lock().unwrap().pop_front() {
+                    Some(early) => early,
+                    None => return Ok(None),
+                };
+            if !self.inner.accept(&header, &arg[..])? {
+                continue;
+            }
+            if let Some(req) = self.inner.new_request(header, arg, received)? {
+                return Ok(Some(req));
+            }
+        }
+    }
+
+    /// Return the unique IDs of requests whose reply could not be sent to the kernel.
+    ///
+    /// The kernel keeps waiting for the replies of these requests, so the
+    /// corresponding system calls will never complete. Operators can use this
+    /// information to detect the hung requests and abort the connection.
+    ///
+    /// Only the latest 1024 of them are kept, in the order of failures.
+    pub fn failed_replies(&self) -> Vec<u64> {
+        self.inner
+            .failed_replies
+            .lock()
+            .unwrap()
+            .iter()
+            .copied()
+            .collect()
+    }
+
+    /// Return the number of requests denied by `KernelConfig::opcode_filter`.
+    pub fn denied_requests(&self) -> u64 {
+        self.inner.denied_requests.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of requests received as small and large messages.
+    ///
+    /// Every message is received into a buffer of `bufsize` bytes, since
+    /// the read from the FUSE device fails unless the whole message fits.
+    /// The arguments of the messages up to 16 KiB, i.e. almost all of the
+    /// requests other than `WRITE`, are copied into an allocation of their
+    /// own length and the buffer is reused for the next message.  A larger
+    /// message takes over the buffer to avoid copying the data.
+    pub fn received_messages(&self) -> (u64, u64) {
+        let buffer = &self.inner.receive_buffer;
+        (
+            buffer.small.load(Ordering::Relaxed),
+            buffer.large.load(Ordering::Relaxed),
+        )
+    }
+
+    /// Return the number of requests rejected by `KernelConfig::reject_stale_inodes`.
+    pub fn stale_requests(&self) -> u64 {
+        self.inner.stale_requests.load(Ordering::Relaxed)
+    }
+
+    /// Return the snapshot of the lookup counts of inodes, if
+    /// `KernelConfig::lookup_audit` or `KernelConfig::reject_stale_inodes`
+    /// is enabled.
+    pub fn lookup_counts(&self) -> Option<LookupCounts> {
+        self.inner
+            .lookups
+            .as_ref()
+            .map(|lookups| lookups.snapshot())
+    }
+
+    /// Return the number of background requests waiting for their replies,
+    /// counted when `KernelConfig::background_admission` is enabled.
+    pub fn background_requests(&self) -> usize {
+        self.inner
+            .background
+            .as_ref()
+            .map_or(0, |background| background.count())
+    }
+
+    /// Return the number of background requests at which receiving is paused,
+    /// and the one below which it is resumed, if `KernelConfig::background_admission`
+    /// is enabled.
+    pub fn background_limits(&self) -> Option<(usize, usize)> {
+        self.inner.background.as_ref().map(|background| {
+            (
+                background.max_background(),
+                background.congestion_threshold(),
+            )
+        })
+    }
+
+    /// Return the number of replies dropped because the kernel had already
+    /// aborted the request.
+    ///
+    /// The write of a reply fails with `ENOENT` when the request has been
+    /// interrupted and finished in the kernel (e.g. the process was killed
+    /// by a signal), which is not treated as an error.
+    pub fn aborted_replies(&self) -> u64 {
+        self.inner.aborted_replies.load(Ordering::Relaxed)
+    }
+
+    /// Return the reason why the connection has been closed.
+    ///
+    /// The returned value is `None` while the connection is alive.
+    pub fn closed_reason(&self) -> Option<ConnectionClosed> {
+        *self.inner.closed.lock().unwrap()
+    }
+}
+
+enum Received {
+    Request(fuse_in_header, Vec<u8>),
+    Closed(ConnectionClosed),
+}
+
+/// The buffer receiving the request messages, reused across small messages.
+struct ReceiveBuffer {
+    // The negotiated `max_write`, for the error message.
+    max_write: usize,
+    arg_size: AtomicUsize,
+    // Taken by the reader while receiving, so that concurrent readers
+    // allocate their own buffers instead of waiting for it.
+    spare: Mutex<Vec<u8>>,
+    small: AtomicU64,
+    large: AtomicU64,
+}
+
+impl ReceiveBuffer {
+    fn new(bufsize: usize) -> Self {
+        Self {
+            max_write: bufsize.saturating_sub(BUFFER_HEADER_SIZE),
+            arg_size: AtomicUsize::new(bufsize - mem::size_of::<fuse_in_header>()),
+            spare: Mutex::new(vec![]),
+            small: AtomicU64::new(0),
+            large: AtomicU64::new(0),
+        }
+    }
+
+    fn bufsize(&self) -> usize {
+        self.arg_size.load(Ordering::Relaxed) + mem::size_of::<fuse_in_header>()
+    }
+
+    fn take(&self) -> Vec<u8> {
+        let arg_size = self.arg_size.load(Ordering::Relaxed);
+        let buf = mem::take(&mut *self.spare.lock().unwrap());
+        if buf.len() == arg_size {
+            buf
+        } else {
+            vec![0u8; arg_size]
+        }
+    }
+
+    fn put_back(&self, buf: Vec<u8>) {
+        *self.spare.lock().unwrap() = buf;
+    }
+
+    /// Double the size of the buffer up to `MAX_RECEIVE_BUFFER_SIZE`, and
+    /// return the new one, or `None` if it cannot grow any more.
+    fn grow(&self, current: usize) -> Option<Vec<u8>> {
+        let bufsize = current + mem::size_of::<fuse_in_header>();
+        if bufsize >= MAX_RECEIVE_BUFFER_SIZE {
+            return None;
+        }
+        let arg_size =
+            cmp::min(bufsize * 2, MAX_RECEIVE_BUFFER_SIZE) - mem::size_of::<fuse_in_header>();
+        self.arg_size.fetch_max(arg_size, Ordering::Relaxed);
+        Some(vec![0u8; arg_size])
+    }
+
+    fn too_small(&self, bufsize: usize) -> io::Error {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!(
+                "the request message does not fit in the receive buffer of {} bytes \
+                 (max_write = {}); the kernel sends larger messages than max_write \
+                 if max_pages is not negotiated consistently with it",
+                bufsize, self.max_write
+            ),
+        )
+    }
+}
+
+/// Read a request message from the kernel.
+///
+/// The kernel fails the read with `EINVAL` without dequeuing the message
+/// if the buffer is too small for it, which is also the case where nothing
+/// of the header can be retrieved.  The buffer is grown and the read is
+/// retried once, and the grown size is kept for the subsequent messages.
+fn read_request<R>(mut reader: R, buffer: &ReceiveBuffer) -> io::Result<Received>
+where
+    R: io::Read,
+{
+    // FIXME: Align the allocated region in `arg` with the FUSE argument types.
+    let mut header = fuse_in_header::default();
+    let mut arg = buffer.take();
+
+    let mut res = reader.read_vectored(&mut [
+        io::IoSliceMut::new(header.as_bytes_mut()),
+        io::IoSliceMut::new(&mut arg[..]),
+    ]);
+    if matches!(res, Err(ref err) if err.raw_os_error() == Some(libc::EINVAL)) {
+        let bufsize = arg.len() + mem::size_of::<fuse_in_header>();
+        arg = match buffer.grow(arg.len()) {
+            Some(grown) => grown,
+            None => return Err(buffer.too_small(bufsize)),
+        };
+        warn!(
+            "the request message does not fit in {} bytes; retry with {} bytes (max_write = {})",
+            bufsize,
+            arg.len() + mem::size_of::<fuse_in_header>(),
+            buffer.max_write
+        );
+        res = reader.read_vectored(&mut [
+            io::IoSliceMut::new(header.as_bytes_mut()),
+            io::IoSliceMut::new(&mut arg[..]),
+        ]);
+        if matches!(res, Err(ref err) if err.raw_os_error() == Some(libc::EINVAL)) {
+            return Err(buffer.too_small(arg.len() + mem::size_of::<fuse_in_header>()));
+        }
+    }
+    let len = match res {
+        Ok(len) if len >= mem::size_of::<fuse_in_header>() => len,
+        res => {
+            buffer.put_back(arg);
+            return match res {
+                // The FUSE device never returns EOF, but the emulated one does
+                // when the peer has been closed.
+                Ok(0) => Ok(Received::Closed(ConnectionClosed::Unmounted)),
+                Ok(..) => Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "dequeued request message is too short",
+                )),
+                Err(err) => match err.raw_os_error() {
+                    Some(libc::ENODEV) => Ok(Received::Closed(ConnectionClosed::Unmounted)),
+                    Some(libc::ECONNABORTED) => Ok(Received::Closed(ConnectionClosed::Aborted)),
+                    _ => Err(err),
+                },
+            };
+        }
+    };
+
+    let arg_len = len - mem::size_of::<fuse_in_header>();
+    if len <= SMALL_REQUEST_SIZE {
+        buffer.small.fetch_add(1, Ordering::Relaxed);
+        let small = arg[..arg_len].to_vec();
+        buffer.put_back(arg);
+        Ok(Received::Request(header, small))
+    } else {
+        buffer.large.fetch_add(1, Ordering::Relaxed);
+        arg.truncate(arg_len);
+        Ok(Received::Request(header, arg))
+    }
+}
+
+/// Perform the handshake of `INIT` request over the connection.
+fn init_session<R, W>(handshake: &mut Handshake, mut reader: R, mut writer: W) -> io::Result<()>
+where
+    R: io::Read,
+    W: io::Write,
+{
+    // FIXME: align the allocated buffer in `buf` with FUSE argument types.
+    let mut buf = vec![0u8; mem::size_of::<fuse_in_header>() + pagesize() * MAX_MAX_PAGES];
+    while !handshake.is_complete() {
+        let len = reader.read(&mut buf[..])?;
+        let res = handshake.feed(&buf[..len]);
+        while let Some(reply) = handshake.emit() {
+            write_bytes(&mut writer, reply)?;
+        }
+        res?;
+    }
+    Ok(())
+}
+
+// ==== Request ====
+
+/// Context about an incoming FUSE request.
+pub struct Request {
+    session: Arc<SessionInner>,
+    header: fuse_in_header,
+    arg: Vec<u8>,
+    deadline: Option<Instant>,
+    replied: AtomicBool,
+    reply_errno: AtomicI32,
+    turn: Mutex<Turn>,
+    sink: ReplySink,
+}
+
+/// The destination of the reply to a request.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum ReplySink {
+    /// The connection of the session.
+    Connection,
+    /// The reply is consumed without being written, for the requests built
+    /// by `testing::request`.
+    #[cfg(any(test, feature = "testing"))]
+    Discard,
+}
+
+// The value of `Request::reply_errno` until a reply is written successfully.
+const NOT_DELIVERED: i32 = -1;
+
+/// The turn of a request on its inode, taken by `KernelConfig::serialize_per_inode`.
+enum Turn {
+    Free,
+    Waiting(InodeTicket),
+    // The guard is kept only to be released on drop.
+    Holding { _guard: InodeGuard },
+}
+
+impl Drop for Request {
+    fn drop(&mut self) {
+        // Forget the request dropped without replying, so that the set of
+        // the requests waiting for their replies does not grow.
+        if !self.replied() && self.expects_reply() {
+            self.session.claim_reply(self.unique());
+        }
+    }
+}
+
+impl Request {
+    /// Consume the reply to this request without writing it to the connection.
+    #[cfg(any(test, feature = "testing"))]
+    pub(crate) fn discard_reply(&mut self) {
+        self.sink = ReplySink::Discard;
+    }
+
+    /// Return the unique ID of the request.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.header.unique
+    }
+
+    /// Return the user ID of the calling process.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.header.uid
+    }
+
+    /// Return the group ID of the calling process.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.header.gid
+    }
+
+    /// Return the process ID of the calling process.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.header.pid
+    }
+
+    /// Return the path of the executable of the calling process.
+    ///
+    /// The information about the calling processes is read from `/proc`, and
+    /// cached per process while the process is alive.  Since the process may
+    /// exit or execute another program at any time, the returned value is not
+    /// guaranteed to be up to date and must not solely be trusted for
+    /// security decisions.  In particular, a process can `execve` a different
+    /// binary after the value is cached.  The PID reuse is detected by
+    /// comparing the start time of the process.
+    ///
+    /// The lookup performs blocking reads of procfs, and it requires `/proc` of
+    /// the PID namespace where the session runs.  An error with `ESRCH` is
+    /// returned if the request is not issued by any process, or the process
+    /// has already exited.
+    pub fn caller_exe(&self) -> io::Result<PathBuf> {
+        self.session.callers.exe(self.pid())
+    }
+
+    /// Return the path of the calling process in the unified cgroup hierarchy.
+    ///
+    /// The value can change by migrating the process to another cgroup.
+    /// See `caller_exe` for the other caveats.
+    pub fn caller_cgroup(&self) -> io::Result<String> {
+        self.session.callers.cgroup(self.pid())
+    }
+
+    /// Return the supplementary group IDs of the calling process.
+    ///
+    /// The FUSE protocol carries only the primary group in the request, so the
+    /// filesystem that checks the permissions by itself needs this helper.
+    /// See `caller_exe` for the caveats.
+    pub fn caller_supplementary_groups(&self) -> io::Result<Vec<u32>> {
+        self.session.callers.supplementary_groups(self.pid())
+    }
+
+    /// Return the inode number targeted by this request.
+    ///
+    /// The value is meaningless for the requests that do not target
+    /// a specific inode, such as `FORGET` with the batched form.
+    #[inline]
+    pub fn ino(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the opcode of the request, or `None` if it is unknown to this library.
+    #[inline]
+    pub fn opcode(&self) -> Option<Opcode> {
+        Opcode::from_raw(self.header.opcode)
+    }
+
+    /// Return the raw opcode of the request.
+    ///
+    /// Together with `raw_arg`, this allows forwarding the requests
+    /// received as `Operation::Unknown`, e.g. to another FUSE server.
+    #[inline]
+    pub fn raw_opcode(&self) -> u32 {
+        self.header.opcode
+    }
+
+    /// Return the raw argument bytes of the request, i.e. everything after
+    /// the header of the message, including the extensions if any.
+    #[inline]
+    pub fn raw_arg(&self) -> &[u8] {
+        &self.arg[..]
+    }
+
+    /// Return the deadline of this request, specified by `KernelConfig::deadline`.
+    ///
+    /// The deadline is computed when the request is read from the connection.
+    /// The session does not replace the replies sent after it, so the handler
+    /// should check it before starting an operation that cannot be undone.
+    #[inline]
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Decode the argument of this request.
+    ///
+    /// With `KernelConfig::serialize_per_inode`, this waits for the turn of
+    /// the request on its inode before decoding.
+    pub fn operation(&self) -> Result<Operation<'_, Data<'_>>, DecodeError> {
+        self.wait_turn();
+        if self.session.exited() {
+            // The forgets must be delivered even after exiting, so that the
+            // filesystem can release the resources of inodes.
+            match fuse_opcode::try_from(self.header.opcode).ok() {
+                Some(fuse_opcode::FUSE_FORGET)
+                | Some(fuse_opcode::FUSE_BATCH_FORGET)
+                | Some(fuse_opcode::FUSE_DESTROY) => (),
+                _ => return Ok(Operation::unknown()),
+            }
+        }
+
+        let (arg, ext, data) =
+            proto::split_arg(&self.header, &self.arg[..], self.session.init_out.minor)?;
+
+        let mut op = Operation::decode(&self.header, arg, ext, Data { data })?;
+        if self.session.init_out.flags & FUSE_DONT_MASK != 0 {
+            op = op.into_dont_mask();
+        }
+        if self.session.is_stateless(self.header.nodeid) {
+            return Ok(op.into_stateless());
+        }
+        Ok(op)
+    }
+
+    /// Return the extension blocks appended to this request.
+    ///
+    /// The extensions are recognized only if the session has negotiated
+    /// ABI 7.38 or later.  Otherwise, or if the request is malformed,
+    /// the returned iterator is empty.
+    pub fn extensions(&self) -> Extensions<'_> {
+        self.split_extensions()
+            .map(|(_, ext)| ext)
+            .unwrap_or_default()
+    }
+
+    fn split_extensions(&self) -> Result<(&[u8], Extensions<'_>), DecodeError> {
+        proto::split_extensions(&self.header, &self.arg[..], self.session.init_out.minor)
+    }
+
+    pub fn reply<T>(&self, arg: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        self.send_reply(0, arg)
+    }
+
+    pub fn reply_error(&self, code: i32) -> io::Result<()> {
+        self.send_reply(code, ())
+    }
+
+    /// Return whether a reply to this request has been sent.
+    ///
+    /// The failed attempts to send a reply are also counted, since the
+    /// kernel may have received a part of them.
+    pub fn replied(&self) -> bool {
+        self.replied.load(Ordering::Acquire)
+    }
+
+    /// Return the error number of the reply written to the kernel, or
+    /// `None` if no reply has been written successfully.
+    ///
+    /// The value is `0` for a successful reply, and reflects the error
+    /// substituted by the session, e.g. by the strict mode or the limits
+    /// of the reply size.
+    pub fn reply_errno(&self) -> Option<i32> {
+        match self.reply_errno.load(Ordering::Acquire) {
+            NOT_DELIVERED => None,
+            errno => Some(errno),
+        }
+    }
+
+    /// Process this request with a handler, making sure that the kernel
+    /// receives a reply even if the handler fails.
+    ///
+    /// When `f` returns an error without replying, the error number mapped
+    /// by `Errno::from_io_error` is replied before the error is returned.
+    /// Otherwise the error is only logged, since replying twice is not
+    /// allowed.  The requests that must not be replied, such as `FORGET`,
+    /// are left as they are.  To reply another error number for a
+    /// particular operation, `f` can reply it by itself.
+    pub fn process<F>(&self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&Self) -> io::Result<()>,
+    {
+        let err = match f(self) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+
+        if self.replied() || !self.expects_reply() {
+            error!(
+                "failed to process the request (unique = {}): {}",
+                self.unique(),
+                err
+            );
+            return Err(err);
+        }
+
+        let errno = Errno::from_io_error(&err).raw();
+        error!(
+            "failed to process the request without replying (unique = {}, errno = {}): {}",
+            self.unique(),
+            errno,
+            err
+        );
+        self.reply_error(errno)?;
+        Err(err)
+    }
+
+    fn wait_turn(&self) {
+        let mut turn = self.turn.lock().unwrap();
+        if let Turn::Waiting(..) = *turn {
+            if let Turn::Waiting(ticket) = mem::replace(&mut *turn, Turn::Free) {
+                *turn = Turn::Holding {
+                    _guard: ticket.wait(),
+                };
+            }
+        }
+    }
+
+    // Unlike `expects_reply`, `INTERRUPT` may be replied with `EAGAIN` or `ENOSYS`.
+    fn takes_no_reply(&self) -> bool {
+        matches!(
+            fuse_opcode::try_from(self.header.opcode).ok(),
+            Some(fuse_opcode::FUSE_FORGET)
+                | Some(fuse_opcode::FUSE_BATCH_FORGET)
+                | Some(fuse_opcode::FUSE_NOTIFY_REPLY)
+        )
+    }
+
+    fn expects_reply(&self) -> bool {
+        !matches!(
+            fuse_opcode::try_from(self.header.opcode).ok(),
+            Some(fuse_opcode::FUSE_FORGET)
+                | Some(fuse_opcode::FUSE_BATCH_FORGET)
+                | Some(fuse_opcode::FUSE_INTERRUPT)
+                | Some(fuse_opcode::FUSE_NOTIFY_REPLY)
+        )
+    }
+
+    /// Reply to `Getxattr` or `Listxattr` with a value whose length is known in advance.
+    ///
+    /// `len` is the length of the value, which may be cached by the
+    /// filesystem.  The value is built by `fill` only if it is actually
+    /// sent: when the kernel probes the length with the zero size, `len` is
+    /// replied with `XattrOut`, and `ERANGE` is replied if `len` exceeds the
+    /// size requested by the kernel.
+    ///
+    /// The value is written in a single message, as the FUSE protocol does
+    /// not allow splitting a reply.  An error with `InvalidInput` is
+    /// returned, without replying, if the request is not `GETXATTR` or
+    /// `LISTXATTR`.
+    pub fn reply_xattr_with<F>(&self, len: usize, fill: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut Vec<u8>),
+    {
+        let size = self.xattr_size().ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "the request does not ask for an extended attribute",
+            )
+        })?;
+        if size == 0 {
+            let len = u32::try_from(len).map_err(|_| io::Error::from_raw_os_error(libc::E2BIG))?;
+            // Held by reference, since `Bytes::size` is found first on `XattrOut` itself.
+            let out = &mut XattrOut::default();
+            out.size(len);
+            return self.reply(out);
+        }
+        if len > size as usize {
+            return self.reply_error(libc::ERANGE);
+        }
+
+        let mut value = Vec::with_capacity(len);
+        fill(&mut value);
+        debug_assert_eq!(value.len(), len, "the length of value is mismatched");
+        self.reply(value)
+    }
+
+    /// Return the size of buffer requested by `GETXATTR` or `LISTXATTR`.
+    fn xattr_size(&self) -> Option<u32> {
+        match fuse_opcode::try_from(self.header.opcode).ok()? {
+            fuse_opcode::FUSE_GETXATTR | fuse_opcode::FUSE_LISTXATTR => {
+                let arg: &fuse_getxattr_in = Decoder::new(&self.arg[..]).fetch().ok()?;
+                Some(arg.size)
+            }
+            _ => None,
+        }
+    }
+
+    /// Return the maximum length of reply accepted by the kernel, along with
+    /// the error number sent when the reply exceeds it.
+    fn reply_limit(&self) -> Option<(usize, i32)> {
+        match fuse_opcode::try_from(self.header.opcode).ok()? {
+            fuse_opcode::FUSE_READLINK => Some((libc::PATH_MAX as usize - 1, libc::ENAMETOOLONG)),
+            // The zero size requests the length of value with `XattrOut`.
+            fuse_opcode::FUSE_GETXATTR | fuse_opcode::FUSE_LISTXATTR => match self.xattr_size()? {
+                0 => None,
+                size => Some((size as usize, libc::ERANGE)),
+            },
+            _ => None,
+        }
+    }
+
+    fn send_reply<T>(&self, error: i32, arg: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        if self.takes_no_reply() {
+            error!(
+                "{} takes no reply (unique = {}, error = {})",
+                DisplayOpcode(self.header.opcode),
+                self.unique(),
+                error
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                AlreadyReplied {
+                    unique: self.unique(),
+                },
+            ));
+        }
+
+        if error == 0 {
+            if let Some((limit, errno)) = self.reply_limit() {
+                if arg.size() > limit {
+                    error!(
+                        "the reply size exceeds the limit (unique = {}, size = {}, limit = {})",
+                        self.unique(),
+                        arg.size(),
+                        limit
+                    );
+                    self.send_reply(errno, ())?;
+                    return Err(io::Error::from_raw_os_error(errno));
+                }
+            }
+        }
+
+        if error == 0 && self.session.strict {
+            if let Err(violation) = self.check_reply(&arg) {
+                error!(
+                    "invalid reply to {} (unique = {}): {}",
+                    DisplayOpcode(self.header.opcode),
+                    self.unique(),
+                    violation
+                );
+                self.send_reply(libc::EIO, ())?;
+                return Err(io::Error::from_raw_os_error(libc::EIO));
+            }
+        }
+
+        if error == 0 {
+            if let Some(filtered) = self.filter_attr_flags(&arg) {
+                return self.send_reply(0, &filtered[..]);
+            }
+        }
+
+        if cfg!(debug_assertions) && error == 0 {
+            self.audit_entry(&arg);
+        }
+
+        if let Some(interceptor) = &self.session.reply_interceptor {
+            match self.intercept_reply(&**interceptor, error, &arg) {
+                Intercepted::Unchanged => (),
+                Intercepted::Rendered(rendered) => return self.deliver_reply(0, &rendered[..]),
+                Intercepted::Error(errno) => return self.deliver_reply(errno, ()),
+            }
+        }
+
+        self.deliver_reply(error, arg)
+    }
+
+    fn deliver_reply<T>(&self, mut error: i32, arg: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        if error == libc::ENOSYS
+            && matches!(self.session.unknown_opcode_eopnotsupp, Some(first) if self.header.opcode >= first)
+            && self.opcode().is_none()
+        {
+            error = libc::EOPNOTSUPP;
+        }
+
+        if self.expects_reply() && !self.session.claim_reply(self.unique()) {
+            error!(
+                "the request has already been replied (unique = {}, error = {})",
+                self.unique(),
+                error
+            );
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                AlreadyReplied {
+                    unique: self.unique(),
+                },
+            ));
+        }
+        self.replied.store(true, Ordering::Release);
+
+        if error == 0 {
+            if let Some(ref lookups) = self.session.lookups {
+                self.audit_lookups(lookups, &arg);
+            }
+            if self.session.init_out.flags & FUSE_NO_OPEN_SUPPORT != 0 {
+                self.audit_opens(&arg);
+            }
+        }
+        self.session.observe_error(self.header.opcode, error);
+
+        let res = match self.sink {
+            ReplySink::Connection => write_reply(
+                &self.session.conn,
+                Reply::new(self.unique(), error, arg),
+                &self.session.aborted_replies,
+            ),
+            #[cfg(any(test, feature = "testing"))]
+            ReplySink::Discard => Ok(()),
+        };
+        self.session.record_reply(&res);
+        // Pass the turn on the inode to the next request.
+        *self.turn.lock().unwrap() = Turn::Free;
+        if res.is_ok() {
+            self.reply_errno.store(error, Ordering::Release);
+        }
+        res.map_err(|err| {
+            error!(
+                "failed to send a reply (unique = {}): {}",
+                self.unique(),
+                err
+            );
+            let mut failed = self.session.failed_replies.lock().unwrap();
+            if failed.len() == MAX_FAILED_REPLIES {
+                failed.pop_front();
+            }
+            failed.push_back(self.unique());
+            err
+        })
+    }
+}
+
+/// The remaining part of request message.
+pub struct Data<'op> {
+    data: &'op [u8],
+}
+
+impl fmt::Debug for Data<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Data").finish()
+    }
+}
+
+impl<'op> io::Read for Data<'op> {
+    #[inline]
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        io::Read::read(&mut self.data, buf)
+    }
+
+    #[inline]
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        io::Read::read_vectored(&mut self.data, bufs)
+    }
+}
+
+impl<'op> BufRead for Data<'op> {
+    #[inline]
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        io::BufRead::fill_buf(&mut self.data)
+    }
+
+    #[inline]
+    fn consume(&mut self, amt: usize) {
+        io::BufRead::consume(&mut self.data, amt)
+    }
+}
+
+/// The result of `ReplyInterceptor` applied to a reply.
+enum Intercepted {
+    Unchanged,
+    Rendered(Vec<u8>),
+    Error(i32),
+}
+
+impl Request {
+    fn intercept_reply<T>(
+        &self,
+        interceptor: &dyn ReplyInterceptor,
+        error: i32,
+        arg: &T,
+    ) -> Intercepted
+    where
+        T: Bytes,
+    {
+        use fuse_opcode::*;
+        let caller = Caller {
+            header: &self.header,
+            arg: &self.arg[..],
+        };
+        if error != 0 {
+            return match interceptor.before_send(&caller, ReplyBody::Error { errno: error }) {
+                Action::Error(errno) if errno != error => Intercepted::Error(errno),
+                _ => Intercepted::Unchanged,
+            };
+        }
+
+        let (action, rendered) = match fuse_opcode::try_from(self.header.opcode).ok() {
+            Some(FUSE_LOOKUP) | Some(FUSE_MKNOD) | Some(FUSE_MKDIR) | Some(FUSE_SYMLINK)
+            | Some(FUSE_LINK) | Some(FUSE_CREATE) => match reply_prefix::<fuse_entry_out, _>(arg) {
+                Some(mut out) => {
+                    let orig = out;
+                    let reply = ReplyBody::Entry {
+                        ino: out.nodeid,
+                        attr: ReplyAttr::new(&mut out.attr),
+                    };
+                    let action = interceptor.before_send(&caller, reply);
+                    (action, render_prefix(arg, &orig, &out))
+                }
+                None => (
+                    interceptor.before_send(&caller, ReplyBody::Other { len: arg.size() }),
+                    None,
+                ),
+            },
+            Some(FUSE_GETATTR) | Some(FUSE_SETATTR) => {
+                match reply_prefix::<fuse_attr_out, _>(arg) {
+                    Some(mut out) => {
+                        let orig = out;
+                        let reply = ReplyBody::Attr(ReplyAttr::new(&mut out.attr));
+                        let action = interceptor.before_send(&caller, reply);
+                        (action, render_prefix(arg, &orig, &out))
+                    }
+                    None => (
+                        interceptor.before_send(&caller, ReplyBody::Other { len: arg.size() }),
+                        None,
+                    ),
+                }
+            }
+            Some(FUSE_READDIRPLUS) => {
+                let mut payload = crate::bytes::to_vec(arg);
+                let mut modified = false;
+                let reply = ReplyBody::Entries(ReplyEntries::new(&mut payload, &mut modified));
+                let action = interceptor.before_send(&caller, reply);
+                (action, if modified { Some(payload) } else { None })
+            }
+            Some(FUSE_READ) | Some(FUSE_READLINK) => (
+                interceptor.before_send(&caller, ReplyBody::Data { len: arg.size() }),
+                None,
+            ),
+            _ => (
+                interceptor.before_send(&caller, ReplyBody::Other { len: arg.size() }),
+                None,
+            ),
+        };
+
+        match (action, rendered) {
+            (Action::Error(errno), _) => Intercepted::Error(errno),
+            (Action::Send, Some(rendered)) => Intercepted::Rendered(rendered),
+            (Action::Send, None) => Intercepted::Unchanged,
+        }
+    }
+
+    /// Render the reply again without the attribute flags that the
+    /// negotiated protocol does not define, if it contains any of them.
+    fn filter_attr_flags<T>(&self, arg: &T) -> Option<Vec<u8>>
+    where
+        T: Bytes,
+    {
+        // The flags are a padding before ABI 7.32, which the kernel ignores.
+        if self.session.init_out.minor < 32 {
+            return None;
+        }
+        match fuse_opcode::try_from(self.header.opcode).ok()? {
+            fuse_opcode::FUSE_LOOKUP
+            | fuse_opcode::FUSE_MKNOD
+            | fuse_opcode::FUSE_MKDIR
+            | fuse_opcode::FUSE_SYMLINK
+            | fuse_opcode::FUSE_LINK
+            | fuse_opcode::FUSE_CREATE => {
+                self.strip_attr_flags(arg, |out: &mut fuse_entry_out| &mut out.attr)
+            }
+            fuse_opcode::FUSE_GETATTR | fuse_opcode::FUSE_SETATTR => {
+                self.strip_attr_flags(arg, |out: &mut fuse_attr_out| &mut out.attr)
+            }
+            _ => None,
+        }
+    }
+
+    fn strip_attr_flags<T, O>(&self, arg: &T, attr: fn(&mut O) -> &mut fuse_attr) -> Option<Vec<u8>>
+    where
+        T: Bytes,
+        O: AsBytes + FromBytes + Default,
+    {
+        // The reply of these operations starts with `O`.
+        let mut out: O = reply_prefix(arg)?;
+
+        let supported = AttrFlags::supported(self.session.init_out.minor).bits();
+        let flags = &mut attr(&mut out).flags;
+        if *flags & !supported == 0 {
+            return None;
+        }
+        debug!(
+            "drop the attribute flags unsupported by ABI 7.{} (unique = {}, flags = {:#x})",
+            self.session.init_out.minor,
+            self.unique(),
+            *flags & !supported
+        );
+        *flags &= supported;
+
+        let mut rendered = crate::bytes::to_vec(arg);
+        rendered[..mem::size_of::<O>()].copy_from_slice(out.as_bytes());
+        Some(rendered)
+    }
+
+    /// Check the reply in the strict mode, and describe the violation if found.
+    fn check_reply<T>(&self, arg: &T) -> Result<(), String>
+    where
+        T: Bytes,
+    {
+        use fuse_opcode::*;
+        let opcode = match fuse_opcode::try_from(self.header.opcode) {
+            Ok(opcode) => opcode,
+            Err(..) => return Ok(()),
+        };
+        match opcode {
+            FUSE_LOOKUP | FUSE_MKNOD | FUSE_MKDIR | FUSE_SYMLINK | FUSE_LINK | FUSE_CREATE => {
+                let out: fuse_entry_out = match reply_prefix(arg) {
+                    Some(out) => out,
+                    None => return Ok(()),
+                };
+                if out.nodeid == 0 {
+                    if opcode != FUSE_LOOKUP {
+                        return Err("negative entry replied to a request other than LOOKUP".into());
+                    }
+                } else {
+                    check_attr(&out.attr)?;
+                    if out.attr.nlink == 0 {
+                        return Err(format!(
+                            "the entry of the inode {} has no links",
+                            out.nodeid
+                        ));
+                    }
+                }
+            }
+            FUSE_GETATTR | FUSE_SETATTR => {
+                let out: fuse_attr_out = match reply_prefix(arg) {
+                    Some(out) => out,
+                    None => return Ok(()),
+                };
+                check_attr(&out.attr)?;
+            }
+            FUSE_WRITE => {
+                let out: fuse_write_out = match reply_prefix(arg) {
+                    Some(out) => out,
+                    None => return Ok(()),
+                };
+                if let Ok(write_in) = Decoder::new(&self.arg[..]).fetch::<fuse_write_in>() {
+                    if out.size > write_in.size {
+                        return Err(format!(
+                            "written size ({}) exceeds the request data ({})",
+                            out.size, write_in.size
+                        ));
+                    }
+                }
+            }
+            FUSE_READDIR | FUSE_READDIRPLUS => {
+                if let Ok(read_in) = Decoder::new(&self.arg[..]).fetch::<fuse_read_in>() {
+                    if arg.size() > read_in.size as usize {
+                        return Err(format!(
+                            "directory entries ({} bytes) exceed the requested size ({})",
+                            arg.size(),
+                            read_in.size
+                        ));
+                    }
+                }
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn audit_lookups<T>(&self, lookups: &LookupAudit, arg: &T)
+    where
+        T: Bytes,
+    {
+        use fuse_opcode::*;
+        match fuse_opcode::try_from(self.header.opcode).ok() {
+            Some(FUSE_LOOKUP) | Some(FUSE_MKNOD) | Some(FUSE_MKDIR) | Some(FUSE_SYMLINK)
+            | Some(FUSE_LINK) | Some(FUSE_CREATE) => {
+                if let Some(out) = reply_prefix::<fuse_entry_out, _>(arg) {
+                    lookups.entry(out.nodeid);
+                }
+            }
+            Some(FUSE_READDIRPLUS) => lookups.entries_plus(&crate::bytes::to_vec(arg)),
+            _ => (),
+        }
+    }
+
+    fn audit_opens<T>(&self, arg: &T)
+    where
+        T: Bytes,
+    {
+        match fuse_opcode::try_from(self.header.opcode).ok() {
+            Some(fuse_opcode::FUSE_OPEN) => {
+                if let Some(out) = reply_prefix::<fuse_open_out, _>(arg) {
+                    if out.fh == 0 {
+                        self.session.open_zero_handle(self.header.nodeid);
+                    }
+                }
+            }
+            Some(fuse_opcode::FUSE_CREATE) => {
+                // The reply of CREATE is `fuse_entry_out` followed by `fuse_open_out`.
+                let bytes = crate::bytes::to_vec(arg);
+                let mut decoder = Decoder::new(&bytes[..]);
+                let entry_out = decoder.fetch_bytes(mem::size_of::<fuse_entry_out>());
+                let open_out = decoder.fetch_bytes(mem::size_of::<fuse_open_out>());
+                if let (Ok(entry_out), Ok(open_out)) = (entry_out, open_out) {
+                    let mut entry = fuse_entry_out::default();
+                    entry.as_bytes_mut().copy_from_slice(entry_out);
+                    let mut open = fuse_open_out::default();
+                    open.as_bytes_mut().copy_from_slice(open_out);
+                    if open.fh == 0 {
+                        self.session.open_zero_handle(entry.nodeid);
+                    }
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn audit_entry<T>(&self, arg: &T)
+    where
+        T: Bytes,
+    {
+        match fuse_opcode::try_from(self.header.opcode).ok() {
+            Some(fuse_opcode::FUSE_LOOKUP)
+            | Some(fuse_opcode::FUSE_MKNOD)
+            | Some(fuse_opcode::FUSE_MKDIR)
+            | Some(fuse_opcode::FUSE_SYMLINK)
+            | Some(fuse_opcode::FUSE_LINK)
+            | Some(fuse_opcode::FUSE_CREATE) => (),
+            Some(fuse_opcode::FUSE_READDIRPLUS) => {
+                let payload = crate::bytes::to_vec(arg);
+                self.session.generations.entries_plus(&payload[..]);
+                return;
+            }
+            _ => return,
+        }
+
+        // The reply of these operations starts with `fuse_entry_out`.
+        if let Some(out) = reply_prefix::<fuse_entry_out, _>(arg) {
+            self.session.generations.entry(&out);
+        }
+    }
+}
+
+/// Render the reply again with the modified leading structure, if it differs from the original.
+fn render_prefix<O, T>(arg: &T, orig: &O, out: &O) -> Option<Vec<u8>>
+where
+    O: AsBytes,
+    T: Bytes,
+{
+    if orig.as_bytes() == out.as_bytes() {
+        return None;
+    }
+    let mut rendered = crate::bytes::to_vec(arg);
+    rendered[..mem::size_of::<O>()].copy_from_slice(out.as_bytes());
+    Some(rendered)
+}
+
+/// Read the leading structure of a reply, or return `None` if the reply is shorter.
+fn reply_prefix<O, T>(arg: &T) -> Option<O>
+where
+    O: AsBytes + FromBytes + Default,
+    T: Bytes,
+{
+    let mut out = O::default();
+    let mut collector = PrefixCollector {
+        dst: out.as_bytes_mut(),
+        len: 0,
+    };
+    arg.fill_bytes(&mut collector);
+    if collector.len < mem::size_of::<O>() {
+        return None;
+    }
+    return Some(out);
+
+    struct PrefixCollector<'b> {
+        dst: &'b mut [u8],
+        len: usize,
+    }
+    impl<'a> FillBytes<'a> for PrefixCollector<'_> {
+        fn put(&mut self, chunk: &'a [u8]) {
+            let dst = &mut self.dst[self.len..];
+            let len = cmp::min(dst.len(), chunk.len());
+            dst[..len].copy_from_slice(&chunk[..len]);
+            self.len += len;
+        }
+    }
+}
+
+/// The caller and the kind of an incoming request, examined by `KernelConfig::caller_filter`
+/// and `ReplyInterceptor`.
+pub struct Caller<'a> {
+    header: &'a fuse_in_header,
+    arg: &'a [u8],
+}
+
+impl fmt::Debug for Caller<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Caller")
+            .field("uid", &self.uid())
+            .field("gid", &self.gid())
+            .field("pid", &self.pid())
+            .field("opcode", &self.opcode())
+            .finish()
+    }
+}
+
+impl Caller<'_> {
+    /// Return the unique ID of the request.
+    #[inline]
+    pub fn unique(&self) -> u64 {
+        self.header.unique
+    }
+
+    /// Return the inode number targeted by the request.
+    #[inline]
+    pub fn nodeid(&self) -> u64 {
+        self.header.nodeid
+    }
+
+    /// Return the user ID of the calling process.
+    #[inline]
+    pub fn uid(&self) -> u32 {
+        self.header.uid
+    }
+
+    /// Return the group ID of the calling process.
+    #[inline]
+    pub fn gid(&self) -> u32 {
+        self.header.gid
+    }
+
+    /// Return the process ID of the calling process.
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.header.pid
+    }
+
+    /// Return the opcode of the request, or `None` if it is unknown to this library.
+    #[inline]
+    pub fn opcode(&self) -> Option<Opcode> {
+        Opcode::from_raw(self.header.opcode)
+    }
+
+    /// Return whether the request may modify the filesystem.
+    ///
+    /// In addition to the operations that change the contents or metadata,
+    /// this includes `OPEN` and `CREATE` with the writable access mode, so
+    /// that the read-only access can be permitted by rejecting them.
+    pub fn is_write(&self) -> bool {
+        if is_write_opcode(self.header.opcode) {
+            return true;
+        }
+        match fuse_opcode::try_from(self.header.opcode).ok() {
+            Some(fuse_opcode::FUSE_OPEN) => match Decoder::new(self.arg).fetch::<fuse_open_in>() {
+                Ok(arg) => arg.flags as i32 & libc::O_ACCMODE != libc::O_RDONLY,
+                Err(..) => true,
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Return whether the requests of the opcode are delivered while receiving is paused.
+fn is_urgent(opcode: u32) -> bool {
+    use fuse_opcode::*;
+    matches!(
+        fuse_opcode::try_from(opcode).ok(),
+        Some(FUSE_FORGET) | Some(FUSE_BATCH_FORGET) | Some(FUSE_INTERRUPT)
+    )
+}
+
+/// Return whether the requests of the opcode always modify the filesystem.
+fn is_write_opcode(opcode: u32) -> bool {
+    use fuse_opcode::*;
+    matches!(
+        fuse_opcode::try_from(opcode).ok(),
+        Some(FUSE_SETATTR)
+            | Some(FUSE_SYMLINK)
+            | Some(FUSE_MKNOD)
+            | Some(FUSE_MKDIR)
+            | Some(FUSE_UNLINK)
+            | Some(FUSE_RMDIR)
+            | Some(FUSE_RENAME)
+            | Some(FUSE_RENAME2)
+            | Some(FUSE_LINK)
+            | Some(FUSE_WRITE)
+            | Some(FUSE_SETXATTR)
+            | Some(FUSE_REMOVEXATTR)
+            | Some(FUSE_CREATE)
+            | Some(FUSE_FALLOCATE)
+            | Some(FUSE_COPY_FILE_RANGE)
+    )
+}
+
+/// The class of opcodes, used to specify the time budget of requests.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Hash)]
+pub enum OpcodeClass {
+    /// The requests that query the metadata of inodes, such as `LOOKUP` and `GETATTR`.
+    Metadata,
+    /// The requests that read the contents of files or directories.
+    Read,
+    /// The requests that modify the filesystem.
+    Write,
+    /// The other requests, such as `OPEN` and `FSYNC`.
+    Other,
+}
+
+impl OpcodeClass {
+    /// Return the class of the raw opcode.
+    pub fn of(opcode: u32) -> Self {
+        use fuse_opcode::*;
+        if is_write_opcode(opcode) {
+            return Self::Write;
+        }
+        match fuse_opcode::try_from(opcode).ok() {
+            Some(FUSE_LOOKUP) | Some(FUSE_GETATTR) | Some(FUSE_READLINK) | Some(FUSE_ACCESS)
+            | Some(FUSE_STATFS) | Some(FUSE_GETXATTR) | Some(FUSE_LISTXATTR) | Some(FUSE_GETLK)
+            | Some(FUSE_BMAP) | Some(FUSE_LSEEK) => Self::Metadata,
+            Some(FUSE_READ) | Some(FUSE_READDIR) | Some(FUSE_READDIRPLUS) => Self::Read,
+            _ => Self::Other,
+        }
+    }
+}
+
+/// How `KernelConfig::reject_stale_inodes` determines the stale inodes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum InodeTracking {
+    /// Reject only the inodes whose lookup counts have dropped to zero by
+    /// `FORGET`, until they are looked up again.
+    ///
+    /// The inodes never seen by the session are accepted, so this is safe
+    /// even if the kernel learns inodes without the replies observed by the
+    /// session.  The forgotten inode numbers are kept as long as the
+    /// session lives.
+    Forgotten,
+
+    /// Reject all inodes whose lookup counts are not held by the kernel.
+    Live,
+}
+
+// ==== utils ====
+
+/// The maximum size of messages copied into a stack buffer before writing.
+const SMALL_MESSAGE_SIZE: usize = 256;
+
+/// Write a message in a single write.
+///
+/// The small messages, such as error replies and the fixed-size outs,
+/// are serialized into a stack buffer and written with a plain `write`.
+/// The others are written with a vectored write.
+#[inline]
+fn write_bytes<W, T>(writer: W, bytes: T) -> io::Result<()>
+where
+    W: io::Write,
+    T: Bytes,
+{
+    if bytes.size() <= SMALL_MESSAGE_SIZE {
+        write_small_bytes(writer, bytes)
+    } else {
+        write_vectored_bytes(writer, bytes)
+    }
+}
+
+/// Write a reply message, ignoring the failure due to the aborted request.
+fn write_reply<W, T>(writer: W, reply: Reply<T>, aborted: &AtomicU64) -> io::Result<()>
+where
+    W: io::Write,
+    T: Bytes,
+{
+    let unique = reply.header.unique;
+    match write_bytes(writer, reply) {
+        Err(err) if err.raw_os_error() == Some(libc::ENOENT) => {
+            debug!(
+                "the request has been aborted by the kernel (unique = {})",
+                unique
+            );
+            aborted.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }
+        res => res,
+    }
+}
+
+fn write_small_bytes<W, T>(mut writer: W, bytes: T) -> io::Result<()>
+where
+    W: io::Write,
+    T: Bytes,
+{
+    let size = bytes.size();
+    debug_assert!(size <= SMALL_MESSAGE_SIZE);
+
+    let mut buf = [0u8; SMALL_MESSAGE_SIZE];
+    let mut fill = FillSmallBytes {
+        buf: &mut buf[..],
+        offset: 0,
+        overflowed: false,
+    };
+    bytes.fill_bytes(&mut fill);
+    let len = fill.offset;
+    if fill.overflowed || len > size {
+        // The message is not written, since the kernel rejects or misreads
+        // a message whose length differs from its header.
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the filled bytes exceed the size of message",
+        ));
+    }
+
+    let written = retry_interrupted(|| writer.write(&buf[..len]))?;
+    check_written(written, size)
+}
+
+struct FillSmallBytes<'buf> {
+    buf: &'buf mut [u8],
+    offset: usize,
+    overflowed: bool,
+}
+
+impl<'a> FillBytes<'a> for FillSmallBytes<'_> {
+    fn put(&mut self, chunk: &'a [u8]) {
+        // A `Bytes` whose `size` disagrees with the filled chunks must not
+        // panic, so the chunk is truncated to the rest of the buffer.
+        let len = cmp::min(chunk.len(), self.buf.len() - self.offset);
+        self.buf[self.offset..self.offset + len].copy_from_slice(&chunk[..len]);
+        self.offset += len;
+        self.overflowed |= len < chunk.len();
+    }
+}
+
+/// The maximum number of I/O slices kept by `IO_SLICES` on each thread.
+const MAX_CACHED_IO_SLICES: usize = 1024;
+
+thread_local! {
+    // The I/O slices of the messages with more segments than the stack
+    // buffers of `write_vectored_bytes`, e.g. the READDIR replies built from
+    // many chunks, so that such a reply does not allocate them on every write.
+    // The `const` initializer requires Rust 1.59.
+    #[allow(clippy::missing_const_for_thread_local)]
+    static IO_SLICES: Cell<Vec<IoSlice<'static>>> = Cell::new(Vec::new());
+}
+
+/// Write a message in a single vectored write.
+///
+/// The I/O slices are collected from `bytes` only once, and reused as they
+/// are when the write is retried by `write_vectored_retry`.
+fn write_vectored_bytes<W, T>(mut writer: W, bytes: T) -> io::Result<()>
+where
+    W: io::Write,
+    T: Bytes,
+{
+    let size = bytes.size();
+    let count = bytes.count();
+
+    let written;
+
+    macro_rules! small_write {
+        ($n:expr) => {{
+            let mut vec: [MaybeUninit<IoSlice<'_>>; $n] =
+                unsafe { MaybeUninit::uninit().assume_init() };
+            bytes.fill_bytes(&mut FillWriteBytes {
+                vec: &mut vec[..],
+                offset: 0,
+            });
+            let vec = unsafe { slice_assume_init_ref(&vec[..]) };
+
+            written = write_vectored_retry(&mut writer, vec)?;
+        }};
+    }
+
+    match count {
+        // Skip writing.
+        0 => return Ok(()),
+
+        // Avoid heap allocation if count is small.
+        1 => small_write!(1),
+        2 => small_write!(2),
+        3 => small_write!(3),
+        4 => small_write!(4),
+
+        count => {
+            let mut vec: Vec<IoSlice<'_>> = IO_SLICES.with(|slices| slices.take());
+            vec.reserve(count);
+            unsafe {
+                let dst = std::slice::from_raw_parts_mut(
+                    vec.as_mut_ptr().cast(), //
+                    count,
+                );
+                bytes.fill_bytes(&mut FillWriteBytes {
+                    vec: dst,
+                    offset: 0,
+                });
+                vec.set_len(count);
+            }
+
+            let res = write_vectored_retry(&mut writer, &vec);
+
+            vec.clear();
+            if vec.capacity() <= MAX_CACHED_IO_SLICES {
+                // SAFETY: the vector is empty, so no slice borrowed from
+                // `bytes` outlives this call.
+                let vec = unsafe { mem::transmute::<Vec<IoSlice<'_>>, Vec<IoSlice<'static>>>(vec) };
+                IO_SLICES.with(|slices| slices.set(vec));
+            }
+            written = res?;
+        }
+    }
+
+    check_written(written, size)
+}
+
+fn check_written(written: usize, size: usize) -> io::Result<()> {
+    if written < size {
+        #[allow(clippy::io_other_error)] // `io::Error::other` requires Rust 1.74
+        return Err(io::Error::new(
+            io::ErrorKind::Other,
+            "written data is too short",
+        ));
+    }
+    Ok(())
+}
+
+/// Write the vectored data, retrying while the write is interrupted.
+///
+/// The FUSE kernel driver requires that a reply message is passed in a single
+/// `write(2)` call, so the interrupted writes are retried with the same
+/// slices instead of being reported to the caller.
+fn write_vectored_retry<W>(writer: &mut W, bufs: &[IoSlice<'_>]) -> io::Result<usize>
+where
+    W: io::Write,
+{
+    retry_interrupted(|| writer.write_vectored(bufs))
+}
+
+/// Retry the write failed with `EINTR`.
+///
+/// `EAGAIN` is returned to the caller as it is, rather than retried here:
+/// the writes to `Connection` already wait for `POLLOUT` on the descriptor,
+/// and a non-blocking writer is left to its caller to wait for the
+/// readiness, e.g. by the asynchronous runtime, without blocking the thread.
+fn retry_interrupted(mut write: impl FnMut() -> io::Result<usize>) -> io::Result<usize> {
+    loop {
+        match write() {
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            res => return res,
+        }
+    }
+}
+
+struct FillWriteBytes<'a, 'vec> {
+    vec: &'vec mut [MaybeUninit<IoSlice<'a>>],
+    offset: usize,
+}
+
+impl<'a, 'vec> FillBytes<'a> for FillWriteBytes<'a, 'vec> {
+    fn put(&mut self, chunk: &'a [u8]) {
+        self.vec[self.offset] = MaybeUninit::new(IoSlice::new(chunk));
+        self.offset += 1;
+    }
+}
+
+// FIXME: replace with stabilized MaybeUninit::slice_assume_init_ref.
+#[inline(always)]
+unsafe fn slice_assume_init_ref<T>(slice: &[MaybeUninit<T>]) -> &[T] {
+    #[allow(unused_unsafe)]
+    unsafe {
+        &*(slice as *const [MaybeUninit<T>] as *const [T])
+    }
+}
+
+#[inline]
+pub(crate) fn pagesize() -> usize {
+    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+}
+
+/// Check the invariants of the attributes common to all of the replies.
+#[inline]
+fn check_attr(attr: &fuse_attr) -> Result<(), String> {
+    if attr.ino == 0 {
+        return Err("the inode number of attributes is zero".into());
+    }
+    if attr.mode & libc::S_IFMT == 0 {
+        return Err(format!(
+            "the file mode ({:#o}) of the inode {} has no file type",
+            attr.mode, attr.ino
+        ));
+    }
+    Ok(())
+}
+
+const fn default_init_out() -> fuse_init_out {
+    fuse_init_out {
+        major: FUSE_KERNEL_VERSION,
+        minor: FUSE_KERNEL_MINOR_VERSION,
+        max_readahead: u32::MAX,
+        flags: DEFAULT_INIT_FLAGS,
+        max_background: 0,
+        congestion_threshold: 0,
+        max_write: DEFAULT_MAX_WRITE,
+        time_gran: 1,
+        max_pages: 0,
+        padding: 0,
+        unused: [0; 8],
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        proto::{clamp_init_out, INIT_FLAGS_MASK},
+        reply::{AttrFlags, AttrOut, EntryOut, OpenOut, WriteOut},
+    };
+    use std::{cell::Cell, mem, os::unix::net::UnixStream};
+
+    #[test]
+    fn init_default() {
+        let input_len = mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_init_in>();
+        let in_header = fuse_in_header {
+            len: input_len as u32,
+            opcode: fuse_opcode::FUSE_INIT as u32,
+            unique: 2,
+            nodeid: 0,
+            uid: 100,
+            gid: 100,
+            pid: 12,
+            total_extlen: 0,
+            padding: 0,
+        };
+        let init_in = fuse_init_in {
+            major: 7,
+            minor: 23,
+            max_readahead: 40,
+            flags: INIT_FLAGS_MASK
+                | FUSE_MAX_PAGES
+                | FUSE_NO_OPEN_SUPPORT
+                | FUSE_NO_OPENDIR_SUPPORT,
+        };
+
+        let mut input = Vec::with_capacity(input_len);
+        input.extend_from_slice(in_header.as_bytes());
+        input.extend_from_slice(init_in.as_bytes());
+        assert_eq!(input.len(), input_len);
+
+        let mut output = Vec::<u8>::new();
+
+        let mut handshake = Handshake::with_init_out(default_init_out(), 0);
+        init_session(&mut handshake, &input[..], &mut output).expect("initialization failed");
+        let (_, init_out, _) = handshake.into_parts();
+
+        let expected_max_pages = (DEFAULT_MAX_WRITE / (pagesize() as u32)) as u16;
+
+        assert_eq!(init_out.major, 7);
+        assert_eq!(init_out.minor, 23);
+        assert_eq!(init_out.max_readahead, 40);
+        assert_eq!(init_out.max_background, 0);
+        assert_eq!(init_out.congestion_threshold, 0);
+        assert_eq!(init_out.max_write, DEFAULT_MAX_WRITE);
+        assert_eq!(init_out.max_pages, expected_max_pages);
+        assert_eq!(init_out.time_gran, 1);
+        assert!(init_out.flags & FUSE_NO_OPEN_SUPPORT != 0);
+        assert!(init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0);
+
+        let output_len = mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_init_out>();
+        let out_header = fuse_out_header {
+            len: output_len as u32,
+            error: 0,
+            unique: 2,
+        };
+        let init_out = fuse_init_out {
+            major: 7,
+            minor: 23,
+            max_readahead: 40,
+            flags: DEFAULT_INIT_FLAGS | FUSE_MAX_PAGES | FUSE_BIG_WRITES,
+            max_background: 0,
+            congestion_threshold: 0,
+            max_write: DEFAULT_MAX_WRITE,
+            time_gran: 1,
+            max_pages: expected_max_pages,
+            padding: 0,
+            unused: [0; 8],
+        };
+
+        let mut expected = Vec::with_capacity(output_len);
+        expected.extend_from_slice(out_header.as_bytes());
+        expected.extend_from_slice(init_out.as_bytes());
+        assert_eq!(output.len(), output_len);
+
+        assert_eq!(expected[0..4], output[0..4], "out_header.len");
+        assert_eq!(expected[4..8], output[4..8], "out_header.error");
+        assert_eq!(expected[8..16], output[8..16], "out_header.unique");
+
+        let expected = &expected[mem::size_of::<fuse_out_header>()..];
+        let output = &output[mem::size_of::<fuse_out_header>()..];
+        assert_eq!(expected[0..4], output[0..4], "init_out.major");
+        assert_eq!(expected[4..8], output[4..8], "init_out.minor");
+        assert_eq!(expected[8..12], output[8..12], "init_out.max_readahead");
+        assert_eq!(expected[12..16], output[12..16], "init_out.flags");
+        assert_eq!(expected[16..18], output[16..18], "init_out.max_background");
+        assert_eq!(
+            expected[18..20],
+            output[18..20],
+            "init_out.congestion_threshold"
+        );
+        assert_eq!(expected[20..24], output[20..24], "init_out.max_write");
+        assert_eq!(expected[24..28], output[24..28], "init_out.time_gran");
+        assert_eq!(expected[28..30], output[28..30], "init_out.max_pages");
+        assert!(
+            output[30..30 + 2 + 4 * 8].iter().all(|&b| b == 0x00),
+            "init_out.paddings"
+        );
+    }
+
+    #[test]
+    fn session_state_roundtrip() {
+        let state = SessionState::new(
+            fuse_init_in {
+                major: 7,
+                minor: 40,
+                max_readahead: 4096,
+                flags: FUSE_ASYNC_READ | FUSE_MAX_PAGES,
+            },
+            default_init_out(),
+        );
+        let bytes = state.to_bytes();
+        let decoded = SessionState::from_bytes(&bytes[..]).unwrap();
+        assert_eq!(decoded.init_in.as_bytes(), state.init_in.as_bytes());
+        assert_eq!(decoded.init_out.as_bytes(), state.init_out.as_bytes());
+        assert!(!decoded.stateless_io);
+        assert_eq!(decoded.lookups, Some(LookupCounts::default()));
+
+        let mut state = state;
+        state.stateless_io = true;
+        state.lookups = Some(LookupCounts::from_counts(
+            vec![(2, 1), (3, 5)].into_iter().collect(),
+        ));
+        let bytes = state.to_bytes();
+        let decoded = SessionState::from_bytes(&bytes[..]).unwrap();
+        assert!(decoded.stateless_io);
+        assert_eq!(decoded.lookups, state.lookups);
+
+        state.lookups = None;
+        let decoded = SessionState::from_bytes(&state.to_bytes()[..]).unwrap();
+        assert_eq!(decoded.lookups, None);
+
+        assert!(SessionState::from_bytes(&bytes[..bytes.len() - 1]).is_err());
+        assert!(SessionState::from_bytes(b"XXXX").is_err());
+    }
+
+    pub(super) fn dup(conn: &Connection) -> RawFd {
+        let fd = unsafe { libc::dup(conn.as_raw_fd()) };
+        assert!(fd >= 0, "failed to duplicate the file descriptor");
+        fd
+    }
+
+    #[test]
+    fn resume_with_config() {
+        let state = SessionState::new(
+            fuse_init_in {
+                major: 7,
+                minor: 31,
+                max_readahead: 4096,
+                flags: 0,
+            },
+            default_init_out(),
+        );
+        let mut config = KernelConfig::default();
+        config.opcode_filter(|opcode| opcode == Some(Opcode::Lookup));
+
+        let (conn, mut peer) = Connection::pair().unwrap();
+        let session = unsafe { Session::resume(dup(&conn), state, config) }.unwrap();
+        send_message(&mut peer, fuse_opcode::FUSE_OPENDIR, 1, &[0u8; 16]);
+        send_message(&mut peer, fuse_opcode::FUSE_LOOKUP, 2, b"foo\0");
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 2);
+        let reply = recv_header(&mut peer);
+        assert_eq!(reply.unique, 1);
+        assert_eq!(reply.error, -libc::ENOSYS);
+        drop(conn);
+    }
+
+    #[test]
+    fn hand_over_connection() {
+        let (old, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let (tx, rx) = UnixStream::pair().unwrap();
+
+        // The requests read by the old process must be replied first.
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        let req = old.next_request().unwrap().unwrap();
+        let err = old.hand_over(&tx).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        req.reply_error(libc::ENOSYS).unwrap();
+        let _ = kernel.recv_reply().unwrap();
+
+        let receiver = std::thread::spawn(move || {
+            let mut config = KernelConfig::default();
+            config.opcode_filter(|opcode| opcode != Some(Opcode::Statfs));
+            Session::take_over(&rx, config).unwrap()
+        });
+        old.hand_over(&tx).unwrap();
+        let new = receiver.join().unwrap();
+        assert!(old.next_request().unwrap().is_none());
+        assert_eq!(new.state().to_bytes(), old.state().to_bytes());
+        drop(old);
+
+        // The settings of the new process are applied after the handover.
+        kernel
+            .send_request(fuse_opcode::FUSE_STATFS as u32, 1, &[])
+            .unwrap();
+        let unique = kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        let req = new.next_request().unwrap().unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
+        assert_eq!(req.unique(), unique);
+        req.reply_error(libc::ENOSYS).unwrap();
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (unique, -libc::ENOSYS));
+    }
+
+    #[test]
+    fn hand_over_lookup_counts() {
+        for &tracked in &[true, false] {
+            let mut config = KernelConfig::default();
+            if tracked {
+                config.reject_stale_inodes(InodeTracking::Live);
+            }
+            let (old, kernel) = crate::testing::session(config).unwrap();
+            let (tx, rx) = UnixStream::pair().unwrap();
+
+            kernel
+                .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+                .unwrap();
+            let req = old.next_request().unwrap().unwrap();
+            let mut out = EntryOut::default();
+            out.ino(2);
+            out.attr().ino(2);
+            req.reply(out).unwrap();
+            let _ = kernel.recv_reply().unwrap();
+
+            let receiver = std::thread::spawn(move || {
+                let mut config = KernelConfig::default();
+                config.reject_stale_inodes(InodeTracking::Live);
+                Session::take_over(&rx, config).unwrap()
+            });
+            old.hand_over(&tx).unwrap();
+            let new = receiver.join().unwrap();
+            drop(old);
+
+            // The inode looked up before the handover is still alive.
+            kernel
+                .send_request(fuse_opcode::FUSE_GETATTR as u32, 2, &[0u8; 16])
+                .unwrap();
+            let unknown = kernel
+                .send_request(fuse_opcode::FUSE_GETATTR as u32, 3, &[0u8; 16])
+                .unwrap();
+            kernel
+                .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+                .unwrap();
+            let req = new.next_request().unwrap().unwrap();
+            assert_eq!(req.header.nodeid, 2);
+            if !tracked {
+                // Without the counts, only the forgotten inodes are rejected.
+                let req = new.next_request().unwrap().unwrap();
+                assert_eq!(req.header.nodeid, 3);
+            }
+            let req = new.next_request().unwrap().unwrap();
+            assert_eq!(req.header.nodeid, 1);
+            if tracked {
+                assert_eq!(new.lookup_counts().unwrap().get(2), 1);
+                let reply = kernel.recv_reply().unwrap();
+                assert_eq!((reply.unique(), reply.error()), (unknown, -libc::ESTALE));
+            }
+        }
+    }
+
+    #[test]
+    fn decline_interrupts() {
+        let mut config = KernelConfig::default();
+        config.no_interrupt(true);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        assert!(session.no_interrupt());
+
+        let getattr = kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                1,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+        let interrupt_in = fuse_interrupt_in { unique: getattr };
+        let interrupts: Vec<_> = (0..100)
+            .map(|_| {
+                kernel
+                    .send_request(
+                        fuse_opcode::FUSE_INTERRUPT as u32,
+                        0,
+                        interrupt_in.as_bytes(),
+                    )
+                    .unwrap()
+            })
+            .collect();
+        kernel
+            .send_request(fuse_opcode::FUSE_STATFS as u32, 1, &[])
+            .unwrap();
+
+        // The interrupts are replied by the session, and nothing is kept for them.
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.opcode(), Some(Opcode::Getattr));
+        let statfs = session.next_request().unwrap().unwrap();
+        assert_eq!(statfs.opcode(), Some(Opcode::Statfs));
+        for &unique in &interrupts {
+            let reply = kernel.recv_reply().unwrap();
+            assert_eq!((reply.unique(), reply.error()), (unique, -libc::ENOSYS));
+        }
+        assert_eq!(session.inner.in_flight.lock().unwrap().len(), 2);
+        assert_eq!(session.denied_requests(), 0);
+        req.reply_error(libc::ENOSYS).unwrap();
+        statfs.reply_error(libc::ENOSYS).unwrap();
+
+        // They are delivered by default.
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        assert!(!session.no_interrupt());
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_INTERRUPT as u32,
+                0,
+                interrupt_in.as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.opcode(), Some(Opcode::Interrupt));
+    }
+
+    #[test]
+    fn readdirplus_negotiation() {
+        let all = u32::MAX;
+        let table = [
+            // (readdirplus, readdirplus_auto, offered, expected)
+            (false, false, all, ReaddirplusMode::Disabled),
+            (false, true, all, ReaddirplusMode::Disabled),
+            (true, false, all, ReaddirplusMode::Always),
+            (true, true, all, ReaddirplusMode::Auto),
+            (
+                true,
+                true,
+                all & !FUSE_READDIRPLUS_AUTO,
+                ReaddirplusMode::Always,
+            ),
+            (
+                true,
+                true,
+                all & !FUSE_DO_READDIRPLUS,
+                ReaddirplusMode::Disabled,
+            ),
+        ];
+        for &(plus, auto, offered, expected) in table.iter() {
+            let mut config = KernelConfig::default();
+            config.readdirplus(plus).readdirplus_auto(auto);
+            let (session, kernel) = crate::testing::session_offering(config, offered).unwrap();
+            assert_eq!(session.readdirplus(), expected, "{:?}", (plus, auto));
+            assert_eq!(
+                ReaddirplusMode::from_flags(kernel.init_flags()),
+                expected,
+                "{:?}",
+                (plus, auto)
+            );
+
+            // READDIRPLUS is delivered only if it is negotiated.
+            let read_in = fuse_read_in {
+                size: 4096,
+                ..Default::default()
+            };
+            for &opcode in &[fuse_opcode::FUSE_READDIRPLUS, fuse_opcode::FUSE_READDIR] {
+                kernel
+                    .send_request(opcode as u32, 1, read_in.as_bytes())
+                    .unwrap();
+            }
+            if expected != ReaddirplusMode::Disabled {
+                let req = session.next_request().unwrap().unwrap();
+                assert_eq!(req.opcode(), Some(Opcode::Readdirplus));
+                req.reply(()).unwrap();
+                assert_eq!(kernel.recv_reply().unwrap().error(), 0);
+            }
+            let req = session.next_request().unwrap().unwrap();
+            assert_eq!(req.opcode(), Some(Opcode::Readdir));
+            if expected == ReaddirplusMode::Disabled {
+                assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
+            }
+        }
+    }
+
+    #[test]
+    fn forgets_after_destroy() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+
+        kernel
+            .send_request(fuse_opcode::FUSE_DESTROY as u32, 0, &[])
+            .unwrap();
+        let forget_in = fuse_forget_in { nlookup: 3 };
+        kernel
+            .send_request(fuse_opcode::FUSE_FORGET as u32, 2, forget_in.as_bytes())
+            .unwrap();
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                2,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        assert!(matches!(req.operation().unwrap(), Operation::Destroy(..)));
+        req.reply(()).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().error(), 0);
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Forget(forgets) => {
+                let forgets = forgets.as_ref();
+                assert_eq!(forgets.len(), 1);
+                assert_eq!(forgets[0].ino(), 2);
+                assert_eq!(forgets[0].nlookup(), 3);
+            }
+            op => panic!("unexpected operation: {:?}", op),
+        }
+
+        let req = session.next_request().unwrap().unwrap();
+        assert!(matches!(req.operation().unwrap(), Operation::Unknown));
+    }
+
+    #[test]
+    fn forget_takes_no_reply() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        kernel.match_replies(true);
+
+        let forget_in = fuse_forget_in { nlookup: 1 };
+        kernel
+            .send_request(fuse_opcode::FUSE_FORGET as u32, 2, forget_in.as_bytes())
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let err = req.reply_error(libc::ENOSYS).unwrap_err();
+        assert!(AlreadyReplied::is(&err));
+        assert!(!req.replied());
+        // A failing handler is not replied either.
+        let err = req
+            .process(|_| Err(io::Error::from_raw_os_error(libc::EIO)))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+
+        // Nothing has been written before the reply to the next request.
+        let unique = kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                1,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOSYS).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().unique(), unique);
+    }
+
+    fn getxattr_reply(size: u32, value: &[u8]) -> (io::Result<()>, crate::testing::RawReply) {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let mut arg = fuse_getxattr_in { size, padding: 0 }.as_bytes().to_vec();
+        arg.extend_from_slice(b"user.foo\0");
+        kernel
+            .send_request(fuse_opcode::FUSE_GETXATTR as u32, 2, &arg[..])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let res = req.reply(value);
+        (res, kernel.recv_reply().unwrap())
+    }
+
+    #[test]
+    fn getxattr_reply_limit() {
+        let (res, reply) = getxattr_reply(4, b"abcd");
+        res.unwrap();
+        assert_eq!(reply.error(), 0);
+        assert_eq!(reply.payload(), b"abcd");
+
+        let (res, reply) = getxattr_reply(4, b"abcde");
+        assert_eq!(res.unwrap_err().raw_os_error(), Some(libc::ERANGE));
+        assert_eq!(reply.error(), -libc::ERANGE);
+        assert!(reply.payload().is_empty());
+    }
+
+    #[test]
+    fn readlink_reply_limit() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let max_len = libc::PATH_MAX as usize - 1;
+
+        kernel
+            .send_request(fuse_opcode::FUSE_READLINK as u32, 2, &[])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply(vec![b'a'; max_len]).unwrap();
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!(reply.error(), 0);
+        assert_eq!(reply.payload().len(), max_len);
+
+        kernel
+            .send_request(fuse_opcode::FUSE_READLINK as u32, 2, &[])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let err = req.reply(vec![b'a'; max_len + 1]).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENAMETOOLONG));
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENAMETOOLONG);
+    }
+
+    #[test]
+    fn caller_credentials() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                1,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+
+        assert_eq!(req.caller_exe().unwrap(), std::env::current_exe().unwrap());
+
+        let mut groups = vec![0; 256];
+        let len = unsafe { libc::getgroups(groups.len() as libc::c_int, groups.as_mut_ptr()) };
+        assert!(len >= 0, "getgroups failed");
+        groups.truncate(len as usize);
+        assert_eq!(req.caller_supplementary_groups().unwrap(), groups);
+    }
+
+    #[test]
+    fn caller_filter() {
+        let mut config = KernelConfig::default();
+        config.caller_filter(|caller| caller.uid() == 1000 || !caller.is_write());
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        let getattr_in = fuse_getattr_in::default();
+        let open_in = |flags: i32| fuse_open_in {
+            flags: flags as u32,
+            ..Default::default()
+        };
+
+        kernel.set_credentials(Some((1001, 1001)));
+        let rejected = kernel
+            .send_request(
+                fuse_opcode::FUSE_SETATTR as u32,
+                2,
+                fuse_setattr_in::default().as_bytes(),
+            )
+            .unwrap();
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_OPEN as u32,
+                2,
+                open_in(libc::O_RDWR).as_bytes(),
+            )
+            .unwrap();
+        let read_only = kernel
+            .send_request(
+                fuse_opcode::FUSE_OPEN as u32,
+                2,
+                open_in(libc::O_RDONLY).as_bytes(),
+            )
+            .unwrap();
+        let forget_in = fuse_forget_in { nlookup: 1 };
+        let forget = kernel
+            .send_request(fuse_opcode::FUSE_FORGET as u32, 2, forget_in.as_bytes())
+            .unwrap();
+
+        kernel.set_credentials(Some((1000, 1000)));
+        let allowed = kernel
+            .send_request(
+                fuse_opcode::FUSE_SETATTR as u32,
+                2,
+                fuse_setattr_in::default().as_bytes(),
+            )
+            .unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 2, getattr_in.as_bytes())
+            .unwrap();
+
+        assert_eq!(session.next_request().unwrap().unwrap().unique(), read_only);
+        assert_eq!(session.next_request().unwrap().unwrap().unique(), forget);
+        assert_eq!(session.next_request().unwrap().unwrap().unique(), allowed);
+
+        for unique in rejected..read_only {
+            let reply = kernel.recv_reply().unwrap();
+            assert_eq!(reply.unique(), unique);
+            assert_eq!(reply.error(), -libc::EACCES);
+        }
+    }
+
+    #[test]
+    fn allowed_uids() {
+        let mut config = KernelConfig::default();
+        config.allowed_uids(vec![0, 1000]);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        let getattr_in = fuse_getattr_in::default();
+
+        for &uid in &[1001, 0, 1000] {
+            kernel.set_credentials(Some((uid, uid)));
+            kernel
+                .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, getattr_in.as_bytes())
+                .unwrap();
+        }
+        assert_eq!(session.next_request().unwrap().unwrap().uid(), 0);
+        assert_eq!(session.next_request().unwrap().unwrap().uid(), 1000);
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EACCES);
+        assert!(session.try_next_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn write_with_extensions() {
+        let mut init_out = default_init_out();
+        init_out.minor = 38;
+        let state = SessionState::new(
+            fuse_init_in {
+                major: 7,
+                minor: 38,
+                max_readahead: 4096,
+                flags: 0,
+            },
+            init_out,
+        );
+        let (conn, mut peer) = Connection::pair().unwrap();
+        let session =
+            unsafe { Session::resume(dup(&conn), state, KernelConfig::default()) }.unwrap();
+
+        let ext_header = fuse_ext_header { size: 16, type_: 0 };
+        let write_in = fuse_write_in {
+            size: 3,
+            ..Default::default()
+        };
+        let arg_len = mem::size_of::<fuse_write_in>() + 3 + 16;
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg_len) as u32,
+            opcode: fuse_opcode::FUSE_WRITE as u32,
+            unique: 2,
+            nodeid: 2,
+            total_extlen: 2,
+            ..Default::default()
+        };
+        let mut msg = header.as_bytes().to_vec();
+        msg.extend_from_slice(write_in.as_bytes());
+        msg.extend_from_slice(b"foo");
+        msg.extend_from_slice(ext_header.as_bytes());
+        msg.extend_from_slice(&[0xff; 8]);
+        peer.write_all(&msg[..]).unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.extensions().count(), 1);
+        match req.operation().unwrap() {
+            Operation::Write(_, mut data) => {
+                let mut buf = vec![];
+                io::Read::read_to_end(&mut data, &mut buf).unwrap();
+                assert_eq!(buf, b"foo");
             }
+            op => panic!("unexpected operation: {:?}", op),
+        }
+    }
+
+    #[test]
+    fn attr_flags_by_protocol() {
+        let mut out = AttrOut::default();
+        out.attr().ino(2);
+        out.attr().flags(AttrFlags::SUBMOUNT | AttrFlags::DAX);
+
+        // ABI 7.31 does not define any flags, and the field is not rendered again.
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 2, &[0u8; 16])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        assert!(req.filter_attr_flags(&&out).is_none());
+        req.reply(&out).unwrap();
+        let reply = kernel.recv_reply().unwrap();
+        let mut attr_out = fuse_attr_out::default();
+        attr_out.as_bytes_mut().copy_from_slice(reply.payload());
+        assert_eq!(attr_out.attr.ino, 2);
+
+        // ABI 7.32 defines only SUBMOUNT.
+        let mut init_out = default_init_out();
+        init_out.minor = 32;
+        let state = SessionState::new(
+            fuse_init_in {
+                major: 7,
+                minor: 32,
+                max_readahead: 4096,
+                flags: 0,
+            },
+            init_out,
+        );
+        let (conn, mut peer) = Connection::pair().unwrap();
+        let session =
+            unsafe { Session::resume(dup(&conn), state, KernelConfig::default()) }.unwrap();
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + 16) as u32,
+            opcode: fuse_opcode::FUSE_GETATTR as u32,
+            unique: 2,
+            nodeid: 2,
+            ..Default::default()
+        };
+        let mut msg = header.as_bytes().to_vec();
+        msg.extend_from_slice(&[0u8; 16]);
+        peer.write_all(&msg[..]).unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        req.reply(&out).unwrap();
+        let mut buf = vec![0u8; 1024];
+        let len = peer.read(&mut buf[..]).unwrap();
+        assert_eq!(
+            len,
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_attr_out>()
+        );
+        attr_out
+            .as_bytes_mut()
+            .copy_from_slice(&buf[mem::size_of::<fuse_out_header>()..len]);
+        assert_eq!(attr_out.attr.flags, FUSE_ATTR_SUBMOUNT);
+    }
+
+    #[test]
+    fn init_failure_closes_connection() {
+        let (conn, mut peer) = Connection::pair().unwrap();
+
+        let init_in = fuse_init_in {
+            major: 6,
+            minor: 0,
+            max_readahead: 0,
+            flags: 0,
+        };
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_init_in>()) as u32,
+            opcode: fuse_opcode::FUSE_INIT as u32,
+            unique: 1,
+            ..Default::default()
+        };
+        let mut msg = header.as_bytes().to_vec();
+        msg.extend_from_slice(init_in.as_bytes());
+        peer.write_all(&msg[..]).unwrap();
+        // The kernel aborts the connection after replying EPROTO.
+        peer.shutdown(std::net::Shutdown::Write).unwrap();
+
+        let err = Session::init(conn, KernelConfig::default()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut buf = [0u8; 64];
+        let len = peer.read(&mut buf[..]).unwrap();
+        assert_eq!(len, mem::size_of::<fuse_out_header>());
+        assert_eq!(buf[4..8], (-libc::EPROTO).to_ne_bytes(), "header.error");
+
+        // The device has been closed by the failed session.
+        assert_eq!(peer.read(&mut buf[..]).unwrap(), 0);
+    }
+
+    #[test]
+    fn truncated_write_payload() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+
+        // The argument is shorter than `fuse_write_in`.
+        kernel
+            .send_request(fuse_opcode::FUSE_WRITE as u32, 2, &[0u8; 8])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let err = req.operation().unwrap_err();
+        assert_eq!(err.errno(), libc::EIO);
+    }
+
+    #[test]
+    fn reply_xattr_with() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let value = b"security label";
+        let filled = Cell::new(0);
+        let fill = |buf: &mut Vec<u8>| {
+            filled.set(filled.get() + 1);
+            buf.extend_from_slice(value);
+        };
+
+        for &size in &[0, 4, value.len() as u32] {
+            let mut arg = fuse_getxattr_in { size, padding: 0 }.as_bytes().to_vec();
+            arg.extend_from_slice(b"security.foo\0");
+            kernel
+                .send_request(fuse_opcode::FUSE_GETXATTR as u32, 2, &arg[..])
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            req.reply_xattr_with(value.len(), fill).unwrap();
+        }
+
+        let probe = kernel.recv_reply().unwrap();
+        assert_eq!(probe.error(), 0);
+        assert_eq!(probe.payload()[0..4], (value.len() as u32).to_ne_bytes());
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ERANGE);
+        assert_eq!(kernel.recv_reply().unwrap().payload(), value);
+        assert_eq!(filled.get(), 1);
+
+        // The other requests are not replied by this method.
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 2, &[0u8; 16])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let err = req.reply_xattr_with(value.len(), fill).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(filled.get(), 1);
+        req.reply_error(libc::ENOSYS).unwrap();
+    }
+
+    fn send_message(peer: &mut UnixStream, opcode: fuse_opcode, unique: u64, arg: &[u8]) {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + arg.len()) as u32,
+            opcode: opcode as u32,
+            unique,
+            nodeid: 1,
+            ..Default::default()
+        };
+        let mut msg = header.as_bytes().to_vec();
+        msg.extend_from_slice(arg);
+        peer.write_all(&msg[..]).unwrap();
+    }
+
+    fn early_requests(max_early_requests: usize) -> (Session, UnixStream) {
+        let (conn, mut peer) = Connection::pair().unwrap();
+        let forget_in = fuse_forget_in { nlookup: 1 };
+        send_message(&mut peer, fuse_opcode::FUSE_FORGET, 1, forget_in.as_bytes());
+        send_message(&mut peer, fuse_opcode::FUSE_LOOKUP, 2, b"foo\0");
+        let init_in = fuse_init_in {
+            major: FUSE_KERNEL_VERSION,
+            minor: FUSE_KERNEL_MINOR_VERSION,
+            max_readahead: 4096,
+            flags: 0,
+        };
+        send_message(&mut peer, fuse_opcode::FUSE_INIT, 3, init_in.as_bytes());
+
+        let mut config = KernelConfig::default();
+        config.max_early_requests(max_early_requests);
+        let session = Session::init(conn, config).unwrap();
+        (session, peer)
+    }
+
+    fn recv_header(peer: &mut UnixStream) -> fuse_out_header {
+        let mut buf = vec![0u8; 4096];
+        let len = peer.read(&mut buf[..]).unwrap();
+        assert!(len >= mem::size_of::<fuse_out_header>());
+        let mut header = fuse_out_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&buf[..mem::size_of::<fuse_out_header>()]);
+        header
+    }
+
+    #[test]
+    fn early_requests_rejected() {
+        let (session, mut peer) = early_requests(0);
+
+        // The FORGET is not replied.
+        let reply = recv_header(&mut peer);
+        assert_eq!(reply.unique, 2);
+        assert_eq!(reply.error, -libc::EIO);
+        let reply = recv_header(&mut peer);
+        assert_eq!(reply.unique, 3);
+        assert_eq!(reply.error, 0);
+
+        assert!(session.try_next_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn blocking_and_nonblocking_readers() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let session = Arc::new(session);
+        assert!(session.try_next_request().unwrap().is_none());
+
+        let reader = std::thread::spawn({
+            let session = session.clone();
+            move || session.next_request().unwrap().unwrap().unique()
+        });
+        let getattr_in = fuse_getattr_in::default();
+        let unique = kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, getattr_in.as_bytes())
+            .unwrap();
+        assert_eq!(reader.join().unwrap(), unique);
+        assert!(session.try_next_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn early_requests_queued() {
+        let (session, mut peer) = early_requests(1);
+
+        let reply = recv_header(&mut peer);
+        assert_eq!(reply.unique, 3, "only INIT is replied");
+
+        let req = session.try_next_request().unwrap().unwrap();
+        assert_eq!(req.unique(), 2);
+        match req.operation().unwrap() {
+            Operation::Lookup(op) => assert_eq!(op.name(), "foo"),
+            op => panic!("unexpected operation: {:?}", op),
+        }
+        assert!(session.try_next_request().unwrap().is_none());
+    }
+
+    #[test]
+    fn opcode_filter() {
+        let mut config = KernelConfig::default();
+        config
+            .opcode_filter(|opcode| matches!(opcode, Some(Opcode::Lookup) | Some(Opcode::Getattr)));
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        kernel
+            .send_request(fuse_opcode::FUSE_UNLINK as u32, 1, b"foo\0")
+            .unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_READLINK as u32, 2, &[])
+            .unwrap();
+        // An opcode unknown to the library.
+        kernel.send_request(9999, 2, &[]).unwrap();
+        let lookup = kernel
+            .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+            .unwrap();
+
+        assert_eq!(session.next_request().unwrap().unwrap().unique(), lookup);
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EROFS);
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
+        assert_eq!(session.denied_requests(), 3);
+    }
+
+    #[test]
+    fn request_deadline() {
+        let mut config = KernelConfig::default();
+        config
+            .deadline(OpcodeClass::Metadata, Duration::from_secs(0))
+            .deadline(OpcodeClass::Read, Duration::from_secs(60));
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        let getattr = kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        let read_in = fuse_read_in::default();
+        kernel
+            .send_request(fuse_opcode::FUSE_READ as u32, 2, read_in.as_bytes())
+            .unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_OPEN as u32, 2, &[0u8; 8])
+            .unwrap();
+
+        // The request already exceeding its deadline is not delivered.
+        let mut req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.raw_opcode(), fuse_opcode::FUSE_READ as u32);
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (getattr, -libc::ETIMEDOUT));
+
+        // The reply after the deadline is sent as it is.
+        assert!(req.deadline().unwrap() > Instant::now());
+        req.deadline = Some(Instant::now());
+        req.reply(&b"data"[..]).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().payload(), b"data");
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.deadline(), None);
+    }
 
-            fn count(&self) -> usize {
-                2
-            }
+    #[test]
+    fn destroy_without_deadline() {
+        let mut config = KernelConfig::default();
+        config.deadline(OpcodeClass::Other, Duration::from_secs(0));
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        let open = kernel
+            .send_request(fuse_opcode::FUSE_OPEN as u32, 2, &[0u8; 8])
+            .unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_DESTROY as u32, 0, &[])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.raw_opcode(), fuse_opcode::FUSE_DESTROY as u32);
+        assert_eq!(req.deadline(), None);
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (open, -libc::ETIMEDOUT));
+    }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
+    #[test]
+    fn process_replies_on_error() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        for _ in 0..3 {
+            kernel
+                .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+                .unwrap();
+        }
+        let forget_in = fuse_forget_in { nlookup: 1 };
+        kernel
+            .send_request(fuse_opcode::FUSE_FORGET as u32, 2, forget_in.as_bytes())
+            .unwrap();
+
+        // The backend is disconnected before replying.
+        let req = session.next_request().unwrap().unwrap();
+        let err = req
+            .process(|_| Err(io::Error::from_raw_os_error(libc::ENOTCONN)))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOTCONN));
+        assert!(req.replied());
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOTCONN);
+
+        // The errors without error numbers are replied as EIO.
+        let req = session.next_request().unwrap().unwrap();
+        let _ = req.process(|_| Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted")));
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EIO);
+
+        // The handler has already replied, so nothing is sent twice.
+        let req = session.next_request().unwrap().unwrap();
+        let _ = req.process(|req| {
+            req.reply_error(libc::ENOENT)?;
+            Err(io::Error::from_raw_os_error(libc::EPIPE))
+        });
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOENT);
+
+        let req = session.next_request().unwrap().unwrap();
+        let _ = req.process(|_| Err(io::Error::from_raw_os_error(libc::EIO)));
+        assert!(!req.replied());
+
+        // The FORGET is not replied.
+        let unique = kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOSYS).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().unique(), unique);
+    }
+
+    #[test]
+    fn create_mode_with_and_without_dont_mask() {
+        // (dont_mask, mode sent by the kernel, mode visible to the handler)
+        for &(dont_mask, sent, expected) in
+            &[(false, 0o100644, 0o100644), (true, 0o100666, 0o100644)]
+        {
+            let mut config = KernelConfig::default();
+            config.dont_mask(dont_mask);
+            let (session, kernel) = crate::testing::session(config).unwrap();
+            assert_eq!(session.granted().contains(FUSE_DONT_MASK), dont_mask);
+
+            let create_in = fuse_create_in {
+                flags: libc::O_CREAT as u32,
+                mode: sent,
+                umask: 0o022,
+                ..Default::default()
+            };
+            let mut arg = create_in.as_bytes().to_vec();
+            arg.extend_from_slice(b"foo\0");
+            kernel
+                .send_request(fuse_opcode::FUSE_CREATE as u32, 1, &arg[..])
+                .unwrap();
+            let mkdir_in = fuse_mkdir_in {
+                mode: sent & 0o777,
+                umask: 0o022,
+            };
+            let mut arg = mkdir_in.as_bytes().to_vec();
+            arg.extend_from_slice(b"bar\0");
+            kernel
+                .send_request(fuse_opcode::FUSE_MKDIR as u32, 1, &arg[..])
+                .unwrap();
+
+            let req = session.next_request().unwrap().unwrap();
+            match req.operation().unwrap() {
+                Operation::Create(op) => {
+                    assert_eq!(op.mode(), expected);
+                    assert_eq!(op.raw_mode(), sent);
+                    assert_eq!(op.umask(), 0o022);
+                }
+                _ => panic!("incorrect operation is returned"),
+            }
+            let req = session.next_request().unwrap().unwrap();
+            match req.operation().unwrap() {
+                Operation::Mkdir(op) => {
+                    assert_eq!(op.mode(), expected & 0o777);
+                    assert_eq!(op.raw_mode(), sent & 0o777);
+                }
+                _ => panic!("incorrect operation is returned"),
             }
         }
     }
 
-    /// Send I/O readiness to the kernel.
-    pub fn poll_wakeup(&self, kh: u64) -> io::Result<()> {
-        let total_len = u32::try_from(
-            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_poll_wakeup_out>(),
-        )
-        .unwrap();
-
-        return write_bytes(
-            &self.session.conn,
-            PollWakeup {
-                header: fuse_out_header {
-                    len: total_len,
-                    error: fuse_notify_code::FUSE_NOTIFY_POLL as i32,
-                    unique: 0,
-                },
-                arg: fuse_notify_poll_wakeup_out { kh },
-            },
-        );
+    #[test]
+    fn stateless_io_after_enosys_open() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        assert!(session.no_open_support());
+        assert!(!session.stateless_io());
+
+        let read = |ino: u64, fh: u64| {
+            let read_in = fuse_read_in {
+                fh,
+                size: 10,
+                ..Default::default()
+            };
+            kernel
+                .send_request(fuse_opcode::FUSE_READ as u32, ino, read_in.as_bytes())
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            let fh = match req.operation().unwrap() {
+                Operation::Read(op) => op.fh(),
+                _ => panic!("incorrect operation is returned"),
+            };
+            fh
+        };
+        let open = |ino: u64, reply: &dyn Fn(Request)| {
+            kernel
+                .send_request(
+                    fuse_opcode::FUSE_OPEN as u32,
+                    ino,
+                    fuse_open_in::default().as_bytes(),
+                )
+                .unwrap();
+            reply(session.next_request().unwrap().unwrap());
+            kernel.recv_reply().unwrap().error()
+        };
+        assert_eq!(read(2, 0), Some(0));
+
+        // The file opened with the handle 0 by the filesystem.
+        let open_zero = |req: Request| req.reply(OpenOut::default()).unwrap();
+        assert_eq!(open(3, &open_zero), 0);
+
+        let enosys = |req: Request| req.reply_error(libc::ENOSYS).unwrap();
+        assert_eq!(open(2, &enosys), -libc::ENOSYS);
+        assert!(session.stateless_io());
+
+        assert_eq!(read(2, 0), None);
+        // The files opened before keep their handles.
+        assert_eq!(read(2, 3), Some(3));
+        assert_eq!(read(3, 0), Some(0));
+
+        let release_in = fuse_release_in::default();
+        kernel
+            .send_request(fuse_opcode::FUSE_RELEASE as u32, 3, release_in.as_bytes())
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply(()).unwrap();
+        let _ = kernel.recv_reply().unwrap();
+        assert_eq!(read(3, 0), None);
+
+        let write_in = fuse_write_in {
+            size: 0,
+            ..Default::default()
+        };
+        kernel
+            .send_request(fuse_opcode::FUSE_WRITE as u32, 2, write_in.as_bytes())
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Write(op, _) => assert_eq!(op.fh(), None),
+            _ => panic!("incorrect operation is returned"),
+        }
+    }
+
+    #[test]
+    fn stateless_io_by_opcode_filter() {
+        let mut config = KernelConfig::default();
+        config.opcode_filter(|opcode| opcode != Some(Opcode::Open));
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_OPEN as u32,
+                2,
+                fuse_open_in::default().as_bytes(),
+            )
+            .unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        drop(session.next_request().unwrap().unwrap());
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
+        assert!(session.stateless_io());
+    }
 
-        struct PollWakeup {
-            header: fuse_out_header,
-            arg: fuse_notify_poll_wakeup_out,
+    #[test]
+    fn racing_replies_send_one() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let unique = kernel
+            .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+            .unwrap();
+        let req = Arc::new(session.next_request().unwrap().unwrap());
+
+        let handles: Vec<_> = (0..8)
+            .map(|_| {
+                let req = req.clone();
+                std::thread::spawn(move || req.reply_error(libc::ENOENT))
+            })
+            .collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+
+        assert_eq!(results.iter().filter(|res| res.is_ok()).count(), 1);
+        for err in results.iter().filter_map(|res| res.as_ref().err()) {
+            assert!(AlreadyReplied::is(err));
+            let inner = err.get_ref().unwrap().downcast_ref::<AlreadyReplied>();
+            assert_eq!(inner.map(|e| e.unique()), Some(unique));
         }
-        impl Bytes for PollWakeup {
-            fn size(&self) -> usize {
-                self.header.len as usize
+
+        // Only one reply hits the wire.
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (unique, -libc::ENOENT));
+        let next = kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        session
+            .next_request()
+            .unwrap()
+            .unwrap()
+            .reply_error(libc::EIO)
+            .unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().unique(), next);
+    }
+
+    #[test]
+    fn reply_after_error_reply() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+
+        // An error reply by the process wrapper wins over the late reply.
+        let res = req.process(|req| {
+            req.reply_error(libc::EACCES)?;
+            Err(io::Error::from_raw_os_error(libc::EIO))
+        });
+        assert!(res.is_err());
+        let err = req.reply(AttrOut::default()).unwrap_err();
+        assert!(AlreadyReplied::is(&err));
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EACCES);
+        assert!(session.inner.in_flight.lock().unwrap().is_empty());
+
+        // A request dropped without replying is also forgotten.
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        drop(session.next_request().unwrap().unwrap());
+        assert!(session.inner.in_flight.lock().unwrap().is_empty());
+    }
+
+    /// Send a request to the sessions in both modes, and return the errors of the replies.
+    fn reply_in_both_modes<F>(opcode: fuse_opcode, arg: &[u8], reply: F) -> (i32, i32)
+    where
+        F: Fn(&Request) -> io::Result<()>,
+    {
+        let mut errors = [0; 2];
+        for (strict, error) in [false, true].iter().zip(errors.iter_mut()) {
+            let mut config = KernelConfig::default();
+            config.strict(*strict).readdirplus(true);
+            let (session, kernel) = crate::testing::session(config).unwrap();
+            kernel.send_request(opcode as u32, 1, arg).unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            let res = reply(&req);
+            *error = kernel.recv_reply().unwrap().error();
+            assert_eq!(res.is_err(), *error != 0);
+        }
+        (errors[0], errors[1])
+    }
+
+    #[test]
+    fn strict_entry_ino() {
+        let reply = |attr_ino: u64| {
+            move |req: &Request| {
+                let mut out = EntryOut::default();
+                out.ino(2);
+                out.attr().ino(attr_ino);
+                out.attr().mode(libc::S_IFREG | 0o644);
+                out.attr().nlink(1);
+                req.reply(out)
             }
+        };
+        // The attributes may carry the inode number of the underlying file.
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_LOOKUP, b"foo\0", reply(3)),
+            (0, 0)
+        );
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_LOOKUP, b"foo\0", reply(2)),
+            (0, 0)
+        );
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_LOOKUP, b"foo\0", reply(0)),
+            (0, -libc::EIO)
+        );
+    }
 
-            fn count(&self) -> usize {
-                2
+    #[test]
+    fn strict_entry_attr() {
+        let reply = |mode: u32, nlink: u32| {
+            move |req: &Request| {
+                let mut out = EntryOut::default();
+                out.ino(2);
+                out.attr().ino(2);
+                out.attr().mode(mode);
+                out.attr().nlink(nlink);
+                req.reply(out)
             }
+        };
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_LOOKUP, b"foo\0", reply(0o644, 1)),
+            (0, -libc::EIO)
+        );
+        assert_eq!(
+            reply_in_both_modes(
+                fuse_opcode::FUSE_LOOKUP,
+                b"foo\0",
+                reply(libc::S_IFREG | 0o644, 0)
+            ),
+            (0, -libc::EIO)
+        );
+        // The block size is left to the kernel.
+        assert_eq!(
+            reply_in_both_modes(
+                fuse_opcode::FUSE_LOOKUP,
+                b"foo\0",
+                reply(libc::S_IFDIR | 0o755, 2)
+            ),
+            (0, 0)
+        );
+    }
 
-            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-                dst.put(self.header.as_bytes());
-                dst.put(self.arg.as_bytes());
+    #[test]
+    fn strict_attr() {
+        let reply = |ino: u64, mode: u32, nlink: u32| {
+            move |req: &Request| {
+                let mut out = AttrOut::default();
+                out.attr().ino(ino);
+                out.attr().mode(mode);
+                out.attr().nlink(nlink);
+                req.reply(out)
+            }
+        };
+        let getattr_in = fuse_getattr_in::default();
+        let arg = getattr_in.as_bytes();
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_GETATTR, arg, reply(0, libc::S_IFDIR, 2)),
+            (0, -libc::EIO)
+        );
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_GETATTR, arg, reply(1, 0o755, 2)),
+            (0, -libc::EIO)
+        );
+        // An opened file may have been unlinked.
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_GETATTR, arg, reply(1, libc::S_IFREG, 0)),
+            (0, 0)
+        );
+        let setattr_in = fuse_setattr_in::default();
+        assert_eq!(
+            reply_in_both_modes(
+                fuse_opcode::FUSE_SETATTR,
+                setattr_in.as_bytes(),
+                reply(1, 0, 1)
+            ),
+            (0, -libc::EIO)
+        );
+    }
+
+    #[test]
+    fn strict_negative_entry() {
+        let reply = |req: &Request| req.reply(EntryOut::default());
+        let mkdir_in = fuse_mkdir_in::default();
+        let mut arg = mkdir_in.as_bytes().to_vec();
+        arg.extend_from_slice(b"foo\0");
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_MKDIR, &arg, reply),
+            (0, -libc::EIO)
+        );
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_LOOKUP, b"foo\0", reply),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn strict_write_size() {
+        let write_in = fuse_write_in {
+            size: 4,
+            ..Default::default()
+        };
+        let mut arg = write_in.as_bytes().to_vec();
+        arg.extend_from_slice(b"data");
+        let reply = |size: u32| {
+            move |req: &Request| {
+                let mut out = WriteOut::default();
+                WriteOut::size(&mut out, size);
+                req.reply(out)
             }
+        };
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_WRITE, &arg, reply(8)),
+            (0, -libc::EIO)
+        );
+        assert_eq!(
+            reply_in_both_modes(fuse_opcode::FUSE_WRITE, &arg, reply(4)),
+            (0, 0)
+        );
+    }
+
+    #[test]
+    fn strict_readdir_size() {
+        let read_in = fuse_read_in {
+            size: 16,
+            ..Default::default()
+        };
+        let reply = |len: usize| move |req: &Request| req.reply(vec![0u8; len]);
+        for &opcode in &[fuse_opcode::FUSE_READDIR, fuse_opcode::FUSE_READDIRPLUS] {
+            assert_eq!(
+                reply_in_both_modes(opcode, read_in.as_bytes(), reply(32)),
+                (0, -libc::EIO)
+            );
+            assert_eq!(
+                reply_in_both_modes(opcode, read_in.as_bytes(), reply(16)),
+                (0, 0)
+            );
         }
     }
-}
 
-// ==== utils ====
+    #[test]
+    fn fsync_flags() {
+        for &opcode in &[fuse_opcode::FUSE_FSYNC, fuse_opcode::FUSE_FSYNCDIR] {
+            for &fsync_flags in &[0, FUSE_FSYNC_FDATASYNC] {
+                let fsync_in = fuse_fsync_in {
+                    fh: 3,
+                    fsync_flags,
+                    ..Default::default()
+                };
+                let datasync = |req: &Request| {
+                    let flags = match req.operation().unwrap() {
+                        Operation::Fsync(op) => op.flags(),
+                        Operation::Fsyncdir(op) => op.flags(),
+                        _ => unreachable!(),
+                    };
+                    assert_eq!(flags.bits(), fsync_flags);
+                    assert_eq!(flags.datasync(), fsync_flags != 0);
+                    assert_eq!(flags.unknown(), 0);
+                    req.reply_error(0)
+                };
+                assert_eq!(
+                    reply_in_both_modes(opcode, fsync_in.as_bytes(), datasync),
+                    (0, 0)
+                );
+            }
 
-struct Reply<T> {
-    header: fuse_out_header,
-    arg: T,
-}
-impl<T> Reply<T>
-where
-    T: Bytes,
-{
-    #[inline]
-    fn new(unique: u64, error: i32, arg: T) -> Self {
-        let len = (mem::size_of::<fuse_out_header>() + arg.size())
-            .try_into()
-            .expect("Argument size is too large");
-        Self {
-            header: fuse_out_header {
-                len,
-                error: -error,
-                unique,
-            },
-            arg,
+            // The unknown bits are delivered only in the non-strict mode.
+            let fsync_in = fuse_fsync_in {
+                fsync_flags: FUSE_FSYNC_FDATASYNC | 0x10,
+                ..Default::default()
+            };
+            for &strict in &[false, true] {
+                let mut config = KernelConfig::default();
+                config.strict(strict);
+                let (session, kernel) = crate::testing::session(config).unwrap();
+                kernel
+                    .send_request(opcode as u32, 1, fsync_in.as_bytes())
+                    .unwrap();
+                kernel
+                    .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+                    .unwrap();
+                let mut req = session.next_request().unwrap().unwrap();
+                if strict {
+                    assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EINVAL);
+                } else {
+                    assert_eq!(req.raw_opcode(), opcode as u32);
+                    req.reply_error(libc::ENOSYS).unwrap();
+                    kernel.recv_reply().unwrap();
+                    req = session.next_request().unwrap().unwrap();
+                }
+                assert_eq!(req.raw_opcode(), fuse_opcode::FUSE_GETATTR as u32);
+            }
         }
     }
-}
-impl<T> Bytes for Reply<T>
-where
-    T: Bytes,
-{
-    #[inline]
-    fn size(&self) -> usize {
-        self.header.len as usize
+
+    #[test]
+    fn reply_interceptor() {
+        let observed = Arc::new(Mutex::new(vec![]));
+        let mut config = KernelConfig::default();
+        config.reply_interceptor({
+            let observed = observed.clone();
+            move |req: &Caller<'_>, reply: ReplyBody<'_>| {
+                let action = match reply {
+                    ReplyBody::Entry { mut attr, .. } | ReplyBody::Attr(mut attr) => {
+                        if attr.uid() == 1000 {
+                            attr.set_uid(0);
+                        }
+                        Action::Send
+                    }
+                    ReplyBody::Error { errno } if errno == libc::ENOSYS => {
+                        Action::Error(libc::EOPNOTSUPP)
+                    }
+                    ReplyBody::Other { .. } if req.nodeid() == 5 => Action::Error(libc::EACCES),
+                    _ => Action::Send,
+                };
+                observed
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {:?}", req.unique(), action));
+                action
+            }
+        });
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        fn decode<O: AsBytes + FromBytes + Default>(payload: &[u8]) -> O {
+            let mut out = O::default();
+            out.as_bytes_mut()
+                .copy_from_slice(&payload[..mem::size_of::<O>()]);
+            out
+        }
+
+        let getattr = kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                2,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = AttrOut::default();
+        out.attr().ino(2);
+        out.attr().uid(1000);
+        out.attr().gid(1000);
+        req.reply(out).unwrap();
+        let out: fuse_attr_out = decode(kernel.recv_reply().unwrap().payload());
+        assert_eq!((out.attr.ino, out.attr.uid, out.attr.gid), (2, 0, 1000));
+
+        kernel
+            .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = EntryOut::default();
+        out.ino(3);
+        out.attr().ino(3);
+        out.attr().uid(1000);
+        req.reply(out).unwrap();
+        let out: fuse_entry_out = decode(kernel.recv_reply().unwrap().payload());
+        assert_eq!((out.nodeid, out.attr.uid), (3, 0));
+
+        let read = kernel
+            .send_request(
+                fuse_opcode::FUSE_READ as u32,
+                3,
+                fuse_read_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply("hello").unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().payload(), b"hello");
+
+        kernel
+            .send_request(fuse_opcode::FUSE_READLINK as u32, 4, &[])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOSYS).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EOPNOTSUPP);
+
+        kernel
+            .send_request(fuse_opcode::FUSE_STATFS as u32, 5, &[])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply(crate::reply::StatfsOut::default()).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EACCES);
+
+        let observed = observed.lock().unwrap();
+        assert_eq!(observed.len(), 5);
+        assert_eq!(observed[0], format!("{} Send", getattr));
+        assert_eq!(observed[2], format!("{} Send", read));
+        assert_eq!(
+            observed[4],
+            format!("{} Error({})", getattr + 4, libc::EACCES)
+        );
     }
 
-    #[inline]
-    fn count(&self) -> usize {
-        self.arg.count() + 1
+    #[test]
+    fn background_admission() {
+        let mut config = KernelConfig::default();
+        config.max_background(2);
+        config.congestion_threshold(1);
+        config.background_admission(true);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        assert_eq!(session.background_limits(), Some((2, 1)));
+        let session = Arc::new(session);
+
+        let read_in = fuse_read_in {
+            size: 4096,
+            ..Default::default()
+        };
+        for _ in 0..3 {
+            kernel
+                .send_request(fuse_opcode::FUSE_READDIR as u32, 1, read_in.as_bytes())
+                .unwrap();
+        }
+        let first = session.next_request().unwrap().unwrap();
+        let second = session.next_request().unwrap().unwrap();
+        assert_eq!(session.background_requests(), 2);
+        assert!(session.try_next_request().unwrap().is_none());
+
+        first.reply_error(libc::ENOSYS).unwrap();
+        assert_eq!(session.background_requests(), 1);
+        assert!(session.try_next_request().unwrap().is_none());
+
+        let receiver = std::thread::spawn({
+            let session = session.clone();
+            move || session.next_request().unwrap().unwrap()
+        });
+        // The third one has been put off by `try_next_request`, and the
+        // receiver may take it as soon as the second one is dropped.
+        drop(second);
+        let third = receiver.join().unwrap();
+        assert_eq!(session.background_requests(), 1);
+        drop(third);
+        assert_eq!(session.background_requests(), 0);
+
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
     }
 
-    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
-        dst.put(self.header.as_bytes());
-        self.arg.fill_bytes(dst);
+    #[test]
+    fn interrupt_while_paused() {
+        let resumed = Arc::new(AtomicU32::new(0));
+        let mut config = KernelConfig::default();
+        config.max_background(2);
+        config.congestion_threshold(1);
+        config.background_admission(true);
+        config.on_background_resumed({
+            let resumed = resumed.clone();
+            move || {
+                resumed.fetch_add(1, Ordering::SeqCst);
+            }
+        });
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        let read_in = fuse_read_in {
+            size: 4096,
+            ..Default::default()
+        };
+        let uniques: Vec<_> = (0..3)
+            .map(|_| {
+                kernel
+                    .send_request(fuse_opcode::FUSE_READDIR as u32, 1, read_in.as_bytes())
+                    .unwrap()
+            })
+            .collect();
+        let interrupt_in = fuse_interrupt_in { unique: uniques[0] };
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_INTERRUPT as u32,
+                0,
+                interrupt_in.as_bytes(),
+            )
+            .unwrap();
+
+        let first = session.try_next_request().unwrap().unwrap();
+        let second = session.try_next_request().unwrap().unwrap();
+        assert_eq!(session.background_requests(), 2);
+        // The third READDIR is put off, and the interrupt is delivered.
+        let interrupt = session.try_next_request().unwrap().unwrap();
+        match interrupt.operation().unwrap() {
+            Operation::Interrupt(op) => assert_eq!(op.unique(), uniques[0]),
+            op => panic!("unexpected operation: {:?}", op),
+        }
+        assert!(session.try_next_request().unwrap().is_none());
+
+        first.reply_error(libc::EINTR).unwrap();
+        assert_eq!(resumed.load(Ordering::SeqCst), 0);
+        second.reply_error(libc::ENOSYS).unwrap();
+        assert_eq!(resumed.load(Ordering::SeqCst), 1);
+        let third = session.try_next_request().unwrap().unwrap();
+        assert_eq!(third.unique(), uniques[2]);
+        third.reply_error(libc::ENOSYS).unwrap();
+
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EINTR);
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
     }
-}
 
-#[inline]
-fn write_bytes<W, T>(mut writer: W, bytes: T) -> io::Result<()>
-where
-    W: io::Write,
-    T: Bytes,
-{
-    let size = bytes.size();
-    let count = bytes.count();
+    #[test]
+    fn reply_interceptor_readdirplus() {
+        let mut config = KernelConfig::default();
+        config.readdirplus(true);
+        config.reply_interceptor(|_: &Caller<'_>, reply: ReplyBody<'_>| {
+            if let ReplyBody::Entries(mut entries) = reply {
+                entries.for_each(|ino, mut attr| {
+                    if ino != 0 && attr.uid() == 1000 {
+                        attr.set_uid(0);
+                    }
+                });
+            }
+            Action::Send
+        });
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        let entry = |ino: u64, uid: u32, name: &[u8]| {
+            let mut header = fuse_direntplus::default();
+            header.entry_out.nodeid = ino;
+            header.entry_out.attr.ino = ino;
+            header.entry_out.attr.uid = uid;
+            header.dirent.ino = ino;
+            header.dirent.namelen = name.len() as u32;
+            let mut entry = header.as_bytes().to_vec();
+            entry.extend_from_slice(name);
+            entry.resize((entry.len() + 7) & !7, 0);
+            entry
+        };
+        let mut payload = entry(2, 1000, b"foo");
+        payload.extend(entry(3, 1001, b"bar.txt"));
+
+        for &(uid, expected) in &[(1000, 0), (1001, 1001)] {
+            kernel
+                .send_request(
+                    fuse_opcode::FUSE_READDIRPLUS as u32,
+                    1,
+                    fuse_read_in::default().as_bytes(),
+                )
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            let payload = entry(2, uid, b"foo");
+            req.reply(&payload[..]).unwrap();
+            let reply = kernel.recv_reply().unwrap();
+            let mut header = fuse_direntplus::default();
+            header
+                .as_bytes_mut()
+                .copy_from_slice(&reply.payload()[..mem::size_of::<fuse_direntplus>()]);
+            assert_eq!(header.entry_out.attr.uid, expected);
+            assert_eq!(
+                &reply.payload()[mem::size_of::<fuse_direntplus>()..][..3],
+                b"foo"
+            );
+        }
 
-    let written;
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_READDIRPLUS as u32,
+                1,
+                fuse_read_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply(&payload[..]).unwrap();
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!(reply.payload().len(), payload.len());
+        let second = &reply.payload()[entry(2, 0, b"foo").len()..];
+        let mut header = fuse_direntplus::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&second[..mem::size_of::<fuse_direntplus>()]);
+        assert_eq!(
+            (header.entry_out.nodeid, header.entry_out.attr.uid),
+            (3, 1001)
+        );
+    }
 
-    macro_rules! small_write {
-        ($n:expr) => {{
-            let mut vec: [MaybeUninit<IoSlice<'_>>; $n] =
-                unsafe { MaybeUninit::uninit().assume_init() };
-            bytes.fill_bytes(&mut FillWriteBytes {
-                vec: &mut vec[..],
-                offset: 0,
-            });
-            let vec = unsafe { slice_assume_init_ref(&vec[..]) };
+    #[test]
+    fn lookup_audit() {
+        let mut config = KernelConfig::default();
+        config.lookup_audit(true).readdirplus(true);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        assert!(session.lookup_counts().unwrap().is_empty());
+
+        for _ in 0..2 {
+            kernel
+                .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = EntryOut::default();
+            out.ino(2);
+            out.attr().ino(2);
+            req.reply(out).unwrap();
+        }
 
-            written = writer.write_vectored(vec)?;
-        }};
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_READDIRPLUS as u32,
+                1,
+                fuse_read_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let mut payload = crate::audit::tests::direntplus(1, b".");
+        payload.extend(crate::audit::tests::direntplus(3, b"bar"));
+        req.reply(&payload[..]).unwrap();
+
+        // The replies replaced with errors are not counted.
+        kernel
+            .send_request(fuse_opcode::FUSE_MKDIR as u32, 1, &[])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::EEXIST).unwrap();
+
+        let forget_in = fuse_forget_in { nlookup: 1 };
+        kernel
+            .send_request(fuse_opcode::FUSE_FORGET as u32, 3, forget_in.as_bytes())
+            .unwrap();
+        let _ = session.next_request().unwrap().unwrap();
+
+        let counts = session.lookup_counts().unwrap();
+        assert_eq!(counts.iter().collect::<Vec<_>>(), [(2, 2)]);
+        assert_eq!(
+            counts.diff(vec![(2, 1)]),
+            [crate::LookupDiscrepancy {
+                ino: 2,
+                expected: 2,
+                actual: 1
+            }]
+        );
     }
 
-    match count {
-        // Skip writing.
-        0 => return Ok(()),
+    #[test]
+    fn serialize_per_inode() {
+        let mut config = KernelConfig::default();
+        config.serialize_per_inode(true);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        let write_in = fuse_write_in {
+            size: 3,
+            ..Default::default()
+        };
+        let mut arg = write_in.as_bytes().to_vec();
+        arg.extend_from_slice(b"foo");
+        let write_unique = kernel
+            .send_request(fuse_opcode::FUSE_WRITE as u32, 2, &arg[..])
+            .unwrap();
+        let setattr_in = fuse_setattr_in {
+            valid: FATTR_SIZE,
+            size: 0,
+            ..Default::default()
+        };
+        kernel
+            .send_request(fuse_opcode::FUSE_SETATTR as u32, 2, setattr_in.as_bytes())
+            .unwrap();
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                3,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+
+        let write = session.next_request().unwrap().unwrap();
+        let setattr = session.next_request().unwrap().unwrap();
+        let getattr = session.next_request().unwrap().unwrap();
+
+        // The truncation is dispatched before the write.
+        let log = Arc::new(Mutex::new(vec![]));
+        let truncate = std::thread::spawn({
+            let log = log.clone();
+            move || {
+                match setattr.operation().unwrap() {
+                    Operation::Setattr(op) => assert_eq!(op.size(), Some(0)),
+                    _ => unreachable!(),
+                }
+                log.lock().unwrap().push("setattr");
+                setattr.reply(AttrOut::default()).unwrap();
+            }
+        });
 
-        // Avoid heap allocation if count is small.
-        1 => small_write!(1),
-        2 => small_write!(2),
-        3 => small_write!(3),
-        4 => small_write!(4),
+        // The requests on the other inodes are not blocked.
+        assert!(matches!(getattr.operation(), Ok(Operation::Getattr(..))));
+        drop(getattr);
 
-        count => {
-            let mut vec: Vec<IoSlice<'_>> = Vec::with_capacity(count);
-            unsafe {
-                let dst = std::slice::from_raw_parts_mut(
-                    vec.as_mut_ptr().cast(), //
-                    count,
-                );
-                bytes.fill_bytes(&mut FillWriteBytes {
-                    vec: dst,
-                    offset: 0,
-                });
-                vec.set_len(count);
+        std::thread::sleep(Duration::from_millis(10));
+        assert!(log.lock().unwrap().is_empty());
+        assert!(matches!(write.operation(), Ok(Operation::Write(..))));
+        log.lock().unwrap().push("write");
+        write.reply(WriteOut::default()).unwrap();
+        truncate.join().unwrap();
+
+        assert_eq!(*log.lock().unwrap(), ["write", "setattr"]);
+        assert_eq!(kernel.recv_reply().unwrap().unique(), write_unique);
+    }
+
+    #[test]
+    fn reject_stale_inodes() {
+        for &tracking in &[InodeTracking::Forgotten, InodeTracking::Live] {
+            let mut config = KernelConfig::default();
+            config.reject_stale_inodes(tracking);
+            config.live_inode(10);
+            let (session, kernel) = crate::testing::session(config).unwrap();
+
+            kernel
+                .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            let mut out = EntryOut::default();
+            out.ino(2);
+            out.attr().ino(2);
+            req.reply(out).unwrap();
+            let _ = kernel.recv_reply().unwrap();
+
+            let forget_in = fuse_forget_in { nlookup: 1 };
+            kernel
+                .send_request(fuse_opcode::FUSE_FORGET as u32, 2, forget_in.as_bytes())
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            assert_eq!(req.header.opcode, fuse_opcode::FUSE_FORGET as u32);
+
+            // The handler does not see the request for the forgotten inode.
+            let stale = kernel
+                .send_request(fuse_opcode::FUSE_GETATTR as u32, 2, &[])
+                .unwrap();
+            // The inode never looked up is rejected only by the full tracking.
+            let unknown = kernel
+                .send_request(fuse_opcode::FUSE_GETATTR as u32, 3, &[])
+                .unwrap();
+            for &ino in &[10, 1] {
+                kernel
+                    .send_request(fuse_opcode::FUSE_GETATTR as u32, ino, &[])
+                    .unwrap();
+            }
+
+            if tracking == InodeTracking::Forgotten {
+                let req = session.next_request().unwrap().unwrap();
+                assert_eq!(req.header.nodeid, 3);
+            }
+            for &ino in &[10, 1] {
+                let req = session.next_request().unwrap().unwrap();
+                assert_eq!(req.header.nodeid, ino);
             }
 
-            written = writer.write_vectored(&*vec)?;
+            let reply = kernel.recv_reply().unwrap();
+            assert_eq!((reply.unique(), reply.error()), (stale, -libc::ESTALE));
+            if tracking == InodeTracking::Live {
+                let reply = kernel.recv_reply().unwrap();
+                assert_eq!((reply.unique(), reply.error()), (unknown, -libc::ESTALE));
+            }
+            assert_eq!(
+                session.stale_requests(),
+                if tracking == InodeTracking::Live {
+                    2
+                } else {
+                    1
+                }
+            );
         }
     }
 
-    if written < size {
-        return Err(io::Error::new(
-            io::ErrorKind::Other,
-            "written data is too short",
-        ));
-    }
+    #[test]
+    fn validate_config() {
+        fn config_error(config: &KernelConfig) -> ConfigError {
+            let err = config.validate().unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+            err.get_ref()
+                .and_then(|err| err.downcast_ref::<ConfigError>())
+                .expect("not a ConfigError")
+                .clone()
+        }
 
-    Ok(())
-}
+        let mut config = KernelConfig::default();
+        config
+            .max_background(16)
+            .congestion_threshold(12)
+            .opcode_filter(|_| false)
+            .denied_opcode_errno(libc::EPERM)
+            .deadline(OpcodeClass::Metadata, Duration::from_secs(1))
+            .deadline_errno(libc::EIO);
+        config.validate().unwrap();
+
+        config.max_background(8);
+        assert_eq!(
+            config_error(&config),
+            ConfigError::Conflict("congestion_threshold", "max_background")
+        );
+        config.max_background(16);
 
-struct FillWriteBytes<'a, 'vec> {
-    vec: &'vec mut [MaybeUninit<IoSlice<'a>>],
-    offset: usize,
-}
+        config.deadline_errno(0);
+        assert_eq!(
+            config_error(&config),
+            ConfigError::InvalidErrno("deadline_errno", 0)
+        );
 
-impl<'a, 'vec> FillBytes<'a> for FillWriteBytes<'a, 'vec> {
-    fn put(&mut self, chunk: &'a [u8]) {
-        self.vec[self.offset] = MaybeUninit::new(IoSlice::new(chunk));
-        self.offset += 1;
+        let mut config = KernelConfig::default();
+        config.denied_opcode_errno(libc::EPERM);
+        assert_eq!(
+            config_error(&config),
+            ConfigError::Requires("denied_opcode_errno", "opcode_filter")
+        );
+
+        // The values from the option string are checked here, instead of panicking.
+        let mut config = KernelConfig::default();
+        config.mount_option("blksize=1000");
+        assert_eq!(
+            config_error(&config),
+            ConfigError::InvalidValue("blksize", 1000)
+        );
+
+        // The session is not started with the invalid configuration.
+        let mut config = KernelConfig::default();
+        config.live_inode(10);
+        let err = crate::testing::session(config).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "`live_inode` requires `reject_stale_inodes`"
+        );
     }
-}
 
-// FIXME: replace with stabilized MaybeUninit::slice_assume_init_ref.
-#[inline(always)]
-unsafe fn slice_assume_init_ref<T>(slice: &[MaybeUninit<T>]) -> &[T] {
-    #[allow(unused_unsafe)]
-    unsafe {
-        &*(slice as *const [MaybeUninit<T>] as *const [T])
+    #[test]
+    fn copy_to_unknown_inode() {
+        let mut config = KernelConfig::default();
+        config.reject_stale_inodes(InodeTracking::Live);
+        config.live_inode(10);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        // The inode 7 is unknown to the session, e.g. of another mount.
+        let mut copy_in = fuse_copy_file_range_in {
+            nodeid_out: 7,
+            len: 4096,
+            ..Default::default()
+        };
+        let unknown = kernel
+            .send_request(
+                fuse_opcode::FUSE_COPY_FILE_RANGE as u32,
+                1,
+                copy_in.as_bytes(),
+            )
+            .unwrap();
+        copy_in.nodeid_out = 10;
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_COPY_FILE_RANGE as u32,
+                1,
+                copy_in.as_bytes(),
+            )
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::CopyFileRange(op) => assert_eq!(op.ino_out(), 10),
+            _ => panic!("unexpected operation"),
+        }
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!((reply.unique(), reply.error()), (unknown, -libc::EXDEV));
+        assert_eq!(session.stale_requests(), 1);
     }
-}
 
-#[inline]
-fn pagesize() -> usize {
-    unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
-}
+    #[test]
+    fn buffer_size_fits_max_write() {
+        let mut config = KernelConfig::default();
+        config.max_write(128 * 1024);
+        let (session, _kernel) = crate::testing::session(config).unwrap();
+        assert_eq!(session.buffer_size(), BUFFER_HEADER_SIZE + 128 * 1024);
+    }
 
-#[inline]
-const fn default_init_out() -> fuse_init_out {
-    fuse_init_out {
-        major: FUSE_KERNEL_VERSION,
-        minor: FUSE_KERNEL_MINOR_VERSION,
-        max_readahead: u32::MAX,
-        flags: DEFAULT_INIT_FLAGS,
-        max_background: 0,
-        congestion_threshold: 0,
-        max_write: DEFAULT_MAX_WRITE,
-        time_gran: 1,
-        max_pages: 0,
-        padding: 0,
-        unused: [0; 8],
+    #[test]
+    fn atomic_o_trunc_negotiation() {
+        for &enabled in &[true, false] {
+            let mut config = KernelConfig::default();
+            config.atomic_o_trunc(enabled);
+            let (session, kernel) = crate::testing::session(config).unwrap();
+            assert_eq!(session.atomic_o_trunc(), enabled);
+            assert_eq!(session.granted().contains(FUSE_ATOMIC_O_TRUNC), enabled);
+
+            let open_in = fuse_open_in {
+                flags: (libc::O_WRONLY | libc::O_TRUNC) as u32,
+                ..Default::default()
+            };
+            kernel
+                .send_request(fuse_opcode::FUSE_OPEN as u32, 2, open_in.as_bytes())
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            match req.operation().unwrap() {
+                Operation::Open(op) => assert_ne!(op.flags() as i32 & libc::O_TRUNC, 0),
+                _ => panic!("incorrect operation is returned"),
+            }
+        }
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
-    use std::mem;
+    #[test]
+    fn capability_flags_display() {
+        let flags = CapabilityFlags::from_bits(FUSE_ASYNC_READ | FUSE_POSIX_ACL | 1 << 31);
+        assert_eq!(flags.to_string(), "ASYNC_READ | POSIX_ACL | 0x80000000");
+        assert_eq!(
+            format!("{:?}", flags),
+            "CapabilityFlags(ASYNC_READ | POSIX_ACL | 0x80000000)"
+        );
+        assert_eq!(CapabilityFlags::from_bits(0).to_string(), "(empty)");
+    }
 
     #[test]
-    fn init_default() {
-        let input_len = mem::size_of::<fuse_in_header>() + mem::size_of::<fuse_init_in>();
-        let in_header = fuse_in_header {
-            len: input_len as u32,
-            opcode: fuse_opcode::FUSE_INIT as u32,
-            unique: 2,
-            nodeid: 0,
+    fn read_request_message() {
+        let header = fuse_in_header {
+            len: (mem::size_of::<fuse_in_header>() + 4) as u32,
+            opcode: fuse_opcode::FUSE_LOOKUP as u32,
+            unique: 42,
+            nodeid: 1,
             uid: 100,
             gid: 100,
             pid: 12,
+            total_extlen: 0,
             padding: 0,
         };
-        let init_in = fuse_init_in {
-            major: 7,
-            minor: 23,
-            max_readahead: 40,
-            flags: INIT_FLAGS_MASK
-                | FUSE_MAX_PAGES
-                | FUSE_NO_OPEN_SUPPORT
-                | FUSE_NO_OPENDIR_SUPPORT,
+        let mut input = Vec::new();
+        input.extend_from_slice(header.as_bytes());
+        input.extend_from_slice(b"foo\0");
+
+        let (header, arg) = match read_request(&input[..], &ReceiveBuffer::new(BUFFER_HEADER_SIZE))
+        {
+            Ok(Received::Request(header, arg)) => (header, arg),
+            Ok(Received::Closed(..)) => panic!("unexpected closed connection"),
+            Err(err) => panic!("failed to read a request: {}", err),
         };
+        assert_eq!(header.unique, 42);
+        assert_eq!(header.nodeid, 1);
+        assert_eq!(arg, b"foo\0");
+    }
 
-        let mut input = Vec::with_capacity(input_len);
-        input.extend_from_slice(in_header.as_bytes());
-        input.extend_from_slice(init_in.as_bytes());
-        assert_eq!(input.len(), input_len);
+    #[test]
+    fn read_request_too_short() {
+        let input = [0u8; 8];
+        let res = read_request(&input[..], &ReceiveBuffer::new(BUFFER_HEADER_SIZE));
+        assert!(matches!(res, Err(err) if err.kind() == io::ErrorKind::InvalidData));
+    }
 
-        let mut output = Vec::<u8>::new();
+    struct ErrorReader(i32);
 
-        let mut init_out = default_init_out();
-        init_session(&mut init_out, &input[..], &mut output).expect("initialization failed");
+    impl io::Read for ErrorReader {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::Error::from_raw_os_error(self.0))
+        }
+    }
 
-        let expected_max_pages = (DEFAULT_MAX_WRITE / (pagesize() as u32)) as u16;
+    #[test]
+    fn read_request_closed() {
+        let res = read_request(
+            ErrorReader(libc::ENODEV),
+            &ReceiveBuffer::new(BUFFER_HEADER_SIZE),
+        );
+        assert!(matches!(
+            res,
+            Ok(Received::Closed(ConnectionClosed::Unmounted))
+        ));
+    }
 
-        assert_eq!(init_out.major, 7);
-        assert_eq!(init_out.minor, 23);
-        assert_eq!(init_out.max_readahead, 40);
-        assert_eq!(init_out.max_background, 0);
-        assert_eq!(init_out.congestion_threshold, 0);
-        assert_eq!(init_out.max_write, DEFAULT_MAX_WRITE);
-        assert_eq!(init_out.max_pages, expected_max_pages);
-        assert_eq!(init_out.time_gran, 1);
-        assert!(init_out.flags & FUSE_NO_OPEN_SUPPORT != 0);
-        assert!(init_out.flags & FUSE_NO_OPENDIR_SUPPORT != 0);
+    #[test]
+    fn read_request_aborted() {
+        let res = read_request(
+            ErrorReader(libc::ECONNABORTED),
+            &ReceiveBuffer::new(BUFFER_HEADER_SIZE),
+        );
+        assert!(matches!(
+            res,
+            Ok(Received::Closed(ConnectionClosed::Aborted))
+        ));
+    }
 
-        let output_len = mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_init_out>();
-        let out_header = fuse_out_header {
-            len: output_len as u32,
-            error: 0,
-            unique: 2,
-        };
-        let init_out = fuse_init_out {
-            major: 7,
-            minor: 23,
-            max_readahead: 40,
-            flags: DEFAULT_INIT_FLAGS | FUSE_MAX_PAGES | FUSE_BIG_WRITES,
-            max_background: 0,
-            congestion_threshold: 0,
-            max_write: DEFAULT_MAX_WRITE,
-            time_gran: 1,
-            max_pages: expected_max_pages,
-            padding: 0,
-            unused: [0; 8],
+    #[test]
+    fn close_after_reply_failures() {
+        let mut config = KernelConfig::default();
+        config.max_reply_failures(3);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        let getattr_in = fuse_getattr_in::default();
+        let reply = || {
+            kernel
+                .send_request(FUSE_GETATTR, 1, getattr_in.as_bytes())
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            req.reply_error(libc::ENOSYS)
         };
 
-        let mut expected = Vec::with_capacity(output_len);
-        expected.extend_from_slice(out_header.as_bytes());
-        expected.extend_from_slice(init_out.as_bytes());
-        assert_eq!(output.len(), output_len);
+        // Two replies are written, and then the writer starts failing.
+        for _ in 0..2 {
+            reply().unwrap();
+            kernel.recv_reply().unwrap();
+        }
+        assert_eq!(
+            unsafe { libc::shutdown(session.as_raw_fd(), libc::SHUT_WR) },
+            0
+        );
+        for _ in 0..2 {
+            assert_eq!(reply().unwrap_err().raw_os_error(), Some(libc::EPIPE));
+        }
+        assert_eq!(session.closed_reason(), None);
+        assert!(reply().is_err());
+        assert_eq!(session.closed_reason(), Some(ConnectionClosed::ReplyFailed));
+        assert_eq!(session.failed_replies().len(), 3);
+
+        // The requests still arriving are no longer received.
+        kernel
+            .send_request(FUSE_GETATTR, 1, getattr_in.as_bytes())
+            .unwrap();
+        for res in [session.next_request(), session.try_next_request()].iter() {
+            let err = match res {
+                Err(err) => err,
+                Ok(..) => panic!("the request should not be received"),
+            };
+            assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+            let reason = err.get_ref().unwrap().downcast_ref::<ConnectionClosed>();
+            assert_eq!(reason, Some(&ConnectionClosed::ReplyFailed));
+        }
+    }
 
-        assert_eq!(expected[0..4], output[0..4], "out_header.len");
-        assert_eq!(expected[4..8], output[4..8], "out_header.error");
-        assert_eq!(expected[8..16], output[8..16], "out_header.unique");
+    #[test]
+    fn read_request_would_block() {
+        let res = read_request(
+            ErrorReader(libc::EAGAIN),
+            &ReceiveBuffer::new(BUFFER_HEADER_SIZE),
+        );
+        assert!(matches!(res, Err(err) if err.kind() == io::ErrorKind::WouldBlock));
+    }
 
-        let expected = &expected[mem::size_of::<fuse_out_header>()..];
-        let output = &output[mem::size_of::<fuse_out_header>()..];
-        assert_eq!(expected[0..4], output[0..4], "init_out.major");
-        assert_eq!(expected[4..8], output[4..8], "init_out.minor");
-        assert_eq!(expected[8..12], output[8..12], "init_out.max_readahead");
-        assert_eq!(expected[12..16], output[12..16], "init_out.flags");
-        assert_eq!(expected[16..18], output[16..18], "init_out.max_background");
+    /// A reader failing with `EINVAL` until the buffer has `required` bytes,
+    /// as the FUSE device does.
+    struct ScriptedReader {
+        required: usize,
+        message: Vec<u8>,
+        attempts: Vec<usize>,
+    }
+
+    impl io::Read for ScriptedReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.read_vectored(&mut [IoSliceMut::new(buf)])
+        }
+
+        fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+            let len = bufs.iter().map(|buf| buf.len()).sum();
+            self.attempts.push(len);
+            if len < self.required {
+                return Err(io::Error::from_raw_os_error(libc::EINVAL));
+            }
+            (&self.message[..]).read_vectored(bufs)
+        }
+    }
+
+    #[test]
+    fn read_request_grows_buffer() {
+        let message = crate::testing::encode_raw_request(
+            fuse_opcode::FUSE_WRITE as u32,
+            2,
+            &vec![0xaa; BUFFER_HEADER_SIZE + 100],
+        );
+        let mut reader = ScriptedReader {
+            required: message.len(),
+            message,
+            attempts: vec![],
+        };
+        let buffer = ReceiveBuffer::new(BUFFER_HEADER_SIZE);
+        match read_request(&mut reader, &buffer).unwrap() {
+            Received::Request(header, arg) => {
+                assert_eq!(header.opcode, fuse_opcode::FUSE_WRITE as u32);
+                assert_eq!(arg.len(), BUFFER_HEADER_SIZE + 100);
+            }
+            Received::Closed(..) => panic!("unexpected closed connection"),
+        }
         assert_eq!(
-            expected[18..20],
-            output[18..20],
-            "init_out.congestion_threshold"
+            reader.attempts,
+            [BUFFER_HEADER_SIZE, 2 * BUFFER_HEADER_SIZE]
         );
-        assert_eq!(expected[20..24], output[20..24], "init_out.max_write");
-        assert_eq!(expected[24..28], output[24..28], "init_out.time_gran");
-        assert_eq!(expected[28..30], output[28..30], "init_out.max_pages");
-        assert!(
-            output[30..30 + 2 + 4 * 8].iter().all(|&b| b == 0x00),
-            "init_out.paddings"
+
+        // The grown buffer is used for the subsequent messages.
+        assert_eq!(buffer.bufsize(), 2 * BUFFER_HEADER_SIZE);
+        reader.attempts.clear();
+        read_request(&mut reader, &buffer).unwrap();
+        assert_eq!(reader.attempts, [2 * BUFFER_HEADER_SIZE]);
+    }
+
+    #[test]
+    fn read_request_gives_up_on_oversized_message() {
+        // The retry is attempted only once.
+        let mut reader = ScriptedReader {
+            required: 8 * BUFFER_HEADER_SIZE,
+            message: vec![],
+            attempts: vec![],
+        };
+        let buffer = ReceiveBuffer::new(BUFFER_HEADER_SIZE + 1024);
+        let err = read_request(&mut reader, &buffer).err().unwrap();
+        assert_eq!(
+            reader.attempts,
+            [BUFFER_HEADER_SIZE + 1024, 2 * BUFFER_HEADER_SIZE + 2048]
         );
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let message = err.to_string();
+        assert!(message.contains("max_write = 1024"), "{}", message);
+        assert!(message.contains("max_pages"), "{}", message);
+
+        // The buffer is not grown beyond the upper bound.
+        let mut reader = ScriptedReader {
+            required: usize::MAX,
+            message: vec![],
+            attempts: vec![],
+        };
+        let buffer = ReceiveBuffer::new(MAX_RECEIVE_BUFFER_SIZE);
+        assert!(read_request(&mut reader, &buffer).is_err());
+        assert_eq!(reader.attempts.len(), 1);
+    }
+
+    #[test]
+    fn forward_unknown_opcodes() {
+        let mut config = KernelConfig::default();
+        config.unknown_opcode_eopnotsupp(64);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+
+        let mut replies = vec![];
+        for &opcode in &[63, 64] {
+            let unique = kernel.send_request(opcode, 2, b"newer arg").unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            assert!(matches!(req.operation().unwrap(), Operation::Unknown));
+            assert_eq!(req.opcode(), None);
+            assert_eq!((req.raw_opcode(), req.ino()), (opcode, 2));
+            assert_eq!(req.raw_arg(), b"newer arg");
+            req.reply_error(libc::ENOSYS).unwrap();
+
+            let reply = kernel.recv_reply().unwrap();
+            assert_eq!(reply.unique(), unique);
+            replies.push(reply.error());
+        }
+        // The ENOSYS to the opcodes below the threshold is kept as it is.
+        assert_eq!(replies, [-libc::ENOSYS, -libc::EOPNOTSUPP]);
+
+        // The known opcodes are not affected.
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                1,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(req.opcode(), Some(Opcode::Getattr));
+        req.reply_error(libc::ENOSYS).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
+    }
+
+    #[test]
+    fn read_raw_request() {
+        let message = crate::testing::encode_raw_request(1000, 3, b"payload");
+        let buffer = ReceiveBuffer::new(BUFFER_HEADER_SIZE);
+        match read_request(&message[..], &buffer).unwrap() {
+            Received::Request(header, arg) => {
+                assert_eq!((header.opcode, header.nodeid, header.unique), (1000, 3, 1));
+                assert_eq!(header.len as usize, message.len());
+                assert_eq!(arg, b"payload");
+            }
+            Received::Closed(..) => panic!("unexpected closed connection"),
+        }
+    }
+
+    #[test]
+    fn receive_buffer_is_reused_for_small_messages() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let (small, large) = session.received_messages();
+
+        let mut long_name = vec![b'x'; 255];
+        long_name.push(0);
+        for name in [&long_name[..], b"foo\0"].iter() {
+            kernel
+                .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, name)
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            match req.operation().unwrap() {
+                Operation::Lookup(op) => assert_eq!(op.name().as_bytes(), &name[..name.len() - 1]),
+                _ => panic!("incorrect operation is returned"),
+            }
+            // The leftover of the former message is not visible.
+            assert_eq!(req.arg.len(), name.len());
+            assert!(req.arg.capacity() < SMALL_REQUEST_SIZE);
+        }
+
+        let data = vec![0xaa; 2 * SMALL_REQUEST_SIZE];
+        let write_in = fuse_write_in {
+            size: data.len() as u32,
+            ..Default::default()
+        };
+        let mut arg = write_in.as_bytes().to_vec();
+        arg.extend_from_slice(&data);
+        kernel
+            .send_request(fuse_opcode::FUSE_WRITE as u32, 2, &arg)
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        match req.operation().unwrap() {
+            Operation::Write(op, mut reader) => {
+                assert_eq!(op.size() as usize, data.len());
+                let mut received = vec![];
+                io::Read::read_to_end(&mut reader, &mut received).unwrap();
+                assert_eq!(received, data);
+            }
+            _ => panic!("incorrect operation is returned"),
+        }
+
+        assert_eq!(session.received_messages(), (small + 2, large + 1));
     }
 
     #[inline]
@@ -1228,6 +6223,41 @@ mod tests {
         ($($b:expr),*$(,)?) => ( *bytes(&[$($b),*]) );
     }
 
+    #[test]
+    fn clamp_init_out_to_mount_options() {
+        let mut config = KernelConfig::default();
+        config.max_readahead(128 * 1024);
+        clamp_init_out(&mut config.init_out, &config.mountopts);
+        assert_eq!(config.init_out.max_readahead, 128 * 1024);
+
+        config.mount_option("max_read=65536,blksize=4096");
+        assert_eq!(config.mountopts.max_read, Some(65536));
+        assert_eq!(config.mountopts.blksize, Some(4096));
+        assert!(config.mountopts.options.is_empty());
+        clamp_init_out(&mut config.init_out, &config.mountopts);
+        assert_eq!(config.init_out.max_readahead, 65536);
+
+        let (session, _kernel) = crate::testing::session(config).unwrap();
+        assert_eq!(session.max_read(), Some(65536));
+        assert_eq!(session.blksize(), Some(4096));
+    }
+
+    #[test]
+    fn fsname_and_subtype_options() {
+        let mut config = KernelConfig::default();
+        config.mount_option("fsname=myfsd,subtype=backup,allow_other");
+        assert_eq!(config.mountopts.fsname.as_deref(), Some("myfsd"));
+        assert_eq!(config.mountopts.subtype.as_deref(), Some("backup"));
+        assert_eq!(config.mountopts.options, ["allow_other"]);
+
+        config.fsname("host:/a,b");
+        assert_eq!(config.mountopts.fsname.as_deref(), Some("host:/a,b"));
+
+        // The session over the mock kernel is not mounted.
+        let (session, _kernel) = crate::testing::session(config).unwrap();
+        assert_eq!(session.mount_description(), None);
+    }
+
     #[test]
     fn send_msg_empty() {
         let mut buf = vec![0u8; 0];
@@ -1255,6 +6285,137 @@ mod tests {
         assert_eq!(buf[16..], b![0x68, 0x65, 0x6c, 0x6c, 0x6f], "payload");
     }
 
+    struct InterruptedOnce {
+        buf: Vec<u8>,
+        interrupted: bool,
+    }
+
+    impl io::Write for InterruptedOnce {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            if !self.interrupted {
+                self.interrupted = true;
+                return Err(io::Error::from_raw_os_error(libc::EINTR));
+            }
+            self.buf.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_msg_retry_interrupted() {
+        let mut writer = InterruptedOnce {
+            buf: vec![],
+            interrupted: false,
+        };
+        write_bytes(&mut writer, Reply::new(42, 0, "hello")).unwrap();
+        assert!(writer.interrupted);
+        assert_eq!(writer.buf[0..4], b![0x15, 0x00, 0x00, 0x00], "header.len");
+        assert_eq!(writer.buf[16..], *b"hello", "payload");
+    }
+
+    struct InterruptedTwice {
+        buf: Vec<u8>,
+        attempts: usize,
+    }
+
+    impl io::Write for InterruptedTwice {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            self.attempts += 1;
+            if self.attempts <= 2 {
+                return Err(io::Error::from_raw_os_error(libc::EINTR));
+            }
+            self.buf.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn send_msg_returns_would_block() {
+        struct AlwaysWouldBlock {
+            attempts: u32,
+        }
+
+        impl io::Write for AlwaysWouldBlock {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> {
+                self.attempts += 1;
+                Err(io::Error::from_raw_os_error(libc::EAGAIN))
+            }
+
+            fn write_vectored(&mut self, _: &[IoSlice<'_>]) -> io::Result<usize> {
+                self.attempts += 1;
+                Err(io::Error::from_raw_os_error(libc::EAGAIN))
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        // The caller waits for the writer to be ready, instead of the thread
+        // sleeping or spinning here.
+        let mut writer = AlwaysWouldBlock { attempts: 0 };
+        let err = write_bytes(&mut writer, Reply::new(42, 0, "hello")).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert_eq!(writer.attempts, 1);
+    }
+
+    #[test]
+    fn send_msg_retry_without_refill() {
+        struct Counted<T> {
+            inner: T,
+            fills: Cell<usize>,
+        }
+
+        impl<T: Bytes> Bytes for Counted<T> {
+            fn size(&self) -> usize {
+                self.inner.size()
+            }
+
+            fn count(&self) -> usize {
+                self.inner.count()
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                self.fills.set(self.fills.get() + 1);
+                self.inner.fill_bytes(dst);
+            }
+        }
+
+        // The entry and open parameters of CREATE, and the chunked data
+        // payload that does not fit in the inline slice array.
+        let create = (EntryOut::default(), OpenOut::default());
+        let chunks: &[&[u8]] = &[b"a", b"b", b"c", b"d"];
+        for count in [3, 7].iter() {
+            let mut writer = InterruptedTwice {
+                buf: vec![],
+                attempts: 0,
+            };
+            let counted = Counted {
+                inner: (&create, if *count > 3 { chunks } else { &[] }),
+                fills: Cell::new(0),
+            };
+            assert_eq!(Reply::new(42, 0, &counted).count(), *count);
+            write_vectored_bytes(&mut writer, Reply::new(42, 0, &counted)).unwrap();
+            assert_eq!(writer.attempts, 3);
+            assert_eq!(counted.fills.get(), 1, "the slices are not rebuilt");
+            assert_eq!(writer.buf.len(), Reply::new(42, 0, &counted).size());
+        }
+    }
+
     #[test]
     fn send_msg_chunked_data() {
         let payload: &[&[u8]] = &[
@@ -1274,4 +6435,177 @@ mod tests {
         );
         assert_eq!(buf[16..], *b"hello, this is a message.", "payload");
     }
+
+    #[test]
+    fn send_msg_small_and_vectored_paths() {
+        fn check<T: Bytes>(bytes: T) {
+            let mut small = vec![];
+            write_small_bytes(&mut small, &bytes).unwrap();
+            let mut vectored = vec![];
+            write_vectored_bytes(&mut vectored, &bytes).unwrap();
+            assert_eq!(small, vectored);
+            assert_eq!(small.len(), bytes.size());
+        }
+
+        let chunks: &[&[u8]] = &[b"hello, ", b"this ", b"is a ", b"message."];
+        check(Reply::new(42, -libc::ENOENT, ()));
+        check(Reply::new(42, 0, "hello"));
+        check(Reply::new(42, 0, chunks));
+        check(Reply::new(42, 0, (EntryOut::default(), OpenOut::default())));
+
+        // The large messages are written with the vectored write.
+        let payload = vec![0xaa; SMALL_MESSAGE_SIZE];
+        let mut writer = InterruptedTwice {
+            buf: vec![],
+            attempts: 0,
+        };
+        write_bytes(&mut writer, Reply::new(42, 0, &payload[..])).unwrap();
+        assert_eq!(writer.buf.len(), 16 + SMALL_MESSAGE_SIZE);
+        assert_eq!(writer.buf[16..], payload[..]);
+    }
+
+    #[test]
+    fn send_msg_size_mismatch() {
+        struct Oversized(usize);
+        impl Bytes for Oversized {
+            fn size(&self) -> usize {
+                16
+            }
+            fn count(&self) -> usize {
+                1
+            }
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(&[0xaa; SMALL_MESSAGE_SIZE * 2][..self.0]);
+            }
+        }
+
+        for &len in &[17, SMALL_MESSAGE_SIZE * 2] {
+            let mut buf = vec![];
+            let err = write_small_bytes(&mut buf, Oversized(len)).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(buf.is_empty());
+        }
+    }
+
+    struct Faulty {
+        buf: Vec<u8>,
+        errno: i32,
+        failures: usize,
+    }
+
+    impl io::Write for Faulty {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.write_vectored(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(io::Error::from_raw_os_error(self.errno));
+            }
+            self.buf.write_vectored(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn aligned_reply_without_copy() {
+        struct Recorder(Vec<(usize, usize)>);
+        impl io::Write for Recorder {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+                self.write_vectored(&[IoSlice::new(buf)])
+            }
+
+            fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+                self.0
+                    .extend(bufs.iter().map(|buf| (buf.as_ptr() as usize, buf.len())));
+                Ok(bufs.iter().map(|buf| buf.len()).sum())
+            }
+
+            fn flush(&mut self) -> io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let mut data = crate::util::AlignedBuf::new(2 * pagesize());
+        data.truncate(pagesize() + 10);
+        let mut recorder = Recorder(vec![]);
+        write_bytes(&mut recorder, Reply::new(1, 0, &data)).unwrap();
+        assert_eq!(
+            recorder.0[1],
+            (data.as_ptr() as usize, pagesize() + 10),
+            "the payload is passed to the writer as it is"
+        );
+        assert_eq!(recorder.0[1].0 % pagesize(), 0);
+    }
+
+    #[test]
+    fn reuse_io_slices() {
+        let cached = || {
+            IO_SLICES.with(|slices| {
+                let vec = slices.take();
+                let capacity = vec.capacity();
+                slices.set(vec);
+                capacity
+            })
+        };
+        let chunks: Vec<Vec<u8>> = (0..8).map(|i| vec![i; 100]).collect();
+        let reply = |chunks: &[Vec<u8>]| {
+            let mut writer = Faulty {
+                buf: vec![],
+                errno: libc::EINTR,
+                failures: 1,
+            };
+            write_bytes(&mut writer, Reply::new(42, 0, chunks)).unwrap();
+            assert_eq!(writer.buf.len(), 16 + 100 * chunks.len());
+            assert_eq!(
+                writer.buf[writer.buf.len() - 100..],
+                chunks[chunks.len() - 1][..]
+            );
+        };
+
+        reply(&chunks);
+        let capacity = cached();
+        assert!(capacity >= 9, "the header and the chunks");
+        reply(&chunks[..6]);
+        reply(&chunks);
+        assert_eq!(cached(), capacity);
+    }
+
+    #[test]
+    fn write_reply_errors() {
+        let payload = vec![0xaa; SMALL_MESSAGE_SIZE];
+        for &data in [&b"hello"[..], &payload[..]].iter() {
+            let aborted = AtomicU64::new(0);
+
+            let mut writer = Faulty {
+                buf: vec![],
+                errno: libc::EINTR,
+                failures: 2,
+            };
+            write_reply(&mut writer, Reply::new(42, 0, data), &aborted).unwrap();
+            assert_eq!(writer.buf[16..], *data);
+
+            let mut writer = Faulty {
+                buf: vec![],
+                errno: libc::ENOENT,
+                failures: usize::MAX,
+            };
+            write_reply(&mut writer, Reply::new(42, 0, data), &aborted).unwrap();
+            assert!(writer.buf.is_empty());
+            assert_eq!(aborted.load(Ordering::Relaxed), 1);
+
+            let mut writer = Faulty {
+                buf: vec![],
+                errno: libc::EIO,
+                failures: usize::MAX,
+            };
+            let err = write_reply(&mut writer, Reply::new(42, 0, data), &aborted).unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::EIO));
+            assert_eq!(aborted.load(Ordering::Relaxed), 1);
+        }
+    }
 }
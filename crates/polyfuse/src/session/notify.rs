@@ -0,0 +1,1180 @@
+//! Notifications to the kernel, enabled by the `notify` feature.
+
+use super::{write_bytes, ConnectionClosed, Session, SessionInner};
+use crate::{
+    bytes::{Bytes, FillBytes},
+    conn::Connection,
+    decoder::Decoder,
+    util::{num, AsNameBytes},
+};
+use polyfuse_kernel::*;
+use std::{
+    cmp,
+    collections::{BTreeMap, HashMap},
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    io, mem,
+    pin::Pin,
+    sync::{atomic::Ordering, mpsc, Arc, Weak},
+    task::{self, Poll},
+};
+use zerocopy::AsBytes;
+/// The reply of `NOTIFY_RETRIEVE` routed to `Notifier::retrieve_range`,
+/// with the unique ID of the notification.
+pub(super) type RetrieveReply = (u64, io::Result<(u64, Vec<u8>)>);
+
+/// The data retrieved from the kernel cache by `Notifier::retrieve_range`.
+#[derive(Debug)]
+pub struct Retrieved {
+    offset: u64,
+    len: u64,
+    data: Vec<u8>,
+}
+
+impl Retrieved {
+    /// Return the starting position of the data.
+    #[inline]
+    pub fn offset(&self) -> u64 {
+        self.offset
+    }
+
+    /// Return the retrieved data, which is contiguous from `offset`.
+    #[inline]
+    pub fn data(&self) -> &[u8] {
+        &self.data[..]
+    }
+
+    /// Take the retrieved data.
+    #[inline]
+    pub fn into_data(self) -> Vec<u8> {
+        self.data
+    }
+
+    /// Return whether the whole of the requested range has been retrieved.
+    #[inline]
+    pub fn is_complete(&self) -> bool {
+        self.data.len() as u64 == self.len
+    }
+}
+
+// ==== Notifier ====
+
+/// A handle to send notifications to the kernel.
+///
+/// The notifier does not keep the connection alive, so the filesystem is
+/// unmounted as soon as the session and its requests are dropped, even if
+/// some background tasks still hold the clones of notifier.
+#[derive(Clone)]
+pub struct Notifier {
+    session: Weak<SessionInner>,
+}
+
+impl Notifier {
+    /// Return whether the session has exited.
+    ///
+    /// Once the session has exited, all of notifications fail immediately
+    /// without sending any message to the kernel.
+    pub fn is_closed(&self) -> bool {
+        match self.session.upgrade() {
+            Some(session) => session.exited(),
+            None => true,
+        }
+    }
+
+    /// Create a future that completes when the session has exited.
+    ///
+    /// The background tasks holding a `Notifier` can use this to stop themselves.
+    pub fn closed(&self) -> Closed {
+        Closed {
+            session: self.session.clone(),
+        }
+    }
+
+    fn ensure_open(&self) -> io::Result<Arc<SessionInner>> {
+        let session = self
+            .session
+            .upgrade()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotConnected, ConnectionClosed::Exited))?;
+        if session.exited() {
+            let reason = session
+                .closed
+                .lock()
+                .unwrap()
+                .unwrap_or(ConnectionClosed::Exited);
+            return Err(io::Error::new(io::ErrorKind::NotConnected, reason));
+        }
+        Ok(session)
+    }
+
+    /// Ask the kernel to resend the pending requests that have not been replied yet.
+    ///
+    /// This is intended to be used after `Session::resume`, to re-deliver the
+    /// requests that the previous process had read but not replied.  The unique
+    /// IDs of the resent requests have the `FUSE_UNIQUE_RESEND` bit.
+    ///
+    /// This notification is supported since ABI 7.40 (Linux 6.9).  If the kernel
+    /// is older, the error `ENOSYS` is returned without sending the notification.
+    pub fn resend(&self) -> io::Result<()> {
+        let session = self.ensure_open()?;
+        if session.init_in.minor < 40 {
+            debug!("FUSE_NOTIFY_RESEND requires ABI 7.40 or later");
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+
+        let header = fuse_out_header {
+            len: mem::size_of::<fuse_out_header>() as u32,
+            error: fuse_notify_code::FUSE_NOTIFY_RESEND as i32,
+            unique: 0,
+        };
+        write_bytes(&session.conn, header.as_bytes())
+    }
+
+    /// Notify the cache invalidation about an inode to the kernel.
+    ///
+    /// Unlike the offsets in requests, `off` and `len` are signed as in the
+    /// kernel: a negative `off` invalidates the attributes only, and a
+    /// non-positive `len` invalidates the page cache until the end of file.
+    pub fn inval_inode(&self, ino: u64, off: i64, len: i64) -> io::Result<()> {
+        let session = self.ensure_open()?;
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_inval_inode_out>(),
+        )
+        .unwrap();
+
+        return write_bytes(
+            &session.conn,
+            InvalInode {
+                header: fuse_out_header {
+                    len: total_len,
+                    error: fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32,
+                    unique: 0,
+                },
+                arg: fuse_notify_inval_inode_out { ino, off, len },
+            },
+        );
+
+        struct InvalInode {
+            header: fuse_out_header,
+            arg: fuse_notify_inval_inode_out,
+        }
+        impl Bytes for InvalInode {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                2
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+            }
+        }
+    }
+
+    /// Notify the cache invalidation about the contents of a directory to the kernel.
+    ///
+    /// This drops the `readdir` results cached by the kernel, which are enabled
+    /// by `OpenOut::cache_dir`, as well as the attributes of the directory.
+    /// The filesystem should call this when the directory is changed outside
+    /// of the kernel.  The individual entries looked up in the directory are
+    /// not affected; use `inval_entry` for them.
+    pub fn inval_dir(&self, ino: u64) -> io::Result<()> {
+        self.inval_inode(ino, 0, 0)
+    }
+
+    /// Notify the invalidation about a directory entry to the kernel.
+    ///
+    /// The name is passed to the kernel as the raw bytes, so either the
+    /// `OsStr` of the operation or the bytes stored by the filesystem can
+    /// be used even if it is not valid UTF-8.
+    pub fn inval_entry<T>(&self, parent: u64, name: T) -> io::Result<()>
+    where
+        T: AsNameBytes,
+    {
+        let session = self.ensure_open()?;
+        let namelen = u32::try_from(name.as_name_bytes().len()).expect("provided name is too long");
+
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>()
+                + mem::size_of::<fuse_notify_inval_entry_out>()
+                + name.as_name_bytes().len()
+                + 1,
+        )
+        .unwrap();
+
+        return write_bytes(
+            &session.conn,
+            InvalEntry {
+                header: fuse_out_header {
+                    len: total_len,
+                    error: fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32,
+                    unique: 0,
+                },
+                arg: fuse_notify_inval_entry_out {
+                    parent,
+                    namelen,
+                    padding: 0,
+                },
+                name,
+            },
+        );
+
+        struct InvalEntry<T>
+        where
+            T: AsNameBytes,
+        {
+            header: fuse_out_header,
+            arg: fuse_notify_inval_entry_out,
+            name: T,
+        }
+        impl<T> Bytes for InvalEntry<T>
+        where
+            T: AsNameBytes,
+        {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                4
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+                dst.put(self.name.as_name_bytes());
+                dst.put(b"\0"); // null terminator
+            }
+        }
+    }
+
+    /// Notify the invalidation about a directory entry to the kernel.
+    ///
+    /// The role of this notification is similar to `notify_inval_entry`.
+    /// Additionally, when the provided `child` inode matches the inode
+    /// in the dentry cache, the inotify will inform the deletion to
+    /// watchers if exists.
+    pub fn delete<T>(&self, parent: u64, child: u64, name: T) -> io::Result<()>
+    where
+        T: AsNameBytes,
+    {
+        let session = self.ensure_open()?;
+        let namelen = u32::try_from(name.as_name_bytes().len()).expect("provided name is too long");
+
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>()
+                + mem::size_of::<fuse_notify_delete_out>()
+                + name.as_name_bytes().len()
+                + 1,
+        )
+        .expect("payload is too long");
+
+        return write_bytes(
+            &session.conn,
+            Delete {
+                header: fuse_out_header {
+                    len: total_len,
+                    error: fuse_notify_code::FUSE_NOTIFY_DELETE as i32,
+                    unique: 0,
+                },
+                arg: fuse_notify_delete_out {
+                    parent,
+                    child,
+                    namelen,
+                    padding: 0,
+                },
+                name,
+            },
+        );
+
+        struct Delete<T>
+        where
+            T: AsNameBytes,
+        {
+            header: fuse_out_header,
+            arg: fuse_notify_delete_out,
+            name: T,
+        }
+        impl<T> Bytes for Delete<T>
+        where
+            T: AsNameBytes,
+        {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                4
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+                dst.put(self.name.as_name_bytes());
+                dst.put(b"\0"); // null terminator
+            }
+        }
+    }
+
+    /// Notify the kernel that an entry has been renamed outside of the kernel.
+    ///
+    /// This sends the following notifications in order, so that no stale
+    /// name of `child` survives in the kernel caches:
+    ///
+    /// 1. `inval_entry` for `old_name` in `parent`,
+    /// 2. `inval_entry` for `new_name` in `new_parent`, which drops the
+    ///    negative entry or the entry of the replaced file,
+    /// 3. `inval_dir` for `parent`, and for `new_parent` if it differs,
+    /// 4. `inval_inode` for the attributes of `child`, whose `ctime` has
+    ///    been changed by the rename.
+    ///
+    /// `ENOENT` from the kernel, which means that the entry or the inode is
+    /// not cached, is ignored in each step.  If the rename has replaced an
+    /// existing file, its removal should be notified with `delete`
+    /// separately.
+    ///
+    /// The kernel processes the entry invalidations while holding the lock
+    /// of the parent directories, which is also held during the requests
+    /// modifying or looking up the entries in them.  This method must not
+    /// be called from the handler of a request on `parent` or `new_parent`
+    /// before replying to it, since the kernel waits for the reply while
+    /// the notification waits for the lock.  Call it from another thread or
+    /// task instead.
+    pub fn invalidate_rename<T, U>(
+        &self,
+        parent: u64,
+        old_name: T,
+        new_parent: u64,
+        new_name: U,
+        child: u64,
+    ) -> io::Result<()>
+    where
+        T: AsNameBytes,
+        U: AsNameBytes,
+    {
+        fn tolerate_uncached(result: io::Result<()>) -> io::Result<()> {
+            match result {
+                Err(err) if err.raw_os_error() == Some(libc::ENOENT) => Ok(()),
+                result => result,
+            }
+        }
+
+        tolerate_uncached(self.inval_entry(parent, old_name))?;
+        tolerate_uncached(self.inval_entry(new_parent, new_name))?;
+        tolerate_uncached(self.inval_dir(parent))?;
+        if new_parent != parent {
+            tolerate_uncached(self.inval_dir(new_parent))?;
+        }
+        tolerate_uncached(self.inval_inode(child, -1, 0))
+    }
+
+    /// Push the data in an inode for updating the kernel cache.
+    ///
+    /// The data is sent in a single notification with a vectored write,
+    /// so any `Bytes` such as `&[IoSlice]` or `Vec<Vec<u8>>` can be passed
+    /// without copying.  Use `store_chunks` for the data larger than
+    /// `max_write`.
+    pub fn store<T>(&self, ino: u64, offset: u64, data: T) -> io::Result<()>
+    where
+        T: Bytes,
+    {
+        let session = self.ensure_open()?;
+        check_file_offset(offset)?;
+
+        let size = u32::try_from(data.size()).expect("provided data is too large");
+
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>()
+                + mem::size_of::<fuse_notify_store_out>()
+                + data.size(),
+        )
+        .expect("payload is too long");
+
+        return write_bytes(
+            &session.conn,
+            Store {
+                header: fuse_out_header {
+                    len: total_len,
+                    error: fuse_notify_code::FUSE_NOTIFY_STORE as i32,
+                    unique: 0,
+                },
+                arg: fuse_notify_store_out {
+                    nodeid: ino,
+                    offset,
+                    size,
+                    padding: 0,
+                },
+                data,
+            },
+        );
+
+        struct Store<T>
+        where
+            T: Bytes,
+        {
+            header: fuse_out_header,
+            arg: fuse_notify_store_out,
+            data: T,
+        }
+        impl<T> Bytes for Store<T>
+        where
+            T: Bytes,
+        {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                2 + self.data.count()
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+                self.data.fill_bytes(dst);
+            }
+        }
+    }
+
+    /// Push the data stored in multiple chunks, such as the buffers of a cache.
+    ///
+    /// The chunks are passed to the kernel as the separate segments of a
+    /// vectored write without being copied into a contiguous buffer.  The
+    /// data is split into the multiple `STORE` notifications so that each
+    /// of them carries at most `max_write` bytes and fits in `UIO_MAXIOV`
+    /// segments; a chunk on the boundary is split as well.  If one of them
+    /// fails, the data before it has already been stored in the kernel.
+    pub fn store_chunks<I>(&self, ino: u64, offset: u64, chunks: I) -> io::Result<()>
+    where
+        I: IntoIterator,
+        I::Item: AsRef<[u8]>,
+    {
+        // UIO_MAXIOV, minus the header and the argument of the notification.
+        const MAX_SEGMENTS: usize = 1024 - 2;
+
+        let session = self.ensure_open()?;
+        let chunks: Vec<I::Item> = chunks.into_iter().collect();
+        let len: usize = chunks.iter().map(|chunk| chunk.as_ref().len()).sum();
+        offset
+            .checked_add(len as u64)
+            .and_then(num::file_offset)
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "the range is out of bounds")
+            })?;
+        let max_size = session.init_out.max_write as usize;
+
+        let mut offset = offset;
+        let mut segments: Vec<&[u8]> = vec![];
+        let mut size = 0;
+        for chunk in &chunks {
+            let mut chunk = chunk.as_ref();
+            while !chunk.is_empty() {
+                let (head, tail) = chunk.split_at(cmp::min(chunk.len(), max_size - size));
+                segments.push(head);
+                size += head.len();
+                chunk = tail;
+                if size == max_size || segments.len() == MAX_SEGMENTS {
+                    self.store(ino, offset, &segments[..])?;
+                    offset += size as u64;
+                    segments.clear();
+                    size = 0;
+                }
+            }
+        }
+        if !segments.is_empty() {
+            self.store(ino, offset, &segments[..])?;
+        }
+        Ok(())
+    }
+
+    /// Retrieve data in an inode from the kernel cache.
+    pub fn retrieve(&self, ino: u64, offset: u64, size: u32) -> io::Result<u64> {
+        let session = self.ensure_open()?;
+        check_file_offset(offset)?;
+
+        // FIXME: choose appropriate memory ordering.
+        let notify_unique = session.notify_unique.fetch_add(1, Ordering::SeqCst);
+        write_retrieve(&session.conn, ino, offset, size, notify_unique)?;
+        Ok(notify_unique)
+    }
+
+    /// Retrieve a range of data in an inode from the kernel cache, blocking
+    /// until all of the data is received.
+    ///
+    /// Since the kernel returns at most `max_write` bytes for each retrieve,
+    /// the range is split into the multiple retrieves, up to four of which
+    /// are in flight at once.  Their replies are consumed by the session
+    /// instead of being returned from `Session::next_request` as
+    /// `Operation::NotifyReply`, so the requests must be received by
+    /// another thread while this method is waiting.
+    ///
+    /// If the kernel does not have some part of the range in the cache,
+    /// the returned data stops at the gap and `Retrieved::is_complete`
+    /// returns `false`.
+    pub fn retrieve_range(&self, ino: u64, offset: u64, len: u64) -> io::Result<Retrieved> {
+        const MAX_RETRIEVES_IN_FLIGHT: usize = 4;
+
+        let session = self.ensure_open()?;
+        check_file_offset(offset)?;
+        let end = offset
+            .checked_add(len)
+            .filter(|&end| num::file_offset(end).is_some())
+            .ok_or_else(|| {
+                io::Error::new(io::ErrorKind::InvalidInput, "the range is out of bounds")
+            })?;
+        let max_chunk = u64::from(session.init_out.max_write);
+
+        let (tx, rx) = mpsc::channel();
+        let mut pending = HashMap::new();
+        let mut chunks = BTreeMap::new();
+        let mut next = offset;
+        let res = 'retrieve: loop {
+            while pending.len() < MAX_RETRIEVES_IN_FLIGHT && next < end {
+                let size = cmp::min(max_chunk, end - next) as u32;
+                // FIXME: choose appropriate memory ordering.
+                let notify_unique = session.notify_unique.fetch_add(1, Ordering::SeqCst);
+                session
+                    .retrievals
+                    .lock()
+                    .unwrap()
+                    .insert(notify_unique, tx.clone());
+                pending.insert(notify_unique, (next, size));
+                if session.exited() {
+                    // The retrieve registered after the session has exited is never routed.
+                    break 'retrieve Err(io::Error::new(
+                        io::ErrorKind::NotConnected,
+                        ConnectionClosed::Exited,
+                    ));
+                }
+                if let Err(err) = write_retrieve(&session.conn, ino, next, size, notify_unique) {
+                    break 'retrieve Err(err);
+                }
+                next += u64::from(size);
+            }
+            if pending.is_empty() {
+                break Ok(());
+            }
+
+            let (notify_unique, reply) = rx.recv().expect("the sender is alive");
+            let (chunk_offset, size) = match pending.remove(&notify_unique) {
+                Some(chunk) => chunk,
+                None => continue,
+            };
+            let (reply_offset, data) = match reply {
+                Ok(reply) => reply,
+                Err(err) => break Err(err),
+            };
+            if reply_offset != chunk_offset || data.len() > size as usize {
+                break Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "unexpected range of retrieved data (offset = {}, size = {}, \
+                         expected offset = {}, size = {})",
+                        reply_offset,
+                        data.len(),
+                        chunk_offset,
+                        size
+                    ),
+                ));
+            }
+            if data.len() < size as usize {
+                // The rest of the range cannot be contiguous.
+                next = end;
+            }
+            chunks.insert(chunk_offset, (size, data));
+        };
+
+        if !pending.is_empty() {
+            let mut retrievals = session.retrievals.lock().unwrap();
+            for notify_unique in pending.keys() {
+                retrievals.remove(notify_unique);
+            }
+        }
+        res?;
+
+        let mut data = Vec::with_capacity(chunks.values().map(|(_, data)| data.len()).sum());
+        for (size, chunk) in chunks.values() {
+            data.extend_from_slice(chunk);
+            if chunk.len() < *size as usize {
+                break;
+            }
+        }
+        Ok(Retrieved { offset, len, data })
+    }
+
+    /// Send I/O readiness to the kernel.
+    pub fn poll_wakeup(&self, kh: u64) -> io::Result<()> {
+        let session = self.ensure_open()?;
+        let total_len = u32::try_from(
+            mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_poll_wakeup_out>(),
+        )
+        .unwrap();
+
+        return write_bytes(
+            &session.conn,
+            PollWakeup {
+                header: fuse_out_header {
+                    len: total_len,
+                    error: fuse_notify_code::FUSE_NOTIFY_POLL as i32,
+                    unique: 0,
+                },
+                arg: fuse_notify_poll_wakeup_out { kh },
+            },
+        );
+
+        struct PollWakeup {
+            header: fuse_out_header,
+            arg: fuse_notify_poll_wakeup_out,
+        }
+        impl Bytes for PollWakeup {
+            fn size(&self) -> usize {
+                self.header.len as usize
+            }
+
+            fn count(&self) -> usize {
+                2
+            }
+
+            fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+                dst.put(self.header.as_bytes());
+                dst.put(self.arg.as_bytes());
+            }
+        }
+    }
+}
+
+/// A future that completes when the session has exited, created by `Notifier::closed`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Closed {
+    session: Weak<SessionInner>,
+}
+
+impl fmt::Debug for Closed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Closed").finish()
+    }
+}
+
+impl Future for Closed {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Self::Output> {
+        let session = match self.session.upgrade() {
+            Some(session) => session,
+            None => return Poll::Ready(()),
+        };
+        if session.exited() {
+            return Poll::Ready(());
+        }
+
+        let mut wakers = session.exit_wakers.lock().unwrap();
+        // Check again since the session may exit before acquiring the lock.
+        if session.exited() {
+            return Poll::Ready(());
+        }
+        if !wakers.iter().any(|waker| waker.will_wake(cx.waker())) {
+            wakers.push(cx.waker().clone());
+        }
+        Poll::Pending
+    }
+}
+
+impl Session {
+    /// Create an instance of `Notifier` corresponding to this session.
+    pub fn notifier(&self) -> Notifier {
+        Notifier {
+            session: Arc::downgrade(&self.inner),
+        }
+    }
+}
+
+impl SessionInner {
+    /// Route the reply to a retrieve issued by `Notifier::retrieve_range`,
+    /// and return whether it has been consumed.
+    pub(super) fn deliver_retrieved(&self, header: &fuse_in_header, arg: &[u8]) -> bool {
+        if header.opcode != fuse_opcode::FUSE_NOTIFY_REPLY as u32 {
+            return false;
+        }
+        let tx = match self.retrievals.lock().unwrap().remove(&header.unique) {
+            Some(tx) => tx,
+            None => return false,
+        };
+        let mut decoder = Decoder::new(arg);
+        let reply = match decoder.fetch::<fuse_notify_retrieve_in>() {
+            Ok(retrieve_in) => {
+                let data = &arg[mem::size_of::<fuse_notify_retrieve_in>()..];
+                let len = cmp::min(retrieve_in.size as usize, data.len());
+                Ok((retrieve_in.offset, data[..len].to_vec()))
+            }
+            Err(..) => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "malformed NOTIFY_REPLY message",
+            )),
+        };
+        let _ = tx.send((header.unique, reply));
+        true
+    }
+
+    /// Fail the retrieves waiting for their replies, on exit of the session.
+    pub(super) fn cancel_retrievals(&self) {
+        let reason = self
+            .closed
+            .lock()
+            .unwrap()
+            .unwrap_or(ConnectionClosed::Exited);
+        for (unique, tx) in self.retrievals.lock().unwrap().drain() {
+            let _ = tx.send((
+                unique,
+                Err(io::Error::new(io::ErrorKind::NotConnected, reason)),
+            ));
+        }
+    }
+}
+
+fn check_file_offset(offset: u64) -> io::Result<()> {
+    num::file_offset(offset).map(drop).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the file offset is out of range",
+        )
+    })
+}
+
+fn write_retrieve(
+    conn: &Connection,
+    ino: u64,
+    offset: u64,
+    size: u32,
+    notify_unique: u64,
+) -> io::Result<()> {
+    let total_len = u32::try_from(
+        mem::size_of::<fuse_out_header>() + mem::size_of::<fuse_notify_retrieve_out>(),
+    )
+    .unwrap();
+
+    return write_bytes(
+        conn,
+        Retrieve {
+            header: fuse_out_header {
+                len: total_len,
+                error: fuse_notify_code::FUSE_NOTIFY_RETRIEVE as i32,
+                unique: 0,
+            },
+            arg: fuse_notify_retrieve_out {
+                nodeid: ino,
+                offset,
+                size,
+                notify_unique,
+                padding: 0,
+            },
+        },
+    );
+
+    struct Retrieve {
+        header: fuse_out_header,
+        arg: fuse_notify_retrieve_out,
+    }
+    impl Bytes for Retrieve {
+        fn size(&self) -> usize {
+            self.header.len as usize
+        }
+
+        fn count(&self) -> usize {
+            2
+        }
+
+        fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+            dst.put(self.header.as_bytes());
+            dst.put(self.arg.as_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        op::Operation,
+        reply::{EntryOut, OpenOut},
+        session::{default_init_out, tests::dup, KernelConfig, SessionState, MIN_MAX_WRITE},
+    };
+    use std::{
+        io::{IoSlice, Read as _},
+        os::unix::ffi::OsStrExt as _,
+        sync::atomic::AtomicBool,
+        task::Waker,
+    };
+    use zerocopy::AsBytes;
+
+    #[test]
+    fn resume_and_resend() {
+        let mut state = SessionState::new(
+            fuse_init_in {
+                major: 7,
+                minor: 31,
+                max_readahead: 4096,
+                flags: 0,
+            },
+            default_init_out(),
+        );
+
+        let (conn, peer) = Connection::pair().unwrap();
+        let session =
+            unsafe { Session::resume(dup(&conn), state.clone(), KernelConfig::default()) }.unwrap();
+        let err = session.notifier().resend().unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+        drop(session);
+
+        state.init_in.minor = 40;
+        let (conn, mut peer2) = Connection::pair().unwrap();
+        let session =
+            unsafe { Session::resume(dup(&conn), state, KernelConfig::default()) }.unwrap();
+        session.notifier().resend().unwrap();
+
+        let mut buf = [0u8; 64];
+        let len = peer2.read(&mut buf[..]).unwrap();
+        assert_eq!(len, mem::size_of::<fuse_out_header>());
+        assert_eq!(buf[0..4], 16u32.to_ne_bytes(), "header.len");
+        assert_eq!(buf[4..8], 7i32.to_ne_bytes(), "header.error");
+        drop(peer);
+    }
+
+    #[test]
+    fn invalidate_rename_sequence() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let notifier = session.notifier();
+
+        let parse = |reply: crate::testing::RawReply| {
+            let payload = reply.payload();
+            let code = reply.error();
+            if code == fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY as i32 {
+                let mut out = fuse_notify_inval_entry_out::default();
+                let (arg, name) = payload.split_at(mem::size_of_val(&out));
+                out.as_bytes_mut().copy_from_slice(arg);
+                let name = &name[..out.namelen as usize];
+                format!("entry({}, {})", out.parent, String::from_utf8_lossy(name))
+            } else if code == fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32 {
+                let mut out = fuse_notify_inval_inode_out::default();
+                out.as_bytes_mut().copy_from_slice(payload);
+                format!("inode({}, {}, {})", out.ino, out.off, out.len)
+            } else {
+                panic!("unexpected notification: {}", code)
+            }
+        };
+
+        notifier
+            .invalidate_rename(2, "old.txt", 3, "new.txt", 10)
+            .unwrap();
+        let notifications: Vec<_> = (0..5)
+            .map(|_| parse(kernel.recv_reply().unwrap()))
+            .collect();
+        assert_eq!(
+            notifications,
+            [
+                "entry(2, old.txt)",
+                "entry(3, new.txt)",
+                "inode(2, 0, 0)",
+                "inode(3, 0, 0)",
+                "inode(10, -1, 0)",
+            ]
+        );
+
+        // The parent is invalidated once if the file is renamed within it.
+        notifier.invalidate_rename(2, "a", 2, "b", 10).unwrap();
+        let notifications: Vec<_> = (0..4)
+            .map(|_| parse(kernel.recv_reply().unwrap()))
+            .collect();
+        assert_eq!(
+            notifications,
+            [
+                "entry(2, a)",
+                "entry(2, b)",
+                "inode(2, 0, 0)",
+                "inode(10, -1, 0)"
+            ]
+        );
+    }
+
+    #[test]
+    fn non_utf8_names_round_trip() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let notifier = session.notifier();
+        let name: &[u8] = b"a\xff\nb";
+
+        kernel
+            .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, &[name, b"\0"].concat())
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let op = match req.operation().unwrap() {
+            Operation::Lookup(op) => op,
+            _ => panic!("incorrect operation is returned"),
+        };
+        assert_eq!(op.name().as_bytes(), name);
+        assert_eq!(
+            format!("{:?}", op),
+            "Lookup { parent: 1, name: \"a\\xff\\nb\" }"
+        );
+        let mut out = EntryOut::default();
+        out.ino(2);
+        req.reply(out).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().error(), 0);
+
+        // The name is invalidated with the bytes of the operation, and
+        // with the bytes stored by the filesystem.
+        notifier.inval_entry(1, op.name()).unwrap();
+        notifier.inval_entry(1, name).unwrap();
+        notifier.delete(1, 2, name.to_vec()).unwrap();
+        for code in [
+            fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY,
+            fuse_notify_code::FUSE_NOTIFY_INVAL_ENTRY,
+            fuse_notify_code::FUSE_NOTIFY_DELETE,
+        ]
+        .iter()
+        {
+            let reply = kernel.recv_reply().unwrap();
+            assert_eq!(reply.error(), *code as i32);
+            let payload = reply.payload();
+            let arg_len = if let fuse_notify_code::FUSE_NOTIFY_DELETE = code {
+                mem::size_of::<fuse_notify_delete_out>()
+            } else {
+                mem::size_of::<fuse_notify_inval_entry_out>()
+            };
+            assert_eq!(&payload[arg_len..], &[name, b"\0"].concat()[..]);
+        }
+    }
+
+    /// Create a waker setting `flag` when woken.
+    ///
+    /// `std::task::Wake` requires Rust 1.51, so the waker is built from the vtable.
+    fn flag_waker(flag: Arc<AtomicBool>) -> Waker {
+        use std::task::{RawWaker, RawWakerVTable};
+
+        const VTABLE: RawWakerVTable = RawWakerVTable::new(clone, wake, wake_by_ref, drop);
+
+        unsafe fn clone(data: *const ()) -> RawWaker {
+            let flag = Arc::from_raw(data as *const AtomicBool);
+            let cloned = flag.clone();
+            mem::forget(flag);
+            RawWaker::new(Arc::into_raw(cloned) as *const (), &VTABLE)
+        }
+        unsafe fn wake(data: *const ()) {
+            wake_by_ref(data);
+            drop(data);
+        }
+        unsafe fn wake_by_ref(data: *const ()) {
+            (*(data as *const AtomicBool)).store(true, Ordering::SeqCst);
+        }
+        unsafe fn drop(data: *const ()) {
+            mem::drop(Arc::from_raw(data as *const AtomicBool));
+        }
+
+        unsafe { Waker::from_raw(RawWaker::new(Arc::into_raw(flag) as *const (), &VTABLE)) }
+    }
+
+    #[test]
+    fn notifier_after_destroy() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let notifier = session.notifier();
+        assert!(!notifier.is_closed());
+
+        let flag = Arc::new(AtomicBool::new(false));
+        let waker = flag_waker(flag.clone());
+        let mut cx = task::Context::from_waker(&waker);
+        let mut closed = notifier.closed();
+        assert!(Pin::new(&mut closed).poll(&mut cx).is_pending());
+
+        kernel
+            .send_request(fuse_opcode::FUSE_DESTROY as u32, 0, &[])
+            .unwrap();
+        let _req = session.next_request().unwrap().unwrap();
+
+        assert!(flag.load(Ordering::SeqCst));
+        assert!(Pin::new(&mut closed).poll(&mut cx).is_ready());
+        assert!(notifier.is_closed());
+
+        let err = notifier.inval_inode(2, 0, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert_eq!(err.to_string(), "the session has exited");
+    }
+
+    #[test]
+    fn notifier_does_not_keep_connection() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+        let notifier = session.notifier();
+        let closed = notifier.closed();
+        notifier.inval_inode(2, 0, 0).unwrap();
+        assert!(kernel.recv_reply().is_ok());
+
+        drop(session);
+
+        // The connection is closed even though the notifier is alive.
+        let err = kernel.recv_reply().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(notifier.is_closed());
+        let err = notifier.inval_inode(2, 0, 0).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+
+        let waker = flag_waker(Arc::new(AtomicBool::new(false)));
+        let mut cx = task::Context::from_waker(&waker);
+        let mut closed = closed;
+        assert!(Pin::new(&mut closed).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn cache_dir_flags() {
+        let (session, kernel) = crate::testing::session(KernelConfig::default()).unwrap();
+
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_OPENDIR as u32,
+                2,
+                fuse_open_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let mut out = OpenOut::default();
+        out.fh(1);
+        out.cache_dir(true);
+        out.keep_cache(true);
+        req.reply(out).unwrap();
+
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!(reply.error(), 0);
+        let open_flags = &reply.payload()[8..12];
+        assert_eq!(
+            open_flags,
+            (FOPEN_CACHE_DIR | FOPEN_KEEP_CACHE).to_ne_bytes()
+        );
+
+        session.notifier().inval_dir(2).unwrap();
+        let notify = kernel.recv_reply().unwrap();
+        assert_eq!(notify.unique(), 0);
+        assert_eq!(
+            notify.error(),
+            fuse_notify_code::FUSE_NOTIFY_INVAL_INODE as i32
+        );
+        let expected = fuse_notify_inval_inode_out {
+            ino: 2,
+            off: 0,
+            len: 0,
+        };
+        assert_eq!(notify.payload(), expected.as_bytes());
+    }
+
+    #[test]
+    fn retrieve_range_in_chunks() {
+        let mut config = KernelConfig::default();
+        config.max_write(MIN_MAX_WRITE);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        let session = Arc::new(session);
+        let chunk = kernel.max_write() as usize;
+        let content: Vec<u8> = (0..chunk * 3).map(|i| i as u8).collect();
+
+        let receiver = std::thread::spawn({
+            let session = session.clone();
+            move || loop {
+                let req = session.next_request().unwrap().unwrap();
+                if req.header.opcode == fuse_opcode::FUSE_DESTROY as u32 {
+                    break;
+                }
+            }
+        });
+
+        // The kernel only has the first chunk and the half of the second in the cache.
+        for &cached in &[content.len(), chunk + chunk / 2] {
+            let len = (chunk * 2 + 100) as u64;
+            let retriever = std::thread::spawn({
+                let notifier = session.notifier();
+                move || notifier.retrieve_range(2, 10, len).unwrap()
+            });
+
+            let mut retrieves = vec![];
+            for _ in 0..3 {
+                let reply = kernel.recv_reply().unwrap();
+                assert_eq!(reply.unique(), 0);
+                assert_eq!(reply.error(), fuse_notify_code::FUSE_NOTIFY_RETRIEVE as i32);
+                let mut out = fuse_notify_retrieve_out::default();
+                out.as_bytes_mut().copy_from_slice(reply.payload());
+                assert_eq!(out.nodeid, 2);
+                retrieves.push(out);
+            }
+            let sizes: Vec<_> = retrieves.iter().map(|out| out.size as usize).collect();
+            assert_eq!(sizes, [chunk, chunk, 100]);
+
+            for out in retrieves.iter().rev() {
+                let start = cmp::min(out.offset as usize, cached);
+                let end = cmp::min(start + out.size as usize, cached);
+                kernel
+                    .send_notify_reply(out.notify_unique, 2, out.offset, &content[start..end])
+                    .unwrap();
+            }
+
+            let retrieved = retriever.join().unwrap();
+            assert_eq!(retrieved.offset(), 10);
+            let expected_end = cmp::min(10 + len as usize, cached);
+            assert_eq!(retrieved.data(), &content[10..expected_end]);
+            assert_eq!(retrieved.is_complete(), cached == content.len());
+        }
+
+        kernel
+            .send_request(fuse_opcode::FUSE_DESTROY as u32, 0, &[])
+            .unwrap();
+        receiver.join().unwrap();
+    }
+
+    #[test]
+    fn store_chunks_split_by_max_write() {
+        let mut config = KernelConfig::default();
+        config.max_write(MIN_MAX_WRITE);
+        let (session, kernel) = crate::testing::session(config).unwrap();
+        let notifier = session.notifier();
+        let max = kernel.max_write() as usize;
+
+        let content: Vec<u8> = (0..max * 3 + 80).map(|i| (i % 251) as u8).collect();
+        let mut chunks: Vec<Arc<[u8]>> = vec![];
+        let mut rest = &content[..];
+        for &len in &[100, max, max * 2 - 50, 0, 30] {
+            let (chunk, tail) = rest.split_at(len);
+            chunks.push(chunk.into());
+            rest = tail;
+        }
+        assert!(rest.is_empty());
+        notifier.store_chunks(2, 10, chunks.iter()).unwrap();
+
+        let mut received = vec![];
+        for (i, &size) in [max, max, max, 80].iter().enumerate() {
+            let reply = kernel.recv_reply().unwrap();
+            assert_eq!(reply.error(), fuse_notify_code::FUSE_NOTIFY_STORE as i32);
+            let (arg, data) = reply
+                .payload()
+                .split_at(mem::size_of::<fuse_notify_store_out>());
+            let mut out = fuse_notify_store_out::default();
+            out.as_bytes_mut().copy_from_slice(arg);
+            assert_eq!(out.nodeid, 2);
+            assert_eq!(out.offset, (10 + i * max) as u64);
+            assert_eq!(out.size as usize, size);
+            assert_eq!(data.len(), size);
+            received.extend_from_slice(data);
+        }
+        assert_eq!(received, content);
+
+        // The segments of a vectored write are also accepted by `store`.
+        let slices = [
+            IoSlice::new(b"foo"),
+            IoSlice::new(b""),
+            IoSlice::new(b"bar"),
+        ];
+        notifier.store(2, 0, &slices[..]).unwrap();
+        let reply = kernel.recv_reply().unwrap();
+        let data = &reply.payload()[mem::size_of::<fuse_notify_store_out>()..];
+        assert_eq!(data, b"foobar");
+
+        let err = notifier
+            .store_chunks(2, i64::MAX as u64 - 2, vec![&b"abc"[..]])
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,544 @@
+//! Utilities for testing filesystems without mounting.
+//!
+//! The session created by `session` communicates with a `MockKernel` over a
+//! pair of sockets, instead of the FUSE device.  The test code plays the role
+//! of the kernel: it sends request messages and inspects the replies written
+//! by the filesystem.  The simulator in `sim` builds on it and emulates the
+//! caches of the kernel side, for testing the filesystems with
+//! path-based calls.
+
+pub mod sim;
+
+use crate::{
+    bytes::{self, Bytes},
+    conn::Connection,
+    session::{KernelConfig, Request},
+    Session,
+};
+use polyfuse_kernel::*;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeMap, VecDeque},
+    convert::TryFrom as _,
+    fmt, io,
+    io::prelude::*,
+    mem,
+    os::unix::{net::UnixStream, prelude::*},
+};
+use zerocopy::AsBytes as _;
+
+// The maximum length of reply messages received by `MockKernel`.
+const MAX_REPLY_SIZE: usize = 1024 * 1024;
+
+/// Create a session connected to a `MockKernel`.
+///
+/// The `INIT` handshake is completed before returning, and its reply is
+/// available via `MockKernel::init_out`.  The configuration is checked by
+/// `KernelConfig::validate` as `Session::mount` does.
+pub fn session(config: KernelConfig) -> io::Result<(Session, MockKernel)> {
+    session_offering(config, u32::MAX)
+}
+
+/// Create a session connected to a `MockKernel` offering only the specified
+/// capability flags in the `INIT` handshake.
+pub fn session_offering(config: KernelConfig, flags: u32) -> io::Result<(Session, MockKernel)> {
+    config.validate()?;
+    let (conn, socket) = Connection::pair()?;
+    let mut kernel = MockKernel {
+        socket,
+        next_unique: Cell::new(1),
+        credentials: Cell::new(None),
+        init_out: fuse_init_out::default(),
+        match_replies: Cell::new(false),
+        outstanding: RefCell::new(BTreeMap::new()),
+        buffered: RefCell::new(VecDeque::new()),
+    };
+
+    let init_in = fuse_init_in {
+        major: FUSE_KERNEL_VERSION,
+        minor: FUSE_KERNEL_MINOR_VERSION,
+        max_readahead: u32::MAX,
+        flags,
+    };
+    kernel.send_request(fuse_opcode::FUSE_INIT as u32, 0, init_in.as_bytes())?;
+
+    let session = Session::init(conn, config)?;
+
+    let reply = kernel.recv_reply()?;
+    if reply.error() != 0 {
+        return Err(io::Error::from_raw_os_error(-reply.error()));
+    }
+    let payload = reply.payload();
+    let len = mem::size_of::<fuse_init_out>().min(payload.len());
+    kernel.init_out.as_bytes_mut()[..len].copy_from_slice(&payload[..len]);
+
+    Ok((session, kernel))
+}
+
+/// Build a request whose reply is discarded, for testing the handlers
+/// without a `MockKernel`.
+///
+/// The request is received by a session negotiated with `config`.  The
+/// reply of the handler is consumed as if it were written, and its error
+/// number is obtained by `Request::reply_errno`.  The second reply fails
+/// with `AlreadyReplied` as on the mounted sessions.
+pub fn request(config: KernelConfig, opcode: u32, nodeid: u64, arg: &[u8]) -> io::Result<Request> {
+    let (session, kernel) = self::session(config)?;
+    kernel.send_request(opcode, nodeid, arg)?;
+    let mut req = session.next_request()?.ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "the request is not delivered to the filesystem",
+        )
+    })?;
+    req.discard_reply();
+    Ok(req)
+}
+
+/// Render a reply into the bytes written after the header of the reply message.
+///
+/// The result is the same as `RawReply::payload` of the corresponding reply,
+/// so the code that builds replies can be tested without sessions.
+pub fn capture_reply<T: Bytes>(reply: T) -> Vec<u8> {
+    bytes::to_vec(&reply)
+}
+
+/// Encode a request message as the kernel writes it to the FUSE device.
+///
+/// Any opcode can be used, including the ones unknown to this library,
+/// so that the handling of the operations added in newer kernels can be
+/// tested.  The unique ID of the message is `1`, and the credentials are
+/// those of the current process.  `MockKernel::send_request` sends the
+/// same message with its own unique ID.
+pub fn encode_raw_request(opcode: u32, nodeid: u64, payload: &[u8]) -> Vec<u8> {
+    let header = in_header(opcode, 1, nodeid, None, payload.len());
+    let mut message = header.as_bytes().to_vec();
+    message.extend_from_slice(payload);
+    message
+}
+
+fn in_header(
+    opcode: u32,
+    unique: u64,
+    nodeid: u64,
+    credentials: Option<(u32, u32)>,
+    arg_len: usize,
+) -> fuse_in_header {
+    let (uid, gid) = credentials.unwrap_or_else(|| unsafe { (libc::getuid(), libc::getgid()) });
+    fuse_in_header {
+        len: (mem::size_of::<fuse_in_header>() + arg_len) as u32,
+        opcode,
+        unique,
+        nodeid,
+        uid,
+        gid,
+        pid: std::process::id(),
+        total_extlen: 0,
+        padding: 0,
+    }
+}
+
+/// The emulated kernel side of a session created by `session`.
+///
+/// Dropping this value closes the connection, and then the session
+/// observes that the filesystem has been unmounted.
+pub struct MockKernel {
+    socket: UnixStream,
+    next_unique: Cell<u64>,
+    credentials: Cell<Option<(u32, u32)>>,
+    init_out: fuse_init_out,
+    match_replies: Cell<bool>,
+    // The uniques sent but not replied yet, with whether a reply is required.
+    outstanding: RefCell<BTreeMap<u64, bool>>,
+    buffered: RefCell<VecDeque<RawReply>>,
+}
+
+impl fmt::Debug for MockKernel {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MockKernel").finish()
+    }
+}
+
+impl AsRawFd for MockKernel {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl MockKernel {
+    /// Return the flags replied by the filesystem in the `INIT` handshake.
+    pub fn init_flags(&self) -> u32 {
+        self.init_out.flags
+    }
+
+    /// Return the `max_write` replied by the filesystem in the `INIT` handshake.
+    pub fn max_write(&self) -> u32 {
+        self.init_out.max_write
+    }
+
+    /// Specify the user and group IDs of the subsequent requests.
+    ///
+    /// If `None` is given, the credentials of the current process are used.
+    pub fn set_credentials(&self, credentials: Option<(u32, u32)>) {
+        self.credentials.set(credentials);
+    }
+
+    /// Check that every reply corresponds to a request sent by this mock.
+    ///
+    /// When enabled, receiving a reply to the unique that has not been
+    /// sent or has already been replied fails with `InvalidData`, which
+    /// detects the duplicated replies.  The dropped replies remain in
+    /// `outstanding`.  Disabled by default.
+    pub fn match_replies(&self, enabled: bool) {
+        self.match_replies.set(enabled);
+    }
+
+    /// Return the unique IDs of the requests that have not been replied yet.
+    ///
+    /// The requests that do not require replies, such as `FORGET` and
+    /// `INTERRUPT`, are not included.
+    pub fn outstanding(&self) -> Vec<u64> {
+        let outstanding = self.outstanding.borrow();
+        outstanding
+            .iter()
+            .filter(|&(_, &required)| required)
+            .map(|(&unique, _)| unique)
+            .collect()
+    }
+
+    /// Send a request message to the filesystem, and return its unique ID.
+    ///
+    /// The credentials of the request are those of the current process,
+    /// unless they are overridden by `set_credentials`.
+    pub fn send_request(&self, opcode: u32, nodeid: u64, arg: &[u8]) -> io::Result<u64> {
+        let unique = self.next_unique.get();
+        self.next_unique.set(unique + 1);
+
+        self.send_message(opcode, unique, nodeid, &[arg])?;
+
+        let required = match fuse_opcode::try_from(opcode).ok() {
+            Some(fuse_opcode::FUSE_FORGET) | Some(fuse_opcode::FUSE_BATCH_FORGET) => None,
+            // The filesystem may reply EAGAIN to interrupts.
+            Some(fuse_opcode::FUSE_INTERRUPT) => Some(false),
+            _ => Some(true),
+        };
+        if let Some(required) = required {
+            self.outstanding.borrow_mut().insert(unique, required);
+        }
+
+        Ok(unique)
+    }
+
+    /// Send the cached data in reply to the `NOTIFY_RETRIEVE` notification
+    /// of `notify_unique`, as a `NOTIFY_REPLY` request.
+    pub fn send_notify_reply(
+        &self,
+        notify_unique: u64,
+        ino: u64,
+        offset: u64,
+        data: &[u8],
+    ) -> io::Result<()> {
+        let arg = fuse_notify_retrieve_in {
+            offset,
+            size: data.len() as u32,
+            ..Default::default()
+        };
+        self.send_message(
+            fuse_opcode::FUSE_NOTIFY_REPLY as u32,
+            notify_unique,
+            ino,
+            &[arg.as_bytes(), data],
+        )
+    }
+
+    fn send_message(&self, opcode: u32, unique: u64, nodeid: u64, arg: &[&[u8]]) -> io::Result<()> {
+        let arg_len: usize = arg.iter().map(|arg| arg.len()).sum();
+        let header = in_header(opcode, unique, nodeid, self.credentials.get(), arg_len);
+
+        let mut bufs = vec![io::IoSlice::new(header.as_bytes())];
+        bufs.extend(arg.iter().map(|arg| io::IoSlice::new(arg)));
+        let written = (&self.socket).write_vectored(&bufs)?;
+        if written != header.len as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "failed to write the entire request message",
+            ));
+        }
+        Ok(())
+    }
+
+    /// Receive a reply (or notification) message written by the filesystem.
+    ///
+    /// The replies buffered by `recv_reply_for` are returned first.
+    pub fn recv_reply(&self) -> io::Result<RawReply> {
+        if let Some(reply) = self.buffered.borrow_mut().pop_front() {
+            return Ok(reply);
+        }
+        self.read_reply()
+    }
+
+    /// Receive the reply to the request of `unique`, buffering the other
+    /// messages to be returned by the later calls.
+    ///
+    /// This is useful for testing the filesystems that process requests
+    /// concurrently and may reply to them out of order.
+    pub fn recv_reply_for(&self, unique: u64) -> io::Result<RawReply> {
+        {
+            let mut buffered = self.buffered.borrow_mut();
+            if let Some(pos) = buffered.iter().position(|reply| reply.unique() == unique) {
+                return Ok(buffered.remove(pos).unwrap());
+            }
+        }
+        loop {
+            let reply = self.read_reply()?;
+            if reply.unique() == unique {
+                return Ok(reply);
+            }
+            self.buffered.borrow_mut().push_back(reply);
+        }
+    }
+
+    fn read_reply(&self) -> io::Result<RawReply> {
+        let mut buf = vec![0u8; MAX_REPLY_SIZE];
+        let len = (&self.socket).read(&mut buf[..])?;
+        if len < mem::size_of::<fuse_out_header>() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "reply message is too short",
+            ));
+        }
+        buf.truncate(len);
+
+        let mut header = fuse_out_header::default();
+        header
+            .as_bytes_mut()
+            .copy_from_slice(&buf[..mem::size_of::<fuse_out_header>()]);
+        if header.len as usize != len {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "the length in the header is mismatched",
+            ));
+        }
+        buf.drain(..mem::size_of::<fuse_out_header>());
+
+        // The notifications have the unique ID of zero.
+        if header.unique != 0
+            && self
+                .outstanding
+                .borrow_mut()
+                .remove(&header.unique)
+                .is_none()
+            && self.match_replies.get()
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!(
+                    "unexpected or duplicated reply (unique = {})",
+                    header.unique
+                ),
+            ));
+        }
+
+        Ok(RawReply {
+            header,
+            payload: buf,
+        })
+    }
+}
+
+/// A message written by the filesystem.
+pub struct RawReply {
+    header: fuse_out_header,
+    payload: Vec<u8>,
+}
+
+impl fmt::Debug for RawReply {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RawReply")
+            .field("unique", &self.unique())
+            .field("error", &self.error())
+            .field("payload", &self.payload)
+            .finish()
+    }
+}
+
+impl RawReply {
+    /// Return the unique ID of the corresponding request.
+    ///
+    /// The value is zero if this message is a notification.
+    pub fn unique(&self) -> u64 {
+        self.header.unique
+    }
+
+    /// Return the error number of this reply.
+    ///
+    /// As in the FUSE protocol, the value is negated (e.g. `-libc::ENOENT`).
+    /// For the notifications, the value is the notification code.
+    pub fn error(&self) -> i32 {
+        self.header.error
+    }
+
+    /// Return the payload of this message.
+    pub fn payload(&self) -> &[u8] {
+        &self.payload[..]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn init_handshake() {
+        let (session, kernel) = session(KernelConfig::default()).unwrap();
+        assert!(kernel.init_flags() & FUSE_ASYNC_READ != 0);
+        assert!(kernel.max_write() > 0);
+        assert!(session.granted().contains(kernel.init_flags()));
+        assert_eq!(session.offered().bits(), u32::MAX);
+        assert!(session.summary().contains("ASYNC_READ"));
+    }
+
+    #[test]
+    fn discarded_reply() {
+        let req = request(
+            KernelConfig::default(),
+            fuse_opcode::FUSE_GETATTR as u32,
+            1,
+            fuse_getattr_in::default().as_bytes(),
+        )
+        .unwrap();
+        assert_eq!(req.reply_errno(), None);
+
+        let err = req
+            .process(|_| Err(io::Error::from_raw_os_error(libc::ENOSYS)))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOSYS));
+        assert_eq!(req.reply_errno(), Some(libc::ENOSYS));
+
+        let err = req.reply_error(libc::EIO).unwrap_err();
+        assert!(crate::AlreadyReplied::is(&err));
+    }
+
+    #[test]
+    fn reply_roundtrip() {
+        let (session, kernel) = session(KernelConfig::default()).unwrap();
+
+        let unique = kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                1,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+
+        let req = session.next_request().unwrap().expect("unexpected closed");
+        assert_eq!(req.unique(), unique);
+        req.reply_error(libc::ENOSYS).unwrap();
+
+        let reply = kernel.recv_reply().unwrap();
+        assert_eq!(reply.unique(), unique);
+        assert_eq!(reply.error(), -libc::ENOSYS);
+        assert!(reply.payload().is_empty());
+    }
+
+    #[test]
+    fn capture_reply_matches_wire() {
+        use crate::reply::{EntryOut, FileAttr};
+        use std::time::Duration;
+
+        let (session, kernel) = session(KernelConfig::default()).unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+            .unwrap();
+
+        let mut out = EntryOut::default();
+        out.ino(2);
+        out.attr().ino(2);
+        out.attr().mode(libc::S_IFREG | 0o644);
+        out.ttl_entry(Duration::from_secs(1));
+        assert_eq!(out.entry_valid(), Duration::from_secs(1));
+        assert_eq!(out.attr_valid(), Duration::from_secs(0));
+
+        let mut attr = FileAttr::default();
+        attr.ino(2);
+        attr.mode(libc::S_IFREG | 0o644);
+        assert_eq!(*out.attr(), attr);
+        assert_ne!(out, EntryOut::default());
+
+        let expected = capture_reply(&out);
+        let req = session.next_request().unwrap().unwrap();
+        req.reply(out).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().payload(), &expected[..]);
+    }
+
+    #[test]
+    fn replies_out_of_order() {
+        let (session, kernel) = session(KernelConfig::default()).unwrap();
+        kernel.match_replies(true);
+
+        let uniques: Vec<_> = (0..3)
+            .map(|_| {
+                kernel
+                    .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+                    .unwrap()
+            })
+            .collect();
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_FORGET as u32,
+                2,
+                fuse_forget_in::default().as_bytes(),
+            )
+            .unwrap();
+        assert_eq!(kernel.outstanding(), uniques);
+
+        let reqs: Vec<_> = (0..3)
+            .map(|_| session.next_request().unwrap().unwrap())
+            .collect();
+        for req in reqs.into_iter().rev() {
+            req.reply_error(libc::ENOENT).unwrap();
+        }
+
+        assert_eq!(
+            kernel.recv_reply_for(uniques[1]).unwrap().unique(),
+            uniques[1]
+        );
+        assert_eq!(kernel.recv_reply().unwrap().unique(), uniques[2]);
+        assert_eq!(
+            kernel.recv_reply_for(uniques[0]).unwrap().unique(),
+            uniques[0]
+        );
+        assert!(kernel.outstanding().is_empty());
+    }
+
+    #[test]
+    fn dropped_reply_remains_outstanding() {
+        let (session, kernel) = session(KernelConfig::default()).unwrap();
+        kernel.match_replies(true);
+
+        let dropped = kernel
+            .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+            .unwrap();
+        let replied = kernel
+            .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"bar\0")
+            .unwrap();
+
+        drop(session.next_request().unwrap().unwrap());
+        let req = session.next_request().unwrap().unwrap();
+        req.reply_error(libc::ENOENT).unwrap();
+
+        assert_eq!(kernel.recv_reply().unwrap().unique(), replied);
+        assert_eq!(kernel.outstanding(), vec![dropped]);
+    }
+
+    #[test]
+    fn closed_by_kernel() {
+        let (session, kernel) = session(KernelConfig::default()).unwrap();
+        drop(kernel);
+        assert!(session.next_request().unwrap().is_none());
+        assert_eq!(
+            session.closed_reason(),
+            Some(crate::ConnectionClosed::Unmounted)
+        );
+    }
+}
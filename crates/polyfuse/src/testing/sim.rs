@@ -0,0 +1,962 @@
+//! A simulator of the kernel side, driving a filesystem with path-based calls.
+//!
+//! While `MockKernel` leaves the construction of every request message to
+//! the test code, `Simulator` plays the role of the VFS and the FUSE driver
+//! in the kernel: a call like `stat(path)` resolves the path with `LOOKUP`
+//! requests, and the replies are kept in the dentry, attribute, page and
+//! directory caches of the simulator, as long as their timeouts permit.
+//! This allows testing the caching behaviour of a filesystem, e.g. that
+//! a `stat` right after `mkdir` is answered without reaching it.
+//!
+//! The timeouts are measured with a virtual clock advanced by
+//! `Simulator::advance`, so the scenarios do not depend on real time.
+//! The requests are processed one at a time on the calling thread, and
+//! the notifications from the filesystem are not interpreted.
+
+use super::MockKernel;
+use crate::{session::KernelConfig, Request, Session};
+use polyfuse_kernel::*;
+use std::{
+    collections::{BTreeMap, HashMap},
+    ffi::{OsStr, OsString},
+    fmt, io, mem,
+    os::unix::prelude::*,
+    path::{Component, Path},
+    time::{Duration, SystemTime},
+};
+use zerocopy::{AsBytes, FromBytes};
+
+const ROOT_INO: u64 = 1;
+
+// The unit of the page cache.
+const PAGE_SIZE: u64 = 4096;
+
+// The size of the buffer passed in `READDIR` requests.
+const READDIR_SIZE: u32 = 4096;
+
+/// The attributes of a file observed by the simulator.
+#[derive(Copy, Clone)]
+pub struct Attr(fuse_attr);
+
+impl fmt::Debug for Attr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Attr")
+            .field("ino", &self.ino())
+            .field("size", &self.size())
+            .field("mode", &format_args!("{:o}", self.mode()))
+            .field("nlink", &self.nlink())
+            .finish()
+    }
+}
+
+impl Attr {
+    /// Return the inode number.
+    pub fn ino(&self) -> u64 {
+        self.0.ino
+    }
+
+    /// Return the size of the file in bytes.
+    pub fn size(&self) -> u64 {
+        self.0.size
+    }
+
+    /// Return the file type and mode, as `st_mode`.
+    pub fn mode(&self) -> u32 {
+        self.0.mode
+    }
+
+    /// Return the number of hard links.
+    pub fn nlink(&self) -> u32 {
+        self.0.nlink
+    }
+
+    /// Return the user ID of the owner.
+    pub fn uid(&self) -> u32 {
+        self.0.uid
+    }
+
+    /// Return the group ID of the owner.
+    pub fn gid(&self) -> u32 {
+        self.0.gid
+    }
+
+    /// Return the time of last access.
+    pub fn atime(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(self.0.atime, self.0.atimensec)
+    }
+
+    /// Return the time of last modification.
+    pub fn mtime(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(self.0.mtime, self.0.mtimensec)
+    }
+
+    /// Return the time of last status change.
+    pub fn ctime(&self) -> SystemTime {
+        SystemTime::UNIX_EPOCH + Duration::new(self.0.ctime, self.0.ctimensec)
+    }
+}
+
+/// A directory entry returned by `Simulator::readdir`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    /// The name of the entry.
+    pub name: OsString,
+    /// The inode number of the entry.
+    pub ino: u64,
+    /// The file type of the entry, as `d_type`.
+    pub typ: u32,
+}
+
+/// A file opened by `Simulator::open`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct OpenFile {
+    ino: u64,
+    fh: u64,
+    direct_io: bool,
+    // Opened without sending `OPEN`, so that `RELEASE` is not sent either.
+    zero_message: bool,
+}
+
+impl OpenFile {
+    /// Return the inode number of the opened file.
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Return the file handle replied by the filesystem.
+    pub fn fh(&self) -> u64 {
+        self.fh
+    }
+}
+
+struct Dentry {
+    // Zero if the entry is negative.
+    ino: u64,
+    expires: Duration,
+}
+
+#[derive(Default)]
+struct Inode {
+    nlookup: u64,
+    attr: Option<(fuse_attr, Duration)>,
+    // The cached pages, where a page shorter than `PAGE_SIZE` marks the end of file.
+    pages: BTreeMap<u64, Vec<u8>>,
+    dir: Option<Vec<DirEntry>>,
+}
+
+/// A simulated kernel that drives a filesystem through a `Session`.
+pub struct Simulator<F> {
+    session: Session,
+    kernel: MockKernel,
+    fs: F,
+    now: Duration,
+    dentries: HashMap<(u64, OsString), Dentry>,
+    inodes: HashMap<u64, Inode>,
+    requests: Vec<u32>,
+    // The operations found to be unimplemented, which the kernel stops sending.
+    no_open: bool,
+    no_opendir: bool,
+    no_flush: bool,
+}
+
+impl<F> fmt::Debug for Simulator<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Simulator")
+            .field("now", &self.now)
+            .field("dentries", &self.dentries.len())
+            .field("requests", &self.requests.len())
+            .finish()
+    }
+}
+
+impl<F> Simulator<F>
+where
+    F: FnMut(&Request) -> io::Result<()>,
+{
+    /// Start a simulator with a session created by `testing::session`.
+    ///
+    /// Every request is passed to `fs` via `Request::process`, so an error
+    /// returned without replying is replied as the error number.
+    pub fn new(config: KernelConfig, fs: F) -> io::Result<Self> {
+        let (session, kernel) = super::session(config)?;
+        let mut inodes = HashMap::new();
+        inodes.insert(
+            ROOT_INO,
+            Inode {
+                // The root inode is never forgotten.
+                nlookup: 1,
+                ..Inode::default()
+            },
+        );
+        Ok(Self {
+            session,
+            kernel,
+            fs,
+            now: Duration::from_secs(0),
+            dentries: HashMap::new(),
+            inodes,
+            requests: vec![],
+            no_open: false,
+            no_opendir: false,
+            no_flush: false,
+        })
+    }
+
+    /// Return the session connected to the simulator.
+    pub fn session(&self) -> &Session {
+        &self.session
+    }
+
+    /// Return the underlying mock, e.g. to set the credentials.
+    pub fn kernel(&self) -> &MockKernel {
+        &self.kernel
+    }
+
+    /// Advance the virtual clock used for the timeouts of the caches.
+    pub fn advance(&mut self, duration: Duration) {
+        self.now += duration;
+    }
+
+    /// Return the number of requests that have reached the filesystem.
+    pub fn requests(&self) -> usize {
+        self.requests.len()
+    }
+
+    /// Return the number of requests with the specified opcode that have
+    /// reached the filesystem.
+    pub fn count(&self, opcode: u32) -> usize {
+        self.requests.iter().filter(|&&op| op == opcode).count()
+    }
+
+    /// Return the lookup count of the inode held by the simulator, i.e. the
+    /// number of successful lookups that have not been forgotten yet.
+    pub fn nlookup(&self, ino: u64) -> u64 {
+        self.inodes.get(&ino).map_or(0, |inode| inode.nlookup)
+    }
+
+    /// Resolve the path to the inode number, like the path walk of the VFS.
+    ///
+    /// Every component whose dentry is missing or expired is looked up.
+    /// Paths are relative to the root, and `..` is not supported.
+    pub fn lookup(&mut self, path: impl AsRef<Path>) -> io::Result<u64> {
+        let mut ino = ROOT_INO;
+        for component in path.as_ref().components() {
+            match component {
+                Component::RootDir | Component::CurDir => (),
+                Component::Normal(name) => ino = self.lookup_entry(ino, name)?,
+                _ => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "unsupported path component",
+                    ))
+                }
+            }
+        }
+        Ok(ino)
+    }
+
+    /// Return the attributes of the file, like `stat(2)`.
+    pub fn stat(&mut self, path: impl AsRef<Path>) -> io::Result<Attr> {
+        let ino = self.lookup(path)?;
+        self.getattr(ino).map(Attr)
+    }
+
+    /// Create a directory, like `mkdir(2)`.
+    pub fn mkdir(&mut self, path: impl AsRef<Path>, mode: u32) -> io::Result<Attr> {
+        let (parent, name) = self.lookup_parent(path.as_ref())?;
+        let arg = fuse_mkdir_in { mode, umask: 0 };
+        self.make_entry(fuse_opcode::FUSE_MKDIR, parent, arg.as_bytes(), &name)
+    }
+
+    /// Create a file node, like `mknod(2)`.
+    pub fn mknod(&mut self, path: impl AsRef<Path>, mode: u32) -> io::Result<Attr> {
+        let (parent, name) = self.lookup_parent(path.as_ref())?;
+        let arg = fuse_mknod_in {
+            mode,
+            ..Default::default()
+        };
+        self.make_entry(fuse_opcode::FUSE_MKNOD, parent, arg.as_bytes(), &name)
+    }
+
+    /// Open the file, like `open(2)`.
+    ///
+    /// The cached pages of the file are discarded unless the filesystem
+    /// replies with `FOPEN_KEEP_CACHE`.  As the kernel does, `ENOSYS` is
+    /// treated as a successful open if `no_open_support` is granted, and
+    /// then `OPEN` is no longer sent.
+    ///
+    /// `O_TRUNC` is passed through to `OPEN` if `atomic_o_trunc` is granted,
+    /// and otherwise the file is truncated by a separate `SETATTR` after
+    /// it is opened.
+    pub fn open(&mut self, path: impl AsRef<Path>, flags: i32) -> io::Result<OpenFile> {
+        let ino = self.lookup(path)?;
+        let zero_message = self.no_open;
+        let truncate = flags & libc::O_TRUNC != 0;
+        let atomic_o_trunc = self.session.atomic_o_trunc();
+        let open_flags = if atomic_o_trunc {
+            flags
+        } else {
+            flags & !libc::O_TRUNC
+        };
+        let out = self.open_inode(fuse_opcode::FUSE_OPEN, ino, open_flags)?;
+        let inode = self.inodes.entry(ino).or_default();
+        if out.open_flags & FOPEN_KEEP_CACHE == 0 || truncate {
+            inode.pages.clear();
+        }
+        if truncate {
+            if atomic_o_trunc {
+                inode.attr = None;
+            } else {
+                let arg = fuse_setattr_in {
+                    valid: FATTR_SIZE,
+                    size: 0,
+                    ..Default::default()
+                };
+                self.setattr(ino, arg)?;
+            }
+        }
+        Ok(OpenFile {
+            ino,
+            fh: out.fh,
+            direct_io: out.open_flags & FOPEN_DIRECT_IO != 0,
+            zero_message: zero_message || self.no_open,
+        })
+    }
+
+    /// Read the data of the opened file, like `pread(2)`.
+    ///
+    /// The data is read through the page cache in units of pages, unless
+    /// the file has been opened with `FOPEN_DIRECT_IO`.
+    pub fn read(&mut self, file: &OpenFile, offset: u64, size: usize) -> io::Result<Vec<u8>> {
+        if file.direct_io {
+            return self.read_raw(file, offset, size as u32);
+        }
+
+        let first = offset / PAGE_SIZE;
+        let end = (offset + size as u64 + PAGE_SIZE - 1) & !(PAGE_SIZE - 1);
+        let last = end / PAGE_SIZE;
+        let mut index = first;
+        while index < last {
+            let pages = &self.inodes.entry(file.ino).or_default().pages;
+            match pages.get(&index) {
+                Some(page) if (page.len() as u64) < PAGE_SIZE => break,
+                Some(..) => index += 1,
+                None => {
+                    let end = (index..last)
+                        .find(|index| pages.contains_key(index))
+                        .unwrap_or(last);
+                    let data =
+                        self.read_raw(file, index * PAGE_SIZE, ((end - index) * PAGE_SIZE) as u32)?;
+                    let pages = &mut self.inodes.entry(file.ino).or_default().pages;
+                    let mut chunks = data.chunks(PAGE_SIZE as usize);
+                    for index in index..end {
+                        let page = chunks.next().unwrap_or(&[]);
+                        pages.insert(index, page.to_vec());
+                        if (page.len() as u64) < PAGE_SIZE {
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+
+        let pages = &self.inodes[&file.ino].pages;
+        let mut data = Vec::with_capacity(size);
+        for (&index, page) in pages.range(first..last) {
+            let start = (offset.saturating_sub(index * PAGE_SIZE) as usize).min(page.len());
+            let remaining = size - data.len();
+            data.extend_from_slice(&page[start..page.len().min(start + remaining)]);
+            if (page.len() as u64) < PAGE_SIZE {
+                break;
+            }
+        }
+        Ok(data)
+    }
+
+    /// Write the data to the opened file, like `pwrite(2)` on a file
+    /// opened with `O_DIRECT`.
+    ///
+    /// The data is sent to the filesystem immediately, and the cached
+    /// pages and attributes of the file are discarded.
+    pub fn write(&mut self, file: &OpenFile, offset: u64, data: &[u8]) -> io::Result<usize> {
+        let arg = fuse_write_in {
+            fh: file.fh,
+            offset,
+            size: data.len() as u32,
+            ..Default::default()
+        };
+        let payload = self.call(fuse_opcode::FUSE_WRITE, file.ino, &[arg.as_bytes(), data]);
+        let inode = self.inodes.entry(file.ino).or_default();
+        inode.attr = None;
+        inode.pages.clear();
+        let out: fuse_write_out = decode(&payload?)?;
+        Ok(out.size as usize)
+    }
+
+    /// Change the mode of the file, like `chmod(2)`.
+    pub fn chmod(&mut self, path: impl AsRef<Path>, mode: u32) -> io::Result<Attr> {
+        let ino = self.lookup(path)?;
+        let arg = fuse_setattr_in {
+            valid: FATTR_MODE,
+            mode,
+            ..Default::default()
+        };
+        self.setattr(ino, arg)
+    }
+
+    /// Rename the entry, like `renameat2(2)` with the flags such as
+    /// `libc::RENAME_EXCHANGE`.
+    ///
+    /// As the kernel does, the dentries are moved (or swapped) without
+    /// looking them up again, so the lookup counts are not changed.  The
+    /// attributes of the parents and the renamed inodes are invalidated.
+    pub fn rename(
+        &mut self,
+        from: impl AsRef<Path>,
+        to: impl AsRef<Path>,
+        flags: u32,
+    ) -> io::Result<()> {
+        let exchange = flags & libc::RENAME_EXCHANGE != 0;
+        let (parent, name) = self.lookup_parent(from.as_ref())?;
+        let (newparent, newname) = self.lookup_parent(to.as_ref())?;
+        let ino = self.lookup_entry(parent, &name)?;
+        let newino = match self.lookup_entry(newparent, &newname) {
+            Ok(newino) => Some(newino),
+            Err(err) if exchange => return Err(err),
+            Err(..) => None,
+        };
+
+        let arg = fuse_rename2_in {
+            newdir: newparent,
+            flags,
+            padding: 0,
+        };
+        let mut name_arg = name.as_bytes().to_vec();
+        name_arg.push(0);
+        let mut newname_arg = newname.as_bytes().to_vec();
+        newname_arg.push(0);
+        self.call(
+            fuse_opcode::FUSE_RENAME2,
+            parent,
+            &[arg.as_bytes(), &name_arg, &newname_arg],
+        )?;
+
+        let key = (parent, name);
+        let newkey = (newparent, newname);
+        let dentry = self.dentries.remove(&key);
+        let newdentry = self.dentries.remove(&newkey);
+        if let (true, Some(newdentry)) = (exchange, newdentry) {
+            self.dentries.insert(key, newdentry);
+        }
+        if let Some(dentry) = dentry {
+            self.dentries.insert(newkey, dentry);
+        }
+        for ino in [Some(parent), Some(newparent), Some(ino), newino]
+            .iter()
+            .flatten()
+        {
+            if let Some(inode) = self.inodes.get_mut(ino) {
+                inode.attr = None;
+                inode.dir = None;
+            }
+        }
+        Ok(())
+    }
+
+    /// Close the opened file, like the last `close(2)`.
+    pub fn release(&mut self, file: OpenFile) -> io::Result<()> {
+        if !self.no_flush {
+            let arg = fuse_flush_in {
+                fh: file.fh,
+                ..Default::default()
+            };
+            match self.call(fuse_opcode::FUSE_FLUSH, file.ino, &[arg.as_bytes()]) {
+                Ok(..) => (),
+                Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => self.no_flush = true,
+                Err(err) => return Err(err),
+            }
+        }
+        if !file.zero_message {
+            let arg = fuse_release_in {
+                fh: file.fh,
+                ..Default::default()
+            };
+            // As in close(2), the error replied to RELEASE is not reported.
+            let _ = self.call(fuse_opcode::FUSE_RELEASE, file.ino, &[arg.as_bytes()]);
+        }
+        Ok(())
+    }
+
+    /// Read the entries of the directory, like `getdents(2)` until the end.
+    ///
+    /// The entries are cached if the filesystem replies `OPENDIR` with
+    /// `FOPEN_CACHE_DIR`, and the cache is reused by the later calls while
+    /// they are replied with `FOPEN_KEEP_CACHE`.
+    pub fn readdir(&mut self, path: impl AsRef<Path>) -> io::Result<Vec<DirEntry>> {
+        let ino = self.lookup(path)?;
+        let zero_message = self.no_opendir;
+        let out = self.open_inode(fuse_opcode::FUSE_OPENDIR, ino, libc::O_RDONLY)?;
+        let zero_message = zero_message || self.no_opendir;
+
+        let cached = {
+            let inode = self.inodes.entry(ino).or_default();
+            if out.open_flags & FOPEN_KEEP_CACHE == 0 || out.open_flags & FOPEN_CACHE_DIR == 0 {
+                inode.dir = None;
+            }
+            inode.dir.clone()
+        };
+        let entries = match cached {
+            Some(entries) => Ok(entries),
+            None => self.read_dir_entries(ino, out.fh),
+        };
+
+        if !zero_message {
+            let arg = fuse_release_in {
+                fh: out.fh,
+                ..Default::default()
+            };
+            let _ = self.call(fuse_opcode::FUSE_RELEASEDIR, ino, &[arg.as_bytes()]);
+        }
+
+        let entries = entries?;
+        if out.open_flags & FOPEN_CACHE_DIR != 0 {
+            self.inodes.entry(ino).or_default().dir = Some(entries.clone());
+        }
+        Ok(entries)
+    }
+
+    /// Drop all the dentries and sends `FORGET` for the inodes, like
+    /// writing `2` to `/proc/sys/vm/drop_caches`.
+    pub fn drop_caches(&mut self) -> io::Result<()> {
+        self.dentries.clear();
+        let forgotten: Vec<(u64, u64)> = self
+            .inodes
+            .iter()
+            .filter(|&(&ino, inode)| ino != ROOT_INO && inode.nlookup > 0)
+            .map(|(&ino, inode)| (ino, inode.nlookup))
+            .collect();
+        self.inodes.retain(|&ino, _| ino == ROOT_INO);
+        for (ino, nlookup) in forgotten {
+            self.forget(ino, nlookup)?;
+        }
+        Ok(())
+    }
+
+    fn lookup_parent(&mut self, path: &Path) -> io::Result<(u64, OsString)> {
+        let name = path
+            .file_name()
+            .ok_or_else(|| io::Error::from_raw_os_error(libc::EEXIST))?;
+        let parent = match path.parent() {
+            Some(parent) => self.lookup(parent)?,
+            None => ROOT_INO,
+        };
+        Ok((parent, name.to_owned()))
+    }
+
+    fn lookup_entry(&mut self, parent: u64, name: &OsStr) -> io::Result<u64> {
+        let key = (parent, name.to_owned());
+        if let Some(dentry) = self.dentries.get(&key) {
+            if dentry.expires > self.now {
+                return match dentry.ino {
+                    0 => Err(io::Error::from_raw_os_error(libc::ENOENT)),
+                    ino => Ok(ino),
+                };
+            }
+        }
+
+        let mut arg = name.as_bytes().to_vec();
+        arg.push(0);
+        match self.call(fuse_opcode::FUSE_LOOKUP, parent, &[&arg]) {
+            Ok(payload) => {
+                let out: fuse_entry_out = decode(&payload)?;
+                if out.nodeid == 0 {
+                    // The negative entry.
+                    self.insert_dentry(key, &out);
+                    return Err(io::Error::from_raw_os_error(libc::ENOENT));
+                }
+                self.insert_entry(key, &out);
+                Ok(out.nodeid)
+            }
+            Err(err) => {
+                self.dentries.remove(&key);
+                Err(err)
+            }
+        }
+    }
+
+    fn make_entry(
+        &mut self,
+        opcode: fuse_opcode,
+        parent: u64,
+        arg: &[u8],
+        name: &OsStr,
+    ) -> io::Result<Attr> {
+        let mut name_arg = name.as_bytes().to_vec();
+        name_arg.push(0);
+        let payload = self.call(opcode, parent, &[arg, &name_arg])?;
+        let out: fuse_entry_out = decode(&payload)?;
+        if let Some(inode) = self.inodes.get_mut(&parent) {
+            inode.attr = None;
+            inode.dir = None;
+        }
+        Ok(Attr(self.insert_entry((parent, name.to_owned()), &out)))
+    }
+
+    fn insert_dentry(&mut self, key: (u64, OsString), out: &fuse_entry_out) {
+        let valid = timeout(out.entry_valid, out.entry_valid_nsec);
+        if valid == Duration::from_secs(0) {
+            self.dentries.remove(&key);
+            return;
+        }
+        self.dentries.insert(
+            key,
+            Dentry {
+                ino: out.nodeid,
+                expires: self.now + valid,
+            },
+        );
+    }
+
+    fn insert_entry(&mut self, key: (u64, OsString), out: &fuse_entry_out) -> fuse_attr {
+        self.insert_dentry(key, out);
+        let expires = self.now + timeout(out.attr_valid, out.attr_valid_nsec);
+        let inode = self.inodes.entry(out.nodeid).or_default();
+        inode.nlookup += 1;
+        inode.attr = Some((out.attr, expires));
+        out.attr
+    }
+
+    fn getattr(&mut self, ino: u64) -> io::Result<fuse_attr> {
+        if let Some(&(attr, expires)) = self.inodes.get(&ino).and_then(|inode| inode.attr.as_ref())
+        {
+            if expires > self.now {
+                return Ok(attr);
+            }
+        }
+        let arg = fuse_getattr_in::default();
+        let payload = self.call(fuse_opcode::FUSE_GETATTR, ino, &[arg.as_bytes()])?;
+        let out: fuse_attr_out = decode(&payload)?;
+        let expires = self.now + timeout(out.attr_valid, out.attr_valid_nsec);
+        self.inodes.entry(ino).or_default().attr = Some((out.attr, expires));
+        Ok(out.attr)
+    }
+
+    fn setattr(&mut self, ino: u64, arg: fuse_setattr_in) -> io::Result<Attr> {
+        let payload = self.call(fuse_opcode::FUSE_SETATTR, ino, &[arg.as_bytes()]);
+        self.inodes.entry(ino).or_default().attr = None;
+        let out: fuse_attr_out = decode(&payload?)?;
+        let expires = self.now + timeout(out.attr_valid, out.attr_valid_nsec);
+        self.inodes.entry(ino).or_default().attr = Some((out.attr, expires));
+        Ok(Attr(out.attr))
+    }
+
+    fn open_inode(
+        &mut self,
+        opcode: fuse_opcode,
+        ino: u64,
+        flags: i32,
+    ) -> io::Result<fuse_open_out> {
+        let (skipped, supported) = match opcode {
+            fuse_opcode::FUSE_OPENDIR => (self.no_opendir, self.session.no_opendir_support()),
+            _ => (self.no_open, self.session.no_open_support()),
+        };
+        // The default flags of the kernel for the zero-message opens.
+        let zero_message = fuse_open_out {
+            open_flags: match opcode {
+                fuse_opcode::FUSE_OPENDIR => FOPEN_KEEP_CACHE | FOPEN_CACHE_DIR,
+                _ => FOPEN_KEEP_CACHE,
+            },
+            ..Default::default()
+        };
+        if skipped {
+            return Ok(zero_message);
+        }
+
+        let arg = fuse_open_in {
+            flags: flags as u32,
+            unused: 0,
+        };
+        match self.call(opcode, ino, &[arg.as_bytes()]) {
+            Ok(payload) => decode(&payload),
+            Err(err) if err.raw_os_error() == Some(libc::ENOSYS) && supported => {
+                match opcode {
+                    fuse_opcode::FUSE_OPENDIR => self.no_opendir = true,
+                    _ => self.no_open = true,
+                }
+                Ok(zero_message)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    fn read_raw(&mut self, file: &OpenFile, offset: u64, size: u32) -> io::Result<Vec<u8>> {
+        let arg = fuse_read_in {
+            fh: file.fh,
+            offset,
+            size,
+            ..Default::default()
+        };
+        let mut data = self.call(fuse_opcode::FUSE_READ, file.ino, &[arg.as_bytes()])?;
+        data.truncate(size as usize);
+        Ok(data)
+    }
+
+    fn read_dir_entries(&mut self, ino: u64, fh: u64) -> io::Result<Vec<DirEntry>> {
+        let mut entries = vec![];
+        let mut offset = 0;
+        loop {
+            let arg = fuse_read_in {
+                fh,
+                offset,
+                size: READDIR_SIZE,
+                ..Default::default()
+            };
+            let payload = self.call(fuse_opcode::FUSE_READDIR, ino, &[arg.as_bytes()])?;
+            if payload.is_empty() {
+                return Ok(entries);
+            }
+
+            let mut payload = &payload[..];
+            while !payload.is_empty() {
+                let dirent: fuse_dirent = decode(payload)?;
+                let name_start = mem::size_of::<fuse_dirent>();
+                let name_end = name_start + dirent.namelen as usize;
+                if payload.len() < name_end {
+                    return Err(invalid_data("the directory entry is truncated"));
+                }
+                entries.push(DirEntry {
+                    name: OsStr::from_bytes(&payload[name_start..name_end]).to_owned(),
+                    ino: dirent.ino,
+                    typ: dirent.typ,
+                });
+                offset = dirent.off;
+                let aligned = (name_end + 7) & !7;
+                payload = &payload[aligned.min(payload.len())..];
+            }
+        }
+    }
+
+    fn forget(&mut self, ino: u64, nlookup: u64) -> io::Result<()> {
+        let arg = fuse_forget_in { nlookup };
+        self.kernel
+            .send_request(fuse_opcode::FUSE_FORGET as u32, ino, arg.as_bytes())?;
+        self.dispatch(fuse_opcode::FUSE_FORGET)?;
+        Ok(())
+    }
+
+    /// Send a request, let the filesystem process it and return the payload of the reply.
+    fn call(&mut self, opcode: fuse_opcode, nodeid: u64, arg: &[&[u8]]) -> io::Result<Vec<u8>> {
+        let arg = arg.concat();
+        let unique = self.kernel.send_request(opcode as u32, nodeid, &arg)?;
+        self.dispatch(opcode)?;
+        let reply = self.kernel.recv_reply_for(unique)?;
+        match reply.error() {
+            0 => Ok(reply.payload().to_vec()),
+            err => Err(io::Error::from_raw_os_error(-err)),
+        }
+    }
+
+    fn dispatch(&mut self, opcode: fuse_opcode) -> io::Result<()> {
+        // The requests rejected by the session are replied without reaching
+        // the filesystem.
+        if let Some(req) = self.session.try_next_request()? {
+            self.requests.push(opcode as u32);
+            let fs = &mut self.fs;
+            // The error has been replied or logged by `process`.
+            let _ = req.process(|req| fs(req));
+        }
+        Ok(())
+    }
+}
+
+fn timeout(secs: u64, nsecs: u32) -> Duration {
+    Duration::new(secs, nsecs)
+}
+
+fn decode<T: FromBytes + AsBytes + Default>(payload: &[u8]) -> io::Result<T> {
+    let mut out = T::default();
+    let len = mem::size_of::<T>();
+    if payload.len() < len {
+        return Err(invalid_data("the reply is too short"));
+    }
+    out.as_bytes_mut().copy_from_slice(&payload[..len]);
+    Ok(out)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        op,
+        reply::{AttrOut, EntryOut, OpenOut, ReaddirOut},
+        util::CachePolicy,
+        Operation,
+    };
+
+    const FILE_CONTENT: &[u8] = b"hello, simulator\n";
+
+    // A filesystem with a single file `/foo`, similar to the `hello` example.
+    fn hello(cache: CachePolicy, open_flags: u32) -> impl FnMut(&Request) -> io::Result<()> {
+        fn fill_attr(out: &mut crate::reply::FileAttr, ino: u64) {
+            out.ino(ino);
+            match ino {
+                ROOT_INO => {
+                    out.mode(libc::S_IFDIR | 0o755);
+                    out.nlink(2);
+                }
+                _ => {
+                    out.mode(libc::S_IFREG | 0o644);
+                    out.nlink(1);
+                    out.size(FILE_CONTENT.len() as u64);
+                }
+            }
+        }
+
+        move |req| match req.operation().map_err(|_| invalid_data("decode"))? {
+            Operation::Lookup(op) if op.parent() == ROOT_INO && op.name() == "foo" => {
+                let mut out = EntryOut::default();
+                out.ino(2);
+                fill_attr(out.attr(), 2);
+                cache.apply(&mut out);
+                req.reply(out)
+            }
+            Operation::Lookup(..) => match cache.negative_entry() {
+                Some(out) => req.reply(out),
+                None => req.reply_error(libc::ENOENT),
+            },
+            Operation::Getattr(op) => {
+                let mut out = AttrOut::default();
+                fill_attr(out.attr(), op.ino());
+                cache.apply_attr(&mut out);
+                req.reply(out)
+            }
+            Operation::Open(..) | Operation::Opendir(..) => {
+                let mut out = OpenOut::default();
+                out.fh(42);
+                out.keep_cache(open_flags & FOPEN_KEEP_CACHE != 0);
+                out.cache_dir(open_flags & FOPEN_CACHE_DIR != 0);
+                req.reply(out)
+            }
+            Operation::Read(op) => {
+                let offset = (op.offset() as usize).min(FILE_CONTENT.len());
+                let end = (offset + op.size() as usize).min(FILE_CONTENT.len());
+                req.reply(&FILE_CONTENT[offset..end])
+            }
+            Operation::Readdir(op) => readdir(req, op),
+            Operation::Release(..) | Operation::Releasedir(..) => req.reply(()),
+            Operation::Forget(..) => Ok(()),
+            _ => req.reply_error(libc::ENOSYS),
+        }
+    }
+
+    fn readdir(req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+        let entries = [
+            (".", ROOT_INO, libc::DT_DIR),
+            ("..", ROOT_INO, libc::DT_DIR),
+            ("foo", 2, libc::DT_REG),
+        ];
+        let mut out = ReaddirOut::with_offset(op.size() as usize, op.offset());
+        for &(name, ino, typ) in entries.iter().skip(op.offset() as usize) {
+            if out.next_entry(name.as_ref(), ino, typ as u32) {
+                break;
+            }
+        }
+        req.reply(out)
+    }
+
+    #[test]
+    fn lookups_are_cached_until_expired() {
+        let cache = CachePolicy::short();
+        let mut sim = Simulator::new(KernelConfig::default(), hello(cache, 0)).unwrap();
+
+        let attr = sim.stat("/foo").unwrap();
+        assert_eq!((attr.ino(), attr.size()), (2, FILE_CONTENT.len() as u64));
+        assert_eq!(sim.count(fuse_opcode::FUSE_LOOKUP as u32), 1);
+        assert_eq!(sim.requests(), 1);
+
+        // Within the timeouts, neither LOOKUP nor GETATTR reaches the filesystem.
+        sim.stat("/foo").unwrap();
+        assert_eq!(sim.requests(), 1);
+
+        // The expired dentry is revalidated, which counts another lookup.
+        sim.advance(Duration::from_secs(2));
+        sim.stat("/foo").unwrap();
+        assert_eq!(sim.count(fuse_opcode::FUSE_LOOKUP as u32), 2);
+        assert_eq!(sim.nlookup(2), 2);
+
+        // The negative entries are also cached.
+        let err = sim.stat("/bar").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        sim.stat("/bar").unwrap_err();
+        assert_eq!(sim.count(fuse_opcode::FUSE_LOOKUP as u32), 3);
+    }
+
+    #[test]
+    fn forget_on_eviction() {
+        let mut config = KernelConfig::default();
+        config.lookup_audit(true);
+        let mut sim = Simulator::new(config, hello(CachePolicy::never(), 0)).unwrap();
+
+        sim.lookup("/foo").unwrap();
+        sim.lookup("/foo").unwrap();
+        assert_eq!(sim.count(fuse_opcode::FUSE_LOOKUP as u32), 2);
+        assert_eq!(sim.nlookup(2), 2);
+        assert_eq!(sim.session().lookup_counts().unwrap().get(2), 2);
+
+        sim.drop_caches().unwrap();
+        assert_eq!(sim.count(fuse_opcode::FUSE_FORGET as u32), 1);
+        assert_eq!(sim.nlookup(2), 0);
+        assert_eq!(sim.session().lookup_counts().unwrap().get(2), 0);
+    }
+
+    #[test]
+    fn reads_through_page_cache() {
+        let cache = CachePolicy::immutable();
+        let mut sim =
+            Simulator::new(KernelConfig::default(), hello(cache, FOPEN_KEEP_CACHE)).unwrap();
+
+        let file = sim.open("/foo", libc::O_RDONLY).unwrap();
+        assert_eq!(sim.read(&file, 0, 5).unwrap(), b"hello");
+        assert_eq!(sim.read(&file, 7, 100).unwrap(), &FILE_CONTENT[7..]);
+        assert_eq!(sim.count(fuse_opcode::FUSE_READ as u32), 1);
+        sim.release(file).unwrap();
+
+        // The pages are kept across opens with FOPEN_KEEP_CACHE.
+        let file = sim.open("/foo", libc::O_RDONLY).unwrap();
+        assert_eq!(sim.read(&file, 0, 100).unwrap(), FILE_CONTENT);
+        assert_eq!(sim.count(fuse_opcode::FUSE_READ as u32), 1);
+        assert_eq!(sim.count(fuse_opcode::FUSE_LOOKUP as u32), 1);
+
+        let mut sim = Simulator::new(KernelConfig::default(), hello(cache, 0)).unwrap();
+        for _ in 0..2 {
+            let file = sim.open("/foo", libc::O_RDONLY).unwrap();
+            assert_eq!(sim.read(&file, 0, 100).unwrap(), FILE_CONTENT);
+            sim.release(file).unwrap();
+        }
+        assert_eq!(sim.count(fuse_opcode::FUSE_READ as u32), 2);
+    }
+
+    #[test]
+    fn readdir_cache() {
+        let cache = CachePolicy::immutable();
+        let flags = FOPEN_KEEP_CACHE | FOPEN_CACHE_DIR;
+        let mut sim = Simulator::new(KernelConfig::default(), hello(cache, flags)).unwrap();
+
+        for _ in 0..2 {
+            let names: Vec<_> = sim
+                .readdir("/")
+                .unwrap()
+                .into_iter()
+                .map(|entry| entry.name)
+                .collect();
+            assert_eq!(names, [".", "..", "foo"]);
+        }
+        assert_eq!(sim.count(fuse_opcode::FUSE_OPENDIR as u32), 2);
+        assert_eq!(sim.count(fuse_opcode::FUSE_READDIR as u32), 2);
+        assert_eq!(sim.count(fuse_opcode::FUSE_RELEASEDIR as u32), 2);
+    }
+}
@@ -0,0 +1,53 @@
+//! Miscellaneous utilities for implementing filesystems.
+
+mod aligned;
+mod attr;
+mod cache;
+mod clock;
+mod dir;
+mod dirty;
+mod dispatch;
+mod flight;
+mod handles;
+mod inode_locks;
+mod inode_numbers;
+mod ioctl;
+mod name;
+pub(crate) mod num;
+#[cfg(feature = "notify")]
+mod poll;
+mod rate;
+mod size_epoch;
+mod statfs;
+mod transaction;
+mod xattr;
+
+pub use crate::conn::{recv_fd, send_fd};
+
+pub use self::{
+    aligned::AlignedBuf,
+    attr::AttrCache,
+    cache::CachePolicy,
+    clock::{Clock, ManualClock, SystemClock},
+    dir::{DirPager, DirSnapshot},
+    dirty::DirtyTracker,
+    dispatch::{DispatchHint, Dispatcher},
+    flight::LookupFlights,
+    handles::{HandleError, HandlePolicy, HandleReservation, HandleTable},
+    inode_locks::{InodeGuard, InodeLocks, InodeTicket},
+    inode_numbers::InodeNumbers,
+    ioctl::{
+        InodeFlags, FS_IOC32_GETFLAGS, FS_IOC32_GETVERSION, FS_IOC32_SETFLAGS, FS_IOC32_SETVERSION,
+        FS_IOC_FSGETXATTR, FS_IOC_FSSETXATTR, FS_IOC_GETFLAGS, FS_IOC_GETVERSION, FS_IOC_SETFLAGS,
+        FS_IOC_SETVERSION,
+    },
+    name::{validate_lookup_name, validate_name, AsNameBytes, DisplayName, NAME_MAX},
+    rate::RateLimit,
+    size_epoch::SizeEpoch,
+    statfs::CachedStatfs,
+    transaction::{transact, TransactionHook, TransactionOutcome},
+    xattr::XattrProbeCache,
+};
+
+#[cfg(feature = "notify")]
+pub use self::poll::PollRegistry;
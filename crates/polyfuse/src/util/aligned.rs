@@ -0,0 +1,198 @@
+use crate::bytes::{Bytes, FillBytes};
+use std::{
+    alloc::{self, Layout},
+    fmt, ops,
+    ptr::NonNull,
+    slice,
+};
+
+/// A byte buffer whose start is aligned to the page size.
+///
+/// The data replied to `READ` requests are copied by the kernel into the
+/// page cache or the buffer of the application, and an application using
+/// `O_DIRECT` expects the data to be handled in units of pages.  Reading the
+/// data from the backend into this buffer, and replying it as it is, keeps
+/// the payload page-aligned without any intermediate copy on the side of
+/// the library, since `Bytes` passes the buffer to the writer as a single
+/// chunk.
+///
+/// The capacity is rounded up to the alignment, so the buffer can be reused
+/// for the replies of up to `max_write` bytes, which is a multiple of the
+/// page size.
+pub struct AlignedBuf {
+    ptr: NonNull<u8>,
+    len: usize,
+    layout: Layout,
+}
+
+// The buffer is uniquely owned as `Vec<u8>`.
+unsafe impl Send for AlignedBuf {}
+unsafe impl Sync for AlignedBuf {}
+
+impl fmt::Debug for AlignedBuf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AlignedBuf")
+            .field("len", &self.len)
+            .field("capacity", &self.capacity())
+            .field("alignment", &self.alignment())
+            .finish()
+    }
+}
+
+impl AlignedBuf {
+    /// Return the preferred alignment of the reply data, i.e. the page size.
+    pub fn page_size() -> usize {
+        unsafe { libc::sysconf(libc::_SC_PAGESIZE) as usize }
+    }
+
+    /// Create a zero-filled buffer of `len` bytes, aligned to the page size.
+    pub fn new(len: usize) -> Self {
+        Self::with_alignment(len, Self::page_size())
+    }
+
+    /// Create a zero-filled buffer of `len` bytes with the specified alignment.
+    ///
+    /// # Panics
+    /// Panics if `align` is not a power of two.
+    pub fn with_alignment(len: usize, align: usize) -> Self {
+        assert!(align.is_power_of_two(), "alignment must be a power of two");
+        let capacity = (len.max(1) + align - 1) & !(align - 1);
+        let layout = Layout::from_size_align(capacity, align).expect("too large buffer");
+        let ptr = unsafe { alloc::alloc_zeroed(layout) };
+        let ptr = NonNull::new(ptr).unwrap_or_else(|| alloc::handle_alloc_error(layout));
+        Self { ptr, len, layout }
+    }
+
+    /// Return the length of the data.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Return whether the buffer is empty.
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Return the number of bytes the buffer can hold without reallocation.
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.layout.size()
+    }
+
+    /// Return the alignment of the start of the buffer.
+    #[inline]
+    pub fn alignment(&self) -> usize {
+        self.layout.align()
+    }
+
+    /// Shorten the buffer, e.g. to the length actually read from the backend.
+    ///
+    /// This has no effect if `len` is greater than the current length.
+    #[inline]
+    pub fn truncate(&mut self, len: usize) {
+        self.len = self.len.min(len);
+    }
+
+    /// Change the length within the capacity, filling the extended area with zeros.
+    ///
+    /// # Panics
+    /// Panics if `len` exceeds the capacity.
+    pub fn resize(&mut self, len: usize) {
+        assert!(len <= self.capacity(), "length exceeds the capacity");
+        if len > self.len {
+            unsafe {
+                self.ptr
+                    .as_ptr()
+                    .add(self.len)
+                    .write_bytes(0, len - self.len);
+            }
+        }
+        self.len = len;
+    }
+}
+
+impl Drop for AlignedBuf {
+    fn drop(&mut self) {
+        unsafe { alloc::dealloc(self.ptr.as_ptr(), self.layout) }
+    }
+}
+
+impl ops::Deref for AlignedBuf {
+    type Target = [u8];
+
+    #[inline]
+    fn deref(&self) -> &Self::Target {
+        unsafe { slice::from_raw_parts(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl ops::DerefMut for AlignedBuf {
+    #[inline]
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { slice::from_raw_parts_mut(self.ptr.as_ptr(), self.len) }
+    }
+}
+
+impl AsRef<[u8]> for AlignedBuf {
+    #[inline]
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
+impl AsMut<[u8]> for AlignedBuf {
+    #[inline]
+    fn as_mut(&mut self) -> &mut [u8] {
+        self
+    }
+}
+
+impl Bytes for AlignedBuf {
+    #[inline]
+    fn size(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    fn count(&self) -> usize {
+        if self.is_empty() {
+            0
+        } else {
+            1
+        }
+    }
+
+    fn fill_bytes<'a>(&'a self, dst: &mut dyn FillBytes<'a>) {
+        if !self.is_empty() {
+            dst.put(self);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aligned_buffer() {
+        let page_size = AlignedBuf::page_size();
+        let mut buf = AlignedBuf::new(100);
+        assert_eq!(buf.as_ptr() as usize % page_size, 0);
+        assert_eq!((buf.len(), buf.capacity()), (100, page_size));
+        assert!(buf.iter().all(|&b| b == 0));
+
+        buf[..5].copy_from_slice(b"hello");
+        buf.truncate(5);
+        assert_eq!(&buf[..], b"hello");
+        assert_eq!(crate::bytes::to_vec(&buf), b"hello");
+        buf.resize(8);
+        assert_eq!(&buf[..], b"hello\0\0\0");
+
+        let empty = AlignedBuf::with_alignment(0, 64);
+        assert_eq!((empty.count(), empty.capacity()), (0, 64));
+        let buf = AlignedBuf::with_alignment(65, 64);
+        assert_eq!(buf.capacity(), 128);
+    }
+}
@@ -0,0 +1,275 @@
+use super::clock::{Clock, SystemClock};
+use crate::{
+    reply::{AttrOut, EntryOut, FileAttr},
+    Operation,
+};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// A cache of the attributes replied to the kernel, for answering `GETATTR`.
+///
+/// The kernel caches the attributes of an entry for its `attr_valid`, but
+/// it still sends `GETATTR` for the inodes it has not looked up through
+/// the same path, e.g. right after `READDIRPLUS` or when `stat(2)` races
+/// with the expiry.  The filesystem already knows these attributes, so
+/// this cache remembers the ones replied by `insert_entry` and serves the
+/// following `GETATTR` from them until the `attr_valid` of the reply
+/// expires, saving the `stat` on the backend.
+///
+/// The attributes may change by the requests on the same inode, so
+/// `observe` should be called with every operation before processing it.
+/// It discards the attributes of the inodes changed by `SETATTR`, `WRITE`,
+/// `FALLOCATE`, `COPY_FILE_RANGE` and `LINK`, and of the inodes forgotten
+/// by the kernel.  The operations changing the inodes without telling
+/// their numbers, such as `UNLINK` of the last link, require an explicit
+/// `invalidate`.  The attributes replied with the zero `attr_valid` are
+/// never cached.
+pub struct AttrCache {
+    clock: Box<dyn Clock>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<u64, (Instant, FileAttr)>,
+    // Incremented on every invalidation, so that the attributes fetched
+    // before it are not inserted afterwards.
+    epoch: u64,
+}
+
+impl fmt::Debug for AttrCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("AttrCache")
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl Default for AttrCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl AttrCache {
+    /// Create an empty cache.
+    pub fn new() -> Self {
+        Self {
+            clock: Box::new(SystemClock),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Measure the expiry with `clock` instead of the system clock.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Return the number of inodes currently remembered, including the expired ones.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Return whether no inode is remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Remember the attributes of the entry replied to `LOOKUP`, `READDIRPLUS`
+    /// or the requests creating a node, until its `attr_valid` expires.
+    ///
+    /// Negative entries are ignored.
+    pub fn insert_entry(&self, entry: &EntryOut) {
+        if entry.nodeid() == 0 {
+            return;
+        }
+        let epoch = self.state.lock().unwrap().epoch;
+        self.insert(epoch, entry.nodeid(), entry.attr_ref(), entry.attr_valid());
+    }
+
+    /// Return the remembered attributes of the inode, with the remaining
+    /// time of the validity as their `attr_valid`.
+    pub fn get(&self, ino: u64) -> Option<AttrOut> {
+        let mut state = self.state.lock().unwrap();
+        let &(expires, attr) = state.entries.get(&ino)?;
+        let now = self.clock.instant();
+        if now >= expires {
+            state.entries.remove(&ino);
+            return None;
+        }
+        let mut out = AttrOut::default();
+        *out.attr() = attr;
+        out.ttl(expires - now);
+        Some(out)
+    }
+
+    /// Return the attributes of the inode requested by `GETATTR`, fetching
+    /// them with `fetch` unless remembered.
+    ///
+    /// The fetched attributes are remembered until their `attr_valid`.
+    pub fn fetch<F>(&self, ino: u64, fetch: F) -> io::Result<AttrOut>
+    where
+        F: FnOnce() -> io::Result<AttrOut>,
+    {
+        if let Some(out) = self.get(ino) {
+            return Ok(out);
+        }
+        let epoch = self.state.lock().unwrap().epoch;
+        let out = fetch()?;
+        self.insert(epoch, ino, out.attr_ref(), out.attr_valid());
+        Ok(out)
+    }
+
+    /// Discard the remembered attributes of the inode.
+    pub fn invalidate(&self, ino: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch = state.epoch.wrapping_add(1);
+        state.entries.remove(&ino);
+    }
+
+    /// Discard the attributes of the inodes changed or forgotten by the operation.
+    pub fn observe<T>(&self, op: &Operation<'_, T>) {
+        match op {
+            Operation::Setattr(op) => self.invalidate(op.ino()),
+            Operation::Write(op, _) => self.invalidate(op.ino()),
+            Operation::Fallocate(op) => self.invalidate(op.ino()),
+            Operation::CopyFileRange(op) => self.invalidate(op.ino_out()),
+            Operation::Link(op) => self.invalidate(op.ino()),
+            Operation::Forget(forgets) => {
+                let mut state = self.state.lock().unwrap();
+                state.epoch = state.epoch.wrapping_add(1);
+                for forget in forgets.iter() {
+                    state.entries.remove(&forget.ino());
+                }
+            }
+            _ => (),
+        }
+    }
+
+    fn insert(&self, epoch: u64, ino: u64, attr: &FileAttr, ttl: Duration) {
+        if ttl == Duration::from_secs(0) {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return;
+        }
+        let now = self.clock.instant();
+        state.entries.insert(ino, (now + ttl, *attr));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, util::ManualClock, KernelConfig};
+    use polyfuse_kernel::*;
+    use std::cell::Cell;
+    use zerocopy::AsBytes as _;
+
+    fn entry(ino: u64, size: u64, ttl: Duration) -> EntryOut {
+        let mut out = EntryOut::default();
+        out.ino(ino);
+        out.attr().ino(ino);
+        out.attr().size(size);
+        out.ttl_attr(ttl);
+        out
+    }
+
+    #[test]
+    fn expire_with_attr_valid() {
+        let clock = ManualClock::default();
+        let cache = AttrCache::new().with_clock(clock.clone());
+        cache.insert_entry(&entry(2, 42, Duration::from_secs(1)));
+        cache.insert_entry(&entry(3, 42, Duration::from_secs(0)));
+        cache.insert_entry(&entry(0, 42, Duration::from_secs(1)));
+        assert_eq!(cache.len(), 1);
+
+        clock.advance(Duration::from_millis(400));
+        let out = cache.get(2).unwrap();
+        assert_eq!(out.attr_valid(), Duration::from_millis(600));
+        let mut expected = FileAttr::default();
+        expected.ino(2);
+        expected.size(42);
+        assert_eq!(*out.attr_ref(), expected);
+
+        clock.advance(Duration::from_millis(600));
+        assert!(cache.get(2).is_none());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn fetch_and_invalidate() {
+        let clock = ManualClock::default();
+        let cache = AttrCache::new().with_clock(clock.clone());
+        let fetches = Cell::new(0u64);
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            let mut out = AttrOut::default();
+            out.attr().size(fetches.get());
+            out.ttl(Duration::from_secs(1));
+            Ok(out)
+        };
+
+        assert_eq!(cache.fetch(2, fetch).unwrap().attr_valid().as_secs(), 1);
+        cache.fetch(2, fetch).unwrap();
+        assert_eq!(fetches.get(), 1);
+
+        clock.advance(Duration::from_secs(1));
+        cache.fetch(2, fetch).unwrap();
+        assert_eq!(fetches.get(), 2);
+
+        // The attributes fetched across an invalidation are not remembered.
+        let epoch = cache.state.lock().unwrap().epoch;
+        cache.invalidate(2);
+        cache.insert(epoch, 2, &FileAttr::default(), Duration::from_secs(1));
+        assert!(cache.is_empty());
+        assert!(cache
+            .fetch(2, || Err(io::Error::from_raw_os_error(libc::EIO)))
+            .is_err());
+        assert!(cache.is_empty());
+    }
+
+    #[test]
+    fn observe_changes() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let cache = AttrCache::new();
+        for ino in 2..=4 {
+            cache.insert_entry(&entry(ino, 0, Duration::from_secs(3600)));
+        }
+
+        let observe = |opcode: fuse_opcode, ino: u64, arg: &[u8]| {
+            kernel.send_request(opcode as u32, ino, arg).unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            cache.observe(&req.operation().unwrap());
+        };
+
+        observe(
+            fuse_opcode::FUSE_GETATTR,
+            2,
+            fuse_getattr_in::default().as_bytes(),
+        );
+        assert_eq!(cache.len(), 3);
+        observe(
+            fuse_opcode::FUSE_SETATTR,
+            2,
+            fuse_setattr_in::default().as_bytes(),
+        );
+        assert!(cache.get(2).is_none());
+        observe(
+            fuse_opcode::FUSE_FORGET,
+            3,
+            fuse_forget_in { nlookup: 1 }.as_bytes(),
+        );
+        assert!(cache.get(3).is_none());
+        assert!(cache.get(4).is_some());
+    }
+}
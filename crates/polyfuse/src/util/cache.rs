@@ -0,0 +1,120 @@
+use crate::{
+    reply::{AttrOut, EntryOut},
+    KernelConfig,
+};
+use std::time::Duration;
+
+/// The policy of validity timeouts of the entry and attribute caches in the kernel.
+///
+/// The policy gathers the timeouts that the filesystem replies to the kernel,
+/// so that they are chosen consistently across the operations.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct CachePolicy {
+    /// The validity timeout for the names of directory entries.
+    pub entry_ttl: Duration,
+
+    /// The validity timeout for the inode attributes.
+    pub attr_ttl: Duration,
+
+    /// The validity timeout for the negative entries.
+    ///
+    /// If this value is `None`, the lookups of missing entries are replied
+    /// with `ENOENT` and thus they are not cached by the kernel.
+    pub negative_ttl: Option<Duration>,
+
+    /// Whether the kernel automatically invalidates the cached pages
+    /// when the modification time of a file is changed.
+    pub use_auto_inval: bool,
+}
+
+impl Default for CachePolicy {
+    fn default() -> Self {
+        Self::short()
+    }
+}
+
+impl CachePolicy {
+    /// Create a policy that the kernel never caches anything.
+    ///
+    /// This is appropriate when the contents of filesystem are changed
+    /// from outside of the FUSE requests at any time.
+    pub const fn never() -> Self {
+        Self {
+            entry_ttl: Duration::from_secs(0),
+            attr_ttl: Duration::from_secs(0),
+            negative_ttl: None,
+            use_auto_inval: false,
+        }
+    }
+
+    /// Create a policy that the kernel caches everything for a very long time.
+    ///
+    /// This is appropriate when the filesystem is read-only, or all of the
+    /// changes are caused only by the FUSE requests.
+    pub const fn immutable() -> Self {
+        const TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+        Self {
+            entry_ttl: TTL,
+            attr_ttl: TTL,
+            negative_ttl: Some(TTL),
+            use_auto_inval: false,
+        }
+    }
+
+    /// Create a policy with short (1 second) timeouts, like the defaults of libfuse.
+    pub const fn short() -> Self {
+        const TTL: Duration = Duration::from_secs(1);
+        Self {
+            entry_ttl: TTL,
+            attr_ttl: TTL,
+            negative_ttl: Some(TTL),
+            use_auto_inval: true,
+        }
+    }
+
+    /// Apply the concerned kernel parameters to the configuration.
+    pub fn apply_config(&self, config: &mut KernelConfig) {
+        config.auto_inval_data(self.use_auto_inval);
+    }
+
+    /// Set the validity timeouts of an entry reply.
+    pub fn apply(&self, out: &mut EntryOut) {
+        out.ttl_entry(self.entry_ttl);
+        out.ttl_attr(self.attr_ttl);
+    }
+
+    /// Set the validity timeout of an attribute reply.
+    pub fn apply_attr(&self, out: &mut AttrOut) {
+        out.ttl(self.attr_ttl);
+    }
+
+    /// Create a negative entry reply, if the negative entries are cached.
+    ///
+    /// If the returned value is `None`, the filesystem should reply `ENOENT` instead.
+    pub fn negative_entry(&self) -> Option<EntryOut> {
+        let ttl = self.negative_ttl?;
+        let mut out = EntryOut::default();
+        out.ino(0);
+        out.ttl_entry(ttl);
+        Some(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::bytes::Bytes;
+
+    #[test]
+    fn negative_entry() {
+        assert!(CachePolicy::never().negative_entry().is_none());
+
+        let out = CachePolicy::short()
+            .negative_entry()
+            .expect("negative entry is disabled");
+        assert_eq!(
+            out.size(),
+            std::mem::size_of::<polyfuse_kernel::fuse_entry_out>()
+        );
+    }
+}
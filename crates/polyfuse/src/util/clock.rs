@@ -0,0 +1,81 @@
+use std::{
+    fmt,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+
+/// A source of the current time.
+///
+/// The utilities with timeouts and the filesystems setting the timestamps
+/// of inodes take the time from a `Clock`, so that the tests can replace
+/// it with `ManualClock` and advance the time deterministically.
+pub trait Clock: Send + Sync {
+    /// Return the current wall-clock time, e.g. for `st_mtime`.
+    fn now(&self) -> SystemTime;
+
+    /// Return the current monotonic time, for measuring the timeouts.
+    fn instant(&self) -> Instant;
+}
+
+/// The clock of the system.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+
+    fn instant(&self) -> Instant {
+        Instant::now()
+    }
+}
+
+/// A clock that advances only when told to, for tests.
+///
+/// The clones share the same time, so a clone can be passed to the code
+/// under test while the test advances the original.
+#[derive(Clone)]
+pub struct ManualClock {
+    origin: (SystemTime, Instant),
+    elapsed: Arc<Mutex<Duration>>,
+}
+
+impl fmt::Debug for ManualClock {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ManualClock")
+            .field("now", &self.now())
+            .finish()
+    }
+}
+
+impl Default for ManualClock {
+    fn default() -> Self {
+        Self::new(SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000_000))
+    }
+}
+
+impl ManualClock {
+    /// Create a clock stopped at `now`.
+    pub fn new(now: SystemTime) -> Self {
+        Self {
+            origin: (now, Instant::now()),
+            elapsed: Arc::default(),
+        }
+    }
+
+    /// Advance the time by `duration`.
+    pub fn advance(&self, duration: Duration) {
+        *self.elapsed.lock().unwrap() += duration;
+    }
+}
+
+impl Clock for ManualClock {
+    fn now(&self) -> SystemTime {
+        self.origin.0 + *self.elapsed.lock().unwrap()
+    }
+
+    fn instant(&self) -> Instant {
+        self.origin.1 + *self.elapsed.lock().unwrap()
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    collections::BTreeMap,
+    convert::TryFrom,
+    fmt, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+};
+
+/// A snapshot of directory entries, taken when the directory is opened.
+///
+/// Reading the entries directly from the live directory at every `READDIR`
+/// request may produce duplicates or omissions if the directory is modified
+/// between the requests on the same handle.  This snapshot serves all of the
+/// offsets from the list taken at `OPENDIR`, and takes a new one when the
+/// directory is read from the offset 0 again (i.e. `rewinddir(3)`), as
+/// required by POSIX.
+///
+/// The offset of each entry is its index in the list plus one, so the entry
+/// at `entries[i]` passed to the closure of `read` should be replied with
+/// the offset `offset + i + 1`.
+pub struct DirSnapshot<T> {
+    entries: Mutex<Vec<T>>,
+    fresh: AtomicBool,
+}
+
+impl<T> fmt::Debug for DirSnapshot<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirSnapshot").finish()
+    }
+}
+
+impl<T> DirSnapshot<T> {
+    /// Create a snapshot from the entries of the opened directory.
+    pub fn new(entries: Vec<T>) -> Self {
+        Self {
+            entries: Mutex::new(entries),
+            fresh: AtomicBool::new(true),
+        }
+    }
+
+    /// Pass the entries after `offset` in the snapshot to `f`.
+    ///
+    /// If the directory is read from the beginning again, the snapshot is
+    /// replaced with the entries obtained from `refresh` in advance.  The
+    /// first read after creating the snapshot does not refresh it.
+    pub fn read<R>(
+        &self,
+        offset: u64,
+        refresh: impl FnOnce() -> io::Result<Vec<T>>,
+        f: impl FnOnce(&[T]) -> R,
+    ) -> io::Result<R> {
+        let mut entries = self.entries.lock().unwrap();
+        let fresh = self.fresh.swap(false, Ordering::SeqCst);
+        if offset == 0 && !fresh {
+            *entries = refresh()?;
+        }
+        let offset = usize::try_from(offset).unwrap_or(usize::MAX);
+        Ok(f(entries.get(offset..).unwrap_or(&[])))
+    }
+}
+
+/// A page of directory entries fetched from the backend.
+struct Page<T, C> {
+    // The offset of the entry before the first one in this page.
+    base: u64,
+    entries: Vec<T>,
+    // The cursor to fetch the next page, or `None` at the end.
+    next: Option<C>,
+}
+
+struct PagerState<T, C> {
+    page: Option<Page<T, C>>,
+    // The cursors to fetch the pages after the first one, keyed by `base`.
+    cursors: BTreeMap<u64, C>,
+}
+
+/// The directory entries fetched from the backend page by page.
+///
+/// `DirSnapshot` holds all of the entries in memory, which is not
+/// feasible for the directories with millions of entries.  This fetches
+/// the entries lazily from a paged backend as the offset of `READDIR`
+/// advances, and keeps only the latest page in memory.
+///
+/// The backend is a function receiving the cursor to resume from (`None`
+/// for the beginning) and the number of entries desired, and returning a
+/// page of entries and the cursor of the next page (`None` at the end).
+/// The cursors at the page boundaries are kept for the life of the
+/// handle, so that an offset replied earlier can be resumed after its page
+/// has been dropped, e.g. by `seekdir(3)`.  Reading from the offset 0
+/// restarts from the beginning of the backend, as with `DirSnapshot`.
+///
+/// The offset of each entry is assigned in the same way as `DirSnapshot`:
+/// the entry at `entries[i]` passed to the closure of `read` should be
+/// replied with the offset `offset + i + 1`.
+pub struct DirPager<T, C> {
+    state: Mutex<PagerState<T, C>>,
+    page_size: usize,
+}
+
+impl<T, C> fmt::Debug for DirPager<T, C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DirPager")
+            .field("page_size", &self.page_size)
+            .finish()
+    }
+}
+
+impl<T, C> DirPager<T, C>
+where
+    C: Clone,
+{
+    /// Create a pager fetching about `page_size` entries at once.
+    pub fn new(page_size: usize) -> Self {
+        Self {
+            state: Mutex::new(PagerState {
+                page: None,
+                cursors: BTreeMap::new(),
+            }),
+            page_size,
+        }
+    }
+
+    /// Pass the entries after `offset` to `f`, fetching the pages from
+    /// the backend with `fetch` as needed.
+    ///
+    /// The entries passed to `f` are those remaining in the page, so `f`
+    /// may receive fewer entries than fit in the reply.  The kernel then
+    /// reads again from the next offset, which fetches the next page.
+    pub fn read<F, R>(&self, offset: u64, mut fetch: F, f: impl FnOnce(&[T]) -> R) -> io::Result<R>
+    where
+        F: FnMut(Option<&C>, usize) -> io::Result<(Vec<T>, Option<C>)>,
+    {
+        let mut state = self.state.lock().unwrap();
+        let state = &mut *state;
+        if offset == 0 {
+            // rewinddir(3)
+            state.page = None;
+            state.cursors.clear();
+        }
+
+        loop {
+            let (base, cursor) = match state.page {
+                Some(ref page) => {
+                    let end = page.base + page.entries.len() as u64;
+                    if page.base <= offset && offset < end {
+                        let start = usize::try_from(offset - page.base).unwrap();
+                        return Ok(f(&page.entries[start..]));
+                    }
+                    match page.next {
+                        _ if offset < page.base => resume_at(&state.cursors, offset),
+                        Some(ref next) => {
+                            state.cursors.insert(end, next.clone());
+                            (end, Some(next.clone()))
+                        }
+                        None => return Ok(f(&[])),
+                    }
+                }
+                None => resume_at(&state.cursors, offset),
+            };
+
+            let (entries, next) = fetch(cursor.as_ref(), self.page_size)?;
+            state.page = Some(Page {
+                base,
+                entries,
+                next,
+            });
+        }
+    }
+}
+
+/// Find the page boundary at or before `offset` to resume the backend.
+fn resume_at<C: Clone>(cursors: &BTreeMap<u64, C>, offset: u64) -> (u64, Option<C>) {
+    match cursors.range(..=offset).next_back() {
+        Some((&base, cursor)) => (base, Some(cursor.clone())),
+        None => (0, None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn consistent_between_batches() {
+        let live = Mutex::new(vec!["a", "b", "c", "d"]);
+        let refresh = || Ok(live.lock().unwrap().clone());
+        let snapshot = DirSnapshot::new(refresh().unwrap());
+
+        let mut seen = vec![];
+        let next = snapshot
+            .read(0, refresh, |entries| {
+                seen.extend_from_slice(&entries[..2]);
+                2
+            })
+            .unwrap();
+
+        // Modify the directory between the batches.
+        live.lock()
+            .unwrap()
+            .retain(|name| *name != "a" && *name != "c");
+        live.lock().unwrap().insert(0, "e");
+
+        snapshot
+            .read(next, refresh, |entries| seen.extend_from_slice(entries))
+            .unwrap();
+        assert_eq!(seen, ["a", "b", "c", "d"]);
+
+        // Rewinding takes a new snapshot.
+        let entries = snapshot.read(0, refresh, |entries| entries.to_vec());
+        assert_eq!(entries.unwrap(), ["e", "b", "d"]);
+        let entries = snapshot.read(10, refresh, |entries| entries.to_vec());
+        assert!(entries.unwrap().is_empty());
+    }
+
+    type TestPage = (Vec<String>, Option<usize>);
+
+    // A backend of `len` entries, whose cursor is the index of the next entry.
+    fn backend(
+        len: usize,
+        fetches: &Mutex<Vec<Option<usize>>>,
+    ) -> impl FnMut(Option<&usize>, usize) -> io::Result<TestPage> + '_ {
+        move |cursor, hint| {
+            fetches.lock().unwrap().push(cursor.copied());
+            let start = cursor.copied().unwrap_or(0);
+            let end = (start + hint).min(len);
+            let entries = (start..end).map(|i| format!("entry-{:06}", i)).collect();
+            Ok((entries, if end < len { Some(end) } else { None }))
+        }
+    }
+
+    #[test]
+    fn read_large_directory_by_pages() {
+        use crate::reply::ReaddirOut;
+        use std::ffi::OsStr;
+
+        const LEN: usize = 100_000;
+        let fetches = Mutex::new(vec![]);
+        let pager = DirPager::new(1000);
+
+        let mut offset = 0;
+        let mut seen = 0;
+        loop {
+            let mut out = ReaddirOut::with_offset(32 * 1024, offset);
+            pager
+                .read(offset, backend(LEN, &fetches), |entries| {
+                    for (i, name) in entries.iter().enumerate() {
+                        assert_eq!(*name, format!("entry-{:06}", offset as usize + i));
+                        if out.next_entry(OsStr::new(name), 10 + offset + i as u64, 0) {
+                            break;
+                        }
+                    }
+                })
+                .unwrap();
+            if out.last_offset() == offset {
+                break;
+            }
+            seen += (out.last_offset() - offset) as usize;
+            offset = out.last_offset();
+        }
+        assert_eq!(seen, LEN);
+        // Each page is fetched only once.
+        assert_eq!(fetches.lock().unwrap().len(), LEN / 1000);
+
+        // Resuming an old offset fetches its page again from the boundary.
+        fetches.lock().unwrap().clear();
+        let name = pager
+            .read(2500, backend(LEN, &fetches), |entries| entries[0].clone())
+            .unwrap();
+        assert_eq!(name, "entry-002500");
+        assert_eq!(*fetches.lock().unwrap(), [Some(2000)]);
+
+        // Rewinding restarts the backend.
+        fetches.lock().unwrap().clear();
+        let name = pager
+            .read(0, backend(LEN, &fetches), |entries| entries[0].clone())
+            .unwrap();
+        assert_eq!(name, "entry-000000");
+        assert_eq!(*fetches.lock().unwrap(), [None]);
+    }
+}
@@ -0,0 +1,233 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Mutex, MutexGuard},
+};
+
+/// Tracks the handles and inodes written since the last synchronization.
+///
+/// The kernel sends `FLUSH` on every `close(2)` of a file descriptor,
+/// including the ones duplicated by `dup(2)` or inherited by `fork(2)`, so
+/// that the filesystem can write back the data buffered for that handle and
+/// report the errors to the closing process.  It says nothing about
+/// durability.  `FSYNC` and `FSYNCDIR`, on the other hand, request that the
+/// data and metadata of the whole inode reach the storage, no matter which
+/// handle they were written with.  Therefore the state is kept at both
+/// levels: `take_dirty` answers the former and `take_dirty_ino` the latter.
+///
+/// The writes mark both the data and the metadata (the size and the
+/// modification time) of the inode, while the changes of the attributes or
+/// the directory entries mark only the metadata.  A sync with `datasync`
+/// set, i.e. `fdatasync(2)`, consumes only the data part.
+///
+/// The handlers should mark the state after the change has been applied to
+/// the backend and take it before starting the synchronization.  Then a
+/// write interleaved with a sync is either covered by that sync or left
+/// dirty for the next one.  The state is cleared even if the following
+/// synchronization fails; mark it again to have it retried.
+pub struct DirtyTracker {
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    // The handles written since the last flush, mapped to their inodes.
+    handles: HashMap<u64, u64>,
+    inodes: HashMap<u64, Dirty>,
+}
+
+#[derive(Copy, Clone, Default)]
+struct Dirty {
+    data: bool,
+    metadata: bool,
+}
+
+impl fmt::Debug for DirtyTracker {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let state = self.state();
+        f.debug_struct("DirtyTracker")
+            .field("handles", &state.handles.len())
+            .field("inodes", &state.inodes.len())
+            .finish()
+    }
+}
+
+impl Default for DirtyTracker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DirtyTracker {
+    /// Create a tracker with no dirty handles.
+    pub fn new() -> Self {
+        Self {
+            state: Mutex::default(),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    /// Record that the data of `ino` has been written through the handle `fh`.
+    ///
+    /// `fh` is `None` for the writes to the files opened without sending
+    /// `OPEN`, which only mark the inode.
+    pub fn mark_dirty(&self, fh: Option<u64>, ino: u64) {
+        let mut state = self.state();
+        if let Some(fh) = fh {
+            state.handles.insert(fh, ino);
+        }
+        let dirty = state.inodes.entry(ino).or_default();
+        dirty.data = true;
+        dirty.metadata = true;
+    }
+
+    /// Record that the metadata of `ino` has changed, e.g. by `SETATTR` or
+    /// by the creation and removal of the entries in that directory.
+    pub fn mark_metadata(&self, ino: u64) {
+        self.state().inodes.entry(ino).or_default().metadata = true;
+    }
+
+    /// Return whether any data has been written through `fh` since the last
+    /// call, for `FLUSH`.
+    ///
+    /// The inode stays dirty until it is synchronized by `take_dirty_ino`.
+    pub fn take_dirty(&self, fh: u64) -> bool {
+        self.state().handles.remove(&fh).is_some()
+    }
+
+    /// Return whether `ino` needs to be synchronized, for `FSYNC` and `FSYNCDIR`.
+    ///
+    /// If `datasync` is set, only the written data is considered and
+    /// consumed, so that a following sync without it still writes back the
+    /// metadata.  The handles of the inode are also cleared, since their
+    /// data is covered by the sync.
+    pub fn take_dirty_ino(&self, ino: u64, datasync: bool) -> bool {
+        let mut state = self.state();
+        let dirty = match state.inodes.get_mut(&ino) {
+            Some(dirty) => dirty,
+            None => return false,
+        };
+        let taken = if datasync {
+            dirty.data
+        } else {
+            dirty.data || dirty.metadata
+        };
+        dirty.data = false;
+        if !datasync {
+            dirty.metadata = false;
+        }
+        if !dirty.metadata {
+            state.inodes.remove(&ino);
+        }
+        if taken {
+            state.handles.retain(|_, handle_ino| *handle_ino != ino);
+        }
+        taken
+    }
+
+    /// Forget the handle on `RELEASE`, returning whether it was still dirty.
+    ///
+    /// The handle numbers may be reused by the following opens.
+    pub fn release(&self, fh: u64) -> bool {
+        self.take_dirty(fh)
+    }
+
+    /// Return whether `ino` has any change not synchronized yet.
+    pub fn is_dirty(&self, ino: u64) -> bool {
+        self.state().inodes.contains_key(&ino)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
+        thread,
+    };
+
+    #[test]
+    fn flush_and_fsync() {
+        let tracker = DirtyTracker::new();
+        assert!(!tracker.take_dirty(10));
+        assert!(!tracker.take_dirty_ino(2, false));
+
+        tracker.mark_dirty(Some(10), 2);
+        tracker.mark_dirty(Some(11), 2);
+        // close(2) of one descriptor does not sync the inode.
+        assert!(tracker.take_dirty(10));
+        assert!(!tracker.take_dirty(10));
+        assert!(tracker.is_dirty(2));
+
+        // fsync(2) covers the writes through all handles.
+        assert!(tracker.take_dirty_ino(2, false));
+        assert!(!tracker.is_dirty(2));
+        assert!(!tracker.take_dirty(11));
+        assert!(!tracker.release(11));
+
+        // The writes without a handle only mark the inode.
+        tracker.mark_dirty(None, 3);
+        assert!(tracker.take_dirty_ino(3, false));
+    }
+
+    #[test]
+    fn datasync_leaves_metadata() {
+        let tracker = DirtyTracker::new();
+        tracker.mark_metadata(2);
+        assert!(!tracker.take_dirty_ino(2, true));
+        assert!(tracker.is_dirty(2));
+
+        tracker.mark_dirty(Some(10), 2);
+        assert!(tracker.take_dirty_ino(2, true));
+        assert!(!tracker.take_dirty_ino(2, true));
+        assert!(!tracker.take_dirty(10));
+        assert!(tracker.take_dirty_ino(2, false));
+        assert!(!tracker.take_dirty_ino(2, false));
+    }
+
+    #[test]
+    fn interleaved_write_and_fsync() {
+        // Every write must be seen by some sync that starts after it.
+        let tracker = Arc::new(DirtyTracker::new());
+        let written = Arc::new(AtomicU64::new(0));
+        let synced = Arc::new(AtomicU64::new(0));
+
+        let writers: Vec<_> = (0..4u64)
+            .map(|fh| {
+                let tracker = tracker.clone();
+                let written = written.clone();
+                thread::spawn(move || {
+                    for _ in 0..1000 {
+                        written.fetch_add(1, Ordering::SeqCst);
+                        tracker.mark_dirty(Some(fh), 2);
+                    }
+                })
+            })
+            .collect();
+        let syncer = thread::spawn({
+            let tracker = tracker.clone();
+            let written = written.clone();
+            let synced = synced.clone();
+            move || {
+                while synced.load(Ordering::SeqCst) < 4000 {
+                    if tracker.take_dirty_ino(2, false) {
+                        synced.store(written.load(Ordering::SeqCst), Ordering::SeqCst);
+                    }
+                }
+            }
+        });
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        syncer.join().unwrap();
+
+        assert_eq!(synced.load(Ordering::SeqCst), 4000);
+        assert!(!tracker.is_dirty(2));
+    }
+}
@@ -0,0 +1,243 @@
+use crate::Request;
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+/// The way to process an incoming request.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum DispatchHint {
+    /// Process the request on the receiving loop, without spawning.
+    Inline,
+    /// Spawn a thread or task to process the request.
+    Spawn,
+}
+
+/// A helper to select the requests processed inline on the receiving loop.
+///
+/// Spawning a thread or task for each request has a non-negligible cost
+/// compared to the operations answered from in-memory data in a moment.
+/// The dispatcher classifies the requests with the specified hint, so that
+/// the receiving loop processes such the requests itself and spawns only
+/// the slow ones.
+///
+/// The receiving loop cannot read the next request while an inline handler
+/// is running, so an inline handler that blocks stalls the whole mount.
+/// In debug builds, `run_inline` logs a warning when the handler takes
+/// longer than the threshold.
+///
+/// Even if each inline handler is short, a burst of them delays reading the
+/// following requests, and the queue of the kernel may fill up meanwhile.
+/// With `budget`, the run of consecutive inline handlers is limited by the
+/// number and the total duration, and the request hinted as inline after the
+/// budget is exhausted is spawned instead, which lets the receiving loop
+/// return to the connection (or the single-threaded executor run the other
+/// tasks).  The budget is renewed by every spawned request.
+pub struct Dispatcher<F> {
+    hint: F,
+    threshold: Duration,
+    budget: Option<(u32, Duration)>,
+    slice: Mutex<Slice>,
+    yields: AtomicU64,
+}
+
+// The consecutive inline handlers run so far.
+#[derive(Default)]
+struct Slice {
+    count: u32,
+    elapsed: Duration,
+}
+
+impl<F> fmt::Debug for Dispatcher<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Dispatcher")
+            .field("threshold", &self.threshold)
+            .field("budget", &self.budget)
+            .field("yields", &self.yields.load(Ordering::Relaxed))
+            .finish()
+    }
+}
+
+impl<F> Dispatcher<F>
+where
+    F: Fn(&Request) -> DispatchHint,
+{
+    /// Create a dispatcher that classifies the requests with `hint`.
+    pub fn new(hint: F) -> Self {
+        Self {
+            hint,
+            threshold: Duration::from_millis(10),
+            budget: None,
+            slice: Mutex::default(),
+            yields: AtomicU64::new(0),
+        }
+    }
+
+    /// Specify the duration of inline handlers regarded as too long.
+    ///
+    /// The default value is 10 milliseconds.
+    pub fn threshold(&mut self, threshold: Duration) -> &mut Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Limit the consecutive inline handlers to `max_requests` or `max_time`
+    /// in total, whichever is reached first.
+    ///
+    /// The requests that must be processed in order, such as the forgets
+    /// updating the lookup counts, should not be given to a dispatcher with
+    /// the budget since some of them may be spawned.
+    ///
+    /// By default, the inline handlers are not limited.
+    pub fn budget(&mut self, max_requests: u32, max_time: Duration) -> &mut Self {
+        self.budget = Some((max_requests.max(1), max_time));
+        self
+    }
+
+    /// Return how the request should be processed.
+    pub fn hint(&self, req: &Request) -> DispatchHint {
+        let hint = (self.hint)(req);
+        let (max_requests, max_time) = match self.budget {
+            Some(budget) => budget,
+            None => return hint,
+        };
+
+        let mut slice = self.slice.lock().unwrap();
+        match hint {
+            DispatchHint::Inline if slice.count < max_requests && slice.elapsed < max_time => {
+                DispatchHint::Inline
+            }
+            DispatchHint::Inline => {
+                trace!(
+                    "the inline budget is exhausted (unique = {}, count = {}, elapsed = {:?})",
+                    req.unique(),
+                    slice.count,
+                    slice.elapsed
+                );
+                *slice = Slice::default();
+                self.yields.fetch_add(1, Ordering::Relaxed);
+                DispatchHint::Spawn
+            }
+            DispatchHint::Spawn => {
+                *slice = Slice::default();
+                DispatchHint::Spawn
+            }
+        }
+    }
+
+    /// Return the number of the inline requests spawned due to the budget.
+    pub fn yields(&self) -> u64 {
+        self.yields.load(Ordering::Relaxed)
+    }
+
+    /// Run the handler of a request inline.
+    pub fn run_inline<R>(&self, req: &Request, f: impl FnOnce() -> R) -> R {
+        if !cfg!(debug_assertions) && self.budget.is_none() {
+            return f();
+        }
+
+        let start = Instant::now();
+        let res = f();
+        let elapsed = start.elapsed();
+        if self.budget.is_some() {
+            let mut slice = self.slice.lock().unwrap();
+            slice.count += 1;
+            slice.elapsed += elapsed;
+        }
+        if cfg!(debug_assertions) && elapsed > self.threshold {
+            warn!(
+                "the inline handler blocks the receiving loop too long \
+                 (unique = {}, elapsed = {:?}, threshold = {:?})",
+                req.unique(),
+                elapsed,
+                self.threshold,
+            );
+        }
+        res
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, KernelConfig, Operation};
+    use polyfuse_kernel::*;
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn dispatch_forgets_inline() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let dispatcher = Dispatcher::new(|req: &Request| match req.operation() {
+            Ok(Operation::Forget(..)) => DispatchHint::Inline,
+            _ => DispatchHint::Spawn,
+        });
+
+        let forget_in = fuse_forget_in { nlookup: 1 };
+        kernel
+            .send_request(fuse_opcode::FUSE_FORGET as u32, 2, forget_in.as_bytes())
+            .unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 2, &[0u8; 16])
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(dispatcher.hint(&req), DispatchHint::Inline);
+        assert_eq!(dispatcher.run_inline(&req, || 42), 42);
+
+        let req = session.next_request().unwrap().unwrap();
+        assert_eq!(dispatcher.hint(&req), DispatchHint::Spawn);
+    }
+
+    #[test]
+    fn spawn_after_budget() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let mut dispatcher = Dispatcher::new(|req: &Request| match req.operation() {
+            Ok(Operation::Getattr(..)) => DispatchHint::Inline,
+            _ => DispatchHint::Spawn,
+        });
+        dispatcher.budget(2, Duration::from_secs(3600));
+
+        for _ in 0..5 {
+            kernel
+                .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+                .unwrap();
+        }
+        kernel
+            .send_request(fuse_opcode::FUSE_STATFS as u32, 1, &[])
+            .unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+
+        let hints: Vec<_> = (0..7)
+            .map(|_| {
+                let req = session.next_request().unwrap().unwrap();
+                let hint = dispatcher.hint(&req);
+                if hint == DispatchHint::Inline {
+                    dispatcher.run_inline(&req, || ());
+                }
+                hint
+            })
+            .collect();
+        use DispatchHint::*;
+        assert_eq!(
+            hints,
+            vec![Inline, Inline, Spawn, Inline, Inline, Spawn, Inline]
+        );
+        assert_eq!(dispatcher.yields(), 1);
+
+        // The time budget is also counted.
+        dispatcher.budget(100, Duration::from_millis(1));
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        dispatcher.run_inline(&req, || std::thread::sleep(Duration::from_millis(2)));
+        assert_eq!(dispatcher.hint(&req), DispatchHint::Spawn);
+        assert_eq!(dispatcher.yields(), 2);
+    }
+}
@@ -0,0 +1,267 @@
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt, io,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// Coalesces the concurrent lookups of the same entry onto a single call
+/// of the backend.
+///
+/// When the kernel does not use `READDIRPLUS`, a burst of `stat(2)` calls
+/// from the applications results in many concurrent `LOOKUP` requests for
+/// the same pair of the parent and the name.  The first caller of `lookup`
+/// for a pair runs the query, and the others arriving while it is in
+/// flight wait for its result instead of querying the backend again.
+///
+/// Every request still has to be replied individually with the shared
+/// result, and the filesystem must increment the lookup count of the
+/// inode for each of them, since the kernel counts each reply.
+///
+/// The results are not cached: the pair is removed as soon as the flight
+/// completes, so a lookup after an unlink always queries the backend.
+/// The waiting is blocking, so `lookup` should not be called directly
+/// inside of asynchronous tasks.
+pub struct LookupFlights<T> {
+    flights: Mutex<Flights<T>>,
+}
+
+type Flights<T> = HashMap<(u64, OsString), Arc<Flight<T>>>;
+
+struct Flight<T> {
+    result: Mutex<Option<Result<T, SharedError>>>,
+    cond: Condvar,
+}
+
+/// The clonable representation of `io::Error` shared by the waiters.
+#[derive(Clone)]
+struct SharedError {
+    errno: Option<i32>,
+    kind: io::ErrorKind,
+    message: String,
+}
+
+impl SharedError {
+    fn new(err: &io::Error) -> Self {
+        Self {
+            errno: err.raw_os_error(),
+            kind: err.kind(),
+            message: err.to_string(),
+        }
+    }
+
+    fn to_io_error(&self) -> io::Error {
+        match self.errno {
+            Some(errno) => io::Error::from_raw_os_error(errno),
+            None => io::Error::new(self.kind, self.message.clone()),
+        }
+    }
+}
+
+impl<T> fmt::Debug for LookupFlights<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("LookupFlights")
+            .field("in_flight", &self.in_flight())
+            .finish()
+    }
+}
+
+impl<T> Default for LookupFlights<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> LookupFlights<T> {
+    /// Create an empty set of flights.
+    pub fn new() -> Self {
+        Self {
+            flights: Mutex::default(),
+        }
+    }
+
+    /// Return the number of lookups currently in flight.
+    pub fn in_flight(&self) -> usize {
+        self.flights.lock().unwrap().len()
+    }
+}
+
+impl<T> LookupFlights<T>
+where
+    T: Clone,
+{
+    /// Look up the entry `name` in `parent` with `query`, or wait for the
+    /// result of the same lookup in flight.
+    ///
+    /// If the query fails, the waiters receive the same error.  If it
+    /// panics, the waiters receive `EIO`.
+    pub fn lookup<F>(&self, parent: u64, name: &OsStr, query: F) -> io::Result<T>
+    where
+        F: FnOnce() -> io::Result<T>,
+    {
+        let key = (parent, name.to_owned());
+        let (flight, leader) = {
+            let mut flights = self.flights.lock().unwrap();
+            match flights.get(&key) {
+                Some(flight) => (flight.clone(), false),
+                None => {
+                    let flight = Arc::new(Flight {
+                        result: Mutex::new(None),
+                        cond: Condvar::new(),
+                    });
+                    flights.insert(key.clone(), flight.clone());
+                    (flight, true)
+                }
+            }
+        };
+
+        if !leader {
+            let mut result = flight.result.lock().unwrap();
+            loop {
+                match *result {
+                    Some(Ok(ref out)) => return Ok(out.clone()),
+                    Some(Err(ref err)) => return Err(err.to_io_error()),
+                    None => result = flight.cond.wait(result).unwrap(),
+                }
+            }
+        }
+
+        let guard = FlightGuard {
+            flights: &self.flights,
+            key,
+            flight: &flight,
+        };
+        let result = query();
+        *flight.result.lock().unwrap() = Some(match result {
+            Ok(ref out) => Ok(out.clone()),
+            Err(ref err) => Err(SharedError::new(err)),
+        });
+        drop(guard);
+        result
+    }
+}
+
+/// Remove the flight and wake up the waiters, even if the query panics.
+struct FlightGuard<'a, T> {
+    flights: &'a Mutex<Flights<T>>,
+    key: (u64, OsString),
+    flight: &'a Flight<T>,
+}
+
+impl<T> Drop for FlightGuard<'_, T> {
+    fn drop(&mut self) {
+        // Ignore poisoning so that the waiters are woken up while panicking.
+        let mut flights = match self.flights.lock() {
+            Ok(flights) => flights,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        flights.remove(&self.key);
+        drop(flights);
+
+        let mut result = match self.flight.result.lock() {
+            Ok(result) => result,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        result
+            .get_or_insert_with(|| Err(SharedError::new(&io::Error::from_raw_os_error(libc::EIO))));
+        self.flight.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{reply::EntryOut, testing, KernelConfig, Operation};
+    use polyfuse_kernel::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Barrier,
+        },
+        thread,
+        time::Duration,
+    };
+
+    #[test]
+    fn coalesce_concurrent_lookups() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let flights = Arc::new(LookupFlights::new());
+        let queries = Arc::new(AtomicUsize::new(0));
+        let barrier = Arc::new(Barrier::new(10));
+
+        for _ in 0..10 {
+            kernel
+                .send_request(fuse_opcode::FUSE_LOOKUP as u32, 1, b"foo\0")
+                .unwrap();
+        }
+        let handlers: Vec<_> = (0..10)
+            .map(|_| {
+                let req = session.next_request().unwrap().unwrap();
+                let flights = flights.clone();
+                let queries = queries.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    let op = match req.operation().unwrap() {
+                        Operation::Lookup(op) => op,
+                        _ => unreachable!(),
+                    };
+                    barrier.wait();
+                    let ino = flights
+                        .lookup(op.parent(), op.name(), || {
+                            queries.fetch_add(1, Ordering::SeqCst);
+                            thread::sleep(Duration::from_millis(100));
+                            Ok(2u64)
+                        })
+                        .unwrap();
+                    let mut out = EntryOut::default();
+                    out.ino(ino);
+                    req.reply(out).unwrap();
+                })
+            })
+            .collect();
+        for handler in handlers {
+            handler.join().unwrap();
+        }
+
+        assert_eq!(queries.load(Ordering::SeqCst), 1);
+        for _ in 0..10 {
+            assert_eq!(kernel.recv_reply().unwrap().error(), 0);
+        }
+        assert_eq!(flights.in_flight(), 0);
+    }
+
+    #[test]
+    fn results_are_not_cached() {
+        let flights = LookupFlights::new();
+        let name = OsStr::new("foo");
+        assert_eq!(flights.lookup(1, name, || Ok(2)).unwrap(), 2);
+        // e.g. the entry has been unlinked after the flight.
+        let err = flights
+            .lookup(1, name, || Err(io::Error::from_raw_os_error(libc::ENOENT)))
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        assert_eq!(flights.lookup(1, name, || Ok(3)).unwrap(), 3);
+    }
+
+    #[test]
+    fn waiters_see_the_panic_as_eio() {
+        let flights = Arc::new(LookupFlights::<u64>::new());
+        let barrier = Arc::new(Barrier::new(2));
+        let leader = thread::spawn({
+            let flights = flights.clone();
+            let barrier = barrier.clone();
+            move || {
+                flights.lookup(1, OsStr::new("foo"), || {
+                    barrier.wait();
+                    thread::sleep(Duration::from_millis(100));
+                    panic!("backend failure")
+                })
+            }
+        });
+        barrier.wait();
+        let err = flights.lookup(1, OsStr::new("foo"), || Ok(2)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EIO));
+        assert!(leader.join().is_err());
+        assert_eq!(flights.in_flight(), 0);
+    }
+}
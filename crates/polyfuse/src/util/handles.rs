@@ -0,0 +1,492 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    error, fmt, io,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+/// What `HandleTable::insert` does when the table is full.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HandlePolicy {
+    /// Fail with `EMFILE`, which the kernel returns from `open(2)`.
+    Reject,
+
+    /// Evict the least recently used handle to make room.
+    ///
+    /// The subsequent operations on the evicted handle see no entry, and
+    /// should be replied `EBADF`.
+    EvictLeastRecentlyUsed,
+}
+
+/// The reason why `HandleTable::try_remove` found no entry.
+///
+/// In either case, the filesystem should reply `EBADF` unless it waits
+/// for the entry of `NotYetInserted`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum HandleError {
+    /// The handle is reserved but its entry has not been inserted yet.
+    NotYetInserted,
+
+    /// The handle has never been allocated, or has been released or evicted.
+    Unknown,
+}
+
+impl fmt::Display for HandleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotYetInserted => f.write_str("the file handle has not been inserted yet"),
+            Self::Unknown => f.write_str("unknown file handle"),
+        }
+    }
+}
+
+impl error::Error for HandleError {}
+
+/// A table of the opened file handles, with an optional upper bound.
+///
+/// The handles are allocated from 1 and never reused, so that a handle
+/// evicted or released earlier is not confused with a newer one.  The
+/// entries are reference-counted, and the operations in flight keep using
+/// the entry obtained by `get` even if it is removed concurrently.
+///
+/// A client that keeps opening files without closing them can exhaust the
+/// file descriptors of the backend.  With a maximum number of handles, the
+/// excess opens either fail with `EMFILE` or evict the least recently
+/// used handles, depending on `HandlePolicy`.  The callback registered by
+/// `on_evict` receives the evicted entry, e.g. to close the backend
+/// resource explicitly instead of waiting for its last reference.
+///
+/// The kernel never sends `RELEASE` for a handle before receiving the
+/// reply to `OPEN`, but the requests are processed out of order once they
+/// are spawned onto separate threads or tasks.  If the handle is replied
+/// before its entry is inserted, e.g. with `reserve` to know the handle
+/// in advance, the `RELEASE` may find no entry.  With `rendezvous`,
+/// `remove` waits for the reserved handles to be inserted instead.
+pub struct HandleTable<T> {
+    inner: Mutex<Inner<T>>,
+    inserted: Condvar,
+    capacity: Option<(usize, HandlePolicy)>,
+    on_evict: Option<EvictCallback<T>>,
+    rendezvous: Option<Duration>,
+}
+
+type EvictCallback<T> = Box<dyn Fn(u64, Arc<T>) + Send + Sync>;
+
+struct Inner<T> {
+    entries: HashMap<u64, Entry<T>>,
+    // The handles allocated by `reserve` whose entries are not inserted yet.
+    reserved: HashSet<u64>,
+    // The handles ordered by the time of last use.
+    lru: BTreeMap<u64, u64>,
+    next_fh: u64,
+    tick: u64,
+    peak: usize,
+    evicted: u64,
+}
+
+struct Entry<T> {
+    value: Arc<T>,
+    last_used: u64,
+}
+
+impl<T> Inner<T> {
+    fn touch(&mut self, fh: u64) -> Option<Arc<T>> {
+        self.tick += 1;
+        let tick = self.tick;
+        let entry = self.entries.get_mut(&fh)?;
+        self.lru.remove(&entry.last_used);
+        entry.last_used = tick;
+        self.lru.insert(tick, fh);
+        Some(entry.value.clone())
+    }
+
+    fn remove(&mut self, fh: u64) -> Option<Arc<T>> {
+        let entry = self.entries.remove(&fh)?;
+        self.lru.remove(&entry.last_used);
+        Some(entry.value)
+    }
+
+    fn try_remove(&mut self, fh: u64) -> Result<Arc<T>, HandleError> {
+        match self.remove(fh) {
+            Some(value) => Ok(value),
+            None if self.reserved.contains(&fh) => Err(HandleError::NotYetInserted),
+            None => Err(HandleError::Unknown),
+        }
+    }
+}
+
+impl<T> fmt::Debug for HandleTable<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandleTable")
+            .field("len", &self.len())
+            .field("peak", &self.peak())
+            .field("capacity", &self.capacity)
+            .field("rendezvous", &self.rendezvous)
+            .finish()
+    }
+}
+
+impl<T> Default for HandleTable<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> HandleTable<T> {
+    /// Create a table with no limit of the number of handles.
+    pub fn new() -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                entries: HashMap::new(),
+                reserved: HashSet::new(),
+                lru: BTreeMap::new(),
+                next_fh: 1,
+                tick: 0,
+                peak: 0,
+                evicted: 0,
+            }),
+            inserted: Condvar::new(),
+            capacity: None,
+            on_evict: None,
+            rendezvous: None,
+        }
+    }
+
+    /// Create a table holding at most `max` handles.
+    ///
+    /// # Panics
+    /// Panics if `max` is zero.
+    pub fn with_capacity_policy(max: usize, policy: HandlePolicy) -> Self {
+        assert!(max > 0, "the maximum number of handles must be positive");
+        Self {
+            capacity: Some((max, policy)),
+            ..Self::new()
+        }
+    }
+
+    /// Register the callback invoked with the evicted handle and its entry.
+    ///
+    /// The callback is called on the thread inserting the new handle,
+    /// after the internal lock is released.
+    pub fn on_evict<F>(mut self, f: F) -> Self
+    where
+        F: Fn(u64, Arc<T>) + Send + Sync + 'static,
+    {
+        self.on_evict = Some(Box::new(f));
+        self
+    }
+
+    /// Let `remove` wait up to `timeout` for the reserved handles to be inserted.
+    ///
+    /// By default, `remove` returns `None` immediately for such handles.
+    pub fn rendezvous(mut self, timeout: Duration) -> Self {
+        self.rendezvous = Some(timeout);
+        self
+    }
+
+    fn inner(&self) -> MutexGuard<'_, Inner<T>> {
+        self.inner.lock().unwrap()
+    }
+
+    /// Register an entry and return its handle.
+    ///
+    /// If the table is full, this fails with `EMFILE` or evicts the least
+    /// recently used handle according to the policy.
+    pub fn insert(&self, value: T) -> io::Result<u64> {
+        let reservation = self.reserve()?;
+        Ok(reservation.insert(value))
+    }
+
+    /// Allocate a handle whose entry is inserted later, e.g. after replying
+    /// the handle to the kernel.
+    ///
+    /// The reserved handle counts toward the maximum number of handles.
+    /// It is released if the reservation is dropped without inserting.
+    pub fn reserve(&self) -> io::Result<HandleReservation<'_, T>> {
+        let mut inner = self.inner();
+        let mut evicted = None;
+        if let Some((max, policy)) = self.capacity {
+            if inner.entries.len() + inner.reserved.len() >= max {
+                match (policy, inner.lru.iter().next()) {
+                    (HandlePolicy::EvictLeastRecentlyUsed, Some((_, &fh))) => {
+                        evicted = inner.remove(fh).map(|value| (fh, value));
+                        inner.evicted += 1;
+                    }
+                    // All of the handles are reserved, so none can be evicted.
+                    _ => {
+                        return Err(io::Error::from_raw_os_error(libc::EMFILE));
+                    }
+                }
+            }
+        }
+
+        let fh = inner.next_fh;
+        inner.next_fh += 1;
+        inner.reserved.insert(fh);
+        drop(inner);
+
+        if let Some((fh, value)) = evicted {
+            debug!("evict the file handle {}", fh);
+            if let Some(ref on_evict) = self.on_evict {
+                on_evict(fh, value);
+            }
+        }
+
+        Ok(HandleReservation {
+            table: self,
+            fh: Some(fh),
+        })
+    }
+
+    fn fill(&self, fh: u64, value: T) {
+        let mut inner = self.inner();
+        inner.reserved.remove(&fh);
+        inner.tick += 1;
+        let last_used = inner.tick;
+        inner.entries.insert(
+            fh,
+            Entry {
+                value: Arc::new(value),
+                last_used,
+            },
+        );
+        inner.lru.insert(last_used, fh);
+        inner.peak = inner.peak.max(inner.entries.len());
+        drop(inner);
+        self.inserted.notify_all();
+    }
+
+    fn cancel(&self, fh: u64) {
+        self.inner().reserved.remove(&fh);
+        self.inserted.notify_all();
+    }
+
+    /// Return the entry of the handle, marking it as recently used.
+    ///
+    /// `None` is returned for the handles released or evicted, to which
+    /// the filesystem should reply `EBADF`.
+    pub fn get(&self, fh: u64) -> Option<Arc<T>> {
+        self.inner().touch(fh)
+    }
+
+    /// Remove the handle on `RELEASE` or `RELEASEDIR`.
+    ///
+    /// With `rendezvous`, a reserved handle is removed once its entry is
+    /// inserted, and `None` is returned if it is not inserted in time.
+    pub fn remove(&self, fh: u64) -> Option<Arc<T>> {
+        let mut inner = self.inner();
+        let timeout = match self.rendezvous {
+            Some(timeout) if inner.reserved.contains(&fh) => timeout,
+            _ => return inner.remove(fh),
+        };
+        let deadline = Instant::now() + timeout;
+        loop {
+            match inner.try_remove(fh) {
+                Err(HandleError::NotYetInserted) => (),
+                res => return res.ok(),
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                debug!("the file handle {} has not been inserted in time", fh);
+                return None;
+            }
+            inner = self.inserted.wait_timeout(inner, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Remove the handle without waiting, telling whether a missing entry is
+    /// still reserved.
+    pub fn try_remove(&self, fh: u64) -> Result<Arc<T>, HandleError> {
+        self.inner().try_remove(fh)
+    }
+
+    /// Return the number of handles currently opened.
+    pub fn len(&self) -> usize {
+        self.inner().entries.len()
+    }
+
+    /// Return whether no handle is opened.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the largest number of handles opened at the same time.
+    pub fn peak(&self) -> usize {
+        self.inner().peak
+    }
+
+    /// Return the number of handles evicted so far.
+    pub fn evicted(&self) -> u64 {
+        self.inner().evicted
+    }
+}
+
+/// A handle allocated by `HandleTable::reserve`.
+#[must_use = "the handle is released unless the entry is inserted"]
+pub struct HandleReservation<'a, T> {
+    table: &'a HandleTable<T>,
+    fh: Option<u64>,
+}
+
+impl<T> fmt::Debug for HandleReservation<'_, T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HandleReservation")
+            .field("fh", &self.fh)
+            .finish()
+    }
+}
+
+impl<T> HandleReservation<'_, T> {
+    /// Return the reserved handle.
+    pub fn fh(&self) -> u64 {
+        self.fh.expect("the handle is reserved")
+    }
+
+    /// Insert the entry of the reserved handle, and return the handle.
+    pub fn insert(mut self, value: T) -> u64 {
+        let fh = self.fh.take().expect("the handle is reserved");
+        self.table.fill(fh, value);
+        fh
+    }
+}
+
+impl<T> Drop for HandleReservation<'_, T> {
+    fn drop(&mut self) {
+        if let Some(fh) = self.fh.take() {
+            self.table.cancel(fh);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    // A backend resource counting the open descriptors.
+    struct Resource(Arc<AtomicUsize>);
+
+    impl Resource {
+        fn open(count: &Arc<AtomicUsize>) -> Self {
+            count.fetch_add(1, Ordering::SeqCst);
+            Self(count.clone())
+        }
+    }
+
+    impl Drop for Resource {
+        fn drop(&mut self) {
+            self.0.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn reject_when_full() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let table = HandleTable::with_capacity_policy(2, HandlePolicy::Reject);
+        let fh1 = table.insert(Resource::open(&opened)).unwrap();
+        let fh2 = table.insert(Resource::open(&opened)).unwrap();
+        assert_ne!(fh1, fh2);
+
+        let err = table.insert(Resource::open(&opened)).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+        // The rejected resource is dropped immediately.
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+
+        assert!(table.remove(fh1).is_some());
+        let fh3 = table.insert(Resource::open(&opened)).unwrap();
+        assert!(fh3 > fh2, "the handles are never reused");
+        assert!(table.get(fh1).is_none());
+        assert_eq!((table.len(), table.peak(), table.evicted()), (2, 2, 0));
+    }
+
+    #[test]
+    fn evict_least_recently_used() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let evicted = Arc::new(Mutex::new(vec![]));
+        let table = HandleTable::with_capacity_policy(2, HandlePolicy::EvictLeastRecentlyUsed)
+            .on_evict({
+                let evicted = evicted.clone();
+                move |fh, _resource| evicted.lock().unwrap().push(fh)
+            });
+
+        let fh1 = table.insert(Resource::open(&opened)).unwrap();
+        let fh2 = table.insert(Resource::open(&opened)).unwrap();
+        assert!(table.get(fh1).is_some());
+        // fh2 is the least recently used one.
+        let fh3 = table.insert(Resource::open(&opened)).unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec![fh2]);
+        assert!(table.get(fh2).is_none());
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+
+        // An operation in flight keeps the evicted resource alive.
+        let in_flight = table.get(fh1).unwrap();
+        let _fh4 = table.insert(Resource::open(&opened)).unwrap();
+        let _fh5 = table.insert(Resource::open(&opened)).unwrap();
+        assert_eq!(*evicted.lock().unwrap(), vec![fh2, fh3, fh1]);
+        assert_eq!(opened.load(Ordering::SeqCst), 3);
+        drop(in_flight);
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+
+        assert_eq!((table.len(), table.peak(), table.evicted()), (2, 2, 3));
+    }
+
+    #[test]
+    fn rendezvous_with_reordered_release() {
+        let table = Arc::new(HandleTable::new().rendezvous(Duration::from_secs(5)));
+        let release = |fh: u64| {
+            let table = table.clone();
+            thread::spawn(move || table.remove(fh))
+        };
+
+        // The RELEASE of the replied handle is processed on another thread
+        // before the OPEN handler inserts the entry.
+        let reservation = table.reserve().unwrap();
+        let fh = reservation.fh();
+        assert_eq!(
+            table.try_remove(fh).unwrap_err(),
+            HandleError::NotYetInserted
+        );
+        assert_eq!(table.len(), 0);
+        let handle = release(fh);
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(reservation.insert("file"), fh);
+        let released = handle.join().unwrap();
+        assert_eq!(released.as_deref(), Some(&"file"));
+        assert_eq!(table.try_remove(fh).unwrap_err(), HandleError::Unknown);
+
+        // The waiter stops when the reservation is dropped.
+        let reservation = table.reserve().unwrap();
+        let fh = reservation.fh();
+        let start = Instant::now();
+        let handle = release(fh);
+        thread::sleep(Duration::from_millis(50));
+        drop(reservation);
+        let released = handle.join().unwrap();
+        assert!(released.is_none());
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        // Without the rendezvous, the release finds no entry immediately.
+        let table = HandleTable::new();
+        let reservation = table.reserve().unwrap();
+        assert!(table.remove(reservation.fh()).is_none());
+        let fh = reservation.insert("file");
+        assert!(table.get(fh).is_some());
+    }
+
+    #[test]
+    fn reserved_handles_count_toward_capacity() {
+        let table = HandleTable::with_capacity_policy(1, HandlePolicy::EvictLeastRecentlyUsed);
+        let reservation = table.reserve().unwrap();
+        let err = table.insert("other").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EMFILE));
+        let fh = reservation.insert("file");
+        assert!(table.insert("other").is_ok());
+        assert!(table.get(fh).is_none());
+        assert_eq!(table.evicted(), 1);
+    }
+}
@@ -0,0 +1,250 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    sync::{Arc, Condvar, Mutex},
+};
+
+/// A table of per-inode locks, to serialize the operations on the same inode.
+///
+/// The kernel does not guarantee the order of concurrent requests, and thus
+/// a `WRITE` and the following `SETATTR` for truncation may be processed in
+/// the reversed order when they are dispatched to different threads.
+/// This table provides two ways to avoid such the situation:
+///
+/// * `enqueue` takes a ticket in the order of arrival.  When the ticket is
+///   taken in the receiving loop before spawning the processing of request,
+///   the requests on the same inode are processed in the order that the
+///   kernel sent, while the requests on the other inodes run in parallel.
+/// * `lock` acquires the lock of the inode immediately, for the filesystems
+///   that prefer to serialize only a part of operations.
+///
+/// `KernelConfig::serialize_per_inode` applies the former to all requests.
+///
+/// `lock` and `InodeTicket::wait` park the calling thread until the
+/// preceding holders of the inode drop their guards, whereas `enqueue`
+/// itself returns immediately.  In asynchronous tasks, take the tickets in
+/// order and wait for them on a thread that is allowed to block.
+#[derive(Clone, Default)]
+pub struct InodeLocks {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    queues: Mutex<HashMap<u64, Queue>>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct Queue {
+    next: u64,
+    serving: u64,
+    cancelled: BTreeSet<u64>,
+}
+
+impl Queue {
+    /// Pass the turn to the next ticket, and return whether no tickets remain.
+    fn advance(&mut self) -> bool {
+        self.serving += 1;
+        while self.cancelled.remove(&self.serving) {
+            self.serving += 1;
+        }
+        self.serving == self.next
+    }
+}
+
+impl fmt::Debug for InodeLocks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InodeLocks").finish()
+    }
+}
+
+impl InodeLocks {
+    /// Create an empty lock table.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Take a ticket for the specified inode, in the order of calls.
+    pub fn enqueue(&self, ino: u64) -> InodeTicket {
+        let mut queues = self.inner.queues.lock().unwrap();
+        let queue = queues.entry(ino).or_default();
+        let ticket = queue.next;
+        queue.next += 1;
+        InodeTicket {
+            inner: Some(self.inner.clone()),
+            ino,
+            ticket,
+        }
+    }
+
+    /// Acquire the lock of the specified inode, waiting for the preceding holders.
+    pub fn lock(&self, ino: u64) -> InodeGuard {
+        self.enqueue(ino).wait()
+    }
+}
+
+/// A reserved turn to acquire the lock of an inode.
+///
+/// Dropping the ticket without `wait` gives up the turn.
+#[must_use]
+pub struct InodeTicket {
+    inner: Option<Arc<Inner>>,
+    ino: u64,
+    ticket: u64,
+}
+
+impl fmt::Debug for InodeTicket {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InodeTicket")
+            .field("ino", &self.ino)
+            .field("ticket", &self.ticket)
+            .finish()
+    }
+}
+
+impl Drop for InodeTicket {
+    fn drop(&mut self) {
+        if let Some(inner) = self.inner.take() {
+            let mut queues = inner.queues.lock().unwrap();
+            let queue = queues.get_mut(&self.ino).expect("missing inode queue");
+            if queue.serving == self.ticket {
+                if queue.advance() {
+                    queues.remove(&self.ino);
+                }
+                inner.cond.notify_all();
+            } else {
+                queue.cancelled.insert(self.ticket);
+            }
+        }
+    }
+}
+
+impl InodeTicket {
+    /// Return the inode number of this ticket.
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+
+    /// Wait until all of preceding tickets for the same inode are released.
+    pub fn wait(mut self) -> InodeGuard {
+        let inner = self.inner.take().expect("the ticket has already been used");
+        {
+            let mut queues = inner.queues.lock().unwrap();
+            while queues[&self.ino].serving != self.ticket {
+                queues = inner.cond.wait(queues).unwrap();
+            }
+        }
+        InodeGuard {
+            inner,
+            ino: self.ino,
+        }
+    }
+}
+
+/// The lock of an inode, released when dropped.
+pub struct InodeGuard {
+    inner: Arc<Inner>,
+    ino: u64,
+}
+
+impl fmt::Debug for InodeGuard {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InodeGuard")
+            .field("ino", &self.ino)
+            .finish()
+    }
+}
+
+impl Drop for InodeGuard {
+    fn drop(&mut self) {
+        let mut queues = self.inner.queues.lock().unwrap();
+        let queue = queues.get_mut(&self.ino).expect("missing inode queue");
+        if queue.advance() {
+            queues.remove(&self.ino);
+        }
+        self.inner.cond.notify_all();
+    }
+}
+
+impl InodeGuard {
+    /// Return the inode number of the locked inode.
+    pub fn ino(&self) -> u64 {
+        self.ino
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, KernelConfig, Operation};
+    use polyfuse_kernel::*;
+    use std::{thread, time::Duration};
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn cancelled_ticket() {
+        let locks = InodeLocks::new();
+        let first = locks.enqueue(2);
+        let second = locks.enqueue(2);
+        let third = locks.enqueue(2);
+        drop(second);
+        drop(first);
+        drop(third.wait());
+        assert!(locks.inner.queues.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn ordered_write_and_truncate() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+
+        let write_in = fuse_write_in {
+            size: 3,
+            ..Default::default()
+        };
+        let mut arg = write_in.as_bytes().to_vec();
+        arg.extend_from_slice(b"foo");
+        kernel
+            .send_request(fuse_opcode::FUSE_WRITE as u32, 2, &arg[..])
+            .unwrap();
+
+        let setattr_in = fuse_setattr_in {
+            valid: FATTR_SIZE,
+            size: 0,
+            ..Default::default()
+        };
+        kernel
+            .send_request(fuse_opcode::FUSE_SETATTR as u32, 2, setattr_in.as_bytes())
+            .unwrap();
+
+        let locks = InodeLocks::new();
+        let log = Arc::new(Mutex::new(vec![]));
+        let mut tasks = vec![];
+        for _ in 0..2 {
+            let req = session.next_request().unwrap().unwrap();
+            let ticket = locks.enqueue(req.ino());
+            let log = log.clone();
+            tasks.push(move || {
+                let _guard = ticket.wait();
+                let name = match req.operation().unwrap() {
+                    Operation::Write(..) => "write",
+                    Operation::Setattr(..) => "setattr",
+                    _ => unreachable!(),
+                };
+                log.lock().unwrap().push(name);
+            });
+        }
+
+        // Spawn the later request first.
+        let second = tasks.pop().unwrap();
+        let first = tasks.pop().unwrap();
+        let second = thread::spawn(second);
+        thread::sleep(Duration::from_millis(10));
+        let first = thread::spawn(first);
+        first.join().unwrap();
+        second.join().unwrap();
+
+        assert_eq!(*log.lock().unwrap(), vec!["write", "setattr"]);
+        drop(kernel);
+    }
+}
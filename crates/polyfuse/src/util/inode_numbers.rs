@@ -0,0 +1,83 @@
+use crate::reply::Generation;
+use std::{fmt, sync::Mutex};
+
+/// An allocator of the inode numbers announced to the kernel, which gives
+/// a new generation to every reused number.
+///
+/// The numbers are allocated from 2, since 1 is the root.  Once the kernel
+/// has forgotten an inode removed from the filesystem, its number is
+/// returned by `release` and handed out again by `allocate` with the next
+/// generation, so the pair of the inode number and the generation set by
+/// `EntryOut::inode_generation` is never repeated.
+#[derive(Default)]
+pub struct InodeNumbers {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    // The largest number allocated so far.
+    last: u64,
+    // The released numbers, with the generations they are reused with.
+    released: Vec<(u64, Generation)>,
+}
+
+impl fmt::Debug for InodeNumbers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("InodeNumbers").finish()
+    }
+}
+
+impl InodeNumbers {
+    /// Create an allocator that has handed out no numbers.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allocate an inode number with its generation.
+    ///
+    /// The released numbers are reused first.
+    ///
+    /// # Panics
+    /// This method panics if the inode numbers are exhausted.
+    pub fn allocate(&self) -> (u64, Generation) {
+        let mut inner = self.inner.lock().unwrap();
+        if let Some(released) = inner.released.pop() {
+            return released;
+        }
+        inner.last = inner
+            .last
+            .max(1)
+            .checked_add(1)
+            .expect("the inode numbers are exhausted");
+        (inner.last, Generation::default())
+    }
+
+    /// Release the inode number allocated with `generation`, so that it is
+    /// reused with the next generation.
+    ///
+    /// The number must not be released until the kernel has forgotten it,
+    /// i.e. its lookup count has dropped to zero by `FORGET`.
+    pub fn release(&self, ino: u64, generation: Generation) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.released.push((ino, generation.next()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reuse_with_next_generation() {
+        let numbers = InodeNumbers::new();
+        let (a, gen_a) = numbers.allocate();
+        let (b, _) = numbers.allocate();
+        assert_eq!((a, b), (2, 3));
+        assert_eq!(gen_a, Generation::default());
+
+        numbers.release(a, gen_a);
+        assert_eq!(numbers.allocate(), (a, gen_a.next()));
+        assert_eq!(numbers.allocate().0, 4);
+    }
+}
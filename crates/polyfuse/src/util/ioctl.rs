@@ -0,0 +1,217 @@
+use std::{convert::TryInto as _, fmt, mem, ops};
+
+// The commands are encoded as `_IOR`/`_IOW` of `<asm/ioctl.h>`.  Most of
+// the architectures use the generic layout of `<asm-generic/ioctl.h>`,
+// while powerpc, mips and sparc have the wider direction field and the
+// different direction bits.
+#[cfg(not(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc",
+    target_arch = "sparc64",
+)))]
+mod layout {
+    pub(super) const SIZE_BITS: u32 = 14;
+    pub(super) const WRITE: u32 = 1;
+    pub(super) const READ: u32 = 2;
+}
+#[cfg(any(
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+    target_arch = "mips",
+    target_arch = "mips64",
+    target_arch = "sparc",
+    target_arch = "sparc64",
+))]
+mod layout {
+    pub(super) const SIZE_BITS: u32 = 13;
+    pub(super) const WRITE: u32 = 4;
+    pub(super) const READ: u32 = 2;
+}
+
+const fn ioc(dir: u32, ty: u8, nr: u8, size: usize) -> u32 {
+    dir << (16 + layout::SIZE_BITS) | (size as u32) << 16 | (ty as u32) << 8 | nr as u32
+}
+const IOC_WRITE: u32 = layout::WRITE;
+const IOC_READ: u32 = layout::READ;
+
+// The size of `long`, with which the native commands are declared.
+const LONG: usize = mem::size_of::<libc::c_long>();
+// The size of `int`, with which the 32-bit variants are declared.
+const INT: usize = mem::size_of::<libc::c_int>();
+
+/// Get the inode flags (`lsattr(1)`), as `_IOR('f', 1, long)`.
+pub const FS_IOC_GETFLAGS: u32 = ioc(IOC_READ, b'f', 1, LONG);
+/// Set the inode flags (`chattr(1)`), as `_IOW('f', 2, long)`.
+pub const FS_IOC_SETFLAGS: u32 = ioc(IOC_WRITE, b'f', 2, LONG);
+/// Get the inode generation number, as `_IOR('v', 1, long)`.
+pub const FS_IOC_GETVERSION: u32 = ioc(IOC_READ, b'v', 1, LONG);
+/// Set the inode generation number, as `_IOW('v', 2, long)`.
+pub const FS_IOC_SETVERSION: u32 = ioc(IOC_WRITE, b'v', 2, LONG);
+/// The variant of `FS_IOC_GETFLAGS` issued by the 32-bit processes.
+///
+/// The commands of the 32-bit variants are the same as the native ones
+/// on the 32-bit hosts, where `long` has the size of `int`.
+pub const FS_IOC32_GETFLAGS: u32 = ioc(IOC_READ, b'f', 1, INT);
+/// The variant of `FS_IOC_SETFLAGS` issued by the 32-bit processes.
+pub const FS_IOC32_SETFLAGS: u32 = ioc(IOC_WRITE, b'f', 2, INT);
+/// The variant of `FS_IOC_GETVERSION` issued by the 32-bit processes.
+pub const FS_IOC32_GETVERSION: u32 = ioc(IOC_READ, b'v', 1, INT);
+/// The variant of `FS_IOC_SETVERSION` issued by the 32-bit processes.
+pub const FS_IOC32_SETVERSION: u32 = ioc(IOC_WRITE, b'v', 2, INT);
+/// Get the extended attributes of the inode as `struct fsxattr`.
+pub const FS_IOC_FSGETXATTR: u32 = ioc(IOC_READ, b'X', 31, 28);
+/// Set the extended attributes of the inode as `struct fsxattr`.
+pub const FS_IOC_FSSETXATTR: u32 = ioc(IOC_WRITE, b'X', 32, 28);
+
+/// The inode flags exchanged by `FS_IOC_GETFLAGS` and `FS_IOC_SETFLAGS`.
+///
+/// Although the commands are declared with `long`, the kernel and the
+/// tools pass an `int` in both of the 64-bit and the 32-bit layouts, so
+/// the flags are encoded as 4 bytes in the native byte order.
+///
+/// Unlike the local filesystems, the kernel does not enforce these flags
+/// on the FUSE inodes, and the filesystem must check them by itself.
+#[derive(Copy, Clone, Default, PartialEq, Eq, Hash)]
+pub struct InodeFlags(u32);
+
+const INODE_FLAG_NAMES: &[(u32, &str)] = &[
+    (InodeFlags::SYNC.0, "SYNC"),
+    (InodeFlags::IMMUTABLE.0, "IMMUTABLE"),
+    (InodeFlags::APPEND.0, "APPEND"),
+    (InodeFlags::NODUMP.0, "NODUMP"),
+    (InodeFlags::NOATIME.0, "NOATIME"),
+    (InodeFlags::DIRSYNC.0, "DIRSYNC"),
+    (InodeFlags::NOCOW.0, "NOCOW"),
+    (InodeFlags::PROJINHERIT.0, "PROJINHERIT"),
+];
+
+impl InodeFlags {
+    /// Synchronous updates (`FS_SYNC_FL`).
+    pub const SYNC: Self = Self(0x0000_0008);
+    /// The file cannot be modified, removed or linked (`FS_IMMUTABLE_FL`).
+    pub const IMMUTABLE: Self = Self(0x0000_0010);
+    /// The file can only be opened for appending (`FS_APPEND_FL`).
+    pub const APPEND: Self = Self(0x0000_0020);
+    /// The file is not backed up by `dump(8)` (`FS_NODUMP_FL`).
+    pub const NODUMP: Self = Self(0x0000_0040);
+    /// The access time is not updated (`FS_NOATIME_FL`).
+    pub const NOATIME: Self = Self(0x0000_0080);
+    /// Synchronous updates of the directory (`FS_DIRSYNC_FL`).
+    pub const DIRSYNC: Self = Self(0x0001_0000);
+    /// No copy-on-write (`FS_NOCOW_FL`).
+    pub const NOCOW: Self = Self(0x0080_0000);
+    /// The project ID is inherited by the children (`FS_PROJINHERIT_FL`).
+    pub const PROJINHERIT: Self = Self(0x2000_0000);
+
+    /// Create an empty set of flags.
+    #[inline]
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Create a set of flags from the raw value.
+    #[inline]
+    pub const fn from_bits(bits: u32) -> Self {
+        Self(bits)
+    }
+
+    /// Return the raw value of flags.
+    #[inline]
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    /// Return whether all of the specified flags are contained.
+    #[inline]
+    pub const fn contains(self, flags: Self) -> bool {
+        self.0 & flags.0 == flags.0
+    }
+
+    /// Decode the flags from the input data of `FS_IOC_SETFLAGS`.
+    ///
+    /// `None` is returned if the data is shorter than an `int`.
+    pub fn decode(input: &[u8]) -> Option<Self> {
+        let bytes = input.get(..4)?.try_into().ok()?;
+        Some(Self(u32::from_ne_bytes(bytes)))
+    }
+
+    /// Encode the flags into the output data of `FS_IOC_GETFLAGS`.
+    pub fn encode(self) -> [u8; 4] {
+        self.0.to_ne_bytes()
+    }
+}
+
+impl ops::BitOr for InodeFlags {
+    type Output = Self;
+
+    #[inline]
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for InodeFlags {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let names: Vec<_> = INODE_FLAG_NAMES
+            .iter()
+            .filter(|&&(flag, _)| self.0 & flag != 0)
+            .map(|&(_, name)| name)
+            .collect();
+        let unknown = INODE_FLAG_NAMES
+            .iter()
+            .fold(self.0, |bits, &(flag, _)| bits & !flag);
+        match unknown {
+            0 => write!(f, "InodeFlags({})", names.join(" | ")),
+            _ if names.is_empty() => write!(f, "InodeFlags({:#x})", unknown),
+            _ => write!(f, "InodeFlags({} | {:#x})", names.join(" | "), unknown),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ioctl_commands_and_flags() {
+        // The values in <linux/fs.h> on x86_64.
+        #[cfg(target_arch = "x86_64")]
+        {
+            assert_eq!(FS_IOC_GETFLAGS, 0x8008_6601);
+            assert_eq!(FS_IOC_SETFLAGS, 0x4008_6602);
+            assert_eq!(FS_IOC32_GETFLAGS, 0x8004_6601);
+            assert_eq!(FS_IOC32_SETFLAGS, 0x4004_6602);
+            assert_eq!(FS_IOC_FSGETXATTR, 0x801c_581f);
+        }
+        // ... and on powerpc64, with the 3-bit direction field.
+        #[cfg(target_arch = "powerpc64")]
+        {
+            assert_eq!(FS_IOC_GETFLAGS, 0x4008_6601);
+            assert_eq!(FS_IOC_SETFLAGS, 0x8008_6602);
+            assert_eq!(FS_IOC32_GETFLAGS, 0x4004_6601);
+        }
+        #[cfg(target_pointer_width = "32")]
+        assert_eq!(FS_IOC_GETFLAGS, FS_IOC32_GETFLAGS);
+        #[cfg(target_pointer_width = "64")]
+        assert_ne!(FS_IOC_GETFLAGS, FS_IOC32_GETFLAGS);
+
+        let flags = InodeFlags::IMMUTABLE | InodeFlags::APPEND;
+        assert_eq!(InodeFlags::decode(&flags.encode()), Some(flags));
+        // The 64-bit layout passes the flags in the leading bytes.
+        let mut long = [0u8; 8];
+        long[..4].copy_from_slice(&flags.encode());
+        assert_eq!(InodeFlags::decode(&long), Some(flags));
+        assert_eq!(InodeFlags::decode(&[0u8; 2]), None);
+
+        assert!(flags.contains(InodeFlags::APPEND));
+        assert!(!flags.contains(InodeFlags::NOATIME));
+        assert_eq!(format!("{:?}", flags), "InodeFlags(IMMUTABLE | APPEND)");
+        assert_eq!(
+            format!("{:?}", InodeFlags::from_bits(0x4 | 0x80)),
+            "InodeFlags(NOATIME | 0x4)"
+        );
+    }
+}
@@ -0,0 +1,206 @@
+use std::{
+    ffi::{OsStr, OsString},
+    fmt,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+};
+
+/// The maximum length of a name in bytes, as `NAME_MAX` in `<limits.h>`.
+pub const NAME_MAX: usize = 255;
+
+/// Check the name of an entry to be created, and return the error number
+/// to reply if it is not acceptable.
+///
+/// The name is the target of `Mknod`, `Mkdir`, `Symlink`, `Create`, `Link`
+/// or the new name of `Rename`.  The kernel rejects most of invalid names
+/// before sending requests, but the filesystems that pass the names to
+/// other APIs (e.g. path-based backends) should not rely on it:
+///
+/// * `EINVAL` if the name is empty, or contains `/` or NUL characters.
+/// * `EEXIST` if the name is `.` or `..`, as `mkdir(2)` does, since these
+///   entries always exist in any directories.
+/// * `ENAMETOOLONG` if the name is longer than `NAME_MAX` bytes.
+pub fn validate_name(name: &OsStr) -> Result<(), i32> {
+    validate_lookup_name(name)?;
+    match name.as_bytes() {
+        b"." | b".." => Err(libc::EEXIST),
+        _ => Ok(()),
+    }
+}
+
+/// Check the name of an existing entry, such as the target of `Lookup`
+/// and the old name of `Rename`.
+///
+/// This is the same as `validate_name` except that `.` and `..` are
+/// accepted, since the kernel looks up `..` of the directories when
+/// `KernelConfig::export_support` is enabled.
+pub fn validate_lookup_name(name: &OsStr) -> Result<(), i32> {
+    let name = name.as_bytes();
+    if name.is_empty() || name.iter().any(|&b| b == b'/' || b == b'\0') {
+        return Err(libc::EINVAL);
+    }
+    if name.len() > NAME_MAX {
+        return Err(libc::ENAMETOOLONG);
+    }
+    Ok(())
+}
+
+/// The types of names passed to the kernel, as the raw bytes.
+///
+/// The names of entries are arbitrary bytes except `/` and NUL, so the
+/// notification APIs accept both `OsStr` and `[u8]` (as well as `str` and
+/// `Path`) and pass the bytes through without conversions.
+pub trait AsNameBytes {
+    /// Return the name as the raw bytes.
+    fn as_name_bytes(&self) -> &[u8];
+}
+
+impl<T: AsNameBytes + ?Sized> AsNameBytes for &T {
+    fn as_name_bytes(&self) -> &[u8] {
+        (**self).as_name_bytes()
+    }
+}
+
+macro_rules! impl_as_name_bytes {
+    ($($t:ty => |$name:ident| $e:expr,)*) => {$(
+        impl AsNameBytes for $t {
+            fn as_name_bytes(&self) -> &[u8] {
+                let $name = self;
+                $e
+            }
+        }
+    )*};
+}
+
+impl_as_name_bytes! {
+    OsStr => |name| name.as_bytes(),
+    OsString => |name| name.as_bytes(),
+    Path => |name| name.as_os_str().as_bytes(),
+    PathBuf => |name| name.as_os_str().as_bytes(),
+    str => |name| name.as_bytes(),
+    String => |name| name.as_bytes(),
+    [u8] => |name| name,
+    Vec<u8> => |name| name,
+}
+
+/// A name shown in the logs without losing the bytes.
+///
+/// `Display` writes the valid UTF-8 sequences as they are, and escapes the
+/// control characters, `\` and the bytes that are not valid UTF-8 (as
+/// `\xff`), so that a name never breaks a line of the log and two
+/// different names are never shown the same.  `Debug` writes the same
+/// string in double quotes.
+#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct DisplayName<'a>(&'a [u8]);
+
+impl<'a> DisplayName<'a> {
+    /// Wrap the name.
+    pub fn new<T: AsNameBytes + ?Sized>(name: &'a T) -> Self {
+        Self(name.as_name_bytes())
+    }
+
+    /// Return the raw bytes of the name.
+    pub fn as_bytes(&self) -> &'a [u8] {
+        self.0
+    }
+
+    fn write_escaped(&self, f: &mut fmt::Formatter<'_>, quoted: bool) -> fmt::Result {
+        use fmt::Write as _;
+
+        let mut bytes = self.0;
+        while !bytes.is_empty() {
+            // Split off the valid UTF-8 sequence and the invalid bytes after it.
+            let (valid, invalid) = match std::str::from_utf8(bytes) {
+                Ok(valid) => (valid, &[][..]),
+                Err(err) => {
+                    let (valid, rest) = bytes.split_at(err.valid_up_to());
+                    let invalid_len = err.error_len().unwrap_or(rest.len());
+                    // SAFETY: the bytes up to `valid_up_to` are valid UTF-8.
+                    let valid = unsafe { std::str::from_utf8_unchecked(valid) };
+                    (valid, &rest[..invalid_len])
+                }
+            };
+            for c in valid.chars() {
+                match c {
+                    '"' if quoted => f.write_str("\\\"")?,
+                    '"' | '\'' => f.write_char(c)?,
+                    c => write!(f, "{}", c.escape_debug())?,
+                }
+            }
+            for b in invalid {
+                write!(f, "\\x{:02x}", b)?;
+            }
+            bytes = &bytes[valid.len() + invalid.len()..];
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for DisplayName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write_escaped(f, false)
+    }
+}
+
+impl fmt::Debug for DisplayName<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("\"")?;
+        self.write_escaped(f, true)?;
+        f.write_str("\"")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    type Rule<'a> = (&'a [u8], Result<(), i32>, Result<(), i32>);
+
+    #[test]
+    fn name_rules() {
+        let long = "a".repeat(NAME_MAX);
+        let too_long = "a".repeat(NAME_MAX + 1);
+        let table: &[Rule<'_>] = &[
+            (b"foo", Ok(()), Ok(())),
+            (b".hidden", Ok(()), Ok(())),
+            (b"...", Ok(()), Ok(())),
+            (long.as_bytes(), Ok(()), Ok(())),
+            (b"", Err(libc::EINVAL), Err(libc::EINVAL)),
+            (b"a/b", Err(libc::EINVAL), Err(libc::EINVAL)),
+            (b"a\0b", Err(libc::EINVAL), Err(libc::EINVAL)),
+            (b".", Err(libc::EEXIST), Ok(())),
+            (b"..", Err(libc::EEXIST), Ok(())),
+            (
+                too_long.as_bytes(),
+                Err(libc::ENAMETOOLONG),
+                Err(libc::ENAMETOOLONG),
+            ),
+        ];
+
+        for &(name, create, lookup) in table {
+            let name = OsStr::from_bytes(name);
+            assert_eq!(validate_name(name), create, "create {:?}", name);
+            assert_eq!(validate_lookup_name(name), lookup, "lookup {:?}", name);
+        }
+    }
+
+    #[test]
+    fn display_names() {
+        let name = OsStr::from_bytes(b"caf\xc3\xa9\xff\n\"x\"\\");
+        assert_eq!(
+            DisplayName::new(name).to_string(),
+            "caf\u{e9}\\xff\\n\"x\"\\\\"
+        );
+        assert_eq!(
+            format!("{:?}", DisplayName::new(name)),
+            "\"caf\u{e9}\\xff\\n\\\"x\\\"\\\\\""
+        );
+        // The bytes are kept as they are.
+        assert_eq!(DisplayName::new(name).as_bytes(), name.as_bytes());
+        assert_eq!(DisplayName::new(&b"a\xffb"[..]).to_string(), "a\\xffb");
+        assert_ne!(
+            DisplayName::new(&b"\xff"[..]).to_string(),
+            DisplayName::new("\u{fffd}").to_string()
+        );
+    }
+}
@@ -0,0 +1,51 @@
+//! Conversions between the integer types used in the FUSE protocol.
+//!
+//! The kernel transfers file offsets as `u64` while they are `loff_t` (i.e.
+//! signed 64-bit integers) inside of the kernel. A value that does not fit in
+//! the range of `i64` is never produced by a well-behaved kernel, so they are
+//! rejected instead of being wrapped silently.
+//!
+//! Note that the directory offsets used in `READDIR` are opaque cookies chosen
+//! by the filesystem and thus they are not checked here.
+
+#![deny(clippy::cast_sign_loss, clippy::cast_possible_wrap)]
+
+use std::convert::TryFrom;
+
+/// Convert a file offset received from the kernel into the signed representation.
+#[inline]
+pub(crate) fn file_offset(offset: u64) -> Option<i64> {
+    i64::try_from(offset).ok()
+}
+
+/// Convert a region of file received from the kernel into the signed representation.
+///
+/// In addition to the offset, the end of region must not exceed `i64::MAX`.
+#[inline]
+pub(crate) fn file_range(offset: u64, length: u64) -> Option<(i64, i64)> {
+    let offset = file_offset(offset)?;
+    let length = i64::try_from(length).ok()?;
+    offset.checked_add(length)?;
+    Some((offset, length))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_offset_boundary() {
+        assert_eq!(file_offset(0), Some(0));
+        assert_eq!(file_offset(i64::MAX as u64), Some(i64::MAX));
+        assert_eq!(file_offset(i64::MAX as u64 + 1), None);
+        assert_eq!(file_offset(u64::MAX), None);
+    }
+
+    #[test]
+    fn file_range_boundary() {
+        assert_eq!(file_range(0, i64::MAX as u64), Some((0, i64::MAX)));
+        assert_eq!(file_range(i64::MAX as u64, 0), Some((i64::MAX, 0)));
+        assert_eq!(file_range(1, i64::MAX as u64), None);
+        assert_eq!(file_range(0, i64::MAX as u64 + 1), None);
+    }
+}
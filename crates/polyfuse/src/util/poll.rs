@@ -0,0 +1,138 @@
+use crate::{Notifier, Operation};
+use std::{collections::HashMap, fmt, io, sync::Mutex};
+
+/// A registry of the poll handles to be notified when the I/O becomes ready.
+///
+/// When a `POLL` request is issued with `FUSE_POLL_SCHEDULE_NOTIFY`, the
+/// kernel waits for a wakeup notification with the handle `kh` before
+/// polling the file again.  This registry remembers such the handles for
+/// each key, such as the file handle or the inode number, and sends the
+/// notifications to all of them at once when the key becomes ready.
+///
+/// The registry can be shared between the request handlers and the
+/// callbacks that observe the readiness of the backend.
+#[derive(Default)]
+pub struct PollRegistry {
+    handles: Mutex<HashMap<u64, Vec<u64>>>,
+}
+
+impl fmt::Debug for PollRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PollRegistry").finish()
+    }
+}
+
+impl PollRegistry {
+    /// Create an empty registry.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register the poll handle to be notified when `key` becomes ready.
+    pub fn register(&self, key: u64, kh: u64) {
+        let mut handles = self.handles.lock().unwrap();
+        let khs = handles.entry(key).or_default();
+        if !khs.contains(&kh) {
+            khs.push(kh);
+        }
+    }
+
+    /// Send the wakeup notifications to all of the poll handles registered
+    /// for `key`, and forget them.
+    ///
+    /// The return value is the number of the sent notifications.  The kernel
+    /// registers the handle again by the next `POLL` request if needed.
+    ///
+    /// A failed notification does not stop the others, since the handles
+    /// are forgotten anyway.  The first error is returned after all of
+    /// the handles have been notified.
+    pub fn wake(&self, key: u64, notifier: &Notifier) -> io::Result<usize> {
+        let khs = match self.handles.lock().unwrap().remove(&key) {
+            Some(khs) => khs,
+            None => return Ok(0),
+        };
+        let mut error = None;
+        for &kh in &khs {
+            if let Err(err) = notifier.poll_wakeup(kh) {
+                error.get_or_insert(err);
+            }
+        }
+        match error {
+            Some(err) => Err(err),
+            None => Ok(khs.len()),
+        }
+    }
+
+    /// Forget the poll handles registered for `key` without notifying.
+    pub fn remove(&self, key: u64) {
+        self.handles.lock().unwrap().remove(&key);
+    }
+
+    /// Update the registry according to the request, using the file handle as the key.
+    ///
+    /// The poll handle of a `POLL` request is registered if the kernel
+    /// requests the notification, and the handles of a file are forgotten
+    /// when it is released.
+    pub fn track<T>(&self, op: &Operation<'_, T>) {
+        match op {
+            Operation::Poll(op) => {
+                if let Some(kh) = op.kh() {
+                    self.register(op.fh(), kh);
+                }
+            }
+            Operation::Release(op) => self.remove(op.fh()),
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn register_wake_and_poll_again() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let notifier = session.notifier();
+        let registry = PollRegistry::new();
+
+        let poll_in = fuse_poll_in {
+            fh: 3,
+            kh: 7,
+            flags: FUSE_POLL_SCHEDULE_NOTIFY,
+            events: libc::POLLIN as u32,
+        };
+        for _ in 0..2 {
+            kernel
+                .send_request(fuse_opcode::FUSE_POLL as u32, 2, poll_in.as_bytes())
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            registry.track(&req.operation().unwrap());
+            assert_eq!(registry.handles.lock().unwrap()[&3], vec![7]);
+
+            assert_eq!(registry.wake(3, &notifier).unwrap(), 1);
+            let wakeup = kernel.recv_reply().unwrap();
+            assert_eq!(wakeup.unique(), 0);
+            assert_eq!(wakeup.error(), fuse_notify_code::FUSE_NOTIFY_POLL as i32);
+            assert_eq!(wakeup.payload(), 7u64.to_ne_bytes());
+
+            // The handle is forgotten after the wakeup.
+            assert_eq!(registry.wake(3, &notifier).unwrap(), 0);
+        }
+
+        let release_in = fuse_release_in {
+            fh: 3,
+            ..Default::default()
+        };
+        registry.register(3, 7);
+        kernel
+            .send_request(fuse_opcode::FUSE_RELEASE as u32, 2, release_in.as_bytes())
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        registry.track(&req.operation().unwrap());
+        assert!(registry.handles.lock().unwrap().is_empty());
+    }
+}
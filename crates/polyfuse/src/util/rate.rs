@@ -0,0 +1,315 @@
+use crate::{Operation, Request};
+use std::{
+    collections::HashMap,
+    fmt, io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Per-user rate limiting of the requests, with token buckets.
+///
+/// On a mount shared by several users, a single process walking the tree
+/// (e.g. `grep -r`) can keep all of the handlers busy.  `admit` charges
+/// the request to the bucket of the caller's uid, and delays the caller
+/// when the bucket is exhausted, so that the requests of the other users
+/// are processed in the meantime.  `READ` and `WRITE` are also charged by
+/// their sizes if the byte rate is limited.
+///
+/// Each bucket holds up to one second of its rate, which is the burst
+/// allowed after a period of inactivity.  The buckets are created on the
+/// first request of each uid, and removed after they have been idle for
+/// the specified duration.
+///
+/// The delay is a blocking sleep, so `admit` should be called on the
+/// spawned threads rather than on the receiving loop.  A request that
+/// would have to wait longer than `max_delay` fails with `EBUSY` instead.
+pub struct RateLimit {
+    ops_per_sec: Option<f64>,
+    bytes_per_sec: Option<f64>,
+    max_delay: Option<Duration>,
+    idle_timeout: Duration,
+    state: Mutex<State>,
+    throttled: AtomicU64,
+    rejected: AtomicU64,
+}
+
+struct State {
+    buckets: HashMap<u32, Bucket>,
+    last_sweep: Instant,
+}
+
+struct Bucket {
+    ops: f64,
+    bytes: f64,
+    updated: Instant,
+}
+
+impl fmt::Debug for RateLimit {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RateLimit")
+            .field("ops_per_sec", &self.ops_per_sec)
+            .field("bytes_per_sec", &self.bytes_per_sec)
+            .field("max_delay", &self.max_delay)
+            .field("throttled", &self.throttled())
+            .field("rejected", &self.rejected())
+            .finish()
+    }
+}
+
+impl Default for RateLimit {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RateLimit {
+    /// Create a rate limiter with no limits.
+    pub fn new() -> Self {
+        Self {
+            ops_per_sec: None,
+            bytes_per_sec: None,
+            max_delay: None,
+            idle_timeout: Duration::from_secs(60),
+            state: Mutex::new(State {
+                buckets: HashMap::new(),
+                last_sweep: Instant::now(),
+            }),
+            throttled: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+        }
+    }
+
+    /// Limit the number of requests per second of each uid.
+    pub fn ops_per_sec(&mut self, rate: u32) -> &mut Self {
+        self.ops_per_sec = Some(f64::from(rate.max(1)));
+        self
+    }
+
+    /// Limit the bytes read and written per second by each uid.
+    pub fn bytes_per_sec(&mut self, rate: u64) -> &mut Self {
+        self.bytes_per_sec = Some(rate.max(1) as f64);
+        self
+    }
+
+    /// Reply `EBUSY` to the requests that would be delayed longer than `max_delay`.
+    ///
+    /// By default, the requests are always delayed.
+    pub fn max_delay(&mut self, max_delay: Duration) -> &mut Self {
+        self.max_delay = Some(max_delay);
+        self
+    }
+
+    /// Specify the duration after which an unused bucket is removed.
+    ///
+    /// The default value is 60 seconds.
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.idle_timeout = timeout;
+        self
+    }
+
+    /// Charge the request to the bucket of its caller, and wait until the
+    /// budget allows it.
+    ///
+    /// This fails with `EBUSY` if the required delay exceeds `max_delay`,
+    /// in which case nothing is charged.
+    pub fn admit(&self, req: &Request) -> io::Result<()> {
+        let bytes = match req.operation() {
+            Ok(Operation::Read(op)) => u64::from(op.size()),
+            Ok(Operation::Write(op, _)) => u64::from(op.size()),
+            _ => 0,
+        };
+        let delay = self.charge(req.uid(), bytes, Instant::now())?;
+        if delay > Duration::from_secs(0) {
+            self.throttled.fetch_add(1, Ordering::Relaxed);
+            debug!(
+                "throttle the request (unique = {}, uid = {}, delay = {:?})",
+                req.unique(),
+                req.uid(),
+                delay
+            );
+            thread::sleep(delay);
+        }
+        Ok(())
+    }
+
+    /// Take the tokens from the bucket of `uid`, and return how long the
+    /// caller has to wait for them.
+    fn charge(&self, uid: u32, bytes: u64, now: Instant) -> io::Result<Duration> {
+        let mut state = self.state.lock().unwrap();
+        if now.saturating_duration_since(state.last_sweep) >= self.idle_timeout {
+            let idle_timeout = self.idle_timeout;
+            state
+                .buckets
+                .retain(|_, bucket| now.saturating_duration_since(bucket.updated) < idle_timeout);
+            state.last_sweep = now;
+        }
+
+        let ops_per_sec = self.ops_per_sec;
+        let bytes_per_sec = self.bytes_per_sec;
+        let bucket = state.buckets.entry(uid).or_insert_with(|| Bucket {
+            ops: ops_per_sec.unwrap_or(0.0),
+            bytes: bytes_per_sec.unwrap_or(0.0),
+            updated: now,
+        });
+
+        // Refill the bucket, up to one second of the rate.
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.updated = bucket.updated.max(now);
+        let mut wait = 0.0f64;
+        let mut ops = bucket.ops;
+        if let Some(rate) = ops_per_sec {
+            ops = (ops + elapsed * rate).min(rate) - 1.0;
+            wait = wait.max(-ops / rate);
+        }
+        let mut remaining_bytes = bucket.bytes;
+        if let Some(rate) = bytes_per_sec {
+            remaining_bytes = (remaining_bytes + elapsed * rate).min(rate) - bytes as f64;
+            wait = wait.max(-remaining_bytes / rate);
+        }
+
+        let delay = Duration::from_secs_f64(wait.max(0.0));
+        if let Some(max_delay) = self.max_delay {
+            if delay > max_delay {
+                self.rejected.fetch_add(1, Ordering::Relaxed);
+                return Err(io::Error::from_raw_os_error(libc::EBUSY));
+            }
+        }
+
+        // The tokens may go negative, so the following requests wait
+        // behind this one.
+        bucket.ops = ops;
+        bucket.bytes = remaining_bytes;
+        Ok(delay)
+    }
+
+    /// Return the number of requests delayed so far.
+    pub fn throttled(&self) -> u64 {
+        self.throttled.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of requests failed with `EBUSY` so far.
+    pub fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Return the number of buckets currently kept.
+    pub fn buckets(&self) -> usize {
+        self.state.lock().unwrap().buckets.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use std::sync::Arc;
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn bucket_refill_and_hard_cap() {
+        let mut limit = RateLimit::new();
+        limit
+            .ops_per_sec(10)
+            .bytes_per_sec(4096)
+            .max_delay(Duration::from_secs(1));
+        let start = Instant::now();
+
+        // The burst of one second is allowed.
+        for _ in 0..10 {
+            assert_eq!(
+                limit.charge(1000, 0, start).unwrap(),
+                Duration::from_secs(0)
+            );
+        }
+        let delay = limit.charge(1000, 0, start).unwrap();
+        assert!(delay > Duration::from_millis(90) && delay <= Duration::from_millis(100));
+        // The other users have their own buckets.
+        assert_eq!(
+            limit.charge(2000, 0, start).unwrap(),
+            Duration::from_secs(0)
+        );
+
+        // 12 KiB exceeds the byte budget by two seconds.
+        let err = limit.charge(2000, 12288, start).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EBUSY));
+        assert_eq!(limit.rejected(), 1);
+        let later = start + Duration::from_secs(1);
+        assert_eq!(
+            limit.charge(2000, 4096, later).unwrap(),
+            Duration::from_secs(0)
+        );
+    }
+
+    #[test]
+    fn idle_buckets_expire() {
+        let mut limit = RateLimit::new();
+        limit.ops_per_sec(10).idle_timeout(Duration::from_secs(5));
+        let start = Instant::now();
+        limit.charge(1000, 0, start).unwrap();
+        limit
+            .charge(2000, 0, start + Duration::from_secs(4))
+            .unwrap();
+        assert_eq!(limit.buckets(), 2);
+        limit
+            .charge(2000, 0, start + Duration::from_secs(6))
+            .unwrap();
+        assert_eq!(limit.buckets(), 1);
+    }
+
+    #[test]
+    fn delay_over_budget_user() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let session = Arc::new(session);
+        let mut limit = RateLimit::new();
+        limit.ops_per_sec(20);
+        let limit = Arc::new(limit);
+
+        // uid 1000 sends 30 requests at once, and uid 2000 sends 5 after them.
+        let getattr_in = fuse_getattr_in::default();
+        kernel.set_credentials(Some((1000, 1000)));
+        for _ in 0..30 {
+            kernel
+                .send_request(FUSE_GETATTR, 1, getattr_in.as_bytes())
+                .unwrap();
+        }
+        kernel.set_credentials(Some((2000, 2000)));
+        for _ in 0..5 {
+            kernel
+                .send_request(FUSE_GETATTR, 1, getattr_in.as_bytes())
+                .unwrap();
+        }
+
+        let start = Instant::now();
+        let handlers: Vec<_> = (0..35)
+            .map(|_| {
+                let req = session.next_request().unwrap().unwrap();
+                let limit = limit.clone();
+                thread::spawn(move || {
+                    limit.admit(&req).unwrap();
+                    let elapsed = start.elapsed();
+                    req.reply_error(libc::ENOSYS).unwrap();
+                    (req.uid(), elapsed)
+                })
+            })
+            .collect();
+        let mut slowest = HashMap::new();
+        for handler in handlers {
+            let (uid, elapsed) = handler.join().unwrap();
+            let slowest = slowest.entry(uid).or_insert(elapsed);
+            *slowest = (*slowest).max(elapsed);
+        }
+        for _ in 0..35 {
+            kernel.recv_reply().unwrap();
+        }
+
+        // The 10 requests over the burst of uid 1000 take another 0.5 seconds.
+        assert!(slowest[&1000] >= Duration::from_millis(400));
+        assert!(slowest[&2000] < Duration::from_millis(200));
+        assert_eq!(limit.throttled(), 10);
+    }
+}
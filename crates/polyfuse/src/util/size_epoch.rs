@@ -0,0 +1,120 @@
+use std::{collections::HashMap, fmt, sync::Mutex};
+
+/// A per-inode tracker of the file sizes extended by writes, for the
+/// filesystems that enable `KernelConfig::writeback_cache`.
+///
+/// With the writeback cache, the kernel owns the file size until the dirty
+/// pages are written back, and it trusts the size replied to `GETATTR` and
+/// `SETATTR`.  If the backend applies the writes lazily, replying its
+/// stale size makes the kernel truncate the cached pages that are still in
+/// flight, and the readers on the same mount see the file shrink.
+///
+/// The pattern is as follows:
+///
+/// * Call `record_write` on each `WRITE` request with its range, before
+///   replying to it.
+/// * Pass the size known to the backend through `size` when filling the
+///   attributes of `GETATTR` and `SETATTR`, which never returns a size
+///   smaller than the end of the recorded writes.
+/// * Once the writes are persisted in the backend (e.g. on `FLUSH` or
+///   `FSYNC`), call `flushed` with the epoch taken by `epoch` before
+///   starting to flush, so that the writes arriving meanwhile are kept.
+/// * Call `truncate` on `SETATTR` with a size, or `OPEN` with `O_TRUNC`,
+///   since the size set explicitly should be reported as is.
+/// * Call `forget` when the inode is removed from the filesystem.
+#[derive(Default)]
+pub struct SizeEpoch {
+    inodes: Mutex<HashMap<u64, Pending>>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    end: u64,
+    epoch: u64,
+}
+
+impl fmt::Debug for SizeEpoch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SizeEpoch").finish()
+    }
+}
+
+impl SizeEpoch {
+    /// Create an empty tracker.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record a write of `len` bytes at `offset`, and return the new epoch of the inode.
+    pub fn record_write(&self, ino: u64, offset: u64, len: u64) -> u64 {
+        let mut inodes = self.inodes.lock().unwrap();
+        let pending = inodes.entry(ino).or_insert(Pending { end: 0, epoch: 0 });
+        pending.end = pending.end.max(offset.saturating_add(len));
+        pending.epoch += 1;
+        pending.epoch
+    }
+
+    /// Return the current epoch of the inode, which is advanced by every `record_write`.
+    pub fn epoch(&self, ino: u64) -> u64 {
+        let inodes = self.inodes.lock().unwrap();
+        inodes.get(&ino).map_or(0, |pending| pending.epoch)
+    }
+
+    /// Return the size to be reported to the kernel, given the size known to the backend.
+    pub fn size(&self, ino: u64, backend_size: u64) -> u64 {
+        let inodes = self.inodes.lock().unwrap();
+        inodes
+            .get(&ino)
+            .map_or(backend_size, |pending| pending.end.max(backend_size))
+    }
+
+    /// Mark the writes up to `epoch` as persisted in the backend.
+    ///
+    /// The recorded writes are discarded only if no write has been
+    /// recorded since `epoch` was taken.
+    pub fn flushed(&self, ino: u64, epoch: u64) {
+        let mut inodes = self.inodes.lock().unwrap();
+        if matches!(inodes.get(&ino), Some(pending) if pending.epoch == epoch) {
+            inodes.remove(&ino);
+        }
+    }
+
+    /// Discard the recorded writes, as the file size is set explicitly.
+    pub fn truncate(&self, ino: u64) {
+        self.inodes.lock().unwrap().remove(&ino);
+    }
+
+    /// Discard the state of the inode.
+    pub fn forget(&self, ino: u64) {
+        self.truncate(ino);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn size_epoch() {
+        let sizes = SizeEpoch::new();
+        assert_eq!(sizes.size(2, 10), 10);
+
+        sizes.record_write(2, 0, 4096);
+        let epoch = sizes.record_write(2, 8192, 100);
+        assert_eq!(sizes.size(2, 0), 8292);
+        assert_eq!(sizes.size(2, 10000), 10000);
+        assert_eq!(sizes.size(3, 0), 0);
+
+        // A write arriving during the flush keeps the recorded size.
+        sizes.record_write(2, 100, 10);
+        sizes.flushed(2, epoch);
+        assert_eq!(sizes.size(2, 0), 8292);
+
+        sizes.flushed(2, sizes.epoch(2));
+        assert_eq!(sizes.size(2, 0), 0);
+
+        sizes.record_write(2, 0, 4096);
+        sizes.truncate(2);
+        assert_eq!(sizes.size(2, 0), 0);
+    }
+}
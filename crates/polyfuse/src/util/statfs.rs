@@ -0,0 +1,221 @@
+use super::clock::{Clock, SystemClock};
+use crate::{reply::StatfsOut, Errno, Operation, Request};
+use std::{
+    fmt, io,
+    sync::{Condvar, Mutex},
+    time::{Duration, Instant},
+};
+
+/// A cache of the filesystem statistics, refreshed at most once per interval.
+///
+/// Some applications, such as file managers, call `statfs(2)` very
+/// frequently, whereas computing the statistics may be expensive for the
+/// filesystems backed by remote storage.  This wrapper keeps the last
+/// statistics during the specified interval, and when it expires, only one
+/// of the concurrent callers invokes the refresh closure while the others
+/// wait for its result.
+///
+/// `get` and `handle` run the refresh closure on the calling thread, or
+/// park it until another caller finishes the refresh, so an asynchronous
+/// filesystem should answer `STATFS` from a thread that is allowed to block.
+pub struct CachedStatfs<F> {
+    refresh: F,
+    interval: Duration,
+    clock: Box<dyn Clock>,
+    state: Mutex<State>,
+    cond: Condvar,
+}
+
+#[derive(Default)]
+struct State {
+    cached: Option<(Instant, StatfsOut)>,
+    refreshing: bool,
+}
+
+impl<F> fmt::Debug for CachedStatfs<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("CachedStatfs")
+            .field("interval", &self.interval)
+            .finish()
+    }
+}
+
+impl<F> CachedStatfs<F>
+where
+    F: Fn() -> io::Result<StatfsOut>,
+{
+    /// Create a cache that refreshes the statistics with `refresh` after `interval`.
+    pub fn new(interval: Duration, refresh: F) -> Self {
+        Self {
+            refresh,
+            interval,
+            clock: Box::new(SystemClock),
+            state: Mutex::new(State::default()),
+            cond: Condvar::new(),
+        }
+    }
+
+    /// Measure the interval with `clock` instead of the system clock.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Return the cached statistics, refreshing them if expired.
+    ///
+    /// If the refresh fails, the error is returned only to the caller that
+    /// invoked it, and one of the waiting callers tries again.
+    pub fn get(&self) -> io::Result<StatfsOut> {
+        let mut state = self.state.lock().unwrap();
+        loop {
+            if let Some((refreshed, ref out)) = state.cached {
+                let elapsed = self.clock.instant().saturating_duration_since(refreshed);
+                if elapsed < self.interval {
+                    return Ok(out.clone());
+                }
+            }
+            if !state.refreshing {
+                break;
+            }
+            state = self.cond.wait(state).unwrap();
+        }
+        state.refreshing = true;
+        drop(state);
+
+        let guard = RefreshGuard(self);
+        let result = (self.refresh)();
+        if let Ok(ref out) = result {
+            self.state.lock().unwrap().cached = Some((self.clock.instant(), out.clone()));
+        }
+        drop(guard);
+        result
+    }
+
+    /// Discard the cached statistics, so that the next `get` refreshes them.
+    pub fn invalidate(&self) {
+        self.state.lock().unwrap().cached = None;
+    }
+
+    /// Reply to the request with the cached statistics if it is a `STATFS` request.
+    ///
+    /// The return value indicates whether the request has been handled, so
+    /// that this method can be placed in front of the filesystem's dispatcher.
+    pub fn handle(&self, req: &Request) -> io::Result<bool> {
+        match req.operation() {
+            Ok(Operation::Statfs(..)) => (),
+            _ => return Ok(false),
+        }
+        match self.get() {
+            Ok(out) => req.reply(out)?,
+            Err(err) => req.reply_error(Errno::from_io_error(&err).raw())?,
+        }
+        Ok(true)
+    }
+}
+
+/// Clear the refreshing flag and wake up the waiters, even if the refresh panics.
+struct RefreshGuard<'a, F>(&'a CachedStatfs<F>);
+
+impl<F> Drop for RefreshGuard<'_, F> {
+    fn drop(&mut self) {
+        // Ignore poisoning so that the flag is cleared while panicking.
+        let mut state = match self.0.state.lock() {
+            Ok(state) => state,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        state.refreshing = false;
+        self.0.cond.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, util::ManualClock, KernelConfig};
+    use polyfuse_kernel::*;
+    use std::{
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc, Barrier,
+        },
+        thread,
+    };
+
+    #[test]
+    fn single_flight_refresh() {
+        let calls = Arc::new(AtomicUsize::new(0));
+        let statfs = Arc::new(CachedStatfs::new(Duration::from_secs(3600), {
+            let calls = calls.clone();
+            move || {
+                calls.fetch_add(1, Ordering::SeqCst);
+                thread::sleep(Duration::from_millis(50));
+                let mut out = StatfsOut::default();
+                out.statfs().namelen(255);
+                Ok(out)
+            }
+        }));
+
+        let barrier = Arc::new(Barrier::new(8));
+        let threads: Vec<_> = (0..8)
+            .map(|_| {
+                let statfs = statfs.clone();
+                let barrier = barrier.clone();
+                thread::spawn(move || {
+                    barrier.wait();
+                    statfs.get().unwrap();
+                })
+            })
+            .collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+
+        statfs.invalidate();
+        statfs.get().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn refresh_after_interval() {
+        let clock = ManualClock::default();
+        let calls = AtomicUsize::new(0);
+        let statfs = CachedStatfs::new(Duration::from_secs(10), || {
+            calls.fetch_add(1, Ordering::SeqCst);
+            Ok(StatfsOut::default())
+        })
+        .with_clock(clock.clone());
+
+        statfs.get().unwrap();
+        clock.advance(Duration::from_secs(9));
+        statfs.get().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 1);
+        clock.advance(Duration::from_secs(1));
+        statfs.get().unwrap();
+        assert_eq!(calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn handle_statfs_request() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let statfs = CachedStatfs::new(Duration::from_secs(3600), || {
+            Err(io::Error::from_raw_os_error(libc::EAGAIN))
+        });
+
+        kernel
+            .send_request(fuse_opcode::FUSE_GETATTR as u32, 1, &[0u8; 16])
+            .unwrap();
+        kernel
+            .send_request(fuse_opcode::FUSE_STATFS as u32, 1, &[])
+            .unwrap();
+
+        let req = session.next_request().unwrap().unwrap();
+        assert!(!statfs.handle(&req).unwrap());
+        let req = session.next_request().unwrap().unwrap();
+        assert!(statfs.handle(&req).unwrap());
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EAGAIN);
+    }
+}
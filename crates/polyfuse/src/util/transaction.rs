@@ -0,0 +1,255 @@
+use crate::{errno::Errno, OpcodeClass, Request};
+use std::{fmt, io};
+
+/// A hook opening a transaction of the backend around each operation.
+///
+/// `transact` calls `begin` before the handler, and then calls exactly
+/// one of `commit` and `rollback` with the transaction, depending on the
+/// outcome of the request.  This is intended for the backends like
+/// databases, where each mutating operation should be atomic.
+pub trait TransactionHook: Send + Sync {
+    /// The transaction of the backend.
+    type Transaction;
+
+    /// Open a transaction for the request, or return `None` if the request
+    /// does not need one.
+    ///
+    /// `class` is the class of the request's opcode, e.g. the hook may open
+    /// transactions only for `OpcodeClass::Write`.  If this fails, the
+    /// handler is not called and the request is replied with the error.
+    fn begin(&self, req: &Request, class: OpcodeClass) -> io::Result<Option<Self::Transaction>>;
+
+    /// Commit the transaction, after a successful reply has been written.
+    fn commit(&self, tx: Self::Transaction);
+
+    /// Roll back the transaction, since the request has not been replied successfully.
+    fn rollback(&self, tx: Self::Transaction, outcome: TransactionOutcome<'_>);
+}
+
+/// The reason why a transaction is rolled back.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum TransactionOutcome<'a> {
+    /// The request has been replied with the positive error number.
+    Replied(i32),
+
+    /// The handler or the reply has failed, and no reply has been written.
+    Failed(&'a io::Error),
+
+    /// The handler has returned without replying.
+    NotReplied,
+
+    /// The handler has panicked.
+    ///
+    /// The rollback runs during the unwinding, so it should not panic.
+    Panicked,
+}
+
+impl fmt::Display for TransactionOutcome<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Replied(errno) => write!(f, "replied with {}", Errno::from_raw(*errno)),
+            Self::Failed(err) => write!(f, "failed: {}", err),
+            Self::NotReplied => f.write_str("not replied"),
+            Self::Panicked => f.write_str("the handler has panicked"),
+        }
+    }
+}
+
+/// Process the request with `f` inside of a transaction opened by `hook`.
+///
+/// The handler is called through `Request::process`, so its errors are
+/// replied as usual.  The transaction is committed only if the reply has
+/// been written with no error, and rolled back otherwise, including when
+/// `f` panics; the panic is resumed after the rollback.
+pub fn transact<H, F>(hook: &H, req: &Request, f: F) -> io::Result<()>
+where
+    H: TransactionHook + ?Sized,
+    F: FnOnce(&Request) -> io::Result<()>,
+{
+    let class = OpcodeClass::of(req.raw_opcode());
+    let tx = match hook.begin(req, class) {
+        Ok(Some(tx)) => tx,
+        Ok(None) => return req.process(f),
+        Err(err) => {
+            debug!(
+                "failed to begin a transaction (unique = {}): {}",
+                req.unique(),
+                err
+            );
+            return req.process(|_| Err(err));
+        }
+    };
+
+    let mut guard = RollbackGuard { hook, tx: Some(tx) };
+    let res = req.process(f);
+    let tx = guard.tx.take().expect("the transaction has been taken");
+    drop(guard);
+
+    match (req.reply_errno(), &res) {
+        (Some(0), _) => hook.commit(tx),
+        (Some(errno), _) => hook.rollback(tx, TransactionOutcome::Replied(errno)),
+        (None, Err(err)) => hook.rollback(tx, TransactionOutcome::Failed(err)),
+        (None, Ok(())) => hook.rollback(tx, TransactionOutcome::NotReplied),
+    }
+    res
+}
+
+/// Roll back the transaction if the handler panics.
+struct RollbackGuard<'a, H: TransactionHook + ?Sized> {
+    hook: &'a H,
+    tx: Option<H::Transaction>,
+}
+
+impl<H: TransactionHook + ?Sized> Drop for RollbackGuard<'_, H> {
+    fn drop(&mut self) {
+        if let Some(tx) = self.tx.take() {
+            self.hook.rollback(tx, TransactionOutcome::Panicked);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, KernelConfig};
+    use polyfuse_kernel::*;
+    use std::{
+        panic::{self, AssertUnwindSafe},
+        sync::Mutex,
+    };
+    use zerocopy::AsBytes as _;
+
+    #[derive(Default)]
+    struct Log {
+        entries: Mutex<Vec<String>>,
+        fail_begin: bool,
+    }
+
+    impl Log {
+        fn take(&self) -> Vec<String> {
+            std::mem::take(&mut *self.entries.lock().unwrap())
+        }
+    }
+
+    impl TransactionHook for Log {
+        type Transaction = u64;
+
+        fn begin(&self, req: &Request, class: OpcodeClass) -> io::Result<Option<u64>> {
+            if class != OpcodeClass::Write {
+                return Ok(None);
+            }
+            if self.fail_begin {
+                return Err(io::Error::from_raw_os_error(libc::EROFS));
+            }
+            let mut entries = self.entries.lock().unwrap();
+            entries.push(format!("begin {}", req.unique()));
+            Ok(Some(req.unique()))
+        }
+
+        fn commit(&self, tx: u64) {
+            let mut entries = self.entries.lock().unwrap();
+            entries.push(format!("commit {}", tx));
+        }
+
+        fn rollback(&self, tx: u64, outcome: TransactionOutcome<'_>) {
+            let outcome = match outcome {
+                TransactionOutcome::Replied(errno) => format!("errno {}", errno),
+                TransactionOutcome::Failed(..) => "failed".into(),
+                TransactionOutcome::NotReplied => "not replied".into(),
+                TransactionOutcome::Panicked => "panicked".into(),
+            };
+            let mut entries = self.entries.lock().unwrap();
+            entries.push(format!("rollback {} ({})", tx, outcome));
+        }
+    }
+
+    #[test]
+    fn commit_and_rollback_by_outcome() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let log = Log::default();
+        let mkdir = |f: &dyn Fn(&Request) -> io::Result<()>| {
+            let mut arg = fuse_mkdir_in::default().as_bytes().to_vec();
+            arg.extend_from_slice(b"dir\0");
+            let unique = kernel
+                .send_request(fuse_opcode::FUSE_MKDIR as u32, 1, &arg[..])
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            let res = panic::catch_unwind(AssertUnwindSafe(|| transact(&log, &req, f)));
+            (unique, res)
+        };
+
+        let (unique, res) = mkdir(&|req| req.reply(crate::reply::EntryOut::default()));
+        assert!(res.unwrap().is_ok());
+        assert_eq!(kernel.recv_reply().unwrap().error(), 0);
+        assert_eq!(
+            log.take(),
+            [format!("begin {}", unique), format!("commit {}", unique)]
+        );
+
+        let (unique, res) = mkdir(&|req| req.reply_error(libc::ENOSPC));
+        assert!(res.unwrap().is_ok());
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSPC);
+        assert_eq!(
+            log.take(),
+            [
+                format!("begin {}", unique),
+                format!("rollback {} (errno {})", unique, libc::ENOSPC)
+            ]
+        );
+
+        // The error returned without replying is replied by `process`.
+        let (unique, res) = mkdir(&|_| Err(io::Error::from_raw_os_error(libc::EIO)));
+        assert!(res.unwrap().is_err());
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EIO);
+        assert_eq!(
+            log.take(),
+            [
+                format!("begin {}", unique),
+                format!("rollback {} (errno {})", unique, libc::EIO)
+            ]
+        );
+
+        let (unique, res) = mkdir(&|_| panic!("the handler panics"));
+        assert!(res.is_err());
+        assert_eq!(
+            log.take(),
+            [
+                format!("begin {}", unique),
+                format!("rollback {} (panicked)", unique)
+            ]
+        );
+    }
+
+    #[test]
+    fn skip_or_fail_begin() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+
+        // The requests not in a transaction are processed as usual.
+        let log = Log::default();
+        kernel
+            .send_request(
+                fuse_opcode::FUSE_GETATTR as u32,
+                1,
+                fuse_getattr_in::default().as_bytes(),
+            )
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        transact(&log, &req, |req| req.reply_error(libc::ENOSYS)).unwrap();
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::ENOSYS);
+        assert!(log.take().is_empty());
+
+        let log = Log {
+            fail_begin: true,
+            ..Log::default()
+        };
+        kernel
+            .send_request(fuse_opcode::FUSE_UNLINK as u32, 1, b"foo\0")
+            .unwrap();
+        let req = session.next_request().unwrap().unwrap();
+        let err = transact(&log, &req, |_| panic!("the handler is called")).unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EROFS));
+        assert_eq!(kernel.recv_reply().unwrap().error(), -libc::EROFS);
+        assert!(log.take().is_empty());
+    }
+}
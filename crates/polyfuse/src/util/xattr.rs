@@ -0,0 +1,250 @@
+use super::clock::{Clock, SystemClock};
+use crate::op::Getxattr;
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fmt, io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// The default time to live of the values remembered by `XattrProbeCache`.
+const DEFAULT_TTL: Duration = Duration::from_millis(300);
+
+/// The default number of values remembered by `XattrProbeCache`.
+const DEFAULT_CAPACITY: usize = 256;
+
+/// A short-lived cache of the extended attributes fetched for the size probes.
+///
+/// Applications read an extended attribute in two steps: `getxattr(2)`
+/// with the size `0` to probe the length of the value, followed by another
+/// call with a buffer of that length.  A filesystem has to fetch the value
+/// to answer the probe, so this cache keeps the fetched value briefly and
+/// hands it to the following data-phase request, saving the second fetch
+/// from the backend.
+///
+/// The values are keyed by the inode number, the name of the attribute
+/// and the user ID of the caller, since the visible attributes may depend
+/// on the caller.  A value is served at most once and expires after the
+/// time to live, and all values of an inode are discarded by `invalidate`,
+/// which should be called on `SETXATTR` and `REMOVEXATTR`.
+///
+/// The fetch runs without holding the internal lock, so the cache can be
+/// shared among the threads or tasks processing the requests concurrently.
+pub struct XattrProbeCache {
+    ttl: Duration,
+    capacity: usize,
+    clock: Box<dyn Clock>,
+    state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+    entries: HashMap<Key, (Instant, Vec<u8>)>,
+    // Incremented on every invalidation, so that a value fetched before it
+    // is not inserted afterwards.
+    epoch: u64,
+}
+
+type Key = (u64, OsString, u32);
+
+impl fmt::Debug for XattrProbeCache {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("XattrProbeCache")
+            .field("ttl", &self.ttl)
+            .field("capacity", &self.capacity)
+            .field("len", &self.len())
+            .finish()
+    }
+}
+
+impl Default for XattrProbeCache {
+    fn default() -> Self {
+        Self::new(DEFAULT_TTL, DEFAULT_CAPACITY)
+    }
+}
+
+impl XattrProbeCache {
+    /// Create a cache that keeps up to `capacity` values during `ttl`.
+    ///
+    /// The default, used by `XattrProbeCache::default`, is 256 values
+    /// during 300 milliseconds.
+    pub fn new(ttl: Duration, capacity: usize) -> Self {
+        Self {
+            ttl,
+            capacity,
+            clock: Box::new(SystemClock),
+            state: Mutex::new(State::default()),
+        }
+    }
+
+    /// Measure the time to live with `clock` instead of the system clock.
+    pub fn with_clock<C>(mut self, clock: C) -> Self
+    where
+        C: Clock + 'static,
+    {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Return the number of values currently remembered, including the expired ones.
+    pub fn len(&self) -> usize {
+        self.state.lock().unwrap().entries.len()
+    }
+
+    /// Return whether no value is remembered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Return the value of the attribute requested by `GETXATTR`, fetching
+    /// it with `fetch` unless remembered.
+    ///
+    /// For a size probe, the value is always fetched and remembered for the
+    /// following request.  For a data-phase request, the remembered value is
+    /// taken if it has not expired.  The caller replies the length or the
+    /// value itself, or `ERANGE` if it does not fit in `op.size()`.
+    pub fn fetch<F>(&self, op: &Getxattr<'_>, uid: u32, fetch: F) -> io::Result<Vec<u8>>
+    where
+        F: FnOnce() -> io::Result<Vec<u8>>,
+    {
+        if op.size() == 0 {
+            let epoch = self.state.lock().unwrap().epoch;
+            let value = fetch()?;
+            self.insert(epoch, (op.ino(), op.name().to_owned(), uid), &value);
+            Ok(value)
+        } else {
+            match self.take(op.ino(), op.name(), uid) {
+                Some(value) => Ok(value),
+                None => fetch(),
+            }
+        }
+    }
+
+    /// Discard the remembered values of the inode.
+    pub fn invalidate(&self, ino: u64) {
+        let mut state = self.state.lock().unwrap();
+        state.epoch = state.epoch.wrapping_add(1);
+        state.entries.retain(|&(key_ino, ..), _| key_ino != ino);
+    }
+
+    fn insert(&self, epoch: u64, key: Key, value: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.epoch != epoch {
+            return;
+        }
+
+        let now = self.clock.instant();
+        if state.entries.len() >= self.capacity {
+            let ttl = self.ttl;
+            state
+                .entries
+                .retain(|_, &mut (inserted, _)| now.saturating_duration_since(inserted) < ttl);
+        }
+        if state.entries.len() >= self.capacity {
+            let oldest = state
+                .entries
+                .iter()
+                .min_by_key(|(_, &(inserted, _))| inserted)
+                .map(|(key, _)| key.clone());
+            if let Some(oldest) = oldest {
+                state.entries.remove(&oldest);
+            }
+        }
+        state.entries.insert(key, (now, value.to_vec()));
+    }
+
+    fn take(&self, ino: u64, name: &OsStr, uid: u32) -> Option<Vec<u8>> {
+        let mut state = self.state.lock().unwrap();
+        let (inserted, value) = state.entries.remove(&(ino, name.to_owned(), uid))?;
+        if self.clock.instant().saturating_duration_since(inserted) < self.ttl {
+            Some(value)
+        } else {
+            None
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{testing, util::ManualClock, KernelConfig, Operation};
+    use polyfuse_kernel::*;
+    use std::cell::Cell;
+    use zerocopy::AsBytes as _;
+
+    #[test]
+    fn probe_then_fetch() {
+        let (session, kernel) = testing::session(KernelConfig::default()).unwrap();
+        let cache = XattrProbeCache::new(Duration::from_secs(3600), 2);
+        let fetches = Cell::new(0);
+        let fetch = || {
+            fetches.set(fetches.get() + 1);
+            Ok(b"value".to_vec())
+        };
+
+        let getxattr = |ino: u64, size: u32, uid: u32| {
+            let header = fuse_getxattr_in {
+                size,
+                ..Default::default()
+            };
+            let mut arg = header.as_bytes().to_vec();
+            arg.extend_from_slice(b"user.foo\0");
+            kernel.set_credentials(Some((uid, 0)));
+            kernel
+                .send_request(fuse_opcode::FUSE_GETXATTR as u32, ino, &arg)
+                .unwrap();
+            let req = session.next_request().unwrap().unwrap();
+            match req.operation().unwrap() {
+                Operation::Getxattr(op) => cache.fetch(&op, req.uid(), fetch).unwrap(),
+                _ => unreachable!(),
+            }
+        };
+
+        // The data phase following the probe is served from the cache.
+        assert_eq!(getxattr(2, 0, 1000), b"value");
+        assert_eq!(getxattr(2, 64, 1000), b"value");
+        assert_eq!(fetches.get(), 1);
+        assert!(cache.is_empty());
+
+        // The other callers and the invalidated inodes are fetched again.
+        getxattr(2, 0, 1000);
+        getxattr(2, 64, 0);
+        assert_eq!(fetches.get(), 3);
+        cache.invalidate(2);
+        getxattr(2, 64, 1000);
+        assert_eq!(fetches.get(), 4);
+
+        // The oldest value is evicted at the capacity.
+        getxattr(3, 0, 0);
+        getxattr(4, 0, 0);
+        getxattr(5, 0, 0);
+        assert_eq!(cache.len(), 2);
+        getxattr(3, 64, 0);
+        assert_eq!(fetches.get(), 8);
+    }
+
+    #[test]
+    fn expired_values_are_not_served() {
+        let clock = ManualClock::default();
+        let cache = XattrProbeCache::new(Duration::from_millis(300), 4).with_clock(clock.clone());
+        cache.insert(0, (2, "user.foo".into(), 0), b"value");
+        clock.advance(Duration::from_millis(299));
+        assert_eq!(
+            cache.take(2, OsStr::new("user.foo"), 0).as_deref(),
+            Some(&b"value"[..])
+        );
+        cache.insert(0, (2, "user.foo".into(), 0), b"value");
+        clock.advance(Duration::from_millis(300));
+        assert_eq!(cache.take(2, OsStr::new("user.foo"), 0), None);
+
+        // A value fetched across an invalidation is not remembered.
+        let epoch = cache.state.lock().unwrap().epoch;
+        cache.invalidate(3);
+        cache.insert(epoch, (2, "user.foo".into(), 0), b"value");
+        assert!(cache.is_empty());
+    }
+}
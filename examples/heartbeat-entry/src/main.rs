@@ -8,7 +8,7 @@
 #![deny(clippy::unimplemented, clippy::todo)]
 
 use polyfuse::{
-    reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
+    reply::{AttrOut, EntryOut, ReaddirOut},
     KernelConfig, Notifier, Operation, Request, Session,
 };
 
@@ -132,8 +132,14 @@ impl Heartbeat {
 
             match notifier {
                 Some(ref notifier) if current.nlookup > 0 => {
-                    tracing::info!("send notify_inval_entry");
-                    notifier.inval_entry(ROOT_INO, old_filename)?;
+                    tracing::info!("notify the rename of the file");
+                    notifier.invalidate_rename(
+                        ROOT_INO,
+                        old_filename,
+                        ROOT_INO,
+                        &current.filename,
+                        FILE_INO,
+                    )?;
                 }
                 _ => (),
             }
@@ -159,7 +165,7 @@ impl Heartbeat {
                     if op.name().as_bytes() == current.filename.as_bytes() {
                         let mut out = EntryOut::default();
                         out.ino(self.file_attr.st_ino);
-                        fill_attr(out.attr(), &self.file_attr);
+                        out.attr().stat(&self.file_attr);
                         out.ttl_entry(self.ttl);
                         out.ttl_attr(self.ttl);
 
@@ -190,7 +196,7 @@ impl Heartbeat {
                 };
 
                 let mut out = AttrOut::default();
-                fill_attr(out.attr(), attr);
+                out.attr().stat(attr);
                 out.ttl(self.ttl);
 
                 req.reply(out)?;
@@ -198,7 +204,7 @@ impl Heartbeat {
 
             Operation::Read(op) => match op.ino() {
                 ROOT_INO => req.reply_error(libc::EISDIR)?,
-                FILE_INO => req.reply(&[])?,
+                FILE_INO => req.reply(())?,
                 _ => req.reply_error(libc::ENOENT)?,
             },
 
@@ -207,11 +213,11 @@ impl Heartbeat {
                     if op.offset() == 0 {
                         let current = self.current.lock().unwrap();
 
-                        let mut out = ReaddirOut::new(op.size() as usize);
-                        out.entry(current.filename.as_ref(), FILE_INO, 0, 1);
+                        let mut out = ReaddirOut::with_offset(op.size() as usize, 0);
+                        out.next_entry(current.filename.as_ref(), FILE_INO, 0);
                         req.reply(out)?;
                     } else {
-                        req.reply(&[])?;
+                        req.reply(())?;
                     }
                 }
                 _ => req.reply_error(libc::ENOTDIR)?,
@@ -223,18 +229,3 @@ impl Heartbeat {
         Ok(())
     }
 }
-
-fn fill_attr(attr: &mut FileAttr, st: &libc::stat) {
-    attr.ino(st.st_ino);
-    attr.size(st.st_size as u64);
-    attr.mode(st.st_mode);
-    attr.nlink(st.st_nlink as u32);
-    attr.uid(st.st_uid);
-    attr.gid(st.st_gid);
-    attr.rdev(st.st_rdev as u32);
-    attr.blksize(st.st_blksize as u32);
-    attr.blocks(st.st_blocks as u64);
-    attr.atime(Duration::new(st.st_atime as u64, st.st_atime_nsec as u32));
-    attr.mtime(Duration::new(st.st_mtime as u64, st.st_mtime_nsec as u32));
-    attr.ctime(Duration::new(st.st_ctime as u64, st.st_ctime_nsec as u32));
-}
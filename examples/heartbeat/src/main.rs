@@ -8,7 +8,7 @@
 #![deny(clippy::unimplemented)]
 
 use polyfuse::{
-    reply::{AttrOut, FileAttr, OpenOut},
+    reply::{AttrOut, OpenOut},
     KernelConfig, Notifier, Operation, Session,
 };
 
@@ -89,7 +89,7 @@ fn main() -> Result<()> {
                     ROOT_INO => {
                         let inner = heartbeat.inner.lock().unwrap();
                         let mut out = AttrOut::default();
-                        fill_attr(out.attr(), &inner.attr);
+                        out.attr().stat(&inner.attr);
                         req.reply(out)?;
                     }
                     _ => req.reply_error(libc::ENOENT)?,
@@ -108,7 +108,7 @@ fn main() -> Result<()> {
 
                         let offset = op.offset() as usize;
                         if offset >= inner.content.len() {
-                            req.reply(&[])?;
+                            req.reply(())?;
                         } else {
                             let size = op.size() as usize;
                             let data = &inner.content.as_bytes()[offset..];
@@ -207,18 +207,3 @@ impl Heartbeat {
         Ok(())
     }
 }
-
-fn fill_attr(attr: &mut FileAttr, st: &libc::stat) {
-    attr.ino(st.st_ino);
-    attr.size(st.st_size as u64);
-    attr.mode(st.st_mode);
-    attr.nlink(st.st_nlink as u32);
-    attr.uid(st.st_uid);
-    attr.gid(st.st_gid);
-    attr.rdev(st.st_rdev as u32);
-    attr.blksize(st.st_blksize as u32);
-    attr.blocks(st.st_blocks as u64);
-    attr.atime(Duration::new(st.st_atime as u64, st.st_atime_nsec as u32));
-    attr.mtime(Duration::new(st.st_mtime as u64, st.st_mtime_nsec as u32));
-    attr.ctime(Duration::new(st.st_ctime as u64, st.st_ctime_nsec as u32));
-}
@@ -4,13 +4,14 @@
 use polyfuse::{
     op,
     reply::{AttrOut, EntryOut, FileAttr, ReaddirOut},
+    util::CachePolicy,
     KernelConfig, Operation, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
-use std::{io, os::unix::prelude::*, path::PathBuf, time::Duration};
+use std::{io, os::unix::prelude::*, path::PathBuf};
 
-const TTL: Duration = Duration::from_secs(60 * 60 * 24 * 365);
+const CACHE: CachePolicy = CachePolicy::immutable();
 const ROOT_INO: u64 = 1;
 const HELLO_INO: u64 = 2;
 const HELLO_FILENAME: &str = "hello.txt";
@@ -29,13 +30,7 @@ fn main() -> Result<()> {
     let fs = Hello::new();
 
     while let Some(req) = session.next_request()? {
-        match req.operation()? {
-            Operation::Lookup(op) => fs.lookup(&req, op)?,
-            Operation::Getattr(op) => fs.getattr(&req, op)?,
-            Operation::Read(op) => fs.read(&req, op)?,
-            Operation::Readdir(op) => fs.readdir(&req, op)?,
-            _ => req.reply_error(libc::ENOSYS)?,
-        }
+        fs.handle_request(&req)?;
     }
 
     Ok(())
@@ -79,9 +74,20 @@ impl Hello {
         }
     }
 
+    fn handle_request(&self, req: &Request) -> Result<()> {
+        match req.operation()? {
+            Operation::Lookup(op) => self.lookup(req, op)?,
+            Operation::Getattr(op) => self.getattr(req, op)?,
+            Operation::Read(op) => self.read(req, op)?,
+            Operation::Readdir(op) => self.readdir(req, op)?,
+            _ => req.reply_error(libc::ENOSYS)?,
+        }
+        Ok(())
+    }
+
     fn fill_root_attr(&self, attr: &mut FileAttr) {
         attr.ino(ROOT_INO);
-        attr.mode(libc::S_IFDIR as u32 | 0o555);
+        attr.mode(libc::S_IFDIR | 0o555);
         attr.nlink(2);
         attr.uid(self.uid);
         attr.gid(self.gid);
@@ -90,7 +96,7 @@ impl Hello {
     fn fill_hello_attr(&self, attr: &mut FileAttr) {
         attr.ino(HELLO_INO);
         attr.size(HELLO_CONTENT.len() as u64);
-        attr.mode(libc::S_IFREG as u32 | 0o444);
+        attr.mode(libc::S_IFREG | 0o444);
         attr.nlink(1);
         attr.uid(self.uid);
         attr.gid(self.gid);
@@ -102,10 +108,13 @@ impl Hello {
                 let mut out = EntryOut::default();
                 self.fill_hello_attr(out.attr());
                 out.ino(HELLO_INO);
-                out.ttl_attr(TTL);
-                out.ttl_entry(TTL);
+                CACHE.apply(&mut out);
                 req.reply(out)
             }
+            ROOT_INO => match CACHE.negative_entry() {
+                Some(out) => req.reply(out),
+                None => req.reply_error(libc::ENOENT),
+            },
             _ => req.reply_error(libc::ENOENT),
         }
     }
@@ -119,7 +128,7 @@ impl Hello {
 
         let mut out = AttrOut::default();
         fill_attr(self, out.attr());
-        out.ttl(TTL);
+        CACHE.apply_attr(&mut out);
 
         req.reply(out)
     }
@@ -143,28 +152,15 @@ impl Hello {
         req.reply(data)
     }
 
-    fn dir_entries(&self) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
-        self.entries.iter().enumerate().map(|(i, ent)| {
-            let offset = (i + 1) as u64;
-            (offset, ent)
-        })
-    }
-
     fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
         if op.ino() != ROOT_INO {
             return req.reply_error(libc::ENOTDIR);
         }
 
-        let mut out = ReaddirOut::new(op.size() as usize);
+        let mut out = ReaddirOut::with_offset(op.size() as usize, op.offset());
 
-        for (i, entry) in self.dir_entries().skip(op.offset() as usize) {
-            let full = out.entry(
-                entry.name.as_ref(), //
-                entry.ino,
-                entry.typ,
-                i + 1,
-            );
-            if full {
+        for entry in self.entries.iter().skip(op.offset() as usize) {
+            if out.next_entry(entry.name.as_ref(), entry.ino, entry.typ) {
                 break;
             }
         }
@@ -172,3 +168,66 @@ impl Hello {
         req.reply(out)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polyfuse::testing::sim::Simulator;
+
+    fn simulator() -> Simulator<impl FnMut(&Request) -> io::Result<()>> {
+        let fs = Hello::new();
+        let mut config = KernelConfig::default();
+        config.strict(true);
+        Simulator::new(config, move |req| {
+            fs.handle_request(req).map_err(|err| {
+                err.downcast()
+                    .unwrap_or_else(|_| io::Error::from_raw_os_error(libc::EIO))
+            })
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn cat_hello() {
+        let mut sim = simulator();
+
+        let attr = sim.stat("/hello.txt").unwrap();
+        assert_eq!(attr.ino(), HELLO_INO);
+        assert_eq!(attr.size(), HELLO_CONTENT.len() as u64);
+
+        // The immutable entries and attributes are never revalidated.
+        let requests = sim.requests();
+        sim.advance(std::time::Duration::from_secs(60 * 60));
+        sim.stat("/hello.txt").unwrap();
+        assert_eq!(sim.requests(), requests);
+
+        // OPEN is not implemented, so the files are opened without it and
+        // the page cache is kept across the opens.
+        let file = sim.open("/hello.txt", libc::O_RDONLY).unwrap();
+        assert_eq!(sim.read(&file, 0, 4096).unwrap(), HELLO_CONTENT);
+        sim.release(file).unwrap();
+        let file = sim.open("/hello.txt", libc::O_RDONLY).unwrap();
+        assert_eq!(sim.read(&file, 7, 5).unwrap(), b"world");
+        sim.release(file).unwrap();
+        assert_eq!(sim.requests(), requests + 3);
+    }
+
+    #[test]
+    fn negative_lookups_are_cached() {
+        let mut sim = simulator();
+        for _ in 0..3 {
+            let err = sim.stat("/nonexistent").unwrap_err();
+            assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        }
+        assert_eq!(sim.requests(), 1);
+    }
+
+    #[test]
+    fn list_root() {
+        let mut sim = simulator();
+        let entries = sim.readdir("/").unwrap();
+        let names: Vec<_> = entries.iter().map(|entry| entry.name.as_os_str()).collect();
+        assert_eq!(names, [".", "..", HELLO_FILENAME]);
+        assert_eq!(entries[2].ino, HELLO_INO);
+    }
+}
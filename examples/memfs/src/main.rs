@@ -3,8 +3,13 @@
 
 use polyfuse::{
     op,
-    reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, WriteOut, XattrOut},
-    KernelConfig, Operation, Request, Session,
+    reply::{AttrOut, EntryOut, IoctlOut, OpenOut, ReaddirOut, WriteOut},
+    util::{
+        validate_lookup_name, validate_name, CachePolicy, Clock, DirSnapshot, InodeFlags,
+        SizeEpoch, SystemClock, FS_IOC32_GETFLAGS, FS_IOC32_SETFLAGS, FS_IOC_GETFLAGS,
+        FS_IOC_SETFLAGS,
+    },
+    Errno, InodeTracking, KernelConfig, Operation, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
@@ -15,9 +20,10 @@ use std::{
     ffi::{OsStr, OsString},
     io::{self, BufRead},
     mem,
+    os::unix::prelude::*,
     path::PathBuf,
     sync::{
-        atomic::{AtomicU64, AtomicUsize, Ordering},
+        atomic::{AtomicU64, Ordering},
         Arc,
     },
     time::{Duration, SystemTime},
@@ -31,9 +37,18 @@ fn main() -> Result<()> {
     let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
     ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
 
-    let session = Session::mount(mountpoint, KernelConfig::default())?;
+    let writeback = args.contains("--writeback");
+
+    let mut fs = MemFS::new(SystemClock);
 
-    let mut fs = MemFS::new();
+    let mut config = KernelConfig::default();
+    fs.cache.apply_config(&mut config);
+    config.writeback_cache(writeback);
+    config.reject_stale_inodes(InodeTracking::Forgotten);
+    let session = Session::mount(mountpoint, config)?;
+    if session.writeback_cache() {
+        fs.sizes = Some(SizeEpoch::new());
+    }
 
     while let Some(req) = session.next_request()? {
         let span = tracing::debug_span!("handle_request", unique = req.unique());
@@ -96,7 +111,7 @@ impl std::ops::Deref for INodeRef<'_> {
 
     #[inline]
     fn deref(&self) -> &Self::Target {
-        &*self.ref_
+        &self.ref_
     }
 }
 
@@ -108,13 +123,13 @@ impl std::ops::Deref for INodeRefMut<'_> {
 
     #[inline]
     fn deref(&self) -> &Self::Target {
-        &*self.ref_mut
+        &self.ref_mut
     }
 }
 impl std::ops::DerefMut for INodeRefMut<'_> {
     #[inline]
     fn deref_mut(&mut self) -> &mut Self::Target {
-        &mut *self.ref_mut
+        &mut self.ref_mut
     }
 }
 
@@ -153,6 +168,8 @@ impl<'a> VacantEntry<'a> {
 struct INode {
     attr: libc::stat,
     xattrs: HashMap<OsString, Arc<Vec<u8>>>,
+    // The flags set by chattr(1), which the kernel does not enforce on FUSE.
+    flags: InodeFlags,
     refcount: u64,
     links: u64,
     kind: INodeKind,
@@ -212,18 +229,23 @@ impl Directory {
 }
 
 struct DirHandle {
-    entries: Vec<Arc<DirEntry>>,
-    offset: AtomicUsize,
+    ino: Ino,
+    snapshot: DirSnapshot<Arc<DirEntry>>,
 }
 
 struct MemFS {
     inodes: INodeTable,
     dir_handles: Slab<DirHandle>,
-    ttl: Duration,
+    cache: CachePolicy,
+    // Tracks the file sizes extended by writes when the writeback cache is enabled.
+    sizes: Option<SizeEpoch>,
+    // The source of the timestamps, replaced in the tests.
+    clock: Box<dyn Clock>,
 }
 
 impl MemFS {
-    fn new() -> Self {
+    fn new(clock: impl Clock + 'static) -> Self {
+        let now = since_epoch(clock.now());
         let inodes = INodeTable::new();
         inodes.vacant_entry().unwrap().insert(INode {
             attr: {
@@ -231,11 +253,15 @@ impl MemFS {
                 attr.st_ino = 1;
                 attr.st_nlink = 2;
                 attr.st_mode = libc::S_IFDIR | 0o755;
+                set_atime(&mut attr, now);
+                set_mtime(&mut attr, now);
+                set_ctime(&mut attr, now);
                 attr
             },
             xattrs: HashMap::new(),
-            refcount: u64::max_value() / 2,
-            links: u64::max_value() / 2,
+            flags: InodeFlags::empty(),
+            refcount: u64::MAX / 2,
+            links: u64::MAX / 2,
             kind: INodeKind::Directory(Directory {
                 children: HashMap::new(),
                 parent: None,
@@ -245,42 +271,57 @@ impl MemFS {
         Self {
             inodes,
             dir_handles: Slab::new(),
-            ttl: Duration::from_secs(60 * 60 * 24),
+            // All of changes in this filesystem are caused by FUSE requests.
+            cache: CachePolicy {
+                entry_ttl: Duration::from_secs(60 * 60 * 24),
+                attr_ttl: Duration::from_secs(60 * 60 * 24),
+                negative_ttl: None,
+                use_auto_inval: true,
+            },
+            sizes: None,
+            clock: Box::new(clock),
         }
     }
 
+    fn now(&self) -> Duration {
+        since_epoch(self.clock.now())
+    }
+
     fn handle_request(&mut self, req: &Request) -> Result<()> {
         let op = req.operation()?;
         tracing::debug!(?op);
 
         match op {
-            Operation::Lookup(op) => self.do_lookup(&req, op)?,
+            Operation::Lookup(op) => self.do_lookup(req, op)?,
             Operation::Forget(forgets) => {
                 self.do_forget(forgets.as_ref());
             }
-            Operation::Getattr(op) => self.do_getattr(&req, op)?,
-            Operation::Setattr(op) => self.do_setattr(&req, op)?,
-            Operation::Readlink(op) => self.do_readlink(&req, op)?,
-
-            Operation::Opendir(op) => self.do_opendir(&req, op)?,
-            Operation::Readdir(op) => self.do_readdir(&req, op)?,
-            Operation::Releasedir(op) => self.do_releasedir(&req, op)?,
-
-            Operation::Mknod(op) => self.do_mknod(&req, op)?,
-            Operation::Mkdir(op) => self.do_mkdir(&req, op)?,
-            Operation::Symlink(op) => self.do_symlink(&req, op)?,
-            Operation::Link(op) => self.do_link(&req, op)?,
-            Operation::Unlink(op) => self.do_unlink(&req, op)?,
-            Operation::Rmdir(op) => self.do_rmdir(&req, op)?,
-            Operation::Rename(op) => self.do_rename(&req, op)?,
-
-            Operation::Getxattr(op) => self.do_getxattr(&req, op)?,
-            Operation::Setxattr(op) => self.do_setxattr(&req, op)?,
-            Operation::Listxattr(op) => self.do_listxattr(&req, op)?,
-            Operation::Removexattr(op) => self.do_removexattr(&req, op)?,
-
-            Operation::Read(op) => self.do_read(&req, op)?,
-            Operation::Write(op, data) => self.do_write(&req, op, data)?,
+            Operation::Getattr(op) => self.do_getattr(req, op)?,
+            Operation::Setattr(op) => self.do_setattr(req, op)?,
+            Operation::Readlink(op) => self.do_readlink(req, op)?,
+
+            Operation::Open(op) => self.do_open(req, op)?,
+
+            Operation::Opendir(op) => self.do_opendir(req, op)?,
+            Operation::Readdir(op) => self.do_readdir(req, op)?,
+            Operation::Releasedir(op) => self.do_releasedir(req, op)?,
+
+            Operation::Mknod(op) => self.do_mknod(req, op)?,
+            Operation::Mkdir(op) => self.do_mkdir(req, op)?,
+            Operation::Symlink(op) => self.do_symlink(req, op)?,
+            Operation::Link(op) => self.do_link(req, op)?,
+            Operation::Unlink(op) => self.do_unlink(req, op)?,
+            Operation::Rmdir(op) => self.do_rmdir(req, op)?,
+            Operation::Rename(op) => self.do_rename(req, op)?,
+
+            Operation::Getxattr(op) => self.do_getxattr(req, op)?,
+            Operation::Setxattr(op) => self.do_setxattr(req, op)?,
+            Operation::Listxattr(op) => self.do_listxattr(req, op)?,
+            Operation::Removexattr(op) => self.do_removexattr(req, op)?,
+
+            Operation::Read(op) => self.do_read(req, op)?,
+            Operation::Ioctl(op) => self.do_ioctl(req, op)?,
+            Operation::Write(op, data) => self.do_write(req, op, data)?,
 
             _ => {
                 tracing::debug!("NOSYS");
@@ -292,19 +333,31 @@ impl MemFS {
     }
 
     fn do_lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
-        let parent = match self.inodes.get(op.parent()) {
-            Some(inode) => inode,
-            None => return req.reply_error(libc::ENOENT),
-        };
+        if let Err(errno) = validate_lookup_name(op.name()) {
+            return req.reply_error(errno);
+        }
 
-        let parent = match parent.kind {
-            INodeKind::Directory(ref dir) => dir,
-            _ => return req.reply_error(libc::ENOTDIR),
+        let child_ino = {
+            let parent = match self.inodes.get(op.parent()) {
+                Some(inode) => inode,
+                None => return req.reply_error(libc::ENOENT),
+            };
+            let parent = match parent.kind {
+                INodeKind::Directory(ref dir) => dir,
+                _ => return req.reply_error(libc::ENOTDIR),
+            };
+            parent.children.get(op.name()).copied()
         };
-
-        let child_ino = match parent.children.get(op.name()) {
-            Some(&ino) => ino,
-            None => return req.reply_error(libc::ENOENT),
+        // The parent is released before locking the child, which may be
+        // stored in the same shard of the table.
+        let child_ino = match child_ino {
+            Some(ino) => ino,
+            None => {
+                return match self.cache.negative_entry() {
+                    Some(out) => req.reply(out),
+                    None => req.reply_error(libc::ENOENT),
+                };
+            }
         };
         let mut child = self
             .inodes
@@ -314,8 +367,8 @@ impl MemFS {
 
         let mut out = EntryOut::default();
         out.ino(child_ino);
-        fill_attr(out.attr(), &child.attr);
-        out.ttl_entry(self.ttl);
+        out.attr().stat(&child.attr);
+        self.cache.apply(&mut out);
 
         req.reply(out)
     }
@@ -327,6 +380,9 @@ impl MemFS {
                     inode.get_mut().refcount.saturating_sub(forget.nlookup());
 
                 if inode.get().refcount == 0 && inode.get().links == 0 {
+                    if let Some(ref sizes) = self.sizes {
+                        sizes.forget(forget.ino());
+                    }
                     inode.remove();
                 }
             }
@@ -340,27 +396,36 @@ impl MemFS {
         };
 
         let mut out = AttrOut::default();
-        fill_attr(out.attr(), &inode.attr);
-        out.ttl(self.ttl);
+        self.fill_attr(op.ino(), &inode.attr, &mut out);
 
         req.reply(out)
     }
 
+    fn fill_attr(&self, ino: u64, attr: &libc::stat, out: &mut AttrOut) {
+        out.attr().stat(attr);
+        if let Some(ref sizes) = self.sizes {
+            out.attr().size(sizes.size(ino, attr.st_size as u64));
+        }
+        self.cache.apply_attr(out);
+    }
+
     fn do_setattr(&self, req: &Request, op: op::Setattr<'_>) -> io::Result<()> {
         let mut inode = match self.inodes.get_mut(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
-
-        fn to_duration(t: op::SetAttrTime) -> Duration {
-            match t {
-                op::SetAttrTime::Timespec(ts) => ts,
-                _ => SystemTime::now()
-                    .duration_since(SystemTime::UNIX_EPOCH)
-                    .unwrap(),
-            }
+        if inode.flags.contains(InodeFlags::IMMUTABLE)
+            || (inode.flags.contains(InodeFlags::APPEND) && op.size().is_some())
+        {
+            return req.reply_error(libc::EPERM);
         }
 
+        let now = self.now();
+        let to_duration = |t: op::SetAttrTime| match t {
+            op::SetAttrTime::Timespec(ts) => ts,
+            _ => now,
+        };
+
         if let Some(mode) = op.mode() {
             inode.attr.st_mode = mode;
         }
@@ -371,26 +436,28 @@ impl MemFS {
             inode.attr.st_gid = gid;
         }
         if let Some(size) = op.size() {
+            // Without FUSE_ATOMIC_O_TRUNC, open(2) with O_TRUNC also ends up here.
+            if let INodeKind::RegularFile(ref mut content) = inode.kind {
+                content.resize(size as usize, 0);
+            }
             inode.attr.st_size = size as libc::off_t;
+            if let Some(ref sizes) = self.sizes {
+                sizes.truncate(op.ino());
+            }
         }
         if let Some(atime) = op.atime() {
-            let atime = to_duration(atime);
-            inode.attr.st_atime = atime.as_secs() as i64;
-            inode.attr.st_atime_nsec = atime.subsec_nanos() as u64 as i64;
+            set_atime(&mut inode.attr, to_duration(atime));
         }
         if let Some(mtime) = op.mtime() {
-            let mtime = to_duration(mtime);
-            inode.attr.st_mtime = mtime.as_secs() as i64;
-            inode.attr.st_mtime_nsec = mtime.subsec_nanos() as u64 as i64;
-        }
-        if let Some(ctime) = op.ctime() {
-            inode.attr.st_ctime = ctime.as_secs() as i64;
-            inode.attr.st_ctime_nsec = ctime.subsec_nanos() as u64 as i64;
+            set_mtime(&mut inode.attr, to_duration(mtime));
+        } else if op.size().is_some() {
+            set_mtime(&mut inode.attr, now);
         }
+        // Any change of the attributes updates ctime, unless it is given explicitly.
+        set_ctime(&mut inode.attr, op.ctime().unwrap_or(now));
 
         let mut out = AttrOut::default();
-        fill_attr(out.attr(), &inode.attr);
-        out.ttl(self.ttl);
+        self.fill_attr(op.ino(), &inode.attr, &mut out);
 
         req.reply(out)
     }
@@ -409,6 +476,39 @@ impl MemFS {
         req.reply(link)
     }
 
+    fn do_open(&self, req: &Request, op: op::Open<'_>) -> io::Result<()> {
+        let mut inode = match self.inodes.get_mut(op.ino()) {
+            Some(inode) => inode,
+            None => return req.reply_error(libc::ENOENT),
+        };
+
+        let writable = op.flags() as i32 & libc::O_ACCMODE != libc::O_RDONLY;
+        if writable
+            && (inode.flags.contains(InodeFlags::IMMUTABLE)
+                || (inode.flags.contains(InodeFlags::APPEND)
+                    && op.flags() as i32 & libc::O_APPEND == 0))
+        {
+            return req.reply_error(libc::EPERM);
+        }
+
+        // The kernel passes O_TRUNC only when FUSE_ATOMIC_O_TRUNC is granted.
+        let truncate = writable && op.flags() as i32 & libc::O_TRUNC != 0;
+        let content = match inode.kind {
+            INodeKind::RegularFile(ref mut content) => content,
+            _ => return req.reply_error(libc::EISDIR),
+        };
+
+        if truncate {
+            content.clear();
+            inode.attr.st_size = 0;
+            if let Some(ref sizes) = self.sizes {
+                sizes.truncate(op.ino());
+            }
+        }
+
+        req.reply(OpenOut::default())
+    }
+
     fn do_opendir(&mut self, req: &Request, op: op::Opendir<'_>) -> io::Result<()> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
@@ -423,8 +523,8 @@ impl MemFS {
         };
 
         let key = self.dir_handles.insert(DirHandle {
-            entries: dir.collect_entries(&inode.attr),
-            offset: AtomicUsize::new(0),
+            ino: op.ino(),
+            snapshot: DirSnapshot::new(dir.collect_entries(&inode.attr)),
         });
 
         let mut out = OpenOut::default();
@@ -443,16 +543,30 @@ impl MemFS {
             None => return req.reply_error(libc::EINVAL),
         };
 
-        let mut out = ReaddirOut::new(op.size() as usize);
+        // Take a new snapshot when the directory is rewound.
+        let refresh = || {
+            let inode = self
+                .inodes
+                .get(dir.ino)
+                .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
+            match inode.kind {
+                INodeKind::Directory(ref d) => Ok(d.collect_entries(&inode.attr)),
+                _ => Err(io::Error::from_raw_os_error(libc::ENOTDIR)),
+            }
+        };
 
-        for entry in dir.entries.iter().skip(op.offset() as usize) {
-            if out.entry(&entry.name, entry.ino, entry.typ, entry.off) {
-                break;
+        let mut out = ReaddirOut::new(op.size() as usize);
+        let res = dir.snapshot.read(op.offset(), refresh, |entries| {
+            for entry in entries {
+                if out.entry(&entry.name, entry.ino, entry.typ, entry.off) {
+                    break;
+                }
             }
-            dir.offset.fetch_add(1, Ordering::SeqCst);
+        });
+        match res {
+            Ok(()) => req.reply(out),
+            Err(err) => req.reply_error(Errno::from_io_error(&err).raw()),
         }
-
-        req.reply(out)
     }
 
     fn do_releasedir(&mut self, req: &Request, op: op::Releasedir<'_>) -> io::Result<()> {
@@ -461,9 +575,12 @@ impl MemFS {
     }
 
     fn do_mknod(&self, req: &Request, op: op::Mknod<'_>) -> io::Result<()> {
-        match op.mode() & libc::S_IFMT {
-            libc::S_IFREG => (),
-            _ => return req.reply_error(libc::ENOTSUP),
+        match op.node_type() {
+            Some(op::NodeType::Regular) => (),
+            // The device nodes are unusable on the mounts by unprivileged users.
+            Some(node) if node.is_device() => return req.reply_error(libc::EPERM),
+            Some(..) => return req.reply_error(libc::ENOTSUP),
+            None => return req.reply_error(libc::EINVAL),
         }
 
         self.make_node(req, op.parent(), op.name(), |entry| INode {
@@ -475,6 +592,7 @@ impl MemFS {
                 attr
             },
             xattrs: HashMap::new(),
+            flags: InodeFlags::empty(),
             refcount: 1,
             links: 1,
             kind: INodeKind::RegularFile(vec![]),
@@ -491,6 +609,7 @@ impl MemFS {
                 attr
             },
             xattrs: HashMap::new(),
+            flags: InodeFlags::empty(),
             refcount: 1,
             links: 1,
             kind: INodeKind::Directory(Directory {
@@ -510,6 +629,7 @@ impl MemFS {
                 attr
             },
             xattrs: HashMap::new(),
+            flags: InodeFlags::empty(),
             refcount: 1,
             links: 1,
             kind: INodeKind::Symlink(Arc::new(op.link().into())),
@@ -520,35 +640,53 @@ impl MemFS {
     where
         F: FnOnce(&VacantEntry<'_>) -> INode,
     {
-        let mut parent = match self.inodes.get_mut(parent) {
-            Some(inode) => inode,
-            None => return req.reply_error(libc::ENOENT),
-        };
-        let parent = match parent.kind {
-            INodeKind::Directory(ref mut dir) => dir,
-            _ => return req.reply_error(libc::ENOTDIR),
-        };
+        if let Err(errno) = validate_name(name) {
+            return req.reply_error(errno);
+        }
 
-        let map_entry = match parent.children.entry(name.into()) {
-            Entry::Occupied(..) => return req.reply_error(libc::EEXIST),
-            Entry::Vacant(map_entry) => map_entry,
-        };
-        let inode_entry = self.inodes.vacant_entry().expect("inode number conflict");
-        let inode = f(&inode_entry);
+        match self.inodes.get(parent) {
+            Some(inode) => match inode.kind {
+                INodeKind::Directory(ref dir) if dir.children.contains_key(name) => {
+                    return req.reply_error(libc::EEXIST)
+                }
+                INodeKind::Directory(..) => (),
+                _ => return req.reply_error(libc::ENOTDIR),
+            },
+            None => return req.reply_error(libc::ENOENT),
+        }
 
+        // The new inode is inserted without holding the parent, since both
+        // may be stored in the same shard of the table.  The requests are
+        // processed sequentially, so the name is still vacant afterwards.
         let mut out = EntryOut::default();
-        out.ino(inode_entry.ino());
-        fill_attr(out.attr(), &inode.attr);
-        out.ttl_entry(self.ttl);
-        req.reply(out)?;
-
-        map_entry.insert(inode_entry.ino());
-        inode_entry.insert(inode);
+        let ino = {
+            let inode_entry = self.inodes.vacant_entry().expect("inode number conflict");
+            let ino = inode_entry.ino();
+            let mut inode = f(&inode_entry);
+            let now = self.now();
+            set_atime(&mut inode.attr, now);
+            set_mtime(&mut inode.attr, now);
+            set_ctime(&mut inode.attr, now);
+            out.ino(ino);
+            out.attr().stat(&inode.attr);
+            inode_entry.insert(inode);
+            ino
+        };
+        if let Some(mut parent) = self.inodes.get_mut(parent) {
+            if let INodeKind::Directory(ref mut dir) = parent.kind {
+                dir.children.insert(name.into(), ino);
+            }
+        }
 
-        Ok(())
+        self.cache.apply(&mut out);
+        req.reply(out)
     }
 
     fn do_link(&self, req: &Request, op: op::Link<'_>) -> io::Result<()> {
+        if let Err(errno) = validate_name(op.newname()) {
+            return req.reply_error(errno);
+        }
+
         let mut inode = match self.inodes.get_mut(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
@@ -576,8 +714,8 @@ impl MemFS {
 
         let mut out = EntryOut::default();
         out.ino(op.ino());
-        fill_attr(out.attr(), &inode.attr);
-        out.ttl_entry(self.ttl);
+        out.attr().stat(&inode.attr);
+        self.cache.apply(&mut out);
 
         req.reply(out)
     }
@@ -625,9 +763,14 @@ impl MemFS {
     }
 
     fn do_rename(&self, req: &Request, op: op::Rename<'_>) -> io::Result<()> {
-        if op.flags() != 0 {
-            // TODO: handle RENAME_NOREPLACE and RENAME_EXCHANGE.
-            return req.reply_error(libc::EINVAL);
+        match op.flags() {
+            // The existing entry is never replaced, even without RENAME_NOREPLACE.
+            0 | libc::RENAME_NOREPLACE => (),
+            libc::RENAME_EXCHANGE => return self.exchange_entries(req, &op),
+            _ => return req.reply_error(libc::EINVAL),
+        }
+        if let Err(errno) = validate_lookup_name(op.name()).and(validate_name(op.newname())) {
+            return req.reply_error(errno);
         }
 
         let mut parent = match self.inodes.get_mut(op.parent()) {
@@ -683,6 +826,64 @@ impl MemFS {
         req.reply(())
     }
 
+    /// Swap the inodes of two entries, for `RENAME_EXCHANGE`.
+    ///
+    /// Both of the inodes stay linked, so their lookup counts and link
+    /// counts are unchanged.  The VFS has already rejected the exchanges
+    /// that would make a directory its own descendant.
+    fn exchange_entries(&self, req: &Request, op: &op::Rename<'_>) -> io::Result<()> {
+        if let Err(errno) = validate_lookup_name(op.name()).and(validate_lookup_name(op.newname()))
+        {
+            return req.reply_error(errno);
+        }
+        let ino = match self.child_of(op.parent(), op.name()) {
+            Ok(ino) => ino,
+            Err(errno) => return req.reply_error(errno),
+        };
+        let newino = match self.child_of(op.newparent(), op.newname()) {
+            Ok(newino) => newino,
+            Err(errno) => return req.reply_error(errno),
+        };
+        if ino == newino {
+            // The same entry, or two hard links of an inode.
+            return req.reply(());
+        }
+
+        // The parents are updated one by one, since both may be stored in
+        // the same shard of the table.
+        let now = self.now();
+        self.replace_child(op.parent(), op.name(), newino, now);
+        self.replace_child(op.newparent(), op.newname(), ino, now);
+        for &(ino, parent) in &[(ino, op.newparent()), (newino, op.parent())] {
+            if let Some(mut inode) = self.inodes.get_mut(ino) {
+                set_ctime(&mut inode.attr, now);
+                if let INodeKind::Directory(ref mut dir) = inode.kind {
+                    dir.parent = Some(parent);
+                }
+            }
+        }
+
+        req.reply(())
+    }
+
+    fn child_of(&self, parent: Ino, name: &OsStr) -> Result<Ino, i32> {
+        let parent = self.inodes.get(parent).ok_or(libc::ENOENT)?;
+        match parent.kind {
+            INodeKind::Directory(ref dir) => dir.children.get(name).copied().ok_or(libc::ENOENT),
+            _ => Err(libc::ENOTDIR),
+        }
+    }
+
+    fn replace_child(&self, parent: Ino, name: &OsStr, ino: Ino, now: Duration) {
+        if let Some(mut parent) = self.inodes.get_mut(parent) {
+            set_mtime(&mut parent.attr, now);
+            set_ctime(&mut parent.attr, now);
+            if let INodeKind::Directory(ref mut dir) = parent.kind {
+                dir.children.insert(name.into(), ino);
+            }
+        }
+    }
+
     fn do_getxattr(&self, req: &Request, op: op::Getxattr<'_>) -> io::Result<()> {
         let inode = match self.inodes.get(op.ino()) {
             Some(inode) => inode,
@@ -694,19 +895,7 @@ impl MemFS {
             None => return req.reply_error(libc::ENODATA),
         };
 
-        match op.size() {
-            0 => {
-                let mut out = XattrOut::default();
-                out.size(value.len() as u32);
-                req.reply(out)
-            }
-            size => {
-                if value.len() as u32 > size {
-                    return req.reply_error(libc::ERANGE);
-                }
-                req.reply(value)
-            }
-        }
+        req.reply_xattr_with(value.len(), |buf| buf.extend_from_slice(&value[..]))
     }
 
     fn do_setxattr(&self, req: &Request, op: op::Setxattr<'_>) -> io::Result<()> {
@@ -750,30 +939,13 @@ impl MemFS {
             None => return req.reply_error(libc::ENOENT),
         };
 
-        match op.size() {
-            0 => {
-                let total_len = inode.xattrs.keys().map(|name| name.len() as u32 + 1).sum();
-                let mut out = XattrOut::default();
-                out.size(total_len);
-                req.reply(out)
+        let total_len = inode.xattrs.keys().map(|name| name.len() + 1).sum();
+        req.reply_xattr_with(total_len, |buf| {
+            for name in inode.xattrs.keys() {
+                buf.extend_from_slice(name.as_bytes());
+                buf.push(b'\0');
             }
-
-            size => {
-                let mut total_len = 0;
-                let names = inode.xattrs.keys().fold(OsString::new(), |mut acc, name| {
-                    acc.push(name);
-                    acc.push("\0");
-                    total_len += name.len() as u32 + 1;
-                    acc
-                });
-
-                if total_len > size {
-                    return req.reply_error(libc::ERANGE);
-                }
-
-                req.reply(names)
-            }
-        }
+        })
     }
 
     fn do_removexattr(&self, req: &Request, op: op::Removexattr<'_>) -> io::Result<()> {
@@ -793,10 +965,11 @@ impl MemFS {
     }
 
     fn do_read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
-        let inode = match self.inodes.get(op.ino()) {
+        let mut inode = match self.inodes.get_mut(op.ino()) {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        set_atime(&mut inode.attr, self.now());
 
         let content = match inode.kind {
             INodeKind::RegularFile(ref content) => content,
@@ -820,6 +993,9 @@ impl MemFS {
             Some(inode) => inode,
             None => return req.reply_error(libc::ENOENT),
         };
+        if inode.flags.contains(InodeFlags::IMMUTABLE) {
+            return req.reply_error(libc::EPERM);
+        }
 
         let content = match inode.kind {
             INodeKind::RegularFile(ref mut content) => content,
@@ -833,26 +1009,324 @@ impl MemFS {
 
         data.read_exact(&mut content[offset..offset + size])?;
 
-        inode.attr.st_size = (offset + size) as libc::off_t;
+        inode.attr.st_size = content.len() as libc::off_t;
+        let now = self.now();
+        set_mtime(&mut inode.attr, now);
+        set_ctime(&mut inode.attr, now);
+        if let Some(ref sizes) = self.sizes {
+            sizes.record_write(op.ino(), op.offset(), u64::from(op.size()));
+        }
 
         let mut out = WriteOut::default();
         out.size(op.size());
 
         req.reply(out)
     }
+
+    fn do_ioctl(&self, req: &Request, op: op::Ioctl<'_>) -> io::Result<()> {
+        // The 32-bit callers use the commands of the other sizes, with the same `int` argument.
+        // They are matched by the guards, since the commands coincide on the 32-bit hosts.
+        match op.cmd() {
+            cmd if cmd == FS_IOC_GETFLAGS || cmd == FS_IOC32_GETFLAGS => {
+                let inode = match self.inodes.get(op.ino()) {
+                    Some(inode) => inode,
+                    None => return req.reply_error(libc::ENOENT),
+                };
+                let data = inode.flags.encode();
+                let len = std::cmp::min(data.len(), op.out_size() as usize);
+                req.reply((IoctlOut::default(), &data[..len]))
+            }
+
+            cmd if cmd == FS_IOC_SETFLAGS || cmd == FS_IOC32_SETFLAGS => {
+                let flags = match InodeFlags::decode(op.input()) {
+                    Some(flags) => flags,
+                    None => return req.reply_error(libc::EINVAL),
+                };
+                let mut inode = match self.inodes.get_mut(op.ino()) {
+                    Some(inode) => inode,
+                    None => return req.reply_error(libc::ENOENT),
+                };
+                if req.uid() != 0 && req.uid() != inode.attr.st_uid {
+                    return req.reply_error(libc::EPERM);
+                }
+                // Changing these flags requires CAP_LINUX_IMMUTABLE.
+                let protected = (InodeFlags::IMMUTABLE | InodeFlags::APPEND).bits();
+                if (flags.bits() ^ inode.flags.bits()) & protected != 0 && req.uid() != 0 {
+                    return req.reply_error(libc::EPERM);
+                }
+                inode.flags = flags;
+                req.reply(IoctlOut::default())
+            }
+
+            _ => req.reply_error(libc::ENOTTY),
+        }
+    }
 }
 
-fn fill_attr(attr: &mut FileAttr, st: &libc::stat) {
-    attr.ino(st.st_ino);
-    attr.size(st.st_size as u64);
-    attr.mode(st.st_mode);
-    attr.nlink(st.st_nlink as u32);
-    attr.uid(st.st_uid);
-    attr.gid(st.st_gid);
-    attr.rdev(st.st_rdev as u32);
-    attr.blksize(st.st_blksize as u32);
-    attr.blocks(st.st_blocks as u64);
-    attr.atime(Duration::new(st.st_atime as u64, st.st_atime_nsec as u32));
-    attr.mtime(Duration::new(st.st_mtime as u64, st.st_mtime_nsec as u32));
-    attr.ctime(Duration::new(st.st_ctime as u64, st.st_ctime_nsec as u32));
+fn since_epoch(time: SystemTime) -> Duration {
+    time.duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+}
+
+fn set_atime(attr: &mut libc::stat, time: Duration) {
+    attr.st_atime = time.as_secs() as i64;
+    attr.st_atime_nsec = i64::from(time.subsec_nanos());
+}
+
+fn set_mtime(attr: &mut libc::stat, time: Duration) {
+    attr.st_mtime = time.as_secs() as i64;
+    attr.st_mtime_nsec = i64::from(time.subsec_nanos());
+}
+
+fn set_ctime(attr: &mut libc::stat, time: Duration) {
+    attr.st_ctime = time.as_secs() as i64;
+    attr.st_ctime_nsec = i64::from(time.subsec_nanos());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polyfuse::{testing::sim::Simulator, util::ManualClock};
+
+    fn simulator(clock: ManualClock) -> Simulator<impl FnMut(&Request) -> io::Result<()>> {
+        simulator_with(clock, KernelConfig::default())
+    }
+
+    fn simulator_with(
+        clock: ManualClock,
+        mut config: KernelConfig,
+    ) -> Simulator<impl FnMut(&Request) -> io::Result<()>> {
+        let mut fs = MemFS::new(clock);
+        fs.cache.apply_config(&mut config);
+        config
+            .reject_stale_inodes(InodeTracking::Forgotten)
+            .strict(true);
+        Simulator::new(config, move |req| {
+            fs.handle_request(req).map_err(|err| {
+                err.downcast()
+                    .unwrap_or_else(|_| io::Error::from_raw_os_error(libc::EIO))
+            })
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn stat_after_create() {
+        let mut sim = simulator(ManualClock::default());
+
+        let dir = sim.mkdir("/dir", 0o755).unwrap();
+        let file = sim.mknod("/dir/file", libc::S_IFREG | 0o644).unwrap();
+        assert_eq!(dir.mode() & libc::S_IFMT, libc::S_IFDIR);
+        assert_eq!(sim.nlookup(file.ino()), 1);
+
+        // The replies of MKDIR and MKNOD are cached during the timeouts,
+        // except the attributes of the parent modified by MKNOD.
+        let requests = sim.requests();
+        assert_eq!(sim.stat("/dir/file").unwrap().size(), 0);
+        assert_eq!(sim.requests(), requests);
+        assert_eq!(sim.stat("/dir").unwrap().ino(), dir.ino());
+        assert_eq!(sim.requests(), requests + 1);
+
+        let names: Vec<_> = sim
+            .readdir("/dir")
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        assert_eq!(names, [".", "..", "file"]);
+    }
+
+    #[test]
+    fn mknod_node_types() {
+        let mut sim = simulator(ManualClock::default());
+        let errno = |sim: &mut Simulator<_>, path: &str, mode: u32| {
+            sim.mknod(path, mode).unwrap_err().raw_os_error()
+        };
+        assert_eq!(
+            errno(&mut sim, "/null", libc::S_IFCHR | 0o666),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            errno(&mut sim, "/sda", libc::S_IFBLK | 0o660),
+            Some(libc::EPERM)
+        );
+        assert_eq!(
+            errno(&mut sim, "/fifo", libc::S_IFIFO | 0o600),
+            Some(libc::ENOTSUP)
+        );
+        assert_eq!(
+            errno(&mut sim, "/dir", libc::S_IFDIR | 0o755),
+            Some(libc::EINVAL)
+        );
+        assert_eq!(sim.readdir("/").unwrap().len(), 2, "only . and ..");
+    }
+
+    #[test]
+    fn truncate_on_open() {
+        for &atomic_o_trunc in &[true, false] {
+            let mut config = KernelConfig::default();
+            config.atomic_o_trunc(atomic_o_trunc);
+            let mut sim = simulator_with(ManualClock::default(), config);
+            assert_eq!(sim.session().atomic_o_trunc(), atomic_o_trunc);
+
+            sim.mknod("/file", libc::S_IFREG | 0o644).unwrap();
+            let file = sim.open("/file", libc::O_RDWR).unwrap();
+            assert_eq!(sim.write(&file, 0, b"data").unwrap(), 4);
+            sim.release(file).unwrap();
+            assert_eq!(sim.stat("/file").unwrap().size(), 4);
+
+            let setattrs = sim.count(op::Opcode::Setattr.as_raw());
+            let file = sim.open("/file", libc::O_RDWR | libc::O_TRUNC).unwrap();
+            assert_eq!(sim.stat("/file").unwrap().size(), 0);
+            assert_eq!(sim.read(&file, 0, 4).unwrap(), b"");
+            sim.release(file).unwrap();
+
+            // Without atomic_o_trunc, the truncation is a separate SETATTR.
+            let expected = if atomic_o_trunc { 0 } else { 1 };
+            assert_eq!(sim.count(op::Opcode::Setattr.as_raw()) - setattrs, expected);
+        }
+    }
+
+    #[test]
+    fn lookup_after_forget() {
+        let mut sim = simulator(ManualClock::default());
+        let file = sim.mknod("/file", libc::S_IFREG | 0o644).unwrap();
+
+        // The inode is kept while linked, and looked up again after evicted.
+        sim.drop_caches().unwrap();
+        assert_eq!(sim.nlookup(file.ino()), 0);
+        assert_eq!(sim.stat("/file").unwrap().ino(), file.ino());
+        assert_eq!(sim.nlookup(file.ino()), 1);
+
+        let err = sim.stat("/nonexistent").unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+    }
+
+    #[test]
+    fn timestamps_follow_the_clock() {
+        let clock = ManualClock::default();
+        let mut sim = simulator(clock.clone());
+        let created = clock.now();
+        let attr = sim.mknod("/file", libc::S_IFREG | 0o644).unwrap();
+        assert_eq!(
+            (attr.atime(), attr.mtime(), attr.ctime()),
+            (created, created, created)
+        );
+
+        // Writes update mtime and ctime.
+        clock.advance(Duration::from_secs(10));
+        let written = clock.now();
+        let file = sim.open("/file", libc::O_RDWR).unwrap();
+        assert_eq!(sim.write(&file, 0, b"data").unwrap(), 4);
+        let attr = sim.stat("/file").unwrap();
+        assert_eq!(attr.size(), 4);
+        assert_eq!(
+            (attr.atime(), attr.mtime(), attr.ctime()),
+            (created, written, written)
+        );
+
+        // Reads update atime, which the kernel sees after the timeout.
+        clock.advance(Duration::from_secs(10));
+        let read = clock.now();
+        assert_eq!(sim.read(&file, 0, 4).unwrap(), b"data");
+        sim.release(file).unwrap();
+        sim.drop_caches().unwrap();
+        assert_eq!(sim.stat("/file").unwrap().atime(), read);
+
+        // chmod(2) bumps only ctime.
+        clock.advance(Duration::from_secs(10));
+        let changed = clock.now();
+        let attr = sim.chmod("/file", libc::S_IFREG | 0o600).unwrap();
+        assert_eq!(attr.mode(), libc::S_IFREG | 0o600);
+        assert_eq!(
+            (attr.atime(), attr.mtime(), attr.ctime()),
+            (read, written, changed)
+        );
+    }
+
+    fn names(
+        sim: &mut Simulator<impl FnMut(&Request) -> io::Result<()>>,
+        path: &str,
+    ) -> Vec<(OsString, u64)> {
+        let mut names: Vec<_> = sim
+            .readdir(path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| (entry.name, entry.ino))
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rename_exchange() {
+        let clock = ManualClock::default();
+        let mut sim = simulator(clock.clone());
+        let dir = sim.mkdir("/dir", 0o755).unwrap();
+        let sub = sim.mkdir("/dir/sub", 0o755).unwrap();
+        let file = sim.mknod("/file", libc::S_IFREG | 0o644).unwrap();
+        let opened = sim.open("/file", libc::O_RDWR).unwrap();
+        assert_eq!(sim.write(&opened, 0, b"data").unwrap(), 4);
+        let nlookups = (sim.nlookup(file.ino()), sim.nlookup(sub.ino()));
+
+        // Exchange a file with a directory across the parents.
+        clock.advance(Duration::from_secs(10));
+        let exchanged = clock.now();
+        sim.rename("/file", "/dir/sub", libc::RENAME_EXCHANGE)
+            .unwrap();
+        assert_eq!(sim.stat("/file").unwrap().ino(), sub.ino());
+        let attr = sim.stat("/dir/sub").unwrap();
+        assert_eq!((attr.ino(), attr.nlink(), attr.size()), (file.ino(), 1, 4));
+        assert_eq!(attr.ctime(), exchanged);
+        assert_eq!((sim.nlookup(file.ino()), sim.nlookup(sub.ino())), nlookups);
+        assert_eq!(sim.stat("/").unwrap().mtime(), exchanged);
+        assert_eq!(sim.stat("/dir").unwrap().mtime(), exchanged);
+
+        assert_eq!(
+            names(&mut sim, "/"),
+            [
+                (".".into(), 1),
+                ("..".into(), 1),
+                ("dir".into(), dir.ino()),
+                ("file".into(), sub.ino())
+            ]
+        );
+        // `..` of the moved directory follows its new parent.
+        assert_eq!(
+            names(&mut sim, "/file"),
+            [(".".into(), sub.ino()), ("..".into(), 1)]
+        );
+
+        // The opened handle keeps referring to the same inode.
+        assert_eq!(sim.read(&opened, 0, 4).unwrap(), b"data");
+        sim.release(opened).unwrap();
+
+        // An entry exchanged with itself is left as it is.
+        sim.rename("/dir/sub", "/dir/sub", libc::RENAME_EXCHANGE)
+            .unwrap();
+        assert_eq!(sim.stat("/dir/sub").unwrap().ino(), file.ino());
+
+        // Within the same parent.
+        let other = sim.mknod("/dir/other", libc::S_IFREG | 0o600).unwrap();
+        sim.rename("/dir/sub", "/dir/other", libc::RENAME_EXCHANGE)
+            .unwrap();
+        assert_eq!(
+            names(&mut sim, "/dir"),
+            [
+                (".".into(), dir.ino()),
+                ("..".into(), 1),
+                ("other".into(), file.ino()),
+                ("sub".into(), other.ino())
+            ]
+        );
+
+        let err = sim
+            .rename("/dir/sub", "/missing", libc::RENAME_EXCHANGE)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::ENOENT));
+        let err = sim
+            .rename("/dir/sub", "/dir/other", libc::RENAME_WHITEOUT)
+            .unwrap_err();
+        assert_eq!(err.raw_os_error(), Some(libc::EINVAL));
+    }
 }
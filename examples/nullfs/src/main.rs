@@ -0,0 +1,527 @@
+//! A filesystem that serves a single file of zeros and discards the written data.
+//!
+//! Since the filesystem does nothing for each request, the throughput and
+//! the latencies are bounded only by the kernel and `polyfuse`, which makes
+//! it useful for comparing the builds of `polyfuse`.  The results of the
+//! self test can be attached to the issues reporting performance regressions:
+//!
+//! ```shell-session
+//! $ cargo run --release -p polyfuse-example-nullfs -- --self-test 10 --mix getattr=1,read=4,write=4
+//! ```
+//!
+//! The requests are handled on the receiving loop by default, or on new
+//! threads with `--spawn`.  `--inline-budget <n>` handles up to `n`
+//! consecutive requests on the receiving loop and spawns the next one, for
+//! comparing the tail latencies with the budget of `util::Dispatcher`.
+
+#![allow(clippy::unnecessary_mut_passed)]
+#![deny(clippy::unimplemented)]
+
+use polyfuse::{
+    op,
+    reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, WriteOut},
+    util::{DispatchHint, Dispatcher},
+    KernelConfig, Operation, Request, Session, UnmountMode,
+};
+
+use anyhow::{anyhow, ensure, Context as _, Result};
+use std::{
+    fmt, fs, io,
+    os::unix::prelude::*,
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+const ROOT_INO: u64 = 1;
+const NULL_INO: u64 = 2;
+const NULL_FILENAME: &str = "null";
+const NULL_SIZE: u64 = 1024 * 1024 * 1024;
+
+const ENTRY_TTL: Duration = Duration::from_secs(60 * 60);
+
+fn main() -> Result<()> {
+    tracing_subscriber::fmt::init();
+
+    let mut args = pico_args::Arguments::from_env();
+
+    let mode = match args.opt_value_from_str("--inline-budget")? {
+        Some(max_requests) => Mode::Budget(max_requests),
+        None if args.contains("--spawn") => Mode::Spawn,
+        None => Mode::Inline,
+    };
+    let self_test = args
+        .opt_value_from_str("--self-test")?
+        .map(Duration::from_secs);
+    let mix: Mix = args
+        .opt_value_from_str("--mix")?
+        .unwrap_or_else(Mix::default);
+    let io_size = args.opt_value_from_str("--io-size")?.unwrap_or(4096);
+
+    if let Some(duration) = self_test {
+        let report = self_test_on_tempdir(duration, &mix, io_size, mode)?;
+        print!("{}", report);
+        return Ok(());
+    }
+
+    let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
+    ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
+
+    let session = Session::mount(mountpoint, KernelConfig::default())?;
+    serve(Arc::new(session), mode)
+}
+
+/// Where the requests are handled.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+enum Mode {
+    /// On the receiving loop.
+    Inline,
+    /// On a new thread for each request.
+    Spawn,
+    /// On the receiving loop, spawning one after the consecutive requests.
+    Budget(u32),
+}
+
+/// Receive the requests until the session is unmounted.
+fn serve(session: Arc<Session>, mode: Mode) -> Result<()> {
+    let fs = Arc::new(NullFs::new(session.buffer_size()));
+    let mut dispatcher = Dispatcher::new(move |_: &Request| match mode {
+        Mode::Spawn => DispatchHint::Spawn,
+        Mode::Inline | Mode::Budget(..) => DispatchHint::Inline,
+    });
+    if let Mode::Budget(max_requests) = mode {
+        dispatcher.budget(max_requests, Duration::from_millis(1));
+    }
+
+    while let Some(req) = session.next_request()? {
+        if dispatcher.hint(&req) == DispatchHint::Inline {
+            dispatcher.run_inline(&req, || fs.handle_request(&req))?;
+            continue;
+        }
+
+        let fs = fs.clone();
+        thread::spawn(move || {
+            if let Err(err) = fs.handle_request(&req) {
+                tracing::error!("failed to reply: {}", err);
+            }
+        });
+    }
+    Ok(())
+}
+
+struct NullFs {
+    zeros: Vec<u8>,
+    uid: u32,
+    gid: u32,
+}
+
+impl NullFs {
+    fn new(max_read: usize) -> Self {
+        Self {
+            zeros: vec![0; max_read],
+            uid: unsafe { libc::getuid() },
+            gid: unsafe { libc::getgid() },
+        }
+    }
+
+    fn handle_request(&self, req: &Request) -> io::Result<()> {
+        let op = match req.operation() {
+            Ok(op) => op,
+            Err(..) => return req.reply_error(libc::EINVAL),
+        };
+        match op {
+            Operation::Lookup(op) => self.lookup(req, op),
+            Operation::Getattr(op) => self.getattr(req, op.ino()),
+            // The size is fixed, so the truncations (e.g. by `O_TRUNC`) are ignored.
+            Operation::Setattr(op) => self.getattr(req, op.ino()),
+            Operation::Open(op) => self.open(req, op),
+            Operation::Read(op) => self.read(req, op),
+            Operation::Write(op, _data) => self.write(req, op),
+            Operation::Readdir(op) => self.readdir(req, op),
+            Operation::Flush(..) | Operation::Release(..) | Operation::Fsync(..) => req.reply(()),
+            Operation::Forget(..) => Ok(()),
+            _ => req.reply_error(libc::ENOSYS),
+        }
+    }
+
+    fn fill_attr(&self, ino: u64, attr: &mut FileAttr) -> bool {
+        match ino {
+            ROOT_INO => {
+                attr.mode(libc::S_IFDIR | 0o755);
+                attr.nlink(2);
+            }
+            NULL_INO => {
+                attr.mode(libc::S_IFREG | 0o666);
+                attr.nlink(1);
+                attr.size(NULL_SIZE);
+            }
+            _ => return false,
+        }
+        attr.ino(ino);
+        attr.uid(self.uid);
+        attr.gid(self.gid);
+        true
+    }
+
+    fn lookup(&self, req: &Request, op: op::Lookup<'_>) -> io::Result<()> {
+        if op.parent() != ROOT_INO || op.name().as_bytes() != NULL_FILENAME.as_bytes() {
+            return req.reply_error(libc::ENOENT);
+        }
+        let mut out = EntryOut::default();
+        self.fill_attr(NULL_INO, out.attr());
+        out.ino(NULL_INO);
+        out.ttl_entry(ENTRY_TTL);
+        // The attributes are not cached, so that each `stat(2)` reaches the filesystem.
+        out.ttl_attr(Duration::from_secs(0));
+        req.reply(out)
+    }
+
+    fn getattr(&self, req: &Request, ino: u64) -> io::Result<()> {
+        let mut out = AttrOut::default();
+        if !self.fill_attr(ino, out.attr()) {
+            return req.reply_error(libc::ENOENT);
+        }
+        out.ttl(Duration::from_secs(0));
+        req.reply(out)
+    }
+
+    fn open(&self, req: &Request, op: op::Open<'_>) -> io::Result<()> {
+        match op.ino() {
+            NULL_INO => {
+                // Bypass the page cache, so that each read and write reaches the filesystem.
+                let mut out = OpenOut::default();
+                out.direct_io(true);
+                req.reply(out)
+            }
+            ROOT_INO => req.reply_error(libc::EISDIR),
+            _ => req.reply_error(libc::ENOENT),
+        }
+    }
+
+    fn read(&self, req: &Request, op: op::Read<'_>) -> io::Result<()> {
+        if op.ino() != NULL_INO {
+            return req.reply_error(libc::EISDIR);
+        }
+        let remaining = NULL_SIZE.saturating_sub(op.offset());
+        let len = (op.size() as u64).min(remaining) as usize;
+        req.reply(&self.zeros[..len.min(self.zeros.len())])
+    }
+
+    fn write(&self, req: &Request, op: op::Write<'_>) -> io::Result<()> {
+        if op.ino() != NULL_INO {
+            return req.reply_error(libc::EISDIR);
+        }
+        let mut out = WriteOut::default();
+        out.size(op.size());
+        req.reply(out)
+    }
+
+    fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
+        if op.ino() != ROOT_INO {
+            return req.reply_error(libc::ENOTDIR);
+        }
+        let entries = [
+            (".", ROOT_INO, libc::DT_DIR),
+            ("..", ROOT_INO, libc::DT_DIR),
+            (NULL_FILENAME, NULL_INO, libc::DT_REG),
+        ];
+        let mut out = ReaddirOut::with_offset(op.size() as usize, op.offset());
+        for &(name, ino, typ) in entries.iter().skip(op.offset() as usize) {
+            if out.next_entry(name.as_ref(), ino, typ as u32) {
+                break;
+            }
+        }
+        req.reply(out)
+    }
+}
+
+// ==== self test ====
+
+/// The operations issued by the self test.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Workload {
+    Getattr,
+    Read,
+    Write,
+}
+
+impl Workload {
+    fn name(self) -> &'static str {
+        match self {
+            Workload::Getattr => "getattr",
+            Workload::Read => "read",
+            Workload::Write => "write",
+        }
+    }
+}
+
+/// The mixture of the operations, by their weights.
+#[derive(Debug, PartialEq)]
+struct Mix(Vec<(Workload, u32)>);
+
+impl Default for Mix {
+    fn default() -> Self {
+        Self(vec![
+            (Workload::Getattr, 1),
+            (Workload::Read, 1),
+            (Workload::Write, 1),
+        ])
+    }
+}
+
+impl std::str::FromStr for Mix {
+    type Err = anyhow::Error;
+
+    /// Parse the mixture such as `getattr=1,read=2,write=1`.
+    fn from_str(s: &str) -> Result<Self> {
+        let mut mix = vec![];
+        for item in s.split(',') {
+            let (name, weight) = match item.find('=') {
+                Some(pos) => (&item[..pos], item[pos + 1..].parse()?),
+                None => (item, 1),
+            };
+            let workload = match name {
+                "getattr" => Workload::Getattr,
+                "read" => Workload::Read,
+                "write" => Workload::Write,
+                name => return Err(anyhow!("unknown operation: {}", name)),
+            };
+            mix.push((workload, weight));
+        }
+        ensure!(
+            mix.iter().any(|&(_, weight)| weight > 0),
+            "no operation is specified"
+        );
+        Ok(Self(mix))
+    }
+}
+
+impl Mix {
+    /// Return the order of the operations issued repeatedly.
+    fn schedule(&self) -> Vec<Workload> {
+        self.0
+            .iter()
+            .flat_map(|&(workload, weight)| (0..weight).map(move |_| workload))
+            .collect()
+    }
+}
+
+/// The result of the self test.
+#[derive(Debug)]
+struct Report {
+    elapsed: Duration,
+    results: Vec<(Workload, Vec<Duration>)>,
+}
+
+impl Report {
+    fn new(elapsed: Duration, mut results: Vec<(Workload, Vec<Duration>)>) -> Self {
+        for (_, latencies) in &mut results {
+            latencies.sort();
+        }
+        Self { elapsed, results }
+    }
+
+    fn ops_per_sec(&self, count: usize) -> f64 {
+        count as f64 / self.elapsed.as_secs_f64()
+    }
+}
+
+/// Return the latency at the percentile, from the sorted latencies.
+fn percentile(sorted: &[Duration], p: f64) -> Option<Duration> {
+    let rank = (sorted.len() as f64 * p / 100.0).ceil() as usize;
+    sorted.get(rank.max(1) - 1).copied()
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total: usize = self.results.iter().map(|(_, l)| l.len()).sum();
+        writeln!(
+            f,
+            "{:<8} {:>10} {:>12} {:>12} {:>12}",
+            "op", "count", "ops/sec", "p50", "p99"
+        )?;
+        for (workload, latencies) in &self.results {
+            writeln!(
+                f,
+                "{:<8} {:>10} {:>12.0} {:>12?} {:>12?}",
+                workload.name(),
+                latencies.len(),
+                self.ops_per_sec(latencies.len()),
+                percentile(latencies, 50.0).unwrap_or_default(),
+                percentile(latencies, 99.0).unwrap_or_default(),
+            )?;
+        }
+        writeln!(
+            f,
+            "{:<8} {:>10} {:>12.0}   (in {:?})",
+            "total",
+            total,
+            self.ops_per_sec(total),
+            self.elapsed
+        )
+    }
+}
+
+/// Issue the operations on the file in order, until the duration elapses.
+fn run_workload(path: &Path, duration: Duration, mix: &Mix, io_size: usize) -> Result<Report> {
+    let file = fs::OpenOptions::new().read(true).write(true).open(path)?;
+    let schedule = mix.schedule();
+    let mut results: Vec<(Workload, Vec<Duration>)> = mix
+        .0
+        .iter()
+        .map(|&(workload, _)| (workload, vec![]))
+        .collect();
+    let mut buf = vec![0u8; io_size];
+    let mut offset = 0;
+
+    let start = Instant::now();
+    for &workload in schedule.iter().cycle() {
+        if start.elapsed() >= duration {
+            break;
+        }
+        let issued = Instant::now();
+        match workload {
+            Workload::Getattr => {
+                fs::metadata(path)?;
+            }
+            Workload::Read => {
+                file.read_at(&mut buf[..], offset)?;
+            }
+            Workload::Write => {
+                file.write_at(&buf[..], offset)?;
+            }
+        }
+        let latency = issued.elapsed();
+        if let Some((_, latencies)) = results.iter_mut().find(|(w, _)| *w == workload) {
+            latencies.push(latency);
+        }
+        offset = (offset + io_size as u64) % (NULL_SIZE - io_size as u64);
+    }
+
+    Ok(Report::new(start.elapsed(), results))
+}
+
+/// Mount the filesystem on a temporary directory, and measure the workload from another thread.
+///
+/// This requires the permission to mount FUSE filesystems, i.e. `/dev/fuse`
+/// and `fusermount`.
+fn self_test_on_tempdir(
+    duration: Duration,
+    mix: &Mix,
+    io_size: usize,
+    mode: Mode,
+) -> Result<Report> {
+    ensure!(
+        io_size > 0 && io_size as u64 <= NULL_SIZE / 2,
+        "invalid I/O size"
+    );
+
+    let mountpoint = std::env::temp_dir().join(format!("polyfuse-nullfs-{}", process::id()));
+    fs::create_dir_all(&mountpoint)?;
+    let session = match Session::mount(mountpoint.clone(), KernelConfig::default()) {
+        Ok(session) => session,
+        Err(err) => {
+            let _ = fs::remove_dir(&mountpoint);
+            return Err(err)
+                .with_context(|| format!("failed to mount on {}", mountpoint.display()));
+        }
+    };
+    let session = Arc::new(session);
+    let server = thread::spawn({
+        let session = session.clone();
+        move || serve(session, mode)
+    });
+
+    let report = run_workload(&mountpoint.join(NULL_FILENAME), duration, mix, io_size);
+
+    session.unmount(UnmountMode::Lazy)?;
+    server
+        .join()
+        .map_err(|_| anyhow!("the server thread panicked"))??;
+    fs::remove_dir(&mountpoint)?;
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polyfuse::testing::sim::Simulator;
+
+    fn simulator() -> Simulator<impl FnMut(&Request) -> io::Result<()>> {
+        let fs = NullFs::new(128 * 1024);
+        let mut config = KernelConfig::default();
+        config.strict(true);
+        Simulator::new(config, move |req| fs.handle_request(req)).unwrap()
+    }
+
+    #[test]
+    fn read_zeros_and_discard_writes() {
+        let mut sim = simulator();
+        let attr = sim.stat("/null").unwrap();
+        assert_eq!(attr.ino(), NULL_INO);
+        assert_eq!(attr.size(), NULL_SIZE);
+
+        let file = sim.open("/null", libc::O_RDWR).unwrap();
+        assert_eq!(sim.write(&file, 0, b"discarded").unwrap(), 9);
+        assert_eq!(sim.read(&file, 0, 4096).unwrap(), vec![0; 4096]);
+        assert_eq!(sim.read(&file, NULL_SIZE - 10, 4096).unwrap(), vec![0; 10]);
+        sim.release(file).unwrap();
+
+        // The attributes are not cached.
+        let requests = sim.requests();
+        sim.stat("/null").unwrap();
+        assert_eq!(sim.requests(), requests + 1);
+    }
+
+    #[test]
+    fn parse_mix() {
+        let mix: Mix = "getattr=1,read=2,write".parse().unwrap();
+        assert_eq!(
+            mix.schedule(),
+            [
+                Workload::Getattr,
+                Workload::Read,
+                Workload::Read,
+                Workload::Write
+            ]
+        );
+        assert!("read=2,open=1".parse::<Mix>().is_err());
+        assert!("read=0".parse::<Mix>().is_err());
+    }
+
+    #[test]
+    fn latency_percentiles() {
+        let latencies: Vec<_> = (1..=100).map(Duration::from_micros).collect();
+        assert_eq!(
+            percentile(&latencies, 99.0),
+            Some(Duration::from_micros(99))
+        );
+        assert_eq!(
+            percentile(&latencies, 50.0),
+            Some(Duration::from_micros(50))
+        );
+        assert_eq!(
+            percentile(&latencies[..1], 99.0),
+            Some(Duration::from_micros(1))
+        );
+        assert_eq!(percentile(&[], 99.0), None);
+    }
+
+    /// Run with `cargo test -p polyfuse-example-nullfs -- --ignored` where
+    /// FUSE filesystems can be mounted.
+    #[test]
+    #[ignore]
+    fn self_test_smoke() {
+        for &mode in &[Mode::Inline, Mode::Spawn, Mode::Budget(8)] {
+            let report =
+                self_test_on_tempdir(Duration::from_secs(1), &Mix::default(), 4096, mode).unwrap();
+            println!("mode = {:?}\n{}", mode, report);
+            for (workload, latencies) in &report.results {
+                assert!(!latencies.is_empty(), "no {} is issued", workload.name());
+            }
+        }
+    }
+}
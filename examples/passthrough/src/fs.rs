@@ -100,11 +100,7 @@ impl FileDesc {
         let dp = NonNull::new(unsafe { libc::fdopendir(fd.0) }) //
             .ok_or_else(io::Error::last_os_error)?;
 
-        Ok(ReadDir {
-            dir: Dir(dp),
-            offset: 0,
-            fd,
-        })
+        Ok(ReadDir { dir: Dir(dp), fd })
     }
 
     pub fn readlinkat(&self, path: impl AsRef<OsStr>) -> io::Result<OsString> {
@@ -266,7 +262,6 @@ unsafe impl Sync for ReadDir {}
 
 pub struct ReadDir {
     dir: Dir,
-    offset: u64,
     #[allow(dead_code)]
     fd: FileDesc,
 }
@@ -280,12 +275,9 @@ impl Drop for ReadDir {
 }
 
 impl ReadDir {
-    pub fn seek(&mut self, offset: u64) {
-        if offset != self.offset {
-            unsafe {
-                libc::seekdir(self.dir.0.as_mut(), offset as libc::off_t);
-            }
-            self.offset = offset;
+    pub fn rewind(&mut self) {
+        unsafe {
+            libc::rewinddir(self.dir.0.as_mut());
         }
     }
 
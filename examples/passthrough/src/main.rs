@@ -5,16 +5,17 @@ mod fs;
 
 use polyfuse::{
     op,
-    reply::{
-        AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, Statfs, StatfsOut, WriteOut, XattrOut,
+    reply::{AttrOut, EntryOut, OpenOut, ReaddirOut, Statfs, StatfsOut, WriteOut, XattrOut},
+    util::{
+        validate_lookup_name, validate_name, AlignedBuf, DirSnapshot, DirtyTracker, DispatchHint,
+        Dispatcher, HandlePolicy, HandleTable, XattrProbeCache,
     },
-    KernelConfig, Operation, Session,
+    Errno, InodeTracking, KernelConfig, Operation, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
 use either::Either;
 use pico_args::Arguments;
-use slab::Slab;
 use std::{
     collections::hash_map::{Entry, HashMap},
     ffi::{OsStr, OsString},
@@ -27,7 +28,7 @@ use std::{
     time::Duration,
 };
 
-use crate::fs::{FileDesc, ReadDir};
+use crate::fs::{DirEntry, FileDesc, ReadDir};
 
 fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
@@ -45,6 +46,12 @@ fn main() -> Result<()> {
         Some(Duration::from_secs(60 * 60 * 24)) // one day
     };
 
+    // Reply ENOSYS to OPEN and serve reads and writes by the inodes.
+    let no_open = args.contains("--no-open");
+
+    // Fail the opens with EMFILE beyond this number of opened files.
+    let max_open_files: Option<usize> = args.opt_value_from_str("--max-open-files")?;
+
     let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
     ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
 
@@ -55,121 +62,170 @@ fn main() -> Result<()> {
         config.mount_option("fsname=passthrough");
         config.export_support(true);
         config.flock_locks(true);
+        config.reject_stale_inodes(InodeTracking::Forgotten);
         config.writeback_cache(timeout.is_some());
         config
     })?;
 
-    let fs = Arc::new(Passthrough::new(source, timeout)?);
+    let no_open = no_open && session.no_open_support();
+    let fs = Arc::new(Passthrough::new(source, timeout, no_open, max_open_files)?);
+
+    // The forgets only update the inode table, and processing them in the
+    // receiving loop also keeps the order of lookup counts.
+    let dispatcher = Dispatcher::new(|req: &Request| match req.operation() {
+        Ok(Operation::Forget(..)) => DispatchHint::Inline,
+        _ => DispatchHint::Spawn,
+    });
 
     while let Some(req) = session.next_request()? {
+        if dispatcher.hint(&req) == DispatchHint::Inline {
+            // The errors are logged by `Request::process`.
+            let _ = dispatcher.run_inline(&req, || req.process(|req| handle_request(&fs, req)));
+            continue;
+        }
+
         let fs = fs.clone();
+        std::thread::spawn(move || req.process(|req| handle_request(&fs, req)));
+    }
 
-        std::thread::spawn(move || -> Result<()> {
-            let span = tracing::debug_span!("handle_request", unique = req.unique());
-            let _enter = span.enter();
-
-            let op = req.operation()?;
-            tracing::debug!(?op);
-
-            macro_rules! try_reply {
-                ($e:expr) => {
-                    match $e {
-                        Ok(data) => {
-                            tracing::debug!(?data);
-                            req.reply(data)?;
-                        }
-                        Err(err) => {
-                            let errno = io_to_errno(err);
-                            tracing::debug!(errno = errno);
-                            req.reply_error(errno)?;
-                        }
-                    }
-                };
-            }
+    // The kernel may close the connection without sending the forgets.
+    fs.forget_all();
 
-            match op {
-                Operation::Lookup(op) => try_reply!(fs.do_lookup(op.parent(), op.name())),
-                Operation::Forget(forgets) => {
-                    for forget in forgets.as_ref() {
-                        fs.forget_one(forget.ino(), forget.nlookup());
-                    }
+    Ok(())
+}
+
+fn handle_request(fs: &Passthrough, req: &Request) -> io::Result<()> {
+    let span = tracing::debug_span!("handle_request", unique = req.unique());
+    let _enter = span.enter();
+
+    let op = req.operation().map_err(|err| {
+        tracing::error!("failed to decode the request: {}", err);
+        io::Error::from_raw_os_error(libc::EINVAL)
+    })?;
+    tracing::debug!(?op);
+
+    macro_rules! try_reply {
+        ($e:expr) => {
+            match $e {
+                Ok(data) => {
+                    tracing::debug!(?data);
+                    req.reply(data)?;
                 }
-                Operation::Getattr(op) => try_reply!(fs.do_getattr(&op)),
-                Operation::Setattr(op) => try_reply!(fs.do_setattr(&op)),
-                Operation::Readlink(op) => try_reply!(fs.do_readlink(&op)),
-                Operation::Link(op) => try_reply!(fs.do_link(&op)),
-
-                Operation::Mknod(op) => {
-                    try_reply!(fs.make_node(
-                        op.parent(),
-                        op.name(),
-                        op.mode(),
-                        Some(op.rdev()),
-                        None
-                    ))
+                Err(err) => {
+                    let errno = io_to_errno(err);
+                    tracing::debug!(errno = errno);
+                    req.reply_error(errno)?;
                 }
-                Operation::Mkdir(op) => try_reply!(fs.make_node(
-                    op.parent(),
-                    op.name(),
-                    libc::S_IFDIR | op.mode(),
-                    None,
-                    None
-                )),
-                Operation::Symlink(op) => try_reply!(fs.make_node(
-                    op.parent(),
-                    op.name(),
-                    libc::S_IFLNK,
-                    None,
-                    Some(op.link())
-                )),
-
-                Operation::Unlink(op) => try_reply!(fs.do_unlink(&op)),
-                Operation::Rmdir(op) => try_reply!(fs.do_rmdir(&op)),
-                Operation::Rename(op) => try_reply!(fs.do_rename(&op)),
-
-                Operation::Opendir(op) => try_reply!(fs.do_opendir(&op)),
-                Operation::Readdir(op) => try_reply!(fs.do_readdir(&op)),
-                Operation::Fsyncdir(op) => try_reply!(fs.do_fsyncdir(&op)),
-                Operation::Releasedir(op) => try_reply!(fs.do_releasedir(&op)),
-
-                Operation::Open(op) => try_reply!(fs.do_open(&op)),
-                Operation::Read(op) => try_reply!(fs.do_read(&op)),
-                Operation::Write(op, data) => try_reply!(fs.do_write(&op, data)),
-                Operation::Flush(op) => try_reply!(fs.do_flush(&op)),
-                Operation::Fsync(op) => try_reply!(fs.do_fsync(&op)),
-                Operation::Flock(op) => try_reply!(fs.do_flock(&op)),
-                Operation::Fallocate(op) => try_reply!(fs.do_fallocate(&op)),
-                Operation::Release(op) => try_reply!(fs.do_release(&op)),
-
-                Operation::Getxattr(op) => try_reply!(fs.do_getxattr(&op)),
-                Operation::Listxattr(op) => try_reply!(fs.do_listxattr(&op)),
-                Operation::Setxattr(op) => try_reply!(fs.do_setxattr(&op)),
-                Operation::Removexattr(op) => try_reply!(fs.do_removexattr(&op)),
-
-                Operation::Statfs(op) => try_reply!(fs.do_statfs(&op)),
-
-                _ => req.reply_error(libc::ENOSYS)?,
             }
+        };
+    }
 
-            Ok(())
-        });
+    match op {
+        Operation::Lookup(op) => try_reply!(fs.do_lookup(op.parent(), op.name())),
+        Operation::Forget(forgets) => {
+            for forget in forgets.as_ref() {
+                fs.forget_one(forget.ino(), forget.nlookup());
+            }
+        }
+        Operation::Destroy(..) => {
+            fs.forget_all();
+            req.reply(())?;
+        }
+        Operation::Getattr(op) => try_reply!(fs.do_getattr(&op)),
+        Operation::Setattr(op) => try_reply!(fs.do_setattr(&op)),
+        Operation::Readlink(op) => try_reply!(fs.do_readlink(&op)),
+        Operation::Link(op) => try_reply!(fs.do_link(&op)),
+
+        Operation::Mknod(op) => match op.node_type() {
+            // The device nodes are created only by the privileged process,
+            // since the unprivileged mounts cannot use them anyway.
+            Some(node) if node.is_device() && unsafe { libc::geteuid() } != 0 => {
+                req.reply_error(libc::EPERM)?
+            }
+            Some(node) => {
+                try_reply!(fs.make_node(op.parent(), op.name(), op.mode(), Some(node.rdev()), None))
+            }
+            None => req.reply_error(libc::EINVAL)?,
+        },
+        Operation::Mkdir(op) => try_reply!(fs.make_node(
+            op.parent(),
+            op.name(),
+            libc::S_IFDIR | op.mode(),
+            None,
+            None
+        )),
+        Operation::Symlink(op) => {
+            try_reply!(fs.make_node(op.parent(), op.name(), libc::S_IFLNK, None, Some(op.link())))
+        }
+
+        Operation::Unlink(op) => try_reply!(fs.do_unlink(&op)),
+        Operation::Rmdir(op) => try_reply!(fs.do_rmdir(&op)),
+        Operation::Rename(op) => try_reply!(fs.do_rename(&op)),
+
+        Operation::Opendir(op) => try_reply!(fs.do_opendir(&op)),
+        Operation::Readdir(op) => try_reply!(fs.do_readdir(&op)),
+        Operation::Fsyncdir(op) => try_reply!(fs.do_fsyncdir(&op)),
+        Operation::Releasedir(op) => try_reply!(fs.do_releasedir(&op)),
+
+        Operation::Open(op) => try_reply!(fs.do_open(&op)),
+        Operation::Read(op) => try_reply!(fs.do_read(&op)),
+        Operation::Write(op, data) => try_reply!(fs.do_write(&op, data)),
+        Operation::Flush(op) => try_reply!(fs.do_flush(&op)),
+        Operation::Fsync(op) => try_reply!(fs.do_fsync(&op)),
+        Operation::Flock(op) => try_reply!(fs.do_flock(&op)),
+        Operation::Fallocate(op) => try_reply!(fs.do_fallocate(&op)),
+        Operation::Release(op) => try_reply!(fs.do_release(&op)),
+
+        Operation::Getxattr(op) => try_reply!(fs.do_getxattr(&op, req.uid())),
+        Operation::Listxattr(op) => try_reply!(fs.do_listxattr(&op)),
+        Operation::Setxattr(op) => try_reply!(fs.do_setxattr(&op)),
+        Operation::Removexattr(op) => try_reply!(fs.do_removexattr(&op)),
+
+        Operation::Statfs(op) => try_reply!(fs.do_statfs(&op)),
+
+        _ => req.reply_error(libc::ENOSYS)?,
     }
 
     Ok(())
 }
 
+/// Read the whole value of the extended attribute.
+fn read_xattr(path: impl AsRef<OsStr>, name: &OsStr) -> io::Result<Vec<u8>> {
+    loop {
+        let size = fs::getxattr(&path, name, None)?;
+        let mut value = vec![0u8; size];
+        match fs::getxattr(&path, name, Some(&mut value[..])) {
+            Ok(n) => {
+                value.truncate(n);
+                return Ok(value);
+            }
+            // The value has grown since the size was queried.
+            Err(err) if err.raw_os_error() == Some(libc::ERANGE) => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 type Ino = u64;
 type SrcId = (u64, libc::dev_t);
 
 struct Passthrough {
     inodes: Mutex<INodeTable>,
-    opened_dirs: HandlePool<Mutex<ReadDir>>,
-    opened_files: HandlePool<Mutex<File>>,
+    opened_dirs: HandleTable<OpenedDir>,
+    opened_files: HandleTable<Mutex<File>>,
+    xattrs: XattrProbeCache,
+    dirty: DirtyTracker,
     timeout: Option<Duration>,
+    no_open: bool,
 }
 
 impl Passthrough {
-    fn new(source: PathBuf, timeout: Option<Duration>) -> io::Result<Self> {
+    fn new(
+        source: PathBuf,
+        timeout: Option<Duration>,
+        no_open: bool,
+        max_open_files: Option<usize>,
+    ) -> io::Result<Self> {
         let source = source.canonicalize()?;
         tracing::debug!("source={:?}", source);
         let fd = FileDesc::open(&source, libc::O_PATH)?;
@@ -181,23 +237,29 @@ impl Passthrough {
         entry.insert(INode {
             ino: 1,
             fd,
-            refcount: u64::max_value() / 2, // the root node's cache is never removed.
+            refcount: u64::MAX / 2, // the root node's cache is never removed.
             src_id: (stat.st_ino, stat.st_dev),
             is_symlink: false,
         });
 
         Ok(Self {
             inodes: Mutex::new(inodes),
-            opened_dirs: HandlePool::default(),
-            opened_files: HandlePool::default(),
+            opened_dirs: HandleTable::default(),
+            opened_files: match max_open_files {
+                Some(max) => HandleTable::with_capacity_policy(max, HandlePolicy::Reject),
+                None => HandleTable::default(),
+            },
+            xattrs: XattrProbeCache::default(),
+            dirty: DirtyTracker::default(),
             timeout,
+            no_open,
         })
     }
 
     fn make_entry_param(&self, ino: u64, attr: libc::stat) -> EntryOut {
         let mut reply = EntryOut::default();
         reply.ino(ino);
-        fill_attr(reply.attr(), &attr);
+        reply.attr().stat(&attr);
         if let Some(timeout) = self.timeout {
             reply.ttl_entry(timeout);
             reply.ttl_attr(timeout);
@@ -206,6 +268,8 @@ impl Passthrough {
     }
 
     fn do_lookup(&self, parent: Ino, name: &OsStr) -> io::Result<EntryOut> {
+        validate_lookup_name(name).map_err(io::Error::from_raw_os_error)?;
+
         let mut inodes = self.inodes.lock().unwrap();
         let inodes = &mut *inodes;
 
@@ -247,6 +311,13 @@ impl Passthrough {
         Ok(self.make_entry_param(ino, stat))
     }
 
+    fn forget_all(&self) {
+        let mut inodes = self.inodes.lock().unwrap();
+        // The root inode is not the target of lookup count.
+        inodes.map.retain(|&ino, _| ino == 1);
+        inodes.src_to_ino.retain(|_, &mut ino| ino == 1);
+    }
+
     fn forget_one(&self, ino: Ino, nlookup: u64) {
         let mut inodes = self.inodes.lock().unwrap();
 
@@ -273,7 +344,7 @@ impl Passthrough {
         let stat = inode.fd.fstatat("", libc::AT_SYMLINK_NOFOLLOW)?;
 
         let mut out = AttrOut::default();
-        fill_attr(out.attr(), &stat);
+        out.attr().stat(&stat);
         if let Some(timeout) = self.timeout {
             out.ttl(timeout);
         };
@@ -289,15 +360,11 @@ impl Passthrough {
         let fd = &inode.fd;
 
         let mut file = if let Some(fh) = op.fh() {
-            Some(self.opened_files.get(fh).ok_or_else(no_entry)?)
-        } else {
-            None
-        };
-        let mut file = if let Some(ref mut file) = file {
-            Some(file.lock().unwrap())
+            Some(self.opened_files.get(fh).ok_or_else(bad_handle)?)
         } else {
             None
         };
+        let mut file = file.as_mut().map(|file| file.lock().unwrap());
 
         // chmod
         if let Some(mode) = op.mode() {
@@ -362,11 +429,17 @@ impl Passthrough {
             }
         }
 
+        if op.size().is_some() {
+            self.dirty.mark_dirty(op.fh(), op.ino());
+        } else {
+            self.dirty.mark_metadata(op.ino());
+        }
+
         // finally, acquiring the latest metadata from the source filesystem.
         let stat = fd.fstatat("", libc::AT_SYMLINK_NOFOLLOW)?;
 
         let mut out = AttrOut::default();
-        fill_attr(out.attr(), &stat);
+        out.attr().stat(&stat);
         if let Some(timeout) = self.timeout {
             out.ttl(timeout);
         };
@@ -382,6 +455,8 @@ impl Passthrough {
     }
 
     fn do_link(&self, op: &op::Link<'_>) -> io::Result<EntryOut> {
+        validate_name(op.newname()).map_err(io::Error::from_raw_os_error)?;
+
         let inodes = self.inodes.lock().unwrap();
 
         let source = inodes.get(op.ino()).ok_or_else(no_entry)?;
@@ -414,6 +489,8 @@ impl Passthrough {
         let entry = self.make_entry_param(source.ino, stat);
 
         source.refcount += 1;
+        self.dirty.mark_metadata(op.ino());
+        self.dirty.mark_metadata(op.newparent());
 
         Ok(entry)
     }
@@ -423,9 +500,10 @@ impl Passthrough {
         parent: Ino,
         name: &OsStr,
         mode: u32,
-        rdev: Option<u32>,
+        rdev: Option<u64>,
         link: Option<&OsStr>,
     ) -> io::Result<EntryOut> {
+        validate_name(name).map_err(io::Error::from_raw_os_error)?;
         {
             let inodes = self.inodes.lock().unwrap();
             let parent = inodes.get(parent).ok_or_else(no_entry)?;
@@ -446,6 +524,7 @@ impl Passthrough {
                 }
             }
         }
+        self.dirty.mark_metadata(parent);
         self.do_lookup(parent, name)
     }
 
@@ -454,6 +533,7 @@ impl Passthrough {
         let parent = inodes.get(op.parent()).ok_or_else(no_entry)?;
         let parent = parent.lock().unwrap();
         parent.fd.unlinkat(op.name(), 0)?;
+        self.dirty.mark_metadata(op.parent());
         Ok(())
     }
 
@@ -462,6 +542,7 @@ impl Passthrough {
         let parent = inodes.get(op.parent()).ok_or_else(no_entry)?;
         let parent = parent.lock().unwrap();
         parent.fd.unlinkat(op.name(), libc::AT_REMOVEDIR)?;
+        self.dirty.mark_metadata(op.parent());
         Ok(())
     }
 
@@ -470,6 +551,8 @@ impl Passthrough {
             // rename2 is not supported.
             return Err(io::Error::from_raw_os_error(libc::EINVAL));
         }
+        validate_lookup_name(op.name()).map_err(io::Error::from_raw_os_error)?;
+        validate_name(op.newname()).map_err(io::Error::from_raw_os_error)?;
 
         let inodes = self.inodes.lock().unwrap();
 
@@ -487,6 +570,8 @@ impl Passthrough {
                 .fd
                 .renameat(op.name(), Some(&newparent.fd), op.newname())?;
         }
+        self.dirty.mark_metadata(op.parent());
+        self.dirty.mark_metadata(op.newparent());
 
         Ok(())
     }
@@ -495,8 +580,12 @@ impl Passthrough {
         let inodes = self.inodes.lock().unwrap();
         let inode = inodes.get(op.ino()).ok_or_else(no_entry)?;
         let inode = inode.lock().unwrap();
-        let dir = inode.fd.read_dir()?;
-        let fh = self.opened_dirs.insert(Mutex::new(dir));
+        let mut read_dir = inode.fd.read_dir()?;
+        let snapshot = DirSnapshot::new(read_entries(&mut read_dir)?);
+        let fh = self.opened_dirs.insert(OpenedDir {
+            read_dir: Mutex::new(read_dir),
+            snapshot,
+        })?;
 
         let mut out = OpenOut::default();
         out.fh(fh);
@@ -509,35 +598,41 @@ impl Passthrough {
             return Err(io::Error::from_raw_os_error(libc::ENOSYS));
         }
 
-        let read_dir = self
-            .opened_dirs
-            .get(op.fh())
-            .ok_or_else(|| io::Error::from_raw_os_error(libc::ENOENT))?;
-        let read_dir = &mut *read_dir.lock().unwrap();
-        read_dir.seek(op.offset());
+        let dir = self.opened_dirs.get(op.fh()).ok_or_else(bad_handle)?;
 
         let mut out = ReaddirOut::new(op.size() as usize);
-        for entry in read_dir {
-            let entry = entry?;
-            if out.entry(&entry.name, entry.ino, entry.typ, entry.off) {
-                break;
-            }
-        }
+        dir.snapshot.read(
+            op.offset(),
+            || read_entries(&mut dir.read_dir.lock().unwrap()),
+            |entries| {
+                for entry in entries {
+                    if out.entry(&entry.name, entry.ino, entry.typ, entry.off) {
+                        break;
+                    }
+                }
+            },
+        )?;
 
         Ok(out)
     }
 
     fn do_fsyncdir(&self, op: &op::Fsyncdir<'_>) -> io::Result<()> {
-        let read_dir = self.opened_dirs.get(op.fh()).ok_or_else(no_entry)?;
-        let read_dir = read_dir.lock().unwrap();
-
-        if op.datasync() {
-            read_dir.sync_data()?;
+        let dir = self.opened_dirs.get(op.fh()).ok_or_else(bad_handle)?;
+        let read_dir = dir.read_dir.lock().unwrap();
+
+        // The sync is always forwarded, since the backend may have changes
+        // that were not made through this filesystem.
+        let dirty = self.dirty.take_dirty_ino(op.ino(), op.datasync());
+        let res = if op.datasync() {
+            read_dir.sync_data()
         } else {
-            read_dir.sync_all()?;
+            read_dir.sync_all()
+        };
+        if res.is_err() && dirty {
+            self.dirty.mark_metadata(op.ino());
         }
 
-        Ok(())
+        res
     }
 
     fn do_releasedir(&self, op: &op::Releasedir<'_>) -> io::Result<()> {
@@ -546,6 +641,11 @@ impl Passthrough {
     }
 
     fn do_open(&self, op: &op::Open<'_>) -> io::Result<OpenOut> {
+        if self.no_open {
+            // The kernel opens the files without sending OPEN from now on.
+            return Err(io::Error::from_raw_os_error(libc::ENOSYS));
+        }
+
         let inodes = self.inodes.lock().unwrap();
         let inode = inodes.get(op.ino()).ok_or_else(no_entry)?;
         let inode = inode.lock().unwrap();
@@ -565,8 +665,8 @@ impl Passthrough {
         }
         options.custom_flags(op.flags() as i32 & !libc::O_NOFOLLOW);
 
-        let file = options.open(&inode.fd.procname())?;
-        let fh = self.opened_files.insert(Mutex::new(file));
+        let file = options.open(inode.fd.procname())?;
+        let fh = self.opened_files.insert(Mutex::new(file))?;
 
         let mut out = OpenOut::default();
         out.fh(fh);
@@ -574,15 +674,41 @@ impl Passthrough {
         Ok(out)
     }
 
-    fn do_read(&self, op: &op::Read<'_>) -> io::Result<Vec<u8>> {
-        let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
+    /// Open the file of the inode for a request without the file handle.
+    fn open_stateless(&self, ino: Ino, write: bool) -> io::Result<Arc<Mutex<File>>> {
+        let inodes = self.inodes.lock().unwrap();
+        let inode = inodes.get(ino).ok_or_else(no_entry)?;
+        let inode = inode.lock().unwrap();
+        let file = OpenOptions::new()
+            .read(!write)
+            .write(write)
+            .open(inode.fd.procname())?;
+        Ok(Arc::new(Mutex::new(file)))
+    }
+
+    fn do_read(&self, op: &op::Read<'_>) -> io::Result<AlignedBuf> {
+        let file = match op.fh() {
+            Some(fh) => self.opened_files.get(fh).ok_or_else(bad_handle)?,
+            None => self.open_stateless(op.ino(), false)?,
+        };
         let mut file = file.lock().unwrap();
         let file = &mut *file;
 
         file.seek(io::SeekFrom::Start(op.offset()))?;
 
-        let mut buf = Vec::<u8>::with_capacity(op.size() as usize);
-        file.take(op.size() as u64).read_to_end(&mut buf)?;
+        // The page-aligned buffer is replied as it is, which suits the
+        // applications reading the files with `O_DIRECT`.
+        let mut buf = AlignedBuf::new(op.size() as usize);
+        let mut filled = 0;
+        while filled < buf.len() {
+            match file.read(&mut buf[filled..]) {
+                Ok(0) => break,
+                Ok(n) => filled += n,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+                Err(err) => return Err(err),
+            }
+        }
+        buf.truncate(filled);
 
         Ok(buf)
     }
@@ -591,7 +717,10 @@ impl Passthrough {
     where
         T: BufRead + Unpin,
     {
-        let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
+        let file = match op.fh() {
+            Some(fh) => self.opened_files.get(fh).ok_or_else(bad_handle)?,
+            None => self.open_stateless(op.ino(), true)?,
+        };
         let mut file = file.lock().unwrap();
         let file = &mut *file;
 
@@ -610,6 +739,7 @@ impl Passthrough {
         let mut buf = &buf[..];
         let mut buf = (&mut buf).take(op.size() as u64);
         let written = std::io::copy(&mut buf, &mut *file)?;
+        self.dirty.mark_dirty(op.fh(), op.ino());
 
         let mut out = WriteOut::default();
         out.size(written as u32);
@@ -618,7 +748,17 @@ impl Passthrough {
     }
 
     fn do_flush(&self, op: &op::Flush<'_>) -> io::Result<()> {
-        let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
+        let file = match self.opened_files.get(op.fh()) {
+            Some(file) => file,
+            // The writes to the files opened without OPEN are not buffered.
+            None if self.no_open => return Ok(()),
+            None => return Err(bad_handle()),
+        };
+        // FLUSH is sent on every close(2), so only the handles written since
+        // the last flush are synchronized.
+        if !self.dirty.take_dirty(op.fh()) {
+            return Ok(());
+        }
         let file = file.lock().unwrap();
 
         file.sync_all()?;
@@ -627,25 +767,34 @@ impl Passthrough {
     }
 
     fn do_fsync(&self, op: &op::Fsync<'_>) -> io::Result<()> {
-        let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
+        let (file, fh) = match self.opened_files.get(op.fh()) {
+            Some(file) => (file, Some(op.fh())),
+            None if self.no_open => (self.open_stateless(op.ino(), false)?, None),
+            None => return Err(bad_handle()),
+        };
         let file = file.lock().unwrap();
 
-        if op.datasync() {
-            file.sync_data()?;
+        // The sync is always forwarded, since the backend may hold the data
+        // written before the mount or directly to the source directory.
+        let dirty = self.dirty.take_dirty_ino(op.ino(), op.datasync());
+        let res = if op.datasync() {
+            file.sync_data()
         } else {
-            file.sync_all()?;
+            file.sync_all()
+        };
+        if res.is_err() && dirty {
+            // Keep the changes dirty so that the following flush and fsync retry them.
+            self.dirty.mark_dirty(fh, op.ino());
         }
 
-        Ok(())
+        res
     }
 
     fn do_flock(&self, op: &op::Flock<'_>) -> io::Result<()> {
-        let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
+        let file = self.opened_files.get(op.fh()).ok_or_else(bad_handle)?;
         let file = file.lock().unwrap();
 
-        let op = op.op().expect("invalid lock operation") as i32;
-
-        fs::flock(&*file, op)?;
+        fs::flock(&*file, op.op().to_raw())?;
 
         Ok(())
     }
@@ -655,22 +804,25 @@ impl Passthrough {
             return Err(io::Error::from_raw_os_error(libc::EOPNOTSUPP));
         }
 
-        let file = self.opened_files.get(op.fh()).ok_or_else(no_entry)?;
+        let file = self.opened_files.get(op.fh()).ok_or_else(bad_handle)?;
         let file = file.lock().unwrap();
 
         fs::posix_fallocate(&*file, op.offset() as i64, op.length() as i64)?;
+        self.dirty.mark_dirty(Some(op.fh()), op.ino());
 
         Ok(())
     }
 
     fn do_release(&self, op: &op::Release<'_>) -> io::Result<()> {
         let _file = self.opened_files.remove(op.fh());
+        self.dirty.release(op.fh());
         Ok(())
     }
 
     fn do_getxattr(
         &self,
         op: &op::Getxattr<'_>,
+        uid: u32,
     ) -> io::Result<impl polyfuse::bytes::Bytes + Debug> {
         let inodes = self.inodes.lock().unwrap();
         let inode = inodes.get(op.ino()).ok_or_else(no_entry)?;
@@ -681,19 +833,18 @@ impl Passthrough {
             return Err(io::Error::from_raw_os_error(libc::ENOTSUP));
         }
 
+        // The value fetched for the size probe is reused by the following request.
+        let value = self
+            .xattrs
+            .fetch(op, uid, || read_xattr(inode.fd.procname(), op.name()))?;
         match op.size() {
             0 => {
-                let size = fs::getxattr(inode.fd.procname(), op.name(), None)?;
                 let mut out = XattrOut::default();
-                out.size(size as u32);
+                out.size(value.len() as u32);
                 Ok(Either::Left(out))
             }
-            size => {
-                let mut value = vec![0u8; size as usize];
-                let n = fs::getxattr(inode.fd.procname(), op.name(), Some(&mut value[..]))?;
-                value.resize(n as usize, 0);
-                Ok(Either::Right(value))
-            }
+            size if value.len() > size as usize => Err(io::Error::from_raw_os_error(libc::ERANGE)),
+            _ => Ok(Either::Right(value)),
         }
     }
 
@@ -742,6 +893,8 @@ impl Passthrough {
             op.value(),
             op.flags() as libc::c_int,
         )?;
+        self.xattrs.invalidate(op.ino());
+        self.dirty.mark_metadata(op.ino());
 
         Ok(())
     }
@@ -757,6 +910,8 @@ impl Passthrough {
         }
 
         fs::removexattr(inode.fd.procname(), op.name())?;
+        self.xattrs.invalidate(op.ino());
+        self.dirty.mark_metadata(op.ino());
 
         Ok(())
     }
@@ -775,21 +930,6 @@ impl Passthrough {
     }
 }
 
-fn fill_attr(attr: &mut FileAttr, st: &libc::stat) {
-    attr.ino(st.st_ino);
-    attr.size(st.st_size as u64);
-    attr.mode(st.st_mode);
-    attr.nlink(st.st_nlink as u32);
-    attr.uid(st.st_uid);
-    attr.gid(st.st_gid);
-    attr.rdev(st.st_rdev as u32);
-    attr.blksize(st.st_blksize as u32);
-    attr.blocks(st.st_blocks as u64);
-    attr.atime(Duration::new(st.st_atime as u64, st.st_atime_nsec as u32));
-    attr.mtime(Duration::new(st.st_mtime as u64, st.st_mtime_nsec as u32));
-    attr.ctime(Duration::new(st.st_ctime as u64, st.st_ctime_nsec as u32));
-}
-
 fn fill_statfs(statfs: &mut Statfs, st: &libc::statvfs) {
     statfs.bsize(st.f_bsize as u32);
     statfs.frsize(st.f_frsize as u32);
@@ -801,28 +941,23 @@ fn fill_statfs(statfs: &mut Statfs, st: &libc::statvfs) {
     statfs.namelen(st.f_namemax as u32);
 }
 
-// ==== HandlePool ====
+// ==== OpenedDir ====
 
-struct HandlePool<T>(Mutex<Slab<Arc<T>>>);
-
-impl<T> Default for HandlePool<T> {
-    fn default() -> Self {
-        Self(Mutex::default())
-    }
+struct OpenedDir {
+    read_dir: Mutex<ReadDir>,
+    snapshot: DirSnapshot<DirEntry>,
 }
 
-impl<T> HandlePool<T> {
-    fn get(&self, fh: u64) -> Option<Arc<T>> {
-        self.0.lock().unwrap().get(fh as usize).cloned()
-    }
-
-    fn remove(&self, fh: u64) -> Arc<T> {
-        self.0.lock().unwrap().remove(fh as usize)
-    }
-
-    fn insert(&self, entry: T) -> u64 {
-        self.0.lock().unwrap().insert(Arc::new(entry)) as u64
+/// Read all of the entries from the beginning, with the offsets in the snapshot.
+fn read_entries(read_dir: &mut ReadDir) -> io::Result<Vec<DirEntry>> {
+    read_dir.rewind();
+    let mut entries = vec![];
+    for entry in read_dir {
+        let mut entry = entry?;
+        entry.off = entries.len() as u64 + 1;
+        entries.push(entry);
     }
+    Ok(entries)
 }
 
 // ==== INode ====
@@ -890,7 +1025,11 @@ fn no_entry() -> io::Error {
     io::Error::from_raw_os_error(libc::ENOENT)
 }
 
+fn bad_handle() -> io::Error {
+    io::Error::from_raw_os_error(libc::EBADF)
+}
+
 #[inline]
 fn io_to_errno(err: io::Error) -> i32 {
-    err.raw_os_error().unwrap_or(libc::EIO)
+    Errno::from_io_error(&err).raw()
 }
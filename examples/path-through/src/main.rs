@@ -10,25 +10,36 @@
 //
 // This example is inteded to be used as a templete for implementing
 // the path based filesystems such as libfuse's highlevel API.
+//
+// The lookups of the siblings arriving in a burst (e.g. from `git status`)
+// are answered by a batched query, `PathThrough::lookup_batch`, which is the
+// place to issue a single request when the backend supports batching.
+//
+// With `--attr-timeout-ms`, the attributes replied to the lookups are kept
+// by `util::AttrCache` and answer the following `GETATTR` until they expire.
 
 use polyfuse::{
     op::{self, Forget},
     reply::{AttrOut, EntryOut, FileAttr, OpenOut, ReaddirOut, WriteOut},
-    KernelConfig, Operation, Session,
+    util::{AttrCache, DisplayName},
+    Errno, InodeTracking, KernelConfig, Operation, Request, Session,
 };
 
 use anyhow::{ensure, Context as _, Result};
 use slab::Slab;
 use std::{
     collections::hash_map::{Entry, HashMap},
-    ffi::OsString,
+    ffi::{CString, OsStr, OsString},
     fs::{self, File, Metadata, OpenOptions, ReadDir},
     io::{self, prelude::*, BufRead},
     os::unix::prelude::*,
     path::{Path, PathBuf},
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+/// The maximum number of lookups answered by a batched query.
+const MAX_LOOKUP_BATCH: usize = 64;
+
 fn main() -> Result<()> {
     tracing_subscriber::fmt::init();
 
@@ -39,46 +50,160 @@ fn main() -> Result<()> {
         .unwrap_or_else(|| std::env::current_dir().unwrap());
     ensure!(source.is_dir(), "the source path must be a directory");
 
+    // How long to wait for more lookups once a burst is observed.
+    let lookup_window: Duration = args
+        .opt_value_from_str("--lookup-window-ms")?
+        .map_or(Duration::from_millis(0), Duration::from_millis);
+
+    // How long the kernel and `AttrCache` may keep the attributes.
+    let attr_timeout: Duration = args
+        .opt_value_from_str("--attr-timeout-ms")?
+        .map_or(Duration::from_millis(0), Duration::from_millis);
+
     let mountpoint: PathBuf = args.free_from_str()?.context("missing mountpoint")?;
     ensure!(mountpoint.is_dir(), "mountpoint must be a directory");
 
-    let session = Session::mount(mountpoint, KernelConfig::default())?;
+    // The late requests for the forgotten inodes are answered by the session,
+    // since their paths have already been removed from the table.
+    let mut config = KernelConfig::default();
+    config.reject_stale_inodes(InodeTracking::Forgotten);
+    let session = Session::mount(mountpoint, config)?;
 
-    let mut fs = PathThrough::new(source)?;
+    let mut fs = PathThrough::new(source, attr_timeout)?;
 
-    while let Some(req) = session.next_request()? {
-        let op = req.operation()?;
-        tracing::debug!("handle operation: {:#?}", op);
+    // The request read ahead while collecting a batch of lookups.
+    let mut pending = None;
 
-        macro_rules! try_reply {
-            ($e:expr) => {
-                match $e {
-                    Ok(data) => req.reply(data)?,
-                    Err(err) => req.reply_error(err.raw_os_error().unwrap_or(libc::EIO))?,
+    loop {
+        let req = match pending.take() {
+            Some(req) => req,
+            None => match session.next_request()? {
+                Some(req) => req,
+                None => break,
+            },
+        };
+
+        let parent = match lookup_parent(&req) {
+            Some(parent) => parent,
+            None => {
+                handle_request(&mut fs, &req)?;
+                continue;
+            }
+        };
+
+        // Collect the lookups of the same parent that have already arrived.
+        // The first request of the others ends the batch, and is processed
+        // after it so that the order of requests is kept.  The window is
+        // started only when a second lookup is found, so a single lookup is
+        // replied without any delay.
+        let mut batch = vec![req];
+        let mut deadline = None;
+        while batch.len() < MAX_LOOKUP_BATCH {
+            match session.try_next_request()? {
+                Some(req) if lookup_parent(&req) == Some(parent) => {
+                    batch.push(req);
+                    deadline.get_or_insert_with(|| Instant::now() + lookup_window);
                 }
-            };
+                Some(req) => {
+                    pending = Some(req);
+                    break;
+                }
+                None => match deadline {
+                    Some(deadline) if wait_readable(&session, deadline)? => continue,
+                    _ => break,
+                },
+            }
+        }
+        handle_lookups(&mut fs, parent, &batch)?;
+    }
+
+    Ok(())
+}
+
+fn lookup_parent(req: &Request) -> Option<Ino> {
+    match req.operation() {
+        Ok(Operation::Lookup(op)) => Some(op.parent()),
+        _ => None,
+    }
+}
+
+/// Wait until the next request is readable or the deadline is reached.
+fn wait_readable(session: &Session, deadline: Instant) -> io::Result<bool> {
+    let timeout = deadline.saturating_duration_since(Instant::now());
+    if timeout == Duration::from_millis(0) {
+        return Ok(false);
+    }
+    let mut fds = libc::pollfd {
+        fd: session.as_raw_fd(),
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    let res = unsafe { libc::poll(&mut fds, 1, timeout.as_millis() as libc::c_int) };
+    if res < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(res > 0)
+}
+
+fn handle_lookups(fs: &mut PathThrough, parent: Ino, batch: &[Request]) -> Result<()> {
+    let ops: Vec<_> = batch
+        .iter()
+        .map(|req| match req.operation() {
+            Ok(Operation::Lookup(op)) => op,
+            _ => unreachable!("not a lookup"),
+        })
+        .collect();
+    let names: Vec<&OsStr> = ops.iter().map(|op| op.name()).collect();
+    tracing::debug!(
+        "handle lookups: parent={}, names={:?}",
+        parent,
+        names.iter().map(DisplayName::new).collect::<Vec<_>>()
+    );
+
+    // Each request is replied individually, with its own lookup count.
+    for (req, res) in batch.iter().zip(fs.do_lookup_batch(parent, &names)) {
+        match res {
+            Ok(out) => req.reply(out)?,
+            Err(err) => req.reply_error(Errno::from_io_error(&err).raw())?,
         }
+    }
+
+    Ok(())
+}
+
+fn handle_request(fs: &mut PathThrough, req: &Request) -> Result<()> {
+    let op = req.operation()?;
+    tracing::debug!("handle operation: {:#?}", op);
+    fs.attrs.observe(&op);
 
-        match op {
-            Operation::Lookup(op) => try_reply!(fs.do_lookup(&op)),
-            Operation::Forget(forgets) => {
-                fs.do_forget(forgets.as_ref());
+    macro_rules! try_reply {
+        ($e:expr) => {
+            match $e {
+                Ok(data) => req.reply(data)?,
+                Err(err) => req.reply_error(Errno::from_io_error(&err).raw())?,
             }
-            Operation::Getattr(op) => try_reply!(fs.do_getattr(&op)),
-            Operation::Setattr(op) => try_reply!(fs.do_setattr(&op)),
-            Operation::Readlink(op) => try_reply!(fs.do_readlink(&op)),
-            Operation::Opendir(op) => try_reply!(fs.do_opendir(&op)),
-            Operation::Readdir(op) => try_reply!(fs.do_readdir(&op)),
-            Operation::Releasedir(op) => try_reply!(fs.do_releasedir(&op)),
-            Operation::Open(op) => try_reply!(fs.do_open(&op)),
-            Operation::Read(op) => try_reply!(fs.do_read(&op)),
-            Operation::Write(op, data) => try_reply!(fs.do_write(&op, data)),
-            Operation::Flush(op) => try_reply!(fs.do_flush(&op)),
-            Operation::Fsync(op) => try_reply!(fs.do_fsync(&op)),
-            Operation::Release(op) => try_reply!(fs.do_release(&op)),
-
-            _ => req.reply_error(libc::ENOSYS)?,
+        };
+    }
+
+    match op {
+        Operation::Forget(forgets) => {
+            fs.do_forget(forgets.as_ref());
         }
+        Operation::Getattr(op) => try_reply!(fs.do_getattr(&op)),
+        Operation::Setattr(op) => try_reply!(fs.do_setattr(&op)),
+        Operation::Readlink(op) => try_reply!(fs.do_readlink(&op)),
+        Operation::Rename(op) => try_reply!(fs.do_rename(&op)),
+        Operation::Opendir(op) => try_reply!(fs.do_opendir(&op)),
+        Operation::Readdir(op) => try_reply!(fs.do_readdir(&op)),
+        Operation::Releasedir(op) => try_reply!(fs.do_releasedir(&op)),
+        Operation::Open(op) => try_reply!(fs.do_open(&op)),
+        Operation::Read(op) => try_reply!(fs.do_read(&op)),
+        Operation::Write(op, data) => try_reply!(fs.do_write(&op, data)),
+        Operation::Flush(op) => try_reply!(fs.do_flush(&op)),
+        Operation::Fsync(op) => try_reply!(fs.do_fsync(&op)),
+        Operation::Release(op) => try_reply!(fs.do_release(&op)),
+
+        _ => req.reply_error(libc::ENOSYS)?,
     }
 
     Ok(())
@@ -120,6 +245,56 @@ impl INodeTable {
         let ino = self.path_to_ino.get(path).copied()?;
         self.map.get_mut(&ino)
     }
+
+    /// Update the paths of the inodes after renaming `from` to `to`.
+    ///
+    /// The inodes below `from` are moved below `to`, and the ones below `to`
+    /// are moved back below `from` if the entries were exchanged.  Otherwise
+    /// the replaced inode loses its path, and remains in the table only
+    /// until it is forgotten.
+    fn rename(&mut self, from: &Path, to: &Path, exchange: bool) {
+        let moved: Vec<(Ino, PathBuf)> = self
+            .map
+            .values()
+            .filter_map(|inode| {
+                let path = match inode.path.strip_prefix(from) {
+                    Ok(rest) => Some(join_path(to, rest)),
+                    Err(..) => match inode.path.strip_prefix(to) {
+                        Ok(rest) if exchange => Some(join_path(from, rest)),
+                        Ok(..) => None,
+                        Err(..) => return None,
+                    },
+                };
+                Some((inode.ino, path?))
+            })
+            .collect();
+
+        // Remove all of the old paths first, since the exchanged inodes
+        // take over the paths of each other.
+        for (ino, _) in &moved {
+            let path = &self.map[ino].path;
+            if self.path_to_ino.get(path) == Some(ino) {
+                self.path_to_ino.remove(path);
+            }
+        }
+        if !exchange {
+            self.path_to_ino.remove(to);
+        }
+        for (ino, path) in moved {
+            self.path_to_ino.insert(path.clone(), ino);
+            if let Some(inode) = self.map.get_mut(&ino) {
+                inode.path = path;
+            }
+        }
+    }
+}
+
+fn join_path(base: &Path, rest: &Path) -> PathBuf {
+    if rest.as_os_str().is_empty() {
+        base.to_path_buf()
+    } else {
+        base.join(rest)
+    }
 }
 
 struct VacantEntry<'a> {
@@ -128,7 +303,7 @@ struct VacantEntry<'a> {
 }
 
 impl VacantEntry<'_> {
-    fn insert(mut self, inode: INode) {
+    fn insert(self, inode: INode) {
         let path = inode.path.clone();
         self.table.map.insert(self.ino, inode);
         self.table.path_to_ino.insert(path, self.ino);
@@ -141,17 +316,19 @@ struct PathThrough {
     inodes: INodeTable,
     dirs: Slab<DirHandle>,
     files: Slab<FileHandle>,
+    attr_timeout: Duration,
+    attrs: AttrCache,
 }
 
 impl PathThrough {
-    fn new(source: PathBuf) -> io::Result<Self> {
+    fn new(source: PathBuf, attr_timeout: Duration) -> io::Result<Self> {
         let source = source.canonicalize()?;
 
         let mut inodes = INodeTable::new();
         inodes.vacant_entry().insert(INode {
             ino: 1,
             path: PathBuf::new(),
-            refcount: u64::max_value() / 2,
+            refcount: u64::MAX / 2,
         });
 
         Ok(Self {
@@ -159,17 +336,43 @@ impl PathThrough {
             inodes,
             dirs: Slab::new(),
             files: Slab::new(),
+            attr_timeout,
+            attrs: AttrCache::new(),
         })
     }
 
-    fn do_lookup(&mut self, op: &op::Lookup<'_>) -> io::Result<EntryOut> {
-        let parent = self.inodes.get(op.parent()).ok_or_else(no_entry)?;
-        let path = parent.path.join(op.name());
+    fn do_lookup_batch(&mut self, parent: Ino, names: &[&OsStr]) -> Vec<io::Result<EntryOut>> {
+        let parent = match self.inodes.get(parent) {
+            Some(parent) => parent.path.clone(),
+            None => return names.iter().map(|_| Err(no_entry())).collect(),
+        };
+
+        let results = self.lookup_batch(&parent, names);
+        debug_assert_eq!(results.len(), names.len());
 
-        let metadata = fs::symlink_metadata(self.source.join(&path))?;
+        names
+            .iter()
+            .zip(results)
+            .map(|(name, metadata)| Ok(self.make_entry(parent.join(name), &metadata?)))
+            .collect()
+    }
+
+    /// Query the attributes of the entries in a directory at once.
+    ///
+    /// The results must be in the same order as `names`.  The local
+    /// filesystem has no batched API, so this simply queries them one by one.
+    fn lookup_batch(&self, parent: &Path, names: &[&OsStr]) -> Vec<io::Result<Metadata>> {
+        let parent = self.source.join(parent);
+        names
+            .iter()
+            .map(|name| fs::symlink_metadata(parent.join(name)))
+            .collect()
+    }
 
+    fn make_entry(&mut self, path: PathBuf, metadata: &Metadata) -> EntryOut {
         let mut out = EntryOut::default();
-        fill_attr(&metadata, out.attr());
+        fill_attr(metadata, out.attr());
+        out.ttl_attr(self.attr_timeout);
 
         match self.inodes.get_by_path_mut(&path) {
             Some(inode) => {
@@ -188,14 +391,15 @@ impl PathThrough {
             }
         }
 
-        Ok(out)
+        self.attrs.insert_entry(&out);
+        out
     }
 
     fn do_forget(&mut self, forgets: &[Forget]) {
         for forget in forgets {
             if let Entry::Occupied(mut entry) = self.inodes.map.entry(forget.ino()) {
                 let refcount = {
-                    let mut inode = entry.get_mut();
+                    let inode = entry.get_mut();
                     inode.refcount = inode.refcount.saturating_sub(forget.nlookup());
                     inode.refcount
                 };
@@ -210,12 +414,14 @@ impl PathThrough {
 
     fn do_getattr(&mut self, op: &op::Getattr<'_>) -> io::Result<AttrOut> {
         let inode = self.inodes.get(op.ino()).ok_or_else(no_entry)?;
-        let metadata = fs::symlink_metadata(self.source.join(&inode.path))?;
-
-        let mut out = AttrOut::default();
-        fill_attr(&metadata, out.attr());
-
-        Ok(out)
+        let path = self.source.join(&inode.path);
+        self.attrs.fetch(op.ino(), || {
+            let metadata = fs::symlink_metadata(path)?;
+            let mut out = AttrOut::default();
+            fill_attr(&metadata, out.attr());
+            out.ttl(self.attr_timeout);
+            Ok(out)
+        })
     }
 
     fn do_setattr(&mut self, op: &op::Setattr<'_>) -> io::Result<AttrOut> {
@@ -257,6 +463,7 @@ impl PathThrough {
 
         let mut out = AttrOut::default();
         fill_attr(&metadata, out.attr());
+        out.ttl(self.attr_timeout);
 
         Ok(out)
     }
@@ -267,6 +474,40 @@ impl PathThrough {
         Ok(path.into_os_string())
     }
 
+    fn do_rename(&mut self, op: &op::Rename<'_>) -> io::Result<()> {
+        let parent = self.inodes.get(op.parent()).ok_or_else(no_entry)?;
+        let newparent = self.inodes.get(op.newparent()).ok_or_else(no_entry)?;
+        let from = parent.path.join(op.name());
+        let to = newparent.path.join(op.newname());
+
+        // The host filesystem checks the flags, e.g. `RENAME_EXCHANGE`
+        // with a missing target or `RENAME_WHITEOUT` without privileges.
+        let src = CString::new(self.source.join(&from).into_os_string().into_vec())?;
+        let dst = CString::new(self.source.join(&to).into_os_string().into_vec())?;
+        let res = unsafe {
+            libc::renameat2(
+                libc::AT_FDCWD,
+                src.as_ptr(),
+                libc::AT_FDCWD,
+                dst.as_ptr(),
+                op.flags(),
+            )
+        };
+        if res < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The renamed and the replaced inodes have their ctime updated.
+        for path in &[&from, &to] {
+            if let Some(&ino) = self.inodes.path_to_ino.get(*path) {
+                self.attrs.invalidate(ino);
+            }
+        }
+        self.inodes
+            .rename(&from, &to, op.flags() & libc::RENAME_EXCHANGE != 0);
+        Ok(())
+    }
+
     fn do_opendir(&mut self, op: &op::Opendir<'_>) -> io::Result<OpenOut> {
         let inode = self.inodes.get(op.ino()).ok_or_else(no_entry)?;
 
@@ -302,7 +543,7 @@ impl PathThrough {
             dir.offset += 1;
         }
 
-        while let Some(entry) = dir.read_dir.next() {
+        for entry in dir.read_dir.by_ref() {
             let entry = entry?;
             match entry.file_name() {
                 name if name.as_bytes() == b"." || name.as_bytes() == b".." => continue,
@@ -375,7 +616,8 @@ impl PathThrough {
     }
 
     fn do_read(&mut self, op: &op::Read<'_>) -> io::Result<Vec<u8>> {
-        let file = Slab::get_mut(&mut self.files, op.fh() as usize).ok_or_else(invalid_handle)?;
+        let fh = op.fh().ok_or_else(invalid_handle)?;
+        let file = Slab::get_mut(&mut self.files, fh as usize).ok_or_else(invalid_handle)?;
         let buf = file.read(op.offset(), op.size() as usize)?;
         Ok(buf)
     }
@@ -384,7 +626,8 @@ impl PathThrough {
     where
         T: BufRead + Unpin,
     {
-        let file = Slab::get_mut(&mut self.files, op.fh() as usize).ok_or_else(invalid_handle)?;
+        let fh = op.fh().ok_or_else(invalid_handle)?;
+        let file = Slab::get_mut(&mut self.files, fh as usize).ok_or_else(invalid_handle)?;
         let written = file.write(data.take(op.size() as u64), op.offset())?;
 
         let mut out = WriteOut::default();
@@ -510,3 +753,127 @@ fn nix_to_io_error(err: nix::Error) -> io::Error {
     let errno = err.as_errno().map_or(libc::EIO, |errno| errno as i32);
     io::Error::from_raw_os_error(errno)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polyfuse::testing::sim::Simulator;
+
+    fn simulator(
+        source: &Path,
+        attr_timeout: Duration,
+    ) -> Simulator<impl FnMut(&Request) -> io::Result<()>> {
+        let mut fs = PathThrough::new(source.to_path_buf(), attr_timeout).unwrap();
+        let mut config = KernelConfig::default();
+        config.reject_stale_inodes(InodeTracking::Forgotten);
+        Simulator::new(config, move |req| {
+            let res = match lookup_parent(req) {
+                Some(parent) => handle_lookups(&mut fs, parent, std::slice::from_ref(req)),
+                None => handle_request(&mut fs, req),
+            };
+            res.map_err(|err| {
+                err.downcast()
+                    .unwrap_or_else(|_| io::Error::from_raw_os_error(libc::EIO))
+            })
+        })
+        .unwrap()
+    }
+
+    fn names(
+        sim: &mut Simulator<impl FnMut(&Request) -> io::Result<()>>,
+        path: &str,
+    ) -> Vec<OsString> {
+        let mut names: Vec<_> = sim
+            .readdir(path)
+            .unwrap()
+            .into_iter()
+            .map(|entry| entry.name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[test]
+    fn rename_exchange() {
+        let source =
+            std::env::temp_dir().join(format!("polyfuse-path-through-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&source);
+        fs::create_dir_all(source.join("dir/sub")).unwrap();
+        fs::write(source.join("file"), "content").unwrap();
+        fs::write(source.join("dir/sub/nested"), "nested").unwrap();
+        let mut sim = simulator(&source, Duration::from_secs(0));
+
+        // Look up the inodes below the directory before the exchange.
+        let file = sim.lookup("/file").unwrap();
+        let nested = sim.lookup("/dir/sub/nested").unwrap();
+        let handle = sim.open("/file", libc::O_RDONLY).unwrap();
+
+        sim.rename("/file", "/dir", libc::RENAME_EXCHANGE).unwrap();
+        assert!(source.join("file/sub/nested").is_file());
+        assert_eq!(sim.lookup("/dir").unwrap(), file);
+        assert_eq!(sim.nlookup(nested), 1);
+
+        // The paths of the descendants follow the exchanged directory.
+        assert_eq!(sim.stat("/file/sub/nested").unwrap().size(), 6);
+        assert_eq!(
+            sim.stat("/dir").unwrap().mode() & libc::S_IFMT,
+            libc::S_IFREG
+        );
+        assert_eq!(names(&mut sim, "/file"), vec![OsString::from("sub")]);
+        assert_eq!(sim.read(&handle, 0, 16).unwrap(), b"content");
+
+        // A plain rename moves the subtree without any replaced entry.
+        sim.rename("/file/sub", "/moved", libc::RENAME_NOREPLACE)
+            .unwrap();
+        assert_eq!(sim.stat("/moved/nested").unwrap().size(), 6);
+        assert_eq!(
+            sim.rename("/dir", "/moved", libc::RENAME_NOREPLACE)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::EEXIST)
+        );
+        assert_eq!(
+            sim.rename("/dir", "/missing", libc::RENAME_EXCHANGE)
+                .unwrap_err()
+                .raw_os_error(),
+            Some(libc::ENOENT)
+        );
+
+        sim.release(handle).unwrap();
+        fs::remove_dir_all(&source).unwrap();
+    }
+
+    #[test]
+    fn getattr_served_from_cache() {
+        let source =
+            std::env::temp_dir().join(format!("polyfuse-path-through-attr-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&source);
+        fs::create_dir_all(&source).unwrap();
+        let mut sim = simulator(&source, Duration::from_millis(500));
+        let mtime = sim.stat("/").unwrap().mtime();
+
+        // Change the attributes behind the filesystem.
+        let path = CString::new(source.clone().into_os_string().into_vec()).unwrap();
+        let times = [libc::timespec {
+            tv_sec: 1000,
+            tv_nsec: 0,
+        }; 2];
+        let res = unsafe { libc::utimensat(libc::AT_FDCWD, path.as_ptr(), times.as_ptr(), 0) };
+        assert_eq!(res, 0);
+
+        // The kernel's cache has expired, while the filesystem's has not.
+        sim.advance(Duration::from_secs(1));
+        let requests = sim.requests();
+        assert_eq!(sim.stat("/").unwrap().mtime(), mtime);
+        assert_eq!(sim.requests(), requests + 1);
+
+        std::thread::sleep(Duration::from_millis(600));
+        sim.advance(Duration::from_secs(1));
+        assert_eq!(
+            sim.stat("/").unwrap().mtime(),
+            std::time::UNIX_EPOCH + Duration::from_secs(1000)
+        );
+
+        fs::remove_dir_all(&source).unwrap();
+    }
+}
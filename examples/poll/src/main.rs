@@ -1,5 +1,6 @@
 use polyfuse::{
     reply::{AttrOut, OpenOut, PollOut},
+    util::PollRegistry,
     Notifier, Operation, Request, Session,
 };
 
@@ -48,6 +49,7 @@ fn main() -> Result<()> {
 struct PollFS {
     handles: DashMap<u64, Arc<FileHandle>>,
     next_fh: AtomicU64,
+    polls: Arc<PollRegistry>,
 
     notifier: Notifier,
     wakeup_interval: Duration,
@@ -58,6 +60,7 @@ impl PollFS {
         Self {
             handles: DashMap::new(),
             next_fh: AtomicU64::new(0),
+            polls: Arc::new(PollRegistry::new()),
             notifier,
             wakeup_interval,
         }
@@ -78,7 +81,7 @@ impl PollFS {
                 out.attr().mode(libc::S_IFREG | 0o444);
                 out.attr().uid(unsafe { libc::getuid() });
                 out.attr().gid(unsafe { libc::getgid() });
-                out.ttl(Duration::from_secs(u64::max_value() / 2));
+                out.ttl(Duration::from_secs(u64::MAX / 2));
 
                 req.reply(out)?;
             }
@@ -101,6 +104,7 @@ impl PollFS {
                 std::thread::spawn({
                     let handle = Arc::downgrade(&handle);
                     let notifier = self.notifier.clone();
+                    let polls = self.polls.clone();
                     let wakeup_interval = self.wakeup_interval;
 
                     move || -> Result<()> {
@@ -116,10 +120,8 @@ impl PollFS {
                         if let Some(handle) = handle.upgrade() {
                             let state = &mut *handle.state.lock().unwrap();
 
-                            if let Some(kh) = state.kh {
-                                tracing::info!("send wakeup notification, kh={}", kh);
-                                notifier.poll_wakeup(kh)?;
-                            }
+                            let woken = polls.wake(fh, &notifier)?;
+                            tracing::info!("send {} wakeup notification(s)", woken);
 
                             state.is_ready = true;
                             handle.condvar.notify_one();
@@ -140,7 +142,7 @@ impl PollFS {
             }
 
             Operation::Read(op) => {
-                let handle = match self.handles.get(&op.fh()) {
+                let handle = match op.fh().and_then(|fh| self.handles.get(&fh)) {
                     Some(h) => h,
                     None => return req.reply_error(libc::EINVAL).map_err(Into::into),
                 };
@@ -184,7 +186,7 @@ impl PollFS {
                     out.revents(op.events() & libc::POLLIN as u32);
                 } else if let Some(kh) = op.kh() {
                     tracing::info!("register the poll handle for notification: kh={}", kh);
-                    state.kh = Some(kh);
+                    self.polls.register(op.fh(), kh);
                 }
 
                 req.reply(out)?;
@@ -192,7 +194,8 @@ impl PollFS {
 
             Operation::Release(op) => {
                 drop(self.handles.remove(&op.fh()));
-                req.reply(&[])?;
+                self.polls.remove(op.fh());
+                req.reply(())?;
             }
 
             _ => req.reply_error(libc::ENOSYS)?,
@@ -211,5 +214,4 @@ struct FileHandle {
 #[derive(Default)]
 struct FileHandleState {
     is_ready: bool,
-    kh: Option<u64>,
 }
@@ -33,6 +33,7 @@ async fn main() -> Result<()> {
     while let Some(req) = session.next_request().await? {
         let fs = fs.clone();
 
+        #[allow(clippy::let_underscore_future)] // the spawned task is detached
         let _: JoinHandle<Result<()>> = task::spawn(async move {
             match req.operation()? {
                 Operation::Lookup(op) => fs.lookup(&req, op).await?,
@@ -89,7 +90,7 @@ impl Hello {
 
     fn fill_root_attr(&self, attr: &mut FileAttr) {
         attr.ino(ROOT_INO);
-        attr.mode(libc::S_IFDIR as u32 | 0o555);
+        attr.mode(libc::S_IFDIR | 0o555);
         attr.nlink(2);
         attr.uid(self.uid);
         attr.gid(self.gid);
@@ -98,7 +99,7 @@ impl Hello {
     fn fill_hello_attr(&self, attr: &mut FileAttr) {
         attr.ino(HELLO_INO);
         attr.size(HELLO_CONTENT.len() as u64);
-        attr.mode(libc::S_IFREG as u32 | 0o444);
+        attr.mode(libc::S_IFREG | 0o444);
         attr.nlink(1);
         attr.uid(self.uid);
         attr.gid(self.gid);
@@ -150,28 +151,15 @@ impl Hello {
         req.reply(data)
     }
 
-    fn dir_entries(&self) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
-        self.entries.iter().enumerate().map(|(i, ent)| {
-            let offset = (i + 1) as u64;
-            (offset, ent)
-        })
-    }
-
     async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
         if op.ino() != ROOT_INO {
             return req.reply_error(libc::ENOTDIR);
         }
 
-        let mut out = ReaddirOut::new(op.size() as usize);
+        let mut out = ReaddirOut::with_offset(op.size() as usize, op.offset());
 
-        for (i, entry) in self.dir_entries().skip(op.offset() as usize) {
-            let full = out.entry(
-                entry.name.as_ref(), //
-                entry.ino,
-                entry.typ,
-                i + 1,
-            );
-            if full {
+        for entry in self.entries.iter().skip(op.offset() as usize) {
+            if out.next_entry(entry.name.as_ref(), entry.ino, entry.typ) {
                 break;
             }
         }
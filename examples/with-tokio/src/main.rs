@@ -8,9 +8,13 @@ use polyfuse::{
 };
 
 use anyhow::{ensure, Context as _, Result};
-use std::{io, os::unix::prelude::*, path::PathBuf, sync::Arc, time::Duration};
+use std::{
+    future::Future, io, os::raw::c_int, os::unix::prelude::*, path::PathBuf, sync::Arc,
+    time::Duration,
+};
 use tokio::{
     io::{unix::AsyncFd, Interest},
+    signal::unix::{signal, SignalKind},
     task::{self, JoinHandle},
 };
 
@@ -33,9 +37,41 @@ async fn main() -> Result<()> {
 
     let fs = Arc::new(Hello::new());
 
-    while let Some(req) = session.next_request().await? {
+    // The signals are listened only while the filesystem is running.
+    if let Some(sig) = run_until(&session, fs, shutdown_signal()?).await? {
+        tracing::info!("shutting down by the signal {}", sig);
+    }
+
+    Ok(())
+}
+
+/// Process the requests until the session is closed or `shutdown` completes,
+/// and return the output of `shutdown` in the latter case.
+///
+/// `shutdown` is dropped on return, along with the signal listeners in it,
+/// so the session can be mounted and run again in the same process.
+async fn run_until<S>(
+    session: &AsyncSession,
+    fs: Arc<Hello>,
+    shutdown: S,
+) -> Result<Option<S::Output>>
+where
+    S: Future,
+{
+    tokio::pin!(shutdown);
+
+    loop {
+        let req = tokio::select! {
+            req = session.next_request() => match req? {
+                Some(req) => req,
+                None => return Ok(None),
+            },
+            output = &mut shutdown => return Ok(Some(output)),
+        };
+
         let fs = fs.clone();
 
+        #[allow(clippy::let_underscore_future)] // the spawned task is detached
         let _: JoinHandle<Result<()>> = task::spawn(async move {
             match req.operation()? {
                 Operation::Lookup(op) => fs.lookup(&req, op).await?,
@@ -48,8 +84,25 @@ async fn main() -> Result<()> {
             Ok(())
         });
     }
+}
 
-    Ok(())
+/// Wait for `SIGHUP`, `SIGINT` or `SIGTERM`, and return the received one.
+///
+/// The listeners are registered when called rather than when polled, so
+/// that a signal arriving in between is not missed.  tokio keeps its
+/// process-wide handlers once installed, but delivers the signals only to
+/// the listeners alive, i.e. those of the current run.
+fn shutdown_signal() -> io::Result<impl Future<Output = c_int>> {
+    let mut sighup = signal(SignalKind::hangup())?;
+    let mut sigint = signal(SignalKind::interrupt())?;
+    let mut sigterm = signal(SignalKind::terminate())?;
+    Ok(async move {
+        tokio::select! {
+            _ = sighup.recv() => libc::SIGHUP,
+            _ = sigint.recv() => libc::SIGINT,
+            _ = sigterm.recv() => libc::SIGTERM,
+        }
+    })
 }
 
 struct Hello {
@@ -92,7 +145,7 @@ impl Hello {
 
     fn fill_root_attr(&self, attr: &mut FileAttr) {
         attr.ino(ROOT_INO);
-        attr.mode(libc::S_IFDIR as u32 | 0o555);
+        attr.mode(libc::S_IFDIR | 0o555);
         attr.nlink(2);
         attr.uid(self.uid);
         attr.gid(self.gid);
@@ -101,7 +154,7 @@ impl Hello {
     fn fill_hello_attr(&self, attr: &mut FileAttr) {
         attr.ino(HELLO_INO);
         attr.size(HELLO_CONTENT.len() as u64);
-        attr.mode(libc::S_IFREG as u32 | 0o444);
+        attr.mode(libc::S_IFREG | 0o444);
         attr.nlink(1);
         attr.uid(self.uid);
         attr.gid(self.gid);
@@ -154,28 +207,15 @@ impl Hello {
         req.reply(data)
     }
 
-    fn dir_entries(&self) -> impl Iterator<Item = (u64, &DirEntry)> + '_ {
-        self.entries.iter().enumerate().map(|(i, ent)| {
-            let offset = (i + 1) as u64;
-            (offset, ent)
-        })
-    }
-
     async fn readdir(&self, req: &Request, op: op::Readdir<'_>) -> io::Result<()> {
         if op.ino() != ROOT_INO {
             return req.reply_error(libc::ENOTDIR);
         }
 
-        let mut out = ReaddirOut::new(op.size() as usize);
+        let mut out = ReaddirOut::with_offset(op.size() as usize, op.offset());
 
-        for (i, entry) in self.dir_entries().skip(op.offset() as usize) {
-            let full = out.entry(
-                entry.name.as_ref(), //
-                entry.ino,
-                entry.typ,
-                i + 1,
-            );
-            if full {
+        for entry in self.entries.iter().skip(op.offset() as usize) {
+            if out.next_entry(entry.name.as_ref(), entry.ino, entry.typ) {
                 break;
             }
         }
@@ -186,20 +226,25 @@ impl Hello {
 
 // ==== AsyncSession ====
 
+// Only the readiness of receiving is registered to the reactor.  The replies
+// are written by `Request` through the session without any lock, so they can
+// be sent from the spawned tasks while `next_request` is waiting.
 struct AsyncSession {
     inner: AsyncFd<Session>,
 }
 
 impl AsyncSession {
     async fn mount(mountpoint: PathBuf, config: KernelConfig) -> io::Result<Self> {
-        tokio::task::spawn_blocking(move || {
-            let session = Session::mount(mountpoint, config)?;
-            Ok(Self {
-                inner: AsyncFd::with_interest(session, Interest::READABLE)?,
-            })
+        let session = tokio::task::spawn_blocking(move || Session::mount(mountpoint, config))
+            .await
+            .expect("join error")?;
+        Self::new(session)
+    }
+
+    fn new(session: Session) -> io::Result<Self> {
+        Ok(Self {
+            inner: AsyncFd::with_interest(session, Interest::READABLE)?,
         })
-        .await
-        .expect("join error")
     }
 
     async fn next_request(&self) -> io::Result<Option<Request>> {
@@ -221,3 +266,23 @@ impl AsyncSession {
         .await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use polyfuse::testing;
+
+    #[tokio::test]
+    async fn run_again_after_signal() {
+        let fs = Arc::new(Hello::new());
+        for _ in 0..2 {
+            let (session, _kernel) = testing::session(KernelConfig::default()).unwrap();
+            let session = AsyncSession::new(session).unwrap();
+
+            let shutdown = shutdown_signal().unwrap();
+            assert_eq!(unsafe { libc::raise(libc::SIGHUP) }, 0);
+            let sig = run_until(&session, fs.clone(), shutdown).await.unwrap();
+            assert_eq!(sig, Some(libc::SIGHUP));
+        }
+    }
+}
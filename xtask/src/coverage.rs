@@ -35,6 +35,7 @@ pub fn do_coverage(env: &Env) -> Result<()> {
     let tests = build_instrumented_tests(env)?;
 
     for test in &tests {
+        #[allow(clippy::needless_borrow)] // flagged only by the recent versions of clippy
         let test_name = extract_test_name(&test).context("failed to extract test name")?;
 
         let profraw_path = cov_dir.join(format!("{}.profraw", test_name));
@@ -110,6 +111,7 @@ fn build_instrumented_tests(env: &Env) -> Result<Vec<PathBuf>> {
 
     let mut executables = vec![];
 
+    #[allow(clippy::lines_filter_map_ok)] // `Iterator::map_while` requires Rust 1.57
     for line in stdout.lines().filter_map(|line| line.ok()) {
         let msg = match json::parse(line.trim()) {
             Ok(JsonValue::Object(msg)) => msg,
@@ -0,0 +1,39 @@
+// Passing the arrays to `Command::args` by value requires Rust 1.53.
+#![allow(clippy::needless_borrows_for_generic_args)]
+
+use crate::{
+    env::Env,
+    process::{cargo, CommandExt as _},
+};
+use anyhow::Result;
+
+/// The optional features of `polyfuse`, checked in every combination.
+const FEATURES: &[&str] = &["notify", "serde", "testing", "tracing"];
+
+pub fn check_features(env: &Env) -> Result<()> {
+    for combination in combinations(FEATURES) {
+        cargo(env)
+            .args(&["check", "--package", "polyfuse", "--all-targets"])
+            .arg("--no-default-features")
+            .arg(format!("--features={}", combination.join(",")))
+            .with(|cmd| {
+                println!("[cargo-xtask] Run {:?}", cmd);
+                cmd
+            })
+            .run()?;
+    }
+    Ok(())
+}
+
+fn combinations<'a>(features: &[&'a str]) -> Vec<Vec<&'a str>> {
+    (0..1usize << features.len())
+        .map(|mask| {
+            features
+                .iter()
+                .enumerate()
+                .filter(|(i, _)| mask & (1 << i) != 0)
+                .map(|(_, &feature)| feature)
+                .collect()
+        })
+        .collect()
+}
@@ -1,3 +1,6 @@
+// Passing the arrays to `Command::args` by value requires Rust 1.53.
+#![allow(clippy::needless_borrows_for_generic_args)]
+
 use crate::{
     env::Env,
     process::{cargo, CommandExt as _},
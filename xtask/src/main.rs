@@ -1,6 +1,7 @@
 mod coverage;
 mod doc;
 mod env;
+mod features;
 mod hook;
 mod lint;
 mod process;
@@ -23,6 +24,7 @@ Subcommands:
     lint            Run lints
     doc             Build API docs
     coverage        Run coverage test
+    features        Check every combination of the optional features
     install-hooks   Install Git hooks
     pre-commit      Run pre-commit hook
 
@@ -63,6 +65,11 @@ fn main() -> Result<()> {
             coverage::do_coverage(&env)?;
         }
 
+        Some("features") => {
+            args.finish()?;
+            features::check_features(&env)?;
+        }
+
         Some("install-hooks") => {
             let force = args.contains(["-f", "--force"]);
             args.finish()?;